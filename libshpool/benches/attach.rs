@@ -0,0 +1,78 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How long it takes to get attached to a session, and to get its
+//! output replayed.
+
+use std::time;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use libshpool::bench::{Daemon, Replay};
+
+const CONFIG: &str = r#"
+norc = true
+noecho = true
+output_spool_lines = 50000
+"#;
+
+// Fills up the scrollback, then sits there.
+const SCROLLBACK: &str = "/bin/sh -c 'seq 1 50000; echo END; exec cat'";
+
+fn handshake(c: &mut Criterion) {
+    let daemon = Daemon::start(CONFIG).unwrap();
+    // create the session up front, so that every iteration is a reattach
+    daemon.attach("handshake", "/bin/cat", Replay::Off).unwrap().detach().unwrap();
+
+    c.bench_function("attach handshake", |b| {
+        b.iter_custom(|iters| {
+            let mut total = time::Duration::ZERO;
+            for _ in 0..iters {
+                let start = time::Instant::now();
+                let session = daemon.attach("handshake", "/bin/cat", Replay::Off).unwrap();
+                total += start.elapsed();
+                session.detach().unwrap();
+            }
+            total
+        })
+    });
+}
+
+fn replay(c: &mut Criterion) {
+    let daemon = Daemon::start(CONFIG).unwrap();
+    let mut session = daemon.attach("replay", SCROLLBACK, Replay::Off).unwrap();
+    session.read_until(b"END").unwrap();
+    session.detach().unwrap();
+
+    let mut group = c.benchmark_group("replay");
+    for lines in [100, 1_000, 10_000, 50_000] {
+        group.bench_with_input(BenchmarkId::from_parameter(lines), &lines, |b, lines| {
+            b.iter_custom(|iters| {
+                let mut total = time::Duration::ZERO;
+                for _ in 0..iters {
+                    let start = time::Instant::now();
+                    let mut session =
+                        daemon.attach("replay", SCROLLBACK, Replay::Lines(*lines)).unwrap();
+                    session.read_until(b"END").unwrap();
+                    total += start.elapsed();
+                    session.detach().unwrap();
+                }
+                total
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, handshake, replay);
+criterion_main!(benches);
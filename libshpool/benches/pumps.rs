@@ -0,0 +1,84 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How many bytes a second make it through the daemon, in each direction.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use libshpool::bench::{Daemon, Replay};
+
+const PAYLOAD_SIZE: usize = 1024 * 1024;
+
+const CONFIG: &str = r#"
+norc = true
+noecho = true
+"#;
+
+// Swallows a payload worth of input at a time, then says so.
+const SINK: &str = "/bin/sh -c 'stty raw -echo; echo ready; \
+                    while head -c 1048576 > /dev/null; do printf ok; done'";
+
+// Writes out a payload worth of output whenever it gets a byte of input.
+const SOURCE: &str = "/bin/sh -c 'stty raw -echo; echo ready; \
+                      while head -c 1 > /dev/null; do \
+                      yes \"the quick brown fox jumps over the lazy dog\" | head -c 1048576; \
+                      printf END; done'";
+
+fn payload() -> Vec<u8> {
+    b"the quick brown fox jumps over the lazy dog\n"
+        .iter()
+        .copied()
+        .cycle()
+        .take(PAYLOAD_SIZE)
+        .collect()
+}
+
+fn input(c: &mut Criterion) {
+    let payload = payload();
+    let mut group = c.benchmark_group("input");
+    group.throughput(Throughput::Bytes(PAYLOAD_SIZE as u64));
+    for (name, keybindings) in [
+        ("no keybindings", "keybinding = []"),
+        ("default keybindings", ""),
+        ("csi u keybindings", "csi_u_keybindings = true"),
+    ] {
+        let daemon = Daemon::start(&format!("{}\n{}", keybindings, CONFIG)).unwrap();
+        let mut session = daemon.attach("input", SINK, Replay::Off).unwrap();
+        session.read_until(b"ready").unwrap();
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                session.write(&payload).unwrap();
+                session.read_until(b"ok").unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+fn output(c: &mut Criterion) {
+    let mut group = c.benchmark_group("output");
+    group.throughput(Throughput::Bytes(PAYLOAD_SIZE as u64));
+    let daemon = Daemon::start(CONFIG).unwrap();
+    let mut session = daemon.attach("output", SOURCE, Replay::Off).unwrap();
+    session.read_until(b"ready").unwrap();
+    group.bench_function("plain text", |b| {
+        b.iter(|| {
+            session.write(b"x").unwrap();
+            session.read_until(b"END").unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, input, output);
+criterion_main!(benches);
@@ -0,0 +1,102 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+use nix::unistd::{Uid, User};
+
+use super::{
+    protocol,
+    protocol::{Access, AclAction, AclReply, AclRequest, ConnectHeader},
+    AclCommands,
+};
+
+pub fn run(session: String, command: AclCommands, socket: PathBuf) -> anyhow::Result<()> {
+    let action = match command {
+        AclCommands::Show => AclAction::Show,
+        AclCommands::Grant { read_write, user } => AclAction::Grant {
+            uid: resolve_uid(&user)?,
+            access: if read_write { Access::ReadWrite } else { Access::ReadOnly },
+        },
+        AclCommands::Revoke { user } => AclAction::Revoke { uid: resolve_uid(&user)? },
+        AclCommands::Clear => AclAction::Clear,
+    };
+
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require_capability("acl")?;
+    client
+        .write_connect_header(ConnectHeader::Acl(AclRequest { session: session.clone(), action }))
+        .context("writing acl request header")?;
+    let reply: AclReply = client.read_reply().context("reading reply")?;
+
+    match reply {
+        AclReply::Acl(None) => {
+            println!("{} has no acl, every allowed peer has full access", session)
+        }
+        AclReply::Acl(Some(entries)) => {
+            for entry in entries {
+                let access = match entry.access {
+                    Access::ReadOnly => "read-only",
+                    Access::ReadWrite => "read-write",
+                };
+                println!("{}\t{}", user_name(entry.uid), access);
+            }
+        }
+        AclReply::NotFound => {
+            eprintln!("not found: {}", session);
+            return Err(anyhow!("not found: {}", session));
+        }
+        AclReply::Forbidden(reason) => {
+            eprintln!("forbidden: {}", reason);
+            return Err(anyhow!("forbidden: {}", reason));
+        }
+        AclReply::Failed(reason) => {
+            eprintln!("changed the acl, but could not save it: {}", reason);
+            return Err(anyhow!("saving acl: {}", reason));
+        }
+    }
+
+    Ok(())
+}
+
+/// Look up a user given by name or uid.
+fn resolve_uid(user: &str) -> anyhow::Result<u32> {
+    if let Ok(uid) = user.parse::<u32>() {
+        return Ok(uid);
+    }
+    match User::from_name(user).context("looking up user")? {
+        Some(u) => Ok(u.uid.as_raw()),
+        None => Err(anyhow!("no such user: {}", user)),
+    }
+}
+
+/// The name of the user with the given uid, or the uid itself if
+/// they have no passwd entry.
+fn user_name(uid: u32) -> String {
+    match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(u)) => u.name,
+        _ => uid.to_string(),
+    }
+}
@@ -12,30 +12,70 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{env, fmt, io, path::PathBuf, thread, time};
+use std::{
+    env, fmt, fs,
+    io::{self, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+    thread, time,
+};
 
 use anyhow::{anyhow, bail, Context};
+use nix::unistd::isatty;
+use regex::Regex;
 use tracing::{error, info, warn};
 
 use super::{
     config, duration, protocol,
-    protocol::{AttachHeader, ConnectHeader},
-    test_hooks, tty,
+    protocol::{AttachHeader, AttachOption, ConnectHeader},
+    test_hooks, timing, tty,
 };
 
 const MAX_FORCE_RETRIES: usize = 20;
+const DEFAULT_ATTACH_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// How to attach, and what to start the session with if it has to
+/// get created.
+pub struct Request {
+    /// Detach any other terminal from the session first.
+    pub force: bool,
+    pub ttl: Option<String>,
+    pub cmd: Option<String>,
+    pub forward_env: Vec<String>,
+    pub options: Vec<AttachOption>,
+    /// Print how long each phase of the attach took.
+    pub print_timing: bool,
+    /// Leave out the attach banner.
+    pub quiet: bool,
+}
+
+/// Everything an attach attempt needs besides the session name, with
+/// the config loaded and the durations parsed.
+struct Attacher {
+    config: config::Manager,
+    ttl: Option<time::Duration>,
+    cmd: Option<String>,
+    forward_env: Vec<String>,
+    options: Vec<AttachOption>,
+    print_timing: bool,
+    quiet: bool,
+    timeout: time::Duration,
+    socket: PathBuf,
+}
 
 pub fn run(
     config_file: Option<String>,
     name: String,
-    force: bool,
-    ttl: Option<String>,
-    cmd: Option<String>,
+    request: Request,
     socket: PathBuf,
 ) -> anyhow::Result<()> {
+    let Request { force, ttl, cmd, forward_env, options, print_timing, quiet } = request;
     info!("\n\n======================== STARTING ATTACH ============================\n\n");
     test_hooks::emit("attach-startup");
-    SignalHandler::new(name.clone(), socket.clone()).spawn()?;
+    let session_name = Arc::new(Mutex::new(name.clone()));
+    SignalHandler::new(Arc::clone(&session_name), socket.clone()).spawn()?;
 
     let config_manager = config::Manager::new(config_file.as_deref())?;
 
@@ -48,21 +88,75 @@ pub fn run(
         },
         None => None,
     };
+    let timeout = match &config_manager.get().attach_timeout {
+        Some(src) => match duration::parse(src.as_str()) {
+            Ok(d) => d,
+            Err(e) => {
+                bail!("could not parse attach_timeout: {:?}", e);
+            }
+        },
+        None => DEFAULT_ATTACH_TIMEOUT,
+    };
+
+    let attacher = Attacher {
+        config: config_manager,
+        ttl,
+        cmd,
+        forward_env,
+        options,
+        print_timing,
+        quiet,
+        timeout,
+        socket,
+    };
+
+    // Switching jobs hands the terminal over to another session, so
+    // keep going until one of them exits.
+    let mut name = name;
+    loop {
+        let next = match attach_with_retries(&attacher, &name, force)? {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+        info!("switching from '{}' to job '{}'", name, next);
+        *session_name.lock().unwrap() = next.clone();
+        name = next;
+    }
+}
 
+/// Attach to the given session, detaching any other terminal first if
+/// `force` is set. Returns the job to switch to if the daemon asks the
+/// client to switch jobs, and None if the session was busy.
+fn attach_with_retries(
+    attacher: &Attacher,
+    name: &str,
+    force: bool,
+) -> anyhow::Result<Option<String>> {
+    let Attacher { timeout, ref socket, .. } = *attacher;
     let mut detached = false;
     let mut tries = 0;
-    while let Err(err) = do_attach(&config_manager, name.as_str(), &ttl, &cmd, &socket) {
+    loop {
+        let err = match do_attach(attacher, name) {
+            Ok(next) => return Ok(Some(next)),
+            Err(err) => err,
+        };
+        if let Some(timeout_err) = err.downcast_ref::<HandshakeTimeout>() {
+            eprintln!("shpool: {}", timeout_err);
+            eprintln!("shpool: {}", session_state(socket, name, timeout));
+            return Err(err);
+        }
+
         match err.downcast() {
             Ok(BusyError) if !force => {
                 eprintln!("session '{}' already has a terminal attached", name);
-                return Ok(());
+                return Ok(None);
             }
             Ok(BusyError) => {
                 if !detached {
-                    let mut client = dial_client(&socket)?;
+                    let mut client = dial_client(socket, timeout)?;
                     client
                         .write_connect_header(ConnectHeader::Detach(protocol::DetachRequest {
-                            sessions: vec![name.clone()],
+                            sessions: vec![String::from(name)],
                         }))
                         .context("writing detach request header")?;
                     let detach_reply: protocol::DetachReply =
@@ -70,6 +164,10 @@ pub fn run(
                     if !detach_reply.not_found_sessions.is_empty() {
                         warn!("could not find session '{}' to detach it", name);
                     }
+                    if !detach_reply.forbidden_sessions.is_empty() {
+                        eprintln!("forbidden: you have read-only access to '{}'", name);
+                        return Err(anyhow!("could not detach session, forced attach failed"));
+                    }
 
                     detached = true;
                 }
@@ -87,8 +185,6 @@ pub fn run(
             Err(err) => return Err(err),
         }
     }
-
-    Ok(())
 }
 
 #[derive(Debug)]
@@ -100,14 +196,97 @@ impl fmt::Display for BusyError {
 }
 impl std::error::Error for BusyError {}
 
-fn do_attach(
-    config: &config::Manager,
-    name: &str,
-    ttl: &Option<time::Duration>,
-    cmd: &Option<String>,
-    socket: &PathBuf,
-) -> anyhow::Result<()> {
-    let mut client = dial_client(socket)?;
+/// The steps of the attach handshake, used to report where
+/// things got stuck if the daemon stops responding.
+#[derive(Debug, Clone, Copy)]
+enum HandshakePhase {
+    Hello,
+    Header,
+    Reply,
+}
+
+impl fmt::Display for HandshakePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakePhase::Hello => write!(f, "the daemon to answer the protocol hello"),
+            HandshakePhase::Header => write!(f, "the daemon to accept the attach request"),
+            HandshakePhase::Reply => write!(f, "the daemon to set up the session"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HandshakeTimeout {
+    phase: HandshakePhase,
+    timeout: time::Duration,
+}
+impl fmt::Display for HandshakeTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out after {:?} waiting for {}", self.timeout, self.phase)
+    }
+}
+impl std::error::Error for HandshakeTimeout {}
+
+/// Convert an error from the given phase of the handshake into a
+/// HandshakeTimeout if it was caused by the socket timing out.
+fn check_timeout(
+    err: anyhow::Error,
+    phase: HandshakePhase,
+    timeout: time::Duration,
+) -> anyhow::Error {
+    let timed_out = err.chain().any(|e| {
+        // bincode does not expose the io error it wraps as the source
+        let io_err = match e.downcast_ref::<bincode::Error>().map(|b| &**b) {
+            Some(bincode::ErrorKind::Io(io_err)) => io_err,
+            _ => match e.downcast_ref::<io::Error>() {
+                Some(io_err) => io_err,
+                None => return false,
+            },
+        };
+        matches!(io_err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+    });
+    if timed_out {
+        return anyhow::Error::new(HandshakeTimeout { phase, timeout }).context(err);
+    }
+    err
+}
+
+/// Ask the daemon what it thinks the state of the given session is,
+/// for diagnosing a stuck attach.
+fn session_state(socket: &PathBuf, name: &str, timeout: time::Duration) -> String {
+    let query = || -> anyhow::Result<protocol::ListReply> {
+        let mut client = protocol::Client::with_timeout(socket, Some(timeout))?;
+        client.write_connect_header(ConnectHeader::List).context("sending list header")?;
+        client.read_reply().context("reading list reply")
+    };
+    match query() {
+        Ok(reply) => match reply.sessions.iter().find(|s| s.name == name) {
+            Some(session) => {
+                format!("the daemon reports session '{}' as {}", name, session.status)
+            }
+            None => format!("the daemon has no record of session '{}'", name),
+        },
+        Err(e) => format!("could not ask the daemon about session '{}' either: {:#}", name, e),
+    }
+}
+
+fn do_attach(attacher: &Attacher, name: &str) -> anyhow::Result<String> {
+    let Attacher {
+        ref config,
+        ref ttl,
+        ref cmd,
+        ref forward_env,
+        ref options,
+        print_timing,
+        quiet,
+        timeout,
+        ref socket,
+    } = *attacher;
+    let mut timer = timing::Timer::new();
+    let mut client = dial_client(socket, timeout)
+        .map_err(|e| check_timeout(e, HandshakePhase::Hello, timeout))?;
+    // the handshake is the tail end of dialing
+    timer.split_phase("connect", Some(("handshake", client.handshake_time)));
 
     let tty_size = match tty::Size::from_fd(0) {
         Ok(s) => s,
@@ -117,32 +296,75 @@ fn do_attach(
         }
     };
 
-    let forward_env = config.get().forward_env.clone();
-    let mut local_env_keys = vec!["TERM", "DISPLAY", "LANG", "SSH_AUTH_SOCK"];
-    if let Some(fenv) = &forward_env {
-        for var in fenv.iter() {
-            local_env_keys.push(var);
-        }
+    let mut forward_patterns = config.get().forward_env.clone().unwrap_or_default();
+    forward_patterns.extend(forward_env.iter().cloned());
+    let mut local_env = local_env(&forward_patterns);
+
+    // A dumb terminal would just print the escape sequences that raw mode
+    // and the daemon's screen handling depend on, so fall back to plain
+    // pipe mode and make sure the daemon knows the terminal is dumb even
+    // if TERM was not set at all.
+    let term = env::var("TERM").ok();
+    let dumb_term =
+        tty::is_dumb(term.as_deref()) && isatty(io::stdin().as_raw_fd()).unwrap_or(false);
+    if dumb_term {
+        eprintln!(
+            "shpool: warn: TERM is {}, falling back to pipe mode without raw input",
+            term.as_deref().map(|t| format!("'{}'", t)).unwrap_or(String::from("unset"))
+        );
+        local_env.retain(|(var, _)| var != "TERM");
+        local_env.push((String::from("TERM"), String::from("dumb")));
     }
 
+    let header = AttachHeader {
+        name: String::from(name),
+        local_tty_size: tty_size.clone(),
+        local_env,
+        ttl_secs: ttl.map(|d| d.as_secs()),
+        cmd: cmd.clone(),
+    };
+    let mut options = options.to_vec();
+    // An older daemon can't send its side of the timings, but the
+    // client side is still worth having.
+    if print_timing && client.capabilities().iter().any(|c| c == "attach-timings") {
+        options.push(AttachOption::Timings);
+    }
+    // likewise, an older daemon just reports the exit status
+    if client.capabilities().iter().any(|c| c == "exited-screen") {
+        options.push(AttachOption::ExitedScreen);
+    }
+    // Prompt hints are cheap, and asking for them even with local
+    // editing off means the keybinding can turn it on later.
+    if !dumb_term && client.capabilities().iter().any(|c| c == "local-edit") {
+        options.push(AttachOption::PromptHints);
+    }
+    // an older daemon can't fill in the banner, so it goes without
+    let banner = if quiet { None } else { config.get().attach_banner.clone() };
+    let banner = banner.filter(|_| client.capabilities().iter().any(|c| c == "attach-info"));
+    if banner.is_some() {
+        options.push(AttachOption::Info);
+    }
+    // stick to the plain attach when we can so that older daemons
+    // still understand us
+    let header = if options.is_empty() {
+        ConnectHeader::Attach(header)
+    } else {
+        for option in options.iter() {
+            client.require_capability(option.capability())?;
+        }
+        ConnectHeader::AttachWithOptions(header, options)
+    };
     client
-        .write_connect_header(ConnectHeader::Attach(AttachHeader {
-            name: String::from(name),
-            local_tty_size: tty_size,
-            local_env: local_env_keys
-                .into_iter()
-                .filter_map(|var| {
-                    let val = env::var(var).context("resolving var").ok()?;
-                    Some((String::from(var), val))
-                })
-                .collect::<Vec<_>>(),
-            ttl_secs: ttl.map(|d| d.as_secs()),
-            cmd: cmd.clone(),
-        }))
-        .context("writing attach header")?;
-
-    let attach_resp: protocol::AttachReplyHeader =
-        client.read_reply().context("reading attach reply")?;
+        .write_connect_header(header)
+        .context("writing attach header")
+        .map_err(|e| check_timeout(e, HandshakePhase::Header, timeout))?;
+    timer.phase("send header");
+
+    let attach_resp: protocol::AttachReplyHeader = client
+        .read_reply()
+        .context("reading attach reply")
+        .map_err(|e| check_timeout(e, HandshakePhase::Reply, timeout))?;
+    timer.phase("reply");
     info!("attach_resp.status={:?}", attach_resp.status);
 
     {
@@ -167,20 +389,218 @@ fn do_attach(
                 }
                 info!("created a new session: '{}'", name);
             }
+            Exited { exit_status } => {
+                eprintln!("session '{}' exited with status {}", name, exit_status);
+                std::process::exit(exit_status);
+            }
+            ExitedWithScreen { exit_status, screen } => {
+                // process::exit skips flushing stdout
+                let mut stdout = io::stdout();
+                if let Err(e) = stdout.write_all(screen.as_bytes()).and_then(|_| stdout.flush()) {
+                    warn!("printing final screen: {:?}", e);
+                }
+                eprintln!("session '{}' exited with status {}", name, exit_status);
+                std::process::exit(exit_status);
+            }
             UnexpectedError(err) => {
                 return Err(anyhow!("BUG: unexpected error attaching to '{}': {}", name, err));
             }
         }
     }
+    if let Some(template) = banner {
+        let info: protocol::AttachInfo = client
+            .read_reply()
+            .context("reading attach info")
+            .map_err(|e| check_timeout(e, HandshakePhase::Reply, timeout))?;
+        eprintln!("{}", render_banner(&template, name, &info, time::SystemTime::now()));
+    }
+
+    // The daemon drops resize messages for sessions it does not know about
+    // yet, so if the terminal got resized while we were waiting for the
+    // session to get set up, the SIGWINCH would have been lost. Make sure
+    // the session starts out with the right size.
+    if let Ok(current_size) = tty::Size::from_fd(0) {
+        if current_size != tty_size {
+            info!("tty resized during attach ({:?} -> {:?})", tty_size, current_size);
+            if let Err(e) = send_resize(socket, name, current_size) {
+                warn!("resizing session after attach: {:?}", e);
+            }
+        }
+    }
+
+    // the session is up and running, so from here on out it is
+    // perfectly normal for there to be long pauses
+    client.stream.set_read_timeout(None).context("unsetting read timeout")?;
+    client.stream.set_write_timeout(None).context("unsetting write timeout")?;
+
+    let local_edit = config.get().local_edit.unwrap_or(false);
+    match client.pipe_bytes(
+        !dumb_term,
+        local_edit,
+        if print_timing { Some(timer) } else { None },
+    )? {
+        protocol::PipeEnd::Exit(exit_status) => std::process::exit(exit_status),
+        protocol::PipeEnd::SwitchJob(protocol::JobTarget::Session(next)) => Ok(next),
+        protocol::PipeEnd::SwitchJob(protocol::JobTarget::Next) => next_job(socket, name, timeout),
+    }
+}
+
+/// Find the job after the given one, wrapping back around to the
+/// session's own shell after the last one.
+fn next_job(socket: &PathBuf, name: &str, timeout: time::Duration) -> anyhow::Result<String> {
+    let mut client = dial_client(socket, timeout)?;
+    client.require_capability("jobs")?;
+    client
+        .write_connect_header(ConnectHeader::Jobs(String::from(name)))
+        .context("writing jobs request header")?;
+    let reply: protocol::JobsReply = client.read_reply().context("reading jobs reply")?;
+    let current = reply.jobs.iter().position(|j| j.name == name);
+    let next = match current {
+        Some(i) => reply.jobs.get(i + 1).or(reply.jobs.first()),
+        None => reply.jobs.first(),
+    };
+    Ok(next.map(|j| j.name.clone()).unwrap_or(String::from(name)))
+}
+
+/// Fill in the placeholders in an attach_banner template. Anything in
+/// braces that isn't a known placeholder is left alone.
+fn render_banner(
+    template: &str,
+    name: &str,
+    info: &protocol::AttachInfo,
+    now: time::SystemTime,
+) -> String {
+    let started_at =
+        time::UNIX_EPOCH + time::Duration::from_millis(info.started_at_unix_ms.max(0) as u64);
+    let age = now.duration_since(started_at).unwrap_or_default();
+    let placeholder = Regex::new(r"\{(\w+)\}").unwrap();
+    placeholder
+        .replace_all(template, |caps: &regex::Captures| match &caps[1] {
+            "session" => String::from(name),
+            "host" => info.host.clone(),
+            "age" => duration::format_coarse(age),
+            "attaches" => info.attaches.to_string(),
+            _ => String::from(&caps[0]),
+        })
+        .into_owned()
+}
+
+/// Collect the attach options that differ from the defaults. A relative
+/// cwd gets resolved here, since the daemon has a cwd of its own, unless
+/// the session is going to live in some other process's namespaces, in
+/// which case it has to be absolute.
+pub fn options(
+    replay: protocol::Replay,
+    cwd: Option<String>,
+    term: Option<String>,
+    on_attach_cmd: Option<String>,
+    nsenter_pid: Option<i32>,
+    restart: bool,
+) -> anyhow::Result<Vec<AttachOption>> {
+    let mut options = vec![];
+    if replay != protocol::Replay::Default {
+        options.push(AttachOption::Replay(replay));
+    }
+    if let Some(pid) = nsenter_pid {
+        options.push(AttachOption::NsenterPid(pid));
+        if let Some(cwd) = &cwd {
+            if !Path::new(cwd).is_absolute() {
+                bail!("--cwd {} must be absolute when entering a container", cwd);
+            }
+        }
+        options.extend(cwd.map(AttachOption::Cwd));
+    } else if let Some(cwd) = cwd {
+        let path = fs::canonicalize(&cwd).with_context(|| format!("resolving --cwd {}", cwd))?;
+        if !path.is_dir() {
+            bail!("--cwd {} is not a directory", cwd);
+        }
+        let path =
+            path.into_os_string().into_string().map_err(|p| anyhow!("non-utf8 --cwd {:?}", p))?;
+        options.push(AttachOption::Cwd(path));
+    }
+    if let Some(term) = term {
+        if term.is_empty() {
+            bail!("--term can't be empty");
+        }
+        options.push(AttachOption::Term(term));
+    }
+    if let Some(cmd) = on_attach_cmd {
+        options.push(AttachOption::OnAttachCmd(cmd));
+    }
+    if restart {
+        options.push(AttachOption::Restart);
+    }
+    Ok(options)
+}
 
-    match client.pipe_bytes() {
-        Ok(exit_status) => std::process::exit(exit_status),
-        Err(e) => Err(e),
+/// Find the pid whose namespaces a new session should start in, given
+/// either a container as `<runtime>:<id>` or the pid itself. The
+/// container runtime gets asked for the pid of the container's init
+/// process, so the container has to be running.
+pub fn nsenter_pid(container: Option<String>, pid: Option<i32>) -> anyhow::Result<Option<i32>> {
+    let container = match container {
+        Some(c) => c,
+        None => return Ok(pid),
+    };
+    let (runtime, id) = match container.split_once(':') {
+        Some((runtime @ ("docker" | "podman"), id)) if !id.is_empty() => (runtime, id),
+        _ => bail!("--container {} should look like docker:<id> or podman:<id>", container),
+    };
+
+    let out = process::Command::new(runtime)
+        .args(["inspect", "--format", "{{.State.Pid}}", id])
+        .stderr(process::Stdio::inherit())
+        .output()
+        .with_context(|| format!("running {} inspect", runtime))?;
+    if !out.status.success() {
+        bail!("{} inspect {} failed", runtime, id);
+    }
+    let pid: i32 = String::from_utf8_lossy(&out.stdout)
+        .trim()
+        .parse()
+        .with_context(|| format!("parsing pid of container {}", id))?;
+    if pid <= 0 {
+        bail!("container {} is not running", id);
     }
+    info!("container {} has pid {}", container, pid);
+
+    Ok(Some(pid))
 }
 
-fn dial_client(socket: &PathBuf) -> anyhow::Result<protocol::Client> {
-    match protocol::Client::new(socket) {
+/// Collect the subset of the local environment that should be shipped
+/// to the daemon. A few variables are always forwarded, and the rest
+/// are selected by the given patterns, which are either exact variable
+/// names or a prefix followed by a trailing '*'.
+pub fn local_env(forward_patterns: &[String]) -> Vec<(String, String)> {
+    let mut local_env: Vec<(String, String)> = vec![];
+    let mut push = |var: String, val: String| {
+        if !local_env.iter().any(|(k, _)| *k == var) {
+            local_env.push((var, val));
+        }
+    };
+
+    for var in ["TERM", "DISPLAY", "LANG", "SSH_AUTH_SOCK"] {
+        if let Ok(val) = env::var(var) {
+            push(String::from(var), val);
+        }
+    }
+    for pattern in forward_patterns.iter() {
+        if let Some(prefix) = pattern.strip_suffix('*') {
+            for (var, val) in env::vars() {
+                if var.starts_with(prefix) {
+                    push(var, val);
+                }
+            }
+        } else if let Ok(val) = env::var(pattern) {
+            push(pattern.clone(), val);
+        }
+    }
+
+    local_env
+}
+
+fn dial_client(socket: &PathBuf, timeout: time::Duration) -> anyhow::Result<protocol::Client> {
+    match protocol::Client::with_timeout(socket, Some(timeout)) {
         Ok(c) => Ok(c),
         Err(err) => {
             let io_err = err.downcast::<io::Error>()?;
@@ -197,25 +617,28 @@ fn dial_client(socket: &PathBuf) -> anyhow::Result<protocol::Client> {
 //
 
 struct SignalHandler {
-    session_name: String,
+    // switching jobs changes which session the terminal belongs to
+    session_name: Arc<Mutex<String>>,
     socket: PathBuf,
 }
 
 impl SignalHandler {
-    fn new(session_name: String, socket: PathBuf) -> Self {
+    fn new(session_name: Arc<Mutex<String>>, socket: PathBuf) -> Self {
         SignalHandler { session_name, socket }
     }
 
     fn spawn(self) -> anyhow::Result<()> {
         use signal_hook::{consts::*, iterator::*};
 
-        let sigs = vec![SIGWINCH];
+        let sigs = vec![SIGWINCH, SIGTSTP, SIGCONT];
         let mut signals = Signals::new(sigs).context("creating signal iterator")?;
 
         thread::spawn(move || {
             for signal in &mut signals {
                 let res = match signal {
                     SIGWINCH => self.handle_sigwinch(),
+                    SIGTSTP => self.handle_sigtstp(),
+                    SIGCONT => self.handle_sigcont(),
                     sig => {
                         error!("unknown signal: {}", sig);
                         panic!("unknown signal: {}", sig);
@@ -232,40 +655,139 @@ impl SignalHandler {
 
     fn handle_sigwinch(&self) -> anyhow::Result<()> {
         info!("handle_sigwinch: enter");
-        let mut client = protocol::Client::new(&self.socket)?;
-
         let tty_size = tty::Size::from_fd(0).context("getting tty size")?;
         info!("handle_sigwinch: tty_size={:?}", tty_size);
 
-        // write the request on a new, seperate connection
-        client
-            .write_connect_header(protocol::ConnectHeader::SessionMessage(
-                protocol::SessionMessageRequest {
-                    session_name: self.session_name.clone(),
-                    payload: protocol::SessionMessageRequestPayload::Resize(
-                        protocol::ResizeRequest { tty_size: tty_size.clone() },
-                    ),
-                },
-            ))
-            .context("writing resize request")?;
-
-        let reply: protocol::SessionMessageReply =
-            client.read_reply().context("reading session message reply")?;
-        match reply {
-            protocol::SessionMessageReply::NotFound => {
-                warn!(
-                    "handle_sigwinch: sent resize for session '{}', but the daemon has no record of that session",
-                    self.session_name
-                );
-            }
-            protocol::SessionMessageReply::Resize(protocol::ResizeReply::Ok) => {
-                info!("handle_sigwinch: resized session '{}' to {:?}", self.session_name, tty_size);
-            }
-            reply => {
-                warn!("handle_sigwinch: unexpected resize reply: {:?}", reply);
-            }
+        let session_name = self.session_name.lock().unwrap().clone();
+        send_resize(&self.socket, &session_name, tty_size)
+    }
+
+    fn handle_sigtstp(&self) -> anyhow::Result<()> {
+        info!("handle_sigtstp: suspending");
+        // leave the terminal usable for whatever gets it next
+        tty::suspend_attach_flags()?;
+        signal_hook::low_level::emulate_default_handler(signal_hook::consts::SIGTSTP)
+            .context("stopping")
+    }
+
+    fn handle_sigcont(&self) -> anyhow::Result<()> {
+        info!("handle_sigcont: enter");
+        if !tty::resume_attach_flags()? {
+            info!("handle_sigcont: not in raw mode in the foreground, leaving the terminal be");
+            return Ok(());
         }
 
-        Ok(())
+        // Whatever ran while we were stopped has scribbled all over the
+        // screen, and it may well have been resized too.
+        let session_name = self.session_name.lock().unwrap().clone();
+        let tty_size = tty::Size::from_fd(0).context("getting tty size")?;
+        send_resize(&self.socket, &session_name, tty_size)?;
+        send_redraw(&self.socket, &session_name)
+    }
+}
+
+/// Ask the daemon to redraw the screen of the client attached to the
+/// given session. An older daemon can't, so the screen stays garbled
+/// until the session draws over it.
+fn send_redraw(socket: &PathBuf, session_name: &str) -> anyhow::Result<()> {
+    let mut client = protocol::Client::new(socket)?;
+    if !client.capabilities().iter().any(|c| c == "redraw") {
+        info!("send_redraw: the daemon does not support redrawing");
+        return Ok(());
+    }
+
+    client
+        .write_connect_header(protocol::ConnectHeader::SessionMessage(
+            protocol::SessionMessageRequest {
+                session_name: String::from(session_name),
+                payload: protocol::SessionMessageRequestPayload::Redraw,
+            },
+        ))
+        .context("writing redraw request")?;
+
+    let reply: protocol::SessionMessageReply =
+        client.read_reply().context("reading session message reply")?;
+    match reply {
+        protocol::SessionMessageReply::Redraw => {
+            info!("send_redraw: redrew session '{}'", session_name);
+        }
+        reply => {
+            warn!("send_redraw: unexpected redraw reply: {:?}", reply);
+        }
+    }
+
+    Ok(())
+}
+
+/// Tell the daemon to resize the pty for the given session. The daemon
+/// applies the new size to the pty, which causes the kernel to deliver
+/// SIGWINCH to the foreground process in the session.
+pub fn send_resize(
+    socket: &PathBuf,
+    session_name: &str,
+    tty_size: tty::Size,
+) -> anyhow::Result<()> {
+    let mut client = protocol::Client::new(socket)?;
+
+    // write the request on a new, seperate connection
+    client
+        .write_connect_header(protocol::ConnectHeader::SessionMessage(
+            protocol::SessionMessageRequest {
+                session_name: String::from(session_name),
+                payload: protocol::SessionMessageRequestPayload::Resize(protocol::ResizeRequest {
+                    tty_size: tty_size.clone(),
+                }),
+            },
+        ))
+        .context("writing resize request")?;
+
+    let reply: protocol::SessionMessageReply =
+        client.read_reply().context("reading session message reply")?;
+    match reply {
+        protocol::SessionMessageReply::NotFound => {
+            warn!(
+                "send_resize: sent resize for session '{}', but the daemon has no record of that session",
+                session_name
+            );
+        }
+        protocol::SessionMessageReply::Resize(protocol::ResizeReply::Ok) => {
+            info!("send_resize: resized session '{}' to {:?}", session_name, tty_size);
+        }
+        reply => {
+            warn!("send_resize: unexpected resize reply: {:?}", reply);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn banner() {
+        let info = protocol::AttachInfo {
+            host: String::from("devbox"),
+            started_at_unix_ms: 1_000_000,
+            attaches: 2,
+        };
+        let now = time::UNIX_EPOCH + time::Duration::from_millis(1_000_000 + 3 * 60 * 60 * 1000);
+        assert_eq!(
+            render_banner(
+                "attached to '{session}' on {host}, created {age} ago, {attaches} previous attaches",
+                "main",
+                &info,
+                now
+            ),
+            "attached to 'main' on devbox, created 3h ago, 2 previous attaches"
+        );
+        // a session named like a placeholder stays put
+        assert_eq!(
+            render_banner("{session} {nope} {host}", "{host}", &info, now),
+            "{host} {nope} devbox"
+        );
     }
 }
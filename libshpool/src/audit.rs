@@ -0,0 +1,319 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Encrypted session recordings for environments that need a full
+  audit trail, and the `shpool audit-dump` command for reading them.
+
+  A recording starts with a magic string and a random salt, followed
+  by a sequence of length prefixed records. Each record is sealed with
+  ChaCha20-Poly1305 under a key derived from the configured key and the
+  salt, so no two recordings ever share a key, and the nonce is just
+  the index of the record, so records can't be reordered or dropped
+  from the middle of a recording without detection.
+*/
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process, time,
+};
+
+use anyhow::{anyhow, bail, Context};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ring::{aead, hkdf, rand, rand::SecureRandom};
+use serde_derive::{Deserialize, Serialize};
+
+use super::{common, config};
+
+const MAGIC: &[u8] = b"SHPOOLAUDIT1";
+const SALT_LEN: usize = 32;
+const KEY_LEN: usize = 32;
+const HKDF_INFO: &[u8] = b"shpool session audit";
+
+/// The warning shown to the user every time they attach to a session
+/// that is being recorded.
+pub const WARNING: &str =
+    "this session is being recorded for audit, including everything you type except passwords";
+
+/// A single event in a recording.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Milliseconds since the recording started.
+    pub at_ms: u64,
+    pub event: Event,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Bytes the user typed, as written to the shell.
+    Input(Vec<u8>),
+    /// Input typed while the terminal had echo turned off for a
+    /// password prompt. Only the number of bytes is kept.
+    RedactedInput(usize),
+    /// Bytes the shell wrote to the terminal.
+    Output(Vec<u8>),
+}
+
+/// Run the configured key command and parse the key out of its output,
+/// which must be 64 hex digits.
+pub fn fetch_key(key_cmd: &str) -> anyhow::Result<[u8; KEY_LEN]> {
+    let out = process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(key_cmd)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()
+        .context("running key command")?;
+    if !out.status.success() {
+        bail!("key command exited with {}", out.status);
+    }
+
+    let hex = String::from_utf8(out.stdout).context("key command output is not utf8")?;
+    let hex = hex.trim();
+    if hex.len() != KEY_LEN * 2 {
+        bail!("key command must print {} hex digits, got {}", KEY_LEN * 2, hex.len());
+    }
+    let mut key = [0; KEY_LEN];
+    key.copy_from_slice(&common::decode_hex(hex).context("parsing key command output")?);
+
+    Ok(key)
+}
+
+fn derive_key(key: &[u8; KEY_LEN], salt: &[u8]) -> anyhow::Result<aead::LessSafeKey> {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(key);
+    let okm = prk
+        .expand(&[HKDF_INFO], &aead::CHACHA20_POLY1305)
+        .map_err(|_| anyhow!("deriving recording key"))?;
+    Ok(aead::LessSafeKey::new(aead::UnboundKey::from(okm)))
+}
+
+fn nonce(counter: u64) -> aead::Nonce {
+    let mut nonce = [0; aead::NONCE_LEN];
+    nonce[aead::NONCE_LEN - 8..].copy_from_slice(&counter.to_be_bytes());
+    aead::Nonce::assume_unique_for_key(nonce)
+}
+
+/// Writes the recording for a single session.
+pub struct Recorder {
+    file: io::BufWriter<fs::File>,
+    key: aead::LessSafeKey,
+    counter: u64,
+    started: time::Instant,
+    pub path: PathBuf,
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder").field("path", &self.path).finish()
+    }
+}
+
+impl Recorder {
+    /// Start a new recording for the given session in the configured
+    /// directory, or audit in the given state dir if none is configured,
+    /// creating the directory if needed.
+    pub fn create(
+        config: &config::SessionAudit,
+        state_dir: &Path,
+        session_name: &str,
+    ) -> anyhow::Result<Self> {
+        let key = fetch_key(&config.key_cmd)?;
+
+        let dir = match &config.dir {
+            Some(dir) => PathBuf::from(dir),
+            None => state_dir.join("audit"),
+        };
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("creating audit dir {:?}", dir))?;
+        let started_at =
+            time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default();
+        let path = dir.join(format!("{}-{}.shpoolaudit", session_name, started_at.as_millis()));
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("creating recording {:?}", path))?;
+
+        let mut salt = [0; SALT_LEN];
+        rand::SystemRandom::new().fill(&mut salt).map_err(|_| anyhow!("generating salt"))?;
+        let mut file = io::BufWriter::new(file);
+        file.write_all(MAGIC).context("writing magic")?;
+        file.write_all(&salt).context("writing salt")?;
+        file.flush().context("flushing recording header")?;
+
+        Ok(Recorder {
+            file,
+            key: derive_key(&key, &salt)?,
+            counter: 0,
+            started: time::Instant::now(),
+            path,
+        })
+    }
+
+    /// Append an event to the recording. Every record gets flushed
+    /// right away so that the recording is complete up to the last
+    /// event even if the daemon gets killed.
+    pub fn record(&mut self, event: Event) -> anyhow::Result<()> {
+        let record = Record { at_ms: self.started.elapsed().as_millis() as u64, event };
+        let mut buf = bincode::serialize(&record).context("serializing record")?;
+        self.key
+            .seal_in_place_append_tag(nonce(self.counter), aead::Aad::empty(), &mut buf)
+            .map_err(|_| anyhow!("sealing record"))?;
+        self.counter += 1;
+
+        self.file.write_u32::<LittleEndian>(buf.len() as u32).context("writing record length")?;
+        self.file.write_all(&buf).context("writing record")?;
+        self.file.flush().context("flushing record")?;
+        Ok(())
+    }
+}
+
+/// Decrypt every record in a recording.
+pub fn read<R: Read>(mut r: R, key: &[u8; KEY_LEN]) -> anyhow::Result<Vec<Record>> {
+    let mut magic = [0; MAGIC.len()];
+    r.read_exact(&mut magic).context("reading magic")?;
+    if magic != MAGIC {
+        bail!("not a shpool recording");
+    }
+    let mut salt = [0; SALT_LEN];
+    r.read_exact(&mut salt).context("reading salt")?;
+    let key = derive_key(key, &salt)?;
+
+    let mut records = vec![];
+    let mut counter = 0;
+    loop {
+        let len = match r.read_u32::<LittleEndian>() {
+            Ok(l) => l as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e).context("reading record length"),
+        };
+        let mut buf = vec![0; len];
+        r.read_exact(&mut buf).context("reading record")?;
+        let plain = key
+            .open_in_place(nonce(counter), aead::Aad::empty(), &mut buf)
+            .map_err(|_| anyhow!("record {} failed to decrypt, wrong key or tampered", counter))?;
+        records.push(bincode::deserialize(plain).context("parsing record")?);
+        counter += 1;
+    }
+
+    Ok(records)
+}
+
+pub fn run(config_file: Option<String>, file: String) -> anyhow::Result<()> {
+    let config_manager = config::Manager::new(config_file.as_deref())?;
+    let key_cmd = match &config_manager.get().session_audit {
+        Some(audit) => audit.key_cmd.clone(),
+        None => {
+            eprintln!("shpool: no session_audit key_cmd configured");
+            bail!("no session_audit config");
+        }
+    };
+
+    let res = fetch_key(&key_cmd).and_then(|key| {
+        let f = fs::File::open(&file).with_context(|| format!("opening {}", file))?;
+        read(io::BufReader::new(f), &key)
+    });
+    let records = match res {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("shpool: {:#}", e);
+            return Err(e);
+        }
+    };
+
+    let mut stdout = io::stdout().lock();
+    for record in records.into_iter() {
+        let secs = record.at_ms as f64 / 1000.0;
+        match record.event {
+            Event::Input(buf) => {
+                writeln!(stdout, "{:.3} in  {:?}", secs, String::from_utf8_lossy(&buf))
+            }
+            Event::RedactedInput(len) => {
+                writeln!(stdout, "{:.3} in  <{} bytes redacted>", secs, len)
+            }
+            Event::Output(buf) => {
+                writeln!(stdout, "{:.3} out {:?}", secs, String::from_utf8_lossy(&buf))
+            }
+        }
+        .context("writing record")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn round_trip() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::with_prefix("shpool-audit")?;
+        let key_hex = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        let config = config::SessionAudit { dir: None, key_cmd: format!("echo {}", key_hex) };
+
+        let mut recorder = Recorder::create(&config, dir.path(), "sh1")?;
+        assert!(recorder.path.starts_with(dir.path().join("audit")));
+        recorder.record(Event::Output(b"$ ".to_vec()))?;
+        recorder.record(Event::Input(b"sudo true\r".to_vec()))?;
+        recorder.record(Event::RedactedInput(7))?;
+        let path = recorder.path.clone();
+        drop(recorder);
+
+        let key = fetch_key(&config.key_cmd)?;
+        let records = read(fs::File::open(&path)?, &key)?;
+        let events = records.into_iter().map(|r| r.event).collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                Event::Output(b"$ ".to_vec()),
+                Event::Input(b"sudo true\r".to_vec()),
+                Event::RedactedInput(7),
+            ]
+        );
+
+        // the plaintext should not show up anywhere in the file
+        let raw = fs::read(&path)?;
+        assert!(!raw.windows(4).any(|w| w == b"sudo"));
+
+        // a different key can't read it
+        let mut wrong_key = key;
+        wrong_key[0] ^= 1;
+        assert!(read(fs::File::open(&path)?, &wrong_key).is_err());
+
+        // and neither can anyone after a bit gets flipped
+        let mut tampered = raw.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(read(&tampered[..], &key).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn bad_keys() {
+        assert!(fetch_key("echo 0011").is_err());
+        assert!(fetch_key("false").is_err());
+        assert!(fetch_key(&format!("echo {}", "zz".repeat(32))).is_err());
+        assert!(fetch_key(&format!("echo {}", "ab".repeat(32))).is_ok());
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! A bare bones daemon and client for the benchmarks in libshpool/benches.
+
+  The benchmarks want to measure the daemon doing real work, so they
+  run a daemon in the background of the benchmark process and talk to
+  it over its socket like `shpool attach` would, just without a
+  terminal on the client end. Nothing in here is a stable API.
+*/
+
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+    thread, time,
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    consts,
+    protocol::{self, AttachHeader, AttachOption, ConnectHeader},
+    tty,
+};
+
+pub use crate::protocol::Replay;
+
+// How long to wait for the daemon to come up, or for a session
+// that is still busy with an old client to free up.
+const STARTUP_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+const RETRY_POLL: time::Duration = time::Duration::from_millis(5);
+
+/// A daemon running on a thread of the calling process. It keeps
+/// running until the process exits.
+pub struct Daemon {
+    socket: PathBuf,
+    // hang on to the dir so the socket and runtime data live as long
+    // as we do
+    _dir: tempfile::TempDir,
+}
+
+impl Daemon {
+    /// Start a daemon with the given config, in the same format as
+    /// config.toml.
+    pub fn start(config: &str) -> anyhow::Result<Self> {
+        let config: crate::Config = toml::from_str(config).context("parsing config")?;
+        let dir = tempfile::Builder::new()
+            .prefix("shpool-bench")
+            .tempdir()
+            .context("creating tmp dir")?;
+        let socket = dir.path().join("shpool.socket");
+
+        let runtime_dir = dir.path().to_path_buf();
+        let daemon_socket = socket.clone();
+        thread::spawn(move || {
+            let res = crate::Daemon::builder()
+                .config(config)
+                .runtime_dir(runtime_dir)
+                .socket(daemon_socket)
+                .build()
+                .and_then(|daemon| daemon.run());
+            if let Err(e) = res {
+                eprintln!("bench daemon: {:?}", e);
+            }
+        });
+
+        let start = time::Instant::now();
+        while protocol::Client::new(&socket).is_err() {
+            if start.elapsed() > STARTUP_TIMEOUT {
+                return Err(anyhow!("daemon never came up"));
+            }
+            thread::sleep(RETRY_POLL);
+        }
+
+        Ok(Daemon { socket, _dir: dir })
+    }
+
+    /// Attach to the named session, creating it to run the given
+    /// command if it does not exist yet. Returns once the daemon has
+    /// replied to the attach, without waiting for any output. If the
+    /// session still has a client attached, this waits for it to let go.
+    pub fn attach(&self, name: &str, cmd: &str, replay: Replay) -> anyhow::Result<Attached> {
+        let start = time::Instant::now();
+        loop {
+            let mut client = protocol::Client::new(&self.socket)?;
+            let header = AttachHeader {
+                name: String::from(name),
+                local_tty_size: tty::Size { rows: 24, cols: 80, xpixel: 0, ypixel: 0 },
+                local_env: vec![(String::from("TERM"), String::from("xterm"))],
+                ttl_secs: None,
+                cmd: Some(String::from(cmd)),
+            };
+            client
+                .write_connect_header(ConnectHeader::AttachWithOptions(
+                    header,
+                    vec![AttachOption::Replay(replay)],
+                ))
+                .context("writing attach header")?;
+            let reply: protocol::AttachReplyHeader =
+                client.read_reply().context("reading attach reply")?;
+
+            match reply.status {
+                protocol::AttachStatus::Attached { .. }
+                | protocol::AttachStatus::Created { .. } => {
+                    return Ok(Attached {
+                        client,
+                        name: String::from(name),
+                        socket: self.socket.clone(),
+                        buf: vec![0; consts::BUF_SIZE],
+                    });
+                }
+                protocol::AttachStatus::Busy if start.elapsed() < STARTUP_TIMEOUT => {
+                    thread::sleep(RETRY_POLL)
+                }
+                status => return Err(anyhow!("attaching to '{}': {:?}", name, status)),
+            }
+        }
+    }
+}
+
+/// A client attached to a session.
+pub struct Attached {
+    client: protocol::Client,
+    name: String,
+    socket: PathBuf,
+    buf: Vec<u8>,
+}
+
+impl Attached {
+    /// Send input along to the session.
+    pub fn write(&mut self, input: &[u8]) -> anyhow::Result<()> {
+        self.client.stream.write_all(input).context("writing input")?;
+        self.client.stream.flush().context("flushing input")
+    }
+
+    /// Read output until the given marker shows up, returning the
+    /// number of bytes of output read, including the marker.
+    pub fn read_until(&mut self, marker: &[u8]) -> anyhow::Result<usize> {
+        let mut total = 0;
+        // the tail end of the output so far, in case the marker gets
+        // split across chunks
+        let mut tail = vec![];
+        loop {
+            let chunk = protocol::Chunk::read_into(&mut self.client.stream, &mut self.buf)
+                .context("reading output chunk")?;
+            match chunk.kind {
+                protocol::ChunkKind::Data => {}
+                protocol::ChunkKind::ExitStatus => return Err(anyhow!("session exited")),
+                _ => continue,
+            }
+            total += chunk.buf.len();
+
+            tail.extend_from_slice(chunk.buf);
+            if tail.windows(marker.len()).any(|w| w == marker) {
+                return Ok(total);
+            }
+            let keep = tail.len().min(marker.len());
+            tail.drain(..tail.len() - keep);
+        }
+    }
+
+    /// Detach from the session, waiting until the daemon has hung up
+    /// on us so that the session is free for the next attach.
+    pub fn detach(mut self) -> anyhow::Result<()> {
+        let mut client = protocol::Client::new(&self.socket)?;
+        client
+            .write_connect_header(ConnectHeader::Detach(protocol::DetachRequest {
+                sessions: vec![self.name.clone()],
+            }))
+            .context("writing detach header")?;
+        let _: protocol::DetachReply = client.read_reply().context("reading detach reply")?;
+
+        // drain whatever output was still on the way
+        while let Ok(len) = self.client.stream.read(&mut self.buf) {
+            if len == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    protocol,
+    protocol::{CaptureMode, CaptureReply, CaptureRequest, ConnectHeader},
+};
+
+pub fn run(
+    session: String,
+    ansi: bool,
+    bytes: Option<usize>,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    let mode = match bytes {
+        Some(bytes) => CaptureMode::Raw { bytes },
+        None => CaptureMode::Screen { ansi },
+    };
+
+    client.require_capability("capture")?;
+    client
+        .write_connect_header(ConnectHeader::Capture(CaptureRequest {
+            session: session.clone(),
+            mode,
+        }))
+        .context("writing capture request header")?;
+    let reply: CaptureReply = client.read_reply().context("reading reply")?;
+
+    match reply {
+        CaptureReply::Output(output) => {
+            io::stdout().write_all(&output).context("writing output")?;
+        }
+        CaptureReply::NotFound => {
+            eprintln!("not found: {}", session);
+            return Err(anyhow!("not found: {}", session));
+        }
+        CaptureReply::NoSpool => {
+            eprintln!(
+                "no screen kept for {}, the daemon is using session_restore_mode = \"simple\"",
+                session
+            );
+            return Err(anyhow!("no output spool"));
+        }
+        CaptureReply::Forbidden(reason) => {
+            eprintln!("forbidden: {}", reason);
+            return Err(anyhow!("forbidden: {}", reason));
+        }
+    }
+
+    Ok(())
+}
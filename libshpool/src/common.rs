@@ -16,7 +16,7 @@
 
 use std::env;
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail, Context};
 
 pub fn resolve_sessions(sessions: &mut Vec<String>, action: &str) -> anyhow::Result<()> {
     if sessions.is_empty() {
@@ -32,3 +32,17 @@ pub fn resolve_sessions(sessions: &mut Vec<String>, action: &str) -> anyhow::Res
 
     Ok(())
 }
+
+/// Decode a string of hex digits, like the keys and signatures that
+/// show up in the config.
+pub fn decode_hex(src: &str) -> anyhow::Result<Vec<u8>> {
+    if src.len() % 2 != 0 || !src.is_ascii() {
+        bail!("'{}' is not an even number of hex digits", src);
+    }
+    (0..src.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&src[i..i + 2], 16).with_context(|| format!("'{}' is not hex", src))
+        })
+        .collect()
+}
@@ -0,0 +1,329 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Shell completion scripts.
+
+  clap_complete knows all the subcommands and flags, but has no way to
+  complete the names of running sessions, so each script gets a little
+  extra shell code bolted on that asks the daemon for the names with
+  `shpool list --names-only` whenever the cursor is somewhere a
+  session name goes.
+*/
+
+use std::io::{self, Write};
+
+use anyhow::Context;
+use clap::CommandFactory;
+use clap_complete::{generate, shells};
+
+use super::{Args, CompletionShell};
+
+const BIN_NAME: &str = "shpool";
+
+/// The names of the positional args that take session names.
+const SESSION_ARGS: [&str; 3] = ["name", "session", "sessions"];
+
+pub fn run(shell: CompletionShell) -> anyhow::Result<()> {
+    io::stdout().write_all(script(shell).as_bytes()).context("writing completion script")?;
+    Ok(())
+}
+
+fn script(shell: CompletionShell) -> String {
+    let mut cmd = Args::command();
+    let slots = SessionSlots::new(&cmd);
+    let mut buf = vec![];
+    match shell {
+        CompletionShell::Bash => {
+            generate(shells::Bash, &mut cmd, BIN_NAME, &mut buf);
+            let mut script = String::from_utf8_lossy(&buf).into_owned();
+            script.push_str(&slots.fill(BASH_SESSIONS, "|"));
+            script
+        }
+        CompletionShell::Zsh => {
+            generate(shells::Zsh, &mut cmd, BIN_NAME, &mut buf);
+            let script = String::from_utf8_lossy(&buf);
+            // zsh already knows where each positional arg goes, so the
+            // session args just need an action to complete them with.
+            let mut script = script
+                .lines()
+                .map(|line| {
+                    let is_session_arg = SESSION_ARGS.iter().any(|arg| {
+                        line.starts_with(&format!("':{} -- ", arg))
+                            || line.starts_with(&format!("'*::{} -- ", arg))
+                    });
+                    match line.strip_suffix(":' \\") {
+                        Some(spec) if is_session_arg => format!("{}:_shpool_sessions' \\\n", spec),
+                        _ => format!("{}\n", line),
+                    }
+                })
+                .collect::<String>();
+            // The function has to be defined before the bit at the end
+            // that runs the completion when zsh autoloads the file.
+            let tail = script.rfind("\nif [ \"$funcstack[1]\"").unwrap_or(script.len());
+            script.insert_str(tail, &slots.fill(ZSH_SESSIONS, "|"));
+            script
+        }
+        CompletionShell::Fish => {
+            generate(shells::Fish, &mut cmd, BIN_NAME, &mut buf);
+            let mut script = String::from_utf8_lossy(&buf).into_owned();
+            script.push_str(&slots.fill(FISH_SESSIONS, " "));
+            script
+        }
+    }
+}
+
+/// Where session names go on the command line.
+struct SessionSlots {
+    /// Subcommands that take a single session name as their first
+    /// positional arg.
+    single: Vec<String>,
+    /// Subcommands whose positional args are all session names.
+    multi: Vec<String>,
+    /// Flags that take a value, which needs to be skipped over when
+    /// counting positional args.
+    value_flags: Vec<String>,
+}
+
+impl SessionSlots {
+    fn new(cmd: &clap::Command) -> Self {
+        let mut slots = SessionSlots { single: vec![], multi: vec![], value_flags: vec![] };
+        slots.add_value_flags(cmd);
+        for sub in cmd.get_subcommands() {
+            slots.add_value_flags(sub);
+            if sub.is_hide_set() {
+                continue;
+            }
+            let first = match sub.get_positionals().next() {
+                Some(arg) => arg,
+                None => continue,
+            };
+            if !SESSION_ARGS.contains(&first.get_id().as_str()) {
+                continue;
+            }
+            let many = first.get_num_args().map(|n| n.max_values() > 1).unwrap_or(false);
+            if many {
+                slots.multi.push(sub.get_name().to_string());
+            } else {
+                slots.single.push(sub.get_name().to_string());
+            }
+        }
+        slots
+    }
+
+    fn add_value_flags(&mut self, cmd: &clap::Command) {
+        for arg in cmd.get_opts().filter(|arg| arg.get_action().takes_values()) {
+            let flags = arg
+                .get_short_and_visible_aliases()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|s| format!("-{}", s))
+                .chain(
+                    arg.get_long_and_visible_aliases()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|l| format!("--{}", l)),
+                );
+            for flag in flags {
+                if !self.value_flags.contains(&flag) {
+                    self.value_flags.push(flag);
+                }
+            }
+        }
+    }
+
+    /// Fill the lists into a script template, joining each one with
+    /// the given separator.
+    fn fill(&self, template: &str, sep: &str) -> String {
+        template
+            .replace("@SINGLE@", &self.single.join(sep))
+            .replace("@MULTI@", &self.multi.join(sep))
+            .replace("@VALUE_FLAGS@", &self.value_flags.join(sep))
+    }
+}
+
+const BASH_SESSIONS: &str = r#"
+_shpool_sessions() {
+    local i word cmd="" npos=0
+    local -a sock=()
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word="${COMP_WORDS[i]}"
+        case "$word" in
+            --)
+                return 1
+                ;;
+            -s|--socket)
+                if [[ -z "$cmd" ]]; then
+                    sock=(--socket "${COMP_WORDS[i+1]}")
+                fi
+                ((i++))
+                ;;
+            @VALUE_FLAGS@)
+                ((i++))
+                ;;
+            -*)
+                ;;
+            *)
+                if [[ -z "$cmd" ]]; then
+                    cmd="$word"
+                else
+                    ((npos++))
+                fi
+                ;;
+        esac
+    done
+
+    case "$cmd" in
+        @SINGLE@)
+            [[ $npos -eq 0 ]] || return 1
+            ;;
+        @MULTI@)
+            ;;
+        *)
+            return 1
+            ;;
+    esac
+    [[ "${COMP_WORDS[COMP_CWORD]}" != -* ]] || return 1
+
+    COMPREPLY=($(compgen -W "$(shpool "${sock[@]}" list --names-only 2>/dev/null)" -- "${COMP_WORDS[COMP_CWORD]}"))
+}
+
+_shpool_with_sessions() {
+    _shpool_sessions || _shpool "$@"
+}
+
+complete -F _shpool_with_sessions -o bashdefault -o default shpool
+"#;
+
+const ZSH_SESSIONS: &str = r#"
+(( $+functions[_shpool_sessions] )) ||
+_shpool_sessions() {
+    local -a cmdline sock sessions
+    local i
+    cmdline=(${(z)BUFFER})
+    for (( i = 2; i < $#cmdline; i++ )); do
+        case $cmdline[i] in
+            (-s|--socket)
+                sock=(--socket ${(Q)cmdline[i+1]})
+                (( i++ ))
+                ;;
+            (@VALUE_FLAGS@)
+                (( i++ ))
+                ;;
+            (-*)
+                ;;
+            (*)
+                break
+                ;;
+        esac
+    done
+    sessions=(${(f)"$(shpool $sock list --names-only 2>/dev/null)"})
+    _wanted sessions expl 'session' compadd -a sessions
+}
+"#;
+
+const FISH_SESSIONS: &str = r#"
+function __shpool_session_slot
+    set -l tokens (commandline -opc)
+    set -l cur (commandline -ct)
+    set -l sock
+    set -l cmd
+    set -l npos 0
+    set -e tokens[1]
+    while set -q tokens[1]
+        if test "$tokens[1]" = --
+            return 1
+        end
+        switch $tokens[1]
+            case -s --socket
+                if test -z "$cmd"
+                    set sock --socket $tokens[2]
+                end
+                set -e tokens[1]
+            case @VALUE_FLAGS@
+                set -e tokens[1]
+            case '-*'
+            case '*'
+                if test -z "$cmd"
+                    set cmd $tokens[1]
+                else
+                    set npos (math $npos + 1)
+                end
+        end
+        set -e tokens[1]
+    end
+
+    switch "$cmd"
+        case @SINGLE@
+            test $npos -eq 0; or return 1
+        case @MULTI@
+        case '*'
+            return 1
+    end
+    string match -q -- '-*' "$cur"; and return 1
+
+    set -g __shpool_sock $sock
+end
+
+complete -c shpool -f -n __shpool_session_slot -a "(shpool \$__shpool_sock list --names-only 2>/dev/null)"
+"#;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn session_slots() {
+        let slots = SessionSlots::new(&Args::command());
+        assert_eq!(
+            slots.single,
+            vec![
+                "attach",
+                "wait",
+                "send",
+                "save-output",
+                "capture",
+                "search",
+                "record",
+                "acl",
+                "setenv",
+                "getenv"
+            ]
+        );
+        assert_eq!(slots.multi, vec!["detach", "reset", "clear", "kill"]);
+        for flag in ["-s", "--socket", "--ttl", "-c", "--cmd", "--forward-env", "--unset"] {
+            assert!(slots.value_flags.contains(&String::from(flag)), "{}", flag);
+        }
+        assert!(!slots.value_flags.contains(&String::from("--force")));
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn scripts() {
+        for shell in [CompletionShell::Bash, CompletionShell::Zsh, CompletionShell::Fish] {
+            let script = script(shell);
+            assert!(!script.contains("@SINGLE@"), "{:?}", shell);
+            assert!(!script.contains("@VALUE_FLAGS@"), "{:?}", shell);
+            assert!(script.contains("list --names-only"), "{:?}", shell);
+        }
+
+        let zsh = script(CompletionShell::Zsh);
+        assert_eq!(zsh.matches(":_shpool_sessions' \\").count(), 15);
+        assert!(
+            zsh.find("_shpool_sessions() {").unwrap()
+                < zsh.find("if [ \"$funcstack[1]\" = \"_shpool\" ]").unwrap()
+        );
+    }
+}
@@ -19,12 +19,12 @@ use std::{
     sync::{Arc, RwLock, RwLockReadGuard},
 };
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use notify::Watcher;
 use serde_derive::Deserialize;
 use tracing::{info, warn};
 
-use super::{daemon::keybindings, user};
+use super::{daemon::keybindings, paths, user};
 
 /// Exposes the shpool config file, watching for file updates
 /// so that the user does not need to restart the daemon when
@@ -42,29 +42,7 @@ pub struct Manager {
 impl Manager {
     // Create a new config manager.
     pub fn new(config_file: Option<&str>) -> anyhow::Result<Self> {
-        let user_info = user::info()?;
-        let mut default_config_path = PathBuf::from(user_info.home_dir);
-
-        let (config, config_path) = if let Some(config_path) = config_file {
-            info!("parsing explicitly passed in config ({})", config_path);
-            let config_str = fs::read_to_string(config_path).context("reading config toml (1)")?;
-            let config = toml::from_str(&config_str).context("parsing config file (1)")?;
-
-            (config, Some(String::from(config_path)))
-        } else {
-            default_config_path.push(".config");
-            default_config_path.push("shpool");
-            default_config_path.push("config.toml");
-            if default_config_path.exists() {
-                let config_str =
-                    fs::read_to_string(&default_config_path).context("reading config toml (2)")?;
-                let config = toml::from_str(&config_str).context("parsing config file (2)")?;
-
-                (config, default_config_path.clone().to_str().map(String::from))
-            } else {
-                (Config::default(), None)
-            }
-        };
+        let (config, config_path) = load(config_file)?;
         info!("starting with config: {:?}", config);
 
         let mut manager = Manager { config: Arc::new(RwLock::new(config)), watcher: None };
@@ -76,15 +54,7 @@ impl Manager {
                 Ok(event) => {
                     info!("config file modify event: {:?}", event);
 
-                    let config_str = match fs::read_to_string(&reload_path) {
-                        Ok(s) => s,
-                        Err(e) => {
-                            warn!("error reading config file: {:?}", e);
-                            return;
-                        }
-                    };
-
-                    let config = match toml::from_str(&config_str) {
+                    let config = match parse_file(Path::new(&reload_path)) {
                         Ok(c) => c,
                         Err(e) => {
                             warn!("error parsing config file: {:?}", e);
@@ -108,12 +78,308 @@ impl Manager {
         Ok(manager)
     }
 
+    /// Create a config manager holding a fixed config, which never
+    /// gets reloaded.
+    pub fn from_config(config: Config) -> Self {
+        Manager { config: Arc::new(RwLock::new(config)), watcher: None }
+    }
+
     // Get the current config value.
     pub fn get(&self) -> RwLockReadGuard<'_, Config> {
         self.config.read().unwrap()
     }
 }
 
+/// Read the config from the given file, or from the default path if
+/// no file is given. Returns the config along with the path it came
+/// from, if any.
+pub fn load(config_file: Option<&str>) -> anyhow::Result<(Config, Option<String>)> {
+    let default_config_path = default_path()?;
+
+    Ok(if let Some(config_path) = config_file {
+        info!("parsing explicitly passed in config ({})", config_path);
+        let config = parse_file(Path::new(config_path)).context("parsing config file (1)")?;
+
+        (config, Some(String::from(config_path)))
+    } else if default_config_path.exists() {
+        let config = parse_file(&default_config_path).context("parsing config file (2)")?;
+
+        (config, default_config_path.to_str().map(String::from))
+    } else {
+        (Config::default(), None)
+    })
+}
+
+/// Read and parse a config file, along with everything it includes.
+/// Keys that shpool doesn't know about get logged, or make the parse
+/// fail if `strict_config` is set.
+pub fn parse_file(path: &Path) -> anyhow::Result<Config> {
+    let (config, unknown_keys) = from_table(read(path)?)?;
+    check_unknown_keys(config, unknown_keys)
+}
+
+fn check_unknown_keys(config: Config, unknown_keys: Vec<String>) -> anyhow::Result<Config> {
+    if !unknown_keys.is_empty() {
+        if config.strict_config.unwrap_or(false) {
+            return Err(anyhow!("unknown config keys: {}", unknown_keys.join(", ")));
+        }
+        for key in unknown_keys.iter() {
+            warn!("ignoring unknown config key '{}'", key);
+        }
+    }
+    Ok(config)
+}
+
+/// Turn a config table into a config, along with the paths of any keys
+/// in it that shpool doesn't know about, like "keybinding.0.actoin".
+pub fn from_table(table: toml::Table) -> anyhow::Result<(Config, Vec<String>)> {
+    let mut unknown_keys = vec![];
+    let config = serde_ignored::deserialize(toml::Value::Table(table), |path| {
+        // drop the segments that mark where an Option got unwrapped
+        unknown_keys.push(path.to_string().replace(".?", ""))
+    })?;
+    Ok((config, unknown_keys))
+}
+
+/// Read a config file into a table, with the files it includes merged
+/// in underneath it and the host sections matching this machine's
+/// hostname applied on top.
+pub fn read(path: &Path) -> anyhow::Result<toml::Table> {
+    let mut table = read_with_includes(path, &mut vec![])?;
+    apply_host_overrides(&mut table, &hostname()?)?;
+    Ok(table)
+}
+
+/// Read a config file, merging it on top of the files it includes, in
+/// order. The stack of files being read guards against include cycles.
+fn read_with_includes(path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<toml::Table> {
+    let src = fs::read_to_string(path)
+        .with_context(|| format!("reading config toml {}", path.display()))?;
+    let mut table: toml::Table =
+        toml::from_str(&src).with_context(|| format!("parsing {}", path.display()))?;
+    for (key, value) in table.iter_mut() {
+        expand_env_vars(value, key, &|name| std::env::var(name).ok())
+            .with_context(|| format!("expanding variables in {}", path.display()))?;
+    }
+    let includes = match table.get("include") {
+        None => return Ok(table),
+        Some(toml::Value::Array(a)) => a.clone(),
+        Some(_) => return Err(anyhow!("{}: include must be a list of paths", path.display())),
+    };
+
+    stack.push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    let mut merged = toml::Table::new();
+    for include in includes.iter() {
+        let pattern = include
+            .as_str()
+            .ok_or(anyhow!("{}: include must be a list of paths", path.display()))?;
+        let is_glob = pattern.contains(['*', '?']);
+        for included in expand_include(pattern, base_dir)? {
+            let canonical = fs::canonicalize(&included).unwrap_or_else(|_| included.clone());
+            if stack.contains(&canonical) {
+                if is_glob {
+                    // a glob like ~/.config/shpool/*.toml matches the
+                    // file that has it
+                    continue;
+                }
+                return Err(anyhow!("{} includes itself", included.display()));
+            }
+            merge(&mut merged, read_with_includes(&included, stack)?);
+        }
+    }
+    stack.pop();
+
+    merge(&mut merged, table);
+    Ok(merged)
+}
+
+/// Expand variable references in every string in a config value, in
+/// place. The key path is just for error messages.
+fn expand_env_vars(
+    value: &mut toml::Value,
+    key_path: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> anyhow::Result<()> {
+    match value {
+        toml::Value::String(s) => {
+            *s = expand_vars(s, lookup).with_context(|| format!("in {}", key_path))?;
+        }
+        toml::Value::Array(a) => {
+            for (i, elem) in a.iter_mut().enumerate() {
+                expand_env_vars(elem, &format!("{}.{}", key_path, i), lookup)?;
+            }
+        }
+        toml::Value::Table(t) => {
+            for (key, elem) in t.iter_mut() {
+                expand_env_vars(elem, &format!("{}.{}", key_path, key), lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Expand `${VAR}` and `${VAR:-fallback}` references in a string. An
+/// unset variable with no fallback is an error, rather than quietly
+/// turning into an empty string, and the fallback also gets used when
+/// the variable is set but empty, like in the shell. `$${` stands for
+/// a literal `${`, and a `$` that isn't followed by `{` is left alone,
+/// so things like `$SHPOOL_SESSION_NAME` in prompt_prefix still work.
+fn expand_vars(src: &str, lookup: &dyn Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(src.len());
+    let mut rest = src;
+    while let Some(idx) = rest.find('$') {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+            continue;
+        }
+        let reference = match rest.strip_prefix("${") {
+            Some(r) => r,
+            None => {
+                out.push('$');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let end = reference.find('}').ok_or(anyhow!("unterminated ${{ in '{}'", src))?;
+        let (name, fallback) = match reference[..end].split_once(":-") {
+            Some((name, fallback)) => (name, Some(fallback)),
+            None => (&reference[..end], None),
+        };
+        let valid_name =
+            name.chars().next().map(|c| c.is_ascii_alphabetic() || c == '_').unwrap_or(false)
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(anyhow!("bad variable name '{}' in '{}'", name, src));
+        }
+        match (lookup(name), fallback) {
+            (Some(value), Some(fallback)) if value.is_empty() => out.push_str(fallback),
+            (Some(value), _) => out.push_str(&value),
+            (None, Some(fallback)) => out.push_str(fallback),
+            (None, None) => return Err(anyhow!("${{{}}} is not set and has no fallback", name)),
+        }
+        rest = &reference[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The files an include entry points at. A leading ~/ means the home
+/// dir, and relative paths are relative to the including file. The
+/// file name may have * and ? wildcards, in which case the matching
+/// files get included in alphabetical order, and it is fine for
+/// nothing to match.
+fn expand_include(pattern: &str, base_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let path = match pattern.strip_prefix("~/") {
+        Some(rest) => PathBuf::from(user::info()?.home_dir).join(rest),
+        None => base_dir.join(pattern),
+    };
+    let file_pattern = match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) if n.contains(['*', '?']) => n,
+        _ => return Ok(vec![path]),
+    };
+    let dir = path.parent().unwrap_or(Path::new("."));
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).with_context(|| format!("listing {}", dir.display())),
+    };
+    let mut paths = vec![];
+    for entry in entries {
+        let entry = entry.with_context(|| format!("listing {}", dir.display()))?;
+        let name = entry.file_name();
+        if name.to_str().map(|n| glob_match(file_pattern, n)).unwrap_or(false) {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Apply every `[host."<pattern>"]` section whose pattern matches the
+/// given hostname on top of the rest of the config, in alphabetical
+/// order of the patterns.
+fn apply_host_overrides(table: &mut toml::Table, hostname: &str) -> anyhow::Result<()> {
+    let hosts = match table.remove("host") {
+        None => return Ok(()),
+        Some(toml::Value::Table(t)) => t,
+        Some(_) => return Err(anyhow!("host must be a table of per-host sections")),
+    };
+    for (pattern, overrides) in hosts.into_iter() {
+        if !glob_match(&pattern, hostname) {
+            continue;
+        }
+        match overrides {
+            toml::Value::Table(overrides) => {
+                info!("applying config overrides for host pattern '{}'", pattern);
+                merge(table, overrides);
+            }
+            _ => return Err(anyhow!("host.\"{}\" must be a table", pattern)),
+        }
+    }
+    Ok(())
+}
+
+/// Merge one config table on top of another. Tables get merged key by
+/// key, anything else (lists included) gets replaced outright.
+fn merge(base: &mut toml::Table, top: toml::Table) {
+    for (key, value) in top.into_iter() {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(top_table)) => {
+                merge(base_table, top_table);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Match a string against a pattern where * matches any run of
+/// characters and ? matches any single character.
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut p, mut i) = (0, 0);
+    // where to pick back up if the current attempt at matching the
+    // last * fails
+    let mut backtrack: Option<(usize, usize)> = None;
+    while i < s.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, i));
+            p += 1;
+        } else if let Some((star_p, star_i)) = backtrack {
+            // let the * eat one more character
+            p = star_p + 1;
+            i = star_i + 1;
+            backtrack = Some((star_p, star_i + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// The hostname of the machine shpool is running on.
+pub fn hostname() -> anyhow::Result<String> {
+    let name = nix::unistd::gethostname().context("getting hostname")?;
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// The path the config is loaded from when no config
+/// file is explicitly passed, $XDG_CONFIG_HOME/shpool/config.toml.
+pub fn default_path() -> anyhow::Result<PathBuf> {
+    paths::default_config_file()
+}
+
 impl std::clone::Clone for Manager {
     fn clone(&self) -> Self {
         Manager { config: Arc::clone(&self.config), watcher: self.watcher.as_ref().map(Arc::clone) }
@@ -141,6 +407,15 @@ pub struct Config {
     /// shells it can make the output easier to parse.
     pub noecho: Option<bool>,
 
+    /// What to do about XON/XOFF flow control, which pauses a
+    /// session's output when Ctrl-S gets typed and resumes it on
+    /// Ctrl-Q. Hitting Ctrl-S by accident makes the session look
+    /// hung. "on" (the default) leaves it be, "off" turns it off on the
+    /// session's pty so that Ctrl-S goes to the program running in the
+    /// session, and "notice" leaves it on but tells the attached client
+    /// how to get going again whenever Ctrl-S pauses the output.
+    pub flow_control: Option<FlowControl>,
+
     /// By default, if there is a SSH_AUTH_SOCK in the environment
     /// where `shpool attach` gets run, shpool will create a
     /// symlink to the socket and set SSH_AUTH_SOCK to that symlink
@@ -166,10 +441,24 @@ pub struct Config {
     /// initial shell
     pub env: Option<HashMap<String, String>>,
 
+    /// The TERM to start new sessions with when the client creating
+    /// them doesn't have one, as happens with `ssh host shpool attach`
+    /// run from a ForceCommand or without a tty. A TERM in the env
+    /// table or given with `--term` takes precedence.
+    pub default_term: Option<String>,
+
     /// A list of environment variables to forward from the environment
     /// of the initial shell that invoked `shpool attach` to the newly
-    /// launched shell. Note that this config option has no impact when
-    /// reattaching to an existing shell.
+    /// launched shell. An entry ending in a '*' forwards every variable
+    /// with the given prefix (i.e. "LC_*"). More variables can be added
+    /// with the `--forward-env` flag to `shpool attach`.
+    ///
+    /// There is no way to change the environment of a shell that is
+    /// already running, so on reattach the forwarded variables (along
+    /// with DISPLAY and LANG) are written as `export` statements to
+    /// `$SHPOOL_SESSION_DIR/forward_env`, which the shell can source
+    /// to pick up the fresh values. `shpool setenv` updates the same
+    /// file.
     pub forward_env: Option<Vec<String>>,
 
     /// The initial path to spawn shell processes with. By default
@@ -187,15 +476,84 @@ pub struct Config {
     /// By default, 10000 lines.
     pub output_spool_lines: Option<usize>,
 
+    /// Keep only this many lines of the output spool's scrollback in
+    /// memory, spilling older lines out to a file under
+    /// $XDG_STATE_HOME/shpool/scrollback that holds the rest of the
+    /// output_spool_lines. Copy mode reads the spilled lines back.
+    /// By default, the whole spool stays in memory.
+    pub scrollback_memory_lines: Option<usize>,
+
+    /// When reattaching would replay at least this many bytes of
+    /// output, the client puts up a status line saying so until the
+    /// replay starts arriving, so that an attach over a slow link
+    /// doesn't look hung. 0 turns the status line off. By default,
+    /// 256 KiB.
+    pub replay_notice_bytes: Option<usize>,
+
     /// The user supplied keybindings.
     pub keybinding: Option<Vec<Keybinding>>,
 
+    /// A status line along the bottom row of the terminal, showing the
+    /// session name, how many times the session has been attached to,
+    /// whether it is being recorded, how long it has been quiet and the
+    /// time. The `toggle-status-line` keybinding action shows and hides
+    /// it either way.
+    pub status_line: Option<StatusLine>,
+
+    /// Also match keybindings that the terminal reports as CSI u
+    /// escape sequences, which happens while a program that has turned
+    /// on the kitty keyboard protocol is running. When the terminal
+    /// reports the key for the base layout, that is what gets matched,
+    /// so chords work the same with non-QWERTY keyboard layouts.
+    /// By default, false.
+    pub csi_u_keybindings: Option<bool>,
+
     /// A prefix to inject into the prompt of freshly spawned shells.
     /// The prefix will get included in the shell's prompt variable
     /// verbatim except that the string '$SHPOOL_SESSION_NAME' will
     /// get replaced with the actual name of the shpool session.
     pub prompt_prefix: Option<String>,
 
+    /// Remove OSC 8 hyperlinks from the output of sessions before sending
+    /// it to the client, leaving just the link text. Useful if your
+    /// terminal mis-renders hyperlinks. By default, hyperlinks are passed
+    /// through untouched. Note that the output spool does not keep track
+    /// of hyperlinks, so text restored on reattach comes back without
+    /// them, though a link that is still open when a client attaches gets
+    /// opened again for the output that follows.
+    pub strip_hyperlinks: Option<bool>,
+
+    /// A suffix to append to the terminal title whenever a program in
+    /// a session sets it with an OSC 0 or OSC 2 escape sequence. This
+    /// makes it easier to tell windows apart when you have several
+    /// sessions attached at once. As with prompt_prefix, the string
+    /// '$SHPOOL_SESSION_NAME' will get replaced with the name of the
+    /// session, so " ($SHPOOL_SESSION_NAME)" is a reasonable choice.
+    /// By default, titles are passed through untouched.
+    pub title_suffix: Option<String>,
+
+    /// What to do with the OSC 52 escape sequences that programs like
+    /// vim and tmux use to copy text into the clipboard of the terminal
+    /// the client is running in. "allow" (the default) passes them
+    /// through, "deny" drops them, and { truncate = n } passes them
+    /// through with anything past the first n bytes of copied text cut
+    /// off. Either way, a sequence only goes out once it is complete,
+    /// so detaching while a program is in the middle of writing one
+    /// can't leave the terminal in a confused state.
+    pub clipboard: Option<ClipboardPolicy>,
+
+    /// A command to type into a session's shell every time a client
+    /// reattaches to it, such as `clear` or `source ~/.refresh-env`.
+    /// So that it doesn't end up as input to some other program, it
+    /// only gets typed when the shell looks like it is sitting at its
+    /// prompt, with nothing running in the foreground. The
+    /// `--on-attach-cmd` flag to `shpool attach` overrides this.
+    pub on_attach_cmd: Option<String>,
+
+    /// Type the on-attach command even when something other than the
+    /// shell is running in the foreground. By default, false.
+    pub on_attach_cmd_always: Option<bool>,
+
     /// Control when and how shpool will display the message of the day.
     pub motd: Option<MotdDisplayMode>,
 
@@ -210,6 +568,180 @@ pub struct Config {
     /// See https://man7.org/linux/man-pages/man8/pam_motd.8.html
     /// for more info.
     pub motd_args: Option<Vec<String>>,
+
+    /// How long `shpool attach` waits for the daemon to answer
+    /// while setting up the connection before giving up and
+    /// reporting where things got stuck. Uses the same format as
+    /// the --ttl flag (i.e. '10s' or '01:30'). By default, 30s.
+    pub attach_timeout: Option<String>,
+
+    /// A line for `shpool attach` to print before it takes over the
+    /// terminal. The placeholders {session}, {host}, {age} and
+    /// {attaches} get filled in with the session's name, the daemon's
+    /// hostname, how long ago the session was created and how many
+    /// times it has been attached to before. By default, no banner.
+    pub attach_banner: Option<String>,
+
+    /// Edit lines typed at the shell's prompt in `shpool attach` and
+    /// only send them once enter gets hit, which makes typing over a
+    /// slow link much less painful. Only backspace and ^U work
+    /// locally, anything fancier sends the line as typed so far on to
+    /// the shell. The toggle-local-edit keybinding turns it on and off
+    /// for a single attach. Needs a session_restore_mode other than
+    /// "simple". By default, false.
+    pub local_edit: Option<bool>,
+
+    /// The maximum number of sessions the daemon will run at once.
+    /// Attempts to create new sessions beyond this limit are rejected
+    /// (reattaching to existing sessions always works). By default,
+    /// there is no limit.
+    pub max_sessions: Option<usize>,
+
+    /// How long sessions created without a --ttl last before the
+    /// daemon kills them, in the same format as the --ttl flag. By
+    /// default, sessions last until their shell exits.
+    pub session_ttl: Option<String>,
+
+    /// The longest any session may last, in the same format as the
+    /// --ttl flag. A longer --ttl or session_ttl gets cut down to this.
+    /// By default, there is no limit.
+    pub max_session_ttl: Option<String>,
+
+    /// How long a session whose shell or command exits while no client
+    /// is attached sticks around, in the same format as the --ttl flag,
+    /// so that `shpool list` can show how it went and the next attach
+    /// can show what it left on the screen. By default, it sticks
+    /// around until the next attach.
+    pub exited_session_linger: Option<String>,
+
+    /// Pin the shells of matching sessions to a set of cpus. Each
+    /// entry names a session (or a session name prefix followed by
+    /// a '*') and the cpus its shell should run on. The first entry
+    /// matching a new session wins. Sessions which don't match any
+    /// entry are left unpinned, and a shell that can't be pinned to
+    /// its cpus fails to start.
+    pub cpu_affinity: Option<Vec<CpuAffinity>>,
+
+    /// Start the command of matching sessions over when it exits, for
+    /// keeping flaky long-running things like dev servers up. Each
+    /// entry names a session (or a session name prefix followed by a
+    /// '*') and when to restart it. The first entry matching a new
+    /// session wins. Sessions which don't match any entry are never
+    /// restarted.
+    pub restart_policy: Option<Vec<RestartPolicy>>,
+
+    /// Also accept connections over TCP, secured with mutual TLS, so
+    /// that `shpool tunnel` can reach the daemon from another machine.
+    /// This is only read when the daemon starts up.
+    pub tcp_listener: Option<TcpListener>,
+
+    /// Record everything shown in and typed into every new session,
+    /// encrypted, for environments that need a full audit trail.
+    /// Input typed at password prompts (with echo turned off) is
+    /// redacted, and users get a warning every time they attach.
+    pub session_audit: Option<SessionAudit>,
+
+    /// Record what sessions display as asciicast v2 files, which
+    /// `asciinema play` can replay. `shpool record` starts and stops
+    /// recordings of running sessions whether or not this is set.
+    pub recording: Option<Recording>,
+
+    /// Patterns to mask in the output of sessions before it goes into
+    /// the output spool or gets recorded by `session_audit` or `shpool
+    /// record`, for things like `AWS_SECRET_ACCESS_KEY=...` which
+    /// shouldn't end up on disk. The output sent to attached clients is
+    /// not redacted. Patterns are matched a line at a time.
+    pub redact: Option<Vec<Redaction>>,
+
+    /// Save the definitions of sessions (their name, command, starting
+    /// directory and some of their environment) so that `shpool
+    /// resurrect` can start them up again after a reboot. Nothing gets
+    /// saved unless this is set.
+    pub resurrect: Option<Resurrect>,
+
+    /// Users and groups other than the user running the daemon that
+    /// may connect to its socket. Connections from anyone else are
+    /// refused, regardless of the permissions on the socket file.
+    pub allowed_peers: Option<AllowedPeers>,
+
+    /// A command to ask whether each attach should be allowed, for
+    /// example by checking for a hardware token. The attach only goes
+    /// ahead if the command exits successfully. Sending input to,
+    /// pasting into, capturing, searching and saving the output of a
+    /// session get checked the same way.
+    pub attach_auth: Option<AttachAuth>,
+
+    /// Limits on the resources each session may use, so that a runaway
+    /// process in a detached session can't take down the whole machine.
+    pub session_limits: Option<SessionLimits>,
+
+    /// The PAM service (for example "login" or "sshd") to open a
+    /// session with for each new shell, so that pam_systemd, pam_env,
+    /// pam_limits and friends treat it like a login and loginctl
+    /// lists it. The session gets closed when the shell exits. Opening
+    /// a session usually takes a daemon running as root.
+    pub pam_session_service: Option<String>,
+
+    /// Add a utmp and wtmp record for each new shell, so that sessions
+    /// show up in `who`, `w` and `last`. Writing the records usually
+    /// takes a daemon running as root or in the utmp group. False by
+    /// default.
+    pub utmp: Option<bool>,
+
+    /// Where `shpool self-update` looks for new releases, and the key
+    /// they must be signed with.
+    pub self_update: Option<SelfUpdate>,
+
+    /// The format of log lines. "json" writes one JSON object per line
+    /// tagged with the session, client connection and component each
+    /// line is about, which is handy for filtering the logs of a busy
+    /// daemon. This is only read when shpool starts up.
+    pub log_format: Option<LogFormat>,
+
+    /// An address like "127.0.0.1:9431" to serve the daemon metrics on
+    /// over plain HTTP, at /metrics, for Prometheus to scrape. The same
+    /// metrics are available from `shpool metrics` either way. This is
+    /// only read when the daemon starts up.
+    pub metrics_listener: Option<String>,
+
+    /// Commands to run when sessions get created, attached to, detached
+    /// from or exit, for things like sending a desktop notification
+    /// when a build running in a detached session finishes.
+    pub hooks: Option<HookCommands>,
+
+    /// Thresholds for the activity monitoring that `shpool list` and
+    /// the `on_silence` and `on_activity` hooks use.
+    pub activity: Option<Activity>,
+
+    /// How much output may queue up for a client that can't keep up
+    /// with the shell, and what to do once that much has queued up.
+    pub output_buffer: Option<OutputBuffer>,
+
+    /// How sessions locked with the lock keybinding action get
+    /// unlocked, and whether they lock themselves after sitting idle.
+    pub lock: Option<Lock>,
+
+    /// Refuse to load a config with keys that shpool doesn't know
+    /// about, rather than just warning about them. Unknown keys are
+    /// most likely typos, like `keybining`, which would otherwise
+    /// quietly do nothing.
+    pub strict_config: Option<bool>,
+
+    /// Other config files to read, relative to this one unless they
+    /// start with ~/ or /. The file name may have * and ? wildcards.
+    /// Included files get read first, in order, and this file gets
+    /// merged on top of them.
+    pub include: Option<Vec<String>>,
+
+    /// Overrides for machines whose hostname matches a pattern, which
+    /// may have * and ? wildcards, like `[host."devbox*"]`. Matching
+    /// sections get merged on top of the rest of the config.
+    pub host: Option<HashMap<String, toml::Table>>,
+
+    /// Where to keep the daemon's runtime data and state, in place of
+    /// the XDG base directories. This is only read when shpool starts
+    /// up.
+    pub paths: Option<Paths>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -221,6 +753,270 @@ pub struct Keybinding {
     pub action: keybindings::Action,
 }
 
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct StatusLine {
+    /// Put the status line up when a client attaches, unless it has
+    /// been toggled off for the session. False by default.
+    pub enabled: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TcpListener {
+    /// The address to listen on (i.e. "0.0.0.0:7431").
+    pub listen: String,
+    /// A PEM file with the certificate chain the daemon presents.
+    pub cert: String,
+    /// A PEM file with the private key for cert.
+    pub key: String,
+    /// A PEM file with the CA certificates that client
+    /// certificates must chain up to.
+    pub client_ca: String,
+    /// If set, clients must also present a certificate with one of
+    /// these sha256 fingerprints, as printed by
+    /// `openssl x509 -noout -fingerprint -sha256`.
+    pub pinned_client_certs: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HookCommands {
+    /// Run when a new session is created.
+    pub on_session_create: Option<String>,
+    /// Run whenever a client attaches, including to a session that was
+    /// just created.
+    pub on_attach: Option<String>,
+    /// Run when a client detaches from a session that keeps running.
+    pub on_detach: Option<String>,
+    /// Run when the shell (or custom command) of a session exits, with
+    /// its exit status in $SHPOOL_SESSION_EXIT_STATUS.
+    pub on_session_exit: Option<String>,
+    /// Run when a session with no client attached rings the bell or
+    /// emits a desktop notification. $SHPOOL_HOOK_EVENT is `bell` or
+    /// `notification`, and notifications put their text in
+    /// $SHPOOL_ALERT_BODY (and $SHPOOL_ALERT_TITLE if they have one).
+    /// Only the first bell after a client detaches runs the command.
+    pub on_alert: Option<String>,
+    /// Run when a session with no client attached that has been
+    /// producing output goes quiet for `activity.silence_threshold`,
+    /// like when a build finishes.
+    pub on_silence: Option<String>,
+    /// Run when a session with no client attached produces output
+    /// after being quiet for `activity.silence_threshold`.
+    pub on_activity: Option<String>,
+    /// How long a hook command may run before it gets killed, in the
+    /// same format as `shpool attach --ttl`. Defaults to 30 seconds.
+    pub timeout: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Activity {
+    /// Sessions that produced output within this long count as active
+    /// in `shpool list`, in the same format as `shpool attach --ttl`.
+    /// Defaults to 30 seconds.
+    pub active_window: Option<String>,
+    /// How long a session has to go without output to count as having
+    /// gone quiet. Defaults to 10 minutes.
+    pub silence_threshold: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OutputBuffer {
+    /// The most shell output to queue up for an attached client, in
+    /// bytes. Defaults to 1 MiB.
+    pub limit: Option<usize>,
+    /// What to do with shell output once the limit is reached.
+    /// Defaults to `block`.
+    pub policy: Option<OutputOverflowPolicy>,
+    /// How long to let small bits of shell output pile up while output
+    /// is streaming in, so they go out in fewer writes, in milliseconds.
+    /// Output after a quiet spell always goes out right away. Defaults
+    /// to 2, and 0 turns this off.
+    pub coalesce_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Lock {
+    /// An argon2 hash of the passphrase that unlocks sessions, in the
+    /// PHC string format that the argon2 cli tool prints with `-e`
+    /// (i.e. "$argon2id$v=19$m=19456,t=2,p=1$...").
+    pub passphrase_hash: Option<String>,
+    /// A PAM service (i.e. "login") to check the password of the user
+    /// running the daemon against, for unlocking sessions with that
+    /// password. Only used if there is no passphrase_hash.
+    pub pam_service: Option<String>,
+    /// Lock sessions once they have gone this long without input from
+    /// an attached client, in the same format as `shpool attach --ttl`.
+    /// By default, sessions only get locked with the keybinding.
+    pub idle_timeout: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SelfUpdate {
+    /// The url of the JSON manifest describing the latest release.
+    /// The format is described in src/self_update.rs.
+    pub url: String,
+    /// The hex encoded ed25519 public key that release binaries must
+    /// be signed with.
+    pub public_key: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SessionLimits {
+    /// Rlimits to set on each new shell, keyed by the lowercase name
+    /// of the resource without the RLIMIT_ prefix (i.e. "nofile" or
+    /// "as"). Both the soft and hard limits get set, and the limits
+    /// apply to each process in the session separately. A shell whose
+    /// limits can't be set fails to start.
+    pub rlimits: Option<HashMap<String, u64>>,
+    /// Move each new shell into its own transient systemd scope with
+    /// these limits, which apply to the session as a whole. This needs
+    /// a running systemd manager and takes precedence over the cgroup
+    /// in cpu_affinity.
+    pub scope: Option<SessionScope>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SessionScope {
+    /// The most memory the session may use (i.e. "4G").
+    pub memory_max: Option<String>,
+    /// The point past which the kernel starts to aggressively reclaim
+    /// memory from the session (i.e. "3G").
+    pub memory_high: Option<String>,
+    /// The share of a cpu the session may use, where "200%" is two
+    /// whole cpus.
+    pub cpu_quota: Option<String>,
+    /// The most processes and threads the session may have.
+    pub tasks_max: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AllowedPeers {
+    /// Additional uids to accept connections from.
+    pub uids: Option<Vec<u32>>,
+    /// Accept connections from processes whose effective gid is one
+    /// of these. Supplementary groups are not considered.
+    pub gids: Option<Vec<u32>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AttachAuth {
+    /// The command to run with `sh -c`. It gets the session name in
+    /// $SHPOOL_SESSION_NAME and the credentials of the attaching
+    /// process in $SHPOOL_PEER_UID, $SHPOOL_PEER_GID and
+    /// $SHPOOL_PEER_PID. The first line it prints, if any, gets shown
+    /// to the user when the attach is denied.
+    pub cmd: String,
+    /// How long to wait for the command before denying the attach, in
+    /// the same format as `shpool attach --ttl`. Defaults to 60
+    /// seconds.
+    pub timeout: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Paths {
+    /// The directory for the socket and per-session runtime data,
+    /// $XDG_RUNTIME_DIR/shpool by default. Setting this moves the
+    /// default socket along with it.
+    pub runtime_dir: Option<String>,
+    /// The directory for state that outlives the daemon, like audit
+    /// recordings, $XDG_STATE_HOME/shpool by default.
+    pub state_dir: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SessionAudit {
+    /// The directory to write recordings to, audit in the state dir
+    /// by default. Each session gets its own file, named after the
+    /// session and the time it started.
+    pub dir: Option<String>,
+    /// A shell command which prints the 32 byte recording key as
+    /// 64 hex digits. It gets run for every new session, and if
+    /// it fails the session is not created.
+    pub key_cmd: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Recording {
+    /// The directory to write recordings to, recordings in the state
+    /// dir by default. Each recording gets its own file, named after
+    /// the session and the time the recording started.
+    pub dir: Option<String>,
+    /// Start recording every new session as soon as it gets created.
+    /// False by default.
+    pub auto: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Redaction {
+    /// The regular expression to look for, in the syntax of the regex
+    /// crate (i.e. "AWS_SECRET_ACCESS_KEY=\\S+").
+    pub pattern: String,
+    /// What to replace matches with, which may refer to groups in the
+    /// pattern as $1 or ${name}. Defaults to "[REDACTED]".
+    pub replacement: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Resurrect {
+    /// The file to save session definitions to, resurrect.json in the
+    /// state dir by default, or resurrect-<instance>.json for a named
+    /// instance.
+    pub file: Option<String>,
+    /// The environment variables to save along with each session,
+    /// either exact names or a prefix followed by a '*'. Only
+    /// variables the client forwarded when the session got created
+    /// can be saved. None by default.
+    pub env: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CpuAffinity {
+    /// The name of the session to pin, or a prefix followed by a '*'
+    /// to pin every session whose name starts with the prefix.
+    pub session: String,
+    /// The cpus to pin the session to, in the same list format
+    /// used by taskset and cpuset.cpus (i.e. "0-3,8").
+    pub cpus: String,
+    /// The path to an existing cgroup v2 directory (i.e. one delegated
+    /// to you by systemd) to move the shell into. Use this if you want
+    /// a cpuset enforced on all descendants, since processes can
+    /// otherwise widen their own affinity mask.
+    pub cgroup: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RestartPolicy {
+    /// The name of the session to restart, or a prefix followed by a
+    /// '*' to restart every session whose name starts with the prefix.
+    pub session: String,
+    /// When to start the command over.
+    pub restart: Restart,
+    /// How long to wait before restarting, in the same format as the
+    /// --ttl flag. The wait doubles with each restart in a row, up to
+    /// max_backoff. "1s" by default.
+    pub backoff: Option<String>,
+    /// The longest to wait before restarting. A command that stays up
+    /// for longer than this gets the shortest wait again the next time
+    /// it exits. "1m" by default.
+    pub max_backoff: Option<String>,
+    /// How many times in a row to restart before giving up and letting
+    /// the session exit. By default, there is no limit.
+    pub max_restarts: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Restart {
+    /// Let the session exit along with its command.
+    #[default]
+    Never,
+    /// Restart the command if it exits with a non-zero status or gets
+    /// killed by a signal other than the ones that ask it to quit
+    /// (SIGHUP, SIGINT, SIGTERM and SIGPIPE).
+    OnFailure,
+    /// Restart the command no matter how it exits.
+    Always,
+}
+
 #[derive(Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum SessionRestoreMode {
@@ -240,6 +1036,55 @@ pub enum SessionRestoreMode {
     Lines(u16),
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipboardPolicy {
+    /// Let programs set the client's clipboard.
+    #[default]
+    Allow,
+    /// Drop clipboard sequences entirely.
+    Deny,
+    /// Let programs set the client's clipboard, but with no more than
+    /// the given number of bytes of text.
+    Truncate(usize),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControl {
+    /// Leave flow control the way the pty starts out, which is on.
+    #[default]
+    On,
+    /// Turn flow control off on the session's pty when spawning it.
+    Off,
+    /// Leave flow control on, but show the attached client a message
+    /// whenever Ctrl-S pauses the session's output.
+    Notice,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputOverflowPolicy {
+    /// Stop reading from the shell until the client catches up, which
+    /// stalls whatever is producing the output.
+    #[default]
+    Block,
+    /// Throw away new output until the client catches up.
+    Drop,
+    /// Throw away the oldest queued output to make room for new output.
+    Clip,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Plain text meant for humans.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
 #[derive(Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum MotdDisplayMode {
@@ -284,10 +1129,181 @@ mod test {
             session_restore_mode = "screen"
             "#,
             r#"
+            replay_notice_bytes = 0
+            "#,
+            r#"
+            [recording]
+            dir = "/tmp/casts"
+            auto = true
+            "#,
+            r#"
+            [resurrect]
+            file = "/tmp/sessions.json"
+            env = ["VIRTUAL_ENV", "PROJECT_*"]
+            "#,
+            r#"
+            [status_line]
+            enabled = true
+            "#,
+            r#"
+            exited_session_linger = "10m"
+            "#,
+            r#"
+            [[restart_policy]]
+            session = "dev*"
+            restart = "on-failure"
+            backoff = "2s"
+            max_restarts = 5
+            "#,
+            r#"
+            [[redact]]
+            pattern = "AWS_SECRET_ACCESS_KEY=\\S+"
+
+            [[redact]]
+            pattern = 'token: (\w{4})\w+'
+            replacement = "token: ${1}..."
+            "#,
+            r#"
+            clipboard = "deny"
+            "#,
+            r#"
+            clipboard = { truncate = 65536 }
+            "#,
+            r#"
             [[keybinding]]
             binding = "Ctrl-q a"
             action = "detach"
             "#,
+            r#"
+            [[keybinding]]
+            binding = "Ctrl-a ["
+            action = "copy-mode"
+            "#,
+            r#"
+            [[cpu_affinity]]
+            session = "build-*"
+            cpus = "8-15"
+            cgroup = "/sys/fs/cgroup/user.slice/shpool-build"
+            "#,
+            r#"
+            [tcp_listener]
+            listen = "0.0.0.0:7431"
+            cert = "/etc/shpool/server.pem"
+            key = "/etc/shpool/server.key"
+            client_ca = "/etc/shpool/ca.pem"
+            pinned_client_certs = ["75:D0:A7:F9"]
+            "#,
+            r#"
+            [session_audit]
+            dir = "/var/log/shpool"
+            key_cmd = "cat /etc/shpool/audit.key"
+            "#,
+            r#"
+            [allowed_peers]
+            uids = [1001]
+            gids = [100]
+            "#,
+            r#"
+            [attach_auth]
+            cmd = "/usr/local/bin/check-token"
+            timeout = "2m"
+            "#,
+            r#"
+            [session_limits.rlimits]
+            nofile = 4096
+            core = 0
+
+            [session_limits.scope]
+            memory_max = "8G"
+            cpu_quota = "200%"
+            tasks_max = 1000
+            "#,
+            r#"
+            pam_session_service = "login"
+            utmp = true
+            "#,
+            r#"
+            [self_update]
+            url = "https://example.com/shpool/latest.json"
+            public_key = "891c64a8b2fbeaee45de972a2367280d0a606965f703fd40dd0c3513d8f6a5a6"
+            "#,
+            r#"
+            log_format = "json"
+            "#,
+            r#"
+            metrics_listener = "127.0.0.1:9431"
+            "#,
+            r#"
+            [hooks]
+            on_session_exit = "notify-send shpool \"$SHPOOL_SESSION_NAME exited\""
+            on_alert = "notify-send \"$SHPOOL_SESSION_NAME\" \"$SHPOOL_ALERT_BODY\""
+            timeout = "10s"
+            "#,
+            r#"
+            [activity]
+            active_window = "1m"
+            silence_threshold = "5m"
+
+            [hooks]
+            on_silence = "notify-send \"$SHPOOL_SESSION_NAME went quiet\""
+            on_activity = "notify-send \"$SHPOOL_SESSION_NAME woke up\""
+            "#,
+            r#"
+            [output_buffer]
+            limit = 262144
+            policy = "clip"
+            coalesce_ms = 5
+
+            [[keybinding]]
+            binding = "Ctrl-a o"
+            action = "flush-output"
+            "#,
+            r#"
+            on_attach_cmd = "clear; tput reset"
+            on_attach_cmd_always = true
+            "#,
+            r#"
+            local_edit = true
+            "#,
+            r#"
+            default_term = "xterm-256color"
+            "#,
+            r#"
+            flow_control = "notice"
+            "#,
+            r#"
+            attach_banner = "attached to '{session}' on {host}, created {age} ago"
+            "#,
+            r#"
+            output_spool_lines = 100000
+            scrollback_memory_lines = 1000
+            "#,
+            r#"
+            [lock]
+            passphrase_hash = "$argon2id$v=19$m=19456,t=2,p=1$c2hwb29sdGVzdHNhbHQhIQ$ouVstKYNKwEkHd47UkUtUXKMnUcVT+xSCA4ymMtENtA"
+            idle_timeout = "15m"
+
+            [[keybinding]]
+            binding = "Ctrl-a x"
+            action = "lock"
+            "#,
+            r#"
+            include = ["common.toml", "conf.d/*.toml"]
+
+            [host."devbox*"]
+            prompt_prefix = "[devbox] "
+
+            [host."devbox*".env]
+            EDITOR = "vim"
+            "#,
+            r#"
+            [paths]
+            runtime_dir = "/tmp/shpool"
+            state_dir = "${HOME}/.shpool-state"
+
+            [session_audit]
+            key_cmd = "cat /etc/shpool/audit.key"
+            "#,
         ];
 
         for case in cases.into_iter() {
@@ -296,4 +1312,144 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    #[timeout(30000)]
+    fn unknown_keys() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let src = r#"
+            norc = true
+            keybining = []
+
+            [[keybinding]]
+            binding = "Ctrl-q a"
+            actoin = "detach"
+            action = "detach"
+
+            [lock]
+            idle_timout = "15m"
+            "#;
+        let path = dir.path().join("config.toml");
+        fs::write(&path, src)?;
+        let (config, mut unknown_keys) = from_table(read(&path)?)?;
+        unknown_keys.sort();
+        assert_eq!(config.norc, Some(true));
+        assert_eq!(unknown_keys, vec!["keybinding.0.actoin", "keybining", "lock.idle_timout"]);
+        assert!(parse_file(&path).is_ok());
+
+        fs::write(&path, format!("strict_config = true\n{}", src))?;
+        let err = parse_file(&path).unwrap_err();
+        assert!(format!("{:#}", err).contains("keybinding.0.actoin"));
+
+        fs::write(&path, "strict_config = true\nnorc = true")?;
+        let (_, unknown_keys) = from_table(read(&path)?)?;
+        assert!(unknown_keys.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn includes_and_hosts() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join("conf.d"))?;
+        fs::write(
+            dir.path().join("conf.d").join("a.toml"),
+            r#"
+            prompt_prefix = "from a"
+            [env]
+            A = "a"
+            B = "a"
+            "#,
+        )?;
+        fs::write(
+            dir.path().join("conf.d").join("b.toml"),
+            r#"
+            [env]
+            B = "b"
+            "#,
+        )?;
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+            include = ["conf.d/*.toml", "missing.d/*.toml"]
+            norc = true
+            [env]
+            A = "main"
+
+            [host."*"]
+            noecho = true
+
+            [host."no-such-host-*"]
+            shell = "/bin/false"
+            "#,
+        )?;
+
+        let config = parse_file(&path)?;
+        assert_eq!(config.norc, Some(true));
+        assert_eq!(config.noecho, Some(true));
+        assert_eq!(config.shell, None);
+        assert_eq!(config.prompt_prefix.as_deref(), Some("from a"));
+        let env = config.env.unwrap_or_default();
+        assert_eq!(env.get("A").map(String::as_str), Some("main"));
+        assert_eq!(env.get("B").map(String::as_str), Some("b"));
+
+        let x = dir.path().join("x.toml");
+        fs::write(&x, "include = [\"y.toml\"]")?;
+        fs::write(dir.path().join("y.toml"), "include = [\"x.toml\"]")?;
+        assert!(parse_file(&x).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn expand() {
+        let lookup = |name: &str| match name {
+            "HOME" => Some(String::from("/home/me")),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let cases = vec![
+            ("no vars", Some("no vars")),
+            ("${HOME}/.shpool", Some("/home/me/.shpool")),
+            ("${UNSET:-/tmp}/x", Some("/tmp/x")),
+            ("${EMPTY:-fallback}", Some("fallback")),
+            ("${HOME:-fallback}", Some("/home/me")),
+            ("${UNSET:-}", Some("")),
+            ("[$SHPOOL_SESSION_NAME] ", Some("[$SHPOOL_SESSION_NAME] ")),
+            ("$${HOME} costs $5", Some("${HOME} costs $5")),
+            ("$", Some("$")),
+            ("${UNSET}", None),
+            ("${EMPTY}", Some("")),
+            ("${HOME", None),
+            ("${1BAD}", None),
+            ("${}", None),
+        ];
+        for (src, want) in cases.into_iter() {
+            let got = expand_vars(src, &lookup).ok();
+            assert_eq!(got.as_deref(), want, "src={}", src);
+        }
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn glob() {
+        let cases = vec![
+            ("devbox*", "devbox12", true),
+            ("devbox*", "prod1", false),
+            ("*.toml", "a.toml", true),
+            ("*.toml", "a.toml.bak", false),
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            ("a*b*c", "aXbYbZc", true),
+            ("*x", "abc", false),
+            ("*", "", true),
+            ("", "", true),
+        ];
+        for (pattern, s, want) in cases.into_iter() {
+            assert_eq!(glob_match(pattern, s), want, "pattern={} s={}", pattern, s);
+        }
+    }
 }
@@ -38,3 +38,9 @@ pub const PROMPT_SENTINEL: &str = "SHPOOL_PROMPT_SETUP_SENTINEL";
 // in the output stream. For the same reason, we don't set the value
 // to an actual sentianl, but instead either "startup" or "prompt".
 pub const SENTINEL_FLAG_VAR: &str = "SHPOOL__INTERNAL__PRINT_SENTINEL";
+
+// Point shpool at a particular daemon, like --socket and --instance do.
+// The daemon sets these in the sessions it spawns, so that shpool
+// commands run inside a session talk to the daemon it belongs to.
+pub const SOCKET_VAR: &str = "SHPOOL_SOCKET";
+pub const INSTANCE_VAR: &str = "SHPOOL_INSTANCE";
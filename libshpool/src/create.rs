@@ -0,0 +1,107 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use tracing::info;
+
+use super::{
+    attach, config, duration, protocol,
+    protocol::{AttachHeader, AttachOption, ConnectHeader, CreateReply},
+    tty,
+};
+
+pub fn run(
+    config_file: Option<String>,
+    name: String,
+    ttl: Option<String>,
+    cmd: Option<String>,
+    forward_env: Vec<String>,
+    options: Vec<AttachOption>,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let config_manager = config::Manager::new(config_file.as_deref())?;
+
+    let ttl = match &ttl {
+        Some(src) => match duration::parse(src.as_str()) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                bail!("could not parse ttl: {:?}", e);
+            }
+        },
+        None => None,
+    };
+
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    // Nothing is attached yet, but the session still needs a size to
+    // start out with, so use the current terminal if there is one.
+    let tty_size = match tty::Size::from_fd(0) {
+        Ok(s) => s,
+        Err(e) => {
+            info!("stdin is not a tty, using default size (err: {:?})", e);
+            tty::Size { rows: 24, cols: 80, xpixel: 0, ypixel: 0 }
+        }
+    };
+
+    let mut forward_patterns = config_manager.get().forward_env.clone().unwrap_or_default();
+    forward_patterns.extend(forward_env.iter().cloned());
+
+    client.require_capability("create")?;
+    let header = AttachHeader {
+        name: name.clone(),
+        local_tty_size: tty_size,
+        local_env: attach::local_env(&forward_patterns),
+        ttl_secs: ttl.map(|d| d.as_secs()),
+        cmd,
+    };
+    let header = if options.is_empty() {
+        ConnectHeader::Create(header)
+    } else {
+        for option in options.iter() {
+            client.require_capability(option.capability())?;
+        }
+        ConnectHeader::CreateWithOptions(header, options)
+    };
+    client.write_connect_header(header).context("writing create request header")?;
+    let reply: CreateReply = client.read_reply().context("reading reply")?;
+
+    match reply {
+        CreateReply::Created { warnings } => {
+            for warning in warnings.into_iter() {
+                eprintln!("shpool: warn: {}", warning);
+            }
+        }
+        CreateReply::Exists => {
+            eprintln!("session '{}' already exists", name);
+            return Err(anyhow!("session already exists: {}", name));
+        }
+        CreateReply::Forbidden(reason) => {
+            eprintln!("forbidden: {}", reason);
+            return Err(anyhow!("forbidden: {}", reason));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,183 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Per-session access control lists, for sharing a session with other
+  users the daemon lets in through `allowed_peers`, like a coworker
+  who should be able to watch a pairing session without typing into it.
+
+  A session starts out without an ACL, and then every peer gets full
+  access, the same as before ACLs existed. Once an ACL has been set up
+  with `shpool acl`, peers only get the access it grants them, and
+  peers it doesn't mention can't attach at all. The user running the
+  daemon always has full access.
+
+  The ACL gets written to the session dir on every change, so that it
+  carries over when another daemon takes the session over.
+*/
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use tracing::{info, warn};
+
+use crate::protocol::{Access, AclEntry};
+
+/// The name of the file the ACL gets saved to in the session dir.
+pub const FILE_NAME: &str = "acl.json";
+
+#[derive(Debug)]
+pub struct Acl {
+    path: PathBuf,
+    /// The user running the daemon.
+    owner: u32,
+    /// None until an ACL gets set up.
+    entries: Option<BTreeMap<u32, Access>>,
+}
+
+impl Acl {
+    /// Load the ACL saved at the given path, if there is one. An ACL
+    /// that can't be read shuts everyone but the owner out, rather
+    /// than quietly opening the session up.
+    pub fn load(path: PathBuf, owner: u32) -> Self {
+        let entries = match read(&path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("loading acl, allowing only the owner in: {:?}", e);
+                Some(BTreeMap::new())
+            }
+        };
+        Acl { path, owner, entries }
+    }
+
+    /// The access the given user has, or None if they may not
+    /// attach at all.
+    pub fn access(&self, uid: u32) -> Option<Access> {
+        if uid == self.owner {
+            return Some(Access::ReadWrite);
+        }
+        match &self.entries {
+            Some(entries) => entries.get(&uid).copied(),
+            None => Some(Access::ReadWrite),
+        }
+    }
+
+    /// The entries of the ACL, or None if there is no ACL.
+    pub fn entries(&self) -> Option<Vec<AclEntry>> {
+        self.entries.as_ref().map(|entries| {
+            entries.iter().map(|(uid, access)| AclEntry { uid: *uid, access: *access }).collect()
+        })
+    }
+
+    pub fn grant(&mut self, uid: u32, access: Access) -> anyhow::Result<()> {
+        self.entries.get_or_insert_with(BTreeMap::new).insert(uid, access);
+        self.save()
+    }
+
+    /// Take away all of the user's access. Revoking on a session
+    /// without an ACL sets one up, which shuts out everyone else too.
+    pub fn revoke(&mut self, uid: u32) -> anyhow::Result<()> {
+        self.entries.get_or_insert_with(BTreeMap::new).remove(&uid);
+        self.save()
+    }
+
+    /// Drop the ACL, giving every peer full access again.
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        self.entries = None;
+        self.save()
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let entries = match &self.entries {
+            Some(entries) => entries,
+            None => return remove(&self.path),
+        };
+        let mut buf = serde_json::to_vec_pretty(&self.entries().unwrap_or_default())
+            .context("encoding acl")?;
+        buf.push(b'\n');
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, buf).context("writing tmp acl file")?;
+        fs::rename(&tmp_path, &self.path).context("moving acl file into place")?;
+        info!("saved acl with {} entries to {:?}", entries.len(), self.path);
+        Ok(())
+    }
+}
+
+/// Remove the ACL saved at the given path, if any, so that a new
+/// session by the same name doesn't inherit it.
+pub fn remove(path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("removing acl file {:?}", path)),
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Option<BTreeMap<u32, Access>>> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("reading acl file {:?}", path)),
+    };
+    let entries: Vec<AclEntry> =
+        serde_json::from_slice(&buf).with_context(|| format!("parsing acl file {:?}", path))?;
+    Ok(Some(entries.into_iter().map(|e| (e.uid, e.access)).collect()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn grant_revoke() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join(FILE_NAME);
+
+        let mut acl = Acl::load(path.clone(), 1000);
+        assert_eq!(acl.entries(), None);
+        assert_eq!(acl.access(1001), Some(Access::ReadWrite));
+
+        acl.grant(1001, Access::ReadOnly)?;
+        assert_eq!(acl.access(1000), Some(Access::ReadWrite));
+        assert_eq!(acl.access(1001), Some(Access::ReadOnly));
+        assert_eq!(acl.access(1002), None);
+
+        // it carries over to the next daemon
+        let mut acl = Acl::load(path.clone(), 1000);
+        assert_eq!(acl.entries(), Some(vec![AclEntry { uid: 1001, access: Access::ReadOnly }]));
+
+        acl.grant(1001, Access::ReadWrite)?;
+        assert_eq!(acl.access(1001), Some(Access::ReadWrite));
+        acl.revoke(1001)?;
+        assert_eq!(acl.access(1001), None);
+        assert_eq!(Acl::load(path.clone(), 1000).entries(), Some(vec![]));
+
+        acl.clear()?;
+        assert_eq!(acl.access(1001), Some(Access::ReadWrite));
+        assert!(!path.exists());
+
+        // a mangled file only lets the owner in
+        fs::write(&path, "{")?;
+        let acl = Acl::load(path, 1000);
+        assert_eq!(acl.access(1000), Some(Access::ReadWrite));
+        assert_eq!(acl.access(1001), None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Activity and silence monitoring for sessions.
+
+  The reader thread notes every chunk of output the shell produces, which
+  lets `shpool list` say how long a session has been quiet. While no
+  client is attached, the monitor also watches for a session that was
+  busy going quiet (a build finishing) and for a session that was quiet
+  producing output (a cron job complaining), so that the `on_silence`
+  and `on_activity` hook commands can be run.
+*/
+
+use std::time;
+
+use tracing::info;
+
+use crate::{config, daemon::hook_commands, duration};
+
+/// Output this recent makes `shpool list` call a session active.
+const DEFAULT_ACTIVE_WINDOW: time::Duration = time::Duration::from_secs(30);
+
+/// How long a session must go without output to count as quiet.
+const DEFAULT_SILENCE_THRESHOLD: time::Duration = time::Duration::from_secs(10 * 60);
+
+/// A change worth running a hook command over.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    /// A detached session that had been producing output went quiet.
+    Silence,
+    /// A detached session that had been quiet produced output.
+    Activity,
+}
+
+#[derive(Debug)]
+pub struct Monitor {
+    last_output: time::Instant,
+    attached: bool,
+    /// Set once a detached session produces output, and cleared again
+    /// when it goes quiet, so that a session which was already idle when
+    /// the client detached doesn't look like something finished.
+    busy: bool,
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Monitor { last_output: time::Instant::now(), attached: true, busy: false }
+    }
+
+    /// How long it has been since the shell last produced output.
+    pub fn idle(&self) -> time::Duration {
+        self.last_output.elapsed()
+    }
+
+    /// Note that a client attached or detached.
+    pub fn set_attached(&mut self, attached: bool) {
+        if attached != self.attached {
+            self.attached = attached;
+            self.busy = false;
+        }
+    }
+
+    /// Note that the shell produced some output.
+    pub fn output(&mut self, now: time::Instant, threshold: time::Duration) -> Option<Change> {
+        let quiet_for = now.saturating_duration_since(self.last_output);
+        self.last_output = now;
+        if self.attached {
+            return None;
+        }
+        self.busy = true;
+        if quiet_for >= threshold {
+            Some(Change::Activity)
+        } else {
+            None
+        }
+    }
+
+    /// Check whether a busy session has gone quiet. This should be
+    /// called regularly even when there is no output.
+    pub fn tick(&mut self, now: time::Instant, threshold: time::Duration) -> Option<Change> {
+        if self.attached
+            || !self.busy
+            || now.saturating_duration_since(self.last_output) < threshold
+        {
+            return None;
+        }
+        self.busy = false;
+        Some(Change::Silence)
+    }
+}
+
+/// Run the hook command for the given change, if one is configured.
+pub fn fire_hook(config: &config::Manager, change: Change, session_name: &str) {
+    info!("activity monitor: {:?}", change);
+    let event = match change {
+        Change::Silence => hook_commands::Event::Silence,
+        Change::Activity => hook_commands::Event::Activity,
+    };
+    hook_commands::fire(config, event, session_name);
+}
+
+/// The window within which output makes a session count as active.
+pub fn active_window(config: &config::Config) -> time::Duration {
+    parse_or(
+        config.activity.as_ref().and_then(|a| a.active_window.as_deref()),
+        DEFAULT_ACTIVE_WINDOW,
+    )
+}
+
+/// How long a session must go without output to count as quiet.
+pub fn silence_threshold(config: &config::Config) -> time::Duration {
+    parse_or(
+        config.activity.as_ref().and_then(|a| a.silence_threshold.as_deref()),
+        DEFAULT_SILENCE_THRESHOLD,
+    )
+}
+
+/// These get looked up constantly by the reader threads, so a bad value
+/// quietly falls back to the default rather than flooding the log.
+/// `shpool doctor` points out bad values instead.
+fn parse_or(src: Option<&str>, default: time::Duration) -> time::Duration {
+    src.and_then(|s| duration::parse(s).ok()).unwrap_or(default)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn transitions() {
+        let threshold = time::Duration::from_secs(60);
+        let secs = time::Duration::from_secs;
+        let start = time::Instant::now();
+        let mut monitor = Monitor::new();
+
+        // nothing fires while attached
+        assert_eq!(monitor.output(start + secs(120), threshold), None);
+        assert_eq!(monitor.tick(start + secs(240), threshold), None);
+
+        // an idle session that gets detached is not a finished build
+        monitor.set_attached(false);
+        assert_eq!(monitor.tick(start + secs(500), threshold), None);
+
+        // output after a long quiet spell is activity
+        assert_eq!(monitor.output(start + secs(600), threshold), Some(Change::Activity));
+        assert_eq!(monitor.output(start + secs(610), threshold), None);
+        assert_eq!(monitor.tick(start + secs(660), threshold), None);
+        assert_eq!(monitor.tick(start + secs(670), threshold), Some(Change::Silence));
+        assert_eq!(monitor.tick(start + secs(800), threshold), None);
+
+        // reattaching resets things
+        monitor.set_attached(true);
+        assert_eq!(monitor.output(start + secs(900), threshold), None);
+        monitor.set_attached(false);
+        assert_eq!(monitor.tick(start + secs(1000), threshold), None);
+    }
+}
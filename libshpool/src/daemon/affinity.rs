@@ -0,0 +1,140 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Support for pinning session shells to a set of cpus as
+  configured by the `cpu_affinity` config table.
+*/
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Context};
+use nix::{sched, unistd::Pid};
+
+use crate::config;
+
+/// The resolved affinity settings for a single session.
+#[derive(Debug, Clone)]
+pub struct Pin {
+    pub cpus: sched::CpuSet,
+    pub cgroup: Option<String>,
+}
+
+/// Find the first cpu_affinity entry matching the given session
+/// name and resolve it. Returns None if no entry matches.
+pub fn resolve(entries: &[config::CpuAffinity], session_name: &str) -> anyhow::Result<Option<Pin>> {
+    for entry in entries.iter() {
+        if matches(&entry.session, session_name) {
+            let cpus = parse_cpu_list(&entry.cpus)
+                .with_context(|| format!("parsing cpus for '{}'", entry.session))?;
+            return Ok(Some(Pin { cpus, cgroup: entry.cgroup.clone() }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Pin the calling process to the resolved cpu set. The affinity mask
+/// is inherited across fork and exec, so all of the shell's descendants
+/// will also be pinned unless they explicitly change their own mask.
+///
+/// This gets called between fork and exec, so it must not allocate.
+pub fn apply(pin: &Pin) -> nix::Result<()> {
+    // pid 0 is the calling process
+    sched::sched_setaffinity(Pid::from_raw(0), &pin.cpus)
+}
+
+/// Move the given process into the pin's cgroup, if it has one.
+pub fn join_cgroup(pin: &Pin, pid: i32) -> anyhow::Result<()> {
+    if let Some(cgroup) = &pin.cgroup {
+        let procs = Path::new(cgroup).join("cgroup.procs");
+        fs::write(&procs, format!("{}\n", pid))
+            .with_context(|| format!("moving {} into cgroup {:?}", pid, procs))?;
+    }
+
+    Ok(())
+}
+
+fn matches(pattern: &str, session_name: &str) -> bool {
+    if let Some(prefix) = pattern.strip_suffix('*') {
+        session_name.starts_with(prefix)
+    } else {
+        pattern == session_name
+    }
+}
+
+/// Parses a cpu list in the format used by cpuset.cpus and taskset,
+/// i.e. "0-3,8,10-11".
+fn parse_cpu_list(src: &str) -> anyhow::Result<sched::CpuSet> {
+    let mut cpus = sched::CpuSet::new();
+    let mut any = false;
+    for part in src.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (lo, hi) = if let Some((lo, hi)) = part.split_once('-') {
+            (lo.trim().parse::<usize>()?, hi.trim().parse::<usize>()?)
+        } else {
+            let cpu = part.parse::<usize>()?;
+            (cpu, cpu)
+        };
+        if lo > hi {
+            bail!("invalid cpu range '{}'", part);
+        }
+        for cpu in lo..=hi {
+            cpus.set(cpu).map_err(|e| anyhow!("cpu {} out of range: {:?}", cpu, e))?;
+            any = true;
+        }
+    }
+    if !any {
+        bail!("empty cpu list '{}'", src);
+    }
+
+    Ok(cpus)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn cpu_list() -> anyhow::Result<()> {
+        let cases = vec![
+            ("0", vec![0]),
+            ("1,3", vec![1, 3]),
+            ("0-2,5", vec![0, 1, 2, 5]),
+            (" 4 - 5 , 7 ", vec![4, 5, 7]),
+        ];
+        for (src, want) in cases.into_iter() {
+            let cpus = parse_cpu_list(src)?;
+            for cpu in 0..16 {
+                assert_eq!(cpus.is_set(cpu)?, want.contains(&cpu), "src={} cpu={}", src, cpu);
+            }
+        }
+
+        for bad in ["", "a", "3-1", "1-", "100000"] {
+            assert!(parse_cpu_list(bad).is_err(), "src={}", bad);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn session_match() {
+        assert!(matches("build", "build"));
+        assert!(!matches("build", "build2"));
+        assert!(matches("build-*", "build-linux"));
+        assert!(!matches("build-*", "test-linux"));
+        assert!(matches("*", "anything"));
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Asking the `attach_auth` command from the config whether an attach
+  should be allowed. Unlike the hook commands, the attach waits for the
+  answer, and anything other than a successful exit, including the
+  command failing to start or timing out, denies the attach.
+*/
+
+use std::{
+    io::Read,
+    os::unix::process::CommandExt,
+    process::{self, Stdio},
+    thread, time,
+};
+
+use anyhow::{anyhow, Context};
+use nix::{
+    sys::signal::{self, Signal},
+    unistd::Pid,
+};
+use tracing::{info, warn};
+
+use crate::{config, duration};
+
+const DEFAULT_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+const POLL_DUR: time::Duration = time::Duration::from_millis(50);
+
+/// How much of the command's output to keep for the denial message.
+const MAX_OUTPUT_LEN: u64 = 4096;
+
+/// Who is asking to attach, from the credentials of their connection.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: i32,
+}
+
+/// Run the configured command for an attach to the given session,
+/// returning the reason to give the user if the attach is denied.
+pub fn check(auth: &config::AttachAuth, session_name: &str, peer: &Peer) -> Result<(), String> {
+    let timeout = match auth.timeout.as_deref().map(duration::parse).transpose() {
+        Ok(t) => t.unwrap_or(DEFAULT_TIMEOUT),
+        Err(e) => {
+            warn!("bad attach_auth.timeout, using the default: {:?}", e);
+            DEFAULT_TIMEOUT
+        }
+    };
+
+    match run(&auth.cmd, session_name, peer, timeout) {
+        Ok(Verdict::Allow) => {
+            info!("attach_auth allowed attach to '{}' by {:?}", session_name, peer);
+            Ok(())
+        }
+        Ok(Verdict::Deny(msg)) => {
+            info!("attach_auth denied attach to '{}' by {:?}: {:?}", session_name, peer, msg);
+            Err(match msg {
+                Some(msg) => format!("attach denied: {}", msg),
+                None => String::from("attach denied by attach_auth command"),
+            })
+        }
+        Err(e) => {
+            warn!("running attach_auth command: {:?}", e);
+            Err(format!("attach denied, could not check it: {:#}", e))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Allow,
+    /// Carries the first line the command printed, if any.
+    Deny(Option<String>),
+}
+
+fn run(
+    cmd: &str,
+    session_name: &str,
+    peer: &Peer,
+    timeout: time::Duration,
+) -> anyhow::Result<Verdict> {
+    let mut command = process::Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(cmd)
+        .env("SHPOOL_SESSION_NAME", session_name)
+        .env("SHPOOL_PEER_UID", peer.uid.to_string())
+        .env("SHPOOL_PEER_GID", peer.gid.to_string())
+        .env("SHPOOL_PEER_PID", peer.pid.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        // put the command in its own process group so that a timeout
+        // kills anything it started too
+        .process_group(0);
+
+    let mut child = command.spawn().context("spawning attach_auth command")?;
+    info!("spawned attach_auth command (pid={})", child.id());
+
+    // read on the side so that a chatty command can't block on a full
+    // pipe while we wait for it to exit
+    let stdout = child.stdout.take().ok_or(anyhow!("no stdout for attach_auth command"))?;
+    let output_h = thread::spawn(move || {
+        let mut output = vec![];
+        let _ = stdout.take(MAX_OUTPUT_LEN).read_to_end(&mut output);
+        output
+    });
+
+    let deadline = time::Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().context("waiting on attach_auth command")? {
+            break status;
+        }
+        if time::Instant::now() > deadline {
+            signal::killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL)
+                .context("killing attach_auth command")?;
+            child.wait().context("reaping attach_auth command")?;
+            return Err(anyhow!("attach_auth command timed out after {:?}", timeout));
+        }
+        thread::sleep(POLL_DUR);
+    };
+
+    if status.success() {
+        return Ok(Verdict::Allow);
+    }
+    info!("attach_auth command exited with {}", status);
+    let output = output_h.join().map_err(|e| anyhow!("joining output reader: {:?}", e))?;
+    let msg = String::from_utf8_lossy(&output)
+        .lines()
+        .next()
+        .map(|l| String::from(l.trim()))
+        .filter(|l| !l.is_empty());
+    Ok(Verdict::Deny(msg))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn verdicts() -> anyhow::Result<()> {
+        let peer = Peer { uid: 1000, gid: 100, pid: 4242 };
+        let short = time::Duration::from_millis(500);
+
+        assert_eq!(run("true", "s", &peer, short)?, Verdict::Allow);
+        assert_eq!(
+            run(
+                "[ \"$SHPOOL_SESSION_NAME/$SHPOOL_PEER_UID/$SHPOOL_PEER_GID/$SHPOOL_PEER_PID\" \
+                   = s/1000/100/4242 ]",
+                "s",
+                &peer,
+                short
+            )?,
+            Verdict::Allow
+        );
+        assert_eq!(run("exit 1", "s", &peer, short)?, Verdict::Deny(None));
+        assert_eq!(
+            run("echo 'no token present'; echo more; exit 3", "s", &peer, short)?,
+            Verdict::Deny(Some(String::from("no token present")))
+        );
+
+        let start = time::Instant::now();
+        let err = run("sleep 20", "s", &peer, short).unwrap_err();
+        assert!(format!("{:?}", err).contains("timed out"));
+        assert!(start.elapsed() < time::Duration::from_secs(5));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Recordings of what a session displays, in the asciicast v2 format
+  that `asciinema play` and the asciinema web player understand.
+
+  A recording is a json header line followed by one json line per event.
+  Each event is an array of the seconds since the recording started, an
+  event code ("o" for output, "r" for a resize) and the event's data.
+  Unlike audit recordings these are plain text and meant to be shared,
+  so they only ever contain output, never input.
+*/
+
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    time,
+};
+
+use anyhow::Context;
+use serde_json::json;
+
+use crate::tty;
+
+/// Writes the recording of a single session.
+#[derive(Debug)]
+pub struct Recorder {
+    file: io::BufWriter<fs::File>,
+    started: time::Instant,
+    pub path: PathBuf,
+}
+
+/// Where a recording of the given session started now should go in the
+/// given dir.
+pub fn default_path(dir: &Path, session_name: &str) -> PathBuf {
+    let started_at = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default();
+    dir.join(format!("{}-{}.cast", session_name, started_at.as_millis()))
+}
+
+impl Recorder {
+    /// Start a new recording at the given path, which must not exist
+    /// yet, for a terminal of the given size. The parent directory gets
+    /// created if needed.
+    pub fn create(
+        path: PathBuf,
+        session_name: &str,
+        size: &tty::Size,
+        term: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+                .with_context(|| format!("creating recording dir {:?}", dir))?;
+        }
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("creating recording {:?}", path))?;
+
+        let timestamp =
+            time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default();
+        let mut header = json!({
+            "version": 2,
+            "width": size.cols,
+            "height": size.rows,
+            "timestamp": timestamp.as_secs(),
+            "title": session_name,
+        });
+        if let Some(term) = term {
+            header["env"] = json!({ "TERM": term });
+        }
+
+        let mut file = io::BufWriter::new(file);
+        writeln!(file, "{}", header).context("writing recording header")?;
+        file.flush().context("flushing recording header")?;
+
+        Ok(Recorder { file, started: time::Instant::now(), path })
+    }
+
+    /// Append some output to the recording. The reader hands over
+    /// output split on character boundaries, so lossy decoding only
+    /// mangles output that was not UTF-8 to begin with.
+    pub fn output(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.event("o", &String::from_utf8_lossy(buf))
+    }
+
+    /// Note that the terminal changed size.
+    pub fn resize(&mut self, size: &tty::Size) -> anyhow::Result<()> {
+        self.event("r", &format!("{}x{}", size.cols, size.rows))
+    }
+
+    /// Every event gets flushed right away so that the recording can be
+    /// watched while it is being made, and is complete up to the last
+    /// event even if the daemon gets killed.
+    fn event(&mut self, code: &str, data: &str) -> anyhow::Result<()> {
+        let at = self.started.elapsed().as_secs_f64();
+        writeln!(self.file, "{}", json!([(at * 1e6).round() / 1e6, code, data]))
+            .context("writing recording event")?;
+        self.file.flush().context("flushing recording event")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn round_trip() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::with_prefix("shpool-cast")?;
+        let path = default_path(&dir.path().join("recordings"), "sh1");
+        let size = tty::Size { rows: 24, cols: 80, xpixel: 0, ypixel: 0 };
+
+        let mut recorder = Recorder::create(path.clone(), "sh1", &size, Some("xterm"))?;
+        recorder.output(b"prompt> ")?;
+        recorder.output("\x1b[1mé\x1b[0m\r\n".as_bytes())?;
+        recorder.resize(&tty::Size { rows: 30, cols: 100, xpixel: 0, ypixel: 0 })?;
+        // a second recording can't clobber the first one
+        assert!(Recorder::create(path.clone(), "sh1", &size, None).is_err());
+        drop(recorder);
+
+        let contents = fs::read_to_string(&path)?;
+        let lines = contents
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[0]["height"], 24);
+        assert_eq!(lines[0]["title"], "sh1");
+        assert_eq!(lines[0]["env"]["TERM"], "xterm");
+        assert_eq!(lines[1][1], "o");
+        assert_eq!(lines[1][2], "prompt> ");
+        assert_eq!(lines[2][2], "\x1b[1mé\x1b[0m\r\n");
+        assert_eq!(lines[3][1], "r");
+        assert_eq!(lines[3][2], "100x30");
+
+        // event times never go backwards
+        let times = lines[1..].iter().map(|l| l[0].as_f64().unwrap()).collect::<Vec<_>>();
+        assert!(times.windows(2).all(|w| w[0] <= w[1]));
+
+        Ok(())
+    }
+}
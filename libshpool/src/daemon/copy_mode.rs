@@ -0,0 +1,590 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Copy mode, for paging through the scrollback of a session.
+
+  When the copy-mode keybinding fires, the reader thread takes a snapshot
+  of the lines in the output spool and the daemon draws them on the
+  client's alternate screen itself. Output from the shell keeps flowing
+  into the spool, but what would have gone to the client gets held back
+  until copy mode is done, at which point it is either replayed or the
+  screen gets redrawn from the spool. Lines can be selected and copied
+  into a fresh paste buffer in the daemon, which `shpool paste` prints.
+
+  This only works when the daemon keeps an output spool, so copy mode is
+  unavailable with `session_restore_mode = "simple"`.
+*/
+
+use std::iter::Peekable;
+
+use crate::tty;
+
+/// The most live output we are willing to hold onto while in copy mode.
+/// Past this we drop it and redraw from the spool on exit instead.
+const MAX_HELD_BYTES: usize = 1024 * 1024;
+
+const HELP: &str = "q:quit  v:select  y:copy  /?:search";
+
+/// What the caller should do after feeding copy mode some input.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Stay in copy mode and redraw.
+    Continue,
+    /// Leave copy mode.
+    Exit,
+    /// Put the text in the paste buffer and leave copy mode.
+    Copy(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Backward,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    HalfPageUp,
+    HalfPageDown,
+    Home,
+    End,
+    Enter,
+    Backspace,
+    Escape,
+    Interrupt,
+    /// Some escape sequence we don't care about.
+    Other,
+}
+
+#[derive(Debug)]
+pub struct CopyMode {
+    lines: Vec<String>,
+    size: tty::Size,
+    /// The first line shown.
+    top: usize,
+    cursor: usize,
+    /// The other end of the selection, if one has been started.
+    mark: Option<usize>,
+    /// A search being typed in.
+    prompt: Option<(Direction, String)>,
+    last_search: Option<(Direction, String)>,
+    message: Option<String>,
+    /// Whether the session was on the alternate screen when copy mode
+    /// started, in which case the client can't just pick up where it
+    /// left off.
+    alt_screen: bool,
+    held: Vec<u8>,
+    overflowed: bool,
+}
+
+impl CopyMode {
+    /// Enter copy mode over the lines spilled out of the spool, if any,
+    /// followed by the contents of the given screen, with the cursor on
+    /// the last line.
+    pub fn new(spilled: Vec<String>, screen: &shpool_vt100::Screen, size: tty::Size) -> Self {
+        let mut lines = spilled;
+        lines.extend(snapshot(screen));
+        let mut copy_mode = CopyMode {
+            cursor: lines.len() - 1,
+            lines,
+            size,
+            top: 0,
+            mark: None,
+            prompt: None,
+            last_search: None,
+            message: None,
+            alt_screen: screen.alternate_screen(),
+            held: vec![],
+            overflowed: false,
+        };
+        copy_mode.scroll_to_cursor();
+        copy_mode
+    }
+
+    /// The bytes to send to the client to put copy mode up.
+    pub fn enter(&self) -> Vec<u8> {
+        let mut buf = b"\x1b[?1049h".to_vec();
+        buf.extend(self.render());
+        buf
+    }
+
+    /// Hang onto output from the shell while the client is looking at
+    /// copy mode.
+    pub fn hold(&mut self, buf: &[u8]) {
+        if self.overflowed {
+            return;
+        }
+        if self.held.len() + buf.len() > MAX_HELD_BYTES {
+            self.overflowed = true;
+            self.held = vec![];
+            return;
+        }
+        self.held.extend_from_slice(buf);
+    }
+
+    /// The bytes to send to the client to get it back to showing the
+    /// session, given the current state of the output spool.
+    pub fn leave(self, screen: &shpool_vt100::Screen) -> Vec<u8> {
+        let mut buf = b"\x1b[?1049l".to_vec();
+        if !self.alt_screen && !self.overflowed {
+            // the main screen is just how the client left it, so it can
+            // pick back up with whatever it missed
+            buf.extend(self.held);
+        } else {
+            if screen.alternate_screen() {
+                buf.extend(b"\x1b[?1049h");
+            }
+            buf.extend(b"\x1b[H\x1b[2J");
+            buf.extend(screen.contents_formatted());
+        }
+        buf
+    }
+
+    pub fn resize(&mut self, size: tty::Size) {
+        self.size = size;
+        self.scroll_to_cursor();
+    }
+
+    /// Handle a chunk of input from the client.
+    pub fn handle_input(&mut self, buf: &[u8]) -> Outcome {
+        let input = String::from_utf8_lossy(buf);
+        let mut chars = input.chars().peekable();
+        while let Some(key) = next_key(&mut chars) {
+            if self.prompt.is_some() {
+                self.prompt_key(key);
+                continue;
+            }
+
+            self.message = None;
+            let view = self.view_rows();
+            match key {
+                Key::Char('q') | Key::Interrupt => return Outcome::Exit,
+                Key::Escape if self.mark.is_some() => self.mark = None,
+                Key::Escape => return Outcome::Exit,
+                Key::Char('k') | Key::Up => self.move_by(-1),
+                Key::Char('j') | Key::Down => self.move_by(1),
+                Key::PageUp => self.page_by(-(view as isize)),
+                Key::PageDown => self.page_by(view as isize),
+                Key::HalfPageUp => self.page_by(-(view as isize / 2).max(1)),
+                Key::HalfPageDown => self.page_by((view as isize / 2).max(1)),
+                Key::Char('g') | Key::Home => self.move_to(0),
+                Key::Char('G') | Key::End => self.move_to(self.lines.len() - 1),
+                Key::Char('v') | Key::Char(' ') => {
+                    self.mark = match self.mark {
+                        Some(_) => None,
+                        None => Some(self.cursor),
+                    };
+                }
+                Key::Char('y') | Key::Enter => return Outcome::Copy(self.selection()),
+                Key::Char('/') => self.prompt = Some((Direction::Forward, String::new())),
+                Key::Char('?') => self.prompt = Some((Direction::Backward, String::new())),
+                Key::Char('n') => self.repeat_search(false),
+                Key::Char('N') => self.repeat_search(true),
+                _ => {}
+            }
+        }
+        Outcome::Continue
+    }
+
+    fn prompt_key(&mut self, key: Key) {
+        let (dir, mut pattern) = match self.prompt.take() {
+            Some(p) => p,
+            None => return,
+        };
+        match key {
+            Key::Enter => {
+                if pattern.is_empty() {
+                    return;
+                }
+                self.search(dir, &pattern);
+                self.last_search = Some((dir, pattern));
+                return;
+            }
+            Key::Escape | Key::Interrupt => return,
+            // backspacing past the start gives up on the search
+            Key::Backspace if pattern.is_empty() => return,
+            Key::Backspace => {
+                pattern.pop();
+            }
+            Key::Char(c) if !c.is_control() => pattern.push(c),
+            _ => {}
+        }
+        self.prompt = Some((dir, pattern));
+    }
+
+    fn repeat_search(&mut self, reverse: bool) {
+        match self.last_search.clone() {
+            Some((dir, pattern)) => {
+                let dir = match (dir, reverse) {
+                    (d, false) => d,
+                    (Direction::Forward, true) => Direction::Backward,
+                    (Direction::Backward, true) => Direction::Forward,
+                };
+                self.search(dir, &pattern);
+            }
+            None => self.message = Some(String::from("no previous search")),
+        }
+    }
+
+    /// Move to the next line containing the pattern, wrapping around
+    /// the ends of the buffer.
+    fn search(&mut self, dir: Direction, pattern: &str) {
+        let n = self.lines.len();
+        let found = (1..=n)
+            .map(|step| match dir {
+                Direction::Forward => (self.cursor + step) % n,
+                Direction::Backward => (self.cursor + n - step) % n,
+            })
+            .find(|i| self.lines[*i].contains(pattern));
+        match found {
+            Some(i) => self.move_to(i),
+            None => self.message = Some(format!("not found: {}", pattern)),
+        }
+    }
+
+    fn selection(&self) -> String {
+        let (start, end) = match self.mark {
+            Some(mark) => (mark.min(self.cursor), mark.max(self.cursor)),
+            None => (self.cursor, self.cursor),
+        };
+        self.lines[start..=end].join("\n")
+    }
+
+    /// The number of rows for showing lines, leaving room for the
+    /// status line.
+    fn view_rows(&self) -> usize {
+        (self.size.rows as usize).saturating_sub(1).max(1)
+    }
+
+    fn move_by(&mut self, delta: isize) {
+        self.move_to(self.cursor.saturating_add_signed(delta));
+    }
+
+    fn move_to(&mut self, line: usize) {
+        self.cursor = line.min(self.lines.len() - 1);
+        self.scroll_to_cursor();
+    }
+
+    /// Scroll the view along with the cursor, the way pagers do.
+    fn page_by(&mut self, delta: isize) {
+        let max_top = self.lines.len().saturating_sub(self.view_rows());
+        self.top = self.top.saturating_add_signed(delta).min(max_top);
+        self.move_by(delta);
+    }
+
+    fn scroll_to_cursor(&mut self) {
+        let view = self.view_rows();
+        if self.cursor < self.top {
+            self.top = self.cursor;
+        } else if self.cursor >= self.top + view {
+            self.top = self.cursor + 1 - view;
+        }
+    }
+
+    fn selected(&self, line: usize) -> bool {
+        match self.mark {
+            Some(mark) => mark.min(self.cursor) <= line && line <= mark.max(self.cursor),
+            None => false,
+        }
+    }
+
+    /// Draw the whole view, leaving the terminal cursor on the line the
+    /// copy mode cursor is on, or in the search prompt.
+    pub fn render(&self) -> Vec<u8> {
+        let cols = self.size.cols as usize;
+        let mut buf = b"\x1b[?25l".to_vec();
+        for row in 0..self.view_rows() {
+            let line = self.top + row;
+            buf.extend(format!("\x1b[{};1H\x1b[2K", row + 1).as_bytes());
+            if let Some(text) = self.lines.get(line) {
+                if self.selected(line) {
+                    buf.extend(b"\x1b[7m");
+                }
+                buf.extend(truncate(text, cols).as_bytes());
+                buf.extend(b"\x1b[0m");
+            }
+        }
+
+        let status = match (&self.prompt, &self.message) {
+            (Some((Direction::Forward, pattern)), _) => format!("/{}", pattern),
+            (Some((Direction::Backward, pattern)), _) => format!("?{}", pattern),
+            (None, Some(message)) => message.clone(),
+            (None, None) => {
+                format!("[copy mode] line {}/{}  {}", self.cursor + 1, self.lines.len(), HELP)
+            }
+        };
+        let status_row = self.view_rows() + 1;
+        buf.extend(format!("\x1b[{};1H\x1b[2K\x1b[7m", status_row).as_bytes());
+        buf.extend(truncate(&status, cols).as_bytes());
+        buf.extend(b"\x1b[0m");
+
+        let (row, col) = match &self.prompt {
+            Some(_) => (status_row, status.chars().count().min(cols.saturating_sub(1)) + 1),
+            None => (self.cursor - self.top + 1, 1),
+        };
+        buf.extend(format!("\x1b[{};{}H\x1b[?25h", row, col).as_bytes());
+        buf
+    }
+}
+
+/// Pull all the lines out of the screen, scrollback first, with trailing
+/// whitespace trimmed and any blank lines at the bottom dropped. There is
+/// always at least one line. This is also what `shpool save-output
+/// --strip-ansi` saves.
+pub fn snapshot(screen: &shpool_vt100::Screen) -> Vec<String> {
+    let mut lines = rows(screen);
+    while lines.last().map(|l| l.is_empty()).unwrap_or(false) {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        lines.push(String::new());
+    }
+    lines
+}
+
+/// The text of each row of the screen, scrollback first, with trailing
+/// whitespace trimmed. Blank rows at the bottom may be missing, and a
+/// row that another one wraps onto carries the text of both, leaving
+/// the one it wrapped onto blank.
+///
+/// The screen can't be scrolled back further than it is tall, so rather
+/// than paging through it we take the formatted dump of every row it has
+/// and keep just the text. Wide characters count as a single column here,
+/// which can throw off the spacing after them a little.
+pub fn rows(screen: &shpool_vt100::Screen) -> Vec<String> {
+    let dump = screen.last_n_rows_contents_formatted(u16::MAX);
+    let dump = String::from_utf8_lossy(&dump);
+
+    let mut grid: Vec<Vec<char>> = vec![];
+    let (mut row, mut col) = (0, 0);
+    let mut chars = dump.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                Some('[') => {
+                    let mut params = String::new();
+                    let final_byte = loop {
+                        match chars.next() {
+                            Some(c @ '\x40'..='\x7e') => break c,
+                            Some(c) => params.push(c),
+                            None => break '\0',
+                        }
+                    };
+                    let mut args =
+                        params.split(';').map(|p| p.parse::<usize>().unwrap_or(1).max(1));
+                    match final_byte {
+                        'H' => {
+                            row = args.next().unwrap_or(1) - 1;
+                            col = args.next().unwrap_or(1) - 1;
+                        }
+                        'C' => col += args.next().unwrap_or(1),
+                        // attributes and erasing don't matter for plain text
+                        _ => {}
+                    }
+                }
+                // titles, terminated by BEL or ST
+                Some(']') => {
+                    for c in chars.by_ref() {
+                        if c == '\x07' || c == '\\' {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            '\r' => col = 0,
+            '\n' => row += 1,
+            '\x08' => col = col.saturating_sub(1),
+            c if c.is_control() => {}
+            c => {
+                if grid.len() <= row {
+                    grid.resize(row + 1, vec![]);
+                }
+                let line = &mut grid[row];
+                if line.len() <= col {
+                    line.resize(col + 1, ' ');
+                }
+                line[col] = c;
+                col += 1;
+            }
+        }
+    }
+
+    grid.into_iter().map(|l| String::from(l.into_iter().collect::<String>().trim_end())).collect()
+}
+
+fn truncate(text: &str, cols: usize) -> String {
+    text.chars().take(cols).collect()
+}
+
+fn next_key<I: Iterator<Item = char>>(chars: &mut Peekable<I>) -> Option<Key> {
+    Some(match chars.next()? {
+        '\x1b' => match chars.peek() {
+            Some('[') | Some('O') => {
+                chars.next();
+                let mut params = String::new();
+                loop {
+                    match chars.next() {
+                        Some(c @ '\x40'..='\x7e') => break escape_key(&params, c),
+                        Some(c) => params.push(c),
+                        None => break Key::Other,
+                    }
+                }
+            }
+            _ => Key::Escape,
+        },
+        '\r' | '\n' => Key::Enter,
+        '\x7f' | '\x08' => Key::Backspace,
+        '\x03' => Key::Interrupt,
+        '\x02' => Key::PageUp,
+        '\x06' => Key::PageDown,
+        '\x15' => Key::HalfPageUp,
+        '\x04' => Key::HalfPageDown,
+        c => Key::Char(c),
+    })
+}
+
+fn escape_key(params: &str, final_byte: char) -> Key {
+    match (params, final_byte) {
+        (_, 'A') => Key::Up,
+        (_, 'B') => Key::Down,
+        (_, 'H') | ("1", '~') | ("7", '~') => Key::Home,
+        (_, 'F') | ("4", '~') | ("8", '~') => Key::End,
+        ("5", '~') => Key::PageUp,
+        ("6", '~') => Key::PageDown,
+        _ => Key::Other,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    fn copy_mode(output: &str, rows: u16) -> CopyMode {
+        let mut parser = shpool_vt100::Parser::new(rows, 80, 100);
+        parser.process(output.as_bytes());
+        CopyMode::new(vec![], parser.screen(), tty::Size { rows, cols: 80, xpixel: 0, ypixel: 0 })
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn snapshot_includes_scrollback() {
+        let output: String = (1..=10).map(|i| format!("line {}  \r\n", i)).collect();
+        let mut parser = shpool_vt100::Parser::new(4, 80, 100);
+        parser.process(output.as_bytes());
+        let lines = snapshot(parser.screen());
+        let want: Vec<String> = (1..=10).map(|i| format!("line {}", i)).collect();
+        assert_eq!(lines, want);
+
+        parser.process(b"\x1b[2J\x1b[Hred \x1b[31mtext\x1b[m\x1b[2;5Hgap");
+        let lines = snapshot(parser.screen());
+        assert_eq!(lines[lines.len() - 2..], ["red text", "    gap"]);
+
+        let parser = shpool_vt100::Parser::new(4, 80, 100);
+        assert_eq!(snapshot(parser.screen()), vec![String::new()]);
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn spilled_lines_come_first() {
+        let mut parser = shpool_vt100::Parser::new(4, 80, 100);
+        parser.process(b"on screen");
+        let spilled = vec![String::from("spilled 1"), String::from("spilled 2")];
+        let size = tty::Size { rows: 4, cols: 80, xpixel: 0, ypixel: 0 };
+        let mut cm = CopyMode::new(spilled, parser.screen(), size);
+        assert_eq!(cm.lines, ["spilled 1", "spilled 2", "on screen"]);
+        assert_eq!(cm.cursor, 2);
+
+        cm.handle_input(b"g");
+        assert_eq!(cm.handle_input(b"y"), Outcome::Copy(String::from("spilled 1")));
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn movement_and_copy() {
+        let output: String = (1..=10).map(|i| format!("line {}\r\n", i)).collect();
+        let mut cm = copy_mode(&output, 4);
+        assert_eq!(cm.cursor, 9);
+        assert_eq!(cm.top, 7);
+
+        assert_eq!(cm.handle_input(b"kk"), Outcome::Continue);
+        assert_eq!(cm.cursor, 7);
+        assert_eq!(cm.handle_input(b"\x1b[A"), Outcome::Continue);
+        assert_eq!((cm.cursor, cm.top), (6, 6));
+        assert_eq!(cm.handle_input(b"\x02"), Outcome::Continue);
+        assert_eq!((cm.cursor, cm.top), (3, 3));
+        assert_eq!(cm.handle_input(b"g"), Outcome::Continue);
+        assert_eq!((cm.cursor, cm.top), (0, 0));
+        assert_eq!(cm.handle_input(b"\x1b[6~"), Outcome::Continue);
+        assert_eq!((cm.cursor, cm.top), (3, 3));
+
+        assert_eq!(cm.handle_input(b"vj"), Outcome::Continue);
+        assert_eq!(cm.handle_input(b"y"), Outcome::Copy(String::from("line 4\nline 5")));
+
+        let mut cm = copy_mode(&output, 4);
+        assert_eq!(cm.handle_input(b"kG\r"), Outcome::Copy(String::from("line 10")));
+        assert_eq!(cm.handle_input(b"q"), Outcome::Exit);
+        assert_eq!(cm.handle_input(b"v\x1b"), Outcome::Continue);
+        assert_eq!(cm.handle_input(b"\x1b"), Outcome::Exit);
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn search() {
+        let mut cm = copy_mode("apple\r\nbanana\r\ncherry\r\nbanana split\r\n", 10);
+        assert_eq!(cm.cursor, 3);
+
+        assert_eq!(cm.handle_input(b"?ban"), Outcome::Continue);
+        assert!(String::from_utf8_lossy(&cm.render()).contains("?ban"));
+        cm.handle_input(b"\r");
+        assert_eq!(cm.cursor, 1);
+        cm.handle_input(b"n");
+        assert_eq!(cm.cursor, 3);
+        cm.handle_input(b"N");
+        assert_eq!(cm.cursor, 1);
+
+        cm.handle_input(b"/cherrz\x7fy\r");
+        assert_eq!(cm.cursor, 2);
+        cm.handle_input(b"/durian\r");
+        assert_eq!(cm.cursor, 2);
+        assert!(String::from_utf8_lossy(&cm.render()).contains("not found: durian"));
+        cm.handle_input(b"/abc\x1b");
+        assert!(cm.prompt.is_none());
+        assert_eq!(cm.handle_input(b"y"), Outcome::Copy(String::from("cherry")));
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn held_output() {
+        let mut parser = shpool_vt100::Parser::new(4, 80, 100);
+        parser.process(b"prompt> ");
+        let size = tty::Size { rows: 4, cols: 80, xpixel: 0, ypixel: 0 };
+
+        let mut cm = CopyMode::new(vec![], parser.screen(), size.clone());
+        cm.hold(b"more");
+        assert_eq!(cm.leave(parser.screen()), b"\x1b[?1049lmore".to_vec());
+
+        let mut cm = CopyMode::new(vec![], parser.screen(), size);
+        cm.hold(&vec![b'x'; MAX_HELD_BYTES + 1]);
+        let buf = cm.leave(parser.screen());
+        assert!(buf.starts_with(b"\x1b[?1049l\x1b[H\x1b[2J"));
+        assert!(String::from_utf8_lossy(&buf).contains("prompt> "));
+    }
+}
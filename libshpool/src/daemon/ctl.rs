@@ -0,0 +1,359 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Per-session control sockets, which let shell scripts and editor
+  plugins drive a session with simple line commands, without attaching
+  to it or depending on the shpool cli. Each session listens on
+  ctl/<session> in the runtime dir, and the path is in
+  $SHPOOL_SESSION_CTL inside the session.
+
+  Every command is a single line, and gets a single line reply that
+  starts with "ok" or "err":
+
+  ```text
+  send-text <text>      type text into the session, with \n, \r, \t,
+                        \e, \\ and \xHH escapes
+  resize <rows> <cols>  resize the session's terminal
+  detach-clients        detach the attached client, if any
+  query-status          reply with "ok " and the session's status as
+                        json, in the same format as the status file
+  ```
+*/
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    sync::Arc,
+    thread, time,
+};
+
+use anyhow::{anyhow, bail, Context};
+use nix::{sys::socket, unistd};
+use tracing::{info, warn};
+
+use crate::{
+    daemon::{registry, shell},
+    tty,
+};
+
+/// How long to wait on the reader thread before giving up on it.
+const READER_TIMEOUT: time::Duration = time::Duration::from_millis(500);
+
+/// The longest command line we are willing to read.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+type Shells = Arc<registry::Sessions>;
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    SendText(Vec<u8>),
+    Resize { rows: u16, cols: u16 },
+    DetachClients,
+    QueryStatus,
+}
+
+/// The session a control socket drives. It gets looked up in the shells
+/// table for every command, and the child pid makes sure that a newer
+/// session by the same name doesn't get driven by accident.
+#[derive(Clone)]
+struct Target {
+    name: String,
+    child_pid: libc::pid_t,
+    shells: Shells,
+}
+
+/// A session's control socket, which stops listening and removes the
+/// socket file when dropped.
+#[derive(Debug)]
+pub struct Socket {
+    path: PathBuf,
+    listener: UnixListener,
+    ino: u64,
+}
+
+impl Socket {
+    /// Start listening for commands for the given session on a socket
+    /// at the given path, replacing anything that was left there.
+    pub fn listen(
+        path: PathBuf,
+        name: &str,
+        child_pid: libc::pid_t,
+        shells: Shells,
+    ) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+                .context("creating ctl dir")?;
+        }
+        match fs::remove_file(&path) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("removing old ctl socket"),
+        }
+        let listener = UnixListener::bind(&path).context("binding ctl socket")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+            .context("setting ctl socket permissions")?;
+        let ino = fs::metadata(&path).context("stating ctl socket")?.ino();
+
+        let target = Target { name: String::from(name), child_pid, shells };
+        let accept_listener = listener.try_clone().context("cloning ctl listener")?;
+        thread::Builder::new()
+            .name(format!("ctl({})", name))
+            .spawn(move || accept_loop(accept_listener, target))
+            .context("spawning ctl thread")?;
+
+        Ok(Socket { path, listener, ino })
+    }
+}
+
+impl std::ops::Drop for Socket {
+    fn drop(&mut self) {
+        // wakes up the accept loop, which then exits
+        if let Err(e) = socket::shutdown(self.listener.as_raw_fd(), socket::Shutdown::Both) {
+            warn!("shutting down ctl socket {:?}: {:?}", self.path, e);
+        }
+        // A new session with the same name may have taken the path
+        // over already.
+        match fs::metadata(&self.path) {
+            Ok(meta) if meta.ino() == self.ino => {
+                if let Err(e) = fs::remove_file(&self.path) {
+                    warn!("removing ctl socket {:?}: {:?}", self.path, e);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn accept_loop(listener: UnixListener, target: Target) {
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let target = target.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, &target) {
+                        info!("ctl connection for '{}': {:?}", target.name, e);
+                    }
+                });
+            }
+            // the socket got shut down
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(e) => {
+                warn!("accepting ctl connection for '{}': {:?}", target.name, e);
+                thread::sleep(READER_TIMEOUT);
+            }
+        }
+    }
+}
+
+fn serve(stream: UnixStream, target: &Target) -> anyhow::Result<()> {
+    let cred = socket::getsockopt(&stream, socket::sockopt::PeerCredentials)
+        .context("getting peer credentials")?;
+    if cred.uid() != unistd::getuid().as_raw() {
+        bail!("refusing ctl connection from uid {}", cred.uid());
+    }
+
+    let mut writer = stream.try_clone().context("cloning ctl stream")?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let len = (&mut reader)
+            .take(MAX_LINE_LEN as u64)
+            .read_line(&mut line)
+            .context("reading ctl command")?;
+        if len == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && len == MAX_LINE_LEN {
+            writeln!(writer, "err command too long")?;
+            return Ok(());
+        }
+
+        let reply = match parse(line.trim_end_matches(['\r', '\n'])) {
+            Ok(cmd) => {
+                info!("ctl command for '{}': {:?}", target.name, cmd);
+                match target.run(cmd) {
+                    Ok(Some(out)) => format!("ok {}", out),
+                    Ok(None) => String::from("ok"),
+                    Err(e) => format!("err {:#}", e),
+                }
+            }
+            Err(e) => format!("err {:#}", e),
+        };
+        writeln!(writer, "{}", reply).context("writing ctl reply")?;
+    }
+}
+
+impl Target {
+    fn run(&self, cmd: Command) -> anyhow::Result<Option<String>> {
+        match cmd {
+            Command::SendText(text) => {
+                // Writing to the pty can block if the shell is not
+                // reading its input, so don't hold the table lock.
+                let (pty, recorder, lock) = self.with_session(|s| {
+                    (Arc::clone(&s.pty), s.recorder.clone(), Arc::clone(&s.lock))
+                })?;
+                // The lock keeps input out until someone gives the
+                // passphrase, and that goes for scripted input too.
+                if lock.is_locked() {
+                    bail!("session '{}' is locked", self.name);
+                }
+                shell::send_input(&*pty, recorder.as_deref(), &text)?;
+                Ok(None)
+            }
+            Command::Resize { rows, cols } => {
+                let size = tty::Size { rows, cols, xpixel: 0, ypixel: 0 }.clamped();
+                // Waiting on the reader can take a while, so don't hold
+                // the table lock, which would hold up other sessions.
+                let reader_ctl = self.with_session(|s| {
+                    s.status_file.lock().unwrap().update(|st| st.tty_size = Some(size.clone()));
+                    Arc::clone(&s.reader_ctl)
+                })?;
+                let reader_ctl = reader_ctl.lock().unwrap();
+                reader_ctl
+                    .tty_size_change
+                    .send_timeout(size, READER_TIMEOUT)
+                    .context("sending tty size change to reader")?;
+                reader_ctl
+                    .tty_size_change_ack
+                    .recv_timeout(READER_TIMEOUT)
+                    .context("recving tty size change ack from reader")?;
+                Ok(None)
+            }
+            Command::DetachClients => {
+                let reader_ctl = self.with_session(|s| Arc::clone(&s.reader_ctl))?;
+                reader_ctl.lock().unwrap().detach()?;
+                Ok(None)
+            }
+            Command::QueryStatus => {
+                let status = self.with_session(|s| s.status_file.lock().unwrap().status())?;
+                Ok(Some(serde_json::to_string(&status).context("encoding status")?))
+            }
+        }
+    }
+
+    fn with_session<F, R>(&self, f: F) -> anyhow::Result<R>
+    where
+        F: FnOnce(&shell::Session) -> R,
+    {
+        let shells = self.shells.shard(&self.name);
+        match shells.get(&self.name) {
+            Some(s) if s.child_pid == self.child_pid => Ok(f(s)),
+            _ => Err(anyhow!("session '{}' is gone", self.name)),
+        }
+    }
+}
+
+fn parse(line: &str) -> anyhow::Result<Command> {
+    let (verb, rest) = line.split_once(' ').unwrap_or((line, ""));
+    Ok(match verb {
+        "send-text" => Command::SendText(unescape(rest)?),
+        "resize" => {
+            let mut parts = rest.split_whitespace();
+            let (rows, cols) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(rows), Some(cols), None) => (rows, cols),
+                _ => bail!("usage: resize <rows> <cols>"),
+            };
+            let rows = rows.parse().with_context(|| format!("parsing rows '{}'", rows))?;
+            let cols = cols.parse().with_context(|| format!("parsing cols '{}'", cols))?;
+            if rows == 0 || cols == 0 {
+                bail!("size must be at least 1x1");
+            }
+            Command::Resize { rows, cols }
+        }
+        "detach-clients" if rest.is_empty() => Command::DetachClients,
+        "query-status" if rest.is_empty() => Command::QueryStatus,
+        "detach-clients" | "query-status" => bail!("{} takes no arguments", verb),
+        _ => bail!("unknown command '{}'", verb),
+    })
+}
+
+/// Expand the backslash escapes in the text for send-text, which is how
+/// it can contain newlines and control characters.
+fn unescape(text: &str) -> anyhow::Result<Vec<u8>> {
+    let mut out = vec![];
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'n') => out.push(b'\n'),
+            Some(b'r') => out.push(b'\r'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'e') => out.push(0x1b),
+            Some(b'\\') => out.push(b'\\'),
+            Some(b'x') => {
+                let hex = [bytes.next(), bytes.next()];
+                let hex = match hex {
+                    [Some(hi), Some(lo)] => [hi, lo],
+                    _ => bail!("\\x needs two hex digits"),
+                };
+                let hex = std::str::from_utf8(&hex).context("parsing \\x escape")?;
+                out.push(u8::from_str_radix(hex, 16).context("parsing \\x escape")?);
+            }
+            Some(c) => bail!("unknown escape '\\{}'", c as char),
+            None => bail!("trailing backslash"),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn parse_commands() -> anyhow::Result<()> {
+        assert_eq!(parse("send-text echo hi\\r")?, Command::SendText(b"echo hi\r".to_vec()));
+        assert_eq!(parse("send-text")?, Command::SendText(vec![]));
+        assert_eq!(
+            parse("send-text \\e[A \\\\ \\x03\\t")?,
+            Command::SendText(b"\x1b[A \\ \x03\t".to_vec())
+        );
+        assert_eq!(parse("resize 24 80")?, Command::Resize { rows: 24, cols: 80 });
+        assert_eq!(parse("detach-clients")?, Command::DetachClients);
+        assert_eq!(parse("query-status")?, Command::QueryStatus);
+
+        for bad in [
+            "",
+            "frobnicate",
+            "send-text \\q",
+            "send-text trailing\\",
+            "send-text \\x4",
+            "send-text \\xzz",
+            "resize 24",
+            "resize 24 80 1",
+            "resize 0 80",
+            "resize -1 80",
+            "detach-clients now",
+        ] {
+            assert!(parse(bad).is_err(), "line={}", bad);
+        }
+
+        Ok(())
+    }
+}
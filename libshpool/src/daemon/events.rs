@@ -0,0 +1,112 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Streaming session lifecycle events to `shpool events`.
+
+  Every event that could run a hook command gets published here too,
+  and each connection streaming events has a queue of its own. A
+  subscriber that falls so far behind that its queue fills up gets
+  dropped rather than holding up the session the event came from.
+*/
+
+use std::{sync::Mutex, time};
+
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use tracing::warn;
+
+use super::hook_commands::Event;
+use crate::protocol::{SessionEvent, SessionEventKind};
+
+/// How many events can pile up for a subscriber before it gets dropped.
+const QUEUE_LEN: usize = 1024;
+
+static SUBSCRIBERS: Mutex<Vec<Sender<SessionEvent>>> = Mutex::new(Vec::new());
+
+/// Start hearing about events. The subscription ends when the
+/// receiver gets dropped, and the receiver disconnects if it falls too
+/// far behind.
+pub fn subscribe() -> Receiver<SessionEvent> {
+    let (tx, rx) = crossbeam_channel::bounded(QUEUE_LEN);
+    SUBSCRIBERS.lock().unwrap().push(tx);
+    rx
+}
+
+/// Hand the event to every subscriber.
+pub fn publish(event: &Event, session_name: &str) {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    if subscribers.is_empty() {
+        return;
+    }
+
+    let at_ms = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let event = SessionEvent { at_ms, session: String::from(session_name), kind: kind(event) };
+    subscribers.retain(|tx| match tx.try_send(event.clone()) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            warn!("dropping an events subscriber that fell behind");
+            false
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    });
+}
+
+fn kind(event: &Event) -> SessionEventKind {
+    match event {
+        Event::SessionCreate => SessionEventKind::Created,
+        Event::Attach => SessionEventKind::Attached,
+        Event::Detach => SessionEventKind::Detached,
+        Event::SessionExit(exit_status) => SessionEventKind::Exited { exit_status: *exit_status },
+        Event::Bell => SessionEventKind::Bell,
+        Event::Notification { title, body } => {
+            SessionEventKind::Notification { title: title.clone(), body: body.clone() }
+        }
+        Event::Silence => SessionEventKind::Silence,
+        Event::Activity => SessionEventKind::Activity,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn subscribers() {
+        // other tests may be publishing too, so only look at our session
+        let session = "events-test-session";
+        let next =
+            |rx: &Receiver<SessionEvent>| rx.iter().find(|e| e.session == session).map(|e| e.kind);
+
+        let first = subscribe();
+        let second = subscribe();
+        publish(&Event::SessionCreate, session);
+        assert_eq!(next(&first), Some(SessionEventKind::Created));
+        assert_eq!(next(&second), Some(SessionEventKind::Created));
+
+        drop(second);
+        publish(&Event::SessionExit(3), session);
+        assert_eq!(next(&first), Some(SessionEventKind::Exited { exit_status: 3 }));
+
+        // a subscriber that never reads gets cut off
+        for _ in 0..=QUEUE_LEN {
+            publish(&Event::Activity, session);
+        }
+        assert!(first.iter().filter(|e| e.session == session).count() <= QUEUE_LEN);
+        assert!(first.recv().is_err());
+    }
+}
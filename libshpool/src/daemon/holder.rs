@@ -0,0 +1,294 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Keeping sessions alive across a daemon restart.
+
+  A session's shell gets a SIGHUP as soon as the last copy of its pty
+  master is closed, which normally happens when the daemon exits. To
+  keep the sessions around, `shpool daemon stop --keep-sessions` hands
+  the masters off to a holder process (`shpool daemon hold`, which isn't
+  meant to be run by hand) before the daemon exits. The holder does
+  nothing but keep the masters open until the next daemon starts up and
+  asks for them on the holder socket, which sits next to the daemon's
+  socket. Both hand offs pass the fds over a unix socket with
+  SCM_RIGHTS.
+
+  The holder doesn't read from the ptys, so a session that writes a lot
+  while there is no daemon will block until it gets adopted. Only the
+  ptys themselves survive the trip, the scrollback, environment record
+  and everything else the old daemon knew about the session is lost.
+*/
+
+use std::{
+    fs,
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            net::{UnixListener, UnixStream},
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    process, thread, time,
+};
+
+use anyhow::{anyhow, Context};
+use nix::{
+    errno::Errno,
+    sys::{
+        signal,
+        socket::{self, ControlMessage, ControlMessageOwned, MsgFlags},
+    },
+    unistd::{self, Pid},
+};
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::consts;
+
+/// The most fds the kernel will pass in a single message (SCM_MAX_FD).
+const MAX_HELD: usize = 253;
+
+/// How long to give the holder to get its socket set up.
+const HOLDER_START_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// How often the holder checks for a daemon, and whether any of the
+/// sessions it holds are still around.
+const HOLDER_POLL_DUR: time::Duration = time::Duration::from_millis(500);
+
+/// What the next daemon needs to know about a held session, along
+/// with its pty master.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HeldSession {
+    pub name: String,
+    pub child_pid: libc::pid_t,
+    pub started_at: time::SystemTime,
+}
+
+/// Where the holder for the daemon listening on the given socket
+/// waits for the next daemon.
+pub fn socket_path(daemon_socket: &Path) -> PathBuf {
+    let mut path = daemon_socket.as_os_str().to_owned();
+    path.push(".held");
+    PathBuf::from(path)
+}
+
+/// Start a holder for the given sessions, which come with the raw pty
+/// master fds in the same order. Returns once the holder is listening
+/// on its socket.
+pub fn spawn(socket: &Path, sessions: &[HeldSession], fds: &[RawFd]) -> anyhow::Result<()> {
+    let (daemon_end, holder_end) = UnixStream::pair().context("creating holder socket pair")?;
+    let exe = std::env::current_exe().context("finding the shpool binary")?;
+    let mut cmd = process::Command::new(exe);
+    cmd.arg("daemon")
+        .arg("hold")
+        .arg(socket)
+        .stdin(OwnedFd::from(holder_end))
+        // the holder outlives the daemon, and whatever was reading
+        // the daemon's output might go away along with it
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        // the holder has no test hooks, so it must not wait for a
+        // test to connect to them
+        .env_remove("SHPOOL_TEST_HOOK_SOCKET_PATH")
+        // stay out of the way of anything signaling the daemon's group
+        .process_group(0);
+    let child = cmd.spawn().context("spawning holder")?;
+    // drop our copy of the holder's end so that we see EOF if it dies
+    drop(cmd);
+    info!("spawned holder (pid={}) for {} sessions", child.id(), sessions.len());
+
+    send(&daemon_end, sessions, fds).context("handing sessions to holder")?;
+    daemon_end.set_read_timeout(Some(HOLDER_START_TIMEOUT)).context("setting read timeout")?;
+    let mut ready = [0; 1];
+    (&daemon_end).read_exact(&mut ready).context("waiting for holder to start")?;
+
+    Ok(())
+}
+
+/// The body of `shpool daemon hold`. Takes the sessions from the daemon
+/// on stdin, then waits for the next daemon to come and get them.
+pub fn run(socket: PathBuf) -> anyhow::Result<()> {
+    // Safety: the daemon always hands us a socket as stdin, and nothing
+    //         else in this process uses stdin.
+    let daemon = unsafe { UnixStream::from_raw_fd(consts::STDIN_FD) };
+    let (sessions, fds) = recv(&daemon).context("taking sessions from daemon")?;
+    info!("holding {} sessions", sessions.len());
+
+    match fs::remove_file(&socket) {
+        Ok(()) => info!("removed stale holder socket"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context("removing stale holder socket"),
+    }
+    let listener = UnixListener::bind(&socket).context("binding holder socket")?;
+    listener.set_nonblocking(true).context("setting holder socket nonblocking")?;
+    (&daemon).write_all(b"\n").context("telling daemon we are ready")?;
+    drop(daemon);
+
+    let res = loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = check_peer(&stream) {
+                    warn!("turning away holder client: {:?}", e);
+                    continue;
+                }
+                stream.set_nonblocking(false).context("setting stream blocking")?;
+                let raw_fds: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+                info!("handing {} sessions to new daemon", sessions.len());
+                break send(&stream, &sessions, &raw_fds);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let alive = sessions.iter().any(|s| {
+                    !matches!(signal::kill(Pid::from_raw(s.child_pid), None), Err(Errno::ESRCH))
+                });
+                if !alive {
+                    info!("all held sessions have exited");
+                    break Ok(());
+                }
+                thread::sleep(HOLDER_POLL_DUR);
+            }
+            Err(e) => break Err(e).context("accepting holder client"),
+        }
+    };
+
+    fs::remove_file(&socket).context("cleaning up holder socket")?;
+    res
+}
+
+/// Take the sessions from the holder waiting next to the given daemon
+/// socket, if there is one.
+pub fn adopt(daemon_socket: &Path) -> anyhow::Result<Vec<(HeldSession, OwnedFd)>> {
+    let socket = socket_path(daemon_socket);
+    let stream = match UnixStream::connect(&socket) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            warn!("removing holder socket left behind by a dead holder");
+            fs::remove_file(&socket).context("removing dead holder socket")?;
+            return Ok(vec![]);
+        }
+        Err(e) => return Err(e).context("connecting to holder"),
+    };
+    let (sessions, fds) = recv(&stream).context("taking sessions from holder")?;
+    Ok(sessions.into_iter().zip(fds).collect())
+}
+
+/// Only hand the sessions to the same user the holder is running as.
+fn check_peer(stream: &UnixStream) -> anyhow::Result<()> {
+    let cred = socket::getsockopt(stream, socket::sockopt::PeerCredentials)
+        .context("getting peer credentials")?;
+    if cred.uid() != unistd::getuid().as_raw() {
+        return Err(anyhow!("uid {} is not ours", cred.uid()));
+    }
+    Ok(())
+}
+
+/// Send the sessions as a length prefixed bincode blob with the fds
+/// riding along on the first byte.
+fn send(stream: &UnixStream, sessions: &[HeldSession], fds: &[RawFd]) -> anyhow::Result<()> {
+    if fds.len() > MAX_HELD {
+        return Err(anyhow!("can only hold {} sessions, not {}", MAX_HELD, fds.len()));
+    }
+    let payload = bincode::serialize(sessions).context("encoding held sessions")?;
+    let mut buf = (payload.len() as u32).to_le_bytes().to_vec();
+    buf.extend(payload);
+
+    let iov = [IoSlice::new(&buf)];
+    let cmsgs = [ControlMessage::ScmRights(fds)];
+    let cmsgs = if fds.is_empty() { &cmsgs[..0] } else { &cmsgs[..] };
+    let sent = socket::sendmsg::<()>(stream.as_raw_fd(), &iov, cmsgs, MsgFlags::empty(), None)
+        .context("sending held sessions")?;
+    let mut stream = stream;
+    stream.write_all(&buf[sent..]).context("sending rest of held sessions")?;
+    Ok(())
+}
+
+fn recv(stream: &UnixStream) -> anyhow::Result<(Vec<HeldSession>, Vec<OwnedFd>)> {
+    let mut len_buf = [0; 4];
+    let mut fds = vec![];
+    let nread = {
+        let mut iov = [IoSliceMut::new(&mut len_buf)];
+        let mut cmsg_buf = nix::cmsg_space!([RawFd; MAX_HELD]);
+        let msg = socket::recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buf),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )
+        .context("receiving held sessions")?;
+        for cmsg in msg.cmsgs() {
+            if let ControlMessageOwned::ScmRights(raw_fds) = cmsg {
+                // Safety: the kernel just gave us these fds, so nothing
+                //         else owns them.
+                fds.extend(raw_fds.into_iter().map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }));
+            }
+        }
+        msg.bytes
+    };
+    let mut stream = stream;
+    stream.read_exact(&mut len_buf[nread..]).context("reading held sessions length")?;
+    let mut payload = vec![0; u32::from_le_bytes(len_buf) as usize];
+    stream.read_exact(&mut payload).context("reading held sessions")?;
+    let sessions: Vec<HeldSession> =
+        bincode::deserialize(&payload).context("decoding held sessions")?;
+    if sessions.len() != fds.len() {
+        return Err(anyhow!("got {} held sessions but {} fds", sessions.len(), fds.len()));
+    }
+    Ok((sessions, fds))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn round_trip() -> anyhow::Result<()> {
+        let (a, b) = UnixStream::pair()?;
+        let (pipe_r, pipe_w) = unistd::pipe()?;
+        let sessions = vec![HeldSession {
+            name: String::from("sh1"),
+            child_pid: 1234,
+            started_at: time::SystemTime::UNIX_EPOCH,
+        }];
+        send(&a, &sessions, &[pipe_w.as_raw_fd()])?;
+        drop(pipe_w);
+
+        let (got, fds) = recv(&b)?;
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].name, "sh1");
+        assert_eq!(got[0].child_pid, 1234);
+
+        // the fd we got is a fresh copy of the write end of the pipe
+        let mut w = fs::File::from(fds.into_iter().next().unwrap());
+        w.write_all(b"hi")?;
+        drop(w);
+        let mut out = String::new();
+        fs::File::from(pipe_r).read_to_string(&mut out)?;
+        assert_eq!(out, "hi");
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn socket_next_to_daemon() {
+        assert_eq!(
+            socket_path(Path::new("/run/user/1000/shpool/shpool.socket")),
+            PathBuf::from("/run/user/1000/shpool/shpool.socket.held")
+        );
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Running the user's own commands on session lifecycle events, as
+  configured by the `hooks` config table. This is the config file
+  counterpart to the `Hooks` trait that wrapping binaries can implement.
+
+  Commands get run with `sh -c` in a background thread so that a slow
+  command can't hold up the daemon, and get killed if they run for
+  longer than the configured timeout.
+*/
+
+use std::{
+    os::unix::process::CommandExt,
+    process::{self, Stdio},
+    thread, time,
+};
+
+use anyhow::{anyhow, Context};
+use nix::{
+    sys::signal::{self, Signal},
+    unistd::Pid,
+};
+use tracing::{info, span, warn, Level};
+
+use super::events;
+use crate::{config, duration, test_hooks};
+
+const DEFAULT_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+const POLL_DUR: time::Duration = time::Duration::from_millis(50);
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    SessionCreate,
+    Attach,
+    Detach,
+    /// Carries the exit status of the shell.
+    SessionExit(i32),
+    /// A BEL from a session with no client attached.
+    Bell,
+    /// An OSC 9 or OSC 777 desktop notification from a session with no
+    /// client attached.
+    Notification {
+        title: Option<String>,
+        body: String,
+    },
+    /// A busy session with no client attached went quiet.
+    Silence,
+    /// A quiet session with no client attached produced output.
+    Activity,
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::SessionCreate => "session_create",
+            Event::Attach => "attach",
+            Event::Detach => "detach",
+            Event::SessionExit(_) => "session_exit",
+            Event::Bell => "bell",
+            Event::Notification { .. } => "notification",
+            Event::Silence => "silence",
+            Event::Activity => "activity",
+        }
+    }
+
+    /// The name of the config key holding the command for the event.
+    fn key(&self) -> &'static str {
+        match self {
+            Event::SessionCreate => "on_session_create",
+            Event::Attach => "on_attach",
+            Event::Detach => "on_detach",
+            Event::SessionExit(_) => "on_session_exit",
+            Event::Bell | Event::Notification { .. } => "on_alert",
+            Event::Silence => "on_silence",
+            Event::Activity => "on_activity",
+        }
+    }
+
+    fn command<'a>(&self, hooks: &'a config::HookCommands) -> Option<&'a String> {
+        match self {
+            Event::SessionCreate => hooks.on_session_create.as_ref(),
+            Event::Attach => hooks.on_attach.as_ref(),
+            Event::Detach => hooks.on_detach.as_ref(),
+            Event::SessionExit(_) => hooks.on_session_exit.as_ref(),
+            Event::Bell | Event::Notification { .. } => hooks.on_alert.as_ref(),
+            Event::Silence => hooks.on_silence.as_ref(),
+            Event::Activity => hooks.on_activity.as_ref(),
+        }
+    }
+
+    /// The event specific environment variables for the command.
+    fn env(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Event::SessionExit(status) => {
+                vec![("SHPOOL_SESSION_EXIT_STATUS", status.to_string())]
+            }
+            Event::Notification { title, body } => {
+                let mut env = vec![("SHPOOL_ALERT_BODY", body.clone())];
+                if let Some(title) = title {
+                    env.push(("SHPOOL_ALERT_TITLE", title.clone()));
+                }
+                env
+            }
+            _ => vec![],
+        }
+    }
+}
+
+/// Run the command configured for the given event, if there is one,
+/// without waiting for it, and let any `shpool events` subscribers know.
+pub fn fire(config: &config::Manager, event: Event, session_name: &str) {
+    events::publish(&event, session_name);
+
+    let hooks = match config.get().hooks.clone() {
+        Some(h) => h,
+        None => return,
+    };
+    let cmd = match event.command(&hooks) {
+        Some(cmd) => cmd.clone(),
+        None => return,
+    };
+    let timeout = match hooks.timeout.as_deref().map(duration::parse).transpose() {
+        Ok(t) => t.unwrap_or(DEFAULT_TIMEOUT),
+        Err(e) => {
+            warn!("bad hooks.timeout, using the default: {:?}", e);
+            DEFAULT_TIMEOUT
+        }
+    };
+
+    let session_name = String::from(session_name);
+    let res = thread::Builder::new().name(format!("hook({})", event.name())).spawn(move || {
+        let _s = span!(Level::INFO, "hook_command", s = session_name).entered();
+        if let Err(e) = run(&cmd, &event, &session_name, timeout) {
+            warn!("running {} hook: {:?}", event.key(), e);
+        }
+        test_hooks::emit("daemon-hook-command-done");
+    });
+    if let Err(e) = res {
+        warn!("spawning hook thread: {:?}", e);
+    }
+}
+
+fn run(
+    cmd: &str,
+    event: &Event,
+    session_name: &str,
+    timeout: time::Duration,
+) -> anyhow::Result<()> {
+    let mut command = process::Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(cmd)
+        .env("SHPOOL_SESSION_NAME", session_name)
+        .env("SHPOOL_HOOK_EVENT", event.name())
+        .envs(event.env())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        // put the hook in its own process group so that a timeout
+        // kills anything it started too
+        .process_group(0);
+
+    let mut child = command.spawn().context("spawning hook command")?;
+    info!("spawned {} hook (pid={})", event.key(), child.id());
+    let deadline = time::Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().context("waiting on hook command")? {
+            if !status.success() {
+                return Err(anyhow!("hook command exited with {}", status));
+            }
+            return Ok(());
+        }
+        if time::Instant::now() > deadline {
+            signal::killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL)
+                .context("killing hook command")?;
+            child.wait().context("reaping hook command")?;
+            return Err(anyhow!("hook command timed out after {:?}", timeout));
+        }
+        thread::sleep(POLL_DUR);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn exit_and_timeout() {
+        let short = time::Duration::from_millis(200);
+        assert!(run("true", &Event::Attach, "s", short).is_ok());
+        assert!(run("[ \"$SHPOOL_SESSION_EXIT_STATUS\" = 7 ]", &Event::SessionExit(7), "s", short)
+            .is_ok());
+        assert!(run(
+            "[ \"$SHPOOL_HOOK_EVENT/$SHPOOL_ALERT_BODY\" = notification/done ]",
+            &Event::Notification { title: None, body: String::from("done") },
+            "s",
+            short
+        )
+        .is_ok());
+        assert!(run("exit 1", &Event::Attach, "s", short).is_err());
+
+        let start = time::Instant::now();
+        let err = run("sleep 20", &Event::Attach, "s", short).unwrap_err();
+        assert!(format!("{:?}", err).contains("timed out"));
+        assert!(start.elapsed() < time::Duration::from_secs(5));
+    }
+}
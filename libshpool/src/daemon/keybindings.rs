@@ -36,17 +36,52 @@
 //!
 //! key ::= mod | sym
 //!
-//! mod ::= 'Ctrl'
+//! mod ::= 'Ctrl' | 'Alt'
 //!
-//! sym ::= 'Space' | <lowercase letters> | <numbers>
+//! sym ::= 'Space' | 'F1' ... 'F12' | <letters> | <numbers>
+//!       | <punctuation besides '-'>
 //! ```
 //!
 //! chords bind tighter than sequnces. A chord must be pressed all at once
 //! while a sequence should have the keys pressed one after another.
 //!
-//! For now, only fairly limited chords are supported. Chords must either
-//! be singletons besides 'Ctrl' or of the form 'Ctrl-x' where
-//! x is some non-'Ctrl' key.
+//! For now, only fairly limited chords are supported. Chords are a
+//! single non-mod key, with Ctrl, Alt or both in front of it. A chord
+//! gets matched against the bytes the terminal sends for it, which is
+//! a single byte for most keys, but an ESC followed by the key for Alt
+//! and an escape sequence for the function keys. Ctrl only goes with
+//! the function keys and the keys that have a
+//! control code: Space, the letters, `@`, `[`, `\`, `]`, `^`, `_`, `?`
+//! and some of the digits. Terminals send the same code for Ctrl-A as
+//! for Ctrl-a, so the two are the same chord. Keys like `;` work on
+//! their own, but Ctrl-; is rejected since most terminals just send a
+//! plain `;` for it.
+//!
+//! ## CSI u
+//!
+//! Programs that enable the kitty keyboard protocol get key presses
+//! reported as `CSI key ; modifiers u` escape sequences rather than
+//! the legacy control codes, which would normally hide them from
+//! the engine. With CSI u decoding turned on, these sequences are
+//! translated back into the legacy code for the same chord before
+//! matching. If the terminal reports the key in the base (US) layout
+//! as well, that is what gets used, so chords keep working the same
+//! way no matter what keyboard layout is active.
+//!
+//! ## Bracketed paste
+//!
+//! When the program in the session turns on bracketed paste mode, the
+//! terminal wraps pasted text in `ESC [ 200 ~` and `ESC [ 201 ~`. The
+//! engine passes everything between those markers straight through, so
+//! pasting text that happens to contain the bytes of a keybinding doesn't
+//! fire it or get part of the paste held back as a partial match.
+//!
+//! ## Where the engine runs
+//!
+//! Only input coming from the attached client goes through the engine,
+//! in the client's attach loop. Output from the shell never does, so
+//! the cost of matching scales with what gets typed or pasted rather
+//! than with what the session prints.
 
 use std::{collections::HashMap, fmt};
 
@@ -79,6 +114,50 @@ pub struct Bindings {
     sequences: Trie<ChordAtom, Action, Vec<Option<usize>>>,
     /// The current match state in the sequences trie.
     sequences_cursor: TrieCursor,
+    /// The CSI u decoder, if CSI u decoding is turned on.
+    csi_u: Option<CsiUDecoder>,
+    /// The bytes that can get anything going from the start state.
+    needles: Needles,
+    /// Whether we are in the middle of a bracketed paste.
+    paste: PasteTracker,
+}
+
+/// A set of bytes to search input for. Almost everyone has three or
+/// fewer bindings, so we can almost always hand the search off to memchr.
+enum Needles {
+    None,
+    One(u8),
+    Two(u8, u8),
+    Three(u8, u8, u8),
+    Many(Box<[bool; 256]>),
+}
+
+impl Needles {
+    fn new(bytes: &[u8]) -> Self {
+        match *bytes {
+            [] => Needles::None,
+            [a] => Needles::One(a),
+            [a, b] => Needles::Two(a, b),
+            [a, b, c] => Needles::Three(a, b, c),
+            _ => {
+                let mut tab = Box::new([false; 256]);
+                for b in bytes.iter() {
+                    tab[*b as usize] = true;
+                }
+                Needles::Many(tab)
+            }
+        }
+    }
+
+    fn find(&self, haystack: &[u8]) -> Option<usize> {
+        match self {
+            Needles::None => None,
+            Needles::One(a) => memchr::memchr(*a, haystack),
+            Needles::Two(a, b) => memchr::memchr2(*a, *b, haystack),
+            Needles::Three(a, b, c) => memchr::memchr3(*a, *b, *c, haystack),
+            Needles::Many(tab) => haystack.iter().position(|b| tab[*b as usize]),
+        }
+    }
 }
 
 /// The result of advancing the binding engine by a single byte.
@@ -100,7 +179,7 @@ struct ChordAtom(u8);
 
 impl TrieTab<ChordAtom> for Vec<Option<usize>> {
     fn new() -> Self {
-        vec![None; u8::MAX as usize]
+        vec![None; u8::MAX as usize + 1]
     }
 
     fn get(&self, index: ChordAtom) -> Option<&usize> {
@@ -121,22 +200,45 @@ impl Bindings {
         let mut sequences = Trie::new();
 
         let mut chord_atom_counter: usize = 0;
-        let mut chord_atom_tab = HashMap::new();
+        let mut chord_atom_tab: HashMap<Vec<u8>, (ChordAtom, Chord)> = HashMap::new();
+        let mut first_keys = vec![];
 
         let tokenizer = Lexer::new();
         for (binding_src, action) in bindings.into_iter() {
-            let tokens =
-                tokenizer.tokenize(binding_src.chars()).context("tokenizing keybinding")?;
-            let sequence = parse(tokens).context("parsing keybinding")?;
-            for chord in sequence.0.iter() {
-                // resolving the key code will also check the validity
-                let code = chord.key_code()?;
-
-                let chord_atom = chord_atom_tab.entry(chord.clone()).or_insert_with(|| {
-                    let atom = ChordAtom(chord_atom_counter as u8);
-                    chord_atom_counter += 1;
-                    atom
+            let tokens = tokenizer.tokenize(binding_src).context("tokenizing keybinding")?;
+            let sequence = parse(binding_src, tokens).context("parsing keybinding")?;
+            let mut atoms = vec![];
+            for (offset, chord) in sequence.0.iter() {
+                // resolving the key codes will also check the validity
+                let codes = chord.key_codes().map_err(|e| spanned_err(binding_src, *offset, e))?;
+
+                // The chords trie hands back the first chord it finds, so
+                // one chord sending the start of what another sends would
+                // keep the longer one from ever matching.
+                let clash = chord_atom_tab.iter().find(|(other, _)| {
+                    **other != codes && (other.starts_with(&codes) || codes.starts_with(other))
                 });
+                if let Some((_, (_, other))) = clash {
+                    return Err(spanned_err(
+                        binding_src,
+                        *offset,
+                        format!(
+                            "chord {} clashes with {}, which starts the same way",
+                            chord, other
+                        ),
+                    ));
+                }
+
+                // Chords that send the same codes, like Ctrl-a and Ctrl-A,
+                // share an atom since the engine can't tell them apart.
+                let chord_atom = chord_atom_tab
+                    .entry(codes.clone())
+                    .or_insert_with(|| {
+                        let atom = ChordAtom(chord_atom_counter as u8);
+                        chord_atom_counter += 1;
+                        (atom, chord.clone())
+                    })
+                    .0;
                 if chord_atom_counter >= u8::MAX as usize {
                     return Err(anyhow!(
                         "shpool only supports up to {} unique chords at a time",
@@ -144,10 +246,18 @@ impl Bindings {
                     ));
                 }
 
-                chords.insert(vec![code].into_iter(), *chord_atom);
+                chords.insert(codes.iter().copied(), chord_atom);
+                if atoms.is_empty() && !first_keys.contains(&codes[0]) {
+                    first_keys.push(codes[0]);
+                }
+                atoms.push(chord_atom);
             }
-            sequences
-                .insert(sequence.0.iter().map(|chord| *chord_atom_tab.get(chord).unwrap()), action);
+            sequences.insert(atoms.into_iter(), action);
+        }
+
+        // ESC starts both CSI u sequences and paste markers
+        if !first_keys.contains(&ESC) {
+            first_keys.push(ESC);
         }
 
         Ok(Bindings {
@@ -155,13 +265,102 @@ impl Bindings {
             chords_cursor: TrieCursor::Start,
             sequences,
             sequences_cursor: TrieCursor::Start,
+            csi_u: None,
+            needles: Needles::new(&first_keys),
+            paste: PasteTracker::default(),
         })
     }
 
+    /// Turn CSI u decoding on or off, see the module docs.
+    pub fn with_csi_u(mut self, enabled: bool) -> Self {
+        self.csi_u = if enabled { Some(CsiUDecoder::default()) } else { None };
+        self
+    }
+
+    /// Give up on any escape sequence that is currently being decoded,
+    /// resetting the engine. Returns true if there was one. Terminals write
+    /// each escape sequence out in one go, so a sequence that is still
+    /// incomplete at the end of an input chunk was really just the user
+    /// hitting the escape key.
+    pub fn abandon_escape(&mut self) -> bool {
+        match self.csi_u.as_mut() {
+            Some(csi_u) if csi_u.in_progress() => {
+                *csi_u = CsiUDecoder::default();
+                self.chords_cursor = TrieCursor::Start;
+                self.sequences_cursor = TrieCursor::Start;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the number of leading bytes of buf that transition would
+    /// do nothing with but return NoMatch, so that callers can skip over
+    /// the bulk of big chunks of input (like a paste) rather than feeding
+    /// them to the engine byte by byte.
+    pub fn skippable(&self, buf: &[u8]) -> usize {
+        if self.paste.matched > 0 {
+            return 0;
+        }
+        if self.paste.in_paste {
+            // only the end marker matters in a paste
+            return memchr::memchr(ESC, buf).unwrap_or(buf.len());
+        }
+        let idle = matches!(self.chords_cursor, TrieCursor::Start)
+            && matches!(self.sequences_cursor, TrieCursor::Start)
+            && !self.csi_u.as_ref().map(|d| d.in_progress()).unwrap_or(false);
+        if !idle {
+            return 0;
+        }
+        self.needles.find(buf).unwrap_or(buf.len())
+    }
+
     /// transition takes the next byte in an input stream and mutates the
     /// bindings engine while possibly emitting an action that the caller
     /// should perform in response to a keybinding that has just been completed.
     pub fn transition(&mut self, byte: u8) -> BindingResult {
+        match self.paste.advance(byte) {
+            PasteStep::Outside => {}
+            PasteStep::Started => {
+                // the marker bytes that were held as a partial match
+                // get released to the shell
+                if let Some(csi_u) = self.csi_u.as_mut() {
+                    *csi_u = CsiUDecoder::default();
+                }
+                self.chords_cursor = TrieCursor::Start;
+                self.sequences_cursor = TrieCursor::Start;
+                return BindingResult::NoMatch;
+            }
+            PasteStep::Inside => return BindingResult::NoMatch,
+        }
+
+        match self.csi_u.as_mut().map(|d| d.advance(byte)) {
+            None | Some(CsiUStep::Pass) => self.advance_chords(byte),
+            Some(CsiUStep::Pending) => BindingResult::Partial,
+            Some(CsiUStep::Key(codes)) | Some(CsiUStep::Foreign(codes)) => {
+                // The codes all come from a single key press, so once
+                // they stop matching, or complete a match, the rest of
+                // them can't start anything new.
+                let mut res = BindingResult::NoMatch;
+                for code in codes {
+                    res = self.advance_chords(code);
+                    if res != BindingResult::Partial {
+                        break;
+                    }
+                }
+                res
+            }
+            Some(CsiUStep::Invalid) => {
+                self.sequences_cursor = TrieCursor::Start;
+                self.chords_cursor = TrieCursor::Start;
+                BindingResult::NoMatch
+            }
+        }
+    }
+
+    /// Feed a single byte of plain input to the chords trie, and any
+    /// chord it completes to the sequences trie.
+    fn advance_chords(&mut self, byte: u8) -> BindingResult {
         self.chords_cursor = self.chords.advance(self.chords_cursor, byte);
         if let Some(chord_atom) = self.chords.get(self.chords_cursor) {
             self.chords_cursor = TrieCursor::Start;
@@ -173,7 +372,7 @@ impl Bindings {
                     let cursor = self.sequences_cursor;
                     self.sequences_cursor = TrieCursor::Start;
                     if let Some(action) = self.sequences.get(cursor) {
-                        BindingResult::Match(*action)
+                        BindingResult::Match(action.clone())
                     } else {
                         BindingResult::NoMatch
                     }
@@ -197,22 +396,327 @@ impl Bindings {
     }
 }
 
-#[derive(Eq, PartialEq, Debug, Deserialize, Copy, Clone)]
-#[serde(rename_all = "lowercase")]
+//
+// Bracketed paste
+//
+
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+#[derive(Default)]
+struct PasteTracker {
+    in_paste: bool,
+    /// How much of the next marker we have seen.
+    matched: usize,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum PasteStep {
+    /// The byte is ordinary input.
+    Outside,
+    /// The byte finished off a paste start marker.
+    Started,
+    /// The byte is part of a paste, including its end marker.
+    Inside,
+}
+
+impl PasteTracker {
+    fn advance(&mut self, byte: u8) -> PasteStep {
+        let was_in_paste = self.in_paste;
+        let marker = if self.in_paste { PASTE_END } else { PASTE_START };
+        if byte == marker[self.matched] {
+            self.matched += 1;
+        } else {
+            self.matched = if byte == marker[0] { 1 } else { 0 };
+        }
+        if self.matched == marker.len() {
+            self.matched = 0;
+            self.in_paste = !self.in_paste;
+        }
+
+        match (was_in_paste, self.in_paste) {
+            (false, true) => PasteStep::Started,
+            (false, false) => PasteStep::Outside,
+            (true, _) => PasteStep::Inside,
+        }
+    }
+}
+
+//
+// CSI u decoding
+//
+
+/// The longest CSI u sequence we are willing to buffer up.
+const MAX_CSI_U_LEN: usize = 32;
+
+const ESC: u8 = 0x1b;
+
+// modifier bits, offset by one on the wire
+const MOD_SHIFT: u32 = 0b1;
+const MOD_ALT: u32 = 0b10;
+const MOD_CTRL: u32 = 0b100;
+const MOD_LOCKS: u32 = 0b1100_0000; // caps lock and num lock
+
+#[derive(Default)]
+struct CsiUDecoder {
+    /// The bytes of the sequence seen so far, including the leading ESC.
+    buf: Vec<u8>,
+}
+
+enum CsiUStep {
+    /// The byte is not part of an escape sequence.
+    Pass,
+    /// The byte is part of a sequence that is not finished yet.
+    Pending,
+    /// A complete sequence for a key press, translated to its legacy codes.
+    Key(Vec<u8>),
+    /// The bytes of an escape sequence which is not CSI u, like the
+    /// ones for function keys, which might still make up a chord.
+    Foreign(Vec<u8>),
+    /// A CSI u sequence that does not map to a chord, like a key release.
+    Invalid,
+}
+
+impl CsiUDecoder {
+    fn in_progress(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    fn advance(&mut self, byte: u8) -> CsiUStep {
+        if self.buf.is_empty() {
+            if byte == ESC {
+                self.buf.push(byte);
+                return CsiUStep::Pending;
+            }
+            return CsiUStep::Pass;
+        }
+
+        self.buf.push(byte);
+        if self.buf.len() == 2 {
+            return if byte == b'[' { CsiUStep::Pending } else { self.foreign() };
+        }
+        if byte.is_ascii_digit() || byte == b';' || byte == b':' {
+            if self.buf.len() >= MAX_CSI_U_LEN {
+                return self.foreign();
+            }
+            return CsiUStep::Pending;
+        }
+        if byte != b'u' {
+            return self.foreign();
+        }
+
+        let step = match Self::legacy_codes(&self.buf[2..self.buf.len() - 1]) {
+            Some(codes) => CsiUStep::Key(codes),
+            None => CsiUStep::Invalid,
+        };
+        self.reset(step)
+    }
+
+    fn reset(&mut self, step: CsiUStep) -> CsiUStep {
+        self.buf.clear();
+        step
+    }
+
+    /// Hand back the bytes of a sequence that turned out not to be CSI u.
+    fn foreign(&mut self) -> CsiUStep {
+        CsiUStep::Foreign(std::mem::take(&mut self.buf))
+    }
+
+    /// Translate the parameters of a `CSI key:shifted:base ; mods:event u`
+    /// sequence into the bytes the same chord would produce without the
+    /// kitty keyboard protocol.
+    fn legacy_codes(params: &[u8]) -> Option<Vec<u8>> {
+        let params = std::str::from_utf8(params).ok()?;
+        let mut fields = params.split(';');
+
+        let mut key_codes = fields.next()?.split(':');
+        let key = key_codes.next()?;
+        let shifted = key_codes.next();
+        let key = match key_codes.next() {
+            Some(base) if !base.is_empty() => base,
+            _ => key,
+        };
+        let key: u32 = key.parse().ok()?;
+
+        let (mods, event) = match fields.next() {
+            Some(f) => {
+                let mut parts = f.split(':');
+                let mods = match parts.next() {
+                    Some("") | None => 1,
+                    Some(m) => m.parse::<u32>().ok()?,
+                };
+                let event = match parts.next() {
+                    Some(e) => e.parse::<u32>().ok()?,
+                    None => 1,
+                };
+                (mods, event)
+            }
+            None => (1, 1),
+        };
+        // only presses count, not repeats or releases
+        if event != 1 {
+            return None;
+        }
+
+        let key = char::from_u32(key).filter(|c| c.is_ascii_graphic() || *c == ' ')?;
+        let mods = mods.checked_sub(1)? & !MOD_LOCKS;
+        let code = match mods & !MOD_ALT {
+            0 => key as u8,
+            // stick with the base layout for letters, but other keys
+            // only have a shifted form in the layout in use
+            MOD_SHIFT if key.is_ascii_lowercase() => key.to_ascii_uppercase() as u8,
+            MOD_SHIFT => {
+                let shifted: u32 = shifted.filter(|s| !s.is_empty())?.parse().ok()?;
+                char::from_u32(shifted).filter(|c| c.is_ascii_graphic())? as u8
+            }
+            MOD_CTRL => {
+                let chord = if key == ' ' {
+                    String::from("Ctrl-Space")
+                } else {
+                    format!("Ctrl-{}", key.to_ascii_lowercase())
+                };
+                CONTROL_CODES.iter().find(|(c, _)| *c == chord).map(|(_, code)| *code)?
+            }
+            _ => return None,
+        };
+        // Alt gets sent as an ESC in front of the key
+        Some(if mods & MOD_ALT != 0 { vec![ESC, code] } else { vec![code] })
+    }
+}
+
+/// Compile a keybinding from arbitrary input and then feed the same
+/// bytes to the engine, for the fuzz target in libshpool/fuzz. Neither
+/// should ever panic.
+#[cfg(feature = "fuzz")]
+pub fn fuzz(data: &[u8]) {
+    let src = String::from_utf8_lossy(data);
+    for csi_u in [false, true] {
+        let mut bindings = match Bindings::new([(&*src, Action::NoOp)]) {
+            Ok(b) => b.with_csi_u(csi_u),
+            Err(_) => return,
+        };
+        let mut i = 0;
+        while i < data.len() {
+            i += bindings.skippable(&data[i..]);
+            if i < data.len() {
+                bindings.transition(data[i]);
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Compare two keybinding strings, ignoring differences in whitespace.
+pub fn same_binding(a: &str, b: &str) -> bool {
+    a.split_whitespace().eq(b.split_whitespace())
+}
+
+#[derive(Eq, PartialEq, Debug, Deserialize, Clone)]
+#[serde(try_from = "String")]
 pub enum Action {
     /// detaches the current shpool session
     Detach,
+    /// kills the current shpool session
+    Kill,
+    /// puts the terminal state of the current session back to defaults,
+    /// for when something like cat'ing a binary garbled it
+    Reset,
+    /// freezes the output of the current session and lets you page
+    /// through its scrollback and copy bits of it
+    CopyMode,
+    /// throws away any output still queued up for the client and
+    /// redraws the screen, for when a flood of output has the client
+    /// lagging far behind the shell
+    FlushOutput,
+    /// wipes the scrollback and screen the daemon keeps for the current
+    /// session, so that output like an accidentally cat'ed secret does
+    /// not get replayed on the next attach
+    ClearScrollback,
+    /// shows or hides the status line along the bottom of the terminal
+    ToggleStatusLine,
+    /// suspends the attached client, handing the terminal back to the
+    /// shell it was started from, like Ctrl-z would without raw mode
+    Suspend,
+    /// turns the client's local line editing at the shell prompt on or
+    /// off, see the local_edit config option
+    ToggleLocalEdit,
+    /// switches the terminal over to the next job of the current
+    /// session, wrapping back around to the session's own shell
+    NextJob,
+    /// locks the session, so that it takes a passphrase to get back to
+    /// it, both from this terminal and on any later attach
+    Lock,
     /// does nothing, useful for testing the keybinding engine and not much else
     NoOp,
+    /// runs an action registered by a binary that embeds the daemon,
+    /// written as "custom:<name>"
+    Custom(String),
+}
+
+impl std::str::FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "detach" => Ok(Action::Detach),
+            "kill" => Ok(Action::Kill),
+            "reset" => Ok(Action::Reset),
+            "copy-mode" => Ok(Action::CopyMode),
+            "flush-output" => Ok(Action::FlushOutput),
+            "clear-scrollback" => Ok(Action::ClearScrollback),
+            "toggle-status-line" => Ok(Action::ToggleStatusLine),
+            "suspend" => Ok(Action::Suspend),
+            "toggle-local-edit" => Ok(Action::ToggleLocalEdit),
+            "next-job" => Ok(Action::NextJob),
+            "lock" => Ok(Action::Lock),
+            "noop" => Ok(Action::NoOp),
+            _ => match s.strip_prefix("custom:") {
+                Some(name) if !name.is_empty() => Ok(Action::Custom(String::from(name))),
+                _ => Err(anyhow!(
+                    "unknown action '{}' (expected detach, kill, reset, copy-mode, flush-output, clear-scrollback, toggle-status-line, suspend, toggle-local-edit, next-job, lock, noop or custom:<name>)",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for Action {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Detach => write!(f, "detach"),
+            Action::Kill => write!(f, "kill"),
+            Action::Reset => write!(f, "reset"),
+            Action::CopyMode => write!(f, "copy-mode"),
+            Action::FlushOutput => write!(f, "flush-output"),
+            Action::ClearScrollback => write!(f, "clear-scrollback"),
+            Action::ToggleStatusLine => write!(f, "toggle-status-line"),
+            Action::Suspend => write!(f, "suspend"),
+            Action::ToggleLocalEdit => write!(f, "toggle-local-edit"),
+            Action::NextJob => write!(f, "next-job"),
+            Action::Lock => write!(f, "lock"),
+            Action::NoOp => write!(f, "noop"),
+            Action::Custom(name) => write!(f, "custom:{}", name),
+        }
+    }
 }
 
 //
 // Parser
 //
 
-/// A list of chords that need to be pressed one after another
+/// A list of chords that need to be pressed one after another, along
+/// with the byte offset of each one in the source of the binding.
 #[derive(Eq, PartialEq, Debug)]
-pub struct Sequence(Vec<Chord>);
+pub struct Sequence(Vec<(usize, Chord)>);
 
 /// a list of keys that need to be held down all together
 #[derive(Eq, PartialEq, Debug, Hash, Clone)]
@@ -223,7 +727,9 @@ impl Chord {
     ///
     /// Valid forms are:
     ///   sym
-    ///   Ctrl-sym
+    ///   mod-sym
+    ///   mod-mod-sym
+    /// where the mods are Ctrl and Alt, each at most once.
     fn check_valid(&self) -> anyhow::Result<()> {
         for key in self.0.iter() {
             if !Self::is_key(key) {
@@ -231,61 +737,77 @@ impl Chord {
             }
         }
 
-        if self.0.len() == 1 {
-            if Self::is_ctrl(&self.0[0]) {
-                return Err(anyhow!("invalid chord: {}: Ctrl is not a cord", self));
+        let (key, mods) = match self.0.split_last() {
+            Some(split) => split,
+            None => return Err(anyhow!("invalid chord: empty chord")),
+        };
+        for (i, m) in mods.iter().enumerate() {
+            if !Self::is_mod(m) {
+                return Err(anyhow!(
+                    "invalid chord: {}: Ctrl and Alt are the only supported mod keys",
+                    self
+                ));
             }
-        } else if self.0.len() == 2 {
-            if !Self::is_ctrl(&self.0[0]) {
-                return Err(anyhow!("invalid chord: {}: Ctrl is the only supported mod key", self));
+            if mods[..i].contains(m) {
+                return Err(anyhow!("invalid chord: {}: {} cannot be repeated", self, m));
             }
-            if Self::is_ctrl(&self.0[1]) {
-                return Err(anyhow!("invalid chord: {}: Ctrl cannot be repeated", self));
+        }
+        if Self::is_mod(key) {
+            if mods.contains(key) {
+                return Err(anyhow!("invalid chord: {}: {} cannot be repeated", self, key));
             }
-        } else {
-            return Err(anyhow!("invalid chord: {}", self));
+            return Err(anyhow!("invalid chord: {}: {} is not a cord", self, key));
         }
         Ok(())
     }
 
-    /// key_code returns the byte that this chord generates when pressed.
-    ///
-    /// Eventually, we might want to extend this to support chords that
-    /// generate multiple codes, but for now we only support single-code
-    /// chords.
-    fn key_code(&self) -> anyhow::Result<u8> {
+    /// key_codes returns the bytes that this chord generates when pressed.
+    /// Most chords send a single byte, but Alt sends an ESC in front of
+    /// the key and function keys send whole escape sequences.
+    fn key_codes(&self) -> anyhow::Result<Vec<u8>> {
         self.check_valid()?;
 
-        if self.0.len() == 1 && Self::is_sym(&self.0[0]) {
-            if self.0[0] == "Space" {
-                return Ok(b' ');
-            }
-            let c = self.0[0].chars().next().unwrap();
-            return Ok(c as u32 as u8);
+        let (key, mods) = self.0.split_last().unwrap();
+        let ctrl = mods.iter().any(|m| m == "Ctrl");
+        let alt = mods.iter().any(|m| m == "Alt");
+
+        if let Some((_, number, final_byte)) = FUNCTION_KEYS.iter().find(|(k, ..)| k == key) {
+            return Ok(function_key_codes(*number, *final_byte, ctrl, alt));
         }
 
-        if self.0.len() == 2 {
-            let ctrl_chord = format!("{}", self);
-            for (chord, code) in CONTROL_CODES.iter() {
-                if ctrl_chord == *chord {
-                    return Ok(*code);
-                }
+        let code = if ctrl {
+            // the terminal can't tell Ctrl-A from Ctrl-a
+            let ctrl_chord = if key.len() == 1 {
+                format!("Ctrl-{}", key.to_ascii_lowercase())
+            } else {
+                format!("Ctrl-{}", key)
+            };
+            match CONTROL_CODES.iter().find(|(chord, _)| ctrl_chord == *chord) {
+                Some((_, code)) => *code,
+                None => return Err(anyhow!(
+                    "invalid chord: {}: terminals send no control code for it, Ctrl only works with Space, letters, digits, function keys and @[\\]^_?",
+                    self
+                )),
             }
-        }
+        } else if key == "Space" {
+            b' '
+        } else {
+            key.chars().next().unwrap() as u32 as u8
+        };
 
-        Err(anyhow!("unknown key code for chord: {}", self))
+        Ok(if alt { vec![ESC, code] } else { vec![code] })
     }
 
     fn is_key(key: &str) -> bool {
-        Self::is_ctrl(key) || Self::is_sym(key)
+        Self::is_mod(key) || Self::is_sym(key)
     }
 
-    fn is_ctrl(key: &str) -> bool {
-        key == "Ctrl"
+    fn is_mod(key: &str) -> bool {
+        key == "Ctrl" || key == "Alt"
     }
 
     fn is_sym(key: &str) -> bool {
-        if key == "Space" {
+        if key == "Space" || FUNCTION_KEYS.iter().any(|(k, ..)| *k == key) {
             return true;
         }
 
@@ -295,9 +817,25 @@ impl Chord {
 
         let c = key.chars().next().unwrap();
 
-        // If we expanded our alphabet size a bit, we can include the
-        // uppercase letters using this method if we wanted to.
-        c.is_digit(10 + 26)
+        // '-' is what glues chords together, so it can't be a key
+        c.is_ascii_graphic() && c != '-'
+    }
+}
+
+/// The bytes xterm sends for a function key, which only use the
+/// modifier parameter when there are modifiers.
+fn function_key_codes(number: u8, final_byte: u8, ctrl: bool, alt: bool) -> Vec<u8> {
+    let mut mods = 0;
+    if alt {
+        mods |= MOD_ALT;
+    }
+    if ctrl {
+        mods |= MOD_CTRL;
+    }
+    match (mods, final_byte) {
+        (0, b'~') => format!("\x1b[{}~", number).into_bytes(),
+        (0, _) => vec![ESC, b'O', final_byte],
+        _ => format!("\x1b[{};{}{}", number, mods + 1, final_byte as char).into_bytes(),
     }
 }
 
@@ -308,40 +846,50 @@ impl fmt::Display for Chord {
     }
 }
 
-fn parse<T: IntoIterator<Item = Token>>(tokens: T) -> anyhow::Result<Sequence> {
+fn parse(src: &str, tokens: Vec<(usize, Token)>) -> anyhow::Result<Sequence> {
     let mut chords = vec![];
     let mut keys = vec![];
-    let mut saw_dash = true;
-    for token in tokens.into_iter() {
+    let mut chord_start = 0;
+    // the dash we still need to see a key after, if any
+    let mut dangling_dash = None;
+    for (offset, token) in tokens.into_iter() {
         match token {
             Token::Key(key) => {
-                if saw_dash {
-                    keys.push(key);
-                    saw_dash = false;
-                } else {
-                    chords.push(Chord(keys.clone()));
-
-                    keys.clear();
-                    keys.push(key);
+                if dangling_dash.take().is_none() && !keys.is_empty() {
+                    chords.push((chord_start, Chord(std::mem::take(&mut keys))));
+                }
+                if keys.is_empty() {
+                    chord_start = offset;
                 }
+                keys.push(key);
             }
             Token::Dash => {
-                if saw_dash {
-                    return Err(anyhow!("unexpected DASH token"));
-                } else {
-                    saw_dash = true;
+                if keys.is_empty() || dangling_dash.is_some() {
+                    return Err(spanned_err(src, offset, "unexpected '-'"));
                 }
+                dangling_dash = Some(offset);
             }
         }
     }
 
+    if let Some(offset) = dangling_dash {
+        return Err(spanned_err(src, offset, "expected a key after '-'"));
+    }
     if !keys.is_empty() {
-        chords.push(Chord(keys));
+        chords.push((chord_start, Chord(keys)));
+    }
+    if chords.is_empty() {
+        return Err(anyhow!("empty keybinding"));
     }
 
     Ok(Sequence(chords))
 }
 
+/// An error about the part of a keybinding at the given byte offset.
+fn spanned_err<M: fmt::Display>(src: &str, offset: usize, msg: M) -> anyhow::Error {
+    anyhow!("{} at position {} in '{}'", msg, offset, src)
+}
+
 //
 // Lexer
 //
@@ -358,7 +906,8 @@ enum Token {
 
 impl Lexer {
     fn new() -> Self {
-        let words = vec!["Ctrl", "Space"];
+        let mut words = vec!["Ctrl", "Alt", "Space"];
+        words.extend(FUNCTION_KEYS.iter().map(|(k, ..)| *k));
         let mut words_trie = Trie::new();
         for word in words {
             words_trie.insert(word.chars(), ());
@@ -366,56 +915,107 @@ impl Lexer {
         Lexer { words_trie }
     }
 
-    fn tokenize<S: Iterator<Item = char>>(&self, src: S) -> anyhow::Result<Vec<Token>> {
+    /// Split a keybinding up into tokens, each tagged with its byte
+    /// offset in src.
+    fn tokenize(&self, src: &str) -> anyhow::Result<Vec<(usize, Token)>> {
         let mut tokens = vec![];
-        let mut word_chars = vec![];
+        // the part of a multi-char key like Ctrl we have seen so far
+        let mut word = String::new();
+        let mut word_start = 0;
         let mut cursor = TrieCursor::Start;
-        for c in src {
+        for (offset, c) in src.char_indices() {
             if c.is_whitespace() {
+                self.end_word(src, &mut word, word_start, cursor, &mut tokens)?;
+                cursor = TrieCursor::Start;
                 continue;
             }
 
             let new_cursor = self.words_trie.advance(cursor, c);
             match new_cursor {
                 TrieCursor::Start => return Err(anyhow!("internal error: trie bug")),
-                TrieCursor::NoMatch => {
+                // Words can't be told apart from their prefixes, like F1
+                // and F10, until something else comes along, and a letter
+                // or digit right after a word means a misspelled one.
+                TrieCursor::NoMatch if !word.is_empty() && !c.is_ascii_alphanumeric() => {
+                    self.end_word(src, &mut word, word_start, cursor, &mut tokens)?;
                     cursor = TrieCursor::Start;
-
-                    word_chars.push(c);
-                    for c in word_chars.iter() {
-                        match *c {
-                            '-' => tokens.push(Token::Dash),
-                            'a'..='z' => tokens.push(Token::Key(String::from(*c))),
-                            _ => return Err(anyhow!("unexpected char: '{}'", *c)),
-                        }
-                    }
-                    word_chars.clear();
-                    continue;
+                    tokens.push((offset, lone_token(src, offset, c)?));
                 }
-                TrieCursor::Match { is_partial, .. } => {
-                    word_chars.push(c);
-                    if is_partial {
-                        cursor = new_cursor;
-                    } else {
-                        tokens.push(Token::Key(word_chars.iter().collect()));
-
-                        // reset match state
-                        cursor = TrieCursor::Start;
-                        word_chars.clear();
-                        continue;
+                TrieCursor::NoMatch if !word.is_empty() => {
+                    word.push(c);
+                    return Err(spanned_err(src, word_start, format!("unknown key '{}'", word)));
+                }
+                TrieCursor::NoMatch => {
+                    tokens.push((offset, lone_token(src, offset, c)?));
+                }
+                TrieCursor::Match { .. } => {
+                    if word.is_empty() {
+                        word_start = offset;
                     }
+                    word.push(c);
+                    cursor = new_cursor;
                 }
             }
         }
+        self.end_word(src, &mut word, word_start, cursor, &mut tokens)?;
 
         Ok(tokens)
     }
+
+    /// Turn the word we have seen so far into a key, if there is one. A
+    /// single char is just an uppercase letter that happens to start a
+    /// word, like the C in Ctrl-C.
+    fn end_word(
+        &self,
+        src: &str,
+        word: &mut String,
+        word_start: usize,
+        cursor: TrieCursor,
+        tokens: &mut Vec<(usize, Token)>,
+    ) -> anyhow::Result<()> {
+        if word.is_empty() {
+            return Ok(());
+        }
+        if word.len() > 1 && self.words_trie.get(cursor).is_none() {
+            return Err(spanned_err(src, word_start, format!("unknown key '{}'", word)));
+        }
+        tokens.push((word_start, Token::Key(std::mem::take(word))));
+        Ok(())
+    }
+}
+
+/// The token for a char that is not part of a word like Ctrl.
+fn lone_token(src: &str, offset: usize, c: char) -> anyhow::Result<Token> {
+    match c {
+        '-' => Ok(Token::Dash),
+        _ if c.is_ascii_graphic() => Ok(Token::Key(String::from(c))),
+        _ => Err(spanned_err(src, offset, format!("unexpected '{}'", c))),
+    }
 }
 
 //
 // Data Tables
 //
 
+// The function keys, along with the number and final byte of the
+// escape sequences xterm sends for them. F1-F4 come as `ESC O <final>`
+// on their own, and everything else as `CSI <number> ; <mods> <final>`
+// with the modifier parameter left off when there are no modifiers.
+const FUNCTION_KEYS: [(&str, u8, u8); 12] = [
+    ("F1", 1, b'P'),
+    ("F2", 1, b'Q'),
+    ("F3", 1, b'R'),
+    ("F4", 1, b'S'),
+    ("F5", 15, b'~'),
+    ("F6", 17, b'~'),
+    ("F7", 18, b'~'),
+    ("F8", 19, b'~'),
+    ("F9", 20, b'~'),
+    ("F10", 21, b'~'),
+    ("F11", 23, b'~'),
+    ("F12", 24, b'~'),
+];
+
 // This table was generated experimentally by logging the key
 // codes the shpool daemon receives and pressing the Ctrl-<key>
 // combo for all the lower-case letters, numbers, some symbols,
@@ -445,8 +1045,8 @@ const CONTROL_CODES: [(&str, u8); 42] = [
     ("Ctrl-u", 21),
     ("Ctrl-v", 22),
     ("Ctrl-w", 23),
-    ("Ctrl-y", 24),
-    ("Ctrl-x", 25),
+    ("Ctrl-x", 24),
+    ("Ctrl-y", 25),
     ("Ctrl-z", 26),
     ("Ctrl-@", 0),
     ("Ctrl-2", 0),
@@ -514,6 +1114,77 @@ mod test {
                 ['a', 'b'].iter().map(|c| *c as u32 as u8).collect::<Vec<_>>(),
                 BindingResult::Partial,
             ),
+            (vec![("A", Action::Detach)], vec![b'A'], BindingResult::Match(Action::Detach)),
+            (vec![("A", Action::Detach)], vec![b'a'], BindingResult::NoMatch),
+            (vec![("Ctrl-A", Action::Detach)], vec![1], BindingResult::Match(Action::Detach)),
+            (
+                vec![("Ctrl-a ;", Action::Detach)],
+                vec![1, b';'],
+                BindingResult::Match(Action::Detach),
+            ),
+            (
+                vec![("Ctrl-a [", Action::Detach)],
+                vec![1, b'['],
+                BindingResult::Match(Action::Detach),
+            ),
+            (vec![("Ctrl-[", Action::Detach)], vec![27], BindingResult::Match(Action::Detach)),
+            (vec![("Ctrl-\\", Action::Detach)], vec![28], BindingResult::Match(Action::Detach)),
+            (vec![("Ctrl-]", Action::Detach)], vec![29], BindingResult::Match(Action::Detach)),
+            (
+                vec![("Alt-x", Action::Detach)],
+                b"\x1bx".to_vec(),
+                BindingResult::Match(Action::Detach),
+            ),
+            (vec![("Alt-x", Action::Detach)], b"\x1b".to_vec(), BindingResult::Partial),
+            (vec![("Alt-x", Action::Detach)], b"x".to_vec(), BindingResult::NoMatch),
+            (
+                vec![("Ctrl-Alt-x", Action::Detach)],
+                vec![ESC, 24],
+                BindingResult::Match(Action::Detach),
+            ),
+            (
+                vec![("F1", Action::Detach)],
+                b"\x1bOP".to_vec(),
+                BindingResult::Match(Action::Detach),
+            ),
+            (
+                vec![("F10", Action::Detach)],
+                b"\x1b[21~".to_vec(),
+                BindingResult::Match(Action::Detach),
+            ),
+            (
+                vec![("Ctrl-F1", Action::Detach)],
+                b"\x1b[1;5P".to_vec(),
+                BindingResult::Match(Action::Detach),
+            ),
+            (
+                vec![("Ctrl-Alt-F12", Action::Detach)],
+                b"\x1b[24;7~".to_vec(),
+                BindingResult::Match(Action::Detach),
+            ),
+            (vec![("F5", Action::Detach)], b"\x1b[15;5~".to_vec(), BindingResult::NoMatch),
+            // multi-byte chords can be mixed into sequences
+            (
+                vec![("Ctrl-a F2 Alt-q", Action::Detach)],
+                b"\x01\x1bOQ\x1bq".to_vec(),
+                BindingResult::Match(Action::Detach),
+            ),
+            (
+                vec![("Ctrl-a F2 Alt-q", Action::Detach)],
+                b"\x01\x1bOQ\x1b".to_vec(),
+                BindingResult::Partial,
+            ),
+            // chords that send the same bytes are the same chord
+            (
+                vec![("Ctrl-a x", Action::Detach), ("Ctrl-A y", Action::NoOp)],
+                vec![1, b'x'],
+                BindingResult::Match(Action::Detach),
+            ),
+            (
+                vec![("Ctrl-a x", Action::Detach), ("Ctrl-A y", Action::NoOp)],
+                vec![1, b'y'],
+                BindingResult::Match(Action::NoOp),
+            ),
         ];
 
         for (bindings_mapping, keypresses, final_output) in cases.into_iter() {
@@ -529,25 +1200,193 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_csi_u_bindings() -> anyhow::Result<()> {
+        let cases: Vec<(&str, &[u8], BindingResult)> = vec![
+            ("Ctrl-a", b"\x1b[97;5u", BindingResult::Match(Action::Detach)),
+            // caps lock doesn't get in the way
+            ("Ctrl-a", b"\x1b[97;69u", BindingResult::Match(Action::Detach)),
+            // the base layout key wins, so a russian layout still works
+            ("Ctrl-a", b"\x1b[1092::97;5u", BindingResult::Match(Action::Detach)),
+            ("Ctrl-a", b"\x1b[97;5:1u", BindingResult::Match(Action::Detach)),
+            // key releases don't count
+            ("Ctrl-a", b"\x1b[97;5:3u", BindingResult::NoMatch),
+            // alt is not ctrl
+            ("Ctrl-a", b"\x1b[97;3u", BindingResult::NoMatch),
+            ("Ctrl-Space Ctrl-q", b"\x1b[32;5u\x1b[113;5u", BindingResult::Match(Action::Detach)),
+            // legacy and CSI u chords can be mixed
+            ("Ctrl-Space Ctrl-q", b"\x00\x1b[113;5u", BindingResult::Match(Action::Detach)),
+            ("Ctrl-Space Ctrl-q", b"\x1b[32;5u\x11", BindingResult::Match(Action::Detach)),
+            ("Ctrl-Space Ctrl-q", b"\x1b[32;5u", BindingResult::Partial),
+            ("Ctrl-Space Ctrl-q", b"\x1b[32;5", BindingResult::Partial),
+            // other escape sequences, like arrow keys, are left alone
+            ("Ctrl-Space Ctrl-q", b"\x1b[A", BindingResult::NoMatch),
+            ("Ctrl-Space Ctrl-q", b"\x1bx", BindingResult::NoMatch),
+            ("q", b"\x1b[113u", BindingResult::Match(Action::Detach)),
+            // shifted keys
+            ("Q", b"\x1b[113;2u", BindingResult::Match(Action::Detach)),
+            ("Q", b"\x1b[1081::113;2u", BindingResult::Match(Action::Detach)),
+            ("!", b"\x1b[49:33;2u", BindingResult::Match(Action::Detach)),
+            ("!", b"\x1b[49;2u", BindingResult::NoMatch),
+            ("Q", b"\x1b[113u", BindingResult::NoMatch),
+            ("Ctrl-]", b"\x1b[93;5u", BindingResult::Match(Action::Detach)),
+            // alt
+            ("Alt-x", b"\x1b[120;3u", BindingResult::Match(Action::Detach)),
+            ("Ctrl-Alt-x", b"\x1b[120;7u", BindingResult::Match(Action::Detach)),
+            ("Alt-x", b"\x1b[120;5u", BindingResult::NoMatch),
+            // sequences that are not CSI u still make up chords
+            ("Alt-x", b"\x1bx", BindingResult::Match(Action::Detach)),
+            ("F1", b"\x1bOP", BindingResult::Match(Action::Detach)),
+            ("F5", b"\x1b[15~", BindingResult::Match(Action::Detach)),
+            ("Ctrl-F1", b"\x1b[1;5P", BindingResult::Match(Action::Detach)),
+            ("F5", b"\x1b[17~", BindingResult::NoMatch),
+            // the tail of an escape sequence doesn't fire a binding
+            ("A", b"\x1b[A", BindingResult::NoMatch),
+        ];
+
+        for (binding, keypresses, final_output) in cases.into_iter() {
+            let mut bindings = Bindings::new(vec![(binding, Action::Detach)])?.with_csi_u(true);
+
+            let mut actual_final_output = BindingResult::NoMatch;
+            for byte in keypresses.iter() {
+                actual_final_output = bindings.transition(*byte);
+            }
+            assert_eq!(actual_final_output, final_output, "keypresses={:?}", keypresses);
+        }
+
+        // without CSI u decoding, the sequence is just noise
+        let mut bindings = Bindings::new(vec![("Ctrl-a", Action::Detach)])?;
+        let mut actual_final_output = BindingResult::NoMatch;
+        for byte in b"\x1b[97;5u".iter() {
+            actual_final_output = bindings.transition(*byte);
+        }
+        assert_eq!(actual_final_output, BindingResult::NoMatch);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chord_clash() {
+        let err = Bindings::new(vec![("Ctrl-[", Action::Detach), ("Alt-x", Action::Kill)])
+            .err()
+            .expect("clashing chords compiled");
+        assert_eq!(
+            format!("{:#}", err),
+            "chord Alt-x clashes with Ctrl-[, which starts the same way at position 0 in 'Alt-x'"
+        );
+        assert!(Bindings::new(vec![("Alt-x", Action::Detach), ("Alt-y", Action::Kill)]).is_ok());
+    }
+
+    #[test]
+    fn test_abandon_escape() -> anyhow::Result<()> {
+        let mut bindings = Bindings::new(vec![("Ctrl-a", Action::Detach)])?.with_csi_u(true);
+        assert!(!bindings.abandon_escape());
+        assert_eq!(bindings.transition(ESC), BindingResult::Partial);
+        assert!(bindings.abandon_escape());
+        assert_eq!(bindings.transition(1), BindingResult::Match(Action::Detach));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_skippable() -> anyhow::Result<()> {
+        let mut bindings = Bindings::new(vec![("Ctrl-Space Ctrl-q", Action::Detach)])?;
+        assert_eq!(bindings.skippable(b"just typing along"), 17);
+        // escape sequences might be paste markers
+        assert_eq!(bindings.skippable(b"up\x1b[A"), 2);
+        // only the first chord can get things going
+        assert_eq!(bindings.skippable(b"ctrl-q: \x11"), 9);
+        assert_eq!(bindings.skippable(b"ab\x00\x11"), 2);
+        // nothing gets skipped in the middle of a sequence
+        assert_eq!(bindings.transition(0), BindingResult::Partial);
+        assert_eq!(bindings.skippable(b"x"), 0);
+
+        let bindings = Bindings::new(vec![("Ctrl-a", Action::Detach)])?.with_csi_u(true);
+        assert_eq!(bindings.skippable(b"abc"), 3);
+        assert_eq!(bindings.skippable(b"a\x1b[97;5u"), 1);
+
+        let bindings = Bindings::new(vec![
+            ("Ctrl-a", Action::Detach),
+            ("Ctrl-b", Action::Kill),
+            ("Ctrl-c", Action::Reset),
+            ("Ctrl-d", Action::NoOp),
+        ])?;
+        assert_eq!(bindings.skippable(b"xyz\x04"), 3);
+        assert_eq!(bindings.skippable(b""), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bracketed_paste() -> anyhow::Result<()> {
+        for csi_u in [false, true] {
+            let mut bindings =
+                Bindings::new(vec![("Ctrl-Space Ctrl-q", Action::Detach)])?.with_csi_u(csi_u);
+            let mut results = vec![];
+            for byte in b"\x1b[200~a\x00\x11b\x1b[201~\x00\x11".iter() {
+                results.push(bindings.transition(*byte));
+            }
+            // the paste start marker gets released in full
+            assert_eq!(results[5], BindingResult::NoMatch, "csi_u={}", csi_u);
+            // nothing in the paste matches
+            assert!(results[6..16].iter().all(|r| *r == BindingResult::NoMatch), "csi_u={}", csi_u);
+            // but things are back to normal after it
+            assert_eq!(results[16], BindingResult::Partial, "csi_u={}", csi_u);
+            assert_eq!(results[17], BindingResult::Match(Action::Detach), "csi_u={}", csi_u);
+        }
+
+        // the fast path doesn't skip over the markers
+        let mut bindings = Bindings::new(vec![("Ctrl-a", Action::Detach)])?;
+        let input = b"xy\x1b[200~a\x01b\x1b[201~\x01";
+        assert_eq!(bindings.skippable(input), 2);
+        let mut i = 2;
+        let mut results = vec![];
+        while i < input.len() {
+            i += bindings.skippable(&input[i..]);
+            if i == input.len() {
+                break;
+            }
+            results.push((i, bindings.transition(input[i])));
+            i += 1;
+        }
+        assert_eq!(results.last(), Some(&(input.len() - 1, BindingResult::Match(Action::Detach))));
+        assert_eq!(
+            results.iter().filter(|(_, r)| *r == BindingResult::Match(Action::Detach)).count(),
+            1
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_cord_validity() -> anyhow::Result<()> {
         let cases = vec![
             ("Ctrl-x", ""),
-            ("a-a", "Ctrl is the only supported mod key"),
+            ("a-a", "Ctrl and Alt are the only supported mod keys"),
             ("Ctrl-a-x", "invalid chord"),
-            ("a-Ctrl", "Ctrl is the only supported mod key"),
+            ("a-Ctrl", "Ctrl and Alt are the only supported mod keys"),
             ("Ctrl-Ctrl", "Ctrl cannot be repeated"),
+            ("Ctrl-Z", ""),
+            ("Ctrl-\\", ""),
+            ("S-x", "Ctrl and Alt are the only supported mod keys"),
+            ("Alt-x", ""),
+            ("Ctrl-Alt-x", ""),
+            ("Alt-Ctrl-F5", ""),
+            ("Alt-Alt-x", "Alt cannot be repeated"),
+            ("Ctrl-Alt", "Alt is not a cord"),
+            ("Ctrl-;", "terminals send no control code for it"),
         ];
 
         let tokenizer = Lexer::new();
         for (src, errstr) in cases.into_iter() {
-            let tokens = tokenizer.tokenize(src.chars())?;
-            let seq = parse(tokens)?;
-            let chord = seq.0[0].clone();
+            let tokens = tokenizer.tokenize(src)?;
+            let seq = parse(src, tokens)?;
+            let chord = seq.0[0].1.clone();
 
+            // resolving the key code checks the validity too
             if errstr.is_empty() {
-                chord.check_valid()?;
-            } else if let Err(e) = chord.check_valid() {
+                chord.key_codes()?;
+            } else if let Err(e) = chord.key_codes() {
                 let got = format!("{:?}", e);
                 assert!(got.contains(errstr));
             } else {
@@ -564,60 +1403,118 @@ mod test {
             (
                 "Ctrl-x a",
                 Sequence(vec![
-                    Chord(vec![String::from("Ctrl"), String::from("x")]),
-                    Chord(vec![String::from("a")]),
+                    (0, Chord(vec![String::from("Ctrl"), String::from("x")])),
+                    (7, Chord(vec![String::from("a")])),
                 ]),
             ),
             (
                 "Ctrl-x-a",
-                Sequence(vec![Chord(vec![
-                    String::from("Ctrl"),
-                    String::from("x"),
-                    String::from("a"),
-                ])]),
+                Sequence(vec![(
+                    0,
+                    Chord(vec![String::from("Ctrl"), String::from("x"), String::from("a")]),
+                )]),
             ),
             (
                 "Ctrl Ctrl b c",
                 Sequence(vec![
-                    Chord(vec![String::from("Ctrl")]),
-                    Chord(vec![String::from("Ctrl")]),
-                    Chord(vec![String::from("b")]),
-                    Chord(vec![String::from("c")]),
+                    (0, Chord(vec![String::from("Ctrl")])),
+                    (5, Chord(vec![String::from("Ctrl")])),
+                    (10, Chord(vec![String::from("b")])),
+                    (12, Chord(vec![String::from("c")])),
                 ]),
             ),
+            (
+                " Ctrl - a",
+                Sequence(vec![(1, Chord(vec![String::from("Ctrl"), String::from("a")]))]),
+            ),
         ];
 
         let tokenizer = Lexer::new();
         for (src, want) in cases.into_iter() {
-            let tokens = tokenizer.tokenize(src.chars())?;
-            let got = parse(tokens)?;
+            let tokens = tokenizer.tokenize(src)?;
+            let got = parse(src, tokens)?;
             assert_eq!(got, want);
         }
 
         Ok(())
     }
 
+    #[test]
+    fn test_parse_err() -> anyhow::Result<()> {
+        let cases = vec![
+            ("Ctrl--a", "unexpected '-' at position 5 in 'Ctrl--a'"),
+            ("-a", "unexpected '-' at position 0 in '-a'"),
+            ("a -", "expected a key after '-' at position 2 in 'a -'"),
+            ("", "empty keybinding"),
+            ("  ", "empty keybinding"),
+        ];
+
+        let tokenizer = Lexer::new();
+        for (src, want) in cases.into_iter() {
+            let tokens = tokenizer.tokenize(src)?;
+            match parse(src, tokens) {
+                Ok(seq) => panic!("bad success for '{}': {:?}", src, seq),
+                Err(e) => assert_eq!(e.to_string(), want),
+            }
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_tokenize_ok() -> anyhow::Result<()> {
         let cases = vec![
-            ("-", vec![Token::Dash]),
-            ("- ", vec![Token::Dash]),
-            ("-\t", vec![Token::Dash]),
-            (" -\t", vec![Token::Dash]),
-            (" \t-\t ", vec![Token::Dash]),
-            ("a", vec![Token::Key(String::from("a"))]),
-            ("a a", vec![Token::Key(String::from("a")), Token::Key(String::from("a"))]),
-            ("aa", vec![Token::Key(String::from("a")), Token::Key(String::from("a"))]),
-            ("Ctrl", vec![Token::Key(String::from("Ctrl"))]),
+            ("-", vec![(0, Token::Dash)]),
+            ("- ", vec![(0, Token::Dash)]),
+            ("-\t", vec![(0, Token::Dash)]),
+            (" -\t", vec![(1, Token::Dash)]),
+            (" \t-\t ", vec![(2, Token::Dash)]),
+            ("a", vec![(0, Token::Key(String::from("a")))]),
+            ("a a", vec![(0, Token::Key(String::from("a"))), (2, Token::Key(String::from("a")))]),
+            ("aa", vec![(0, Token::Key(String::from("a"))), (1, Token::Key(String::from("a")))]),
+            ("Ctrl", vec![(0, Token::Key(String::from("Ctrl")))]),
             (
                 "Ctrl-a",
-                vec![Token::Key(String::from("Ctrl")), Token::Dash, Token::Key(String::from("a"))],
+                vec![
+                    (0, Token::Key(String::from("Ctrl"))),
+                    (4, Token::Dash),
+                    (5, Token::Key(String::from("a"))),
+                ],
+            ),
+            (
+                "Ctrl-C",
+                vec![
+                    (0, Token::Key(String::from("Ctrl"))),
+                    (4, Token::Dash),
+                    (5, Token::Key(String::from("C"))),
+                ],
+            ),
+            (
+                "S-[",
+                vec![
+                    (0, Token::Key(String::from("S"))),
+                    (1, Token::Dash),
+                    (2, Token::Key(String::from("["))),
+                ],
+            ),
+            ("C ;", vec![(0, Token::Key(String::from("C"))), (2, Token::Key(String::from(";")))]),
+            ("X\\", vec![(0, Token::Key(String::from("X"))), (1, Token::Key(String::from("\\")))]),
+            ("F1", vec![(0, Token::Key(String::from("F1")))]),
+            ("F12", vec![(0, Token::Key(String::from("F12")))]),
+            ("F F1", vec![(0, Token::Key(String::from("F"))), (2, Token::Key(String::from("F1")))]),
+            (
+                "Alt-F1",
+                vec![
+                    (0, Token::Key(String::from("Alt"))),
+                    (3, Token::Dash),
+                    (4, Token::Key(String::from("F1"))),
+                ],
             ),
         ];
 
         let tokenizer = Lexer::new();
         for (src, want) in cases.into_iter() {
-            let got = tokenizer.tokenize(src.chars())?;
+            let got = tokenizer.tokenize(src)?;
             assert_eq!(got, want);
         }
 
@@ -626,21 +1523,59 @@ mod test {
 
     #[test]
     fn test_tokenize_err() -> anyhow::Result<()> {
-        let cases = vec![("CtrCtrl", "unexpected char"), ("Ctrc", "unexpected char")];
+        let cases = vec![
+            ("CtrCtrl", "unknown key 'CtrC' at position 0 in 'CtrCtrl'"),
+            ("Ctrc", "unknown key 'Ctrc' at position 0 in 'Ctrc'"),
+            ("a Ctr", "unknown key 'Ctr' at position 2 in 'a Ctr'"),
+            ("Ct rl", "unknown key 'Ct' at position 0 in 'Ct rl'"),
+            ("CC", "unknown key 'CC' at position 0 in 'CC'"),
+            ("Ctrl-Sx", "unknown key 'Sx' at position 5 in 'Ctrl-Sx'"),
+            ("F13", "unknown key 'F13' at position 0 in 'F13'"),
+            ("Altx", "unknown key 'Altx' at position 0 in 'Altx'"),
+            ("a é b", "unexpected 'é' at position 2 in 'a é b'"),
+        ];
 
         let tokenizer = Lexer::new();
-        for (src, errsubstr) in cases.into_iter() {
-            if let Err(err) = tokenizer.tokenize(src.chars()) {
-                let errstr = format!("{:?}", err);
-                assert!(errstr.contains(errsubstr));
-            } else {
-                panic!("expected an error")
+        for (src, want) in cases.into_iter() {
+            match tokenizer.tokenize(src) {
+                Ok(tokens) => panic!("bad success for '{}': {:?}", src, tokens),
+                Err(e) => assert_eq!(e.to_string(), want),
             }
         }
 
         Ok(())
     }
 
+    #[test]
+    fn test_any_input() {
+        // every short string over an alphabet of the interesting bits
+        // should either compile or come back with an error, never panic
+        let alphabet =
+            ["Ctrl", "Alt", "Space", "F1", "C", "S", "-", " ", "a", "z", "0", "X", "é", "\t"];
+        let mut srcs = vec![String::new()];
+        for _ in 0..4 {
+            let mut longer = vec![];
+            for src in srcs.iter() {
+                for piece in alphabet.iter() {
+                    longer.push(format!("{}{}", src, piece));
+                }
+            }
+            for src in longer.iter() {
+                let res = Bindings::new([(src.as_str(), Action::NoOp)]);
+                if let Err(e) = res {
+                    let msg = format!("{:#}", e);
+                    assert!(
+                        msg.contains(" at position ") || msg.contains("empty keybinding"),
+                        "unspanned error for '{}': {}",
+                        src,
+                        msg
+                    );
+                }
+            }
+            srcs = longer;
+        }
+    }
+
     #[test]
     fn test_trie_contains() {
         let cases =
@@ -656,4 +1591,29 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_action_round_trip() -> anyhow::Result<()> {
+        for action in [
+            Action::Detach,
+            Action::Kill,
+            Action::Reset,
+            Action::CopyMode,
+            Action::FlushOutput,
+            Action::ClearScrollback,
+            Action::ToggleStatusLine,
+            Action::Suspend,
+            Action::ToggleLocalEdit,
+            Action::NextJob,
+            Action::Lock,
+            Action::NoOp,
+            Action::Custom(String::from("open-editor")),
+        ] {
+            assert_eq!(action.to_string().parse::<Action>()?, action);
+        }
+        assert!("explode".parse::<Action>().is_err());
+        assert!("custom:".parse::<Action>().is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,238 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Support for capping the resources each session may use, as
+  configured by the `session_limits` config table.
+
+  There are two mechanisms. Rlimits get set on the shell right after
+  the fork and are inherited by everything it spawns, but they apply
+  to each process separately. For limits on the session as a whole,
+  the shell can be moved into its own transient systemd scope with
+  memory and cpu limits, which is done by asking the systemd manager
+  to start the scope over D-Bus.
+*/
+
+use std::process;
+
+use anyhow::{anyhow, bail, Context};
+use nix::{
+    sys::resource::{self, Resource},
+    unistd,
+};
+use tracing::info;
+
+use crate::config;
+
+/// Look up the rlimits from the config and convert them to the
+/// form setrlimit wants. This happens before the fork so that
+/// mistakes in the config can be reported properly.
+pub fn resolve_rlimits(limits: &config::SessionLimits) -> anyhow::Result<Vec<(Resource, u64)>> {
+    let mut resolved = vec![];
+    if let Some(rlimits) = &limits.rlimits {
+        for (name, value) in rlimits.iter() {
+            resolved.push((resource_for_name(name)?, *value));
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Set the given rlimits on the current process. Both the soft and
+/// hard limits get set so that the shell can't just raise them again.
+pub fn apply_rlimits(rlimits: &[(Resource, u64)]) -> nix::Result<()> {
+    for (resource, value) in rlimits.iter() {
+        resource::setrlimit(*resource, *value, *value)?;
+    }
+
+    Ok(())
+}
+
+fn resource_for_name(name: &str) -> anyhow::Result<Resource> {
+    Ok(match name {
+        "as" => Resource::RLIMIT_AS,
+        "core" => Resource::RLIMIT_CORE,
+        "cpu" => Resource::RLIMIT_CPU,
+        "data" => Resource::RLIMIT_DATA,
+        "fsize" => Resource::RLIMIT_FSIZE,
+        "memlock" => Resource::RLIMIT_MEMLOCK,
+        "nofile" => Resource::RLIMIT_NOFILE,
+        "nproc" => Resource::RLIMIT_NPROC,
+        "rss" => Resource::RLIMIT_RSS,
+        "stack" => Resource::RLIMIT_STACK,
+        _ => bail!("unknown rlimit '{}'", name),
+    })
+}
+
+/// Move the given process into a new transient scope unit with the
+/// configured limits. The scope goes away on its own once every
+/// process in it has exited.
+pub fn start_scope(
+    scope: &config::SessionScope,
+    session_name: &str,
+    pid: i32,
+) -> anyhow::Result<()> {
+    let unit = unit_name(session_name, pid);
+    let props = scope_properties(scope)?;
+
+    let mut cmd = process::Command::new("busctl");
+    if !unistd::Uid::current().is_root() {
+        cmd.arg("--user");
+    }
+    cmd.args([
+        "call",
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        "org.freedesktop.systemd1.Manager",
+        "StartTransientUnit",
+        "ssa(sv)a(sa(sv))",
+        &unit,
+        "fail",
+    ]);
+    // the pid and description always go along with the limits
+    cmd.arg((props.len() + 2).to_string());
+    cmd.args(["PIDs", "au", "1", &pid.to_string()]);
+    cmd.args(["Description", "s", &format!("shpool session {}", session_name)]);
+    for (name, value) in props.iter() {
+        cmd.args([name, "t", &value.to_string()]);
+    }
+    // no auxiliary units
+    cmd.arg("0");
+
+    let out = cmd.output().context("running busctl")?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "starting scope {}: {}",
+            unit,
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    info!("moved pid {} into {}", pid, unit);
+
+    Ok(())
+}
+
+/// A unit name for the session's scope. Characters that are not
+/// allowed in unit names get replaced.
+fn unit_name(session_name: &str, pid: i32) -> String {
+    let session_name: String = session_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "_.:".contains(c) { c } else { '_' })
+        .collect();
+    format!("shpool-{}-{}.scope", session_name, pid)
+}
+
+/// The systemd properties for the configured limits, all of which
+/// happen to be 64 bit unsigned ints on the wire.
+fn scope_properties(scope: &config::SessionScope) -> anyhow::Result<Vec<(&'static str, u64)>> {
+    let mut props = vec![];
+    if let Some(max) = &scope.memory_max {
+        props.push(("MemoryMax", parse_size(max).context("parsing memory_max")?));
+    }
+    if let Some(high) = &scope.memory_high {
+        props.push(("MemoryHigh", parse_size(high).context("parsing memory_high")?));
+    }
+    if let Some(quota) = &scope.cpu_quota {
+        props.push(("CPUQuotaPerSecUSec", parse_cpu_quota(quota).context("parsing cpu_quota")?));
+    }
+    if let Some(tasks) = scope.tasks_max {
+        props.push(("TasksMax", tasks));
+    }
+
+    Ok(props)
+}
+
+/// Parse a byte count with an optional K, M, G or T suffix, which
+/// are powers of 1024 just like they are in systemd unit files.
+fn parse_size(src: &str) -> anyhow::Result<u64> {
+    let src = src.trim();
+    let (num, shift) = match src.char_indices().last() {
+        Some((i, 'K')) => (&src[..i], 10),
+        Some((i, 'M')) => (&src[..i], 20),
+        Some((i, 'G')) => (&src[..i], 30),
+        Some((i, 'T')) => (&src[..i], 40),
+        _ => (src, 0),
+    };
+    let num = num.trim().parse::<u64>().with_context(|| format!("invalid size '{}'", src))?;
+    num.checked_mul(1 << shift).ok_or(anyhow!("size '{}' is too big", src))
+}
+
+/// Parse a cpu quota percentage like "150%" into the microseconds of
+/// cpu time allowed per second.
+fn parse_cpu_quota(src: &str) -> anyhow::Result<u64> {
+    let percent = src
+        .trim()
+        .strip_suffix('%')
+        .ok_or(anyhow!("cpu quota '{}' must be a percentage", src))?
+        .trim()
+        .parse::<u64>()
+        .with_context(|| format!("invalid cpu quota '{}'", src))?;
+    if percent == 0 {
+        bail!("cpu quota must be more than 0%");
+    }
+    Ok(percent * 10_000)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn sizes() -> anyhow::Result<()> {
+        assert_eq!(parse_size("512")?, 512);
+        assert_eq!(parse_size("4K")?, 4096);
+        assert_eq!(parse_size("2G")?, 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size(" 1 T ")?, 1 << 40);
+        for bad in ["", "G", "1.5G", "-1", "99999999999T"] {
+            assert!(parse_size(bad).is_err(), "src={}", bad);
+        }
+
+        assert_eq!(parse_cpu_quota("50%")?, 500_000);
+        assert_eq!(parse_cpu_quota("200%")?, 2_000_000);
+        for bad in ["50", "0%", "x%"] {
+            assert!(parse_cpu_quota(bad).is_err(), "src={}", bad);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn scope_setup() -> anyhow::Result<()> {
+        assert_eq!(unit_name("build-linux", 42), "shpool-build_linux-42.scope");
+        assert_eq!(unit_name("a/b c", 7), "shpool-a_b_c-7.scope");
+
+        let scope = config::SessionScope {
+            memory_max: Some(String::from("1G")),
+            memory_high: None,
+            cpu_quota: Some(String::from("100%")),
+            tasks_max: Some(64),
+        };
+        assert_eq!(
+            scope_properties(&scope)?,
+            vec![("MemoryMax", 1 << 30), ("CPUQuotaPerSecUSec", 1_000_000), ("TasksMax", 64)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn rlimit_names() {
+        assert!(resource_for_name("nofile").is_ok());
+        assert!(resource_for_name("NOFILE").is_err());
+        assert!(resource_for_name("bogus").is_err());
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Session locking, for stepping away from an attached terminal.
+
+  When the lock keybinding fires, or the attached client has sent no
+  input for longer than the idle timeout, the session gets marked as
+  locked. While it is locked, the reader thread holds back the shell's
+  output and shows a passphrase prompt instead, and the client's attach
+  loop collects input as a passphrase rather than passing it along to
+  the shell. The lock belongs to the session rather than the client, so
+  reattaching to a locked session lands on the same prompt.
+
+  Passphrases are checked against an argon2 hash from the config, or
+  failing that, against the password of the user running the daemon
+  with PAM. A session can't get locked unless one of the two is set up,
+  since there would be no way to unlock it.
+*/
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time,
+};
+
+use anyhow::anyhow;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use tracing::info;
+
+use crate::{config, duration, user};
+
+/// How long to make someone wait after a wrong passphrase, to slow
+/// down guessing. PAM has a delay of its own.
+const FAILURE_DELAY: time::Duration = time::Duration::from_secs(1);
+
+/// The longest passphrase we will collect. Anything past this is
+/// dropped rather than letting a paste grow the buffer forever.
+const MAX_PASSPHRASE_LEN: usize = 1024;
+
+/// Whether a session is locked, along with when its attached client
+/// last sent input, for the idle timeout. Shared between the threads
+/// serving the session.
+#[derive(Debug)]
+pub struct State {
+    locked: AtomicBool,
+    last_input: Mutex<time::Instant>,
+}
+
+impl State {
+    pub fn new() -> Self {
+        State { locked: AtomicBool::new(false), last_input: Mutex::new(time::Instant::now()) }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Acquire)
+    }
+
+    /// Mark the session as locked. Returns false if it already was.
+    pub fn lock(&self) -> bool {
+        !self.locked.swap(true, Ordering::AcqRel)
+    }
+
+    pub fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+        self.touch();
+    }
+
+    /// Note that the attached client just sent some input.
+    pub fn touch(&self) {
+        *self.last_input.lock().unwrap() = time::Instant::now();
+    }
+
+    /// Check if the session has gone without input for at least the
+    /// given timeout.
+    pub fn idle(&self, timeout: time::Duration) -> bool {
+        self.last_input.lock().unwrap().elapsed() >= timeout
+    }
+}
+
+/// How long a session can go without input before it locks itself,
+/// if the config asks for that. This gets looked up on every heartbeat,
+/// so a bad value is quietly ignored, and `shpool doctor` points it out.
+pub fn idle_timeout(config: &config::Config) -> Option<time::Duration> {
+    let src = config.lock.as_ref()?.idle_timeout.as_deref()?;
+    duration::parse(src).ok()
+}
+
+/// A way of checking the passphrase that unlocks a session.
+#[derive(Debug)]
+pub enum Verifier {
+    /// An argon2 hash in the PHC string format.
+    Hash(String),
+    /// The password of the given user, checked with the given PAM
+    /// service.
+    Pam { service: String, user: String },
+}
+
+impl Verifier {
+    /// The verifier the config asks for, if any.
+    pub fn from_config(config: &config::Config) -> anyhow::Result<Option<Self>> {
+        let lock = match config.lock.as_ref() {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        if let Some(hash) = &lock.passphrase_hash {
+            return Ok(Some(Verifier::Hash(hash.clone())));
+        }
+        if let Some(service) = &lock.pam_service {
+            let user = user::info()?.user;
+            return Ok(Some(Verifier::Pam { service: service.clone(), user }));
+        }
+        Ok(None)
+    }
+
+    /// Check the given passphrase. Failures take a little while to
+    /// come back so that guessing is slow.
+    pub fn verify(&self, passphrase: &[u8]) -> anyhow::Result<bool> {
+        let ok = match self {
+            Verifier::Hash(hash) => {
+                let hash = PasswordHash::new(hash)
+                    .map_err(|e| anyhow!("parsing lock passphrase_hash: {}", e))?;
+                let ok = Argon2::default().verify_password(passphrase, &hash).is_ok();
+                if !ok {
+                    std::thread::sleep(FAILURE_DELAY);
+                }
+                ok
+            }
+            Verifier::Pam { service, user } => {
+                let password = String::from_utf8_lossy(passphrase);
+                let mut authenticator = pam::Authenticator::with_password(service)
+                    .map_err(|e| anyhow!("starting pam conversation: {}", e))?;
+                authenticator.get_handler().set_credentials(user.as_str(), password.as_ref());
+                match authenticator.authenticate() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        info!("pam authentication failed: {}", e);
+                        false
+                    }
+                }
+            }
+        };
+        Ok(ok)
+    }
+}
+
+/// Collects a passphrase from raw terminal input, with just enough
+/// line editing to fix a typo.
+#[derive(Debug, Default)]
+pub struct Prompt {
+    buf: Vec<u8>,
+}
+
+impl Prompt {
+    /// Feed the prompt some input, returning the passphrase once enter
+    /// gets hit. Anything after the enter is dropped.
+    pub fn handle_input(&mut self, input: &[u8]) -> Option<Vec<u8>> {
+        for byte in input.iter() {
+            match *byte {
+                b'\r' | b'\n' => return Some(std::mem::take(&mut self.buf)),
+                // backspace and delete
+                0x08 | 0x7f => {
+                    self.buf.pop();
+                }
+                // Ctrl-U and Ctrl-C start over
+                0x15 | 0x03 => self.buf.clear(),
+                b if b < 0x20 => {}
+                b if self.buf.len() < MAX_PASSPHRASE_LEN => self.buf.push(b),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+/// What to show the client of a locked session in place of its output.
+pub fn screen(session: &str, message: Option<&str>, dumb_term: bool) -> Vec<u8> {
+    let mut screen = String::new();
+    if !dumb_term {
+        // clear the screen, along with the terminal's own scrollback,
+        // which would otherwise still show what was in the session
+        screen.push_str("\x1b[H\x1b[2J\x1b[3J");
+    }
+    screen.push_str(&format!("shpool: session '{}' is locked\r\n", session));
+    if let Some(message) = message {
+        screen.push_str(&format!("shpool: {}\r\n", message));
+    }
+    screen.push_str("passphrase: ");
+    screen.into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn prompt_editing() {
+        let mut prompt = Prompt::default();
+        assert_eq!(prompt.handle_input(b"hunx"), None);
+        assert_eq!(prompt.handle_input(b"\x7fter\x1b"), None);
+        assert_eq!(prompt.handle_input(b"2\rignored"), Some(b"hunter2".to_vec()));
+
+        assert_eq!(prompt.handle_input(b"oops\x15hunter2\n"), Some(b"hunter2".to_vec()));
+        assert_eq!(prompt.handle_input(b"\r"), Some(vec![]));
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn verify_hash() -> anyhow::Result<()> {
+        let verifier = Verifier::Hash(String::from(
+            "$argon2id$v=19$m=19456,t=2,p=1$c2hwb29sdGVzdHNhbHQhIQ$ouVstKYNKwEkHd47UkUtUXKMnUcVT+xSCA4ymMtENtA",
+        ));
+        assert!(verifier.verify(b"hunter2")?);
+        assert!(!verifier.verify(b"hunter3")?);
+
+        let verifier = Verifier::Hash(String::from("not a hash"));
+        assert!(verifier.verify(b"hunter2").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn lock_state() {
+        let state = State::new();
+        assert!(!state.is_locked());
+        assert!(state.lock());
+        assert!(!state.lock());
+        assert!(state.is_locked());
+        state.unlock();
+        assert!(!state.is_locked());
+        assert!(!state.idle(time::Duration::from_secs(60)));
+        assert!(state.idle(time::Duration::ZERO));
+    }
+}
@@ -0,0 +1,281 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Daemon metrics in the Prometheus text format.
+
+  The counters live in a global so that the threads doing the work can
+  bump them without having to thread a handle through everything. The
+  gauges are computed from the session table whenever the metrics get
+  rendered, so they can never drift. Metrics can be dumped with
+  `shpool metrics` or scraped over plain HTTP from the optional
+  `metrics_listener`.
+
+  Nothing here is labeled with session names, so the metrics are safe
+  to hand to a monitoring system that other people can look at.
+*/
+
+use std::{
+    fmt::Write as _,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::atomic::{AtomicU64, Ordering},
+    thread, time,
+};
+
+use anyhow::{anyhow, Context};
+use tracing::{error, info, warn};
+
+use crate::consts;
+
+pub static METRICS: Metrics = Metrics::new();
+
+/// The upper bounds of the attach latency histogram buckets, in
+/// milliseconds.
+const LATENCY_BUCKETS_MS: [u64; 8] = [1, 5, 10, 50, 100, 500, 1000, 5000];
+
+/// The longest HTTP request we bother reading.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+pub struct Metrics {
+    pub connections: AtomicU64,
+    pub sessions_created: AtomicU64,
+    pub bytes_to_clients: AtomicU64,
+    pub dropped_output_bytes: AtomicU64,
+    pub bytes_from_clients: AtomicU64,
+    pub keybinding_matches: AtomicU64,
+    pub reaps: AtomicU64,
+    attach_latency_buckets: [AtomicU64; LATENCY_BUCKETS_MS.len()],
+    attach_latency_sum_us: AtomicU64,
+    attach_latency_count: AtomicU64,
+}
+
+/// Bump one of the counters in METRICS.
+pub fn inc(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+/// The current values of the gauges, which the caller has to look up
+/// since they come from the session table.
+pub struct Gauges {
+    pub sessions: usize,
+    pub attached: usize,
+}
+
+impl Metrics {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const fn new() -> Self {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Metrics {
+            connections: ZERO,
+            sessions_created: ZERO,
+            bytes_to_clients: ZERO,
+            dropped_output_bytes: ZERO,
+            bytes_from_clients: ZERO,
+            keybinding_matches: ZERO,
+            reaps: ZERO,
+            attach_latency_buckets: [ZERO; LATENCY_BUCKETS_MS.len()],
+            attach_latency_sum_us: ZERO,
+            attach_latency_count: ZERO,
+        }
+    }
+
+    /// Note how long it took to get a client attached.
+    pub fn observe_attach_latency(&self, latency: time::Duration) {
+        let ms = latency.as_millis() as u64;
+        if let Some(i) = LATENCY_BUCKETS_MS.iter().position(|bound| ms <= *bound) {
+            self.attach_latency_buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.attach_latency_sum_us.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        self.attach_latency_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render everything in the Prometheus text exposition format.
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+
+        let gauge = |out: &mut String, name: &str, help: &str, value: usize| {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n");
+        };
+        gauge(&mut out, "shpool_sessions", "Sessions currently running.", gauges.sessions);
+        gauge(
+            &mut out,
+            "shpool_attached_clients",
+            "Sessions that currently have a client attached.",
+            gauges.attached,
+        );
+
+        let counter = |out: &mut String, name: &str, help: &str, value: u64| {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n");
+        };
+        counter(
+            &mut out,
+            "shpool_connections_total",
+            "Connections accepted on the daemon socket.",
+            load(&self.connections),
+        );
+        counter(
+            &mut out,
+            "shpool_sessions_created_total",
+            "Sessions created.",
+            load(&self.sessions_created),
+        );
+        counter(
+            &mut out,
+            "shpool_output_bytes_total",
+            "Bytes of shell output sent to clients.",
+            load(&self.bytes_to_clients),
+        );
+        counter(
+            &mut out,
+            "shpool_dropped_output_bytes_total",
+            "Bytes of shell output thrown away because a client could not keep up.",
+            load(&self.dropped_output_bytes),
+        );
+        counter(
+            &mut out,
+            "shpool_input_bytes_total",
+            "Bytes of input read from clients.",
+            load(&self.bytes_from_clients),
+        );
+        counter(
+            &mut out,
+            "shpool_keybinding_matches_total",
+            "Keybindings that fired.",
+            load(&self.keybinding_matches),
+        );
+        counter(
+            &mut out,
+            "shpool_reaped_sessions_total",
+            "Sessions killed because their ttl ran out.",
+            load(&self.reaps),
+        );
+
+        let name = "shpool_attach_latency_seconds";
+        let _ = write!(
+            out,
+            "# HELP {name} Time from an attach request to the reply.\n# TYPE {name} histogram\n"
+        );
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS_MS.iter().zip(self.attach_latency_buckets.iter()) {
+            cumulative += load(bucket);
+            let _ =
+                writeln!(out, "{name}_bucket{{le=\"{}\"}} {cumulative}", *bound as f64 / 1000.0);
+        }
+        let count = load(&self.attach_latency_count);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {}", load(&self.attach_latency_sum_us) as f64 / 1e6);
+        let _ = writeln!(out, "{name}_count {count}");
+
+        out
+    }
+}
+
+/// Bind the metrics listener and start serving scrapes in the
+/// background. The render callback produces the current metrics.
+pub fn spawn_listener<F>(listen: &str, render: F) -> anyhow::Result<()>
+where
+    F: Fn() -> String + Send + 'static,
+{
+    let listener = TcpListener::bind(listen)
+        .with_context(|| format!("binding metrics listener {}", listen))?;
+    info!("serving metrics on http://{}/metrics", listen);
+
+    thread::Builder::new()
+        .name(String::from("metrics-listener"))
+        .spawn(move || {
+            // Scrapes are rare and cheap, so there is no need for a
+            // thread per connection.
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = serve_scrape(stream, &render) {
+                            warn!("serving metrics scrape: {:?}", e);
+                        }
+                    }
+                    Err(e) => error!("accepting metrics connection: {:?}", e),
+                }
+            }
+        })
+        .map_err(|e| anyhow!("{:?}", e))?;
+
+    Ok(())
+}
+
+fn serve_scrape<F>(mut stream: TcpStream, render: &F) -> anyhow::Result<()>
+where
+    F: Fn() -> String,
+{
+    stream.set_read_timeout(Some(consts::SOCK_STREAM_TIMEOUT)).context("setting read timeout")?;
+    stream.set_write_timeout(Some(consts::SOCK_STREAM_TIMEOUT)).context("setting write timeout")?;
+
+    // read up to the end of the headers, we don't care what is in them
+    let mut req = vec![];
+    let mut buf = [0; 1024];
+    while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+        let len = stream.read(&mut buf).context("reading request")?;
+        if len == 0 {
+            break;
+        }
+        req.extend_from_slice(&buf[..len]);
+        if req.len() > MAX_REQUEST_LEN {
+            return Err(anyhow!("request too long"));
+        }
+    }
+
+    let req = String::from_utf8_lossy(&req);
+    let mut request_line = req.lines().next().unwrap_or("").split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .context("writing response")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn render_format() {
+        let metrics = Metrics::new();
+        inc(&metrics.bytes_to_clients, 42);
+        metrics.observe_attach_latency(time::Duration::from_millis(3));
+        metrics.observe_attach_latency(time::Duration::from_millis(70));
+        metrics.observe_attach_latency(time::Duration::from_secs(60));
+
+        let out = metrics.render(&Gauges { sessions: 3, attached: 1 });
+        assert!(out.contains("# TYPE shpool_sessions gauge\nshpool_sessions 3\n"));
+        assert!(out.contains("\nshpool_attached_clients 1\n"));
+        assert!(out.contains("\nshpool_output_bytes_total 42\n"));
+        assert!(out.contains("\nshpool_attach_latency_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(out.contains("\nshpool_attach_latency_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(out.contains("\nshpool_attach_latency_seconds_bucket{le=\"0.1\"} 2\n"));
+        assert!(out.contains("\nshpool_attach_latency_seconds_bucket{le=\"5\"} 2\n"));
+        assert!(out.contains("\nshpool_attach_latency_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("\nshpool_attach_latency_seconds_count 3\n"));
+    }
+}
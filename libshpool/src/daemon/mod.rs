@@ -12,58 +12,334 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{os::unix::net::UnixListener, path::PathBuf};
+use std::{collections::HashMap, fmt, path::PathBuf, sync::Arc, thread};
 
 use anyhow::Context;
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 
-use super::{config, hooks};
+use super::{config, hooks, paths, pty, NoopHooks};
 
+mod acl;
+mod activity;
+mod affinity;
+mod attach_auth;
+mod cast;
+mod copy_mode;
+mod ctl;
 mod etc_environment;
+mod events;
 mod exit_notify;
+pub mod holder;
+mod hook_commands;
 pub mod keybindings;
+mod limits;
+mod lock;
+mod metrics;
+pub mod multi_user;
+mod nsenter;
+mod osc;
+mod output_queue;
 mod pager;
+mod pam_session;
+mod paste_buffers;
 mod prompt;
+mod redact;
+mod reexec;
+mod registry;
+mod resurrect;
+mod search;
 mod server;
+mod session_env;
 mod shell;
 mod show_motd;
 mod signals;
+mod socket_file;
+mod spill;
+mod status_file;
+mod status_line;
+pub mod supervisor;
 mod systemd;
+mod tcp;
+mod term_compat;
 mod trie;
 mod ttl_reaper;
+mod utf8;
+mod utmp;
 
-#[instrument(skip_all)]
-pub fn run(
-    config_file: Option<String>,
+#[cfg(feature = "fuzz")]
+pub use server::fuzz;
+
+/// An action that keybindings can run with `action = "custom:<name>"`,
+/// registered with `DaemonBuilder::custom_action`. It gets called with the
+/// name of the session the keybinding was pressed in.
+///
+/// Like hooks, custom actions are run inline on the thread that reads
+/// input from the client, so they MUST NOT block for long. Errors are
+/// simply logged.
+pub type CustomAction = Box<dyn Fn(&str) -> anyhow::Result<()> + Send + Sync>;
+
+/// The custom actions registered with the builder, by name.
+#[derive(Default)]
+struct CustomActions(HashMap<String, CustomAction>);
+
+impl CustomActions {
+    fn get(&self, name: &str) -> Option<&CustomAction> {
+        self.0.get(name)
+    }
+}
+
+impl fmt::Debug for CustomActions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// The shpool daemon, for tools that want to embed session pooling
+/// rather than shelling out to `shpool daemon`.
+///
+/// ```no_run
+/// let daemon = libshpool::Daemon::builder()
+///     .socket("/tmp/mytool/shpool.socket")
+///     .custom_action("hello", |session| {
+///         eprintln!("hello from {}", session);
+///         Ok(())
+///     })
+///     .build()?;
+/// daemon.run()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// The daemon installs its own handlers for SIGINT and SIGTERM which
+/// tell attached clients that the daemon is going away, clean up the
+/// socket and exit the process. A second signal exits right away.
+pub struct Daemon {
+    config: config::Manager,
     runtime_dir: PathBuf,
-    hooks: Box<dyn hooks::Hooks + Send + Sync>,
     socket: PathBuf,
-) -> anyhow::Result<()> {
-    info!("\n\n======================== STARTING DAEMON ============================\n\n");
+    hooks: Box<dyn hooks::Hooks + Send + Sync>,
+    pty_backend: Box<dyn pty::PtyBackend + Send + Sync>,
+    custom_actions: CustomActions,
+    takeover: bool,
+    instance: Option<String>,
+}
 
-    let config_manager = config::Manager::new(config_file.as_deref())?;
-    let server = server::Server::new(config_manager, hooks, runtime_dir)?;
+/// Sets up a `Daemon`. Everything is optional, and anything left unset
+/// gets the same default that `shpool daemon` would use.
+#[derive(Default)]
+pub struct DaemonBuilder {
+    config_file: Option<String>,
+    config: Option<config::Config>,
+    runtime_dir: Option<PathBuf>,
+    socket: Option<PathBuf>,
+    hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>,
+    pty_backend: Option<Box<dyn pty::PtyBackend + Send + Sync>>,
+    custom_actions: CustomActions,
+    takeover: bool,
+    instance: Option<String>,
+}
+
+impl Daemon {
+    pub fn builder() -> DaemonBuilder {
+        DaemonBuilder::default()
+    }
+
+    /// Serve connections on the socket until the process gets signaled.
+    #[instrument(skip_all)]
+    pub fn run(self) -> anyhow::Result<()> {
+        let Daemon {
+            config,
+            runtime_dir,
+            socket,
+            hooks,
+            pty_backend,
+            custom_actions,
+            takeover,
+            instance,
+        } = self;
+        if let Some(tcp_listener) = &config.get().tcp_listener {
+            tcp::spawn(tcp_listener, socket.clone()).context("starting tcp listener")?;
+        }
+        let metrics_listener = config.get().metrics_listener.clone();
 
-    let (cleanup_socket, listener) = match systemd::activation_socket() {
-        Ok(l) => {
-            info!("using systemd activation socket");
-            (None, l)
+        let (socket_file, listener) = match systemd::activation_socket() {
+            Ok(l) => {
+                info!("using systemd activation socket");
+                (None, l)
+            }
+            Err(e) => {
+                info!("no systemd activation socket: {:?}", e);
+                let (socket_file, l) =
+                    socket_file::bind(&socket, takeover).context("binding to socket")?;
+                (Some(socket_file), l)
+            }
+        };
+        let server = server::Server::new(server::ServerOptions {
+            config,
+            hooks,
+            pty_backend,
+            custom_actions,
+            runtime_dir,
+            socket,
+            socket_file,
+            instance,
+        })?;
+        if let Some(listen) = metrics_listener {
+            let server = Arc::clone(&server);
+            metrics::spawn_listener(&listen, move || server.render_metrics())
+                .context("starting metrics listener")?;
         }
-        Err(e) => {
-            info!("no systemd activation socket: {:?}", e);
-            (Some(socket.clone()), UnixListener::bind(&socket).context("binding to socket")?)
+        if let Err(err) = server.adopt_held_sessions() {
+            warn!("could not adopt held sessions: {:?}", err);
         }
-    };
-    // spawn the signal handler thread in the background
-    signals::Handler::new(cleanup_socket.clone()).spawn()?;
+        if let Some(handoff) = multi_user::handoff_socket()? {
+            info!("taking connections from the multi-user daemon");
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                if let Err(err) = server::Server::serve_handoff(server, handoff) {
+                    warn!("serving handed off connections: {:?}", err);
+                }
+            });
+        }
+
+        // spawn the signal handler thread in the background
+        signals::Handler::new(Arc::clone(&server)).spawn()?;
+
+        server::Server::serve(Arc::clone(&server), listener)?;
 
-    server::Server::serve(server, listener)?;
+        server.shutdown(false, "the daemon stopped serving").context("shutting down")?;
+
+        Ok(())
+    }
+}
 
-    if let Some(sock) = cleanup_socket {
-        std::fs::remove_file(sock).context("cleaning up socket on exit")?;
-    } else {
-        info!("systemd manages the socket, so not cleaning it up");
+impl DaemonBuilder {
+    /// Load the config from the given file, and reload it whenever the
+    /// file changes. Without this or `config`, the config comes from
+    /// ~/.config/shpool/config.toml if it exists.
+    pub fn config_file<S: Into<String>>(mut self, config_file: S) -> Self {
+        self.config_file = Some(config_file.into());
+        self
     }
 
-    Ok(())
+    /// Use the given config, rather than loading one from a file. Takes
+    /// precedence over `config_file`.
+    pub fn config(mut self, config: config::Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Where to keep per-session state like the SSH_AUTH_SOCK symlinks.
+    /// Defaults to $XDG_RUNTIME_DIR/shpool.
+    pub fn runtime_dir<P: Into<PathBuf>>(mut self, runtime_dir: P) -> Self {
+        self.runtime_dir = Some(runtime_dir.into());
+        self
+    }
+
+    /// The socket to listen on. Defaults to shpool.socket in the
+    /// runtime dir.
+    pub fn socket<P: Into<PathBuf>>(mut self, socket: P) -> Self {
+        self.socket = Some(socket.into());
+        self
+    }
+
+    pub fn hooks(mut self, hooks: Box<dyn hooks::Hooks + Send + Sync>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Spawn session shells with the given backend rather than in
+    /// real ptys.
+    pub fn pty_backend(mut self, pty_backend: Box<dyn pty::PtyBackend + Send + Sync>) -> Self {
+        self.pty_backend = Some(pty_backend);
+        self
+    }
+
+    /// If another daemon is already listening on the socket, ask it to
+    /// shut down and hand over its sessions rather than giving up.
+    pub fn takeover(mut self, takeover: bool) -> Self {
+        self.takeover = takeover;
+        self
+    }
+
+    /// Name the daemon's instance. Sessions get the name as
+    /// $SHPOOL_INSTANCE, which is handy for telling them apart in a
+    /// prompt.
+    pub fn instance<S: Into<String>>(mut self, instance: S) -> Self {
+        self.instance = Some(instance.into());
+        self
+    }
+
+    /// Register an action that keybindings can run with
+    /// `action = "custom:<name>"`.
+    pub fn custom_action<S, F>(mut self, name: S, action: F) -> Self
+    where
+        S: Into<String>,
+        F: Fn(&str) -> anyhow::Result<()> + Send + Sync + 'static,
+    {
+        self.custom_actions.0.insert(name.into(), Box::new(action));
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<Daemon> {
+        info!("\n\n======================== STARTING DAEMON ============================\n\n");
+
+        let config = match self.config {
+            Some(config) => config::Manager::from_config(config),
+            None => config::Manager::new(self.config_file.as_deref())?,
+        };
+        let runtime_dir = match self.runtime_dir {
+            Some(d) => d,
+            None => paths::runtime_dir(&config.get())?,
+        };
+        let socket = self.socket.unwrap_or_else(|| runtime_dir.join("shpool.socket"));
+        Ok(Daemon {
+            config,
+            runtime_dir,
+            socket,
+            hooks: self.hooks.unwrap_or_else(|| Box::new(NoopHooks {})),
+            pty_backend: self.pty_backend.unwrap_or_else(|| Box::<pty::Forking>::default()),
+            custom_actions: self.custom_actions,
+            takeover: self.takeover,
+            instance: self.instance,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn builder() -> anyhow::Result<()> {
+        let config: config::Config = toml::from_str(
+            r#"
+            [[keybinding]]
+            binding = "Ctrl-a e"
+            action = "custom:open-editor"
+            "#,
+        )?;
+        let daemon = Daemon::builder()
+            .config(config)
+            .runtime_dir("/tmp/shpool-builder-test")
+            .custom_action("open-editor", |session| {
+                if session == "main" {
+                    Ok(())
+                } else {
+                    Err(anyhow::anyhow!("wrong session"))
+                }
+            })
+            .build()?;
+
+        assert_eq!(daemon.socket, PathBuf::from("/tmp/shpool-builder-test/shpool.socket"));
+        let keybindings = daemon.config.get().keybinding.clone().unwrap_or_default();
+        assert_eq!(keybindings[0].action.to_string(), "custom:open-editor");
+        let action = daemon.custom_actions.get("open-editor").expect("registered action");
+        assert!(action("main").is_ok());
+        assert!(action("other").is_err());
+        assert!(daemon.custom_actions.get("nope").is_none());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,389 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Running one daemon for every user on a machine.
+
+  `shpool daemon --multi-user` is meant to get started as root by the
+  init system, rather than by each user. It listens on a socket anyone
+  can connect to, and looks up who is connecting with SO_PEERCRED. The
+  first connection from a user starts up a worker daemon for them,
+  which is an ordinary `shpool daemon` that has dropped down to the
+  user's uid, gid and groups, with its own socket in a directory only
+  the user can get into. Every connection from that user then gets
+  handed to their worker as an fd over a socketpair, so the worker
+  talks to the client directly, sees the client's own credentials, and
+  spawns shells as the user. Users never see each other's sessions,
+  and each worker uses its user's own config.
+
+  Workers that exit get started back up on the next connection. The
+  root daemon doesn't stop the workers when it exits, so a service
+  manager should stop the whole group of processes.
+*/
+
+use std::{
+    collections::HashMap,
+    env,
+    ffi::CString,
+    fs,
+    io::{IoSlice, IoSliceMut},
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            fs::{chown, PermissionsExt},
+            net::UnixStream,
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{anyhow, Context};
+use nix::{
+    errno::Errno,
+    poll,
+    sys::socket::{self, ControlMessage, ControlMessageOwned, MsgFlags},
+    unistd::{self, Uid, User},
+};
+use tracing::{error, info, instrument, warn};
+
+use crate::daemon::socket_file;
+
+/// Where the root daemon listens by default.
+pub const SYSTEM_SOCKET: &str = "/run/shpool/shpool.socket";
+
+/// The env var that tells a worker daemon which fd to take connections
+/// from, alongside its own socket.
+pub const HANDOFF_FD_VAR: &str = "SHPOOL__INTERNAL__HANDOFF_FD";
+
+/// The fd the handoff socket gets put at in a worker.
+const HANDOFF_FD: RawFd = 3;
+
+/// How long to wait on a worker that is behind on taking connections
+/// before turning a connection away.
+const HANDOFF_RETRY_MS: u16 = 100;
+
+const DEFAULT_PATH: &str = "/usr/bin:/bin:/usr/sbin:/sbin";
+
+/// A worker daemon for one user.
+struct Worker {
+    child: process::Child,
+    handoff: UnixStream,
+}
+
+/// Listen on the given socket as root, handing each connection off to
+/// the worker daemon for the user on the other end.
+#[instrument(skip_all)]
+pub fn run(socket: &Path, verbose: u8) -> anyhow::Result<()> {
+    if !Uid::effective().is_root() {
+        return Err(anyhow!("the multi-user daemon has to run as root"));
+    }
+    let runtime_dir = socket.parent().ok_or(anyhow!("socket has no parent dir"))?;
+    fs::create_dir_all(runtime_dir.join("users")).context("creating runtime dir")?;
+
+    let (_socket_file, listener) = socket_file::bind(socket, false).context("binding to socket")?;
+    // Anyone may connect, who they are decides where the connection goes.
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o666))
+        .context("opening up socket permissions")?;
+    info!("listening for all users on {:?}", socket);
+
+    let mut workers: HashMap<u32, Worker> = HashMap::new();
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(err) => {
+                error!("accepting stream: {:?}", err);
+                continue;
+            }
+        };
+        if let Err(err) = dispatch(&mut workers, runtime_dir, stream, verbose) {
+            error!("handing off connection: {:?}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Hand the connection to the worker for its user, starting one up if
+/// need be.
+fn dispatch(
+    workers: &mut HashMap<u32, Worker>,
+    runtime_dir: &Path,
+    stream: UnixStream,
+    verbose: u8,
+) -> anyhow::Result<()> {
+    let creds = socket::getsockopt(&stream, socket::sockopt::PeerCredentials)
+        .context("getting peer credentials")?;
+    let uid = creds.uid();
+
+    if let Some(mut worker) = workers.remove(&uid) {
+        match worker.child.try_wait() {
+            Ok(None) => match send_conn_retrying(&worker.handoff, &stream) {
+                Ok(()) => {
+                    workers.insert(uid, worker);
+                    return Ok(());
+                }
+                // The worker is still up, it just has a backlog of
+                // connections to get through. Turn this one away rather
+                // than hold up everyone else's connections waiting on
+                // it, and keep the worker, since it has all of the
+                // user's sessions.
+                Err(Errno::EAGAIN) => {
+                    workers.insert(uid, worker);
+                    return Err(anyhow!(
+                        "worker for uid {} is backed up, refusing the connection",
+                        uid
+                    ));
+                }
+                Err(err) => {
+                    warn!("worker for uid {} is not taking connections: {:?}", uid, err);
+                    stop_worker(uid, worker);
+                }
+            },
+            Ok(Some(status)) => info!("worker for uid {} exited with {}", uid, status),
+            Err(err) => warn!("checking on worker for uid {}: {:?}", uid, err),
+        }
+    }
+
+    let worker = spawn_worker(runtime_dir, uid, verbose)
+        .with_context(|| format!("starting worker for uid {}", uid))?;
+    send_conn(&worker.handoff, &stream).context("handing connection to new worker")?;
+    workers.insert(uid, worker);
+    Ok(())
+}
+
+/// Kill off a worker that is still running but has stopped taking
+/// connections altogether, so that a new one can take its place.
+fn stop_worker(uid: u32, mut worker: Worker) {
+    if let Err(err) = worker.child.kill() {
+        warn!("killing worker for uid {}: {:?}", uid, err);
+    }
+    if let Err(err) = worker.child.wait() {
+        warn!("reaping worker for uid {}: {:?}", uid, err);
+    }
+}
+
+/// Start a daemon running as the given user, with its socket in a per
+/// user directory that only the user can get into.
+fn spawn_worker(runtime_dir: &Path, uid: u32, verbose: u8) -> anyhow::Result<Worker> {
+    let user =
+        User::from_uid(Uid::from_raw(uid))?.ok_or(anyhow!("no passwd entry for uid {}", uid))?;
+    let user_dir = runtime_dir.join("users").join(uid.to_string());
+    fs::create_dir_all(&user_dir).context("creating user dir")?;
+    chown(&user_dir, Some(uid), Some(user.gid.as_raw())).context("chowning user dir")?;
+    fs::set_permissions(&user_dir, fs::Permissions::from_mode(0o700))
+        .context("restricting user dir")?;
+
+    let (handoff, worker_end) = UnixStream::pair().context("creating handoff socket")?;
+    // Only our end, so that a worker which stops taking connections
+    // can't block the loop handing them out.
+    handoff.set_nonblocking(true).context("making handoff socket non-blocking")?;
+    let worker_fd = worker_end.as_raw_fd();
+    let name = CString::new(user.name.as_str()).context("user name has a NUL")?;
+    let gid = user.gid;
+    // Looking up the groups reads the group database through NSS, which
+    // is not safe to do in the child after fork.
+    let groups = unistd::getgrouplist(&name, gid).context("looking up the user's groups")?;
+    let user_uid = user.uid;
+
+    let mut cmd = process::Command::new(env::current_exe().context("resolving shpool binary")?);
+    if verbose > 0 {
+        cmd.arg(format!("-{}", "v".repeat(verbose as usize)));
+    }
+    cmd.arg("--socket")
+        .arg(user_dir.join("shpool.socket"))
+        .arg("daemon")
+        .current_dir(&user.dir)
+        .stdin(process::Stdio::null())
+        .env_clear()
+        .env("HOME", &user.dir)
+        .env("USER", &user.name)
+        .env("LOGNAME", &user.name)
+        .env("SHELL", &user.shell)
+        .env("PATH", DEFAULT_PATH)
+        .env(HANDOFF_FD_VAR, HANDOFF_FD.to_string());
+    // Like a login would, point the worker at the user's runtime dir
+    // if they have one, the worker keeps its state there.
+    let xdg_runtime_dir = PathBuf::from(format!("/run/user/{}", uid));
+    if xdg_runtime_dir.is_dir() {
+        cmd.env("XDG_RUNTIME_DIR", xdg_runtime_dir);
+    }
+
+    // Safety: this runs in the child between fork and exec, and only
+    //         makes syscalls, without allocating. The groups have to be
+    //         set up while we are still root, which std would not do in
+    //         time.
+    unsafe {
+        cmd.pre_exec(move || {
+            if worker_fd == HANDOFF_FD {
+                nix::fcntl::fcntl(
+                    worker_fd,
+                    nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::empty()),
+                )?;
+            } else {
+                unistd::dup2(worker_fd, HANDOFF_FD)?;
+            }
+            unistd::setgroups(&groups)?;
+            unistd::setgid(gid)?;
+            unistd::setuid(user_uid)?;
+            Ok(())
+        });
+    }
+
+    let child = cmd.spawn().context("spawning worker daemon")?;
+    info!("started worker for {} (uid={}, pid={})", user.name, uid, child.id());
+    Ok(Worker { child, handoff })
+}
+
+/// The socket to take handed off connections from, if this daemon is
+/// a worker for the multi-user daemon.
+pub fn handoff_socket() -> anyhow::Result<Option<UnixStream>> {
+    let fd = match env::var(HANDOFF_FD_VAR) {
+        Ok(fd) => fd.parse::<RawFd>().context("parsing handoff fd")?,
+        Err(_) => return Ok(None),
+    };
+    // keep the shells we spawn from seeing it
+    env::remove_var(HANDOFF_FD_VAR);
+    // Safety: the multi-user daemon put the handoff socket at this fd
+    //         for us, and nothing else in this process uses it.
+    let handoff = unsafe { UnixStream::from_raw_fd(fd) };
+    nix::fcntl::fcntl(
+        handoff.as_raw_fd(),
+        nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC),
+    )
+    .context("marking handoff socket close on exec")?;
+    Ok(Some(handoff))
+}
+
+/// Pass a connection to a worker, riding along on a single byte.
+fn send_conn(handoff: &UnixStream, conn: &UnixStream) -> nix::Result<()> {
+    let fds = [conn.as_raw_fd()];
+    let iov = [IoSlice::new(b"c")];
+    let cmsgs = [ControlMessage::ScmRights(&fds)];
+    socket::sendmsg::<()>(handoff.as_raw_fd(), &iov, &cmsgs, MsgFlags::empty(), None)?;
+    Ok(())
+}
+
+/// Pass a connection to a worker, giving a worker that is behind on
+/// taking them a moment to catch up before giving up with EAGAIN.
+fn send_conn_retrying(handoff: &UnixStream, conn: &UnixStream) -> nix::Result<()> {
+    match send_conn(handoff, conn) {
+        Err(Errno::EAGAIN) => {}
+        res => return res,
+    }
+    let mut poll_fds = [poll::PollFd::new(handoff.as_fd(), poll::PollFlags::POLLOUT)];
+    match poll::poll(&mut poll_fds, HANDOFF_RETRY_MS) {
+        Ok(0) => Err(Errno::EAGAIN),
+        Ok(_) | Err(Errno::EINTR) => send_conn(handoff, conn),
+        Err(err) => Err(err),
+    }
+}
+
+/// Wait for the next connection to get handed off. Returns None once
+/// the multi-user daemon has gone away.
+pub fn recv_conn(handoff: &UnixStream) -> anyhow::Result<Option<UnixStream>> {
+    let mut buf = [0; 1];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg_buf = nix::cmsg_space!([RawFd; 1]);
+    let msg = socket::recvmsg::<()>(
+        handoff.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buf),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )
+    .context("receiving connection")?;
+    if msg.bytes == 0 {
+        return Ok(None);
+    }
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(fd) = fds.first() {
+                // Safety: the kernel just gave us this fd, so nothing
+                //         else owns it.
+                let fd = unsafe { OwnedFd::from_raw_fd(*fd) };
+                return Ok(Some(UnixStream::from(fd)));
+            }
+        }
+    }
+    Err(anyhow!("handoff message without a connection"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+    use std::io::{Read, Write};
+
+    #[test]
+    #[timeout(30000)]
+    fn handoff_round_trip() -> anyhow::Result<()> {
+        let (daemon_end, worker_end) = UnixStream::pair()?;
+        let (mut client, conn) = UnixStream::pair()?;
+
+        send_conn(&daemon_end, &conn)?;
+        drop(conn);
+        let mut handed_off = recv_conn(&worker_end)?.expect("a connection");
+
+        client.write_all(b"hello")?;
+        let mut buf = [0; 5];
+        handed_off.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"hello");
+
+        drop(daemon_end);
+        assert!(recv_conn(&worker_end)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn handoff_backed_up() -> anyhow::Result<()> {
+        let (daemon_end, _worker_end) = UnixStream::pair()?;
+        daemon_end.set_nonblocking(true)?;
+        let (_client, conn) = UnixStream::pair()?;
+
+        // with nobody taking them, the connections pile up until the
+        // send fails rather than blocks
+        loop {
+            match send_conn(&daemon_end, &conn) {
+                Ok(()) => continue,
+                Err(Errno::EAGAIN) => return Ok(()),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    #[test]
+    #[timeout(30000)]
+    fn backed_up_worker_survives() -> anyhow::Result<()> {
+        let (handoff, _worker_end) = UnixStream::pair()?;
+        handoff.set_nonblocking(true)?;
+        let (_client, conn) = UnixStream::pair()?;
+        while send_conn(&handoff, &conn).is_ok() {}
+        let child = process::Command::new("sleep").arg("1000").spawn()?;
+        let uid = unistd::getuid().as_raw();
+        let mut workers = HashMap::from([(uid, Worker { child, handoff })]);
+
+        let (_client, conn) = UnixStream::pair()?;
+        let res = dispatch(&mut workers, Path::new("/nonexistent"), conn, 0);
+        assert!(res.is_err(), "handing off to a backed up worker should fail");
+
+        // the connection got turned away, but the worker is still there
+        let worker = workers.get_mut(&uid).expect("the worker to be kept");
+        assert!(worker.child.try_wait()?.is_none(), "worker was stopped");
+        worker.child.kill()?;
+        worker.child.wait()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Support for starting a session inside the namespaces of some other
+  process, usually the init process of a container, so that the shell
+  lives in the container rather than on the host.
+
+  This works like nsenter(1). The daemon figures out which of the
+  target's namespaces differ from its own, and the child enters them
+  one after another between fork and exec. A process can't move itself
+  into another pid namespace, so the shell itself stays in the
+  daemon's, but everything it runs lands in the target's.
+*/
+
+use std::{
+    ffi::{CStr, CString},
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context};
+
+/// The kinds of namespace to enter, in the order that nsenter(1)
+/// enters them. The user namespace comes first so that the others can
+/// be entered with the capabilities it grants.
+const NAMESPACES: [(&str, libc::c_int); 7] = [
+    ("user", libc::CLONE_NEWUSER),
+    ("cgroup", libc::CLONE_NEWCGROUP),
+    ("ipc", libc::CLONE_NEWIPC),
+    ("uts", libc::CLONE_NEWUTS),
+    ("net", libc::CLONE_NEWNET),
+    ("pid", libc::CLONE_NEWPID),
+    ("mnt", libc::CLONE_NEWNS),
+];
+
+/// The namespaces of a target process that differ from the daemon's.
+#[derive(Debug)]
+pub struct Target {
+    pid: libc::pid_t,
+    namespaces: Vec<(CString, libc::c_int)>,
+}
+
+impl Target {
+    /// Look up the namespaces of the given process. This fails if the
+    /// process is gone or we are not allowed to look at it, which is
+    /// much easier to report here than once the child is running.
+    pub fn new(pid: libc::pid_t) -> anyhow::Result<Self> {
+        if pid <= 0 {
+            bail!("bad pid {}", pid);
+        }
+
+        let mut namespaces = vec![];
+        for (name, nstype) in NAMESPACES.iter() {
+            let ours = match fs::metadata(format!("/proc/self/ns/{}", name)) {
+                Ok(meta) => meta,
+                // the kernel doesn't have this kind of namespace
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("reading our {} namespace", name)),
+            };
+            let path = format!("/proc/{}/ns/{}", pid, name);
+            let theirs = fs::metadata(&path).with_context(|| format!("reading {}", path))?;
+            // entering a namespace we are already in is an error for
+            // some kinds, and pointless for the rest
+            if (ours.dev(), ours.ino()) != (theirs.dev(), theirs.ino()) {
+                namespaces.push((CString::new(path).context("encoding ns path")?, *nstype));
+            }
+        }
+
+        Ok(Target { pid, namespaces })
+    }
+
+    /// The target's root dir, as seen from the daemon.
+    pub fn root(&self) -> PathBuf {
+        PathBuf::from(format!("/proc/{}/root", self.pid))
+    }
+
+    /// Enter the target's namespaces, then change into the given dir,
+    /// or into / if it does not exist in the target's mount namespace.
+    /// This runs between fork and exec, so it sticks to raw syscalls.
+    pub fn enter(&self, cwd: &CStr) -> io::Result<()> {
+        // Open everything before entering anything, since the target's
+        // /proc entries may not be reachable from inside its namespaces.
+        let mut fds = [-1; NAMESPACES.len()];
+        for (fd, (path, _)) in fds.iter_mut().zip(self.namespaces.iter()) {
+            // Safety: path is a valid nul terminated string
+            *fd = unsafe { libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC) };
+            if *fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        for (fd, (_, nstype)) in fds.iter().zip(self.namespaces.iter()) {
+            // Safety: fd is a namespace file we just opened
+            if unsafe { libc::setns(*fd, *nstype) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Safety: nothing else uses the fd
+            unsafe { libc::close(*fd) };
+        }
+
+        // Safety: both paths are valid nul terminated strings
+        if unsafe { libc::chdir(cwd.as_ptr()) } < 0
+            && unsafe { libc::chdir(b"/\0".as_ptr() as *const libc::c_char) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn targets() -> anyhow::Result<()> {
+        // we are already in all of our own namespaces
+        let us = Target::new(std::process::id() as libc::pid_t)?;
+        assert!(us.namespaces.is_empty());
+        assert_eq!(us.root(), PathBuf::from(format!("/proc/{}/root", std::process::id())));
+
+        assert!(Target::new(0).is_err());
+        assert!(Target::new(-1).is_err());
+        // bigger than the biggest pid_max the kernel allows
+        assert!(Target::new(i32::MAX).is_err());
+
+        Ok(())
+    }
+}
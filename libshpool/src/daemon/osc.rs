@@ -0,0 +1,832 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Handling for OSC (operating system command) escape sequences
+  in the output stream of a shell.
+
+  The scanner picks the OSC 9 (ConEmu / iTerm2) and OSC 777 (urxvt)
+  progress and notification sequences, as well as plain BEL characters,
+  out of the stream. When a client
+  is attached these sequences flow through to the client terminal
+  untouched along with the rest of the output, but the output spool
+  has no notion of them, so the reader thread uses the scanner to hold
+  on to them while no client is attached.
+
+  The filter sits in between the shell and the client and rewrites or
+  drops OSC sequences according to the user's config. It holds on to
+  each sequence until it is complete, so a client only ever gets whole
+  sequences, which matters most for the OSC 52 sequences that set the
+  clipboard, since those can be large enough to span a detach. It also
+  remembers which OSC 8 hyperlink the shell has open, since the output
+  spool can't: text restored on reattach comes back without its links,
+  but the link that is still open gets opened again afterwards, so the
+  output that follows stays linked.
+*/
+
+use std::collections::VecDeque;
+
+use crate::config::ClipboardPolicy;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// The longest OSC payload we are willing to buffer. Anything
+/// longer is not something we care about, so we just drop it.
+const MAX_PAYLOAD_LEN: usize = 4096;
+
+/// The longest OSC 52 clipboard sequence the filter is willing to
+/// buffer. Copying a big file out of vim can easily go past
+/// MAX_PAYLOAD_LEN, so these get a lot more room.
+const MAX_CLIPBOARD_LEN: usize = 1024 * 1024;
+
+/// How an OSC 52 clipboard sequence starts, up to the selection
+/// parameter and then the base64 encoded text.
+const CLIPBOARD_PREFIX: &[u8] = b"\x1b]52;";
+
+/// An OSC 8 sequence with no uri, which ends the current hyperlink.
+pub const CLOSE_LINK: &[u8] = b"\x1b]8;;\x1b\\";
+
+/// The maximum number of notifications to hold on to while
+/// detached. Older notifications get dropped first.
+const MAX_PENDING_NOTIFICATIONS: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    /// An `OSC 9 ; 4 ; st ; pr` progress report. Only the most recent
+    /// progress report matters, since each one replaces the last.
+    Progress { raw: Vec<u8> },
+    /// An `OSC 9 ; msg` or `OSC 777 ; notify ; title ; body` desktop
+    /// notification.
+    Notify { title: Option<String>, body: String, raw: Vec<u8> },
+    /// A BEL character outside of any escape sequence.
+    Bell,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Ground,
+    Esc,
+    Payload,
+    PayloadEsc,
+}
+
+/// A byte at a time state machine for picking OSC sequences
+/// out of the shell's output stream.
+#[derive(Debug)]
+pub struct Scanner {
+    state: State,
+    payload: Vec<u8>,
+    overflowed: bool,
+}
+
+impl Scanner {
+    pub fn new() -> Self {
+        Scanner { state: State::Ground, payload: vec![], overflowed: false }
+    }
+
+    /// Pump a chunk of output through the scanner, collecting the events
+    /// it completes. Runs of plain text, which is most output, get
+    /// skipped over in bulk rather than fed through byte by byte.
+    pub fn scan(&mut self, buf: &[u8], events: &mut Vec<Event>) {
+        let mut i = 0;
+        while i < buf.len() {
+            if let State::Ground = self.state {
+                match memchr::memchr2(ESC, BEL, &buf[i..]) {
+                    Some(n) => i += n,
+                    None => return,
+                }
+            }
+            if let Some(event) = self.transition(buf[i]) {
+                events.push(event);
+            }
+            i += 1;
+        }
+    }
+
+    /// Pump the given byte through the scanner, returning an event
+    /// if the byte completed a sequence we care about.
+    pub fn transition(&mut self, byte: u8) -> Option<Event> {
+        match (self.state, byte) {
+            (State::Ground, ESC) => self.state = State::Esc,
+            (State::Ground, BEL) => return Some(Event::Bell),
+            (State::Ground, _) => {}
+            (State::Esc, b']') => {
+                self.payload.clear();
+                self.overflowed = false;
+                self.state = State::Payload;
+            }
+            (State::Esc, ESC) => {}
+            (State::Esc, _) => self.state = State::Ground,
+            (State::Payload, BEL) => {
+                self.state = State::Ground;
+                return self.finish();
+            }
+            (State::Payload, ESC) => self.state = State::PayloadEsc,
+            (State::Payload, _) => self.push(byte),
+            (State::PayloadEsc, b'\\') => {
+                self.state = State::Ground;
+                return self.finish();
+            }
+            (State::PayloadEsc, b']') => {
+                // An unterminated OSC followed directly by a new one.
+                self.payload.clear();
+                self.overflowed = false;
+                self.state = State::Payload;
+            }
+            (State::PayloadEsc, _) => self.state = State::Ground,
+        }
+
+        None
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.payload.len() < MAX_PAYLOAD_LEN {
+            self.payload.push(byte);
+        } else {
+            self.overflowed = true;
+        }
+    }
+
+    fn finish(&mut self) -> Option<Event> {
+        if self.overflowed {
+            return None;
+        }
+        let payload = String::from_utf8_lossy(&self.payload);
+        let mut raw = vec![ESC, b']'];
+        raw.extend_from_slice(&self.payload);
+        raw.extend_from_slice(&[ESC, b'\\']);
+
+        if let Some(rest) = payload.strip_prefix("9;") {
+            if rest.starts_with("4;") {
+                Some(Event::Progress { raw })
+            } else if rest.is_empty() || rest.as_bytes()[0].is_ascii_digit() {
+                // Other numeric OSC 9 subcommands are ConEmu specific
+                // and don't make sense to replay.
+                None
+            } else {
+                Some(Event::Notify { title: None, body: String::from(rest), raw })
+            }
+        } else if let Some(rest) = payload.strip_prefix("777;notify;") {
+            let (title, body) = rest.split_once(';').unwrap_or((rest, ""));
+            Some(Event::Notify { title: Some(String::from(title)), body: String::from(body), raw })
+        } else {
+            None
+        }
+    }
+}
+
+/// The sequences seen while no client was attached, which
+/// should get replayed once a client reattaches.
+#[derive(Debug, Default)]
+pub struct Pending {
+    progress: Option<Vec<u8>>,
+    notifications: VecDeque<Vec<u8>>,
+    /// Any number of bells get replayed as a single one.
+    bell: bool,
+}
+
+impl Pending {
+    pub fn push(&mut self, event: &Event) {
+        match event {
+            Event::Progress { raw } => self.progress = Some(raw.clone()),
+            Event::Notify { raw, .. } => {
+                if self.notifications.len() >= MAX_PENDING_NOTIFICATIONS {
+                    self.notifications.pop_front();
+                }
+                self.notifications.push_back(raw.clone());
+            }
+            Event::Bell => self.bell = true,
+        }
+    }
+
+    /// Drain the pending sequences into a single buffer suitable
+    /// for writing to a freshly attached client.
+    pub fn take(&mut self) -> Vec<u8> {
+        let mut buf = vec![];
+        for n in self.notifications.drain(..) {
+            buf.extend(n);
+        }
+        if let Some(p) = self.progress.take() {
+            buf.extend(p);
+        }
+        if self.bell {
+            self.bell = false;
+            buf.push(BEL);
+        }
+        buf
+    }
+}
+
+/// Controls what the output filter does with the OSC sequences
+/// it sees.
+#[derive(Debug, Default, Clone)]
+pub struct FilterPolicy {
+    /// Drop OSC 8 hyperlinks, leaving just the link text.
+    pub strip_hyperlinks: bool,
+    /// A string to append to the window title set by OSC 0 and OSC 2.
+    pub title_suffix: Option<String>,
+    /// What to do with OSC 52 clipboard sequences. When unset, they
+    /// are treated like any other sequence.
+    pub clipboard: Option<ClipboardPolicy>,
+}
+
+impl FilterPolicy {
+    /// Returns true if the filter would pass everything through
+    /// untouched, in which case there is no need to run it at all.
+    pub fn is_noop(&self) -> bool {
+        !self.strip_hyperlinks && self.title_suffix.is_none() && self.clipboard.is_none()
+    }
+
+    fn apply(&self, payload: &[u8]) -> FilterAction {
+        if self.strip_hyperlinks && payload.starts_with(b"8;") {
+            return FilterAction::Drop;
+        }
+        if let Some(suffix) = &self.title_suffix {
+            if payload.starts_with(b"0;") || payload.starts_with(b"2;") {
+                let mut new_payload = payload.to_vec();
+                new_payload.extend_from_slice(suffix.as_bytes());
+                return FilterAction::Replace(new_payload);
+            }
+        }
+
+        FilterAction::Keep
+    }
+
+    /// Returns true once enough of the payload has come in to tell that
+    /// the policy leaves the sequence alone.
+    fn ignores(&self, payload: &[u8]) -> bool {
+        let Some(i) = memchr::memchr(b';', payload) else {
+            return false;
+        };
+        match &payload[..i] {
+            // hyperlinks always get held, so the filter knows which one
+            // is open
+            b"8" => false,
+            b"0" | b"2" => self.title_suffix.is_none(),
+            b"52" => false,
+            _ => true,
+        }
+    }
+}
+
+enum FilterAction {
+    Keep,
+    Drop,
+    Replace(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FilterState {
+    Ground,
+    Esc,
+    Payload,
+    PayloadEsc,
+    /// An OSC sequence which was too long to buffer, or which the
+    /// policy leaves alone, which we are just passing through until it
+    /// terminates.
+    Passthrough,
+    PassthroughEsc,
+    /// An OSC sequence which is getting dropped, or whose start went
+    /// out to an earlier client, which we are swallowing until it
+    /// terminates.
+    Discard,
+    DiscardEsc,
+}
+
+/// A streaming filter which rewrites the OSC sequences in a shell's
+/// output according to a policy. Sequences may be split across chunks,
+/// so the filter holds on to any partial sequence until the next call.
+/// It also keeps track of the hyperlink that is open, if any, so that
+/// it can be put back after a redraw.
+#[derive(Debug)]
+pub struct Filter {
+    state: FilterState,
+    /// The bytes of the sequence we are currently buffering, starting
+    /// with the ESC.
+    held: Vec<u8>,
+    /// The OSC 8 sequence that opened the current hyperlink, as it was
+    /// passed on to the client.
+    open_link: Option<Vec<u8>>,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Filter { state: FilterState::Ground, held: vec![], open_link: None }
+    }
+
+    /// The sequence that opens the hyperlink the shell is in the middle
+    /// of, if it is in one.
+    pub fn open_link(&self) -> Option<&[u8]> {
+        self.open_link.as_deref()
+    }
+
+    /// Wrap a redraw of the screen so that the hyperlink that is open
+    /// doesn't take in the redrawn text, which the spool has no links
+    /// for, but is open again for the output that comes after it.
+    pub fn relink(&self, redraw: Vec<u8>) -> Vec<u8> {
+        match &self.open_link {
+            Some(link) => [CLOSE_LINK, &redraw, link].concat(),
+            None => redraw,
+        }
+    }
+
+    /// Run the given chunk of output through the filter, appending
+    /// the result to `out`.
+    pub fn process(&mut self, policy: &FilterPolicy, input: &[u8], out: &mut Vec<u8>) {
+        let mut i = 0;
+        while i < input.len() {
+            if let FilterState::Ground = self.state {
+                // plain text passes straight through, so copy it over in
+                // bulk up to the next thing that might start a sequence
+                let n = memchr::memchr(ESC, &input[i..]).unwrap_or(input.len() - i);
+                out.extend_from_slice(&input[i..i + n]);
+                i += n;
+                if i == input.len() {
+                    break;
+                }
+            }
+            let byte = input[i];
+            i += 1;
+            match (self.state, byte) {
+                (FilterState::Ground, ESC) => {
+                    self.held.push(byte);
+                    self.state = FilterState::Esc;
+                }
+                (FilterState::Ground, _) => out.push(byte),
+                (FilterState::Esc, b']') => {
+                    self.held.push(byte);
+                    self.state = FilterState::Payload;
+                }
+                (FilterState::Esc, _) => {
+                    out.append(&mut self.held);
+                    out.push(byte);
+                    self.state = FilterState::Ground;
+                }
+                (FilterState::Payload, BEL) => {
+                    self.held.push(byte);
+                    self.finish(policy, 1, out);
+                }
+                (FilterState::Payload, ESC) => {
+                    self.held.push(byte);
+                    self.state = FilterState::PayloadEsc;
+                }
+                (FilterState::Payload, _) => {
+                    self.held.push(byte);
+                    if self.held.starts_with(CLIPBOARD_PREFIX) {
+                        self.police_clipboard(policy, out);
+                    } else if (byte == b';' && policy.ignores(&self.held[2..]))
+                        || self.held.len() > MAX_PAYLOAD_LEN
+                    {
+                        // Either the policy has no interest in it or it is too
+                        // long to buffer, so let it out as it comes rather than
+                        // holding back everything after an unterminated one.
+                        out.append(&mut self.held);
+                        self.state = FilterState::Passthrough;
+                    }
+                }
+                (FilterState::PayloadEsc, b'\\') => {
+                    self.held.push(byte);
+                    self.finish(policy, 2, out);
+                }
+                (FilterState::PayloadEsc, _) => {
+                    // Not a valid string terminator, so this was not a well
+                    // formed OSC sequence. Let the terminal deal with it.
+                    out.append(&mut self.held);
+                    out.push(byte);
+                    self.state = FilterState::Ground;
+                }
+                (FilterState::Passthrough, BEL) => {
+                    out.push(byte);
+                    self.state = FilterState::Ground;
+                }
+                (FilterState::Passthrough, ESC) => {
+                    out.push(byte);
+                    self.state = FilterState::PassthroughEsc;
+                }
+                (FilterState::Passthrough, _) => out.push(byte),
+                (FilterState::PassthroughEsc, _) => {
+                    out.push(byte);
+                    self.state = FilterState::Ground;
+                }
+                (FilterState::Discard, BEL) => self.state = FilterState::Ground,
+                (FilterState::Discard, ESC) => self.state = FilterState::DiscardEsc,
+                (FilterState::Discard, _) => {}
+                (FilterState::DiscardEsc, _) => self.state = FilterState::Ground,
+            }
+        }
+    }
+
+    /// Apply the clipboard policy to the OSC 52 sequence being held,
+    /// which just got a new byte. Sequences that are getting dropped or
+    /// cut short are dealt with as soon as possible rather than once
+    /// they are complete, so that they don't need to be held in full.
+    fn police_clipboard(&mut self, policy: &FilterPolicy, out: &mut Vec<u8>) {
+        let limit = match policy.clipboard {
+            None => MAX_PAYLOAD_LEN,
+            Some(ClipboardPolicy::Allow) => MAX_CLIPBOARD_LEN,
+            Some(ClipboardPolicy::Deny) => {
+                self.held.clear();
+                self.state = FilterState::Discard;
+                return;
+            }
+            Some(ClipboardPolicy::Truncate(max_bytes)) => {
+                // The text comes after the selection parameter, base64
+                // encoded. Cutting it at a multiple of 4 characters
+                // keeps it decodable.
+                let params = &self.held[CLIPBOARD_PREFIX.len()..];
+                if let Some(i) = params.iter().position(|b| *b == b';') {
+                    let cut = CLIPBOARD_PREFIX.len() + i + 1 + max_bytes / 3 * 4;
+                    if self.held.len() > cut {
+                        out.extend_from_slice(&self.held[..cut]);
+                        out.extend_from_slice(&[ESC, b'\\']);
+                        self.held.clear();
+                        self.state = FilterState::Discard;
+                        return;
+                    }
+                }
+                MAX_CLIPBOARD_LEN
+            }
+        };
+
+        if self.held.len() > limit {
+            if let None | Some(ClipboardPolicy::Allow) = policy.clipboard {
+                out.append(&mut self.held);
+                self.state = FilterState::Passthrough;
+            } else {
+                self.held.clear();
+                self.state = FilterState::Discard;
+            }
+        }
+    }
+
+    /// Get ready to filter output for a new client. A sequence that is
+    /// still being held never went out to the old client, so the new
+    /// one gets it once it is complete, but the rest of a sequence that
+    /// was passed through to the old client would just be garbage.
+    pub fn reattach(&mut self) {
+        self.state = match self.state {
+            FilterState::Passthrough => FilterState::Discard,
+            FilterState::PassthroughEsc => FilterState::DiscardEsc,
+            state => state,
+        };
+    }
+
+    /// Filter a chunk of output, using `scratch` as the backing storage
+    /// for the result if needed. If the policy is a noop this just hands
+    /// back the input, after releasing any partial sequence held over
+    /// from when the policy was active.
+    pub fn filter<'a>(
+        &mut self,
+        policy: &FilterPolicy,
+        input: &'a [u8],
+        scratch: &'a mut Vec<u8>,
+    ) -> &'a [u8] {
+        scratch.clear();
+        if policy.is_noop() {
+            if self.held.is_empty() {
+                return input;
+            }
+            scratch.append(&mut self.held);
+            scratch.extend_from_slice(input);
+            self.state = FilterState::Ground;
+        } else {
+            self.process(policy, input, scratch);
+        }
+        scratch
+    }
+
+    /// Handle a complete sequence, which is held along with its
+    /// terminator of length `term_len`.
+    fn finish(&mut self, policy: &FilterPolicy, term_len: usize, out: &mut Vec<u8>) {
+        let payload_end = self.held.len() - term_len;
+        let payload = &self.held[2..payload_end];
+        let action = policy.apply(payload);
+        if let Some(params) = payload.strip_prefix(b"8;") {
+            // the uri comes after the params, and an empty one ends
+            // the link
+            let opens =
+                params.iter().position(|b| *b == b';').is_some_and(|i| i + 1 < params.len());
+            self.open_link = match action {
+                FilterAction::Keep if opens => Some(self.held.clone()),
+                _ => None,
+            };
+        }
+        match action {
+            FilterAction::Keep => out.extend_from_slice(&self.held),
+            FilterAction::Drop => {}
+            FilterAction::Replace(new_payload) => {
+                out.extend_from_slice(&self.held[..2]);
+                out.extend_from_slice(&new_payload);
+                out.extend_from_slice(&self.held[payload_end..]);
+            }
+        }
+        self.held.clear();
+        self.state = FilterState::Ground;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    fn scan(input: &[u8]) -> Vec<Event> {
+        let mut scanner = Scanner::new();
+        let mut events = vec![];
+        scanner.scan(input, &mut events);
+
+        // skipping over plain text must not change anything
+        let mut scanner = Scanner::new();
+        let byte_at_a_time: Vec<Event> =
+            input.iter().filter_map(|b| scanner.transition(*b)).collect();
+        assert_eq!(events, byte_at_a_time);
+
+        events
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn events() {
+        let cases: Vec<(&[u8], Vec<Event>)> = vec![
+            (b"plain text", vec![]),
+            (
+                b"a\x1b]9;4;1;50\x07b",
+                vec![Event::Progress { raw: b"\x1b]9;4;1;50\x1b\\".to_vec() }],
+            ),
+            (
+                b"\x1b]9;build done\x1b\\",
+                vec![Event::Notify {
+                    title: None,
+                    body: String::from("build done"),
+                    raw: b"\x1b]9;build done\x1b\\".to_vec(),
+                }],
+            ),
+            (
+                b"\x1b]777;notify;make;finished\x07",
+                vec![Event::Notify {
+                    title: Some(String::from("make")),
+                    body: String::from("finished"),
+                    raw: b"\x1b]777;notify;make;finished\x1b\\".to_vec(),
+                }],
+            ),
+            (b"\x1b]0;window title\x07", vec![]),
+            (b"ding\x07 \x1b\x07\x07", vec![Event::Bell, Event::Bell]),
+            (b"\x1b]9;1;100\x07", vec![]),
+            (
+                b"\x1b[1m\x1b]9;4;0;\x07",
+                vec![Event::Progress { raw: b"\x1b]9;4;0;\x1b\\".to_vec() }],
+            ),
+        ];
+
+        for (input, want) in cases.into_iter() {
+            assert_eq!(scan(input), want, "input={:?}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn pending() {
+        let mut pending = Pending::default();
+        for event in scan(b"\x1b]9;4;1;10\x07\x07\x1b]9;hi\x07\x07\x1b]9;4;1;90\x07") {
+            pending.push(&event);
+        }
+        assert_eq!(pending.take(), b"\x1b]9;hi\x1b\\\x1b]9;4;1;90\x1b\\\x07".to_vec());
+        assert!(pending.take().is_empty());
+
+        for i in 0..(MAX_PENDING_NOTIFICATIONS + 4) {
+            pending.push(&Event::Notify {
+                title: None,
+                body: String::new(),
+                raw: format!("{}", i).into_bytes(),
+            });
+        }
+        assert_eq!(pending.notifications.len(), MAX_PENDING_NOTIFICATIONS);
+        assert_eq!(pending.notifications[0], b"4".to_vec());
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn filter() {
+        let strip = FilterPolicy { strip_hyperlinks: true, ..Default::default() };
+        let cases: Vec<(Vec<&[u8]>, &[u8])> = vec![
+            (vec![b"plain \x1b[1mtext"], b"plain \x1b[1mtext"),
+            (vec![b"see \x1b]8;;https://example.com\x1b\\here\x1b]8;;\x1b\\ ok"], b"see here ok"),
+            (
+                vec![b"see \x1b]8;;https://exa", b"mple.com\x07here\x1b", b"]8;;\x07 ok"],
+                b"see here ok",
+            ),
+            (vec![b"\x1b]0;title\x07"], b"\x1b]0;title\x07"),
+            (vec![b"\x1b]0;garb", b"age\r\n$ "], b"\x1b]0;garbage\r\n$ "),
+        ];
+
+        for (chunks, want) in cases.into_iter() {
+            let mut filter = Filter::new();
+            let mut out = vec![];
+            for chunk in chunks.iter() {
+                filter.process(&strip, chunk, &mut out);
+            }
+            assert_eq!(
+                String::from_utf8_lossy(&out),
+                String::from_utf8_lossy(want),
+                "chunks={:?}",
+                chunks
+            );
+        }
+
+        // with a noop policy, everything gets passed through, including
+        // anything held over from when the policy was active
+        let mut filter = Filter::new();
+        let mut scratch = vec![];
+        assert_eq!(filter.filter(&strip, b"a\x1b]8;;https://e", &mut scratch), b"a");
+        assert_eq!(
+            filter.filter(&FilterPolicy::default(), b"xample.com\x07b", &mut scratch),
+            b"\x1b]8;;https://example.com\x07b"
+        );
+        assert_eq!(filter.filter(&FilterPolicy::default(), b"c", &mut scratch), b"c");
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn filter_title() {
+        let policy =
+            FilterPolicy { title_suffix: Some(String::from(" [sh1]")), ..Default::default() };
+        let cases: Vec<(&[u8], &[u8])> = vec![
+            (b"\x1b]0;vim\x07", b"\x1b]0;vim [sh1]\x07"),
+            (b"a\x1b]2;make\x1b\\b", b"a\x1b]2;make [sh1]\x1b\\b"),
+            (b"\x1b]1;icon\x07", b"\x1b]1;icon\x07"),
+            (b"\x1b]8;;https://example.com\x07", b"\x1b]8;;https://example.com\x07"),
+        ];
+
+        for (input, want) in cases.into_iter() {
+            let mut filter = Filter::new();
+            let mut out = vec![];
+            filter.process(&policy, input, &mut out);
+            assert_eq!(String::from_utf8_lossy(&out), String::from_utf8_lossy(want));
+        }
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn filter_overlong() {
+        let policy = FilterPolicy { strip_hyperlinks: true, ..Default::default() };
+        let mut input = b"\x1b]8;;".to_vec();
+        input.extend(vec![b'a'; MAX_PAYLOAD_LEN + 10]);
+        input.extend(b"\x07after");
+
+        let mut filter = Filter::new();
+        let mut out = vec![];
+        filter.process(&policy, &input, &mut out);
+        assert_eq!(out, input);
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn filter_clipboard() {
+        let mut big = b"\x1b]52;c;".to_vec();
+        big.extend(vec![b'A'; MAX_PAYLOAD_LEN * 2]);
+        big.push(BEL);
+
+        let run = |clipboard: ClipboardPolicy, chunks: &[&[u8]]| -> Vec<u8> {
+            let policy = FilterPolicy { clipboard: Some(clipboard), ..Default::default() };
+            let mut filter = Filter::new();
+            let mut out = vec![];
+            for chunk in chunks.iter() {
+                filter.process(&policy, chunk, &mut out);
+            }
+            out
+        };
+
+        // a long sequence only goes out once it is complete
+        let (first, rest) = big.split_at(MAX_PAYLOAD_LEN + 10);
+        let policy = FilterPolicy { clipboard: Some(ClipboardPolicy::Allow), ..Default::default() };
+        let mut filter = Filter::new();
+        let mut out = vec![];
+        filter.process(&policy, first, &mut out);
+        assert!(out.is_empty());
+        filter.process(&policy, rest, &mut out);
+        assert_eq!(out, big);
+
+        assert_eq!(run(ClipboardPolicy::Deny, &[b"a\x1b]52;c;aGk=", b"\x1b\\b"]), b"ab");
+        assert_eq!(run(ClipboardPolicy::Deny, &[b"a", &big, b"b"]), b"ab");
+        assert_eq!(
+            run(ClipboardPolicy::Deny, &[b"\x1b]0;title\x07"]),
+            b"\x1b]0;title\x07".to_vec()
+        );
+
+        // "hello world", cut down to "hello "
+        assert_eq!(
+            run(ClipboardPolicy::Truncate(8), &[b"\x1b]52;c;aGVsbG8gd2", b"9ybGQ=\x07after"]),
+            b"\x1b]52;c;aGVsbG8g\x1b\\after".to_vec()
+        );
+        assert_eq!(
+            run(ClipboardPolicy::Truncate(100), &[b"\x1b]52;c;aGk=\x07"]),
+            b"\x1b]52;c;aGk=\x07".to_vec()
+        );
+        let out = run(ClipboardPolicy::Truncate(MAX_CLIPBOARD_LEN * 2), &[&big]);
+        assert_eq!(out, big);
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn filter_reattach() {
+        // a held sequence goes out whole to the new client
+        let policy = FilterPolicy { clipboard: Some(ClipboardPolicy::Allow), ..Default::default() };
+        let mut filter = Filter::new();
+        let mut out = vec![];
+        filter.process(&policy, b"old\x1b]52;c;aG", &mut out);
+        assert_eq!(out, b"old");
+        out.clear();
+        filter.reattach();
+        filter.process(&policy, b"k=\x07new", &mut out);
+        assert_eq!(out, b"\x1b]52;c;aGk=\x07new");
+
+        // but the tail of one that was passed through is dropped
+        let policy = FilterPolicy { strip_hyperlinks: true, ..Default::default() };
+        let mut input = b"\x1b]8;;".to_vec();
+        input.extend(vec![b'a'; MAX_PAYLOAD_LEN + 10]);
+        let mut filter = Filter::new();
+        let mut out = vec![];
+        filter.process(&policy, &input, &mut out);
+        assert_eq!(out, input);
+        out.clear();
+        filter.reattach();
+        filter.process(&policy, b"aaa\x1b\\new", &mut out);
+        assert_eq!(out, b"new");
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn filter_open_link() {
+        let policy = FilterPolicy { clipboard: Some(ClipboardPolicy::Allow), ..Default::default() };
+        let mut filter = Filter::new();
+        let mut out = vec![];
+        filter.process(&policy, b"a \x1b]8;id=1;https://exa", &mut out);
+        assert_eq!(filter.open_link(), None);
+        filter.process(&policy, b"mple.com\x07link", &mut out);
+        assert_eq!(filter.open_link(), Some(&b"\x1b]8;id=1;https://example.com\x07"[..]));
+        assert_eq!(
+            filter.relink(b"redraw".to_vec()),
+            b"\x1b]8;;\x1b\\redraw\x1b]8;id=1;https://example.com\x07"
+        );
+
+        filter.process(&policy, b"\x1b]8;;\x1b\\ done", &mut out);
+        assert_eq!(filter.open_link(), None);
+        assert_eq!(filter.relink(b"redraw".to_vec()), b"redraw");
+        assert_eq!(out, b"a \x1b]8;id=1;https://example.com\x07link\x1b]8;;\x1b\\ done");
+
+        // a link that gets stripped never makes it to the client, so
+        // there is nothing to open again
+        let strip = FilterPolicy { strip_hyperlinks: true, ..Default::default() };
+        let mut filter = Filter::new();
+        filter.process(&strip, b"\x1b]8;;https://example.com\x07link", &mut out);
+        assert_eq!(filter.open_link(), None);
+    }
+
+    // A rough measure of how much the per-chunk work on the output path
+    // costs. Run it with
+    // `cargo test --release -p libshpool output_throughput -- --ignored
+    // --nocapture`.
+    #[test]
+    #[ignore]
+    fn output_throughput() {
+        let mut chunk = vec![];
+        while chunk.len() < 16 * 1024 {
+            chunk.extend_from_slice(
+                b"drwxr-xr-x 2 user user 4096 Jan  1 00:00 \x1b[01;34msrc\x1b[0m\r\n",
+            );
+        }
+        chunk.extend_from_slice(b"\x1b]8;;https://example.com\x07link\x1b]8;;\x07\x07");
+        let total = 256 * 1024 * 1024;
+        let policy = FilterPolicy { strip_hyperlinks: true, ..Default::default() };
+
+        let mut scanner = Scanner::new();
+        let mut events = vec![];
+        let start = std::time::Instant::now();
+        for _ in 0..(total / chunk.len()) {
+            events.clear();
+            scanner.scan(&chunk, &mut events);
+        }
+        let took = start.elapsed();
+        println!("scan: {:.0} MiB/s", total as f64 / (1024.0 * 1024.0) / took.as_secs_f64());
+
+        let mut filter = Filter::new();
+        let mut scratch = vec![];
+        let start = std::time::Instant::now();
+        for _ in 0..(total / chunk.len()) {
+            filter.filter(&policy, &chunk, &mut scratch);
+        }
+        let took = start.elapsed();
+        println!("filter: {:.0} MiB/s", total as f64 / (1024.0 * 1024.0) / took.as_secs_f64());
+    }
+}
@@ -0,0 +1,815 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! A bounded queue of output on its way to an attached client.
+
+  The reader thread pushes onto the queue and the client's attach loop
+  drains it into the client socket as the socket has room, so a client
+  that can't keep up (a slow link while someone cats a huge file) doesn't
+  hold the reader hostage on a socket write. Once `output_buffer.limit`
+  bytes of shell output are queued up, the `output_buffer.policy` decides
+  what happens to more of it: `block` stops the reader until there is
+  room again, `drop` throws the new output away and `clip` throws away
+  the oldest queued output.
+
+  Output we generate ourselves, like the session restore buffer, is
+  never dropped. When shell output does get dropped, the reader redraws
+  the client's screen once the queue drains, since the client has
+  missed part of the picture.
+
+  The attach loop does all of a client's I/O from a single thread, so it
+  never blocks on the queue or the socket. Instead the queue pokes a
+  waker socket whenever something new shows up, which the loop polls
+  along with the client socket, and the writer only writes as much as
+  the socket will take without blocking.
+
+  For high throughput sessions the writer takes everything that has
+  queued up since its last write and sends it with a single vectored
+  write, and the buffers get handed back to the queue for reuse rather
+  than being allocated fresh for every read from the pty. Splicing
+  straight from the pty to the socket isn't an option since every chunk
+  needs framing and the output spool needs to see the bytes anyway.
+
+  Something like `cat` on a big file tends to come out of the pty as a
+  flood of small reads, each of which would cost a chunk and a write on
+  both ends. Small pushes get tacked onto the last queued chunk when
+  there is room, and while output is streaming in the writer holds off
+  for up to `output_buffer.coalesce_ms` to let a trickle of small reads
+  pile up. The first output after a quiet spell, like the echo of a
+  keystroke, goes out right away so typing doesn't feel laggy.
+*/
+
+use std::{
+    collections::VecDeque,
+    io::{self, IoSlice, Read, Write},
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::net::UnixStream,
+    },
+    sync::{Arc, Condvar, Mutex},
+    time,
+};
+
+use anyhow::{anyhow, Context};
+use tracing::{info, trace};
+
+use crate::{
+    config::{self, OutputOverflowPolicy},
+    consts,
+    daemon::metrics,
+    protocol, test_hooks,
+};
+
+const DEFAULT_LIMIT: usize = 1024 * 1024;
+
+const DEFAULT_COALESCE_MS: u64 = 2;
+
+/// The most items to send with a single vectored write, which keeps us
+/// well clear of IOV_MAX. This also bounds the number of spare buffers
+/// we hang on to.
+const MAX_BATCH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Output from the shell, which is subject to the overflow policy.
+    Output,
+    /// Output of our own making, which always gets sent.
+    Data,
+    /// The little endian exit status of the shell.
+    ExitStatus,
+    /// An encoded protocol::StreamControl message.
+    Control,
+    /// An empty chunk, which lets us notice when the client is gone.
+    Heartbeat,
+}
+
+struct Item {
+    kind: Kind,
+    /// No bigger than consts::BUF_SIZE, so it fits in a single chunk.
+    buf: Vec<u8>,
+}
+
+impl Item {
+    fn chunk(&self) -> protocol::Chunk<'_> {
+        let kind = match self.kind {
+            Kind::Output | Kind::Data => protocol::ChunkKind::Data,
+            Kind::ExitStatus => protocol::ChunkKind::ExitStatus,
+            Kind::Control => protocol::ChunkKind::Control,
+            Kind::Heartbeat => protocol::ChunkKind::Heartbeat,
+        };
+        protocol::Chunk { kind, buf: &self.buf }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    items: VecDeque<Item>,
+    /// The number of bytes of shell output in items.
+    output_len: usize,
+    /// Buffers the writer is done with, ready for reuse.
+    spare: Vec<Vec<u8>>,
+    /// Set while the writer is writing out the last batch it took.
+    writing: bool,
+    /// Set when shell output got thrown away, until the reader notices.
+    lost_output: bool,
+    /// When the writer last finished writing out a batch.
+    last_write_at: Option<time::Instant>,
+    /// Set once the waker has been poked, until the writer takes
+    /// another look at the queue.
+    woken: bool,
+    closed: bool,
+}
+
+impl State {
+    fn push(&mut self, kind: Kind, buf: &[u8]) {
+        if kind == Kind::Output {
+            self.output_len += buf.len();
+        }
+        if kind == Kind::Output || kind == Kind::Data {
+            if let Some(last) = self.items.back_mut() {
+                if last.kind == kind && last.buf.len() + buf.len() <= consts::BUF_SIZE {
+                    last.buf.extend_from_slice(buf);
+                    return;
+                }
+            }
+        }
+        let mut b = self.spare.pop().unwrap_or_default();
+        b.extend_from_slice(buf);
+        self.items.push_back(Item { kind, buf: b });
+    }
+
+    /// True if everything queued up is a bit of shell output that could
+    /// stand to wait for more.
+    fn only_small_output(&self) -> bool {
+        self.output_len < consts::BUF_SIZE
+            && self.items.iter().all(|item| item.kind == Kind::Output)
+    }
+}
+
+/// What the writer found when it went to take the next batch.
+#[derive(Debug, PartialEq, Eq)]
+enum Taken {
+    /// There is a fresh batch to write.
+    Batch,
+    /// There is nothing to write right now.
+    Empty,
+    /// There is a little shell output, which should wait this much
+    /// longer for more to pile up.
+    Wait(time::Duration),
+    /// The queue is closed and drained.
+    Closed,
+}
+
+pub struct OutputQueue {
+    state: Mutex<State>,
+    cond: Condvar,
+    limit: usize,
+    policy: OutputOverflowPolicy,
+    /// How long the writer waits for small output to pile up while
+    /// output is streaming in. Zero turns that off.
+    coalesce: time::Duration,
+    /// Gets a byte whenever there is something new for the writer, so
+    /// that the attach loop can wait on the queue and the client
+    /// socket at the same time.
+    wake_tx: UnixStream,
+    wake_rx: UnixStream,
+}
+
+impl OutputQueue {
+    pub fn new(
+        limit: usize,
+        policy: OutputOverflowPolicy,
+        coalesce: time::Duration,
+    ) -> anyhow::Result<Self> {
+        let (wake_tx, wake_rx) = UnixStream::pair().context("creating waker")?;
+        wake_tx.set_nonblocking(true).context("making waker non-blocking")?;
+        wake_rx.set_nonblocking(true).context("making waker non-blocking")?;
+        Ok(OutputQueue {
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+            // a single read from the pty must always fit
+            limit: limit.max(consts::BUF_SIZE),
+            policy,
+            coalesce,
+            wake_tx,
+            wake_rx,
+        })
+    }
+
+    pub fn from_config(config: &config::Config) -> anyhow::Result<Self> {
+        let output_buffer = config.output_buffer.as_ref();
+        OutputQueue::new(
+            output_buffer.and_then(|b| b.limit).unwrap_or(DEFAULT_LIMIT),
+            output_buffer.and_then(|b| b.policy).unwrap_or_default(),
+            time::Duration::from_millis(
+                output_buffer.and_then(|b| b.coalesce_ms).unwrap_or(DEFAULT_COALESCE_MS),
+            ),
+        )
+    }
+
+    /// An fd that becomes readable when there is something new for the
+    /// writer.
+    pub fn waker(&self) -> BorrowedFd<'_> {
+        self.wake_rx.as_fd()
+    }
+
+    /// Wake up anyone waiting on the queue, including the writer.
+    fn notify(&self, state: &mut State) {
+        self.cond.notify_all();
+        if !state.woken {
+            state.woken = true;
+            // a full waker has plenty of wake ups in it already
+            let _ = (&self.wake_tx).write(&[0]);
+        }
+    }
+
+    /// Queue up a chunk of shell output, applying the overflow policy if
+    /// the queue is full. Returns false once the client is gone.
+    pub fn push_output(&self, buf: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        // an empty queue always takes the output, so that an oversized
+        // chunk can't wedge things
+        while !state.closed && state.output_len > 0 && state.output_len + buf.len() > self.limit {
+            match self.policy {
+                OutputOverflowPolicy::Block => state = self.cond.wait(state).unwrap(),
+                OutputOverflowPolicy::Drop => {
+                    trace!("output queue full, dropping {} bytes", buf.len());
+                    lose_output(&mut state, buf.len());
+                    return true;
+                }
+                OutputOverflowPolicy::Clip => {
+                    let mut excess = state.output_len + buf.len() - self.limit;
+                    let mut clipped = 0;
+                    state.items.retain(|item| {
+                        if item.kind != Kind::Output || excess == 0 {
+                            return true;
+                        }
+                        excess = excess.saturating_sub(item.buf.len());
+                        clipped += item.buf.len();
+                        false
+                    });
+                    trace!("output queue full, clipped {} bytes", clipped);
+                    state.output_len -= clipped;
+                    lose_output(&mut state, clipped);
+                }
+            }
+        }
+        if state.closed {
+            return false;
+        }
+
+        for block in buf.chunks(consts::BUF_SIZE) {
+            state.push(Kind::Output, block);
+        }
+        self.notify(&mut state);
+        true
+    }
+
+    /// Queue up output of our own making, which never gets dropped.
+    /// Returns false once the client is gone.
+    pub fn push_data(&self, buf: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        for block in buf.chunks(consts::BUF_SIZE) {
+            state.push(Kind::Data, block);
+        }
+        self.notify(&mut state);
+        true
+    }
+
+    /// Queue up the exit status of the shell, which should be the last
+    /// thing the client gets.
+    pub fn push_exit_status(&self, status: i32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        state.push(Kind::ExitStatus, &status.to_le_bytes());
+        self.notify(&mut state);
+        true
+    }
+
+    /// Queue up a control message, which gets interleaved with the
+    /// output in the order it was queued.
+    pub fn push_control(&self, msg: &protocol::StreamControl) -> anyhow::Result<bool> {
+        let buf = bincode::serialize(msg).context("encoding control message")?;
+        if buf.len() > consts::BUF_SIZE {
+            return Err(anyhow!("control message of size {} is too big", buf.len()));
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Ok(false);
+        }
+        state.push(Kind::Control, &buf);
+        self.notify(&mut state);
+        Ok(true)
+    }
+
+    /// Queue up a heartbeat. Returns false once the client is gone.
+    pub fn push_heartbeat(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        state.push(Kind::Heartbeat, &[]);
+        self.notify(&mut state);
+        true
+    }
+
+    /// Throw away all the queued shell output, returning the number of
+    /// bytes thrown away.
+    pub fn flush(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.items.retain(|item| item.kind != Kind::Output);
+        let flushed = state.output_len;
+        state.output_len = 0;
+        if flushed > 0 {
+            lose_output(&mut state, flushed);
+        }
+        self.notify(&mut state);
+        flushed
+    }
+
+    /// Returns true, once, if shell output has been thrown away since
+    /// the last call and the client has now caught up with the queue,
+    /// which means it is time to redraw the client's screen.
+    pub fn take_lost_output(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.lost_output && state.items.is_empty() && !state.writing {
+            state.lost_output = false;
+            return true;
+        }
+        false
+    }
+
+    /// Stop accepting new items. The writer still drains whatever is
+    /// already queued.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        self.notify(&mut state);
+    }
+
+    /// The client is gone, so drop everything.
+    pub fn hangup(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.items.clear();
+        state.output_len = 0;
+        state.writing = false;
+        self.notify(&mut state);
+    }
+
+    /// Wait for the writer to get everything queued so far out the
+    /// door. Returns false on timeout.
+    pub fn wait_drained(&self, timeout: time::Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (_state, res) = self
+            .cond
+            .wait_timeout_while(state, timeout, |s| !s.items.is_empty() || s.writing)
+            .unwrap();
+        !res.timed_out()
+    }
+
+    /// Swap the previous batch, which the caller should be done writing,
+    /// for the next one, if there is anything to write right now.
+    fn take_batch(&self, batch: &mut Vec<Item>) -> Taken {
+        // empty out the waker before looking at the queue, so that
+        // anything pushed after we look pokes it again
+        let mut drain = [0; 64];
+        while let Ok(n) = (&self.wake_rx).read(&mut drain) {
+            if n == 0 {
+                break;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.woken = false;
+        if !batch.is_empty() {
+            state.last_write_at = Some(time::Instant::now());
+        }
+        for mut item in batch.drain(..) {
+            if state.spare.len() < MAX_BATCH {
+                item.buf.clear();
+                state.spare.push(item.buf);
+            }
+        }
+        state.writing = false;
+        self.cond.notify_all();
+
+        if state.items.is_empty() {
+            return if state.closed { Taken::Closed } else { Taken::Empty };
+        }
+
+        // If the last batch went out just now, output is streaming in,
+        // so give a trickle of small reads a moment to pile up rather
+        // than sending each one on its own.
+        if !state.closed && state.only_small_output() {
+            let wait = state
+                .last_write_at
+                .and_then(|t| self.coalesce.checked_sub(t.elapsed()))
+                .unwrap_or_default();
+            if !wait.is_zero() {
+                return Taken::Wait(wait);
+            }
+        }
+
+        let n = state.items.len().min(MAX_BATCH);
+        batch.extend(state.items.drain(..n));
+        state.output_len -= batch
+            .iter()
+            .filter(|item| item.kind == Kind::Output)
+            .map(|item| item.buf.len())
+            .sum::<usize>();
+        state.writing = true;
+        self.cond.notify_all();
+        Taken::Batch
+    }
+}
+
+fn lose_output(state: &mut State, len: usize) {
+    state.lost_output = true;
+    metrics::inc(&metrics::METRICS.dropped_output_bytes, len as u64);
+}
+
+/// Why the writer stopped writing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flushed {
+    /// Everything queued up so far has gone out.
+    Idle,
+    /// The client socket is full, so there is more to write once it
+    /// has room.
+    Blocked,
+    /// A little shell output is waiting for more to pile up, so there
+    /// is more to write after this long.
+    Waiting(time::Duration),
+    /// The queue is closed and everything in it has gone out.
+    Closed,
+    /// A write failed, which we take to mean that the client hung up.
+    HungUp,
+}
+
+/// Drains the queue into a non-blocking client socket. Rather than
+/// parking a thread on the socket, the attach loop calls `flush` when
+/// the socket has room or the queue has something new for it.
+pub struct Writer {
+    queue: Arc<OutputQueue>,
+    batch: Vec<Item>,
+    headers: Vec<u8>,
+    /// Where each item's header ends in headers.
+    header_ends: Vec<usize>,
+    /// How much of the batch has gone out, as the index of the next
+    /// piece to write, counting headers and bodies separately, and how
+    /// far into it we got.
+    written: (usize, usize),
+}
+
+impl Writer {
+    pub fn new(queue: Arc<OutputQueue>) -> Self {
+        Writer { queue, batch: vec![], headers: vec![], header_ends: vec![], written: (0, 0) }
+    }
+
+    /// Write out as much of the queue as the sink will take without
+    /// blocking.
+    pub fn flush<W: io::Write>(&mut self, sink: &mut W) -> Flushed {
+        loop {
+            if !self.batch.is_empty() {
+                match self.write_batch(sink) {
+                    Ok(true) => self.count_batch(),
+                    Ok(false) => return Flushed::Blocked,
+                    Err(err) => {
+                        info!("client_stream write err, assuming hangup: {:?}", err);
+                        self.batch.clear();
+                        self.queue.hangup();
+                        return Flushed::HungUp;
+                    }
+                }
+            }
+
+            match self.queue.take_batch(&mut self.batch) {
+                Taken::Batch => {}
+                Taken::Empty => return Flushed::Idle,
+                Taken::Wait(wait) => return Flushed::Waiting(wait),
+                Taken::Closed => return Flushed::Closed,
+            }
+            self.headers.clear();
+            self.header_ends.clear();
+            for item in self.batch.iter() {
+                item.chunk().encode_header(&mut self.headers);
+                self.header_ends.push(self.headers.len());
+            }
+            self.written = (0, 0);
+        }
+    }
+
+    /// Write as much of the batch as the sink will take, with as few
+    /// syscalls as we can manage. Returns true once all of it is out.
+    fn write_batch<W: io::Write>(&mut self, sink: &mut W) -> io::Result<bool> {
+        let mut pieces = Vec::with_capacity(self.batch.len() * 2);
+        let mut header_start = 0;
+        for (item, header_end) in self.batch.iter().zip(self.header_ends.iter()) {
+            pieces.push(&self.headers[header_start..*header_end]);
+            pieces.push(&item.buf[..]);
+            header_start = *header_end;
+        }
+
+        let (mut next, mut offset) = self.written;
+        let res = loop {
+            while next < pieces.len() && offset == pieces[next].len() {
+                next += 1;
+                offset = 0;
+            }
+            if next == pieces.len() {
+                break Ok(true);
+            }
+
+            let slices: Vec<IoSlice> = [IoSlice::new(&pieces[next][offset..])]
+                .into_iter()
+                .chain(pieces[next + 1..].iter().map(|p| IoSlice::new(p)))
+                .collect();
+            let mut n = match sink.write_vectored(&slices) {
+                Ok(0) => break Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(false),
+                Err(e) => break Err(e),
+            };
+            while n > 0 {
+                let left = pieces[next].len() - offset;
+                if n < left {
+                    offset += n;
+                    break;
+                }
+                n -= left;
+                next += 1;
+                offset = 0;
+            }
+        };
+        self.written = (next, offset);
+        res
+    }
+
+    fn count_batch(&self) {
+        for item in self.batch.iter() {
+            match item.kind {
+                Kind::Output => {
+                    metrics::inc(&metrics::METRICS.bytes_to_clients, item.buf.len() as u64);
+                    test_hooks::emit("daemon-wrote-s2c-chunk");
+                }
+                Kind::ExitStatus => trace!("wrote exit status chunk"),
+                Kind::Control => trace!("wrote control chunk"),
+                Kind::Heartbeat => trace!("wrote heartbeat"),
+                Kind::Data => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nix::poll;
+    use ntest::timeout;
+    use std::thread;
+
+    fn output(queue: &OutputQueue) -> Vec<u8> {
+        let mut out = vec![];
+        let mut batch = vec![];
+        queue.close();
+        while queue.take_batch(&mut batch) == Taken::Batch {
+            for item in batch.iter() {
+                out.extend_from_slice(&item.buf);
+            }
+        }
+        out
+    }
+
+    fn woken(queue: &OutputQueue) -> anyhow::Result<bool> {
+        let mut poll_fds = [poll::PollFd::new(queue.waker(), poll::PollFlags::POLLIN)];
+        Ok(poll::poll(&mut poll_fds, poll::PollTimeout::ZERO)? == 1)
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn drop_policy() -> anyhow::Result<()> {
+        let queue =
+            OutputQueue::new(consts::BUF_SIZE, OutputOverflowPolicy::Drop, time::Duration::ZERO)?;
+        let big = vec![b'a'; consts::BUF_SIZE - 2];
+        assert!(queue.push_output(&big));
+        assert!(queue.push_output(b"bc"));
+        assert!(queue.push_output(b"d"));
+        // our own output is never dropped
+        assert!(queue.push_data(b"e"));
+        assert!(!queue.take_lost_output());
+
+        let out = output(&queue);
+        assert_eq!(&out[big.len()..], b"bce");
+        assert!(queue.take_lost_output());
+        assert!(!queue.take_lost_output());
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn clip_policy() -> anyhow::Result<()> {
+        let queue =
+            OutputQueue::new(consts::BUF_SIZE, OutputOverflowPolicy::Clip, time::Duration::ZERO)?;
+        let big = vec![b'a'; consts::BUF_SIZE - 2];
+        assert!(queue.push_output(&big));
+        assert!(queue.push_data(b"b"));
+        assert!(queue.push_output(b"cd"));
+        assert!(queue.push_output(b"e"));
+
+        assert_eq!(output(&queue), b"bcde");
+        assert!(queue.take_lost_output());
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn block_policy() -> anyhow::Result<()> {
+        let queue = Arc::new(OutputQueue::new(
+            consts::BUF_SIZE,
+            OutputOverflowPolicy::Block,
+            time::Duration::ZERO,
+        )?);
+        let big = vec![b'a'; consts::BUF_SIZE];
+        assert!(queue.push_output(&big));
+
+        let pusher = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.push_output(b"b"))
+        };
+        thread::sleep(time::Duration::from_millis(50));
+        assert!(!pusher.is_finished());
+
+        let mut batch = vec![];
+        assert_eq!(queue.take_batch(&mut batch), Taken::Batch);
+        assert_eq!(batch.len(), 1);
+        assert!(pusher.join().unwrap());
+        assert_eq!(output(&queue), b"b");
+        assert!(!queue.take_lost_output());
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn flush_and_hangup() -> anyhow::Result<()> {
+        let queue = Arc::new(OutputQueue::new(
+            consts::BUF_SIZE,
+            OutputOverflowPolicy::Block,
+            time::Duration::ZERO,
+        )?);
+        assert!(queue.push_output(b"a"));
+        assert!(queue.push_data(b"b"));
+        assert_eq!(queue.flush(), 1);
+
+        // a flush unblocks a full queue
+        assert!(queue.push_output(&vec![b'c'; consts::BUF_SIZE]));
+        let pusher = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.push_output(b"d"))
+        };
+        thread::sleep(time::Duration::from_millis(50));
+        assert_eq!(queue.flush(), consts::BUF_SIZE);
+        assert!(pusher.join().unwrap());
+
+        // and so does the client going away
+        assert!(queue.push_output(&vec![b'e'; consts::BUF_SIZE - 1]));
+        let pusher = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.push_output(b"fg"))
+        };
+        thread::sleep(time::Duration::from_millis(50));
+        queue.hangup();
+        assert!(!pusher.join().unwrap());
+        assert!(!queue.push_data(b"h"));
+        assert!(queue.wait_drained(time::Duration::from_millis(10)));
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn writer() -> anyhow::Result<()> {
+        let queue = Arc::new(OutputQueue::new(
+            consts::BUF_SIZE,
+            OutputOverflowPolicy::Block,
+            time::Duration::ZERO,
+        )?);
+        let mut writer = Writer::new(Arc::clone(&queue));
+        let mut sink = vec![];
+        assert!(!woken(&queue)?);
+        assert!(queue.push_output(b"hi"));
+        assert!(queue.push_exit_status(3));
+        assert!(queue.push_heartbeat());
+        assert!(woken(&queue)?);
+
+        assert_eq!(writer.flush(&mut sink), Flushed::Idle);
+        assert!(!woken(&queue)?);
+        assert!(queue.wait_drained(time::Duration::from_secs(10)));
+        queue.close();
+        assert!(woken(&queue)?);
+        assert_eq!(writer.flush(&mut sink), Flushed::Closed);
+
+        assert_eq!(sink, b"\x00\x02\x00\x00\x00hi\x02\x03\x00\x00\x00\x01\x00\x00\x00\x00");
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn coalesce() -> anyhow::Result<()> {
+        let queue = OutputQueue::new(
+            DEFAULT_LIMIT,
+            OutputOverflowPolicy::Block,
+            time::Duration::from_millis(500),
+        )?;
+        let mut batch = vec![];
+
+        // small pushes get tacked onto the last chunk of the same kind
+        assert!(queue.push_output(b"a"));
+        assert!(queue.push_output(b"b"));
+        assert_eq!(queue.take_batch(&mut batch), Taken::Batch);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].buf, b"ab");
+
+        // with the last write just now, a trickle of output waits for
+        // more to pile up
+        assert!(queue.push_output(b"c"));
+        assert!(matches!(queue.take_batch(&mut batch), Taken::Wait(_)));
+        assert!(queue.push_output(b"d"));
+        assert!(matches!(queue.take_batch(&mut batch), Taken::Wait(_)));
+
+        // anything other than shell output goes out right away
+        assert!(queue.push_exit_status(0));
+        assert_eq!(queue.take_batch(&mut batch), Taken::Batch);
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0].buf, b"cd");
+
+        // as does output after a quiet spell, like the echo of a keystroke
+        assert_eq!(queue.take_batch(&mut batch), Taken::Empty);
+        thread::sleep(time::Duration::from_millis(600));
+        assert!(queue.push_output(b"e"));
+        assert_eq!(queue.take_batch(&mut batch), Taken::Batch);
+        assert_eq!(batch[0].buf, b"e");
+        Ok(())
+    }
+
+    /// Takes at most 3 bytes per write, and every other write would
+    /// block.
+    #[derive(Default)]
+    struct Trickle {
+        out: Vec<u8>,
+        blocked: bool,
+    }
+
+    impl io::Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.blocked = !self.blocked;
+            if self.blocked {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            let n = buf.len().min(3);
+            self.out.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn short_writes() -> anyhow::Result<()> {
+        let queue = Arc::new(OutputQueue::new(
+            consts::BUF_SIZE,
+            OutputOverflowPolicy::Block,
+            time::Duration::ZERO,
+        )?);
+        let mut writer = Writer::new(Arc::clone(&queue));
+        let mut sink = Trickle::default();
+        assert!(queue.push_data(b"abcdefgh"));
+        assert!(queue.push_heartbeat());
+
+        let mut blocked = 0;
+        while writer.flush(&mut sink) == Flushed::Blocked {
+            blocked += 1;
+        }
+        assert!(blocked > 1);
+        assert_eq!(sink.out, b"\x00\x08\x00\x00\x00abcdefgh\x01\x00\x00\x00\x00");
+        Ok(())
+    }
+}
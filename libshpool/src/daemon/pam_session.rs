@@ -0,0 +1,150 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! PAM sessions for spawned shells.
+
+  Without a PAM session, the rest of the system has no idea that a
+  shpool shell is a login, so loginctl doesn't list it, lingering and
+  the user slice accounting don't count it, and modules like pam_env
+  and pam_limits never get a say in how it is set up. When the config
+  names a PAM service, the daemon opens a session with that service for
+  every new shell, hands the environment the modules set up to the
+  shell, and closes the session once the shell exits.
+
+  The session gets opened by the daemon itself, so modules which act on
+  the calling process (pam_limits setting rlimits, pam_systemd picking
+  a session leader) act on the daemon, and the shell inherits the
+  result when it gets forked off. Most modules need the daemon to run
+  as root to register a session.
+*/
+
+use std::{
+    ffi::{c_int, c_void, CStr},
+    ptr,
+};
+
+use anyhow::anyhow;
+use pam_sys::{PamConversation, PamFlag, PamHandle, PamMessage, PamResponse, PamReturnCode};
+use tracing::{info, warn};
+
+/// An open PAM session, which gets closed on drop.
+pub struct Session {
+    handle: *mut PamHandle,
+    service: String,
+    opened: bool,
+}
+
+// Safety: the handle is only ever used by whichever thread owns the
+//         session, PAM doesn't care which thread that is.
+unsafe impl Send for Session {}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session").field("service", &self.service).finish()
+    }
+}
+
+impl Session {
+    /// Open a session for the given user with the given PAM service.
+    pub fn open(service: &str, user: &str) -> anyhow::Result<Self> {
+        // There is nobody to answer any questions the modules might have,
+        // but some modules refuse to run without a conversation function.
+        // pam_start copies the struct, so it is fine for it to live on
+        // the stack.
+        let conv = PamConversation { conv: Some(no_conversation), data_ptr: ptr::null_mut() };
+        let mut handle: *mut PamHandle = ptr::null_mut();
+        let code = pam_sys::start(service, Some(user), &conv, &mut handle);
+        if code != PamReturnCode::SUCCESS || handle.is_null() {
+            return Err(anyhow!("starting pam service '{}': {}", service, code));
+        }
+        // From here on, dropping the session ends the handle.
+        let mut session = Session { handle, service: String::from(service), opened: false };
+
+        let handle = session.handle();
+        let code = pam_sys::setcred(handle, PamFlag::ESTABLISH_CRED);
+        if code != PamReturnCode::SUCCESS {
+            return Err(anyhow!("establishing pam credentials: {}", code));
+        }
+        let code = pam_sys::open_session(handle, PamFlag::NONE);
+        if code != PamReturnCode::SUCCESS {
+            return Err(anyhow!("opening pam session: {}", code));
+        }
+        session.opened = true;
+
+        info!("opened pam session with service '{}' for '{}'", service, user);
+        Ok(session)
+    }
+
+    /// The environment variables the modules set up for the session,
+    /// like XDG_RUNTIME_DIR from pam_systemd.
+    pub fn env(&mut self) -> Vec<(String, String)> {
+        let list = pam_sys::getenvlist(self.handle());
+        if list.is_null() {
+            return vec![];
+        }
+
+        let mut env = vec![];
+        // Safety: PAM hands back a NULL terminated array of NUL terminated
+        //         strings, all of which it allocated with malloc and which
+        //         we are expected to free.
+        unsafe {
+            let mut entry = list;
+            while !(*entry).is_null() {
+                let name_value = CStr::from_ptr(*entry).to_string_lossy();
+                match name_value.split_once('=') {
+                    Some((name, value)) => env.push((String::from(name), String::from(value))),
+                    None => warn!("skipping malformed pam env entry '{}'", name_value),
+                }
+                libc::free(*entry as *mut c_void);
+                entry = entry.add(1);
+            }
+            libc::free(list as *mut c_void);
+        }
+        env
+    }
+
+    fn handle(&mut self) -> &mut PamHandle {
+        // Safety: open makes sure the handle is not null, and it stays
+        //         valid until drop.
+        unsafe { &mut *self.handle }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let opened = self.opened;
+        let handle = self.handle();
+        if opened {
+            let code = pam_sys::close_session(handle, PamFlag::NONE);
+            if code != PamReturnCode::SUCCESS {
+                warn!("closing pam session: {}", code);
+            }
+        }
+        let code = pam_sys::setcred(handle, PamFlag::DELETE_CRED);
+        pam_sys::end(handle, code);
+        if opened {
+            info!("closed pam session with service '{}'", self.service);
+        }
+    }
+}
+
+/// A conversation function that has no answers for anything.
+extern "C" fn no_conversation(
+    _num_msg: c_int,
+    _msg: *mut *mut PamMessage,
+    _resp: *mut *mut PamResponse,
+    _appdata_ptr: *mut c_void,
+) -> c_int {
+    PamReturnCode::CONV_ERR as c_int
+}
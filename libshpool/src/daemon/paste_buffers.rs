@@ -0,0 +1,114 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! The daemon's paste buffers.
+
+  Everything copied in copy mode lands in a fresh automatically named
+  buffer (`buffer0`, `buffer1`, ...), and `shpool load-buffer` can fill
+  a buffer with a name of its own choosing. The newest buffer is the one
+  that gets used when no name is given. Only so many automatically
+  named buffers are kept around, but named ones stay until the daemon
+  exits.
+*/
+
+use std::collections::VecDeque;
+
+/// The most automatically named buffers to hold onto.
+const MAX_AUTO: usize = 50;
+
+const AUTO_PREFIX: &str = "buffer";
+
+#[derive(Default, Debug)]
+pub struct PasteBuffers {
+    /// The buffers and their contents, newest first.
+    buffers: VecDeque<(String, String)>,
+    next_auto: usize,
+}
+
+impl PasteBuffers {
+    /// Fill the named buffer, or a fresh automatically named one, and
+    /// make it the newest. Returns the buffer's name.
+    pub fn set(&mut self, name: Option<String>, contents: String) -> String {
+        let name = name.unwrap_or_else(|| {
+            let name = format!("{}{}", AUTO_PREFIX, self.next_auto);
+            self.next_auto += 1;
+            name
+        });
+        self.buffers.retain(|(n, _)| *n != name);
+        self.buffers.push_front((name.clone(), contents));
+
+        let mut autos = 0;
+        self.buffers.retain(|(n, _)| {
+            if !is_auto(n) {
+                return true;
+            }
+            autos += 1;
+            autos <= MAX_AUTO
+        });
+
+        name
+    }
+
+    /// The contents of the named buffer, or of the newest one.
+    pub fn get(&self, name: Option<&str>) -> Option<&str> {
+        match name {
+            Some(name) => self.buffers.iter().find(|(n, _)| n == name),
+            None => self.buffers.front(),
+        }
+        .map(|(_, contents)| contents.as_str())
+    }
+}
+
+fn is_auto(name: &str) -> bool {
+    name.strip_prefix(AUTO_PREFIX)
+        .map(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn newest_wins() {
+        let mut buffers = PasteBuffers::default();
+        assert_eq!(buffers.get(None), None);
+
+        assert_eq!(buffers.set(None, String::from("one")), "buffer0");
+        assert_eq!(buffers.set(Some(String::from("notes")), String::from("two")), "notes");
+        assert_eq!(buffers.get(None), Some("two"));
+        assert_eq!(buffers.get(Some("buffer0")), Some("one"));
+
+        // refilling a buffer makes it the newest again
+        buffers.set(Some(String::from("buffer0")), String::from("three"));
+        assert_eq!(buffers.get(None), Some("three"));
+        assert_eq!(buffers.get(Some("nope")), None);
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn auto_limit() {
+        let mut buffers = PasteBuffers::default();
+        buffers.set(Some(String::from("keep")), String::from("kept"));
+        for i in 0..(MAX_AUTO + 5) {
+            buffers.set(None, format!("copy {}", i));
+        }
+
+        assert_eq!(buffers.get(Some("buffer0")), None);
+        assert_eq!(buffers.get(Some("buffer5")), Some("copy 5"));
+        assert_eq!(buffers.get(Some("keep")), Some("kept"));
+    }
+}
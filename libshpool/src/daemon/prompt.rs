@@ -23,6 +23,7 @@ use tracing::{debug, info, instrument, warn};
 use crate::{
     consts::{SENTINEL_FLAG_VAR, STARTUP_SENTINEL},
     daemon::trie::{Trie, TrieCursor},
+    pty,
 };
 
 #[derive(Debug, Clone)]
@@ -37,13 +38,13 @@ enum KnownShell {
 /// injecting the prefix.
 #[instrument(skip_all)]
 pub fn inject_prefix(
-    pty_master: &mut shpool_pty::fork::Fork,
+    pty: &dyn pty::Pty,
     prompt_prefix: &str,
     session_name: &str,
 ) -> anyhow::Result<()> {
-    let shell_pid = pty_master.child_pid().ok_or(anyhow!("no child pid"))?;
+    let shell_pid = pty.child_pid();
     // scan for the startup sentinel so we know it is safe to sniff the shell
-    let mut pty_master = pty_master.is_parent().context("expected parent")?;
+    let mut pty_master = pty.master();
     wait_for_startup(&mut pty_master)?;
 
     let shell_type = sniff_shell(shell_pid);
@@ -121,7 +122,7 @@ pub fn inject_prefix(
 }
 
 #[instrument(skip_all)]
-fn wait_for_startup(pty_master: &mut shpool_pty::fork::Master) -> anyhow::Result<()> {
+fn wait_for_startup(pty_master: &mut pty::Master) -> anyhow::Result<()> {
     let mut startup_sentinel_scanner = SentinelScanner::new(STARTUP_SENTINEL);
     let startup_sentinel_cmd =
         format!("\n{}=startup /proc/{}/exe daemon\n", SENTINEL_FLAG_VAR, std::process::id());
@@ -0,0 +1,203 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Masking secrets in a session's output before it gets kept anywhere.
+
+  The `redact` patterns from the config get applied to the output that
+  goes into the output spool, the raw tail that `shpool capture --bytes`
+  reads from, and audit and asciicast recordings. The output that goes
+  to attached clients is left alone, since it isn't kept.
+
+  Patterns are matched a line at a time. So that a secret which the pty
+  happens to hand over in two reads still gets caught, `Redactor` holds
+  the unfinished last line of each chunk back until the rest of it
+  shows up or the output goes quiet.
+*/
+
+use std::borrow::Cow;
+
+use regex::bytes::Regex;
+use tracing::{info, warn};
+
+use crate::config;
+
+/// What matches get replaced with when the pattern doesn't say.
+const DEFAULT_REPLACEMENT: &str = "[REDACTED]";
+
+/// The longest unfinished line to hold back. Anything longer is most
+/// likely not text, like a progress bar redrawing itself with \r.
+const MAX_HELD: usize = 4096;
+
+#[derive(Debug, Default)]
+pub struct Redactor {
+    /// The patterns the rules were built from, to notice config reloads.
+    source: Vec<config::Redaction>,
+    rules: Vec<(Regex, String)>,
+    held: Vec<u8>,
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Redactor::default()
+    }
+
+    /// Pick up the current patterns from the config, if they changed.
+    /// Patterns that don't compile get logged and skipped.
+    pub fn configure(&mut self, redactions: &[config::Redaction]) {
+        if self.source == redactions {
+            return;
+        }
+        self.rules = redactions
+            .iter()
+            .filter_map(|r| match Regex::new(&r.pattern) {
+                Ok(re) => Some((
+                    re,
+                    r.replacement.clone().unwrap_or_else(|| String::from(DEFAULT_REPLACEMENT)),
+                )),
+                Err(e) => {
+                    warn!("skipping bad redact pattern '{}': {}", r.pattern, e);
+                    None
+                }
+            })
+            .collect();
+        self.source = redactions.to_vec();
+        info!("redacting output with {} patterns", self.rules.len());
+    }
+
+    /// Redact a chunk of output, along with whatever was held back from
+    /// the last one, using `scratch` as the backing storage for the
+    /// result if needed. The unfinished last line gets held back in
+    /// turn unless `flush` is set, so the result may be empty.
+    pub fn redact<'a>(
+        &mut self,
+        input: &'a [u8],
+        flush: bool,
+        scratch: &'a mut Vec<u8>,
+    ) -> &'a [u8] {
+        scratch.clear();
+        if self.rules.is_empty() && self.held.is_empty() {
+            return input;
+        }
+
+        self.held.extend_from_slice(input);
+        let end = match memchr::memrchr(b'\n', &self.held) {
+            _ if flush || self.held.len() > MAX_HELD => self.held.len(),
+            Some(i) => i + 1,
+            None => return scratch,
+        };
+        let rest = self.held.split_off(end);
+        let lines = std::mem::replace(&mut self.held, rest);
+        self.apply(&lines, scratch);
+        scratch
+    }
+
+    pub fn is_holding(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    fn apply(&self, input: &[u8], out: &mut Vec<u8>) {
+        let mut replaced: Option<Vec<u8>> = None;
+        for (re, replacement) in self.rules.iter() {
+            let text = replaced.as_deref().unwrap_or(input);
+            if let Cow::Owned(r) = re.replace_all(text, replacement.as_bytes()) {
+                replaced = Some(r);
+            }
+        }
+        out.extend_from_slice(replaced.as_deref().unwrap_or(input));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    fn redactor(patterns: &[(&str, Option<&str>)]) -> Redactor {
+        let mut redactor = Redactor::new();
+        redactor.configure(
+            &patterns
+                .iter()
+                .map(|(pattern, replacement)| config::Redaction {
+                    pattern: String::from(*pattern),
+                    replacement: replacement.map(String::from),
+                })
+                .collect::<Vec<_>>(),
+        );
+        redactor
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn replaces() {
+        let mut redactor = redactor(&[
+            (r"AWS_SECRET_ACCESS_KEY=\S+", None),
+            (r"token: (\w{4})\w+", Some("token: ${1}...")),
+            ("(", None),
+        ]);
+        let mut scratch = vec![];
+        assert_eq!(
+            redactor.redact(
+                b"AWS_SECRET_ACCESS_KEY=abc123 ok\r\ntoken: deadbeef\r\n",
+                false,
+                &mut scratch
+            ),
+            b"[REDACTED] ok\r\ntoken: dead...\r\n"
+        );
+        assert!(!redactor.is_holding());
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn split_secret() {
+        let text = b"$ echo AWS_SECRET_ACCESS_KEY=abc123\r\nAWS_SECRET_ACCESS_KEY=abc123\r\n$ ";
+        for split in 0..text.len() {
+            let mut redactor = redactor(&[(r"AWS_SECRET_ACCESS_KEY=\S+", Some("<key>"))]);
+            let mut scratch = vec![];
+            let mut out = redactor.redact(&text[..split], false, &mut scratch).to_vec();
+            out.extend(redactor.redact(&text[split..], false, &mut scratch));
+            assert!(redactor.is_holding());
+            out.extend(redactor.redact(b"", true, &mut scratch));
+            assert!(!redactor.is_holding());
+            assert_eq!(out, b"$ echo <key>\r\n<key>\r\n$ ", "split={}", split);
+        }
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn long_line() {
+        let mut redactor = redactor(&[("x", Some("y"))]);
+        let mut scratch = vec![];
+        let line = vec![b'x'; MAX_HELD];
+        assert_eq!(redactor.redact(&line, false, &mut scratch), b"");
+        assert_eq!(redactor.redact(b"x", false, &mut scratch), vec![b'y'; MAX_HELD + 1]);
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn reconfigure() {
+        let mut redactor = Redactor::new();
+        let mut scratch = vec![];
+        assert_eq!(redactor.redact(b"secret", false, &mut scratch), b"secret");
+
+        let redactions =
+            vec![config::Redaction { pattern: String::from("secret"), replacement: None }];
+        redactor.configure(&redactions);
+        assert_eq!(redactor.redact(b"a secret", false, &mut scratch), b"");
+
+        // the held line still comes out once the patterns go away
+        redactor.configure(&[]);
+        assert_eq!(redactor.redact(b"\n", false, &mut scratch), b"a secret\n");
+        assert!(!redactor.is_holding());
+    }
+}
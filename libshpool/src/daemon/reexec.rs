@@ -0,0 +1,122 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Restarting the daemon in place.
+
+  `shpool daemon restart` has the daemon exec whatever binary is now
+  installed where it was started from, with the same arguments. The pid
+  stays the same, so systemd and anything else keeping an eye on the
+  daemon doesn't notice. With --preserve-sessions, the sessions take
+  the same trip through a holder process that `shpool daemon stop
+  --keep-sessions` uses, and the new image picks them back up from the
+  holder socket as it starts.
+*/
+
+use std::{
+    env, fs,
+    os::{
+        fd::RawFd,
+        unix::{fs::PermissionsExt, process::CommandExt},
+    },
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{anyhow, Context};
+use nix::{
+    errno::Errno,
+    fcntl::{self, FcntlArg, FdFlag},
+};
+
+use super::systemd;
+use crate::consts;
+
+/// What readlink(2) tacks onto /proc/self/exe once the binary the
+/// process is running has been unlinked, as it is by an upgrade.
+const DELETED_SUFFIX: &str = " (deleted)";
+
+/// The binary to restart into, which is whatever is now installed at
+/// the path the daemon was started from. Checks that there is something
+/// there to exec, so that a restart doesn't tear anything down just to
+/// fail.
+pub fn binary() -> anyhow::Result<PathBuf> {
+    let exe = fs::read_link("/proc/self/exe").context("reading /proc/self/exe")?;
+    let exe = match exe.to_str().and_then(|e| e.strip_suffix(DELETED_SUFFIX)) {
+        Some(e) => PathBuf::from(e),
+        None => exe,
+    };
+
+    let meta = fs::metadata(&exe).with_context(|| format!("checking {}", exe.display()))?;
+    if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+        return Err(anyhow!("{} is not an executable file", exe.display()));
+    }
+    Ok(exe)
+}
+
+/// Replace the daemon with a fresh run of the given binary. Only
+/// returns if the exec fails.
+pub fn exec(exe: &Path) -> anyhow::Error {
+    if let Err(err) = close_on_exec() {
+        return err;
+    }
+
+    let mut args = env::args_os();
+    let mut cmd = process::Command::new(exe);
+    if let Some(arg0) = args.next() {
+        cmd.arg0(arg0);
+    }
+    cmd.args(args)
+        // the test hook socket belongs to the old image, so the new
+        // one must not wait for a test to connect to it
+        .env_remove("SHPOOL_TEST_HOOK_SOCKET_PATH");
+    anyhow::Error::from(cmd.exec()).context("execing new daemon")
+}
+
+/// Keep everything but the std streams and the systemd activation
+/// socket from leaking into the new image. Rust opens its fds close on
+/// exec, but the pty masters come from elsewhere, and a stray copy of
+/// one would keep its shell from ever getting hung up on.
+fn close_on_exec() -> anyhow::Result<()> {
+    let activation_socket = systemd::activation_socket_fd();
+    for entry in fs::read_dir("/proc/self/fd").context("listing open fds")? {
+        let entry = entry.context("listing open fds")?;
+        let fd: RawFd = match entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+            Some(fd) => fd,
+            None => continue,
+        };
+        if fd <= consts::STDERR_FD || Some(fd) == activation_socket {
+            continue;
+        }
+
+        match fcntl::fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            // the fd read_dir used to list the others is gone by now
+            Ok(_) | Err(Errno::EBADF) => {}
+            Err(e) => return Err(e).with_context(|| format!("marking fd {} close on exec", fd)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn binary_is_us() -> anyhow::Result<()> {
+        assert_eq!(binary()?, env::current_exe()?);
+        Ok(())
+    }
+}
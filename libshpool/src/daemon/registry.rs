@@ -0,0 +1,272 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! The daemon's table of sessions.
+
+  Nearly every connection looks something up in the session table, and
+  creating a session spawns its shell with the table locked so that no
+  other connection can create one by the same name at the same time.
+  With a single lock around the whole table, hundreds of sessions being
+  listed and attached to means a lot of waiting around, so the table is
+  split up into shards, each with a lock of its own. A session goes in
+  the shard picked by the name of the session it belongs to, so a
+  session and all of its jobs always share a shard, and anything that
+  deals with one session and its jobs only has to lock that one shard.
+
+  Alongside the shards, the registry keeps a count of the sessions and
+  of the attached clients, so that questions about the table as a whole
+  don't have to lock every shard.
+*/
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    ops::Deref,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+use super::shell;
+use crate::job;
+
+/// How many shards the table gets split into. Comfortably more than
+/// the number of connections likely to be working at once keeps the
+/// odds of two of them wanting the same shard low.
+const SHARDS: usize = 64;
+
+/// The registry the daemon keeps its sessions in.
+pub type Sessions = Registry<Box<shell::Session>>;
+
+#[derive(Debug)]
+pub struct Registry<T> {
+    shards: Vec<Mutex<HashMap<String, T>>>,
+    /// How many sessions there are across all the shards.
+    len: AtomicUsize,
+    /// How many clients are attached, across all the sessions.
+    attached: AtomicUsize,
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Registry {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            len: AtomicUsize::new(0),
+            attached: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<T> Registry<T> {
+    /// Lock the shard that holds the given session, along with the
+    /// rest of the jobs of the session it belongs to.
+    pub fn shard(&self, name: &str) -> Shard<'_, T> {
+        self.lock(shard_index(name, self.shards.len()))
+    }
+
+    /// Lock every shard, for the few things that need the whole table
+    /// to hold still. Shards always get locked in the same order, so
+    /// this can't deadlock with itself.
+    pub fn lock_all(&self) -> All<'_, T> {
+        All { shards: (0..self.shards.len()).map(|i| self.lock(i)).collect() }
+    }
+
+    /// Call f on every session, locking one shard at a time, and collect
+    /// up what it returns. Sessions can come and go in the other shards
+    /// in the meantime, which is fine for reporting on them.
+    pub fn filter_map<R, F>(&self, mut f: F) -> Vec<R>
+    where
+        F: FnMut(&String, &T) -> Option<R>,
+    {
+        let mut out = vec![];
+        for i in 0..self.shards.len() {
+            let shard = self.lock(i);
+            out.extend(shard.iter().filter_map(|(name, session)| f(name, session)));
+        }
+        out
+    }
+
+    /// The names of all the sessions, in no particular order.
+    pub fn names(&self) -> Vec<String> {
+        self.filter_map(|name, _| Some(name.clone()))
+    }
+
+    /// How many sessions there are.
+    pub fn count(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Count a client as attached until the returned guard gets
+    /// dropped.
+    pub fn attach_client(&self) -> ClientGuard<'_> {
+        self.attached.fetch_add(1, Ordering::AcqRel);
+        ClientGuard { attached: &self.attached }
+    }
+
+    /// How many clients are attached, in total across all the sessions.
+    pub fn attached(&self) -> usize {
+        self.attached.load(Ordering::Acquire)
+    }
+
+    fn lock(&self, i: usize) -> Shard<'_, T> {
+        // we unwrap to propagate the poison as an unwind
+        Shard { table: self.shards[i].lock().unwrap(), len: &self.len }
+    }
+}
+
+fn shard_index(name: &str, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    job::base_session(name).hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// A locked shard of the registry. It derefs to the shard's sessions
+/// for looking them up, but adding and removing them has to go through
+/// the shard so that the count stays right.
+pub struct Shard<'a, T> {
+    table: MutexGuard<'a, HashMap<String, T>>,
+    len: &'a AtomicUsize,
+}
+
+impl<T> Deref for Shard<'_, T> {
+    type Target = HashMap<String, T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.table
+    }
+}
+
+impl<T> Shard<'_, T> {
+    /// Add a session, clobbering any with the same name. The session
+    /// must belong in this shard.
+    pub fn insert(&mut self, name: String, session: T) {
+        if self.table.insert(name, session).is_none() {
+            self.len.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<T> {
+        let session = self.table.remove(name);
+        if session.is_some() {
+            self.len.fetch_sub(1, Ordering::AcqRel);
+        }
+        session
+    }
+}
+
+/// Every shard of the registry, locked.
+pub struct All<'a, T> {
+    shards: Vec<Shard<'a, T>>,
+}
+
+impl<T> All<'_, T> {
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &T)> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.shards[shard_index(name, self.shards.len())].contains_key(name)
+    }
+}
+
+/// Keeps a client counted as attached.
+pub struct ClientGuard<'a> {
+    attached: &'a AtomicUsize,
+}
+
+impl Drop for ClientGuard<'_> {
+    fn drop(&mut self) {
+        self.attached.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    #[timeout(30000)]
+    fn jobs_share_a_shard() {
+        let registry: Registry<u32> = Registry::default();
+        let mut shard = registry.shard("main");
+        shard.insert(String::from("main"), 1);
+        shard.insert(String::from("main:build"), 2);
+        shard.insert(String::from("main:build"), 3);
+        drop(shard);
+
+        assert_eq!(registry.count(), 2);
+        assert_eq!(registry.shard("main:build").get("main:build"), Some(&3));
+        assert!(registry.lock_all().contains_key("main"));
+
+        let attached = registry.attach_client();
+        assert_eq!(registry.attached(), 1);
+        drop(attached);
+        assert_eq!(registry.attached(), 0);
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn stress() {
+        const THREADS: usize = 8;
+        const SESSIONS: usize = 1000;
+
+        let registry: Arc<Registry<usize>> = Arc::new(Registry::default());
+        let handles: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let registry = Arc::clone(&registry);
+                thread::spawn(move || {
+                    for i in (t..SESSIONS).step_by(THREADS) {
+                        let name = format!("s{}", i);
+                        registry.shard(&name).insert(name.clone(), i);
+                        let _client = registry.attach_client();
+                        // list and look things up while the others are
+                        // busy adding sessions of their own
+                        assert!(registry.names().len() <= SESSIONS);
+                        assert_eq!(registry.shard(&name).get(&name), Some(&i));
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        assert_eq!(registry.count(), SESSIONS);
+        assert_eq!(registry.names().len(), SESSIONS);
+        assert_eq!(registry.lock_all().iter().count(), SESSIONS);
+        assert_eq!(registry.attached(), 0);
+        let used = (0..SESSIONS).map(|i| shard_index(&format!("s{}", i), SHARDS));
+        assert!(used.collect::<std::collections::HashSet<_>>().len() > SHARDS / 2);
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let registry = Arc::clone(&registry);
+                thread::spawn(move || {
+                    for i in (t..SESSIONS).step_by(THREADS) {
+                        let name = format!("s{}", i);
+                        assert_eq!(registry.shard(&name).remove(&name), Some(i));
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(registry.count(), 0);
+        assert!(registry.names().is_empty());
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Saved session definitions, which let `shpool resurrect` bring back
+  the sessions that were running before a reboot. Only what it takes to
+  start a session up again gets saved, not anything about the processes
+  running in it.
+
+  A session's definition gets saved when the session is created, and
+  forgotten when it gets killed or its shell exits on its own. A shell
+  that gets killed by a signal, which is what happens when the machine
+  goes down or the daemon is stopped, keeps its definition. Sessions
+  with a ttl, jobs and sessions started in a container's namespaces
+  are never saved, since they are not meant to stick around.
+*/
+
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{config, paths};
+
+/// Everything it takes to start a session up again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub name: String,
+    /// The command the session runs instead of the user's shell.
+    pub cmd: Option<String>,
+    /// The directory the session started in, if not $HOME.
+    pub cwd: Option<String>,
+    /// The TERM the session was asked to start with, if it was given
+    /// one with --term.
+    #[serde(default)]
+    pub term: Option<String>,
+    /// The environment variables picked by resurrect.env.
+    pub env: Vec<(String, String)>,
+}
+
+/// The saved definitions of a daemon's sessions. Every change gets
+/// written out right away, since the daemon may not get the chance to
+/// do so later.
+pub struct Store {
+    config: config::Manager,
+    instance: Option<String>,
+    /// Serializes the read-modify-write cycles on the file.
+    lock: Mutex<()>,
+}
+
+impl Store {
+    pub fn new(config: config::Manager, instance: Option<String>) -> Self {
+        Store { config, instance, lock: Mutex::new(()) }
+    }
+
+    /// The file definitions get saved to, or None if saving is turned
+    /// off.
+    fn path(&self) -> anyhow::Result<Option<PathBuf>> {
+        let config = self.config.get();
+        let resurrect = match &config.resurrect {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if let Some(file) = &resurrect.file {
+            return Ok(Some(PathBuf::from(file)));
+        }
+        let file = match &self.instance {
+            Some(instance) => format!("resurrect-{}.json", instance),
+            None => String::from("resurrect.json"),
+        };
+        Ok(Some(paths::state_dir(&config)?.join(file)))
+    }
+
+    /// Build the definition of a new session from the environment the
+    /// client forwarded.
+    pub fn definition(
+        &self,
+        name: &str,
+        cmd: Option<&str>,
+        cwd: Option<&str>,
+        term: Option<&str>,
+        local_env: &[(String, String)],
+    ) -> Definition {
+        let patterns =
+            self.config.get().resurrect.as_ref().and_then(|r| r.env.clone()).unwrap_or_default();
+        let env = local_env
+            .iter()
+            .filter(|(var, _)| patterns.iter().any(|p| env_matches(p, var)))
+            .cloned()
+            .collect();
+        Definition {
+            name: String::from(name),
+            cmd: cmd.map(String::from),
+            cwd: cwd.map(String::from),
+            term: term.map(String::from),
+            env,
+        }
+    }
+
+    /// The saved definitions, or None if saving is turned off.
+    pub fn load(&self) -> anyhow::Result<Option<Vec<Definition>>> {
+        let _guard = self.lock.lock().unwrap();
+        match self.path()? {
+            Some(path) => Ok(Some(read(&path)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Save the given definition, replacing any earlier one with the
+    /// same name. Failing to save is not worth refusing the session
+    /// over, so errors just get logged.
+    pub fn save(&self, definition: Definition) {
+        let name = definition.name.clone();
+        let res = self.update(|defs| {
+            match defs.iter_mut().find(|d| d.name == definition.name) {
+                Some(d) => *d = definition,
+                None => defs.push(definition),
+            }
+            true
+        });
+        if let Err(e) = res {
+            warn!("saving definition of '{}': {:?}", name, e);
+        }
+    }
+
+    /// Forget the definition of the given session, if there is one.
+    pub fn forget(&self, name: &str) {
+        let res = self.update(|defs| {
+            let len = defs.len();
+            defs.retain(|d| d.name != name);
+            defs.len() != len
+        });
+        if let Err(e) = res {
+            warn!("forgetting definition of '{}': {:?}", name, e);
+        }
+    }
+
+    /// Apply a change to the saved definitions, writing them back out
+    /// if f reports that it changed something.
+    fn update<F>(&self, f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut Vec<Definition>) -> bool,
+    {
+        let _guard = self.lock.lock().unwrap();
+        let path = match self.path()? {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut defs = read(&path)?;
+        if f(&mut defs) {
+            info!("writing {} session definitions to {:?}", defs.len(), path);
+            write(&path, &defs)?;
+        }
+        Ok(())
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Vec<Definition>> {
+    match fs::read(path) {
+        Ok(buf) => serde_json::from_slice(&buf)
+            .with_context(|| format!("parsing session definitions in {:?}", path)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e).with_context(|| format!("reading session definitions from {:?}", path)),
+    }
+}
+
+/// Replace the file atomically, so that a crash halfway through can't
+/// lose all the definitions. The environment may hold secrets, so the
+/// file is only readable by the user.
+fn write(path: &Path, defs: &[Definition]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("creating dir {:?}", dir))?;
+    }
+    let mut buf = serde_json::to_vec_pretty(defs).context("encoding session definitions")?;
+    buf.push(b'\n');
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .with_context(|| format!("opening {:?}", tmp_path))?;
+    file.write_all(&buf).context("writing session definitions")?;
+    file.sync_all().context("syncing session definitions")?;
+    fs::rename(&tmp_path, path).context("moving session definitions into place")?;
+    Ok(())
+}
+
+/// Check if the variable is picked by the pattern, which is either an
+/// exact name or a prefix followed by a '*'.
+fn env_matches(pattern: &str, var: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => var.starts_with(prefix),
+        None => pattern == var,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn save_forget() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("state").join("resurrect.json");
+        let config = config::Config {
+            resurrect: Some(config::Resurrect {
+                file: Some(path.to_string_lossy().into_owned()),
+                env: Some(vec![String::from("VIRTUAL_ENV"), String::from("PROJECT_*")]),
+            }),
+            ..Default::default()
+        };
+        let store = Store::new(config::Manager::from_config(config), None);
+        assert_eq!(store.load()?, Some(vec![]));
+
+        let local_env = vec![
+            (String::from("TERM"), String::from("xterm")),
+            (String::from("VIRTUAL_ENV"), String::from("/src/proj/.venv")),
+            (String::from("PROJECT_ROOT"), String::from("/src/proj")),
+        ];
+        let proj = store.definition("proj", None, Some("/src/proj"), None, &local_env);
+        assert_eq!(
+            proj.env,
+            vec![
+                (String::from("VIRTUAL_ENV"), String::from("/src/proj/.venv")),
+                (String::from("PROJECT_ROOT"), String::from("/src/proj")),
+            ]
+        );
+        store.save(proj.clone());
+        let logs =
+            store.definition("logs", Some("journalctl -f"), None, Some("xterm-256color"), &[]);
+        store.save(logs.clone());
+        assert_eq!(store.load()?, Some(vec![proj.clone(), logs.clone()]));
+
+        // saving again replaces the old definition in place
+        let proj = store.definition("proj", None, Some("/src/other"), None, &[]);
+        store.save(proj.clone());
+        assert_eq!(store.load()?, Some(vec![proj.clone(), logs]));
+
+        store.forget("logs");
+        store.forget("nosuchsession");
+        assert_eq!(store.load()?, Some(vec![proj]));
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn disabled() -> anyhow::Result<()> {
+        let store = Store::new(config::Manager::from_config(config::Config::default()), None);
+        store.save(store.definition("proj", None, None, None, &[]));
+        assert_eq!(store.load()?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Searching a session's scrollback for `shpool search`.
+
+use regex::Regex;
+
+use crate::protocol::SearchLine;
+
+/// Find the lines that match, along with up to `context` lines on
+/// either side of each of them.
+pub fn search(lines: &[&str], re: &Regex, context: usize) -> Vec<SearchLine> {
+    let mut found = vec![];
+    // the first line not yet handed back, so overlapping context
+    // doesn't show up twice
+    let mut next = 0;
+    for (i, line) in lines.iter().enumerate() {
+        if !re.is_match(line) {
+            continue;
+        }
+        let start = i.saturating_sub(context).max(next);
+        let end = i.saturating_add(context).saturating_add(1).min(lines.len());
+        for (j, text) in lines.iter().enumerate().take(end).skip(start) {
+            found.push(SearchLine {
+                number: j + 1,
+                text: String::from(*text),
+                matched: re.is_match(text),
+            });
+        }
+        next = end;
+    }
+    found
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn context() {
+        let lines = ["a", "ERROR one", "b", "c", "d", "ERROR two", "ERROR three", "e"];
+        let re = Regex::new("ERROR").unwrap();
+
+        let got = search(&lines, &re, 0);
+        assert_eq!(got.iter().map(|l| l.number).collect::<Vec<_>>(), vec![2, 6, 7]);
+        assert!(got.iter().all(|l| l.matched));
+
+        // overlapping context gets merged
+        let got = search(&lines, &re, 1);
+        assert_eq!(got.iter().map(|l| l.number).collect::<Vec<_>>(), vec![1, 2, 3, 5, 6, 7, 8]);
+        assert_eq!(
+            got.iter().filter(|l| !l.matched).map(|l| l.text.as_str()).collect::<Vec<_>>(),
+            vec!["a", "b", "d", "e"]
+        );
+
+        assert!(search(&lines, &Regex::new("nope").unwrap(), 2).is_empty());
+
+        // more context than there are lines is just all of them
+        assert_eq!(search(&lines, &re, usize::MAX).len(), lines.len());
+    }
+}
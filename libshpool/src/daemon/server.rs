@@ -13,10 +13,12 @@
 // limitations under the License.
 
 use std::{
-    collections::HashMap,
-    env, fs, io, net,
-    ops::Add,
-    os,
+    collections::HashSet,
+    env,
+    ffi::CString,
+    fs,
+    io::{self, Read},
+    net, os,
     os::unix::{
         fs::PermissionsExt,
         net::{UnixListener, UnixStream},
@@ -24,24 +26,36 @@ use std::{
     },
     path::{Path, PathBuf},
     process,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     thread, time,
     time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context};
 use nix::unistd;
-use tracing::{error, info, instrument, span, trace, warn, Level};
+use tracing::{error, info, instrument, span, warn, Level};
 
 use crate::{
-    config,
+    audit, config,
     config::MotdDisplayMode,
     consts,
     daemon::{
-        etc_environment, exit_notify::ExitNotifier, hooks, pager::PagerError, prompt, shell,
-        show_motd, ttl_reaper,
+        acl, activity, affinity, attach_auth, cast, ctl, etc_environment, events,
+        exit_notify::ExitNotifier,
+        holder, hook_commands, hooks, keybindings, limits, lock, metrics, multi_user, nsenter,
+        pager::PagerError,
+        pam_session,
+        paste_buffers::PasteBuffers,
+        prompt, reexec, registry, resurrect, search,
+        session_env::{self, SessionEnv},
+        shell, show_motd,
+        socket_file::SocketFile,
+        spill, status_file, supervisor, term_compat, ttl_reaper, utmp, CustomActions,
     },
-    protocol, test_hooks, tty, user,
+    duration, job, paths, protocol, pty, test_hooks, timing, tty, user,
 };
 
 const DEFAULT_INITIAL_SHELL_PATH: &str = "/usr/bin:/bin:/usr/sbin:/sbin";
@@ -51,32 +65,143 @@ const DEFAULT_PROMPT_PREFIX: &str = "shpool:$SHPOOL_SESSION_NAME ";
 // Half a second should be more than enough time to handle any resize or
 // or detach. If things are taking longer, we can't afford to keep waiting
 // for the reader thread since session message calls are made with the
-// global session table lock held.
+// session's shard of the session table locked.
 const SESSION_MSG_TIMEOUT: time::Duration = time::Duration::from_millis(500);
 
+// Telling an attached client about a shutdown means waiting for the
+// notice to get written out, which can take a while if the client is
+// behind on output.
+const SHUTDOWN_ACK_TIMEOUT: time::Duration = time::Duration::from_secs(3);
+
+// How often a `shpool wait` connection checks whether the client has
+// given up on waiting.
+const WAIT_HANGUP_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+// How long to wait for a killed shell to get reaped before restarting
+// anyway. After a restart nothing would be left to reap it.
+const RESTART_REAP_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
 pub struct Server {
     config: config::Manager,
     /// A map from shell session names to session descriptors.
-    /// We wrap this in Arc<Mutex<_>> so that we can get at the
+    /// We wrap this in an Arc so that we can get at the
     /// table from different threads such as the SIGWINCH thread
     /// that is spawned during the attach process, and so that
     /// handle_conn can delegate to worker threads and quickly allow
     /// the main thread to become available to accept new connections.
-    shells: Arc<Mutex<HashMap<String, Box<shell::Session>>>>,
+    shells: Arc<registry::Sessions>,
     runtime_dir: PathBuf,
     register_new_reapable_session: crossbeam_channel::Sender<(String, Instant)>,
-    hooks: Box<dyn hooks::Hooks + Send + Sync>,
+    hooks: Arc<dyn hooks::Hooks + Send + Sync>,
     daily_messenger: Arc<show_motd::DailyMessenger>,
+    /// Everything copied in copy mode, in any session, and whatever
+    /// got loaded with `shpool load-buffer`.
+    paste_buffers: Arc<Mutex<PasteBuffers>>,
+    /// What session shells get spawned with.
+    pty_backend: Box<dyn pty::PtyBackend + Send + Sync>,
+    /// Actions the embedding binary registered for keybindings to run.
+    custom_actions: Arc<CustomActions>,
+    /// The socket we serve on, which the holder socket sits next to.
+    socket: PathBuf,
+    /// The socket file to clean up on shutdown, unless someone else
+    /// (systemd) owns it.
+    socket_file: Option<SocketFile>,
+    /// The name of the daemon instance, if it has one.
+    instance: Option<String>,
+    /// The saved session definitions for `shpool resurrect`.
+    definitions: Arc<resurrect::Store>,
+    /// The id of the last connection, counted across the socket and
+    /// any connections handed off by the multi-user daemon.
+    conn_counter: AtomicUsize,
+}
+
+/// What a `Server` gets set up with.
+pub struct ServerOptions {
+    pub config: config::Manager,
+    pub hooks: Box<dyn hooks::Hooks + Send + Sync>,
+    pub pty_backend: Box<dyn pty::PtyBackend + Send + Sync>,
+    pub custom_actions: CustomActions,
+    pub runtime_dir: PathBuf,
+    /// The socket we serve on.
+    pub socket: PathBuf,
+    /// The socket file to clean up on shutdown, if we own it.
+    pub socket_file: Option<SocketFile>,
+    pub instance: Option<String>,
+}
+
+/// The child of a session whose threads are about to get started up.
+struct RunningChild {
+    pty: Arc<dyn pty::Pty + Send + Sync>,
+    term: Option<String>,
+    term_db: Arc<termini::TermInfo>,
+    /// The child is something other than a shell we injected the
+    /// prompt prefix into, so there is no prompt sentinel to wait for.
+    custom_cmd: bool,
+    recorder: Option<audit::Recorder>,
+    /// Cleaned up once the child exits.
+    login_records: LoginRecords,
+    started_at: time::SystemTime,
+}
+
+/// What a new session gets created from.
+struct NewSession<'a> {
+    conn_id: usize,
+    /// The client the session starts out attached to, if any.
+    client_stream: Option<UnixStream>,
+    header: &'a protocol::AttachHeader,
+    cwd: Option<&'a str>,
+    term: Option<&'a str>,
+    /// The pid whose namespaces to start the session in.
+    nsenter_pid: Option<i32>,
+    dump_motd: bool,
+}
+
+impl<'a> NewSession<'a> {
+    /// A new session as asked for with a client's attach options.
+    fn from_options(
+        conn_id: usize,
+        client_stream: Option<UnixStream>,
+        header: &'a protocol::AttachHeader,
+        options: &'a [protocol::AttachOption],
+        dump_motd: bool,
+    ) -> Self {
+        NewSession {
+            conn_id,
+            client_stream,
+            header,
+            cwd: cwd_option(options),
+            term: term_option(options),
+            nsenter_pid: nsenter_option(options),
+            dump_motd,
+        }
+    }
+}
+
+/// The ways a session's shell got registered as a login with the rest
+/// of the system, which get undone once the shell exits.
+#[derive(Debug, Default)]
+struct LoginRecords {
+    // Both are only held so that dropping them undoes the login.
+    #[allow(dead_code)]
+    utmp: Option<utmp::Record>,
+    #[allow(dead_code)]
+    pam_session: Option<pam_session::Session>,
 }
 
 impl Server {
     #[instrument(skip_all)]
-    pub fn new(
-        config: config::Manager,
-        hooks: Box<dyn hooks::Hooks + Send + Sync>,
-        runtime_dir: PathBuf,
-    ) -> anyhow::Result<Arc<Self>> {
-        let shells = Arc::new(Mutex::new(HashMap::new()));
+    pub fn new(options: ServerOptions) -> anyhow::Result<Arc<Self>> {
+        let ServerOptions {
+            config,
+            hooks,
+            pty_backend,
+            custom_actions,
+            runtime_dir,
+            socket,
+            socket_file,
+            instance,
+        } = options;
+        let shells = Arc::new(registry::Sessions::default());
         // buffered so that we are unlikely to block when setting up a
         // new session
         let (new_sess_tx, new_sess_rx) = crossbeam_channel::bounded(10);
@@ -91,33 +216,32 @@ impl Server {
             config.get().motd.clone().unwrap_or_default(),
             config.get().motd_args.clone(),
         )?);
+        let definitions = Arc::new(resurrect::Store::new(config.clone(), instance.clone()));
         Ok(Arc::new(Server {
             config,
             shells,
             runtime_dir,
             register_new_reapable_session: new_sess_tx,
-            hooks,
+            hooks: Arc::from(hooks),
             daily_messenger,
+            paste_buffers: Arc::new(Mutex::new(PasteBuffers::default())),
+            pty_backend,
+            custom_actions: Arc::new(custom_actions),
+            socket,
+            socket_file,
+            instance,
+            definitions,
+            conn_counter: AtomicUsize::new(0),
         }))
     }
 
     #[instrument(skip_all)]
     pub fn serve(server: Arc<Self>, listener: UnixListener) -> anyhow::Result<()> {
         test_hooks::emit("daemon-about-to-listen");
-        let mut conn_counter = 0;
         for stream in listener.incoming() {
             info!("socket got a new connection");
             match stream {
-                Ok(stream) => {
-                    conn_counter += 1;
-                    let conn_id = conn_counter;
-                    let server = Arc::clone(&server);
-                    thread::spawn(move || {
-                        if let Err(err) = server.handle_conn(stream, conn_id) {
-                            error!("handling new connection: {:?}", err)
-                        }
-                    });
-                }
+                Ok(stream) => Self::spawn_conn(&server, stream),
                 Err(err) => {
                     error!("accepting stream: {:?}", err);
                 }
@@ -127,27 +251,84 @@ impl Server {
         Ok(())
     }
 
+    /// Serve the connections that the multi-user daemon hands off to
+    /// us, until it goes away.
+    #[instrument(skip_all)]
+    pub fn serve_handoff(server: Arc<Self>, handoff: UnixStream) -> anyhow::Result<()> {
+        while let Some(stream) = multi_user::recv_conn(&handoff)? {
+            info!("got a handed off connection");
+            Self::spawn_conn(&server, stream);
+        }
+        info!("multi-user daemon hung up");
+
+        Ok(())
+    }
+
+    fn spawn_conn(server: &Arc<Self>, stream: UnixStream) {
+        metrics::inc(&metrics::METRICS.connections, 1);
+        let conn_id = server.conn_counter.fetch_add(1, Ordering::Relaxed) + 1;
+        let server = Arc::clone(server);
+        thread::spawn(move || {
+            if let Err(err) = server.handle_conn(stream, conn_id) {
+                error!("handling new connection: {:?}", err)
+            }
+        });
+    }
+
     #[instrument(skip_all, fields(cid = conn_id))]
     fn handle_conn(&self, mut stream: UnixStream, conn_id: usize) -> anyhow::Result<()> {
+        let header = self.read_connect_header(&mut stream)?;
+        self.dispatch(stream, conn_id, header)
+    }
+
+    /// Shake hands with a new client and read the header saying what it
+    /// wants.
+    fn read_connect_header(
+        &self,
+        stream: &mut UnixStream,
+    ) -> anyhow::Result<protocol::ConnectHeader> {
         // We want to avoid timing out while blocking the main thread.
         stream
             .set_read_timeout(Some(consts::SOCK_STREAM_TIMEOUT))
             .context("setting read timout on inbound session")?;
 
-        let header = parse_connect_header(&mut stream).context("parsing connect header")?;
-
-        if let Err(err) = check_peer(&stream) {
-            if let protocol::ConnectHeader::Attach(_) = header {
-                write_reply(
-                    &mut stream,
-                    protocol::AttachReplyHeader {
-                        status: protocol::AttachStatus::Forbidden(format!("{:?}", err)),
-                    },
-                )?;
+        // A hello that doesn't even parse still gets an answer, so that
+        // the client can say what went wrong rather than just seeing the
+        // daemon hang up.
+        let hello: anyhow::Result<protocol::ClientHello> = protocol::read_hello(stream);
+        let mut reply = match &hello {
+            Ok(hello) => protocol::negotiate(hello),
+            Err(_) => protocol::DaemonHello {
+                version: protocol::VERSION,
+                capabilities: vec![],
+                error: Some(protocol::HandshakeError::BadHello),
+            },
+        };
+        // Check who is on the other end before reading anything more than
+        // the hello, so that a peer we don't trust can't get the daemon to
+        // parse anything interesting, but still gets told why it was
+        // turned away.
+        if reply.error.is_none() {
+            if let Err(err) = check_peer(stream, self.config.get().allowed_peers.as_ref()) {
+                reply.capabilities.clear();
+                reply.error = Some(protocol::HandshakeError::Forbidden(format!("{:#}", err)));
             }
+        }
+        // The hello is the one message that never gets framed, so that
+        // clients speaking any protocol version can read it.
+        stream
+            .set_write_timeout(Some(consts::SOCK_STREAM_TIMEOUT))
+            .context("setting write timout on inbound session")?;
+        bincode::serialize_into(&mut *stream, &reply).context("writing daemon hello")?;
+        stream.set_write_timeout(None).context("unsetting write timout on inbound session")?;
+        if let Some(err) = reply.error {
             stream.shutdown(net::Shutdown::Both).context("closing stream")?;
-            return Err(err);
-        };
+            hello.context("parsing client hello")?;
+            return Err(err).context("protocol handshake");
+        }
+
+        let mut header = parse_connect_header(stream).context("parsing connect header")?;
+        clamp_tty_sizes(&mut header);
 
         // Unset the read timeout before we pass things off to a
         // worker thread because it is perfectly fine for there to
@@ -155,48 +336,144 @@ impl Server {
         // is connected to a shell session.
         stream.set_read_timeout(None).context("unsetting read timout on inbound session")?;
 
+        Ok(header)
+    }
+
+    /// Hand the connection off to the handler for what the client
+    /// asked for.
+    fn dispatch(
+        &self,
+        stream: UnixStream,
+        conn_id: usize,
+        header: protocol::ConnectHeader,
+    ) -> anyhow::Result<()> {
         match header {
-            protocol::ConnectHeader::Attach(h) => self.handle_attach(stream, conn_id, h),
+            protocol::ConnectHeader::Attach(h) => self.handle_attach(stream, conn_id, h, vec![]),
+            protocol::ConnectHeader::AttachWithOptions(h, o) => {
+                self.handle_attach(stream, conn_id, h, o)
+            }
             protocol::ConnectHeader::Detach(r) => self.handle_detach(stream, r),
             protocol::ConnectHeader::Kill(r) => self.handle_kill(stream, r),
+            protocol::ConnectHeader::Keybind(r) => self.handle_keybind(stream, r),
+            protocol::ConnectHeader::Gc(r) => self.handle_gc(stream, r),
             protocol::ConnectHeader::List => self.handle_list(stream),
+            protocol::ConnectHeader::Metrics => self.handle_metrics(stream),
+            protocol::ConnectHeader::Reset(r) => self.handle_reset(stream, r),
+            protocol::ConnectHeader::Alerts => self.handle_alerts(stream),
+            protocol::ConnectHeader::Activity => self.handle_activity(stream),
+            protocol::ConnectHeader::Paste => self.handle_paste(stream),
+            protocol::ConnectHeader::SaveOutput(r) => self.handle_save_output(stream, r),
+            protocol::ConnectHeader::SetEnv(r) => self.handle_setenv(stream, r),
+            protocol::ConnectHeader::GetEnv(r) => self.handle_getenv(stream, r),
+            protocol::ConnectHeader::Create(h) => self.handle_create(stream, conn_id, h, vec![]),
+            protocol::ConnectHeader::CreateWithOptions(h, o) => {
+                self.handle_create(stream, conn_id, h, o)
+            }
+            protocol::ConnectHeader::Cwd => self.handle_cwd(stream),
+            protocol::ConnectHeader::Jobs(name) => self.handle_jobs(stream, name),
+            protocol::ConnectHeader::SwitchJob(r) => self.handle_switch_job(stream, r),
+            protocol::ConnectHeader::Wait(r) => self.handle_wait(stream, r),
+            protocol::ConnectHeader::SendInput(r) => self.handle_send_input(stream, r),
+            protocol::ConnectHeader::Capture(r) => self.handle_capture(stream, r),
+            protocol::ConnectHeader::Stop(r) => self.handle_stop(stream, r),
+            protocol::ConnectHeader::Version => self.handle_version(stream),
+            protocol::ConnectHeader::Restart(r) => self.handle_restart(stream, r),
+            protocol::ConnectHeader::Record(r) => self.handle_record(stream, r),
+            protocol::ConnectHeader::Resurrect(r) => self.handle_resurrect(stream, conn_id, r),
+            protocol::ConnectHeader::Acl(r) => self.handle_acl(stream, r),
+            protocol::ConnectHeader::Clear(r) => self.handle_clear(stream, r),
+            protocol::ConnectHeader::Events => self.handle_events(stream),
+            protocol::ConnectHeader::LoadBuffer(r) => self.handle_load_buffer(stream, r),
+            protocol::ConnectHeader::ShowBuffer(r) => self.handle_show_buffer(stream, r),
+            protocol::ConnectHeader::PasteBuffer(r) => self.handle_paste_buffer(stream, r),
+            protocol::ConnectHeader::Search(r) => self.handle_search(stream, r),
             protocol::ConnectHeader::SessionMessage(header) => {
                 self.handle_session_message(stream, header)
             }
         }
     }
 
+    /// Run the attach_auth command, if there is one, for a peer that
+    /// wants to read from or write to the given session, returning the
+    /// reason to give the peer if it gets turned away.
+    fn check_attach_auth(&self, peer: &attach_auth::Peer, session: &str) -> Result<(), String> {
+        let attach_auth = self.config.get().attach_auth.clone();
+        match attach_auth {
+            Some(attach_auth) => attach_auth::check(&attach_auth, session, peer),
+            None => Ok(()),
+        }
+    }
+
+    /// Like check_attach_auth, for the peer on the other end of the
+    /// stream.
+    fn check_peer_attach_auth(&self, stream: &UnixStream, session: &str) -> Result<(), String> {
+        let peer = peer_creds(stream).map_err(|e| format!("{:#}", e))?;
+        self.check_attach_auth(&peer, session)
+    }
+
     #[instrument(skip_all)]
     fn handle_attach(
         &self,
         mut stream: UnixStream,
         conn_id: usize,
-        header: protocol::AttachHeader,
+        mut header: protocol::AttachHeader,
+        mut options: Vec<protocol::AttachOption>,
     ) -> anyhow::Result<()> {
-        // We don't currently populate any warnings, but we used to and we might
-        // want to in the future, so it is not worth breaking the protocol over.
-        let warnings = vec![];
+        let attach_start = Instant::now();
+        let mut timer = timing::Timer::new();
+        let send_timings = options.contains(&protocol::AttachOption::Timings);
+        let prompt_hints = options.contains(&protocol::AttachOption::PromptHints);
+        let replay = replay_option(&options);
+        let dumb_term = header.local_env_get("TERM") == Some("dumb");
+        let mut warnings = if dumb_term { self.dumb_term_warnings() } else { vec![] };
+        if let Some(warning) = self.replay_warning(replay) {
+            warnings.push(warning);
+        }
+
+        let client_pid = peer_pid(&stream);
+        let peer = peer_creds(&stream).context("getting peer creds")?;
+        if let Err(reason) = self.check_attach_auth(&peer, &header.name) {
+            write_reply(
+                &mut stream,
+                protocol::AttachReplyHeader { status: protocol::AttachStatus::Forbidden(reason) },
+            )?;
+            stream.shutdown(net::Shutdown::Both).context("closing stream")?;
+            return Ok(());
+        }
+        timer.phase("attach auth");
 
-        let (child_exit_notifier, inner_to_stream, pager_ctl_slot, status) = {
-            // we unwrap to propagate the poison as an unwind
-            let mut shells = self.shells.lock().unwrap();
-            info!("locked shells table");
+        let (child_exit_notifier, inner_to_stream, pager_ctl_slot, status_file, status, info) = {
+            let mut shells = self.shells.shard(&header.name);
+            info!("locked shells table shard");
+            timer.phase("table lock");
 
             let mut status = protocol::AttachStatus::Attached { warnings: warnings.clone() };
             if let Some(session) = shells.get(&header.name) {
                 info!("found entry for '{}'", header.name);
+                if session.acl.lock().unwrap().access(peer.uid).is_none() {
+                    info!("uid {} is not on the acl of '{}'", peer.uid, header.name);
+                    write_reply(
+                        &mut stream,
+                        protocol::AttachReplyHeader {
+                            status: protocol::AttachStatus::Forbidden(format!(
+                                "you may not attach to '{}'",
+                                header.name
+                            )),
+                        },
+                    )?;
+                    stream.shutdown(net::Shutdown::Both).context("closing stream")?;
+                    return Ok(());
+                }
                 if let Ok(mut inner) = session.inner.try_lock() {
                     info!("session '{}': locked inner", header.name);
                     // We have an existing session in our table, but the subshell
                     // proc might have exited in the meantime, for example if the
-                    // user typed `exit` right before the connection dropped there
-                    // could be a zombie entry in our session table. We need to
-                    // re-check whether the subshell has exited before taking this over.
-                    //
-                    // N.B. this is still technically a race, but in practice it does
-                    // not ever cause problems, and there is no real way to avoid some
-                    // sort of race without just always creating a new session when
-                    // a shell exits, which would break `exit` typed at the shell prompt.
+                    // user typed `exit` right before the connection dropped or
+                    // a custom command finished while detached. We need to
+                    // re-check whether the subshell has exited before taking this
+                    // over, and if it has, report the exit status to this client
+                    // and reap the session so that the next attach gets a fresh one,
+                    // unless the client asked for the command to be started over.
                     match session.child_exit_notifier.wait(Some(time::Duration::from_millis(0))) {
                         None => {
                             // the channel is still open so the subshell is still running
@@ -217,13 +494,28 @@ impl Server {
 
                             // status is already attached
                         }
+                        Some(exit_status) if options.contains(&protocol::AttachOption::Restart) => {
+                            info!(
+                                "stale inner={:?}, (child exited with status {}) restarting session",
+                                inner, exit_status
+                            );
+                            if header.cmd.is_none() {
+                                header.cmd = session.cmd.clone();
+                            }
+                            if term_option(&options).is_none() {
+                                options.extend(
+                                    session.term_override.clone().map(protocol::AttachOption::Term),
+                                );
+                            }
+                            status = protocol::AttachStatus::Created { warnings: warnings.clone() };
+                        }
                         Some(exit_status) => {
                             // the channel is closed so we know the subshell exited
                             info!(
-                                "stale inner={:?}, (child exited with status {}) clobbering with new subshell",
+                                "stale inner={:?}, (child exited with status {}) reaping session",
                                 inner, exit_status
                             );
-                            status = protocol::AttachStatus::Created { warnings };
+                            status = protocol::AttachStatus::Exited { exit_status };
                         }
                     }
 
@@ -234,7 +526,11 @@ impl Server {
                                 .map_err(|e| anyhow!("joining reader on reattach: {:?}", e))?
                                 .context("within reader thread on reattach")?;
                         }
-                        assert!(matches!(status, protocol::AttachStatus::Created { .. }));
+                        assert!(matches!(
+                            status,
+                            protocol::AttachStatus::Created { .. }
+                                | protocol::AttachStatus::Exited { .. }
+                        ));
                     }
 
                     // fallthrough to bidi streaming
@@ -256,22 +552,68 @@ impl Server {
                 status = protocol::AttachStatus::Created { warnings };
             }
 
+            if let protocol::AttachStatus::Exited { exit_status } = status {
+                let screen = if options.contains(&protocol::AttachOption::ExitedScreen) {
+                    shells.get(&header.name).and_then(|s| final_screen(s))
+                } else {
+                    None
+                };
+                shells.remove(&header.name);
+                let status = match screen {
+                    Some(screen) => {
+                        protocol::AttachStatus::ExitedWithScreen { exit_status, screen }
+                    }
+                    None => status,
+                };
+                write_reply(&mut stream, protocol::AttachReplyHeader { status })?;
+                stream.shutdown(net::Shutdown::Both).context("closing stream")?;
+                return Ok(());
+            }
+            timer.phase("lookup");
+
             if matches!(status, protocol::AttachStatus::Created { .. }) {
                 use config::MotdDisplayMode;
 
-                info!("creating new subshell");
-                if let Err(err) = self.hooks.on_new_session(&header.name) {
-                    warn!("new_session hook: {:?}", err);
+                if peer.uid != unistd::getuid().as_raw() {
+                    info!("uid {} may not create '{}'", peer.uid, header.name);
+                    write_reply(
+                        &mut stream,
+                        protocol::AttachReplyHeader {
+                            status: protocol::AttachStatus::Forbidden(owner_only(
+                                "create sessions",
+                            )),
+                        },
+                    )?;
+                    stream.shutdown(net::Shutdown::Both).context("closing stream")?;
+                    return Ok(());
                 }
+
                 let motd = self.config.get().motd.clone().unwrap_or_default();
-                let session = self.spawn_subshell(
+                // a dumb terminal can't run a pager, so just print the motd
+                let dump_motd = match motd {
+                    MotdDisplayMode::Dump => true,
+                    MotdDisplayMode::Pager { .. } => dumb_term,
+                    MotdDisplayMode::Never => false,
+                };
+                let client_stream = stream.try_clone().context("cloning client stream")?;
+                let new = NewSession::from_options(
                     conn_id,
-                    stream,
+                    Some(client_stream),
                     &header,
-                    matches!(motd, MotdDisplayMode::Dump),
-                )?;
-
-                shells.insert(header.name.clone(), Box::new(session));
+                    &options,
+                    dump_motd,
+                );
+                if let Err(reason) = self.create_session(&mut shells, new)? {
+                    write_reply(
+                        &mut stream,
+                        protocol::AttachReplyHeader {
+                            status: protocol::AttachStatus::Forbidden(reason),
+                        },
+                    )?;
+                    stream.shutdown(net::Shutdown::Both).context("closing stream")?;
+                    return Ok(());
+                }
+                timer.phase("spawn");
                 // fallthrough to bidi streaming
             } else if let Err(err) = self.hooks.on_reattach(&header.name) {
                 warn!("reattach hook: {:?}", err);
@@ -281,25 +623,58 @@ impl Server {
             // we can work with it without the global session
             // table lock held
             if let Some(session) = shells.get(&header.name) {
+                // done with the table locked so that a racing setenv
+                // can't clobber the file with a stale view of the env
+                self.write_forward_env(&header, &session.env).context("writing forwarded env")?;
+                // a TERM pinned in the config is what the user wants the
+                // session to use no matter where they attach from
+                let term_pinned =
+                    self.config.get().env.as_ref().map(|e| e.contains_key("TERM")).unwrap_or(false);
+                let mut status = status;
+                if let (protocol::AttachStatus::Attached { warnings }, false) =
+                    (&mut status, term_pinned)
+                {
+                    warnings.extend(term_compat::check(
+                        session.term.as_deref(),
+                        header.local_env_get("TERM"),
+                    ));
+                }
+                let attaches = session.attaches.fetch_add(1, Ordering::Relaxed);
+                let info = if options.contains(&protocol::AttachOption::Info) {
+                    Some(protocol::AttachInfo {
+                        host: config::hostname().unwrap_or_default(),
+                        started_at_unix_ms: session
+                            .started_at
+                            .duration_since(time::UNIX_EPOCH)
+                            .map(|d| d.as_millis() as i64)
+                            .unwrap_or(0),
+                        attaches,
+                    })
+                } else {
+                    None
+                };
                 (
                     Some(Arc::clone(&session.child_exit_notifier)),
                     Some(Arc::clone(&session.inner)),
                     Some(Arc::clone(&session.pager_ctl)),
+                    Some(Arc::clone(&session.status_file)),
                     status,
+                    info,
                 )
             } else {
-                (None, None, None, status)
+                (None, None, None, None, status, None)
             }
         };
-        info!("released lock on shells table");
+        info!("released lock on shells table shard");
 
         self.link_ssh_auth_sock(&header).context("linking SSH_AUTH_SOCK")?;
 
-        if let (Some(child_exit_notifier), Some(inner), Some(pager_ctl_slot)) =
-            (child_exit_notifier, inner_to_stream, pager_ctl_slot)
+        if let (Some(child_exit_notifier), Some(inner), Some(pager_ctl_slot), Some(status_file)) =
+            (child_exit_notifier, inner_to_stream, pager_ctl_slot, status_file)
         {
             let mut child_done = false;
             let mut inner = inner.lock().unwrap();
+            let recorded = inner.recorder.is_some();
             let client_stream = match inner.client_stream.as_mut() {
                 Some(s) => s,
                 None => {
@@ -307,17 +682,43 @@ impl Server {
                 }
             };
 
+            let mut status = status;
+            if recorded {
+                if let protocol::AttachStatus::Attached { warnings }
+                | protocol::AttachStatus::Created { warnings } = &mut status
+                {
+                    warnings.push(String::from(audit::WARNING));
+                }
+            }
+
+            timer.phase("setup");
             let reply_status =
                 write_reply(client_stream, protocol::AttachReplyHeader { status: status.clone() });
             if let Err(e) = reply_status {
                 error!("error writing reply status: {:?}", e);
             }
+            if let Some(info) = info {
+                if let Err(e) = write_reply(client_stream, info) {
+                    warn!("sending attach info: {:?}", e);
+                }
+            }
+            metrics::METRICS.observe_attach_latency(attach_start.elapsed());
+            // nothing else is writing to the stream until the bidi
+            // stream gets going, so this can't get tangled up in output
+            if send_timings {
+                if let Err(e) =
+                    protocol::StreamControl::Timings(timer.take()).write_to(client_stream)
+                {
+                    warn!("sending attach timings: {:?}", e);
+                }
+            }
 
             // If in pager motd mode, launch the pager and block until it is
             // done, picking up any tty size change that happened while the
             // user was examining the motd.
             let motd_mode = self.config.get().motd.clone().unwrap_or_default();
-            let init_tty_size = if matches!(motd_mode, MotdDisplayMode::Pager { .. }) {
+            let init_tty_size = if matches!(motd_mode, MotdDisplayMode::Pager { .. }) && !dumb_term
+            {
                 match self.daily_messenger.display_in_pager(
                     client_stream,
                     pager_ctl_slot,
@@ -341,8 +742,34 @@ impl Server {
                 header.local_tty_size.clone()
             };
 
+            status_file.lock().unwrap().update(|s| {
+                s.attached = true;
+                s.tty_size = Some(init_tty_size.clone());
+                s.client = Some(status_file::Client {
+                    pid: client_pid,
+                    term: header.local_env_get("TERM").map(String::from),
+                });
+            });
+            hook_commands::fire(&self.config, hook_commands::Event::Attach, &header.name);
+            let on_attach_input = if matches!(status, protocol::AttachStatus::Attached { .. }) {
+                self.on_attach_input(&inner, on_attach_cmd_option(&options))
+            } else {
+                None
+            };
+
             info!("starting bidi stream loop");
-            match inner.bidi_stream(conn_id, init_tty_size, child_exit_notifier) {
+            let attached_client = self.shells.attach_client();
+            let attaching = shell::AttachingClient {
+                conn_id,
+                uid: peer.uid,
+                tty_size: init_tty_size,
+                dumb_term,
+                replay,
+                send_timings,
+                prompt_hints,
+                on_attach_input,
+            };
+            match inner.bidi_stream(attaching, child_exit_notifier) {
                 Ok(done) => {
                     child_done = done;
                 }
@@ -351,27 +778,34 @@ impl Server {
                 }
             }
             info!("bidi stream loop finished");
+            drop(attached_client);
 
             if child_done {
                 info!("'{}' exited, removing from session table", header.name);
                 if let Err(err) = self.hooks.on_shell_disconnect(&header.name) {
                     warn!("shell_disconnect hook: {:?}", err);
                 }
-                let mut shells = self.shells.lock().unwrap();
-                shells.remove(&header.name);
+                self.shells.shard(&header.name).remove(&header.name);
 
                 // The child shell has exited, so the reader thread should
                 // attempt to read from its stdout and get an error, causing
                 // it to exit. That means we should be safe to join. We use
-                // a separate if statement to avoid holding the shells lock
+                // a separate statement to avoid holding the shells lock
                 // while we join the old thread.
                 if let Some(h) = inner.reader_join_h.take() {
                     h.join()
                         .map_err(|e| anyhow!("joining reader after child exit: {:?}", e))?
                         .context("within reader thread after child exit")?;
                 }
-            } else if let Err(err) = self.hooks.on_client_disconnect(&header.name) {
-                warn!("client_disconnect hook: {:?}", err);
+            } else {
+                status_file.lock().unwrap().update(|s| {
+                    s.attached = false;
+                    s.client = None;
+                });
+                if let Err(err) = self.hooks.on_client_disconnect(&header.name) {
+                    warn!("client_disconnect hook: {:?}", err);
+                }
+                hook_commands::fire(&self.config, hook_commands::Event::Detach, &header.name);
             }
 
             info!("finished attach streaming section");
@@ -382,6 +816,262 @@ impl Server {
         Ok(())
     }
 
+    /// The input for typing the on-attach command into a session that
+    /// just got reattached to, so long as its shell looks like it is
+    /// sitting at its prompt or the user has asked for it to be typed
+    /// regardless.
+    fn on_attach_input(&self, inner: &shell::SessionInner, cmd: Option<&str>) -> Option<Vec<u8>> {
+        let (cmd, always) = {
+            let config = self.config.get();
+            let cmd = match cmd.or(config.on_attach_cmd.as_deref()) {
+                Some(cmd) if !cmd.is_empty() => String::from(cmd),
+                _ => return None,
+            };
+            (cmd, config.on_attach_cmd_always.unwrap_or(false))
+        };
+        if inner.custom_cmd {
+            info!("'{}' runs a custom command, not typing on-attach command", inner.name);
+            return None;
+        }
+        if !always {
+            match inner.pty.at_prompt() {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!("'{}' is busy, not typing on-attach command", inner.name);
+                    return None;
+                }
+                Err(e) => {
+                    warn!("checking if '{}' is at a prompt: {:?}", inner.name, e);
+                    return None;
+                }
+            }
+        }
+
+        let mut input = cmd.into_bytes();
+        // a terminal sends a carriage return for the enter key
+        input.push(b'\r');
+        Some(input)
+    }
+
+    /// Create a new session and add it to the given session table,
+    /// clobbering any stale entry with the same name. The session
+    /// starts out attached to client_stream if one is given. If the
+    /// session is not allowed, the reason is returned as the inner
+    /// error.
+    fn create_session(
+        &self,
+        shells: &mut registry::Shard<'_, Box<shell::Session>>,
+        new: NewSession<'_>,
+    ) -> anyhow::Result<Result<(), String>> {
+        let NewSession { header, cwd, term, nsenter_pid, .. } = new;
+        // a stale entry for this session is about to get clobbered,
+        // so it does not count against the limit. Sessions in other
+        // shards can come and go while this one is being created, so
+        // a burst of creates can overshoot the limit by a little.
+        if let Err(reason) = check_session_name(&header.name) {
+            return Ok(Err(reason));
+        }
+        let num_sessions =
+            self.shells.count().saturating_sub(usize::from(shells.contains_key(&header.name)));
+        if let Err(reason) = self.admit_new_session(&header.name, num_sessions) {
+            return Ok(Err(reason));
+        }
+
+        let nsenter = match nsenter_pid.map(nsenter::Target::new) {
+            Some(Ok(target)) => Some(target),
+            Some(Err(err)) => {
+                warn!("looking up namespaces: {:?}", err);
+                return Ok(Err(format!("could not enter namespaces: {:#}", err)));
+            }
+            None => None,
+        };
+
+        // The child would fail to start in a missing directory, long
+        // after there is any good way to tell the client about it.
+        if let Some(cwd) = cwd {
+            let path = match &nsenter {
+                Some(target) => target.root().join(cwd.trim_start_matches('/')),
+                None => PathBuf::from(cwd),
+            };
+            if !path.is_dir() {
+                return Ok(Err(format!("{} is not a directory", cwd)));
+            }
+        }
+
+        // Sessions must not go unrecorded when auditing is turned on,
+        // so refuse to create the session if recording can't start.
+        let audit_config = self.config.get().session_audit.clone();
+        let recorder = match audit_config {
+            Some(audit_config) => match paths::state_dir(&self.config.get()).and_then(|state_dir| {
+                audit::Recorder::create(&audit_config, &state_dir, &header.name)
+            }) {
+                Ok(recorder) => {
+                    info!("recording session to {:?}", recorder.path);
+                    Some(recorder)
+                }
+                Err(err) => {
+                    error!("starting session recording: {:?}", err);
+                    return Ok(Err(format!("could not start session recording: {:#}", err)));
+                }
+            },
+            None => None,
+        };
+
+        // an old session by the same name may have left its acl behind
+        if let Err(err) = acl::remove(&self.acl_path(&header.name)?) {
+            warn!("{:?}", err);
+        }
+
+        info!("creating new subshell");
+        if let Err(err) = self.hooks.on_new_session(&header.name) {
+            warn!("new_session hook: {:?}", err);
+        }
+        let mut session = self.spawn_subshell(new, nsenter, recorder)?;
+        session.term_override = term.map(String::from);
+        metrics::inc(&metrics::METRICS.sessions_created, 1);
+        hook_commands::fire(&self.config, hook_commands::Event::SessionCreate, &header.name);
+
+        // sessions that are not meant to stick around don't come back
+        if header.ttl_secs.is_none()
+            && nsenter_pid.is_none()
+            && job::base_session(&header.name) == header.name
+        {
+            self.definitions.save(self.definitions.definition(
+                &header.name,
+                header.cmd.as_deref(),
+                cwd,
+                term,
+                &header.local_env,
+            ));
+        }
+
+        shells.insert(header.name.clone(), Box::new(session));
+        Ok(Ok(()))
+    }
+
+    /// Create a session without attaching to it, so that it starts
+    /// out in the same state as a session whose client has detached.
+    fn handle_create(
+        &self,
+        mut stream: UnixStream,
+        conn_id: usize,
+        header: protocol::AttachHeader,
+        options: Vec<protocol::AttachOption>,
+    ) -> anyhow::Result<()> {
+        if !peer_is_owner(&stream) {
+            return write_reply(
+                &mut stream,
+                protocol::CreateReply::Forbidden(owner_only("create sessions")),
+            );
+        }
+
+        let reply = {
+            let mut shells = self.shells.shard(&header.name);
+            let running = shells.get(&header.name).map(|session| {
+                // a session whose child exited while detached is just
+                // waiting for an attach to reap it, so it can be replaced
+                session.child_exit_notifier.wait(Some(time::Duration::from_millis(0))).is_none()
+            });
+            if running == Some(true) {
+                protocol::CreateReply::Exists
+            } else {
+                let new = NewSession::from_options(conn_id, None, &header, &options, false);
+                match self.create_session(&mut shells, new)? {
+                    Ok(()) => {
+                        if let Some(session) = shells.get(&header.name) {
+                            start_detached(session)?;
+                            self.write_forward_env(&header, &session.env)
+                                .context("writing forwarded env")?;
+                        }
+                        let mut warnings = vec![];
+                        if self.config.get().session_audit.is_some() {
+                            warnings.push(String::from(audit::WARNING));
+                        }
+                        protocol::CreateReply::Created { warnings }
+                    }
+                    Err(reason) => protocol::CreateReply::Forbidden(reason),
+                }
+            }
+        };
+
+        if let protocol::CreateReply::Created { .. } = reply {
+            info!("created detached session '{}'", header.name);
+            self.link_ssh_auth_sock(&header).context("linking SSH_AUTH_SOCK")?;
+        }
+        write_reply(&mut stream, reply)
+    }
+
+    /// Describe the configured features that get turned off when the
+    /// client terminal is dumb so that the user is not left wondering
+    /// where they went.
+    fn dumb_term_warnings(&self) -> Vec<String> {
+        let config = self.config.get();
+        let mut disabled = vec![];
+        if !matches!(
+            config.session_restore_mode.clone().unwrap_or_default(),
+            config::SessionRestoreMode::Simple
+        ) {
+            disabled.push("session restore");
+        }
+        if matches!(config.motd.clone().unwrap_or_default(), MotdDisplayMode::Pager { .. }) {
+            disabled.push("motd pager");
+        }
+        if config.title_suffix.is_some() {
+            disabled.push("title_suffix");
+        }
+        disabled.push("notification replay");
+
+        vec![format!("TERM is dumb, disabled: {}", disabled.join(", "))]
+    }
+
+    /// The simple restore mode doesn't keep any scrollback, so there is
+    /// nothing to honor a request for more of it with.
+    fn replay_warning(&self, replay: protocol::Replay) -> Option<String> {
+        let simple = matches!(
+            self.config.get().session_restore_mode.clone().unwrap_or_default(),
+            config::SessionRestoreMode::Simple
+        );
+        match replay {
+            protocol::Replay::Lines(_) | protocol::Replay::All if simple => Some(String::from(
+                "session_restore_mode is simple, so there is no output to replay",
+            )),
+            _ => None,
+        }
+    }
+
+    /// Check the session limit from the config, giving the hooks a chance
+    /// to override a rejection. Returns the reason for the rejection
+    /// if the new session should not be created.
+    fn admit_new_session(&self, session_name: &str, num_sessions: usize) -> Result<(), String> {
+        let max_sessions = match self.config.get().max_sessions {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+        if num_sessions < max_sessions {
+            return Ok(());
+        }
+
+        warn!(
+            session = session_name,
+            num_sessions, max_sessions, "new session would exceed session quota"
+        );
+        test_hooks::emit("daemon-session-quota-exceeded");
+        match self.hooks.on_quota_exceeded(session_name, num_sessions, max_sessions) {
+            Ok(true) => {
+                info!("quota_exceeded hook admitted session '{}'", session_name);
+                Ok(())
+            }
+            Ok(false) => Err(format!(
+                "creating session '{}' would exceed the limit of {} sessions",
+                session_name, max_sessions
+            )),
+            Err(err) => {
+                warn!("quota_exceeded hook: {:?}", err);
+                Err(format!("session '{}' rejected: {}", session_name, err))
+            }
+        }
+    }
+
     #[instrument(skip_all)]
     fn link_ssh_auth_sock(&self, header: &protocol::AttachHeader) -> anyhow::Result<()> {
         if self.config.get().nosymlink_ssh_auth_sock.unwrap_or(false) {
@@ -389,26 +1079,27 @@ impl Server {
         }
 
         if let Some(ssh_auth_sock) = header.local_env_get("SSH_AUTH_SOCK") {
-            let symlink = self.ssh_auth_sock_symlink(PathBuf::from(&header.name));
-            fs::create_dir_all(symlink.parent().ok_or(anyhow!("no symlink parent dir"))?)
+            self.ensure_session_dir(&header.name)
                 .context("could not create directory for SSH_AUTH_SOCK symlink")?;
-
-            let sessions_dir =
-                symlink.parent().and_then(|d| d.parent()).ok_or(anyhow!("no sessions dir"))?;
-            let sessions_meta = fs::metadata(sessions_dir).context("stating sessions dir")?;
-
-            // set RWX bits for user and no one else
-            let mut sessions_perm = sessions_meta.permissions();
-            if sessions_perm.mode() != 0o700 {
-                sessions_perm.set_mode(0o700);
-                fs::set_permissions(sessions_dir, sessions_perm)
-                    .context("locking down permissions for sessions dir")?;
+            let symlink = self.ssh_auth_sock_symlink(&header.name)?;
+            if Path::new(ssh_auth_sock) == symlink {
+                info!("client SSH_AUTH_SOCK is already the session symlink, leaving it alone");
+                return Ok(());
             }
 
-            let _ = fs::remove_file(&symlink); // clean up the link if it exists already
-            os::unix::fs::symlink(ssh_auth_sock, &symlink).context(format!(
+            // Build the new link off to the side and then rename it over the
+            // old one so that there is never a window where the session's
+            // SSH_AUTH_SOCK is dangling. Otherwise a `git push` racing with a
+            // reattach could fail.
+            let tmp_symlink = symlink.with_extension("socket.tmp");
+            let _ = fs::remove_file(&tmp_symlink); // clean up any leftovers from a crash
+            os::unix::fs::symlink(ssh_auth_sock, &tmp_symlink).context(format!(
                 "could not symlink '{:?}' to point to '{:?}'",
-                symlink, ssh_auth_sock
+                tmp_symlink, ssh_auth_sock
+            ))?;
+            fs::rename(&tmp_symlink, &symlink).context(format!(
+                "could not move SSH_AUTH_SOCK symlink into place at {:?}",
+                symlink
             ))?;
         } else {
             info!("no SSH_AUTH_SOCK in client env, leaving it unlinked");
@@ -417,6 +1108,24 @@ impl Server {
         Ok(())
     }
 
+    /// Fold the forwarded environment variables from the attach header
+    /// into the session's env record and write it out to a file in the
+    /// session directory as a sequence of `export` statements. We can't
+    /// reach into the environment of a running shell, so this is the best
+    /// we can do on reattach. Users can source the file (for example from
+    /// PROMPT_COMMAND) to pick up fresh values for stuff like DISPLAY.
+    #[instrument(skip_all)]
+    fn write_forward_env(
+        &self,
+        header: &protocol::AttachHeader,
+        env: &Mutex<SessionEnv>,
+    ) -> anyhow::Result<()> {
+        let session_dir = self.ensure_session_dir(&header.name)?;
+        let mut env = env.lock().unwrap();
+        env.update_forwarded(&header.local_env);
+        env.write(&session_dir)
+    }
+
     #[instrument(skip_all)]
     fn handle_detach(
         &self,
@@ -425,34 +1134,27 @@ impl Server {
     ) -> anyhow::Result<()> {
         let mut not_found_sessions = vec![];
         let mut not_attached_sessions = vec![];
+        let mut forbidden_sessions = vec![];
         {
-            trace!("about to lock shells table 3");
-            let shells = self.shells.lock().unwrap();
-            trace!("locked shells table 3");
             for session in request.sessions.into_iter() {
-                if let Some(s) = shells.get(&session) {
-                    let reader_ctl = s.reader_ctl.lock().unwrap();
-                    reader_ctl
-                        .client_connection
-                        .send(shell::ClientConnectionMsg::Disconnect)
-                        .context("sending client detach to reader")?;
-                    let status = reader_ctl
-                        .client_connection_ack
-                        .recv()
-                        .context("getting client conn ack")?;
-                    info!("detached session({}), status = {:?}", session, status);
-                    if let shell::ClientConnectionStatus::DetachNone = status {
-                        not_attached_sessions.push(session);
+                let shells = self.shells.shard(&session);
+                match shells.get(&session).map(|s| (s, peer_access(&stream, &s.acl))) {
+                    Some((s, Some(protocol::Access::ReadWrite))) => {
+                        let status = s.reader_ctl.lock().unwrap().detach()?;
+                        info!("detached session({}), status = {:?}", session, status);
+                        if let shell::ClientConnectionStatus::DetachNone = status {
+                            not_attached_sessions.push(session);
+                        }
                     }
-                } else {
-                    not_found_sessions.push(session);
+                    Some((_, Some(protocol::Access::ReadOnly))) => forbidden_sessions.push(session),
+                    Some((_, None)) | None => not_found_sessions.push(session),
                 }
             }
         }
 
         write_reply(
             &mut stream,
-            protocol::DetachReply { not_found_sessions, not_attached_sessions },
+            protocol::DetachReply { not_found_sessions, not_attached_sessions, forbidden_sessions },
         )
         .context("writing detach reply")?;
 
@@ -460,83 +1162,1449 @@ impl Server {
     }
 
     #[instrument(skip_all)]
-    fn handle_kill(
+    fn handle_reset(
         &self,
         mut stream: UnixStream,
-        request: protocol::KillRequest,
+        request: protocol::ResetRequest,
     ) -> anyhow::Result<()> {
         let mut not_found_sessions = vec![];
+        let mut forbidden_sessions = vec![];
         {
-            let mut shells = self.shells.lock().unwrap();
-
-            let mut to_remove = Vec::with_capacity(request.sessions.len());
             for session in request.sessions.into_iter() {
-                if let Some(s) = shells.get(&session) {
-                    s.kill().context("killing shell proc")?;
-
-                    // we don't need to wait since the dedicated reaping thread is active
-                    // even when a tty is not attached
-                    to_remove.push(session);
-                } else {
-                    not_found_sessions.push(session);
+                let shells = self.shells.shard(&session);
+                match shells.get(&session).map(|s| (s, peer_access(&stream, &s.acl))) {
+                    Some((s, Some(protocol::Access::ReadWrite))) => {
+                        let reader_ctl = s.reader_ctl.lock().unwrap();
+                        reader_ctl
+                            .client_connection
+                            .send_timeout(shell::ClientConnectionMsg::Reset, SESSION_MSG_TIMEOUT)
+                            .context("sending reset to reader")?;
+                        let status = reader_ctl
+                            .client_connection_ack
+                            .recv_timeout(SESSION_MSG_TIMEOUT)
+                            .context("getting client conn ack")?;
+                        info!("reset session({}), status = {:?}", session, status);
+                    }
+                    Some((_, Some(protocol::Access::ReadOnly))) => forbidden_sessions.push(session),
+                    Some((_, None)) | None => not_found_sessions.push(session),
                 }
             }
-
-            for session in to_remove.iter() {
-                shells.remove(session);
-            }
-            if !to_remove.is_empty() {
-                test_hooks::emit("daemon-handle-kill-removed-shells");
-            }
         }
 
-        write_reply(&mut stream, protocol::KillReply { not_found_sessions })
-            .context("writing kill reply")?;
+        write_reply(&mut stream, protocol::ResetReply { not_found_sessions, forbidden_sessions })
+            .context("writing reset reply")?;
 
         Ok(())
     }
 
     #[instrument(skip_all)]
-    fn handle_list(&self, mut stream: UnixStream) -> anyhow::Result<()> {
-        let shells = self.shells.lock().unwrap();
-
-        let sessions: anyhow::Result<Vec<protocol::Session>> = shells
-            .iter()
-            .map(|(k, v)| {
-                let status = match v.inner.try_lock() {
-                    Ok(_) => protocol::SessionStatus::Disconnected,
-                    Err(_) => protocol::SessionStatus::Attached,
-                };
-
-                Ok(protocol::Session {
-                    name: k.to_string(),
-                    started_at_unix_ms: v.started_at.duration_since(time::UNIX_EPOCH)?.as_millis()
-                        as i64,
-                    status,
-                })
-            })
-            .collect();
-        let sessions = sessions.context("collecting running session metadata")?;
+    fn handle_clear(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::ClearRequest,
+    ) -> anyhow::Result<()> {
+        let mut not_found_sessions = vec![];
+        let mut forbidden_sessions = vec![];
+        {
+            for session in request.sessions.into_iter() {
+                let shells = self.shells.shard(&session);
+                match shells.get(&session).map(|s| (s, peer_access(&stream, &s.acl))) {
+                    Some((s, Some(protocol::Access::ReadWrite))) => {
+                        let reader_ctl = s.reader_ctl.lock().unwrap();
+                        reader_ctl
+                            .client_connection
+                            .send_timeout(
+                                shell::ClientConnectionMsg::ClearScrollback,
+                                SESSION_MSG_TIMEOUT,
+                            )
+                            .context("sending clear to reader")?;
+                        let status = reader_ctl
+                            .client_connection_ack
+                            .recv_timeout(SESSION_MSG_TIMEOUT)
+                            .context("getting client conn ack")?;
+                        info!("cleared session({}), status = {:?}", session, status);
+                    }
+                    Some((_, Some(protocol::Access::ReadOnly))) => forbidden_sessions.push(session),
+                    Some((_, None)) | None => not_found_sessions.push(session),
+                }
+            }
+        }
 
-        write_reply(&mut stream, protocol::ListReply { sessions })?;
+        write_reply(&mut stream, protocol::ClearReply { not_found_sessions, forbidden_sessions })
+            .context("writing clear reply")?;
 
         Ok(())
     }
 
-    #[instrument(skip_all, fields(s = &header.session_name))]
-    fn handle_session_message(
+    #[instrument(skip_all)]
+    fn handle_save_output(
         &self,
         mut stream: UnixStream,
-        header: protocol::SessionMessageRequest,
+        request: protocol::SaveOutputRequest,
     ) -> anyhow::Result<()> {
-        // create a slot to store our reply so we can do
+        if let Err(reason) = self.check_peer_attach_auth(&stream, &request.session) {
+            write_reply(&mut stream, protocol::SaveOutputReply::Forbidden(reason))
+                .context("writing save output reply")?;
+            return Ok(());
+        }
+
+        let reply = {
+            let shells = self.shells.shard(&request.session);
+            let session =
+                shells.get(&request.session).filter(|s| peer_access(&stream, &s.acl).is_some());
+            if let Some(s) = session {
+                let reader_ctl = s.reader_ctl.lock().unwrap();
+                reader_ctl
+                    .client_connection
+                    .send_timeout(
+                        shell::ClientConnectionMsg::SaveOutput { strip_ansi: request.strip_ansi },
+                        SESSION_MSG_TIMEOUT,
+                    )
+                    .context("sending save output request to reader")?;
+                let status = reader_ctl
+                    .client_connection_ack
+                    .recv_timeout(SESSION_MSG_TIMEOUT)
+                    .context("getting client conn ack")?;
+                match status {
+                    shell::ClientConnectionStatus::Output(Some(output)) => {
+                        info!("saving {} bytes of output", output.len());
+                        protocol::SaveOutputReply::Output(output)
+                    }
+                    shell::ClientConnectionStatus::Output(None) => {
+                        protocol::SaveOutputReply::NoSpool
+                    }
+                    status => {
+                        return Err(anyhow!("unexpected reader status: {:?}", status));
+                    }
+                }
+            } else {
+                protocol::SaveOutputReply::NotFound
+            }
+        };
+
+        write_reply(&mut stream, reply).context("writing save output reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_search(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::SearchRequest,
+    ) -> anyhow::Result<()> {
+        let re = match regex::RegexBuilder::new(&request.pattern)
+            .case_insensitive(request.ignore_case)
+            .build()
+        {
+            Ok(re) => re,
+            Err(e) => {
+                write_reply(&mut stream, protocol::SearchReply::BadPattern(e.to_string()))
+                    .context("writing search reply")?;
+                return Ok(());
+            }
+        };
+        if let Err(reason) = self.check_peer_attach_auth(&stream, &request.session) {
+            write_reply(&mut stream, protocol::SearchReply::Forbidden(reason))
+                .context("writing search reply")?;
+            return Ok(());
+        }
+
+        let output = {
+            let shells = self.shells.shard(&request.session);
+            let session =
+                shells.get(&request.session).filter(|s| peer_access(&stream, &s.acl).is_some());
+            if let Some(s) = session {
+                let reader_ctl = s.reader_ctl.lock().unwrap();
+                reader_ctl
+                    .client_connection
+                    .send_timeout(
+                        shell::ClientConnectionMsg::SaveOutput { strip_ansi: true },
+                        SESSION_MSG_TIMEOUT,
+                    )
+                    .context("sending search request to reader")?;
+                let status = reader_ctl
+                    .client_connection_ack
+                    .recv_timeout(SESSION_MSG_TIMEOUT)
+                    .context("getting client conn ack")?;
+                match status {
+                    shell::ClientConnectionStatus::Output(output) => Some(output),
+                    status => {
+                        return Err(anyhow!("unexpected reader status: {:?}", status));
+                    }
+                }
+            } else {
+                None
+            }
+        };
+
+        // Do the actual searching with the table lock released, since a
+        // slow regex over a long scrollback shouldn't hold up everyone
+        // else.
+        let reply = match output {
+            None => protocol::SearchReply::NotFound,
+            Some(None) => protocol::SearchReply::NoSpool,
+            Some(Some(output)) => {
+                let text = String::from_utf8_lossy(&output);
+                let lines: Vec<&str> = text.lines().collect();
+                let found = search::search(&lines, &re, request.context);
+                info!("found {} lines in {} lines of scrollback", found.len(), lines.len());
+                protocol::SearchReply::Lines(found)
+            }
+        };
+
+        write_reply(&mut stream, reply).context("writing search reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_capture(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::CaptureRequest,
+    ) -> anyhow::Result<()> {
+        if let Err(reason) = self.check_peer_attach_auth(&stream, &request.session) {
+            write_reply(&mut stream, protocol::CaptureReply::Forbidden(reason))
+                .context("writing capture reply")?;
+            return Ok(());
+        }
+
+        let reply = {
+            let shells = self.shells.shard(&request.session);
+            let session =
+                shells.get(&request.session).filter(|s| peer_access(&stream, &s.acl).is_some());
+            if let Some(s) = session {
+                let reader_ctl = s.reader_ctl.lock().unwrap();
+                reader_ctl
+                    .client_connection
+                    .send_timeout(
+                        shell::ClientConnectionMsg::Capture(request.mode),
+                        SESSION_MSG_TIMEOUT,
+                    )
+                    .context("sending capture request to reader")?;
+                let status = reader_ctl
+                    .client_connection_ack
+                    .recv_timeout(SESSION_MSG_TIMEOUT)
+                    .context("getting client conn ack")?;
+                match status {
+                    shell::ClientConnectionStatus::Output(Some(output)) => {
+                        info!("captured {} bytes of output", output.len());
+                        protocol::CaptureReply::Output(output)
+                    }
+                    shell::ClientConnectionStatus::Output(None) => protocol::CaptureReply::NoSpool,
+                    status => {
+                        return Err(anyhow!("unexpected reader status: {:?}", status));
+                    }
+                }
+            } else {
+                protocol::CaptureReply::NotFound
+            }
+        };
+
+        write_reply(&mut stream, reply).context("writing capture reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_record(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::RecordRequest,
+    ) -> anyhow::Result<()> {
+        let target = {
+            let shells = self.shells.shard(&request.session);
+            shells.get(&request.session).map(|s| {
+                (
+                    Arc::clone(&s.cast),
+                    Arc::clone(&s.pty),
+                    s.term.clone(),
+                    peer_access(&stream, &s.acl),
+                )
+            })
+        };
+        let reply = match (target, request.action) {
+            (Some((_, _, _, None)) | None, _) => protocol::RecordReply::NotFound,
+            (Some((_, _, _, Some(protocol::Access::ReadOnly))), _) => {
+                protocol::RecordReply::Forbidden(format!(
+                    "you have read-only access to '{}'",
+                    request.session
+                ))
+            }
+            (Some((cast, pty, term, _)), protocol::RecordAction::Start { file }) => {
+                let mut cast = cast.lock().unwrap();
+                match cast.as_ref() {
+                    Some(recorder) => protocol::RecordReply::AlreadyRecording(
+                        recorder.path.to_string_lossy().into_owned(),
+                    ),
+                    None => {
+                        let size = pty.size().unwrap_or_default();
+                        match self.start_cast(&request.session, file, &size, term.as_deref()) {
+                            Ok(recorder) => {
+                                info!("recording '{}' to {:?}", request.session, recorder.path);
+                                let path = recorder.path.to_string_lossy().into_owned();
+                                *cast = Some(recorder);
+                                protocol::RecordReply::Started(path)
+                            }
+                            Err(err) => {
+                                warn!("starting recording: {:?}", err);
+                                protocol::RecordReply::Failed(format!("{:#}", err))
+                            }
+                        }
+                    }
+                }
+            }
+            (Some((cast, _, _, _)), protocol::RecordAction::Stop) => {
+                match cast.lock().unwrap().take() {
+                    Some(recorder) => {
+                        info!("stopped recording '{}' to {:?}", request.session, recorder.path);
+                        protocol::RecordReply::Stopped(recorder.path.to_string_lossy().into_owned())
+                    }
+                    None => protocol::RecordReply::NotRecording,
+                }
+            }
+        };
+
+        write_reply(&mut stream, reply).context("writing record reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_resurrect(
+        &self,
+        mut stream: UnixStream,
+        conn_id: usize,
+        request: protocol::ResurrectRequest,
+    ) -> anyhow::Result<()> {
+        if !peer_is_owner(&stream) {
+            return write_reply(
+                &mut stream,
+                protocol::ResurrectReply::Failed(owner_only("resurrect sessions")),
+            )
+            .context("writing resurrect reply");
+        }
+
+        let reply = match self.definitions.load() {
+            Ok(Some(definitions)) => protocol::ResurrectReply::Resurrected(
+                definitions
+                    .into_iter()
+                    .map(|definition| {
+                        let name = definition.name.clone();
+                        let outcome = self
+                            .resurrect_session(conn_id, &request, definition)
+                            .unwrap_or_else(|err| {
+                                warn!("resurrecting '{}': {:?}", name, err);
+                                protocol::ResurrectOutcome::Failed(format!("{:#}", err))
+                            });
+                        protocol::ResurrectedSession { name, outcome }
+                    })
+                    .collect(),
+            ),
+            Ok(None) => protocol::ResurrectReply::NotEnabled,
+            Err(err) => {
+                warn!("loading session definitions: {:?}", err);
+                protocol::ResurrectReply::Failed(format!("{:#}", err))
+            }
+        };
+
+        write_reply(&mut stream, reply).context("writing resurrect reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_acl(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::AclRequest,
+    ) -> anyhow::Result<()> {
+        let peer = peer_creds(&stream).context("getting peer creds")?;
+        let acl = {
+            let shells = self.shells.shard(&request.session);
+            shells.get(&request.session).map(|s| Arc::clone(&s.acl))
+        };
+        let reply = match acl {
+            None => protocol::AclReply::NotFound,
+            Some(_)
+                if request.action != protocol::AclAction::Show
+                    && peer.uid != unistd::getuid().as_raw() =>
+            {
+                warn!("uid {} tried to change the acl of '{}'", peer.uid, request.session);
+                protocol::AclReply::Forbidden(String::from(
+                    "only the user running the daemon may change acls",
+                ))
+            }
+            Some(acl) => {
+                let mut acl = acl.lock().unwrap();
+                info!("acl change for '{}': {:?}", request.session, request.action);
+                let res = match request.action {
+                    protocol::AclAction::Show => Ok(()),
+                    protocol::AclAction::Grant { uid, access } => acl.grant(uid, access),
+                    protocol::AclAction::Revoke { uid } => acl.revoke(uid),
+                    protocol::AclAction::Clear => acl.clear(),
+                };
+                match res {
+                    Ok(()) => protocol::AclReply::Acl(acl.entries()),
+                    Err(err) => {
+                        warn!("saving acl: {:?}", err);
+                        protocol::AclReply::Failed(format!("{:#}", err))
+                    }
+                }
+            }
+        };
+
+        write_reply(&mut stream, reply).context("writing acl reply")?;
+
+        Ok(())
+    }
+
+    /// Start a saved session back up, in the same state as one made
+    /// with `shpool new`, unless it is already running.
+    fn resurrect_session(
+        &self,
+        conn_id: usize,
+        request: &protocol::ResurrectRequest,
+        definition: resurrect::Definition,
+    ) -> anyhow::Result<protocol::ResurrectOutcome> {
+        let mut local_env = request.local_env.clone();
+        for (var, val) in definition.env.into_iter() {
+            local_env.retain(|(v, _)| *v != var);
+            local_env.push((var, val));
+        }
+        let header = protocol::AttachHeader {
+            name: definition.name,
+            local_tty_size: request.local_tty_size.clone(),
+            local_env,
+            ttl_secs: None,
+            cmd: definition.cmd,
+        };
+
+        {
+            let mut shells = self.shells.shard(&header.name);
+            if let Some(session) = shells.get(&header.name) {
+                if session.child_exit_notifier.wait(Some(time::Duration::from_millis(0))).is_none()
+                {
+                    return Ok(protocol::ResurrectOutcome::Running);
+                }
+            }
+            let new = NewSession {
+                conn_id,
+                client_stream: None,
+                header: &header,
+                cwd: definition.cwd.as_deref(),
+                term: definition.term.as_deref(),
+                nsenter_pid: None,
+                dump_motd: false,
+            };
+            if let Err(reason) = self.create_session(&mut shells, new)? {
+                return Ok(protocol::ResurrectOutcome::Failed(reason));
+            }
+            if let Some(session) = shells.get(&header.name) {
+                start_detached(session)?;
+                self.write_forward_env(&header, &session.env).context("writing forwarded env")?;
+            }
+        }
+
+        info!("resurrected session '{}'", header.name);
+        self.link_ssh_auth_sock(&header).context("linking SSH_AUTH_SOCK")?;
+        Ok(protocol::ResurrectOutcome::Created)
+    }
+
+    #[instrument(skip_all)]
+    fn handle_setenv(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::SetEnvRequest,
+    ) -> anyhow::Result<()> {
+        let bad_name = request
+            .set
+            .iter()
+            .map(|(var, _)| var)
+            .chain(request.unset.iter())
+            .find(|var| session_env::check_name(var).is_err());
+        let reply = if let Some(var) = bad_name {
+            protocol::SetEnvReply::BadName(var.clone())
+        } else {
+            let shells = self.shells.shard(&request.session);
+            match shells.get(&request.session).map(|s| (s, peer_access(&stream, &s.acl))) {
+                Some((s, Some(protocol::Access::ReadWrite))) => {
+                    let session_dir = self.ensure_session_dir(&request.session)?;
+                    let mut env = s.env.lock().unwrap();
+                    for (var, val) in request.set.into_iter() {
+                        env.set(var, Some(val));
+                    }
+                    for var in request.unset.into_iter() {
+                        env.set(var, None);
+                    }
+                    env.write(&session_dir).context("writing session env")?;
+                    info!("updated env of session '{}'", request.session);
+                    protocol::SetEnvReply::Ok
+                }
+                Some((_, Some(protocol::Access::ReadOnly))) => protocol::SetEnvReply::Forbidden(
+                    format!("you have read-only access to '{}'", request.session),
+                ),
+                Some((_, None)) | None => protocol::SetEnvReply::NotFound,
+            }
+        };
+
+        write_reply(&mut stream, reply).context("writing setenv reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_getenv(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::GetEnvRequest,
+    ) -> anyhow::Result<()> {
+        let reply = {
+            let shells = self.shells.shard(&request.session);
+            match shells.get(&request.session).filter(|s| peer_access(&stream, &s.acl).is_some()) {
+                Some(s) => protocol::GetEnvReply::Env(s.env.lock().unwrap().vars()),
+                None => protocol::GetEnvReply::NotFound,
+            }
+        };
+
+        write_reply(&mut stream, reply).context("writing getenv reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_send_input(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::SendInputRequest,
+    ) -> anyhow::Result<()> {
+        if let Err(reason) = self.check_peer_attach_auth(&stream, &request.session) {
+            write_reply(&mut stream, protocol::SendInputReply::Forbidden(reason))
+                .context("writing send input reply")?;
+            return Ok(());
+        }
+
+        // Writing to the pty can block if the shell is not reading its
+        // input, so don't hold the table lock while doing it.
+        let target = {
+            let shells = self.shells.shard(&request.session);
+            shells
+                .get(&request.session)
+                .map(|s| (Arc::clone(&s.pty), s.recorder.clone(), peer_access(&stream, &s.acl)))
+        };
+        let reply = match target {
+            Some((_, _, None)) | None => protocol::SendInputReply::NotFound,
+            Some((_, _, Some(protocol::Access::ReadOnly))) => protocol::SendInputReply::Forbidden(
+                format!("you have read-only access to '{}'", request.session),
+            ),
+            Some((pty, recorder, Some(protocol::Access::ReadWrite))) => {
+                info!("sending {} bytes of input to '{}'", request.input.len(), request.session);
+                shell::send_input(&*pty, recorder.as_deref(), &request.input)?;
+                protocol::SendInputReply::Ok
+            }
+        };
+
+        write_reply(&mut stream, reply).context("writing send input reply")?;
+
+        Ok(())
+    }
+
+    /// Block until the session's child exits, then reply with its
+    /// exit status. Any number of clients can wait on the same session.
+    fn handle_wait(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::WaitRequest,
+    ) -> anyhow::Result<()> {
+        let child_exit_notifier = {
+            let shells = self.shells.shard(&request.session);
+            shells
+                .get(&request.session)
+                .filter(|s| peer_access(&stream, &s.acl).is_some())
+                .map(|s| Arc::clone(&s.child_exit_notifier))
+        };
+        let child_exit_notifier = match child_exit_notifier {
+            Some(n) => n,
+            None => {
+                write_reply(&mut stream, protocol::WaitReply::NotFound)
+                    .context("writing wait reply")?;
+                return Ok(());
+            }
+        };
+
+        // Check in on the client every so often so that a waiter that
+        // gets killed doesn't leave this thread around until the
+        // session exits.
+        stream.set_nonblocking(true).context("making wait stream nonblocking")?;
+        let exit_status = loop {
+            if let Some(exit_status) = child_exit_notifier.wait(Some(WAIT_HANGUP_CHECK_INTERVAL)) {
+                break exit_status;
+            }
+            match stream.read(&mut [0; 1]) {
+                Ok(0) => {
+                    info!("waiter for '{}' hung up", request.session);
+                    return Ok(());
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e).context("checking on waiter"),
+            }
+        };
+        stream.set_nonblocking(false).context("making wait stream blocking")?;
+
+        info!("'{}' exited with status {}, waking waiter", request.session, exit_status);
+        write_reply(&mut stream, protocol::WaitReply::Exited { exit_status })
+            .context("writing wait reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_events(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        let owner = peer_is_owner(&stream);
+        // The sessions a peer other than the owner got to hear about,
+        // so that it also hears when they exit, which is after they
+        // have left the table along with their ACL.
+        let mut visible = HashSet::new();
+        let events = events::subscribe();
+        test_hooks::emit("daemon-events-subscribed");
+        loop {
+            match events.recv_timeout(WAIT_HANGUP_CHECK_INTERVAL) {
+                Ok(event) => {
+                    if !owner && !self.peer_sees_event(&stream, &event, &mut visible) {
+                        continue;
+                    }
+                    if let Err(e) = write_reply(&mut stream, event) {
+                        info!("events subscriber went away: {:?}", e);
+                        return Ok(());
+                    }
+                }
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    // Check in on the client every so often so that a
+                    // subscriber that gets killed during a quiet spell
+                    // doesn't leave this thread around.
+                    stream.set_nonblocking(true).context("making events stream nonblocking")?;
+                    let res = stream.read(&mut [0; 1]);
+                    stream.set_nonblocking(false).context("making events stream blocking")?;
+                    match res {
+                        Ok(0) => {
+                            info!("events subscriber hung up");
+                            return Ok(());
+                        }
+                        Ok(_) => {}
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        Err(e) => return Err(e).context("checking on events subscriber"),
+                    }
+                }
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                    info!("events subscriber fell too far behind, hanging up");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Whether a peer other than the owner may hear about the event,
+    /// which is the case for sessions whose ACL lists it.
+    fn peer_sees_event(
+        &self,
+        stream: &UnixStream,
+        event: &protocol::SessionEvent,
+        visible: &mut HashSet<String>,
+    ) -> bool {
+        let access = {
+            let shells = self.shells.shard(&event.session);
+            shells.get(&event.session).map(|s| peer_access(stream, &s.acl).is_some())
+        };
+        match access {
+            Some(true) => {
+                visible.insert(event.session.clone());
+                true
+            }
+            Some(false) => {
+                visible.remove(&event.session);
+                false
+            }
+            // the session is gone, so this is its exit
+            None => visible.remove(&event.session),
+        }
+    }
+
+    #[instrument(skip_all)]
+    fn handle_kill(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::KillRequest,
+    ) -> anyhow::Result<()> {
+        let mut not_found_sessions = vec![];
+        let mut forbidden_sessions = vec![];
+        let mut to_kill = vec![];
+        let mut to_remove = vec![];
+
+        for session in request.sessions.into_iter() {
+            let access = {
+                let shells = self.shells.shard(&session);
+                shells.get(&session).and_then(|s| peer_access(&stream, &s.acl))
+            };
+            match access {
+                Some(protocol::Access::ReadWrite) => to_kill.push(session),
+                Some(protocol::Access::ReadOnly) => forbidden_sessions.push(session),
+                None => not_found_sessions.push(session),
+            }
+        }
+
+        // killing a session takes its jobs down with it, and a session's
+        // jobs always live in its shard
+        for session in to_kill.iter() {
+            let shells = self.shells.shard(session);
+            for (name, s) in shells.iter() {
+                if job::base_session(name) == name.as_str()
+                    || job::base_session(name) != session
+                    || to_remove.contains(name)
+                {
+                    continue;
+                }
+                s.kill().context("killing job shell proc")?;
+                to_remove.push(name.clone());
+            }
+        }
+        for session in to_kill.into_iter() {
+            let shells = self.shells.shard(&session);
+            if let Some(s) = shells.get(&session) {
+                s.kill().context("killing shell proc")?;
+
+                // we don't need to wait since the dedicated reaping thread is active
+                // even when a tty is not attached
+                to_remove.push(session);
+            } else {
+                not_found_sessions.push(session);
+            }
+        }
+
+        for session in to_remove.iter() {
+            self.shells.shard(session).remove(session);
+            self.definitions.forget(session);
+        }
+        if !to_remove.is_empty() {
+            test_hooks::emit("daemon-handle-kill-removed-shells");
+        }
+
+        write_reply(&mut stream, protocol::KillReply { not_found_sessions, forbidden_sessions })
+            .context("writing kill reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_stop(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::StopRequest,
+    ) -> anyhow::Result<()> {
+        if !peer_is_owner(&stream) {
+            return write_reply(&mut stream, protocol::StopReply::Failed(owner_only("stop it")))
+                .context("writing stop reply");
+        }
+
+        let reason = if request.keep_sessions {
+            "the daemon was stopped, this session will be back once it restarts"
+        } else {
+            "the daemon was stopped"
+        };
+        match self.shutdown(request.keep_sessions, reason) {
+            Ok(held) => {
+                write_reply(&mut stream, protocol::StopReply::Stopped { held })
+                    .context("writing stop reply")?;
+                info!("exiting");
+                process::exit(0);
+            }
+            Err(err) => {
+                error!("stopping daemon: {:?}", err);
+                write_reply(&mut stream, protocol::StopReply::Failed(format!("{:#}", err)))
+                    .context("writing stop reply")
+            }
+        }
+    }
+
+    #[instrument(skip_all)]
+    fn handle_restart(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::RestartRequest,
+    ) -> anyhow::Result<()> {
+        if !peer_is_owner(&stream) {
+            return write_reply(
+                &mut stream,
+                protocol::RestartReply::Failed(owner_only("restart it")),
+            )
+            .context("writing restart reply");
+        }
+
+        let res = reexec::binary().and_then(|exe| {
+            let reason = if request.preserve_sessions {
+                "the daemon is restarting, this session will be back once it is up"
+            } else {
+                "the daemon restarted"
+            };
+            let held = self.shutdown(request.preserve_sessions, reason)?;
+            if !request.preserve_sessions {
+                self.kill_all();
+            }
+            Ok((exe, held))
+        });
+        match res {
+            Ok((exe, held)) => {
+                write_reply(&mut stream, protocol::RestartReply::Restarting { held })
+                    .context("writing restart reply")?;
+                drop(stream);
+                info!("restarting into {}", exe.display());
+                let err = reexec::exec(&exe);
+                error!("restarting: {:?}", err);
+                process::exit(1);
+            }
+            Err(err) => {
+                error!("restarting daemon: {:?}", err);
+                write_reply(&mut stream, protocol::RestartReply::Failed(format!("{:#}", err)))
+                    .context("writing restart reply")
+            }
+        }
+    }
+
+    /// Kill every session and wait for the shells to get reaped.
+    fn kill_all(&self) {
+        let shells = self.shells.lock_all();
+        for (name, session) in shells.iter() {
+            if session.child_exit_notifier.wait(Some(time::Duration::from_millis(0))).is_some() {
+                continue;
+            }
+            if let Err(err) = session.kill() {
+                warn!("killing '{}': {:?}", name, err);
+                continue;
+            }
+            if session.child_exit_notifier.wait(Some(RESTART_REAP_TIMEOUT)).is_none() {
+                warn!("shell for '{}' did not exit in time", name);
+            }
+        }
+    }
+
+    /// Get everything ready for the daemon to exit: stop taking new
+    /// connections, hand the sessions off to a holder if asked to, and
+    /// tell any attached clients why they are getting disconnected.
+    /// Returns the names of the held sessions. If handing off the
+    /// sessions fails, nothing has been torn down yet, so the daemon
+    /// can keep running.
+    #[instrument(skip_all)]
+    pub fn shutdown(&self, keep_sessions: bool, reason: &str) -> anyhow::Result<Vec<String>> {
+        let shells = self.shells.lock_all();
+
+        let mut held = vec![];
+        if keep_sessions {
+            let mut sessions = vec![];
+            let mut fds = vec![];
+            for (name, session) in shells.iter() {
+                if session.child_exit_notifier.wait(Some(time::Duration::from_millis(0))).is_some()
+                {
+                    continue;
+                }
+                sessions.push(holder::HeldSession {
+                    name: name.clone(),
+                    child_pid: session.child_pid,
+                    started_at: session.started_at,
+                });
+                fds.push(session.pty.master().raw_fd());
+            }
+            if !sessions.is_empty() {
+                holder::spawn(&holder::socket_path(&self.socket), &sessions, &fds)
+                    .context("handing sessions off to holder")?;
+                held = sessions.into_iter().map(|s| s.name).collect();
+            }
+        }
+
+        if let Some(socket_file) = &self.socket_file {
+            if let Err(err) = socket_file.remove() {
+                error!("cleaning up socket: {:?}", err);
+            }
+        } else {
+            info!("systemd manages the socket, so not cleaning it up");
+        }
+
+        for (name, session) in shells.iter() {
+            let reader_ctl = session.reader_ctl.lock().unwrap();
+            let res = reader_ctl
+                .client_connection
+                .send_timeout(
+                    shell::ClientConnectionMsg::Hangup(protocol::StreamControl::Shutdown(
+                        String::from(reason),
+                    )),
+                    SESSION_MSG_TIMEOUT,
+                )
+                .context("sending shutdown msg")
+                .and_then(|_| {
+                    reader_ctl
+                        .client_connection_ack
+                        .recv_timeout(SHUTDOWN_ACK_TIMEOUT)
+                        .context("getting shutdown ack")
+                });
+            match res {
+                Ok(shell::ClientConnectionStatus::Detached) => {
+                    info!("told client of '{}' about shutdown", name)
+                }
+                Ok(_) => {}
+                Err(err) => warn!("notifying '{}' of shutdown: {:?}", name, err),
+            }
+        }
+
+        Ok(held)
+    }
+
+    /// Pick up any sessions a holder kept around from the last daemon.
+    #[instrument(skip_all)]
+    pub fn adopt_held_sessions(&self) -> anyhow::Result<()> {
+        let held = holder::adopt(&self.socket).context("taking sessions from holder")?;
+        for (held, fd) in held.into_iter() {
+            let mut shells = self.shells.shard(&held.name);
+            if shells.contains_key(&held.name) {
+                warn!("dropping held session '{}', the name is taken", held.name);
+                continue;
+            }
+            let pty: Arc<dyn pty::Pty + Send + Sync> = Arc::from(pty::adopt(fd, held.child_pid));
+            let header = protocol::AttachHeader {
+                name: held.name.clone(),
+                local_tty_size: pty.size().unwrap_or_default(),
+                local_env: vec![],
+                ttl_secs: None,
+                cmd: None,
+            };
+            // The env the shell was started with is lost, so all we can
+            // do is guess at the terminfo.
+            let term_db = Arc::new(term_db(None)?);
+            let child = RunningChild {
+                pty,
+                term: None,
+                term_db,
+                custom_cmd: true,
+                recorder: None,
+                login_records: LoginRecords::default(),
+                started_at: held.started_at,
+            };
+            let session = self.start_session(0, None, &header, false, child)?;
+            start_detached(&session)?;
+            info!("adopted held session '{}'", held.name);
+            shells.insert(held.name, Box::new(session));
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_keybind(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::KeybindRequest,
+    ) -> anyhow::Result<()> {
+        use protocol::KeybindOp;
+
+        if !peer_is_owner(&stream) {
+            let reply = protocol::KeybindReply {
+                error: Some(owner_only("change keybindings")),
+                ..Default::default()
+            };
+            return write_reply(&mut stream, reply).context("writing keybind reply");
+        }
+
+        // validate the new binding up front so that we don't
+        // half apply a bad request
+        let new_binding = match &request.op {
+            KeybindOp::Add { binding, action } => match parse_keybinding(binding, action) {
+                Ok(b) => Some(b),
+                Err(e) => {
+                    let reply = protocol::KeybindReply {
+                        error: Some(format!("{:#}", e)),
+                        ..Default::default()
+                    };
+                    write_reply(&mut stream, reply).context("writing keybind reply")?;
+                    return Ok(());
+                }
+            },
+            _ => None,
+        };
+
+        let mut reply = protocol::KeybindReply::default();
+        {
+            let config = self.config.get();
+
+            let all = request.sessions.is_empty();
+            let mut session_names = request.sessions;
+            if all {
+                session_names = self.shells.names();
+                session_names.sort();
+            }
+
+            for name in session_names.into_iter() {
+                let shells = self.shells.shard(&name);
+                let session = match shells.get(&name) {
+                    Some(s) => s,
+                    // a session that exited since we listed them
+                    // wasn't asked for by name
+                    None if all => continue,
+                    None => {
+                        reply.not_found_sessions.push(name);
+                        continue;
+                    }
+                };
+
+                let mut overrides = session.keybindings.lock().unwrap();
+                let mut bindings = overrides.effective(&config);
+                match &request.op {
+                    KeybindOp::Add { binding, .. } => {
+                        bindings.retain(|b| !keybindings::same_binding(&b.binding, binding));
+                        bindings.extend(new_binding.clone());
+                        overrides.set(bindings.clone());
+                    }
+                    KeybindOp::Remove { binding } => {
+                        let old_len = bindings.len();
+                        bindings.retain(|b| !keybindings::same_binding(&b.binding, binding));
+                        if bindings.len() != old_len {
+                            overrides.set(bindings.clone());
+                        }
+                    }
+                    KeybindOp::List => {}
+                }
+                info!("session '{}' keybindings: {:?}", name, bindings);
+
+                reply.sessions.push(protocol::SessionKeybindings {
+                    name,
+                    bindings: bindings
+                        .into_iter()
+                        .map(|b| (b.binding, b.action.to_string()))
+                        .collect(),
+                });
+            }
+        }
+
+        write_reply(&mut stream, reply).context("writing keybind reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_gc(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::GcRequest,
+    ) -> anyhow::Result<()> {
+        if !peer_is_owner(&stream) {
+            let reply =
+                protocol::GcReply { items: vec![], error: Some(owner_only("collect garbage")) };
+            return write_reply(&mut stream, reply);
+        }
+
+        let mut items = vec![];
+        {
+            // Hold every shard for the whole sweep so that a session
+            // can't get created out from under us while we remove its dir.
+            let shells = self.shells.lock_all();
+
+            let sessions_dir = self.runtime_dir.join("sessions");
+            let entries = match fs::read_dir(&sessions_dir) {
+                Ok(e) => e,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    write_reply(&mut stream, protocol::GcReply { items, error: None })?;
+                    return Ok(());
+                }
+                Err(e) => return Err(e).context("reading sessions dir"),
+            };
+            for entry in entries {
+                let entry = entry.context("reading sessions dir entry")?;
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+                // status files are named after the session with a .json suffix
+                let session = String::from(
+                    file_name
+                        .strip_suffix(".json.tmp")
+                        .or_else(|| file_name.strip_suffix(".json"))
+                        .unwrap_or(&file_name),
+                );
+                if shells.contains_key(&session) {
+                    continue;
+                }
+
+                let path = entry.path();
+                let bytes = disk_usage(&path).unwrap_or_else(|e| {
+                    warn!("sizing {:?}: {:?}", path, e);
+                    0
+                });
+                let mut error = None;
+                if !request.dry_run {
+                    info!("removing orphaned session data {:?}", path);
+                    let res = if path.is_dir() {
+                        fs::remove_dir_all(&path)
+                    } else {
+                        fs::remove_file(&path)
+                    };
+                    match res {
+                        Ok(_) => {}
+                        // A session that was just killed may still be cleaning
+                        // up its status file, so there is nothing left to do.
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => {
+                            warn!("removing {:?}: {:?}", path, e);
+                            error = Some(format!("{}", e));
+                        }
+                    }
+                }
+                items.push(protocol::GcItem {
+                    session,
+                    path: path.to_string_lossy().into_owned(),
+                    bytes,
+                    error,
+                });
+            }
+        }
+        items.sort_by(|a, b| a.session.cmp(&b.session));
+
+        write_reply(&mut stream, protocol::GcReply { items, error: None })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_list(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        let sessions: anyhow::Result<Vec<protocol::Session>> = self
+            .shells
+            .filter_map(|k, v| {
+                peer_access(&stream, &v.acl)?;
+                let status = match v.inner.try_lock() {
+                    Ok(_) => match v.child_exit_notifier.wait(Some(time::Duration::from_millis(0)))
+                    {
+                        Some(exit_status) => protocol::SessionStatus::Exited(exit_status),
+                        None => protocol::SessionStatus::Disconnected,
+                    },
+                    Err(_) => protocol::SessionStatus::Attached,
+                };
+
+                let started_at = match v.started_at.duration_since(time::UNIX_EPOCH) {
+                    Ok(d) => d,
+                    Err(e) => return Some(Err(anyhow::Error::from(e))),
+                };
+                Some(Ok(protocol::Session {
+                    name: k.to_string(),
+                    started_at_unix_ms: started_at.as_millis() as i64,
+                    status,
+                }))
+            })
+            .into_iter()
+            .collect();
+        let sessions = sessions.context("collecting running session metadata")?;
+
+        write_reply(&mut stream, protocol::ListReply { sessions })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_alerts(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        let sessions = self.shells.filter_map(|name, session| {
+            peer_access(&stream, &session.acl)?;
+            let alerts = session.alerts.lock().unwrap();
+            if alerts.is_empty() {
+                return None;
+            }
+            Some(protocol::SessionAlerts {
+                name: name.clone(),
+                bells: alerts.bells,
+                notifications: alerts.notifications,
+            })
+        });
+
+        write_reply(&mut stream, protocol::AlertsReply { sessions })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_activity(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        let active_window = activity::active_window(&self.config.get());
+        let sessions = self.shells.filter_map(|name, session| {
+            peer_access(&stream, &session.acl)?;
+            let idle = session.activity.lock().unwrap().idle();
+            Some(protocol::SessionActivity {
+                name: name.clone(),
+                idle_ms: idle.as_millis() as u64,
+                active: idle < active_window,
+            })
+        });
+
+        write_reply(&mut stream, protocol::ActivityReply { sessions })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_cwd(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        let sessions = self.shells.filter_map(|name, session| {
+            peer_access(&stream, &session.acl)?;
+            Some(protocol::SessionCwd { name: name.clone(), cwd: child_cwd(session.child_pid) })
+        });
+
+        write_reply(&mut stream, protocol::CwdReply { sessions })?;
+
+        Ok(())
+    }
+
+    /// List the jobs of a session. A job counts as attached if its
+    /// inner is locked, just like for `shpool list`.
+    #[instrument(skip_all)]
+    fn handle_jobs(&self, mut stream: UnixStream, name: String) -> anyhow::Result<()> {
+        let base = job::base_session(&name);
+        let mut jobs: Vec<protocol::Job> = {
+            let shells = self.shells.shard(&name);
+            let visible =
+                shells.get(base).map(|s| peer_access(&stream, &s.acl).is_some()).unwrap_or(false);
+            shells
+                .iter()
+                .filter(|(name, _)| visible && job::base_session(name) == base)
+                .map(|(name, session)| protocol::Job {
+                    name: name.clone(),
+                    attached: session.inner.try_lock().is_err(),
+                })
+                .collect()
+        };
+        // the session's own shell goes first
+        jobs.sort_by(|a, b| (a.name != base, &a.name).cmp(&(b.name != base, &b.name)));
+
+        write_reply(&mut stream, protocol::JobsReply { jobs })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_switch_job(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::SwitchJobRequest,
+    ) -> anyhow::Result<()> {
+        let base = job::base_session(&request.session);
+        let reply = {
+            let shells = self.shells.shard(&request.session);
+            let attached = shells
+                .iter()
+                .find(|(name, s)| job::base_session(name) == base && s.inner.try_lock().is_err());
+            let access = shells.get(base).and_then(|s| peer_access(&stream, &s.acl));
+            if access.is_none()
+                || job::base_session(&request.target) != base
+                || !shells.contains_key(&request.target)
+            {
+                protocol::SwitchJobReply::NotFound
+            } else if access == Some(protocol::Access::ReadOnly) {
+                protocol::SwitchJobReply::Forbidden(format!(
+                    "you have read-only access to '{}'",
+                    base
+                ))
+            } else {
+                match attached {
+                    None => protocol::SwitchJobReply::NotAttached,
+                    Some((name, _)) if *name == request.target => {
+                        protocol::SwitchJobReply::Switched
+                    }
+                    Some((name, s)) => {
+                        let reader_ctl = s.reader_ctl.lock().unwrap();
+                        reader_ctl
+                            .client_connection
+                            .send_timeout(
+                                shell::ClientConnectionMsg::Hangup(
+                                    protocol::StreamControl::SwitchJob(
+                                        protocol::JobTarget::Session(request.target.clone()),
+                                    ),
+                                ),
+                                SESSION_MSG_TIMEOUT,
+                            )
+                            .context("sending job switch to reader")?;
+                        let status = reader_ctl
+                            .client_connection_ack
+                            .recv_timeout(SESSION_MSG_TIMEOUT)
+                            .context("getting client conn ack")?;
+                        info!("switched '{}' to '{}', status = {:?}", name, request.target, status);
+                        protocol::SwitchJobReply::Switched
+                    }
+                }
+            }
+        };
+
+        write_reply(&mut stream, reply).context("writing switch job reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_paste(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        if !peer_is_owner(&stream) {
+            let reply = protocol::PasteReply {
+                contents: None,
+                error: Some(owner_only("use paste buffers")),
+            };
+            return write_reply(&mut stream, reply);
+        }
+
+        let contents = self.paste_buffers.lock().unwrap().get(None).map(String::from);
+        write_reply(&mut stream, protocol::PasteReply { contents, error: None })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_load_buffer(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::LoadBufferRequest,
+    ) -> anyhow::Result<()> {
+        if !peer_is_owner(&stream) {
+            let reply = protocol::LoadBufferReply {
+                buffer: String::new(),
+                error: Some(owner_only("use paste buffers")),
+            };
+            return write_reply(&mut stream, reply).context("writing load buffer reply");
+        }
+
+        let len = request.contents.len();
+        let buffer = self.paste_buffers.lock().unwrap().set(request.buffer, request.contents);
+        info!("loaded {} bytes into paste buffer '{}'", len, buffer);
+        write_reply(&mut stream, protocol::LoadBufferReply { buffer, error: None })
+            .context("writing load buffer reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_show_buffer(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::ShowBufferRequest,
+    ) -> anyhow::Result<()> {
+        if !peer_is_owner(&stream) {
+            let reply = protocol::PasteReply {
+                contents: None,
+                error: Some(owner_only("use paste buffers")),
+            };
+            return write_reply(&mut stream, reply).context("writing show buffer reply");
+        }
+
+        let contents =
+            self.paste_buffers.lock().unwrap().get(request.buffer.as_deref()).map(String::from);
+        write_reply(&mut stream, protocol::PasteReply { contents, error: None })
+            .context("writing show buffer reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_paste_buffer(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::PasteBufferRequest,
+    ) -> anyhow::Result<()> {
+        if !peer_is_owner(&stream) {
+            let reply = protocol::PasteBufferReply::Forbidden(owner_only("use paste buffers"));
+            return write_reply(&mut stream, reply).context("writing paste buffer reply");
+        }
+        if let Err(reason) = self.check_peer_attach_auth(&stream, &request.session) {
+            write_reply(&mut stream, protocol::PasteBufferReply::Forbidden(reason))
+                .context("writing paste buffer reply")?;
+            return Ok(());
+        }
+
+        let contents =
+            self.paste_buffers.lock().unwrap().get(request.buffer.as_deref()).map(String::from);
+        // Writing to the pty can block if the shell is not reading its
+        // input, so don't hold the table lock while doing it.
+        let target = {
+            let shells = self.shells.shard(&request.session);
+            shells
+                .get(&request.session)
+                .map(|s| (Arc::clone(&s.pty), s.recorder.clone(), peer_access(&stream, &s.acl)))
+        };
+        let reply = match (target, contents) {
+            (Some((_, _, None)), _) | (None, _) => protocol::PasteBufferReply::NotFound,
+            (Some((_, _, Some(protocol::Access::ReadOnly))), _) => {
+                protocol::PasteBufferReply::Forbidden(format!(
+                    "you have read-only access to '{}'",
+                    request.session
+                ))
+            }
+            (_, None) => protocol::PasteBufferReply::NoBuffer,
+            (Some((pty, recorder, Some(protocol::Access::ReadWrite))), Some(contents)) => {
+                info!("pasting {} bytes into '{}'", contents.len(), request.session);
+                shell::send_input(&*pty, recorder.as_deref(), contents.as_bytes())?;
+                protocol::PasteBufferReply::Ok
+            }
+        };
+
+        write_reply(&mut stream, reply).context("writing paste buffer reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_version(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        write_reply(
+            &mut stream,
+            protocol::VersionReply { version: String::from(env!("CARGO_PKG_VERSION")) },
+        )?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_metrics(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        write_reply(&mut stream, protocol::MetricsReply { text: self.render_metrics() })?;
+
+        Ok(())
+    }
+
+    /// Render the daemon metrics, filling in the gauges from the
+    /// session registry without locking any of its shards, so that
+    /// scraping metrics never holds up an attach.
+    pub fn render_metrics(&self) -> String {
+        metrics::METRICS.render(&metrics::Gauges {
+            sessions: self.shells.count(),
+            attached: self.shells.attached(),
+        })
+    }
+
+    #[instrument(skip_all, fields(s = &header.session_name))]
+    fn handle_session_message(
+        &self,
+        mut stream: UnixStream,
+        header: protocol::SessionMessageRequest,
+    ) -> anyhow::Result<()> {
+        // create a slot to store our reply so we can do
         // our IO without the lock held.
         let reply = {
-            let shells = self.shells.lock().unwrap();
-            if let Some(session) = shells.get(&header.session_name) {
+            let shells = self.shells.shard(&header.session_name);
+            let target = shells
+                .get(&header.session_name)
+                .map(|s| (s, peer_access(&stream, &s.acl)))
+                .filter(|(_, access)| access.is_some());
+            if let Some((session, access)) = target {
+                let read_only = access == Some(protocol::Access::ReadOnly);
                 match header.payload {
+                    // a read-only viewer may redraw its own screen, but
+                    // not change the size of or detach anyone else's
+                    protocol::SessionMessageRequestPayload::Resize(_)
+                    | protocol::SessionMessageRequestPayload::Detach
+                        if read_only =>
+                    {
+                        protocol::SessionMessageReply::Forbidden(format!(
+                            "you have read-only access to '{}'",
+                            header.session_name
+                        ))
+                    }
                     protocol::SessionMessageRequestPayload::Resize(resize_request) => {
                         info!("handling resize msg");
+                        session
+                            .status_file
+                            .lock()
+                            .unwrap()
+                            .update(|s| s.tty_size = Some(resize_request.tty_size.clone()));
                         let pager_ctl = session.pager_ctl.lock().unwrap();
                         if let Some(pager_ctl) = pager_ctl.as_ref() {
                             info!("resizing pager");
@@ -582,6 +2650,19 @@ impl Server {
                             protocol::SessionMessageDetachReply::Ok,
                         )
                     }
+                    protocol::SessionMessageRequestPayload::Redraw => {
+                        info!("handling redraw msg");
+                        let reader_ctl = session.reader_ctl.lock().unwrap();
+                        reader_ctl
+                            .client_connection
+                            .send_timeout(shell::ClientConnectionMsg::Redraw, SESSION_MSG_TIMEOUT)
+                            .context("sending redraw to reader")?;
+                        reader_ctl
+                            .client_connection_ack
+                            .recv_timeout(SESSION_MSG_TIMEOUT)
+                            .context("getting redraw ack")?;
+                        protocol::SessionMessageReply::Redraw
+                    }
                 }
             } else {
                 protocol::SessionMessageReply::NotFound
@@ -599,11 +2680,19 @@ impl Server {
     #[instrument(skip_all)]
     fn spawn_subshell(
         &self,
-        conn_id: usize,
-        client_stream: UnixStream,
-        header: &protocol::AttachHeader,
-        dump_motd_on_new_session: bool,
+        new: NewSession<'_>,
+        nsenter: Option<nsenter::Target>,
+        recorder: Option<audit::Recorder>,
     ) -> anyhow::Result<shell::Session> {
+        let NewSession {
+            conn_id,
+            client_stream,
+            header,
+            cwd,
+            term,
+            dump_motd: dump_motd_on_new_session,
+            ..
+        } = new;
         let user_info = user::info()?;
         let shell = if let Some(s) = &self.config.get().shell {
             s.clone()
@@ -613,10 +2702,10 @@ impl Server {
         info!("user_info={:?}", user_info);
 
         // Build up the command we will exec while allocation is still chill.
-        // We will exec this command after a fork, so we want to just inherit
-        // stdout/stderr/stdin. The pty crate automatically `dup2`s the file
-        // descriptors for us.
-        let mut cmd = if let Some(cmd_str) = &header.cmd {
+        // The pty backend will usually exec this command after a fork, so we
+        // want to just inherit stdout/stderr/stdin. The pty crate
+        // automatically `dup2`s the file descriptors for us.
+        let cmd = if let Some(cmd_str) = &header.cmd {
             let cmd_parts = shell_words::split(cmd_str).context("parsing cmd")?;
             info!("running cmd: {:?}", cmd_parts);
             if cmd_parts.is_empty() {
@@ -639,8 +2728,55 @@ impl Server {
             cmd
         };
 
-        cmd.current_dir(user_info.home_dir.clone())
-            .stdin(process::Stdio::inherit())
+        let mut arg0 = if header.cmd.is_none() {
+            // spawn the shell as a login shell by setting
+            // arg0 to be the basename of the shell path
+            // proceeded with a "-". You can see sshd doing the
+            // same thing if you look in the session.c file of
+            // openssh.
+            let shell_basename = Path::new(&shell)
+                .file_name()
+                .ok_or(anyhow!("error building login shell indicator"))?
+                .to_str()
+                .ok_or(anyhow!("error parsing shell name as utf8"))?;
+            Some(format!("-{}", shell_basename))
+        } else {
+            None
+        };
+
+        let restart_policy = match &self.config.get().restart_policy {
+            Some(entries) => supervisor::resolve(entries, &header.name),
+            None => None,
+        };
+        let mut cmd = match restart_policy {
+            // The supervisor is the shpool binary from the host, which
+            // might not be there in the target's mount namespace.
+            Some(_) if nsenter.is_some() => {
+                warn!("not applying restart policy to a session in another namespace");
+                cmd
+            }
+            Some(policy) => {
+                info!("supervising with restart policy: {:?}", policy);
+                policy.wrap(&cmd, arg0.take().as_deref()).context("wrapping cmd")?
+            }
+            None => cmd,
+        };
+
+        let cwd = cwd.unwrap_or(&user_info.home_dir);
+        let nsenter = match nsenter {
+            // The dir is in the target's mount namespace, so the child
+            // can only change into it once it has entered that.
+            Some(target) => {
+                info!("entering namespaces: {:?}", target);
+                cmd.current_dir("/");
+                Some((target, CString::new(cwd).context("encoding cwd")?))
+            }
+            None => {
+                cmd.current_dir(cwd);
+                None
+            }
+        };
+        cmd.stdin(process::Stdio::inherit())
             .stdout(process::Stdio::inherit())
             .stderr(process::Stdio::inherit())
             // The env should mostly be set up by the shell sourcing
@@ -649,99 +2785,202 @@ impl Server {
             // to avoid breakage and vars the user has asked us to inject.
             .env_clear();
 
-        let term = self.inject_env(&mut cmd, &user_info, header).context("setting up shell env")?;
-        let term_db = Arc::new(if let Some(term) = &term {
-            termini::TermInfo::from_name(term).context("resolving terminfo")?
-        } else {
-            warn!("no $TERM, using default terminfo");
-            match termini::TermInfo::from_env() {
-                Ok(db) => db,
+        // The PAM modules get to set up the env before we inject our own
+        // vars, so that the vars from the config win.
+        let pam_service = self.config.get().pam_session_service.clone();
+        let pam_session = match pam_service {
+            Some(service) => match pam_session::Session::open(&service, &user_info.user) {
+                Ok(mut session) => {
+                    cmd.envs(session.env());
+                    Some(session)
+                }
                 Err(err) => {
-                    warn!("could not get terminfo from env: {:?}", err);
-                    match termini::TermInfo::from_name("xterm") {
-                        Ok(db) => db,
-                        Err(err) => {
-                            warn!("could not get xterm terminfo: {:?}", err);
-                            let empty_db = io::Cursor::new(vec![]);
-                            termini::TermInfo::parse(empty_db).context("getting terminfo db")?
-                        }
-                    }
+                    warn!("could not open pam session: {:?}", err);
+                    None
                 }
-            }
-        });
+            },
+            None => None,
+        };
 
-        if header.cmd.is_none() {
-            // spawn the shell as a login shell by setting
-            // arg0 to be the basename of the shell path
-            // proceeded with a "-". You can see sshd doing the
-            // same thing if you look in the session.c file of
-            // openssh.
-            let shell_basename = Path::new(&shell)
-                .file_name()
-                .ok_or(anyhow!("error building login shell indicator"))?
-                .to_str()
-                .ok_or(anyhow!("error parsing shell name as utf8"))?;
-            cmd.arg0(format!("-{}", shell_basename));
+        let term =
+            self.inject_env(&mut cmd, &user_info, header, term).context("setting up shell env")?;
+        if let Some(recorder) = &recorder {
+            // let prompts and the like remind the user that they are being recorded
+            cmd.env("SHPOOL_SESSION_RECORDING", &recorder.path);
+        }
+        let term_db = Arc::new(term_db(term.as_deref())?);
+
+        if let Some(arg0) = arg0 {
+            cmd.arg0(arg0);
+        }
+
+        let pin = match &self.config.get().cpu_affinity {
+            Some(entries) => affinity::resolve(entries, &header.name)?,
+            None => None,
+        };
+        info!("cpu pin: {:?}", pin);
+        let session_limits = self.config.get().session_limits.clone();
+        let rlimits = match &session_limits {
+            Some(l) => limits::resolve_rlimits(l).context("resolving rlimits")?,
+            None => vec![],
         };
 
+        // Safety: this runs in the child between fork and exec, where
+        //         another thread could have been holding the allocator
+        //         lock at fork time, so nothing in here may allocate.
+        let child_pin = pin.clone();
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(pin) = &child_pin {
+                    affinity::apply(pin)?;
+                }
+                limits::apply_rlimits(&rlimits)?;
+                // Last, since the other setup happens on the host. The
+                // shell must not end up on the host by accident, so a
+                // failure here fails the exec.
+                if let Some((target, cwd)) = &nsenter {
+                    target.enter(cwd)?;
+                }
+                Ok(())
+            });
+        }
+
         let noecho = self.config.get().noecho.unwrap_or(false);
-        info!("about to fork subshell noecho={}", noecho);
-        let mut fork = shpool_pty::fork::Fork::from_ptmx().context("forking pty")?;
-        if let Ok(slave) = fork.is_child() {
-            if noecho {
-                if let Some(fd) = slave.borrow_fd() {
-                    tty::disable_echo(fd).context("disabling echo on pty")?;
+        let no_flow_control = self.config.get().flow_control == Some(config::FlowControl::Off);
+        info!("about to spawn subshell noecho={} no_flow_control={}", noecho, no_flow_control);
+        let pty: Arc<dyn pty::Pty + Send + Sync> = Arc::from(
+            self.pty_backend
+                .spawn(pty::SpawnRequest { cmd, noecho, no_flow_control })
+                .context("spawning subshell")?,
+        );
+
+        if let Some(pin) = &pin {
+            if let Err(err) = affinity::join_cgroup(pin, pty.child_pid()) {
+                warn!("could not move shell into its cgroup: {:?}", err);
+            }
+        }
+        if let Some(scope) = session_limits.as_ref().and_then(|l| l.scope.as_ref()) {
+            if let Err(err) = limits::start_scope(scope, &header.name, pty.child_pid()) {
+                warn!("could not move shell into a systemd scope: {:?}", err);
+            }
+        }
+
+        let utmp = if self.config.get().utmp.unwrap_or(false) {
+            match utmp::Record::add(
+                &user_info.user,
+                pty.child_pid(),
+                pty.master().raw_fd(),
+                &header.name,
+            ) {
+                Ok(record) => Some(record),
+                Err(err) => {
+                    warn!("could not add utmp record: {:?}", err);
+                    None
                 }
             }
-            for fd in consts::STDERR_FD + 1..(nix::unistd::SysconfVar::OPEN_MAX as i32) {
-                let _ = nix::unistd::close(fd);
+        } else {
+            None
+        };
+
+        // Inject the prompt prefix, if any. For custom commands, avoid doing this
+        // since we have no idea what the command is so the shell code probably won't
+        // work.
+        if header.cmd.is_none() {
+            info!("injecting prompt prefix");
+            let prompt_prefix = self
+                .config
+                .get()
+                .prompt_prefix
+                .clone()
+                .unwrap_or(String::from(DEFAULT_PROMPT_PREFIX));
+            if let Err(err) = prompt::inject_prefix(&*pty, &prompt_prefix, &header.name) {
+                warn!("issue injecting prefix: {:?}", err);
             }
-            let err = cmd.exec();
-            eprintln!("shell exec err: {:?}", err);
-            std::process::exit(1);
         }
 
+        let child = RunningChild {
+            pty,
+            term,
+            term_db,
+            custom_cmd: header.cmd.is_some(),
+            recorder,
+            login_records: LoginRecords { utmp, pam_session },
+            started_at: time::SystemTime::now(),
+        };
+        self.start_session(conn_id, client_stream, header, dump_motd_on_new_session, child)
+    }
+
+    /// Start up the threads that run a session around a pty whose child
+    /// is already running, whether it was just spawned or was adopted
+    /// from an earlier daemon.
+    fn start_session(
+        &self,
+        conn_id: usize,
+        client_stream: Option<UnixStream>,
+        header: &protocol::AttachHeader,
+        dump_motd_on_new_session: bool,
+        child: RunningChild,
+    ) -> anyhow::Result<shell::Session> {
+        let RunningChild { pty, term, term_db, custom_cmd, recorder, login_records, started_at } =
+            child;
         // spawn a background thread to reap the shell when it exits
         // and notify about the exit by closing a channel.
         let child_exit_notifier = Arc::new(ExitNotifier::new());
-        let waitable_child = fork.clone();
+        let waitable_child = Arc::clone(&pty);
         let session_name = header.name.clone();
         let notifiable_child_exit_notifier = Arc::clone(&child_exit_notifier);
+        let hook_config = self.config.clone();
+        let definitions = Arc::clone(&self.definitions);
+        let shells = Arc::clone(&self.shells);
         thread::spawn(move || {
             let _s = span!(Level::INFO, "child_watcher", s = session_name, cid = conn_id).entered();
 
-            match waitable_child.wait_for_exit() {
-                Ok((_, Some(exit_status))) => {
+            let exit_status = match waitable_child.wait_for_exit() {
+                Ok(Some(exit_status)) => {
                     info!("child exited with status {}", exit_status);
-                    notifiable_child_exit_notifier.notify_exit(exit_status);
+                    // The shell was exited on purpose, rather than
+                    // going down with the machine or the daemon.
+                    definitions.forget(&session_name);
+                    exit_status
                 }
-                Ok((_, None)) => {
+                Ok(None) => {
                     info!("child exited without status, using 1");
-                    notifiable_child_exit_notifier.notify_exit(1);
+                    1
                 }
                 Err(e) => {
                     info!("error waiting on child, using exit status 1: {:?}", e);
-                    notifiable_child_exit_notifier.notify_exit(1);
+                    1
                 }
-            }
+            };
+            notifiable_child_exit_notifier.notify_exit(exit_status);
             info!("reaped child shell: {:?}", waitable_child);
-        });
-
-        // Inject the prompt prefix, if any. For custom commands, avoid doing this
-        // since we have no idea what the command is so the shell code probably won't
-        // work.
-        if header.cmd.is_none() {
-            info!("injecting prompt prefix");
-            let prompt_prefix = self
-                .config
+            drop(login_records);
+            hook_commands::fire(
+                &hook_config,
+                hook_commands::Event::SessionExit(exit_status),
+                &session_name,
+            );
+
+            // A session that exited while detached waits around for the
+            // next attach to collect its exit status, but only for so long.
+            let linger = hook_config
                 .get()
-                .prompt_prefix
-                .clone()
-                .unwrap_or(String::from(DEFAULT_PROMPT_PREFIX));
-            if let Err(err) = prompt::inject_prefix(&mut fork, &prompt_prefix, &header.name) {
-                warn!("issue injecting prefix: {:?}", err);
+                .exited_session_linger
+                .as_deref()
+                .and_then(|s| duration::parse(s).ok());
+            if let Some(linger) = linger {
+                thread::sleep(linger);
+                let mut shells = shells.shard(&session_name);
+                let lingering = shells.get(&session_name).map(|s| {
+                    Arc::ptr_eq(&s.child_exit_notifier, &notifiable_child_exit_notifier)
+                        && s.inner.try_lock().is_ok()
+                });
+                if lingering == Some(true) {
+                    info!("exited session lingered for {:?}, reaping it", linger);
+                    shells.remove(&session_name);
+                }
             }
-        }
+        });
 
         let (client_connection_tx, client_connection_rx) = crossbeam_channel::bounded(0);
         let (client_connection_ack_tx, client_connection_ack_rx) = crossbeam_channel::bounded(0);
@@ -754,51 +2993,150 @@ impl Server {
             tty_size_change: tty_size_change_tx,
             tty_size_change_ack: tty_size_change_ack_rx,
         }));
+        let keybindings = Arc::new(Mutex::new(shell::KeybindingOverrides::default()));
+        let alerts = Arc::new(Mutex::new(shell::PendingAlerts::default()));
+        let activity = Arc::new(Mutex::new(activity::Monitor::new()));
+        let recorder = recorder.map(|r| Arc::new(Mutex::new(r)));
+        let cast = Arc::new(Mutex::new(None));
+        if self.config.get().recording.as_ref().and_then(|r| r.auto).unwrap_or(false) {
+            match self.start_cast(&header.name, None, &header.local_tty_size, term.as_deref()) {
+                Ok(recorder) => {
+                    info!("recording session to {:?}", recorder.path);
+                    *cast.lock().unwrap() = Some(recorder);
+                }
+                Err(err) => warn!("could not start recording: {:?}", err),
+            }
+        }
+        let acl = Arc::new(Mutex::new(acl::Acl::load(
+            self.acl_path(&header.name)?,
+            unistd::getuid().as_raw(),
+        )));
+        let lock = Arc::new(lock::State::new());
         let mut session_inner = shell::SessionInner {
             name: header.name.clone(),
             reader_ctl: Arc::clone(&reader_ctl),
-            pty_master: fork,
-            client_stream: Some(client_stream),
+            pty: Arc::clone(&pty),
+            client_stream,
             config: self.config.clone(),
+            keybindings: Arc::clone(&keybindings),
+            custom_actions: Arc::clone(&self.custom_actions),
             reader_join_h: None,
             term_db,
             daily_messenger: Arc::clone(&self.daily_messenger),
             needs_initial_motd_dump: dump_motd_on_new_session,
-            custom_cmd: header.cmd.is_some(),
+            custom_cmd,
+            recorder: recorder.clone(),
+            cast: Arc::clone(&cast),
+            acl: Arc::clone(&acl),
+            lock: Arc::clone(&lock),
         };
-        let child_pid = session_inner.pty_master.child_pid().ok_or(anyhow!("no child pid"))?;
-        session_inner.reader_join_h = Some(session_inner.spawn_reader(shell::ReaderArgs {
-            conn_id,
-            tty_size: header.local_tty_size.clone(),
-            scrollback_lines: match (
-                self.config.get().output_spool_lines,
-                &self.config.get().session_restore_mode,
-            ) {
+        let child_pid = session_inner.pty.child_pid();
+        let scrollback_lines =
+            match (self.config.get().output_spool_lines, &self.config.get().session_restore_mode) {
                 (Some(l), _) => l,
                 (None, Some(config::SessionRestoreMode::Lines(l))) => *l as usize,
                 (None, _) => DEFAULT_OUTPUT_SPOOL_LINES,
-            },
-            session_restore_mode:
-                self.config.get().session_restore_mode.clone().unwrap_or_default(),
+            };
+        let session_restore_mode =
+            self.config.get().session_restore_mode.clone().unwrap_or_default();
+        // Spilling is only worth it if the spool keeps more than the
+        // budget, and there is no spool at all in the simple mode. The
+        // session can get along fine keeping everything in memory, so
+        // trouble with the ring file doesn't keep it from starting.
+        let spill = match self.config.get().scrollback_memory_lines {
+            Some(memory_lines)
+                if memory_lines < scrollback_lines
+                    && !matches!(session_restore_mode, config::SessionRestoreMode::Simple) =>
+            {
+                match paths::state_dir(&self.config.get()).and_then(|state_dir| {
+                    spill::Spill::create(
+                        &state_dir,
+                        &header.name,
+                        memory_lines,
+                        scrollback_lines - memory_lines,
+                    )
+                }) {
+                    Ok(spill) => Some(spill),
+                    Err(err) => {
+                        warn!("keeping all the scrollback in memory: {:?}", err);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        session_inner.reader_join_h = Some(session_inner.spawn_reader(shell::ReaderArgs {
+            conn_id,
+            tty_size: header.local_tty_size.clone(),
+            scrollback_lines,
+            spill,
+            session_restore_mode,
             client_connection: client_connection_rx,
             client_connection_ack: client_connection_ack_tx,
             tty_size_change: tty_size_change_rx,
             tty_size_change_ack: tty_size_change_ack_tx,
+            hooks: Arc::clone(&self.hooks),
+            alerts: Arc::clone(&alerts),
+            activity: Arc::clone(&activity),
+            paste_buffers: Arc::clone(&self.paste_buffers),
         })?);
 
-        if let Some(ttl_secs) = header.ttl_secs {
+        let ttl =
+            ttl_reaper::session_ttl(&self.config.get(), header.ttl_secs.map(Duration::from_secs));
+        if let Some(ttl) = ttl {
             info!("registering session with ttl with the reaper");
-            self.register_new_reapable_session
-                .send((header.name.clone(), Instant::now().add(Duration::from_secs(ttl_secs))))
-                .context("sending reapable session registration msg")?;
+            // a session picked up from a holder has already used up
+            // some of its lifetime
+            let age = started_at.elapsed().unwrap_or_default();
+            // a ttl too long to count down to is as good as none
+            if let Some(reap_at) = Instant::now().checked_add(ttl.saturating_sub(age)) {
+                self.register_new_reapable_session
+                    .send((header.name.clone(), reap_at))
+                    .context("sending reapable session registration msg")?;
+            }
         }
 
+        self.ensure_session_dir(&header.name)?;
+        let status_file = status_file::StatusFile::new(
+            self.status_file_path(&header.name)?,
+            &header.name,
+            term.as_deref(),
+            started_at,
+        );
+        let ctl = match ctl::Socket::listen(
+            self.ctl_socket_path(&header.name)?,
+            &header.name,
+            child_pid,
+            Arc::clone(&self.shells),
+        ) {
+            Ok(ctl) => Some(ctl),
+            Err(err) => {
+                warn!("could not set up ctl socket: {:?}", err);
+                None
+            }
+        };
+
         Ok(shell::Session {
             reader_ctl,
             pager_ctl: Arc::new(Mutex::new(None)),
+            keybindings,
+            alerts,
+            activity,
+            status_file: Arc::new(Mutex::new(status_file)),
+            ctl,
+            pty,
+            recorder,
+            lock,
+            cast,
+            acl,
+            env: Mutex::new(SessionEnv::default()),
+            term,
+            term_override: None,
+            cmd: header.cmd.clone(),
+            attaches: AtomicU64::new(0),
             child_pid,
             child_exit_notifier,
-            started_at: time::SystemTime::now(),
+            started_at,
             inner: Arc::new(Mutex::new(session_inner)),
         })
     }
@@ -810,6 +3148,7 @@ impl Server {
         cmd: &mut process::Command,
         user_info: &user::Info,
         header: &protocol::AttachHeader,
+        term_override: Option<&str>,
     ) -> anyhow::Result<Option<String>> {
         cmd.env("HOME", &user_info.home_dir)
             .env(
@@ -822,22 +3161,33 @@ impl Server {
                     .unwrap_or(DEFAULT_INITIAL_SHELL_PATH),
             )
             .env("SHPOOL_SESSION_NAME", &header.name)
+            .env("SHPOOL_SESSION_DIR", self.session_dir(&header.name)?)
+            .env("SHPOOL_SESSION_STATUS_FILE", self.status_file_path(&header.name)?)
+            .env("SHPOOL_SESSION_CTL", self.ctl_socket_path(&header.name)?)
             .env("SHELL", &user_info.default_shell)
             .env("USER", &user_info.user)
-            .env("SSH_AUTH_SOCK", self.ssh_auth_sock_symlink(PathBuf::from(&header.name)));
+            .env("SSH_AUTH_SOCK", self.ssh_auth_sock_symlink(&header.name)?)
+            // point shpool commands run in the session back at us
+            .env(consts::SOCKET_VAR, &self.socket);
+        if let Some(instance) = &self.instance {
+            cmd.env(consts::INSTANCE_VAR, instance);
+        }
 
         if let Ok(xdg_runtime_dir) = env::var("XDG_RUNTIME_DIR") {
             cmd.env("XDG_RUNTIME_DIR", xdg_runtime_dir);
         }
 
         // Most of the time, use the TERM that the user sent along in
-        // the attach header. If they have an explicit TERM value set
+        // the attach header, or the default_term from the config if
+        // they didn't send one. If they have an explicit TERM value set
         // in their config file, use that instead. If they have a blank
         // term in their config, don't set TERM in the spawned shell at
-        // all.
-        let mut term = None;
-        if let Some(t) = header.local_env_get("TERM") {
-            term = Some(String::from(t));
+        // all. A TERM passed with --term beats all of these.
+        let mut term = header.local_env_get("TERM").map(String::from);
+        if term.as_deref().map(str::is_empty).unwrap_or(true) {
+            if let Some(t) = &self.config.get().default_term {
+                term = Some(t.clone());
+            }
         }
         if let Some(env) = self.config.get().env.as_ref() {
             term = match env.get("TERM") {
@@ -866,6 +3216,9 @@ impl Server {
                 cmd.envs(env);
             }
         }
+        if let Some(t) = term_override {
+            term = Some(String::from(t));
+        }
         info!("injecting TERM into shell {:?}", term);
         if let Some(t) = &term {
             cmd.env("TERM", t);
@@ -897,16 +3250,356 @@ impl Server {
         Ok(term)
     }
 
-    fn ssh_auth_sock_symlink(&self, session_name: PathBuf) -> PathBuf {
-        self.runtime_dir.join("sessions").join(session_name).join("ssh-auth-sock.socket")
+    fn ssh_auth_sock_symlink(&self, session_name: &str) -> anyhow::Result<PathBuf> {
+        Ok(self.session_dir(session_name)?.join("ssh-auth-sock.socket"))
+    }
+
+    /// The directory where we keep per-session runtime data. The name
+    /// gets checked here, where it turns into a path, so that a name
+    /// which slipped past the checks on the way in can't point it
+    /// outside of the sessions dir.
+    fn session_dir(&self, session_name: &str) -> anyhow::Result<PathBuf> {
+        check_session_name(session_name).map_err(|e| anyhow!(e))?;
+        Ok(self.runtime_dir.join("sessions").join(session_name))
+    }
+
+    /// The path of the session's status file. It lives next to the
+    /// session dir rather than in it so that tools can find the status
+    /// of every session with a single glob.
+    fn status_file_path(&self, session_name: &str) -> anyhow::Result<PathBuf> {
+        check_session_name(session_name).map_err(|e| anyhow!(e))?;
+        Ok(self.runtime_dir.join("sessions").join(format!("{}.json", session_name)))
+    }
+
+    /// Start an asciicast recording of the session, to the given file or
+    /// to a new file in the configured recordings dir.
+    fn start_cast(
+        &self,
+        session_name: &str,
+        file: Option<String>,
+        size: &tty::Size,
+        term: Option<&str>,
+    ) -> anyhow::Result<cast::Recorder> {
+        let path = match file {
+            Some(file) => PathBuf::from(file),
+            None => {
+                let config = self.config.get();
+                let dir = match config.recording.as_ref().and_then(|r| r.dir.as_ref()) {
+                    Some(dir) => PathBuf::from(dir),
+                    None => paths::state_dir(&config)?.join("recordings"),
+                };
+                cast::default_path(&dir, session_name)
+            }
+        };
+        cast::Recorder::create(path, session_name, size, term)
+    }
+
+    /// Where the session's ACL gets saved.
+    fn acl_path(&self, session_name: &str) -> anyhow::Result<PathBuf> {
+        Ok(self.session_dir(session_name)?.join(acl::FILE_NAME))
+    }
+
+    /// The path of the session's control socket. Like the status file,
+    /// it lives outside of the session dir, so that all of them can be
+    /// found in one place.
+    fn ctl_socket_path(&self, session_name: &str) -> anyhow::Result<PathBuf> {
+        check_session_name(session_name).map_err(|e| anyhow!(e))?;
+        Ok(self.runtime_dir.join("ctl").join(session_name))
+    }
+
+    /// Create the per-session runtime directory if needed, making sure
+    /// that the parent sessions directory is only accessible by the
+    /// current user.
+    fn ensure_session_dir(&self, session_name: &str) -> anyhow::Result<PathBuf> {
+        let session_dir = self.session_dir(session_name)?;
+        fs::create_dir_all(&session_dir).context("creating session dir")?;
+
+        let sessions_dir = session_dir.parent().ok_or(anyhow!("no sessions dir"))?;
+        let sessions_meta = fs::metadata(sessions_dir).context("stating sessions dir")?;
+
+        // set RWX bits for user and no one else
+        let mut sessions_perm = sessions_meta.permissions();
+        if sessions_perm.mode() != 0o700 {
+            sessions_perm.set_mode(0o700);
+            fs::set_permissions(sessions_dir, sessions_perm)
+                .context("locking down permissions for sessions dir")?;
+        }
+
+        Ok(session_dir)
     }
 }
 
 #[instrument(skip_all)]
+/// Parse and validate a keybinding received over the control protocol.
+fn parse_keybinding(binding: &str, action: &str) -> anyhow::Result<config::Keybinding> {
+    let action: keybindings::Action = action.parse()?;
+    keybindings::Bindings::new([(binding, action.clone())]).context("invalid keybinding")?;
+    Ok(config::Keybinding { binding: String::from(binding), action })
+}
+
+/// Compute the space used by the given path, without following symlinks.
+fn disk_usage(path: &Path) -> io::Result<u64> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return Ok(meta.len());
+    }
+
+    let mut total = meta.len();
+    for entry in fs::read_dir(path)? {
+        total += disk_usage(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+/// The replay setting from a list of attach options.
+fn replay_option(options: &[protocol::AttachOption]) -> protocol::Replay {
+    options
+        .iter()
+        .find_map(|o| match o {
+            protocol::AttachOption::Replay(r) => Some(*r),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+/// The directory to start a new session in from a list of attach
+/// options, if there is one.
+fn cwd_option(options: &[protocol::AttachOption]) -> Option<&str> {
+    options.iter().find_map(|o| match o {
+        protocol::AttachOption::Cwd(cwd) => Some(cwd.as_str()),
+        _ => None,
+    })
+}
+
+/// The TERM to start a new session with from a list of attach options,
+/// if there is one.
+fn term_option(options: &[protocol::AttachOption]) -> Option<&str> {
+    options.iter().find_map(|o| match o {
+        protocol::AttachOption::Term(term) => Some(term.as_str()),
+        _ => None,
+    })
+}
+
+/// The pid whose namespaces to start a new session in from a list of
+/// attach options, if there is one.
+fn nsenter_option(options: &[protocol::AttachOption]) -> Option<i32> {
+    options.iter().find_map(|o| match o {
+        protocol::AttachOption::NsenterPid(pid) => Some(*pid),
+        _ => None,
+    })
+}
+
+/// The command to type into the session on reattach from a list of
+/// attach options, if there is one.
+fn on_attach_cmd_option(options: &[protocol::AttachOption]) -> Option<&str> {
+    options.iter().find_map(|o| match o {
+        protocol::AttachOption::OnAttachCmd(cmd) => Some(cmd.as_str()),
+        _ => None,
+    })
+}
+
+/// The current working directory of the given process, as tracked by
+/// the kernel.
+fn child_cwd(pid: libc::pid_t) -> Option<String> {
+    match fs::read_link(format!("/proc/{}/cwd", pid)) {
+        Ok(cwd) => Some(cwd.to_string_lossy().into_owned()),
+        Err(e) => {
+            info!("reading cwd of pid {}: {:?}", pid, e);
+            None
+        }
+    }
+}
+
+/// Look up the terminfo for the given TERM, falling back to whatever
+/// we can find when there is no TERM.
+fn term_db(term: Option<&str>) -> anyhow::Result<termini::TermInfo> {
+    if let Some(term) = term {
+        return termini::TermInfo::from_name(term).context("resolving terminfo");
+    }
+    warn!("no $TERM, using default terminfo");
+    match termini::TermInfo::from_env() {
+        Ok(db) => Ok(db),
+        Err(err) => {
+            warn!("could not get terminfo from env: {:?}", err);
+            match termini::TermInfo::from_name("xterm") {
+                Ok(db) => Ok(db),
+                Err(err) => {
+                    warn!("could not get xterm terminfo: {:?}", err);
+                    let empty_db = io::Cursor::new(vec![]);
+                    termini::TermInfo::parse(empty_db).context("getting terminfo db")
+                }
+            }
+        }
+    }
+}
+
+/// The reader holds off on reading any output until it gets its first
+/// connection, so tell the reader of a session that starts out without
+/// a client that there is no client to wait for.
+fn start_detached(session: &shell::Session) -> anyhow::Result<()> {
+    let reader_ctl = session.reader_ctl.lock().unwrap();
+    reader_ctl
+        .client_connection
+        .send_timeout(shell::ClientConnectionMsg::Disconnect, SESSION_MSG_TIMEOUT)
+        .context("starting reader without a client")?;
+    reader_ctl
+        .client_connection_ack
+        .recv_timeout(SESSION_MSG_TIMEOUT)
+        .context("getting client conn ack")?;
+    Ok(())
+}
+
+/// What a session whose shell or command has exited left on its
+/// screen, as plain text without the trailing blank rows, if the reader
+/// thread is still around to say.
+fn final_screen(session: &shell::Session) -> Option<String> {
+    let reader_ctl = session.reader_ctl.lock().unwrap();
+    let status = reader_ctl
+        .client_connection
+        .send_timeout(
+            shell::ClientConnectionMsg::Capture(protocol::CaptureMode::Screen { ansi: false }),
+            SESSION_MSG_TIMEOUT,
+        )
+        .context("sending capture request to reader")
+        .and_then(|_| {
+            reader_ctl
+                .client_connection_ack
+                .recv_timeout(SESSION_MSG_TIMEOUT)
+                .context("getting client conn ack")
+        });
+    match status {
+        Ok(shell::ClientConnectionStatus::Output(Some(output))) => {
+            let screen = String::from_utf8_lossy(&output);
+            let screen = screen.trim_end();
+            if screen.is_empty() {
+                None
+            } else {
+                Some(format!("{}\n", screen))
+            }
+        }
+        Ok(_) => None,
+        Err(err) => {
+            warn!("capturing final screen: {:?}", err);
+            None
+        }
+    }
+}
+
 fn parse_connect_header(stream: &mut UnixStream) -> anyhow::Result<protocol::ConnectHeader> {
-    let header: protocol::ConnectHeader =
-        bincode::deserialize_from(stream).context("parsing header")?;
-    Ok(header)
+    protocol::read_frame(stream).context("parsing header")
+}
+
+/// Squeeze any terminal size in the header into one the daemon can
+/// cope with, so that a client reporting a bogus size doesn't take down
+/// the session's threads.
+fn clamp_tty_sizes(header: &mut protocol::ConnectHeader) {
+    use protocol::ConnectHeader::*;
+    let size = match header {
+        Attach(h) | AttachWithOptions(h, _) | Create(h) | CreateWithOptions(h, _) => {
+            &mut h.local_tty_size
+        }
+        Resurrect(r) => &mut r.local_tty_size,
+        SessionMessage(protocol::SessionMessageRequest {
+            payload: protocol::SessionMessageRequestPayload::Resize(r),
+            ..
+        }) => &mut r.tty_size,
+        _ => return,
+    };
+    *size = size.clamped();
+}
+
+/// Make sure a new session's name is usable as a file name, since
+/// per-session state lives in files and dirs named after the session.
+fn check_session_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(format!("'{}' is not a valid session name", name.escape_debug()));
+    }
+    Ok(())
+}
+
+/// Stands in for the pty backend while fuzzing, running cat rather than
+/// whatever command the fuzzer came up with.
+#[cfg(feature = "fuzz")]
+#[derive(Debug)]
+struct FuzzPtyBackend;
+
+#[cfg(feature = "fuzz")]
+impl pty::PtyBackend for FuzzPtyBackend {
+    fn spawn(&self, req: pty::SpawnRequest) -> anyhow::Result<Box<dyn pty::Pty + Send + Sync>> {
+        let req = pty::SpawnRequest { cmd: process::Command::new("cat"), ..req };
+        pty::PtyBackend::spawn(&pty::Fake::default(), req)
+    }
+}
+
+/// Feed arbitrary bytes to the connection handler as though a client had
+/// sent them, for the fuzz target in libshpool/fuzz. Nothing should
+/// panic, and once the connection is done with, no client should still
+/// be attached to a session.
+#[cfg(feature = "fuzz")]
+pub fn fuzz(data: &[u8]) {
+    use std::io::Write;
+
+    use nix::sys::signal::{self, SigHandler, Signal};
+
+    lazy_static::lazy_static! {
+        static ref SERVER: (Arc<Server>, tempfile::TempDir) = {
+            // Writing to a client that hung up should be an error, just
+            // like it is in the daemon proper.
+            //
+            // Safety: ignoring a signal doesn't install any handler code.
+            unsafe { signal::signal(Signal::SIGPIPE, SigHandler::SigIgn) }
+                .expect("ignoring SIGPIPE");
+            let dir = tempfile::tempdir().expect("creating runtime dir");
+            let server = Server::new(ServerOptions {
+                config: config::Manager::from_config(config::Config::default()),
+                hooks: Box::new(crate::NoopHooks {}),
+                pty_backend: Box::new(FuzzPtyBackend),
+                custom_actions: CustomActions::default(),
+                runtime_dir: dir.path().to_path_buf(),
+                socket: dir.path().join("shpool.socket"),
+                socket_file: None,
+                instance: None,
+            })
+            .expect("creating server");
+            (server, dir)
+        };
+    }
+    let server = &SERVER.0;
+
+    let (mut client, mut stream) = UnixStream::pair().expect("creating socket pair");
+    let data = data.to_vec();
+    let client = thread::spawn(move || {
+        // the daemon is free to hang up before reading all of it
+        let _ = client.write_all(&data);
+        let _ = client.shutdown(net::Shutdown::Write);
+        let _ = io::copy(&mut client, &mut io::sink());
+    });
+
+    let conn_id = server.conn_counter.fetch_add(1, Ordering::Relaxed) + 1;
+    match server.read_connect_header(&mut stream) {
+        // stopping or restarting would take the fuzzer down along with
+        // the daemon, and recordings go wherever the client says
+        Ok(
+            protocol::ConnectHeader::Stop(_)
+            | protocol::ConnectHeader::Restart(_)
+            | protocol::ConnectHeader::Record(_),
+        )
+        | Err(_) => drop(stream),
+        Ok(header) => {
+            let _ = server.dispatch(stream, conn_id, header);
+        }
+    }
+
+    assert_eq!(server.shells.attached(), 0, "a client outlived its connection");
+    let names = server.shells.names();
+    assert_eq!(server.shells.count(), names.len(), "the session count drifted");
+    // start the next input off with an empty table
+    for name in names.iter() {
+        if let Some(session) = server.shells.shard(name).remove(name) {
+            let _ = session.kill();
+        }
+    }
+    client.join().expect("joining client thread");
 }
 
 #[instrument(skip_all)]
@@ -918,31 +3611,46 @@ where
         .set_write_timeout(Some(consts::SOCK_STREAM_TIMEOUT))
         .context("setting write timout on inbound session")?;
 
-    let serializeable_stream = stream.try_clone().context("cloning stream handle")?;
-    bincode::serialize_into(serializeable_stream, &header).context("writing reply")?;
+    protocol::write_frame(stream, &header).context("writing reply")?;
 
     stream.set_write_timeout(None).context("unsetting write timout on inbound session")?;
     Ok(())
 }
 
 /// check_peer makes sure that a process dialing in on the shpool
-/// control socket has the same UID as the current user and that
-/// both have the same executable path.
-fn check_peer(sock: &UnixStream) -> anyhow::Result<()> {
-    use nix::sys::socket;
+/// control socket has the same UID as the current user, or is allowed
+/// in by the config, and warns if the two have different executable
+/// paths.
+fn check_peer(sock: &UnixStream, allowed: Option<&config::AllowedPeers>) -> anyhow::Result<()> {
+    let peer = peer_creds(sock)?;
+    check_peer_creds(&peer, unistd::Uid::current().as_raw(), allowed, exe_for_pid)
+}
 
-    let peer_creds = socket::getsockopt(sock, socket::sockopt::PeerCredentials)
-        .context("could not get peer creds from socket")?;
-    let peer_uid = unistd::Uid::from_raw(peer_creds.uid());
-    let self_uid = unistd::Uid::current();
-    if peer_uid != self_uid {
+/// Decide whether to take a connection from the given peer, looking up
+/// the binaries with the given function.
+fn check_peer_creds<F>(
+    peer: &attach_auth::Peer,
+    self_uid: u32,
+    allowed: Option<&config::AllowedPeers>,
+    exe_for_pid: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(unistd::Pid) -> anyhow::Result<PathBuf>,
+{
+    if !peer_allowed(peer.uid, peer.gid, self_uid, allowed) {
+        warn!("refusing connection from uid={} gid={}", peer.uid, peer.gid);
         return Err(anyhow!("shpool prohibits connections across users"));
     }
 
-    let peer_pid = unistd::Pid::from_raw(peer_creds.pid());
-    let self_pid = unistd::Pid::this();
-    let peer_exe = exe_for_pid(peer_pid).context("could not resolve exe from the pid")?;
-    let self_exe = exe_for_pid(self_pid).context("could not resolve our own exe")?;
+    // We can't look at the exe of another user's process without
+    // privileges, and a mismatch only gets a warning anyway, so
+    // allowed peers running as someone else go unchecked.
+    if peer.uid != self_uid {
+        return Ok(());
+    }
+    let peer_exe = exe_for_pid(unistd::Pid::from_raw(peer.pid))
+        .context("could not resolve exe from the pid")?;
+    let self_exe = exe_for_pid(unistd::Pid::this()).context("could not resolve our own exe")?;
     if peer_exe != self_exe {
         warn!("attach binary differs from daemon binary");
     }
@@ -950,7 +3658,96 @@ fn check_peer(sock: &UnixStream) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn peer_allowed(
+    peer_uid: u32,
+    peer_gid: u32,
+    self_uid: u32,
+    allowed: Option<&config::AllowedPeers>,
+) -> bool {
+    if peer_uid == self_uid {
+        return true;
+    }
+    let allowed = match allowed {
+        Some(a) => a,
+        None => return false,
+    };
+
+    allowed.uids.as_ref().map(|uids| uids.contains(&peer_uid)).unwrap_or(false)
+        || allowed.gids.as_ref().map(|gids| gids.contains(&peer_gid)).unwrap_or(false)
+}
+
+/// The pid of the process on the other end of the socket, if it can be
+/// determined.
+fn peer_creds(sock: &UnixStream) -> anyhow::Result<attach_auth::Peer> {
+    use nix::sys::socket;
+
+    let creds = socket::getsockopt(sock, socket::sockopt::PeerCredentials)
+        .context("could not get peer creds from socket")?;
+    Ok(attach_auth::Peer { uid: creds.uid(), gid: creds.gid(), pid: creds.pid() })
+}
+
+/// The access the peer on the other end of the stream has according
+/// to the given ACL. Peers whose credentials can't be checked get none.
+fn peer_access(sock: &UnixStream, acl: &Mutex<acl::Acl>) -> Option<protocol::Access> {
+    let peer = peer_creds(sock).ok()?;
+    acl.lock().unwrap().access(peer.uid)
+}
+
+/// Whether the peer on the other end of the stream is the user running
+/// the daemon. Creating sessions and anything that reaches past a
+/// single session's ACL is for them only, even if allowed_peers lets
+/// others connect.
+fn peer_is_owner(sock: &UnixStream) -> bool {
+    peer_creds(sock).map(|peer| peer.uid == unistd::getuid().as_raw()).unwrap_or(false)
+}
+
+/// The reason given to peers that try something only the owner may do.
+fn owner_only(what: &str) -> String {
+    format!("only the user running the daemon may {}", what)
+}
+
+fn peer_pid(sock: &UnixStream) -> Option<i32> {
+    use nix::sys::socket;
+
+    socket::getsockopt(sock, socket::sockopt::PeerCredentials).map(|creds| creds.pid()).ok()
+}
+
 fn exe_for_pid(pid: unistd::Pid) -> anyhow::Result<PathBuf> {
     let path = std::fs::read_link(format!("/proc/{}/exe", pid))?;
     Ok(path)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn peer_allowlist() {
+        assert!(peer_allowed(1000, 1000, 1000, None));
+        assert!(!peer_allowed(1001, 1000, 1000, None));
+        assert!(!peer_allowed(1001, 1000, 1000, Some(&config::AllowedPeers::default())));
+
+        let allowed = config::AllowedPeers { uids: Some(vec![1001]), gids: Some(vec![2000]) };
+        assert!(peer_allowed(1001, 1001, 1000, Some(&allowed)));
+        assert!(peer_allowed(1002, 2000, 1000, Some(&allowed)));
+        assert!(!peer_allowed(1002, 1002, 1000, Some(&allowed)));
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn peer_exe_check() {
+        let allowed = config::AllowedPeers { uids: Some(vec![1001]), gids: None };
+        let shpool = |_| Ok(PathBuf::from("/usr/bin/shpool"));
+        let no_access = |_| Err(anyhow!("Permission denied (os error 13)"));
+        let peer = |uid| attach_auth::Peer { uid, gid: uid, pid: 4242 };
+
+        // another user's exe can't be looked at, which must not keep
+        // out a peer the config lets in
+        assert!(check_peer_creds(&peer(1001), 1000, Some(&allowed), no_access).is_ok());
+        assert!(check_peer_creds(&peer(1002), 1000, Some(&allowed), shpool).is_err());
+        assert!(check_peer_creds(&peer(1000), 1000, None, shpool).is_ok());
+        assert!(check_peer_creds(&peer(1000), 1000, None, no_access).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! The daemon's record of a session's environment.
+
+  There is no way to reach into the environment of a shell that is
+  already running, so instead the daemon keeps track of what the
+  environment ought to be and writes it out to
+  `$SHPOOL_SESSION_DIR/forward_env` as a shell script that the session
+  can source. The record gets updated with the forwarded variables on
+  every attach, and by `shpool setenv`, much like tmux's
+  update-environment.
+*/
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::{anyhow, Context};
+
+#[derive(Debug, Default)]
+pub struct SessionEnv {
+    /// A value of None marks a variable that has been explicitly
+    /// unset, so that the script can unset it in the shell too.
+    vars: BTreeMap<String, Option<String>>,
+}
+
+impl SessionEnv {
+    /// Pick up the variables a client forwarded on attach.
+    pub fn update_forwarded(&mut self, local_env: &[(String, String)]) {
+        for (var, val) in local_env.iter() {
+            // TERM is tied to the terminal the session was created with, and
+            // SSH_AUTH_SOCK is handled by the symlink.
+            if var == "TERM" || var == "SSH_AUTH_SOCK" || check_name(var).is_err() {
+                continue;
+            }
+            self.vars.insert(var.clone(), Some(val.clone()));
+        }
+    }
+
+    /// Set a variable, or unset it if the value is None. The name should
+    /// already have been checked with `check_name`.
+    pub fn set(&mut self, var: String, val: Option<String>) {
+        self.vars.insert(var, val);
+    }
+
+    pub fn vars(&self) -> Vec<(String, Option<String>)> {
+        self.vars.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    /// The environment as a script of `export` and `unset` statements.
+    pub fn script(&self) -> String {
+        let mut script = String::new();
+        for (var, val) in self.vars.iter() {
+            match val {
+                Some(val) => {
+                    script.push_str(&format!("export {}={}\n", var, shell_words::quote(val)))
+                }
+                None => script.push_str(&format!("unset {}\n", var)),
+            }
+        }
+        script
+    }
+
+    /// Write the script out to the forward_env file in the given
+    /// session directory.
+    pub fn write(&self, session_dir: &Path) -> anyhow::Result<()> {
+        // write then rename so that a shell sourcing the file never
+        // sees a partial write
+        let env_file = session_dir.join("forward_env");
+        let tmp_env_file = session_dir.join("forward_env.tmp");
+        fs::write(&tmp_env_file, self.script()).context("writing forward_env file")?;
+        fs::rename(&tmp_env_file, &env_file).context("renaming forward_env file")?;
+        Ok(())
+    }
+}
+
+/// Variable names get written into the script unquoted, so only allow
+/// the names that a shell would accept anyway.
+pub fn check_name(var: &str) -> anyhow::Result<()> {
+    let mut chars = var.chars();
+    let valid = match chars.next() {
+        Some(c) => {
+            (c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    };
+    if !valid {
+        return Err(anyhow!("invalid variable name '{}'", var));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn script() {
+        let mut env = SessionEnv::default();
+        env.update_forwarded(&[
+            (String::from("TERM"), String::from("xterm")),
+            (String::from("DISPLAY"), String::from(":1")),
+            (String::from("BAD-NAME"), String::from("x")),
+        ]);
+        env.set(String::from("KRB5CCNAME"), Some(String::from("FILE:/tmp/krb5cc 1")));
+        env.set(String::from("OLDPWD"), None);
+        assert_eq!(
+            env.script(),
+            "export DISPLAY=:1\nexport KRB5CCNAME='FILE:/tmp/krb5cc 1'\nunset OLDPWD\n"
+        );
+
+        // a later attach wins over an earlier setenv
+        env.update_forwarded(&[(String::from("KRB5CCNAME"), String::from("FILE:/tmp/new"))]);
+        assert_eq!(
+            env.vars()[1],
+            (String::from("KRB5CCNAME"), Some(String::from("FILE:/tmp/new")))
+        );
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn names() {
+        for good in ["DISPLAY", "_x", "LC_ALL", "a1"] {
+            assert!(check_name(good).is_ok(), "{}", good);
+        }
+        for bad in ["", "1A", "A-B", "A B", "A=B", "$(rm)", "é"] {
+            assert!(check_name(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -13,27 +13,38 @@
 // limitations under the License.
 
 use std::{
+    collections::VecDeque,
     io,
     io::{Read, Write},
-    net,
+    mem, net,
     ops::Add,
-    os::unix::net::UnixStream,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
-    },
+    os::{fd::AsFd, unix::net::UnixStream},
+    sync::{atomic::AtomicU64, Arc, Mutex},
     thread, time,
     time::Duration,
 };
 
 use anyhow::{anyhow, Context};
-use nix::{sys::signal, unistd::Pid};
+use nix::{poll, sys::signal, unistd::Pid};
 use tracing::{debug, error, info, instrument, span, trace, warn, Level};
 
 use crate::{
-    consts,
-    daemon::{config, exit_notify::ExitNotifier, keybindings, pager::PagerCtl, prompt, show_motd},
-    protocol, test_hooks, tty,
+    audit, consts,
+    daemon::{
+        acl, activity, cast, config, copy_mode, ctl,
+        exit_notify::ExitNotifier,
+        hook_commands, hooks, keybindings, lock, metrics, osc,
+        output_queue::{self, OutputQueue},
+        pager::PagerCtl,
+        paste_buffers::PasteBuffers,
+        prompt, redact,
+        session_env::SessionEnv,
+        show_motd, spill,
+        status_file::StatusFile,
+        status_line::{self, StatusLine},
+        utf8, CustomActions,
+    },
+    protocol, pty, test_hooks, timing, tty,
 };
 
 // To prevent data getting dropped, we set this to be large, but we don't want
@@ -42,9 +53,10 @@ use crate::{
 // lazily initialize its rows, but that is likely a bunch of work.
 const VTERM_WIDTH: u16 = 1024 * 10;
 
-const SHELL_KILL_TIMEOUT: time::Duration = time::Duration::from_millis(500);
+// How much raw output each session hangs on to for `shpool capture --bytes`.
+const RAW_TAIL_SIZE: usize = 1024 * 64;
 
-const SUPERVISOR_POLL_DUR: time::Duration = time::Duration::from_millis(300);
+const SHELL_KILL_TIMEOUT: time::Duration = time::Duration::from_millis(500);
 
 // Chosen experimentally. This value is small enough that no human will likely
 // recognize it, and it seems to be large enough that emacs consistently picks
@@ -52,11 +64,66 @@ const SUPERVISOR_POLL_DUR: time::Duration = time::Duration::from_millis(300);
 // size.
 const REATTACH_RESIZE_DELAY: time::Duration = time::Duration::from_millis(50);
 
+// Puts a terminal back into its default modes (main screen, plain text
+// attributes, visible cursor, no mouse reporting or bracketed paste, ascii
+// charsets, full scroll region) without clearing the screen or scrollback
+// the way a full reset (RIS) would. The leading CAN and ST abort any escape
+// sequence or string that was left half written, for example by cat'ing a
+// binary.
+const SOFT_RESET: &[u8] =
+    b"\x18\x1b\\\x1b[!p\x1b[?1049l\x1b[0m\x1b[r\x1b[4l\x1b[?6l\x1b[?7h\x1b[?25h\
+    \x1b[?1l\x1b>\x1b[?2004l\x1b[?9l\x1b[?1000l\x1b[?1002l\x1b[?1003l\x1b[?1005l\x1b[?1006l\
+    \x1b(B\x1b)B\x0f";
+
+// Sent ahead of the screen redraw for a client that had output thrown
+// away. The CAN and ST abort any escape sequence or string that got cut
+// off partway through.
+const ABORT_SEQUENCE: &[u8] = b"\x18\x1b\\";
+
+// Clears the screen and homes the cursor, for taking down the lock screen.
+const CLEAR_SCREEN: &[u8] = b"\x1b[H\x1b[2J";
+
+// Puts the scroll region back to the whole screen, for a client that
+// something else has had the terminal of.
+const RESET_SCROLL_REGION: &[u8] = b"\x1b[r";
+
+// Switches to the alternate screen.
+const ENTER_ALT_SCREEN: &[u8] = b"\x1b[?1049h";
+
+// Clears the screen along with the terminal's own scrollback, for when
+// the session's scrollback gets cleared.
+const CLEAR_SCROLLBACK: &[u8] = b"\x1b[H\x1b[2J\x1b[3J";
+
+// How often to check whether what the status line says has changed.
+const STATUS_LINE_REFRESH: time::Duration = time::Duration::from_secs(1);
+
+// How long to wait for queued output to make it to a client before giving
+// up on sending the exit status of the shell.
+const EXIT_STATUS_DRAIN_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+// How long the attach loop goes between checks on whether the reader
+// thread has gotten to a message, or acked it, while it keeps the
+// client's output flowing.
+const READER_ACK_POLL_DUR: time::Duration = time::Duration::from_millis(10);
+
 // The reader thread should wake up relatively frequently so it can detect
 // reattach, but we don't need to go crazy since reattach is not part of
 // the inner loop.
 const READER_POLL_MS: u16 = 100;
 
+// Replays at least this big get announced to the client first,
+// unless the config says otherwise.
+const DEFAULT_REPLAY_NOTICE_BYTES: usize = 256 * 1024;
+
+// While in copy mode the reader thread is the one handling keystrokes, so
+// it needs to notice them quickly.
+const COPY_MODE_POLL_MS: u16 = 10;
+
+// The Ctrl-S that pauses output while flow control is on.
+const XOFF: u8 = 0x13;
+
+const PAUSED_MESSAGE: &str = "output paused by Ctrl-S, press Ctrl-Q to resume";
+
 /// Session represent a shell session
 #[derive(Debug)]
 pub struct Session {
@@ -65,12 +132,100 @@ pub struct Session {
     pub child_exit_notifier: Arc<ExitNotifier>,
     pub reader_ctl: Arc<Mutex<ReaderCtl>>,
     pub pager_ctl: Arc<Mutex<Option<PagerCtl>>>,
+    pub keybindings: Arc<Mutex<KeybindingOverrides>>,
+    /// Bells and notifications that went off while nobody was attached.
+    /// Shared with the reader thread, which clears them on reattach.
+    pub alerts: Arc<Mutex<PendingAlerts>>,
+    /// Tracks when the shell last produced output. Shared with the
+    /// reader thread, which does the tracking.
+    pub activity: Arc<Mutex<activity::Monitor>>,
+    /// The session's status file, which gets removed once the last
+    /// reference to it is dropped.
+    pub status_file: Arc<Mutex<StatusFile>>,
+    /// The session's control socket, if it could be set up. It goes
+    /// away along with the session.
+    #[allow(dead_code)]
+    pub ctl: Option<ctl::Socket>,
+    /// The same pty and audit recording the inner session has, so
+    /// that `shpool send` can type into the session even while a
+    /// client has the inner session locked.
+    pub pty: Arc<dyn pty::Pty + Send + Sync>,
+    pub recorder: Option<Arc<Mutex<audit::Recorder>>>,
+    /// Whether the session is locked, shared with the inner session so
+    /// that input from outside the attach loop can respect the lock.
+    pub lock: Arc<lock::State>,
+    /// The asciicast recording of the session, if it is being
+    /// recorded. Shared with the reader thread, which writes it.
+    pub cast: Arc<Mutex<Option<cast::Recorder>>>,
+    /// What the session's environment ought to be, as written out to
+    /// the forward_env file.
+    pub env: Mutex<SessionEnv>,
+    /// Who besides the daemon's user may use the session. Shared with
+    /// the threads serving the attached client.
+    pub acl: Arc<Mutex<acl::Acl>>,
+    /// The TERM the session's child was started with, if known.
+    pub term: Option<String>,
+    /// The TERM the session was asked to start with using --term, if
+    /// any, for starting it over with `--restart`.
+    pub term_override: Option<String>,
+    /// The command the session was started with in place of the
+    /// user's shell, if any, for starting it over with `--restart`.
+    pub cmd: Option<String>,
+    /// How many times a client has attached to the session, for the
+    /// banner `shpool attach` can print.
+    pub attaches: AtomicU64,
     /// Mutable state with the lock held by the servicing handle_attach thread
     /// while a tty is attached to the session. Probing the mutex can be used
     /// to determine if someone is currently attached to the session.
     pub inner: Arc<Mutex<SessionInner>>,
 }
 
+/// Keybindings set for a specific session with `shpool keybind`. Shared
+/// between the session struct (for calls originating with the cli) and the
+/// session inner struct so that changes apply while a client is attached.
+#[derive(Debug, Default)]
+pub struct KeybindingOverrides {
+    /// If set, used in place of the keybindings from the config.
+    pub bindings: Option<Vec<config::Keybinding>>,
+    /// Bumped on every change so the input thread knows when it
+    /// needs to recompile its bindings engine.
+    pub generation: u64,
+}
+
+impl KeybindingOverrides {
+    /// The keybindings that are actually in effect for the session.
+    pub fn effective(&self, config: &config::Config) -> Vec<config::Keybinding> {
+        if let Some(bindings) = &self.bindings {
+            return bindings.clone();
+        }
+        config.keybinding.clone().unwrap_or_else(|| {
+            vec![config::Keybinding {
+                binding: String::from("Ctrl-Space Ctrl-q"),
+                action: keybindings::Action::Detach,
+            }]
+        })
+    }
+
+    pub fn set(&mut self, bindings: Vec<config::Keybinding>) {
+        self.bindings = Some(bindings);
+        self.generation += 1;
+    }
+}
+
+/// Counts of the alerts a detached session has raised since a client
+/// was last attached, so that `shpool list` can point them out.
+#[derive(Debug, Default, Clone)]
+pub struct PendingAlerts {
+    pub bells: u64,
+    pub notifications: u64,
+}
+
+impl PendingAlerts {
+    pub fn is_empty(&self) -> bool {
+        self.bells == 0 && self.notifications == 0
+    }
+}
+
 impl Session {
     /// Kill the session, first sending a SIGHUP and then resorting to a
     /// SIGKILL if that doesn't work (SIGTERM doesn't really work on shells).
@@ -99,13 +254,28 @@ impl Session {
 pub struct SessionInner {
     pub name: String, // to improve logging
     pub reader_ctl: Arc<Mutex<ReaderCtl>>,
-    pub pty_master: shpool_pty::fork::Fork,
+    pub pty: Arc<dyn pty::Pty + Send + Sync>,
     pub client_stream: Option<UnixStream>,
     pub config: config::Manager,
+    pub keybindings: Arc<Mutex<KeybindingOverrides>>,
+    /// Actions registered by the binary embedding the daemon, which
+    /// keybindings can run.
+    pub custom_actions: Arc<CustomActions>,
     pub term_db: Arc<termini::TermInfo>,
     pub daily_messenger: Arc<show_motd::DailyMessenger>,
     pub needs_initial_motd_dump: bool,
     pub custom_cmd: bool,
+    /// The session's audit recording, if session_audit is configured.
+    pub recorder: Option<Arc<Mutex<audit::Recorder>>>,
+    /// The session's asciicast recording, which `shpool record` can
+    /// start and stop at any time.
+    pub cast: Arc<Mutex<Option<cast::Recorder>>>,
+    /// Whether the session is locked, shared between the reader thread
+    /// and the threads serving the attached client.
+    pub lock: Arc<lock::State>,
+    /// Who besides the daemon's user may use the session, checked
+    /// against the attached client as it types.
+    pub acl: Arc<Mutex<acl::Acl>>,
 
     /// The join handle for the always-on background reader thread.
     /// Only wrapped in an option so we can spawn the thread after
@@ -113,19 +283,61 @@ pub struct SessionInner {
     pub reader_join_h: Option<thread::JoinHandle<anyhow::Result<()>>>,
 }
 
+/// The client that bidi_stream attaches to the session.
+pub struct AttachingClient {
+    pub conn_id: usize,
+    /// Checked against the session's ACL as the client types.
+    pub uid: u32,
+    pub tty_size: tty::Size,
+    pub dumb_term: bool,
+    pub replay: protocol::Replay,
+    pub send_timings: bool,
+    pub prompt_hints: bool,
+    /// Typed into the shell once the client is attached.
+    pub on_attach_input: Option<Vec<u8>>,
+}
+
 /// A notification that a new client has connected, sent to the
 /// reader thread.
 pub struct ClientConnection {
-    /// All output data should be written to this sink rather than
-    /// directly to the unix stream. The mutex makes sure that we don't
-    /// accidentally interleave with heartbeat frames.
-    sink: Arc<Mutex<io::BufWriter<UnixStream>>>,
+    /// All output data should be pushed onto this queue rather than
+    /// written directly to the unix stream. The attach loop on the
+    /// other end makes sure that we don't accidentally interleave with
+    /// heartbeat frames.
+    output: Arc<OutputQueue>,
     /// The size of the client tty.
     size: tty::Size,
     /// The raw unix socket stream. The reader should never write
     /// to this directly, just use it for control operations like
     /// shutdown.
     stream: UnixStream,
+    /// The client terminal can't handle escape sequences (TERM=dumb),
+    /// so we should not send any of our own, like the session restore
+    /// buffer.
+    dumb_term: bool,
+    /// How much output the client asked to have replayed.
+    replay: protocol::Replay,
+    /// The client asked for how long the replay took.
+    send_timings: bool,
+    /// The client asked to hear when the shell is at its prompt.
+    prompt_hints: bool,
+}
+
+impl ClientConnection {
+    /// Send output of our own making to the client. The writer breaks it
+    /// up into chunks so that we don't make the client allocate too much.
+    fn write_data(&self, buf: &[u8]) {
+        if !self.output.push_data(buf) {
+            warn!("client gone, dropping {} bytes of data", buf.len());
+        }
+    }
+}
+
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        // lets the attach loop finish up
+        self.output.close();
+    }
 }
 
 #[derive(Debug)]
@@ -139,6 +351,21 @@ pub enum ClientConnectionStatus {
     /// An instruction to detach had no effect, since there was already
     /// no client attached.
     DetachNone,
+    /// We reset the terminal state.
+    Reset,
+    /// We cleared the scrollback.
+    Cleared,
+    /// We redrew the client's screen.
+    Redrawn,
+    /// Whether the client is in copy mode after a copy mode message.
+    CopyMode(bool),
+    /// The contents of the output spool, if there is one.
+    Output(Option<Vec<u8>>),
+    /// Whether the client is looking at the lock screen after a lock
+    /// screen or unlock message.
+    Locked(bool),
+    /// Whether the status line is up after a toggle.
+    StatusLine(bool),
 }
 
 struct ResizeCmd {
@@ -150,6 +377,59 @@ struct ResizeCmd {
     when: time::Instant,
 }
 
+/// Oversize the pty, returning the command to put it back to the right
+/// size after a short delay. The jiggle gets full screen programs to
+/// redraw.
+fn jiggle_size(pty: &dyn pty::Pty, pending: Option<&ResizeCmd>) -> anyhow::Result<ResizeCmd> {
+    let size = match pending {
+        Some(cmd) => cmd.size.clone(),
+        None => pty.size()?,
+    };
+    pty.set_size(&tty::Size {
+        rows: size.rows + 1,
+        cols: size.cols + 1,
+        xpixel: size.xpixel,
+        ypixel: size.ypixel,
+    })?;
+    Ok(ResizeCmd { size, when: time::Instant::now().add(REATTACH_RESIZE_DELAY) })
+}
+
+/// Whether a newly attached client should get a status line, which
+/// unless it has been toggled for the session is up to the config.
+fn wants_status_line(
+    config: &config::Manager,
+    toggled: Option<bool>,
+    conn: &ClientConnection,
+) -> bool {
+    !conn.dumb_term
+        && toggled.unwrap_or_else(|| {
+            config.get().status_line.as_ref().and_then(|s| s.enabled).unwrap_or(false)
+        })
+}
+
+/// The size the pty should be for a client with a terminal of the given
+/// size, leaving room for the status line if it is up.
+fn pty_size(size: &tty::Size, status_line: &Option<StatusLine>) -> tty::Size {
+    match status_line {
+        Some(_) => status_line::shell_size(size),
+        None => size.clone(),
+    }
+}
+
+/// Whether the shell is sitting at its prompt with input echoing, so
+/// that a client could do its own line editing.
+fn at_prompt(pty: &dyn pty::Pty) -> bool {
+    pty.at_prompt().unwrap_or(false) && !pty.reading_password().unwrap_or(true)
+}
+
+/// The lock screen, starting from a clean slate so that whatever modes
+/// the session left the terminal in don't garble it.
+fn lock_screen(name: &str, message: Option<&str>, dumb_term: bool) -> Vec<u8> {
+    let mut screen = if dumb_term { vec![] } else { SOFT_RESET.to_vec() };
+    screen.extend(lock::screen(name, message, dumb_term));
+    screen
+}
+
 fn log_if_error<T, E>(ctx: &str, res: Result<T, E>) -> Result<T, E>
 where
     E: std::fmt::Debug,
@@ -170,758 +450,1771 @@ pub enum ClientConnectionMsg {
     /// Disconnect the client, but stay around and be ready for
     /// reconnects.
     Disconnect,
+    /// Put the terminal state back to defaults, both in the output
+    /// spool and in the attached client's terminal.
+    Reset,
+    /// Throw away the scrollback and screen kept in the output spool,
+    /// along with the raw output, and clear the attached client's
+    /// terminal.
+    ClearScrollback,
+    /// Show or hide the status line on the attached client.
+    ToggleStatusLine,
+    /// Draw the attached client's screen from scratch, for after it
+    /// has been suspended and something else had the terminal.
+    Redraw,
+    /// Put the attached client into copy mode.
+    CopyMode,
+    /// Input from a client that is in copy mode.
+    CopyModeInput(Vec<u8>),
+    /// Show the lock screen in place of the session's output, along
+    /// with the given message, if any. The session must already be
+    /// marked as locked.
+    LockScreen(Option<String>),
+    /// Take down the lock screen and redraw the session. The session
+    /// must already be marked as unlocked.
+    Unlock,
+    /// Dump the contents of the output spool, either as is or as
+    /// plain text.
+    SaveOutput { strip_ansi: bool },
+    /// Dump what is on the screen right now, or the tail of the
+    /// raw output.
+    Capture(protocol::CaptureMode),
+    /// Pass the given control message on to the client, if there is
+    /// one, then hang up on it. This is how a client finds out why the
+    /// daemon is going away, or which job to switch to.
+    Hangup(protocol::StreamControl),
 }
 
 pub struct ReaderArgs {
     pub conn_id: usize,
     pub tty_size: tty::Size,
     pub scrollback_lines: usize,
+    /// Where scrollback past the in-memory budget goes, if the spool
+    /// has one.
+    pub spill: Option<spill::Spill>,
     pub session_restore_mode: config::SessionRestoreMode,
     pub client_connection: crossbeam_channel::Receiver<ClientConnectionMsg>,
     pub client_connection_ack: crossbeam_channel::Sender<ClientConnectionStatus>,
     pub tty_size_change: crossbeam_channel::Receiver<tty::Size>,
     pub tty_size_change_ack: crossbeam_channel::Sender<()>,
+    pub hooks: Arc<dyn hooks::Hooks + Send + Sync>,
+    pub alerts: Arc<Mutex<PendingAlerts>>,
+    pub activity: Arc<Mutex<activity::Monitor>>,
+    /// Where copy mode puts the text it copies.
+    pub paste_buffers: Arc<Mutex<PasteBuffers>>,
 }
 
-impl SessionInner {
-    /// Spawn the reader thread which continually reads from the pty
-    /// and sends data both to the output spool and to the client,
-    /// if one is attached.
-    #[instrument(skip_all, fields(s = self.name))]
-    pub fn spawn_reader(
-        &self,
-        args: ReaderArgs,
-    ) -> anyhow::Result<thread::JoinHandle<anyhow::Result<()>>> {
-        use nix::poll;
-
-        let term_db = Arc::clone(&self.term_db);
-        let mut prompt_sentinel_scanner = prompt::SentinelScanner::new(consts::PROMPT_SENTINEL);
-
-        // We only scan for the prompt sentinel if the user has not set up a
-        // custom command.
-        let mut has_seen_prompt_sentinel = self.custom_cmd;
-
-        let daily_messenger = Arc::clone(&self.daily_messenger);
-        let mut needs_initial_motd_dump = self.needs_initial_motd_dump;
+/// What came of handling a message to the reader thread.
+enum Handled {
+    Continue,
+    /// A new client got attached, so the session has to get restored
+    /// on it.
+    Reattach,
+    /// The shell exited, so the reader thread is done.
+    Exit,
+}
 
-        let mut pty_master = self.pty_master.is_parent()?;
-        let watchable_master = pty_master;
-        let name = self.name.clone();
-        let mut closure = move || {
-            let _s = span!(Level::INFO, "reader", s = name, cid = args.conn_id).entered();
+/// The state of a session's reader thread, which continually reads
+/// from the pty and sends the output to the spool, the recordings and
+/// the attached client, if there is one.
+struct Reader {
+    name: String,
+    config: config::Manager,
+    args: ReaderArgs,
+    pty: Arc<dyn pty::Pty + Send + Sync>,
+    pty_master: pty::Master,
+    term_db: Arc<termini::TermInfo>,
+    daily_messenger: Arc<show_motd::DailyMessenger>,
+    needs_initial_motd_dump: bool,
+    recorder: Option<Arc<Mutex<audit::Recorder>>>,
+    cast: Arc<Mutex<Option<cast::Recorder>>>,
+    lock: Arc<lock::State>,
+
+    /// The last connection message, which holds the attached client
+    /// if there is one.
+    client_conn: ClientConnectionMsg,
+    output_spool: Option<shpool_vt100::Parser>,
+    spill: Option<spill::Spill>,
+    spool_lines: usize,
+    /// The raw output, kept separately from the spool since it is
+    /// around even in the simple restore mode.
+    raw_tail: VecDeque<u8>,
+    read_buf: Vec<u8>,
+    resize_cmd: Option<ResizeCmd>,
+
+    prompt_sentinel_scanner: prompt::SentinelScanner,
+    has_seen_prompt_sentinel: bool,
+
+    // Progress reports and notifications emitted while no client
+    // is attached, to be replayed on reattach.
+    osc_scanner: osc::Scanner,
+    osc_events: Vec<osc::Event>,
+    pending_osc: osc::Pending,
+    osc_filter: osc::Filter,
+    filter_scratch: Vec<u8>,
+    utf8_joiner: utf8::Joiner,
+    utf8_scratch: Vec<u8>,
+    // One for what gets kept in the spool and audit recordings, and one
+    // for the filtered output that asciicast recordings get.
+    redactor: redact::Redactor,
+    redact_scratch: Vec<u8>,
+    cast_redactor: redact::Redactor,
+    cast_redact_scratch: Vec<u8>,
+
+    /// The status line on the attached client's terminal, if it is up.
+    status_line: Option<StatusLine>,
+    /// Whether the status line has been toggled on or off for the
+    /// session.
+    status_toggled: Option<bool>,
+    status_scratch: Vec<u8>,
+    status_refresh: time::Instant,
+    attaches: u64,
+    /// The last prompt hint sent to the attached client, if any.
+    prompt_hint: Option<Option<u16>>,
+    /// Set while the attached client is looking at copy mode rather
+    /// than the live session.
+    copy_mode: Option<copy_mode::CopyMode>,
+}
 
-            let mut output_spool =
-                if matches!(args.session_restore_mode, config::SessionRestoreMode::Simple) {
-                    None
-                } else {
-                    Some(shpool_vt100::Parser::new(
-                        args.tty_size.rows,
-                        VTERM_WIDTH,
-                        args.scrollback_lines,
-                    ))
-                };
-            let mut buf: Vec<u8> = vec![0; consts::BUF_SIZE];
-            let mut poll_fds = [poll::PollFd::new(
-                watchable_master.borrow_fd().ok_or(anyhow!("no master fd"))?,
-                poll::PollFlags::POLLIN,
-            )];
-
-            // block until we get the first connection attached so that we don't drop
-            // the initial prompt on the floor
-            info!("waiting for initial client connection");
-            let mut client_conn: ClientConnectionMsg =
-                args.client_connection.recv().context("waiting for initial client connection")?;
-            args.client_connection_ack
-                .send(ClientConnectionStatus::New)
-                .context("sending initial client connection ack")?;
-            info!("got initial client connection");
-
-            let mut resize_cmd = if let ClientConnectionMsg::New(conn) = &client_conn {
-                Some(ResizeCmd { size: conn.size.clone(), when: time::Instant::now() })
-            } else {
+impl Reader {
+    fn new(inner: &SessionInner, mut args: ReaderArgs) -> Self {
+        let spill = args.spill.take();
+        let spool_lines = spill.as_ref().map(|s| s.spool_lines()).unwrap_or(args.scrollback_lines);
+        let output_spool =
+            if matches!(args.session_restore_mode, config::SessionRestoreMode::Simple) {
                 None
+            } else {
+                Some(shpool_vt100::Parser::new(args.tty_size.rows, VTERM_WIDTH, spool_lines))
             };
+        Reader {
+            name: inner.name.clone(),
+            config: inner.config.clone(),
+            pty: Arc::clone(&inner.pty),
+            pty_master: inner.pty.master(),
+            term_db: Arc::clone(&inner.term_db),
+            daily_messenger: Arc::clone(&inner.daily_messenger),
+            needs_initial_motd_dump: inner.needs_initial_motd_dump,
+            recorder: inner.recorder.clone(),
+            cast: Arc::clone(&inner.cast),
+            lock: Arc::clone(&inner.lock),
+            client_conn: ClientConnectionMsg::Disconnect,
+            output_spool,
+            spill,
+            spool_lines,
+            raw_tail: VecDeque::new(),
+            read_buf: vec![0; consts::BUF_SIZE],
+            resize_cmd: None,
+            prompt_sentinel_scanner: prompt::SentinelScanner::new(consts::PROMPT_SENTINEL),
+            // We only scan for the prompt sentinel if the user has not set
+            // up a custom command.
+            has_seen_prompt_sentinel: inner.custom_cmd,
+            osc_scanner: osc::Scanner::new(),
+            osc_events: vec![],
+            pending_osc: osc::Pending::default(),
+            osc_filter: osc::Filter::new(),
+            filter_scratch: vec![],
+            utf8_joiner: utf8::Joiner::new(),
+            utf8_scratch: vec![],
+            redactor: redact::Redactor::new(),
+            redact_scratch: vec![],
+            cast_redactor: redact::Redactor::new(),
+            cast_redact_scratch: vec![],
+            status_line: None,
+            status_toggled: None,
+            status_scratch: vec![],
+            status_refresh: time::Instant::now(),
+            attaches: 0,
+            prompt_hint: None,
+            copy_mode: None,
+            args,
+        }
+    }
 
-            loop {
-                let mut do_reattach = false;
-                crossbeam_channel::select! {
-                    recv(args.client_connection) -> new_connection => {
-                        match new_connection {
-                            Ok(ClientConnectionMsg::New(conn)) => {
-                                info!("got new connection (rows={}, cols={})", conn.size.rows, conn.size.cols);
-                                do_reattach = true;
-                                let ack = if let ClientConnectionMsg::New(old_conn) = client_conn {
-                                    old_conn.stream.shutdown(net::Shutdown::Both)?;
-                                    ClientConnectionStatus::Replaced
-                                } else {
-                                    ClientConnectionStatus::New
-                                };
-                                // Resize the pty to be bigger than it needs to be,
-                                // we do this immediately so that the extra size
-                                // can "bake" for a little bit, which emacs seems
-                                // to require in order to pick up the jiggle.
-                                let oversize = tty::Size {
-                                    rows: conn.size.rows + 1,
-                                    cols: conn.size.cols + 1,
-                                    xpixel: conn.size.xpixel,
-                                    ypixel: conn.size.ypixel,
-                                };
-                                oversize.set_fd(pty_master.raw_fd().ok_or(anyhow!("no master fd"))?)?;
-
-                                // Always instantly resize the spool, since we don't
-                                // need to inject a delay into that.
-                                if let Some(s) = output_spool.as_mut() {
-                                    s.screen_mut().set_size(conn.size.rows, u16::MAX);
-                                }
-                                resize_cmd = Some(ResizeCmd {
-                                    size: conn.size.clone(),
-                                    when: time::Instant::now().add(REATTACH_RESIZE_DELAY),
-                                });
-                                client_conn = ClientConnectionMsg::New(conn);
-
-                                args.client_connection_ack.send(ack)
-                                    .context("sending client connection ack")?;
-                            }
-                            Ok(ClientConnectionMsg::Disconnect) => {
-                                let ack = if let ClientConnectionMsg::New(old_conn) = client_conn {
-                                    info!("disconnect, shutting down client stream");
-                                    old_conn.stream.shutdown(net::Shutdown::Both)?;
-                                    ClientConnectionStatus::Detached
-                                } else {
-                                    info!("disconnect, no client stream to shut down");
-                                    ClientConnectionStatus::DetachNone
-                                };
-                                client_conn = ClientConnectionMsg::Disconnect;
-
-                                args.client_connection_ack.send(ack)
-                                    .context("sending client connection ack")?;
-                            }
-                            Ok(ClientConnectionMsg::DisconnectExit(exit_status)) => {
-                                let ack = if let ClientConnectionMsg::New(mut old_conn) = client_conn {
-                                    info!("disconnectexit({}), shutting down client stream",
-                                           exit_status);
-
-                                    // write an exit status frame so the attach process
-                                    // can exit with the same exit code as the child shell
-                                    let status_buf: [u8; 4] = exit_status.to_le_bytes();
-                                    let chunk = protocol::Chunk {
-                                        kind: protocol::ChunkKind::ExitStatus,
-                                        buf: status_buf.as_slice(),
-                                    };
-                                    match chunk.write_to(&mut old_conn.stream).and_then(|_| old_conn.stream.flush()) {
-                                        Ok(_) => {
-                                            trace!("wrote exit status chunk");
-                                        }
-                                        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
-                                            trace!("client hangup: {:?}", e);
-                                        }
-                                        Err(e) => {
-                                            error!("writing exit status chunk: {:?}", e);
-                                        }
-                                    };
-
-                                    old_conn.stream.shutdown(net::Shutdown::Both)?;
-
-                                    ClientConnectionStatus::Detached
-                                } else {
-                                    info!(
-                                        "disconnectexit({}), no client stream to shut down",
-                                          exit_status);
-                                    ClientConnectionStatus::DetachNone
-                                };
-                                args.client_connection_ack.send(ack)
-                                    .context("sending client connection ack")?;
-
-                                return Ok(());
-                            }
-
-                            // SessionInner getting dropped, so this thread should go away.
-                            Err(crossbeam_channel::RecvError) => {
-                                info!("client conn: bailing due to RecvError");
-                                return Ok(())
-                            },
-                        }
-                    }
-                    recv(args.tty_size_change) -> new_size => {
-                        match new_size {
-                            Ok(size) => {
-                                info!("resize size={:?}", size);
-                                if let Some(s) = output_spool.as_mut() {
-                                    s.screen_mut().set_size(size.rows, u16::MAX);
-                                }
-                                resize_cmd = Some(ResizeCmd {
-                                    size,
-                                    // No delay needed for ordinary resizes, just
-                                    // for reconnects.
-                                    when: time::Instant::now(),
-                                });
-                                args.tty_size_change_ack.send(())
-                                    .context("sending size change ack")?;
-                            }
-                            Err(err) => {
-                                warn!("size change: bailing due to: {:?}", err);
-                                return Ok(());
-                            }
-                        }
-                    }
-
-                    // make this select non-blocking so we spend most of our time parked
-                    // in poll
-                    default => {}
-                }
+    fn run(mut self) -> anyhow::Result<()> {
+        let _s = span!(Level::INFO, "reader", s = self.name, cid = self.args.conn_id).entered();
+
+        // block until we get the first connection attached so that we don't drop
+        // the initial prompt on the floor
+        info!("waiting for initial client connection");
+        self.client_conn =
+            self.args.client_connection.recv().context("waiting for initial client connection")?;
+        self.args
+            .client_connection_ack
+            .send(ClientConnectionStatus::New)
+            .context("sending initial client connection ack")?;
+        info!("got initial client connection");
+
+        if let ClientConnectionMsg::New(conn) = &self.client_conn {
+            self.attaches += 1;
+            if wants_status_line(&self.config, self.status_toggled, conn) {
+                // the status line has to know where the cursor is
+                let mut sl = StatusLine::new(&conn.size);
+                conn.write_data(CLEAR_SCREEN);
+                sl.reset(CLEAR_SCREEN);
+                self.status_line = Some(sl);
+            }
+            let size = pty_size(&conn.size, &self.status_line);
+            if let Some(s) = self.output_spool.as_mut() {
+                s.screen_mut().set_size(size.rows, VTERM_WIDTH);
+            }
+            self.resize_cmd = Some(ResizeCmd { size, when: time::Instant::now() });
+        }
 
-                let mut executed_resize = false;
-                if let Some(resize_cmd) = resize_cmd.as_ref() {
-                    if resize_cmd.when.saturating_duration_since(time::Instant::now())
-                        == time::Duration::ZERO
-                    {
-                        resize_cmd
-                            .size
-                            .set_fd(pty_master.raw_fd().ok_or(anyhow!("no master fd"))?)?;
-                        executed_resize = true;
-                        info!(
-                            "resized fd (rows={}, cols={})",
-                            resize_cmd.size.rows, resize_cmd.size.cols
-                        );
+        let client_connection = self.args.client_connection.clone();
+        let tty_size_change = self.args.tty_size_change.clone();
+        loop {
+            let mut do_reattach = false;
+            crossbeam_channel::select! {
+                recv(client_connection) -> msg => {
+                    match msg {
+                        Ok(msg) => match self.handle_client_msg(msg)? {
+                            Handled::Continue => {}
+                            Handled::Reattach => do_reattach = true,
+                            Handled::Exit => return Ok(()),
+                        },
+                        // SessionInner getting dropped, so this thread should go away.
+                        Err(crossbeam_channel::RecvError) => {
+                            info!("client conn: bailing due to RecvError");
+                            return Ok(())
+                        },
                     }
                 }
-                if executed_resize {
-                    resize_cmd = None;
-                }
-
-                if do_reattach {
-                    use config::SessionRestoreMode::*;
-
-                    info!("executing reattach protocol (mode={:?})", args.session_restore_mode);
-                    let restore_buf = match (output_spool.as_mut(), &args.session_restore_mode) {
-                        (Some(spool), Screen) => {
-                            let (rows, cols) = spool.screen().size();
-                            info!(
-                                "computing screen restore buf with (rows={}, cols={})",
-                                rows, cols
-                            );
-                            spool.screen().contents_formatted()
-                        }
-                        (Some(spool), Lines(nlines)) => {
-                            let (rows, cols) = spool.screen().size();
-                            info!(
-                                "computing lines({}) restore buf with (rows={}, cols={})",
-                                nlines, rows, cols
-                            );
-                            spool.screen().last_n_rows_contents_formatted(*nlines)
-                        }
-                        (_, _) => vec![],
-                    };
-                    if let (true, ClientConnectionMsg::New(conn)) =
-                        (!restore_buf.is_empty(), &client_conn)
-                    {
-                        trace!("restore chunk='{}'", String::from_utf8_lossy(&restore_buf[..]));
-                        // send the restore buffer, broken up into chunks so that we don't make
-                        // the client allocate too much
-                        let mut s = conn.sink.lock().unwrap();
-                        for block in restore_buf.as_slice().chunks(consts::BUF_SIZE) {
-                            let chunk =
-                                protocol::Chunk { kind: protocol::ChunkKind::Data, buf: block };
-
-                            if let Err(err) = chunk.write_to(&mut *s) {
-                                warn!("err writing session-restore buf: {:?}", err);
-                            }
-                        }
-                        if let Err(err) = s.flush() {
-                            warn!("err flushing session-restore: {:?}", err);
+                recv(tty_size_change) -> new_size => {
+                    match new_size {
+                        Ok(size) => self.resize(size)?,
+                        Err(err) => {
+                            warn!("size change: bailing due to: {:?}", err);
+                            return Ok(());
                         }
                     }
                 }
 
-                // Block until the shell has some data for us so we can be sure our reads
-                // always succeed. We don't want to end up blocked forever on a read while
-                // a client is trying to attach.
-                let nready = match poll::poll(&mut poll_fds, READER_POLL_MS) {
-                    Ok(n) => n,
-                    Err(e) => {
-                        error!("polling pty master: {:?}", e);
-                        return Err(e)?;
-                    }
-                };
-                if nready == 0 {
-                    // if timeout
-                    continue;
-                }
-                if nready != 1 {
-                    return Err(anyhow!("reader thread: expected exactly 1 ready fd"));
-                }
-                let len = match pty_master.read(&mut buf) {
-                    Ok(l) => l,
-                    Err(e) => {
-                        test_hooks::emit("daemon-reader-read-error");
-                        error!("reading chunk from pty master: {:?}", e);
-                        return Err(e).context("reading pty master chunk")?;
-                    }
-                };
-                if len == 0 {
-                    continue;
-                }
-                let mut buf = &buf[..len];
-                trace!("read pty master len={} '{}'", len, String::from_utf8_lossy(buf));
+                // make this select non-blocking so we spend most of our time parked
+                // in poll
+                default => {}
+            }
 
-                if !matches!(args.session_restore_mode, config::SessionRestoreMode::Simple) {
-                    if let Some(s) = output_spool.as_mut() {
-                        s.process(buf);
-                    }
-                }
+            self.run_pending_resize()?;
+            if do_reattach {
+                self.reattach();
+            }
+            self.redraw_after_lost_output()?;
+            self.refresh_status_line();
+            self.send_prompt_hint();
 
-                // scan for control codes we need to handle
-                let mut reset_client_conn = false;
-                if !has_seen_prompt_sentinel {
-                    for (i, byte) in buf.iter().enumerate() {
-                        if prompt_sentinel_scanner.transition(*byte) {
-                            info!("saw prompt sentinel");
-                            // This will cause us to start actually sending data frames back to
-                            // the client.
-                            has_seen_prompt_sentinel = true;
-
-                            // drop everything up to and including the sentinel
-                            buf = &buf[i + 1..];
-                        }
-                    }
+            let silence_threshold = activity::silence_threshold(&self.config.get());
+            {
+                let mut monitor = self.args.activity.lock().unwrap();
+                monitor.set_attached(matches!(self.client_conn, ClientConnectionMsg::New(_)));
+                if let Some(change) = monitor.tick(time::Instant::now(), silence_threshold) {
+                    activity::fire_hook(&self.config, change, &self.name);
                 }
+            }
 
-                if let (ClientConnectionMsg::New(conn), true) =
-                    (&client_conn, has_seen_prompt_sentinel)
-                {
-                    let chunk = protocol::Chunk { kind: protocol::ChunkKind::Data, buf };
-
-                    let mut s = conn.sink.lock().unwrap();
+            self.read_output(silence_threshold)?;
+        }
+    }
 
-                    // If we still need to do an initial motd dump, it means we have just finished
-                    // dropping all the prompt setup stuff, we should dump the motd now before we
-                    // write the first chunk.
-                    if needs_initial_motd_dump {
-                        needs_initial_motd_dump = false;
-                        if let Err(e) = daily_messenger.dump(&mut *s, &term_db) {
-                            warn!("Error handling clear: {:?}", e);
-                        }
-                    }
+    /// Act on a message from the threads serving the session, and ack
+    /// it once it has been dealt with.
+    fn handle_client_msg(&mut self, msg: ClientConnectionMsg) -> anyhow::Result<Handled> {
+        let handled = match &msg {
+            ClientConnectionMsg::New(_) => Handled::Reattach,
+            ClientConnectionMsg::DisconnectExit(_) => Handled::Exit,
+            _ => Handled::Continue,
+        };
+        let ack = match msg {
+            ClientConnectionMsg::New(conn) => self.attach(conn)?,
+            ClientConnectionMsg::Disconnect => self.detach()?,
+            ClientConnectionMsg::DisconnectExit(exit_status) => self.detach_exited(exit_status)?,
+            ClientConnectionMsg::Hangup(notice) => self.hang_up(notice)?,
+            ClientConnectionMsg::Reset => self.reset()?,
+            ClientConnectionMsg::ClearScrollback => self.clear_scrollback()?,
+            ClientConnectionMsg::ToggleStatusLine => self.toggle_status_line(),
+            ClientConnectionMsg::Redraw => self.redraw()?,
+            ClientConnectionMsg::CopyMode => self.enter_copy_mode(),
+            ClientConnectionMsg::CopyModeInput(input) => self.copy_mode_input(&input),
+            ClientConnectionMsg::LockScreen(message) => self.show_lock_screen(message.as_deref()),
+            ClientConnectionMsg::Unlock => self.unlock()?,
+            ClientConnectionMsg::SaveOutput { strip_ansi } => self.save_output(strip_ansi),
+            ClientConnectionMsg::Capture(mode) => self.capture(mode),
+        };
+        self.args.client_connection_ack.send(ack).context("sending client connection ack")?;
+        Ok(handled)
+    }
 
-                    let write_result = chunk.write_to(&mut *s).and_then(|_| s.flush());
-                    if let Err(err) = write_result {
-                        info!("client_stream write err, assuming hangup: {:?}", err);
-                        reset_client_conn = true;
-                    } else {
-                        test_hooks::emit("daemon-wrote-s2c-chunk");
-                    }
-                }
-                if reset_client_conn {
-                    client_conn = ClientConnectionMsg::Disconnect;
-                }
-            }
+    /// Take on a newly attached client, hanging up on the old one if
+    /// there is one.
+    fn attach(&mut self, conn: ClientConnection) -> anyhow::Result<ClientConnectionStatus> {
+        info!("got new connection (rows={}, cols={})", conn.size.rows, conn.size.cols);
+        self.copy_mode = None;
+        self.attaches += 1;
+        self.prompt_hint = None;
+        self.status_line = wants_status_line(&self.config, self.status_toggled, &conn)
+            .then(|| StatusLine::new(&conn.size));
+        let size = pty_size(&conn.size, &self.status_line);
+        let ack = if let ClientConnectionMsg::New(old_conn) =
+            mem::replace(&mut self.client_conn, ClientConnectionMsg::Disconnect)
+        {
+            old_conn.stream.shutdown(net::Shutdown::Both)?;
+            ClientConnectionStatus::Replaced
+        } else {
+            ClientConnectionStatus::New
+        };
+        // Resize the pty to be bigger than it needs to be,
+        // we do this immediately so that the extra size
+        // can "bake" for a little bit, which emacs seems
+        // to require in order to pick up the jiggle.
+        let oversize = tty::Size {
+            rows: size.rows + 1,
+            cols: size.cols + 1,
+            xpixel: size.xpixel,
+            ypixel: size.ypixel,
         };
+        self.pty.set_size(&oversize)?;
 
-        Ok(thread::Builder::new()
-            .name(format!("reader({})", self.name))
-            .spawn(move || log_if_error("error in reader", closure()))?)
+        // Always instantly resize the spool, since we don't
+        // need to inject a delay into that.
+        if let Some(s) = self.output_spool.as_mut() {
+            s.screen_mut().set_size(size.rows, u16::MAX);
+        }
+        self.resize_cmd =
+            Some(ResizeCmd { size, when: time::Instant::now().add(REATTACH_RESIZE_DELAY) });
+        self.client_conn = ClientConnectionMsg::New(conn);
+        Ok(ack)
     }
 
-    /// bidi_stream shuffles bytes between the subprocess and
-    /// the client connection. It returns true if the subprocess
-    /// has exited, and false if it is still running.
-    #[instrument(skip_all, fields(s = self.name))]
-    pub fn bidi_stream(
-        &mut self,
-        conn_id: usize,
-        init_tty_size: tty::Size,
-        child_exit_notifier: Arc<ExitNotifier>,
-    ) -> anyhow::Result<bool> {
-        test_hooks::emit("daemon-bidi-stream-enter");
-        #[allow(clippy::let_unit_value)]
-        let _bidi_stream_test_guard = test_hooks::scoped("daemon-bidi-stream-done");
-
-        // we take the client stream so that it gets closed when this routine
-        // returns
-        let client_stream = match self.client_stream.take() {
-            Some(s) => s,
-            None => return Err(anyhow!("no client stream to take for bidi streaming")),
+    /// Hang up on the attached client, if there is one, and stay around
+    /// for reconnects.
+    fn detach(&mut self) -> anyhow::Result<ClientConnectionStatus> {
+        let ack = if let ClientConnectionMsg::New(old_conn) =
+            mem::replace(&mut self.client_conn, ClientConnectionMsg::Disconnect)
+        {
+            info!("disconnect, shutting down client stream");
+            old_conn.stream.shutdown(net::Shutdown::Both)?;
+            ClientConnectionStatus::Detached
+        } else {
+            info!("disconnect, no client stream to shut down");
+            ClientConnectionStatus::DetachNone
         };
+        self.copy_mode = None;
+        self.status_line = None;
+        Ok(ack)
+    }
 
-        let mut client_to_shell_client_stream =
-            client_stream.try_clone().context("creating reader client stream")?;
-        let reader_client_stream =
-            client_stream.try_clone().context("creating reader client stream handle")?;
-        let client_stream_m = Arc::new(Mutex::new(io::BufWriter::new(
-            client_stream.try_clone().context("wrapping stream in bufwriter")?,
-        )));
-
+    /// Hang up on the attached client, if there is one, once it has the
+    /// shell's exit status.
+    fn detach_exited(&mut self, exit_status: i32) -> anyhow::Result<ClientConnectionStatus> {
+        if let ClientConnectionMsg::New(old_conn) =
+            mem::replace(&mut self.client_conn, ClientConnectionMsg::Disconnect)
         {
-            let reader_ctl = self.reader_ctl.lock().unwrap();
-            reader_ctl
-                .client_connection
-                .send(ClientConnectionMsg::New(ClientConnection {
-                    sink: Arc::clone(&client_stream_m),
-                    size: init_tty_size,
-                    stream: reader_client_stream,
-                }))
-                .context("attaching new client stream to reader thread")?;
-            let status = reader_ctl
-                .client_connection_ack
-                .recv()
-                .context("waiting for client connection ack")?;
-            info!("client connection status={:?}", status);
-        }
-
-        let pty_master =
-            self.pty_master.is_parent().context("internal error: executing in child fork")?;
-
-        // A flag to indicate that outstanding threads should stop
-        let stop = AtomicBool::new(false);
-        // A flag to indicate if the child shell has exited
-        let child_done = AtomicBool::new(false);
-
-        thread::scope(|s| -> anyhow::Result<()> {
-            // Spawn the main data transport threads
-            let client_to_shell_h = self.spawn_client_to_shell(
-                s, conn_id, &stop, &pty_master, &mut client_to_shell_client_stream)?;
-
-            // Send a steady stream of heartbeats to the client
-            // so that if the connection unexpectedly goes
-            // down, we detect it immediately.
-            let heartbeat_h = self.spawn_heartbeat(
-                s, conn_id, &stop, &client_stream_m)?;
-
-            // poll the pty master fd to see if the child
-            // shell has exited.
-            let supervisor_h = self.spawn_supervisor(
-                s, conn_id, &stop, &child_done, &pty_master,
-                Arc::clone(&child_exit_notifier))?;
-
-            loop {
-                let c_done = child_done.load(Ordering::Acquire);
-                if client_to_shell_h.is_finished()
-                    || heartbeat_h.is_finished() || supervisor_h.is_finished() || c_done {
-                    debug!("signaling for threads to stop: client_to_shell_finished={} heartbeat_finished={} supervisor_finished={} child_done={}",
-                        client_to_shell_h.is_finished(),
-                        heartbeat_h.is_finished(),
-                        supervisor_h.is_finished(),
-                        c_done,
-                    );
-                    stop.store(true, Ordering::Relaxed);
-                    break;
-                }
-                thread::sleep(consts::JOIN_POLL_DURATION);
+            info!("disconnectexit({}), shutting down client stream", exit_status);
+
+            // write an exit status frame so the attach process
+            // can exit with the same exit code as the child shell,
+            // after whatever output is still queued up
+            old_conn.output.push_exit_status(exit_status);
+            old_conn.output.close();
+            if !old_conn.output.wait_drained(EXIT_STATUS_DRAIN_TIMEOUT) {
+                warn!("timed out waiting to write exit status chunk");
             }
 
-            // disconnect the reader thread first since that will shutdown the
-            // client stream, and the client->shell thread hangs out blocked on
-            // that stream, so we need to close it in order to get all our
-            // cows to come home.
-            let c_done = child_done.load(Ordering::Acquire);
-            {
-                let reader_ctl = self.reader_ctl.lock().unwrap();
-                let send_res = reader_ctl.client_connection.send_timeout(if c_done {
-                    let exit_status = child_exit_notifier
-                        .wait(Some(Duration::from_secs(0)))
-                        .unwrap_or(1);
-                    info!("telling reader to disconnect with exit status {}", exit_status);
-                    ClientConnectionMsg::DisconnectExit(exit_status)
-                } else {
-                    info!("telling reader to disconnect without reaping");
-                    ClientConnectionMsg::Disconnect
-                }, Duration::from_millis((READER_POLL_MS + (READER_POLL_MS >> 1)) as u64));
-
-                if let Err(send_timeout_err) = send_res {
-                    info!("failed to tell reader to disconnect: {:?}", send_timeout_err);
+            old_conn.stream.shutdown(net::Shutdown::Both)?;
 
-                    // the reader didn't close the client stream for us, so we'll need
-                    // to handle that ourselves
-                    client_stream.shutdown(net::Shutdown::Both)?;
-                } else {
-                    let status = reader_ctl.client_connection_ack.recv()
-                        .context("waiting for client connection ack")?;
-                    info!("detached from reader, status = {:?}", status);
-                }
-            }
+            Ok(ClientConnectionStatus::Detached)
+        } else {
+            info!("disconnectexit({}), no client stream to shut down", exit_status);
+            Ok(ClientConnectionStatus::DetachNone)
+        }
+    }
 
-            debug!("joining client_to_shell_h");
-            match client_to_shell_h.join() {
-                Ok(v) => v.context("joining client_to_shell_h")?,
-                Err(panic_err) => {
-                    debug!("client_to_shell panic_err = {:?}", panic_err);
-                    std::panic::resume_unwind(panic_err)
-                },
-            }
-            debug!("joining heartbeat_h");
-            match heartbeat_h.join() {
-                Ok(v) => v.context("joining heartbeat_h")?,
-                Err(panic_err) => std::panic::resume_unwind(panic_err),
+    /// Pass the control message on to the attached client, if there is
+    /// one, then hang up on it.
+    fn hang_up(
+        &mut self,
+        notice: protocol::StreamControl,
+    ) -> anyhow::Result<ClientConnectionStatus> {
+        let ack = if let ClientConnectionMsg::New(old_conn) =
+            mem::replace(&mut self.client_conn, ClientConnectionMsg::Disconnect)
+        {
+            info!("hanging up after sending {:?}", notice);
+            if let Err(e) = old_conn.output.push_control(&notice) {
+                warn!("queueing hangup notice: {:?}", e);
             }
-            debug!("joining supervisor_h");
-            match supervisor_h.join() {
-                Ok(v) => v.context("joining supervisor_h")?,
-                Err(panic_err) => std::panic::resume_unwind(panic_err),
+            old_conn.output.close();
+            if !old_conn.output.wait_drained(EXIT_STATUS_DRAIN_TIMEOUT) {
+                warn!("timed out waiting to write hangup notice");
             }
-            debug!("joined all threads");
 
+            old_conn.stream.shutdown(net::Shutdown::Both)?;
 
-            Ok(())
-        }).context("outer thread scope")?;
+            ClientConnectionStatus::Detached
+        } else {
+            ClientConnectionStatus::DetachNone
+        };
+        self.copy_mode = None;
+        self.status_line = None;
+        Ok(ack)
+    }
 
-        let c_done = child_done.load(Ordering::Acquire);
-        if c_done {
-            client_stream
-                .shutdown(std::net::Shutdown::Both)
-                .context("shutting down client stream")?;
+    /// Put the terminal state back to defaults, both in the output
+    /// spool and in the attached client's terminal.
+    fn reset(&mut self) -> anyhow::Result<ClientConnectionStatus> {
+        info!("soft resetting terminal state");
+        // the reset takes the client off the alternate
+        // screen, so copy mode is gone anyway
+        self.copy_mode = None;
+        // drop any half scanned sequences along with the
+        // garbage that left them there
+        self.osc_scanner = osc::Scanner::new();
+        self.osc_filter = osc::Filter::new();
+        if let Some(s) = self.output_spool.as_mut() {
+            s.process(SOFT_RESET);
         }
 
-        info!("bidi_stream: done child_done={}", c_done);
-        Ok(c_done)
-    }
+        if let ClientConnectionMsg::New(conn) = &self.client_conn {
+            if !conn.dumb_term {
+                let mut reset_buf = SOFT_RESET.to_vec();
+                if let Some(s) = self.output_spool.as_ref() {
+                    reset_buf.extend(s.screen().contents_formatted());
+                }
+                conn.write_data(&reset_buf);
+                if let Some(sl) = self.status_line.as_mut() {
+                    sl.reset(&reset_buf);
+                }
+            }
 
-    #[instrument(skip_all)]
-    fn spawn_client_to_shell<'scope>(
-        &'scope self,
-        scope: &'scope thread::Scope<'scope, '_>,
-        conn_id: usize,
-        stop: &'scope AtomicBool,
-        pty_master: &'scope shpool_pty::fork::Master,
-        reader_client_stream: &'scope mut UnixStream,
-    ) -> anyhow::Result<thread::ScopedJoinHandle<anyhow::Result<()>>> {
-        let empty_bindings = vec![config::Keybinding {
-            binding: String::from("Ctrl-Space Ctrl-q"),
-            action: keybindings::Action::Detach,
-        }];
-        let bindings = keybindings::Bindings::new(
-            self.config
-                .get()
-                .keybinding
-                .as_ref()
-                .unwrap_or(&empty_bindings)
-                .iter()
-                .map(|binding| (binding.binding.as_str(), binding.action)),
-        );
+            // Jiggle the pty size just like on reattach so that
+            // full screen programs redraw and set up whatever
+            // modes they need again.
+            self.resize_cmd = Some(jiggle_size(&*self.pty, self.resize_cmd.as_ref())?);
+        }
 
-        thread::Builder::new()
-            .name(format!("client->shell({})", self.name))
-            .spawn_scoped(scope, move || -> anyhow::Result<()> {
-                let _s =
-                    span!(Level::INFO, "client->shell", s = self.name, cid = conn_id).entered();
-                let mut bindings = bindings.context("compiling keybindings engine")?;
+        Ok(ClientConnectionStatus::Reset)
+    }
 
-                let mut master_writer = *pty_master;
+    /// Throw away the scrollback and the raw output, and clear the
+    /// attached client's terminal.
+    fn clear_scrollback(&mut self) -> anyhow::Result<ClientConnectionStatus> {
+        info!("clearing scrollback");
+        // copy mode would keep showing what got cleared
+        self.copy_mode = None;
+        if let Some(s) = self.output_spool.as_mut() {
+            // vt100 has no way to erase the scrollback,
+            // so start over with a blank spool
+            let (rows, cols) = s.screen().size();
+            *s = shpool_vt100::Parser::new(rows, cols, self.spool_lines);
+        }
+        if let Some(spill) = self.spill.as_mut() {
+            spill.clear();
+        }
+        self.raw_tail.clear();
 
-                let mut snip_sections = vec![]; // (<len>, <end offset>)
-                let mut keep_sections = vec![]; // (<start offset>, <end offset>)
-                let mut buf: Vec<u8> = vec![0; consts::BUF_SIZE];
-                let mut partial_keybinding = vec![];
+        if let ClientConnectionMsg::New(conn) = &self.client_conn {
+            if !conn.dumb_term {
+                conn.write_data(CLEAR_SCROLLBACK);
+            }
+            if let Some(sl) = self.status_line.as_mut() {
+                sl.track(CLEAR_SCROLLBACK);
+                sl.invalidate();
+            }
+            // give full screen programs a chance to redraw
+            self.resize_cmd = Some(jiggle_size(&*self.pty, self.resize_cmd.as_ref())?);
+        }
 
-                loop {
-                    if stop.load(Ordering::Relaxed) {
-                        info!("recvd stop msg (1)");
-                        return Ok(());
-                    }
+        Ok(ClientConnectionStatus::Cleared)
+    }
 
-                    // N.B. we don't need to muck about with chunking or anything
-                    // in this direction, because there is only one input stream
-                    // to the shell subprocess and we don't need to worry about
-                    // heartbeating to detect hangup or anything like that.
-                    //
-                    // Also, note that we don't access through the mutex because reads
-                    // don't need to be excluded from trampling on writes.
-                    let mut len =
-                        reader_client_stream.read(&mut buf).context("reading client chunk")?;
-                    if len == 0 {
-                        continue;
-                    }
-                    test_hooks::emit("daemon-read-c2s-chunk");
-                    trace!("read client len={}: '{}'", len, String::from_utf8_lossy(&buf[..len]),);
-
-                    // We might be able to gain some perf by doing this scanning in
-                    // a background thread (though maybe not given the need to copy
-                    // the data), but just doing it inline doesn't seem have have
-                    // a major perf impact, and this way is simpler.
-                    snip_sections.clear();
-                    for (i, byte) in buf[0..len].iter().enumerate() {
-                        use keybindings::BindingResult::*;
-                        match bindings.transition(*byte) {
-                            NoMatch
-                                if !partial_keybinding.is_empty()
-                                    && i < partial_keybinding.len() =>
-                            {
-                                // it turned out the partial keybinding match was not
-                                // a real match, so flush it to the output stream
-                                debug!(
-                                    "flushing partial keybinding_len={} i={}",
-                                    partial_keybinding.len(),
-                                    i
-                                );
-                                master_writer
-                                    .write_all(&partial_keybinding)
-                                    .context("writing partial keybinding")?;
-                                if i > 0 {
-                                    // snip the leading part of the input chunk that
-                                    // was part of this keybinding
-                                    snip_sections.push((i, i - 1));
-                                }
-                                partial_keybinding.clear()
-                            }
-                            NoMatch => {
-                                partial_keybinding.clear();
-                            }
-                            Partial => {
-                                partial_keybinding.push(*byte);
-                            }
-                            Match(action) => {
-                                info!("{:?} keybinding action fired", action);
-                                let keybinding_len = partial_keybinding.len() + 1;
-                                if keybinding_len < i {
-                                    // this keybinding is wholly contained in buf
-                                    debug!("snipping keybinding_len={} i={}", keybinding_len, i);
-                                    snip_sections.push((keybinding_len, i));
-                                } else {
-                                    // this keybinding was split across multiple
-                                    // input buffers, just snip the last bit
-                                    debug!("snipping split keybinding i={}", i);
-                                    snip_sections.push((i + 1, i));
-                                }
-                                partial_keybinding.clear();
-
-                                use keybindings::Action::*;
-                                match action {
-                                    Detach => self.action_detach()?,
-                                    NoOp => {}
-                                }
-                            }
-                        }
-                    }
-                    if !partial_keybinding.is_empty() {
-                        // we have a partial keybinding pending, so don't write
-                        // it to the output stream immediately
-                        let snip_chunk_len = if partial_keybinding.len() > len {
-                            len
-                        } else {
-                            partial_keybinding.len()
-                        };
-                        debug!(
-                            "end of buf w/ partial keybinding_len={} snip_chunk_len={} buf_len={}",
-                            partial_keybinding.len(),
-                            snip_chunk_len,
-                            len
-                        );
-                        snip_sections.push((snip_chunk_len, len - 1));
+    /// Show or hide the status line on the attached client.
+    fn toggle_status_line(&mut self) -> ClientConnectionStatus {
+        let up = match &self.client_conn {
+            ClientConnectionMsg::New(conn) if !conn.dumb_term => {
+                let up = self.status_line.is_none();
+                let size = if up { status_line::shell_size(&conn.size) } else { conn.size.clone() };
+                if let Some(s) = self.output_spool.as_mut() {
+                    s.screen_mut().set_size(size.rows, u16::MAX);
+                }
+                if let Some(sl) = self.status_line.take() {
+                    info!("taking down status line");
+                    conn.write_data(&sl.take_down());
+                } else {
+                    info!("putting up status line");
+                    // redraw from scratch, so that the status
+                    // line knows where everything is
+                    let mut sl = StatusLine::new(&conn.size);
+                    let mut redraw = CLEAR_SCREEN.to_vec();
+                    if let Some(s) = self.output_spool.as_ref() {
+                        redraw.extend(s.screen().contents_formatted());
                     }
-                    len = snip_buf(&mut buf[..], len, &snip_sections[..], &mut keep_sections);
-
-                    master_writer.write_all(&buf[0..len]).context("writing client chunk")?;
-
-                    master_writer.flush().context("flushing input from client to shell")?;
-
-                    debug!("flushed chunk of len {}", len);
+                    let redraw = self.osc_filter.relink(redraw);
+                    conn.write_data(&redraw);
+                    sl.reset(&redraw);
+                    self.status_line = Some(sl);
                 }
-            })
-            .map_err(|e| anyhow!("{:?}", e))
+                self.resize_cmd = Some(ResizeCmd { size, when: time::Instant::now() });
+                self.status_toggled = Some(up);
+                up
+            }
+            _ => false,
+        };
+        ClientConnectionStatus::StatusLine(up)
     }
 
-    #[instrument(skip_all)]
-    fn spawn_heartbeat<'scope>(
-        &'scope self,
-        scope: &'scope thread::Scope<'scope, '_>,
-        conn_id: usize,
-        stop: &'scope AtomicBool,
-        client_stream_m: &'scope Arc<Mutex<io::BufWriter<UnixStream>>>,
-    ) -> anyhow::Result<thread::ScopedJoinHandle<anyhow::Result<()>>> {
-        thread::Builder::new()
-            .name(format!("heartbeat({})", self.name))
-            .spawn_scoped(scope, move || -> anyhow::Result<()> {
-                let _s1 = span!(Level::INFO, "heartbeat", s = self.name, cid = conn_id).entered();
-
-                loop {
-                    trace!("checking stop_rx");
-                    if stop.load(Ordering::Relaxed) {
-                        info!("recvd stop msg");
-                        return Ok(());
+    /// Draw the attached client's screen from scratch.
+    fn redraw(&mut self) -> anyhow::Result<ClientConnectionStatus> {
+        info!("redrawing client");
+        if let ClientConnectionMsg::New(conn) = &self.client_conn {
+            if !conn.dumb_term {
+                // Whatever had the terminal in the meantime
+                // probably left it on the main screen with
+                // its own scroll region, so start over.
+                let mut redraw = RESET_SCROLL_REGION.to_vec();
+                if self.lock.is_locked() {
+                    redraw.extend(lock_screen(&self.name, None, false));
+                } else if let Some(cm) = self.copy_mode.as_ref() {
+                    redraw.extend(cm.enter());
+                } else if let Some(s) = self.output_spool.as_ref() {
+                    if s.screen().alternate_screen() {
+                        redraw.extend(ENTER_ALT_SCREEN);
                     }
+                    let mut screen = CLEAR_SCREEN.to_vec();
+                    screen.extend(s.screen().contents_formatted());
+                    redraw.extend(self.osc_filter.relink(screen));
+                }
+                conn.write_data(&redraw);
+                if let Some(sl) = self.status_line.as_mut() {
+                    sl.reset(&redraw);
+                }
+            }
+            // there is nothing to redraw from in simple
+            // restore mode, and full screen programs may
+            // need to set their modes up again anyway
+            if !self.lock.is_locked() {
+                self.resize_cmd = Some(jiggle_size(&*self.pty, self.resize_cmd.as_ref())?);
+            }
+        }
+        Ok(ClientConnectionStatus::Redrawn)
+    }
 
-                    thread::sleep(consts::HEARTBEAT_DURATION);
-                    let chunk = protocol::Chunk { kind: protocol::ChunkKind::Heartbeat, buf: &[] };
-                    {
-                        let mut s = client_stream_m.lock().unwrap();
-                        match chunk.write_to(&mut *s).and_then(|_| s.flush()) {
-                            Ok(_) => {
-                                trace!("wrote heartbeat");
-                            }
-                            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
-                                trace!("client hangup: {:?}", e);
-                                return Ok(());
-                            }
-                            Err(e) => {
-                                return Err(e).context("writing heartbeat")?;
-                            }
+    /// Put the attached client into copy mode, if it can have it.
+    fn enter_copy_mode(&mut self) -> ClientConnectionStatus {
+        let active = match (&self.client_conn, self.output_spool.as_ref()) {
+            (ClientConnectionMsg::New(conn), Some(spool)) if !conn.dumb_term => {
+                if self.copy_mode.is_none() {
+                    info!("entering copy mode");
+                    let spilled = match self.spill.as_ref().map(|s| s.lines()).transpose() {
+                        Ok(lines) => lines.unwrap_or_default(),
+                        Err(e) => {
+                            warn!("reading spilled scrollback: {:?}", e);
+                            vec![]
                         }
+                    };
+                    let cm = copy_mode::CopyMode::new(spilled, spool.screen(), conn.size.clone());
+                    let enter = cm.enter();
+                    conn.write_data(&enter);
+                    if let Some(sl) = self.status_line.as_mut() {
+                        sl.track(&enter);
                     }
+                    self.copy_mode = Some(cm);
                 }
-            })
-            .map_err(|e| anyhow!("{:?}", e))
+                true
+            }
+            (_, None) => {
+                info!("no output spool in simple restore mode, so no copy mode");
+                false
+            }
+            (_, _) => false,
+        };
+        ClientConnectionStatus::CopyMode(active)
     }
 
-    #[instrument(skip_all)]
-    fn spawn_supervisor<'scope>(
-        &'scope self,
-        scope: &'scope thread::Scope<'scope, '_>,
-        conn_id: usize,
-        stop: &'scope AtomicBool,
-        child_done: &'scope AtomicBool,
-        pty_master: &'scope shpool_pty::fork::Master,
-        child_exit_notifier: Arc<ExitNotifier>,
-    ) -> anyhow::Result<thread::ScopedJoinHandle<anyhow::Result<()>>> {
-        thread::Builder::new()
-            .name(format!("supervisor({})", self.name))
-            .spawn_scoped(scope, move || -> anyhow::Result<()> {
-                let _s1 = span!(Level::INFO, "supervisor", s = self.name, cid = conn_id).entered();
-
-                loop {
-                    trace!("checking stop_rx (pty_master={:?})", pty_master.raw_fd());
-                    if stop.load(Ordering::Relaxed) {
-                        info!("recvd stop msg");
-                        return Ok(());
+    /// Feed input from the attached client to copy mode.
+    fn copy_mode_input(&mut self, input: &[u8]) -> ClientConnectionStatus {
+        let outcome = self.copy_mode.as_mut().map(|cm| cm.handle_input(input));
+        let active = match (outcome, &self.client_conn) {
+            (Some(copy_mode::Outcome::Continue), ClientConnectionMsg::New(conn)) => {
+                if let Some(cm) = self.copy_mode.as_ref() {
+                    let render = cm.render();
+                    conn.write_data(&render);
+                    if let Some(sl) = self.status_line.as_mut() {
+                        sl.track(&render);
                     }
-
-                    match child_exit_notifier.wait(Some(SUPERVISOR_POLL_DUR)) {
-                        Some(exit_status) => {
-                            info!("child shell exited with status {}", exit_status);
-                            // mark child as exited so the attach routine will
-                            // cleanup correctly.
-                            child_done.store(true, Ordering::Release);
-
-                            // we don't need to worry about the ExitStatus frame
-                            // because the reader thread cleanup should handle
-                            // that.
-                            return Ok(());
-                        }
-                        None => {
-                            // shell is still running, do nothing
-                            trace!("poll timeout");
-                        }
+                }
+                true
+            }
+            (Some(outcome), ClientConnectionMsg::New(conn)) => {
+                if let copy_mode::Outcome::Copy(text) = outcome {
+                    let len = text.len();
+                    let buffer = self.args.paste_buffers.lock().unwrap().set(None, text);
+                    info!("copy mode copied {} bytes into '{}'", len, buffer);
+                }
+                info!("leaving copy mode");
+                if let (Some(cm), Some(spool)) = (self.copy_mode.take(), self.output_spool.as_ref())
+                {
+                    let leave = cm.leave(spool.screen());
+                    conn.write_data(&leave);
+                    if let Some(sl) = self.status_line.as_mut() {
+                        sl.track(&leave);
+                        sl.invalidate();
                     }
                 }
-            })
-            .map_err(|e| anyhow!("{:?}", e))
+                false
+            }
+            (_, _) => false,
+        };
+        ClientConnectionStatus::CopyMode(active)
     }
 
-    //
-    // actions which can be bound to keybindings
-    //
-
-    #[instrument(skip_all)]
-    fn action_detach(&self) -> anyhow::Result<()> {
+    /// Show the lock screen in place of the session's output.
+    fn show_lock_screen(&mut self, message: Option<&str>) -> ClientConnectionStatus {
+        info!("showing lock screen");
+        // copy mode is behind the lock screen now
+        self.copy_mode = None;
+        if let ClientConnectionMsg::New(conn) = &self.client_conn {
+            let screen = lock_screen(&self.name, message, conn.dumb_term);
+            conn.write_data(&screen);
+            if let Some(sl) = self.status_line.as_mut() {
+                sl.track(&screen);
+            }
+        }
+        ClientConnectionStatus::Locked(true)
+    }
+
+    /// Take down the lock screen and redraw the session.
+    fn unlock(&mut self) -> anyhow::Result<ClientConnectionStatus> {
+        info!("unlocked, redrawing");
+        if let ClientConnectionMsg::New(conn) = &self.client_conn {
+            if !conn.dumb_term {
+                let mut redraw = SOFT_RESET.to_vec();
+                redraw.extend(CLEAR_SCREEN);
+                if let Some(s) = self.output_spool.as_ref() {
+                    redraw.extend(s.screen().contents_formatted());
+                }
+                let redraw = self.osc_filter.relink(redraw);
+                conn.write_data(&redraw);
+                if let Some(sl) = self.status_line.as_mut() {
+                    sl.reset(&redraw);
+                }
+            }
+            // the client missed whatever happened while the
+            // session was locked
+            *self.args.alerts.lock().unwrap() = PendingAlerts::default();
+            let osc_buf = self.pending_osc.take();
+            if !osc_buf.is_empty() && !conn.dumb_term {
+                conn.write_data(&osc_buf);
+            }
+            self.resize_cmd = Some(jiggle_size(&*self.pty, self.resize_cmd.as_ref())?);
+        }
+        Ok(ClientConnectionStatus::Locked(false))
+    }
+
+    /// The contents of the output spool, either as is or as plain text.
+    fn save_output(&self, strip_ansi: bool) -> ClientConnectionStatus {
+        let output = self.output_spool.as_ref().map(|spool| {
+            if strip_ansi {
+                let mut text = copy_mode::snapshot(spool.screen()).join("\n");
+                text.push('\n');
+                text.into_bytes()
+            } else {
+                spool.screen().last_n_rows_contents_formatted(u16::MAX)
+            }
+        });
+        ClientConnectionStatus::Output(output)
+    }
+
+    /// What is on the screen right now, or the tail of the raw output.
+    fn capture(&self, mode: protocol::CaptureMode) -> ClientConnectionStatus {
+        let output = match mode {
+            protocol::CaptureMode::Screen { ansi } => self.output_spool.as_ref().map(|spool| {
+                if ansi {
+                    spool.screen().contents_formatted()
+                } else {
+                    spool
+                        .screen()
+                        .rows(0, VTERM_WIDTH)
+                        .map(|row| format!("{}\n", row.trim_end()))
+                        .collect::<String>()
+                        .into_bytes()
+                }
+            }),
+            protocol::CaptureMode::Raw { bytes } => {
+                let skip = self.raw_tail.len().saturating_sub(bytes);
+                let tail: Vec<u8> = self.raw_tail.iter().skip(skip).copied().collect();
+                Some(utf8::trim_partial_start(&tail).to_vec())
+            }
+        };
+        ClientConnectionStatus::Output(output)
+    }
+
+    /// Follow the attached client's terminal to its new size.
+    fn resize(&mut self, size: tty::Size) -> anyhow::Result<()> {
+        info!("resize size={:?}", size);
+        if let ClientConnectionMsg::New(conn) = &mut self.client_conn {
+            conn.size = size.clone();
+            if let Some(cm) = self.copy_mode.as_mut() {
+                cm.resize(size.clone());
+                conn.write_data(&cm.render());
+            }
+        }
+        if let Some(sl) = self.status_line.as_mut() {
+            sl.resize(&size);
+        }
+        let size = pty_size(&size, &self.status_line);
+        if let Some(s) = self.output_spool.as_mut() {
+            s.screen_mut().set_size(size.rows, u16::MAX);
+        }
+        self.resize_cmd = Some(ResizeCmd {
+            size,
+            // No delay needed for ordinary resizes, just
+            // for reconnects.
+            when: time::Instant::now(),
+        });
+        self.args.tty_size_change_ack.send(()).context("sending size change ack")?;
+        Ok(())
+    }
+
+    /// Resize the pty if there is a resize that is due.
+    fn run_pending_resize(&mut self) -> anyhow::Result<()> {
+        let resize_cmd = match self.resize_cmd.as_ref() {
+            Some(resize_cmd)
+                if resize_cmd.when.saturating_duration_since(time::Instant::now())
+                    == time::Duration::ZERO =>
+            {
+                resize_cmd
+            }
+            _ => return Ok(()),
+        };
+        self.pty.set_size(&resize_cmd.size)?;
+        if let Some(cast) = self.cast.lock().unwrap().as_mut() {
+            if let Err(e) = cast.resize(&resize_cmd.size) {
+                warn!("recording resize: {:?}", e);
+            }
+        }
+        info!("resized fd (rows={}, cols={})", resize_cmd.size.rows, resize_cmd.size.cols);
+        self.resize_cmd = None;
+        Ok(())
+    }
+
+    /// Restore the session on a newly attached client, or show it the
+    /// lock screen if the session is locked.
+    fn reattach(&mut self) {
+        use config::SessionRestoreMode::*;
+
+        self.osc_filter.reattach();
+
+        if self.lock.is_locked() {
+            // Nothing gets restored until the session is unlocked,
+            // and alerts stay pending until then too.
+            info!("session is locked, showing lock screen rather than restoring");
+            if let ClientConnectionMsg::New(conn) = &self.client_conn {
+                let screen = lock_screen(&self.name, None, conn.dumb_term);
+                conn.write_data(&screen);
+                if let Some(sl) = self.status_line.as_mut() {
+                    sl.track(&screen);
+                }
+            }
+            return;
+        }
+
+        info!("executing reattach protocol (mode={:?})", self.args.session_restore_mode);
+        let mut timer = timing::Timer::new();
+        let dumb_term =
+            matches!(&self.client_conn, ClientConnectionMsg::New(conn) if conn.dumb_term);
+        let replay = match &self.client_conn {
+            ClientConnectionMsg::New(conn) => conn.replay,
+            _ => protocol::Replay::Default,
+        };
+        let restore_buf =
+            match (self.output_spool.as_mut(), replay, &self.args.session_restore_mode) {
+                (_, _, _) if dumb_term => vec![],
+                (_, protocol::Replay::Off, _) => vec![],
+                (Some(spool), protocol::Replay::Lines(nlines), _) => {
+                    info!("computing replay of {} lines", nlines);
+                    spool.screen().last_n_rows_contents_formatted(nlines)
+                }
+                (Some(spool), protocol::Replay::All, _) => {
+                    info!("computing replay of all lines");
+                    spool.screen().last_n_rows_contents_formatted(u16::MAX)
+                }
+                (Some(spool), _, Screen) => {
+                    let (rows, cols) = spool.screen().size();
+                    info!("computing screen restore buf with (rows={}, cols={})", rows, cols);
+                    spool.screen().contents_formatted()
+                }
+                (Some(spool), _, Lines(nlines)) => {
+                    let (rows, cols) = spool.screen().size();
+                    info!(
+                        "computing lines({}) restore buf with (rows={}, cols={})",
+                        nlines, rows, cols
+                    );
+                    spool.screen().last_n_rows_contents_formatted(*nlines)
+                }
+                (_, _, _) => vec![],
+            };
+        // The status line has to know where the cursor is, so the
+        // client starts out from a blank screen.
+        let restore_buf = match self.status_line.as_mut() {
+            Some(sl) => {
+                let mut buf = CLEAR_SCREEN.to_vec();
+                buf.extend(restore_buf);
+                sl.reset(&buf);
+                buf
+            }
+            None => restore_buf,
+        };
+        if let (true, ClientConnectionMsg::New(conn)) = (!restore_buf.is_empty(), &self.client_conn)
+        {
+            let notice_bytes =
+                self.config.get().replay_notice_bytes.unwrap_or(DEFAULT_REPLAY_NOTICE_BYTES);
+            if notice_bytes > 0 && restore_buf.len() >= notice_bytes {
+                info!("announcing replay of {} bytes", restore_buf.len());
+                let notice = protocol::StreamControl::Replay { bytes: restore_buf.len() as u64 };
+                if let Err(e) = conn.output.push_control(&notice) {
+                    warn!("sending replay notice: {:?}", e);
+                }
+            }
+            trace!("restore chunk len={}", restore_buf.len());
+            conn.write_data(&restore_buf);
+        }
+
+        *self.args.alerts.lock().unwrap() = PendingAlerts::default();
+        let osc_buf = self.pending_osc.take();
+        if let (true, ClientConnectionMsg::New(conn)) =
+            (!osc_buf.is_empty() && !dumb_term, &self.client_conn)
+        {
+            info!("replaying {} bytes of pending osc sequences", osc_buf.len());
+            conn.write_data(&osc_buf);
+        }
+        // The restored text has no links, but whatever the shell
+        // prints next may still be in the middle of one.
+        if let (Some(link), ClientConnectionMsg::New(conn)) =
+            (self.osc_filter.open_link().filter(|_| !dumb_term), &self.client_conn)
+        {
+            info!("reopening hyperlink");
+            conn.write_data(link);
+        }
+
+        timer.phase("replay");
+        if let ClientConnectionMsg::New(conn) = &self.client_conn {
+            if conn.send_timings {
+                let timings = protocol::StreamControl::Timings(timer.take());
+                if let Err(e) = conn.output.push_control(&timings) {
+                    warn!("sending replay timing: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// A client that had shell output thrown away has missed part of
+    /// the picture, so once it catches up, redraw its screen.
+    fn redraw_after_lost_output(&mut self) -> anyhow::Result<()> {
+        if let ClientConnectionMsg::New(conn) = &self.client_conn {
+            if self.copy_mode.is_none() && !self.lock.is_locked() && conn.output.take_lost_output()
+            {
+                info!("client lost output, redrawing");
+                if let (Some(spool), false) = (self.output_spool.as_ref(), conn.dumb_term) {
+                    // it may have missed a link opening or closing
+                    // too, so close whatever it thinks is open and
+                    // open up the one that really is after the redraw
+                    let mut redraw = ABORT_SEQUENCE.to_vec();
+                    redraw.extend(osc::CLOSE_LINK);
+                    redraw.extend(spool.screen().contents_formatted());
+                    redraw.extend(self.osc_filter.open_link().unwrap_or_default());
+                    conn.write_data(&redraw);
+                    if let Some(sl) = self.status_line.as_mut() {
+                        sl.track(&redraw);
+                        sl.invalidate();
+                    }
+                }
+                self.resize_cmd = Some(jiggle_size(&*self.pty, self.resize_cmd.as_ref())?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep the status line up to date, unless copy mode or the lock
+    /// screen are covering it.
+    fn refresh_status_line(&mut self) {
+        if let (Some(sl), ClientConnectionMsg::New(conn)) =
+            (self.status_line.as_mut(), &self.client_conn)
+        {
+            let now = time::Instant::now();
+            if self.copy_mode.is_none()
+                && !self.lock.is_locked()
+                && (sl.is_stale() || now >= self.status_refresh)
+            {
+                self.status_refresh = now.add(STATUS_LINE_REFRESH);
+                let mut flags = vec![];
+                if self.recorder.is_some() || self.cast.lock().unwrap().is_some() {
+                    flags.push(String::from("rec"));
+                }
+                flags.extend(status_line::idle_flag(self.args.activity.lock().unwrap().idle()));
+                let clock = chrono::Local::now().format("%H:%M").to_string();
+                let text =
+                    status_line::text(&self.name, self.attaches, &flags, &clock, conn.size.cols);
+                if sl.needs_draw(&text) {
+                    conn.write_data(&sl.draw(&text));
+                }
+            }
+        }
+    }
+
+    /// Let a client doing its own line editing know when the shell is
+    /// sitting at its prompt, and where on the line.
+    fn send_prompt_hint(&mut self) {
+        if let ClientConnectionMsg::New(conn) = &self.client_conn {
+            if conn.prompt_hints {
+                let hint = self
+                    .output_spool
+                    .as_ref()
+                    .filter(|_| {
+                        self.copy_mode.is_none() && !self.lock.is_locked() && at_prompt(&*self.pty)
+                    })
+                    .map(|s| s.screen().cursor_position().1);
+                if self.prompt_hint != Some(hint) {
+                    self.prompt_hint = Some(hint);
+                    if let Err(e) = conn.output.push_control(&protocol::StreamControl::Prompt(hint))
+                    {
+                        warn!("sending prompt hint: {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wait a little while for the shell to produce output, then send
+    /// whatever it produced to the spool, the recordings and the
+    /// attached client.
+    fn read_output(&mut self, silence_threshold: time::Duration) -> anyhow::Result<()> {
+        // Block until the shell has some data for us so we can be sure our reads
+        // always succeed. We don't want to end up blocked forever on a read while
+        // a client is trying to attach.
+        let poll_ms = if self.copy_mode.is_some() { COPY_MODE_POLL_MS } else { READER_POLL_MS };
+        let watchable_master = self.pty_master;
+        let mut poll_fds =
+            [poll::PollFd::new(watchable_master.borrow_fd(), poll::PollFlags::POLLIN)];
+        let nready = match poll::poll(&mut poll_fds, poll_ms) {
+            Ok(n) => n,
+            Err(e) => {
+                error!("polling pty master: {:?}", e);
+                return Err(e)?;
+            }
+        };
+        if nready == 0
+            && !self.utf8_joiner.is_holding()
+            && !self.redactor.is_holding()
+            && !self.cast_redactor.is_holding()
+        {
+            // if timeout
+            return Ok(());
+        }
+        if nready > 1 {
+            return Err(anyhow!("reader thread: expected exactly 1 ready fd"));
+        }
+        let mut buf = if nready == 0 {
+            // the rest of the held character would have shown up
+            // by now if it was ever going to
+            self.utf8_joiner.flush(&mut self.utf8_scratch)
+        } else {
+            let len = match self.pty_master.read(&mut self.read_buf) {
+                Ok(l) => l,
+                Err(e) => {
+                    test_hooks::emit("daemon-reader-read-error");
+                    error!("reading chunk from pty master: {:?}", e);
+                    return Err(e).context("reading pty master chunk")?;
+                }
+            };
+            // keep multibyte characters from getting split across
+            // chunks, which might go to different clients
+            self.utf8_joiner.join(&self.read_buf[..len], &mut self.utf8_scratch)
+        };
+
+        // Output only gets kept once it has been redacted, which can
+        // mean holding the end of it back until the output goes quiet.
+        {
+            let config = self.config.get();
+            let redactions = config.redact.as_deref().unwrap_or(&[]);
+            self.redactor.configure(redactions);
+            self.cast_redactor.configure(redactions);
+        }
+        let kept = self.redactor.redact(buf, nready == 0, &mut self.redact_scratch);
+        if !kept.is_empty() {
+            if let Some(recorder) = &self.recorder {
+                if let Err(e) = recorder.lock().unwrap().record(audit::Event::Output(kept.to_vec()))
+                {
+                    warn!("recording output: {:?}", e);
+                }
+            }
+
+            self.raw_tail.extend(kept.iter());
+            let excess = self.raw_tail.len().saturating_sub(RAW_TAIL_SIZE);
+            self.raw_tail.drain(..excess);
+
+            if !matches!(self.args.session_restore_mode, config::SessionRestoreMode::Simple) {
+                if let Some(s) = self.output_spool.as_mut() {
+                    s.process(kept);
+                    if let Err(e) = self.spill.as_mut().map_or(Ok(()), |sp| sp.after_output(s)) {
+                        // the spool keeps working without it, it just
+                        // holds onto less
+                        warn!("spilling scrollback, giving up on it: {:?}", e);
+                        self.spill = None;
+                    }
+                }
+            }
+            // A new prompt can end up in the same column as the
+            // last one, and the client has to hear about it anyway.
+            if matches!(self.prompt_hint, Some(Some(_))) {
+                self.prompt_hint = None;
+            }
+        }
+        if nready == 0 && self.cast_redactor.is_holding() {
+            let held = self.cast_redactor.redact(&[], true, &mut self.cast_redact_scratch);
+            if let Some(cast) = self.cast.lock().unwrap().as_mut() {
+                if let Err(e) = cast.output(held) {
+                    warn!("recording output: {:?}", e);
+                }
+            }
+        }
+
+        if buf.is_empty() {
+            return Ok(());
+        }
+        // Only the length, the output itself can hold things the
+        // redact patterns are there to keep out of logs.
+        trace!("read pty master len={}", buf.len());
+
+        let change =
+            self.args.activity.lock().unwrap().output(time::Instant::now(), silence_threshold);
+        if let Some(change) = change {
+            activity::fire_hook(&self.config, change, &self.name);
+        }
+
+        // scan for control codes we need to handle
+        let mut reset_client_conn = false;
+        if !self.has_seen_prompt_sentinel {
+            for (i, byte) in buf.iter().enumerate() {
+                if self.prompt_sentinel_scanner.transition(*byte) {
+                    info!("saw prompt sentinel");
+                    // This will cause us to start actually sending data frames back to
+                    // the client.
+                    self.has_seen_prompt_sentinel = true;
+
+                    // drop everything up to and including the sentinel
+                    buf = &buf[i + 1..];
+                }
+            }
+        }
+
+        self.osc_events.clear();
+        if self.has_seen_prompt_sentinel {
+            self.osc_scanner.scan(buf, &mut self.osc_events);
+        }
+        // Attached clients get the sequences as part of the normal
+        // output stream.
+        if !matches!(self.client_conn, ClientConnectionMsg::New(_)) {
+            for event in self.osc_events.drain(..) {
+                info!("holding osc event for reattach: {:?}", event);
+                match &event {
+                    osc::Event::Notify { title, body, .. } => {
+                        self.args.alerts.lock().unwrap().notifications += 1;
+                        if let Err(err) =
+                            self.args.hooks.on_notification(&self.name, title.as_deref(), body)
+                        {
+                            warn!("on_notification hook: {:?}", err);
+                        }
+                        hook_commands::fire(
+                            &self.config,
+                            hook_commands::Event::Notification {
+                                title: title.clone(),
+                                body: body.clone(),
+                            },
+                            &self.name,
+                        );
+                    }
+                    osc::Event::Bell => {
+                        let first = {
+                            let mut alerts = self.args.alerts.lock().unwrap();
+                            alerts.bells += 1;
+                            alerts.bells == 1
+                        };
+                        // Something beeping in a loop should not spawn a
+                        // hook command per beep.
+                        if first {
+                            hook_commands::fire(
+                                &self.config,
+                                hook_commands::Event::Bell,
+                                &self.name,
+                            );
+                        }
+                    }
+                    osc::Event::Progress { .. } => {}
+                }
+                self.pending_osc.push(&event);
+            }
+        }
+
+        // The filter sees the output even while no client is
+        // attached, so that it knows about sequences that span a
+        // detach, but what comes out of it only goes anywhere if
+        // there is a client.
+        let filtered = if self.has_seen_prompt_sentinel {
+            let dumb_term =
+                matches!(&self.client_conn, ClientConnectionMsg::New(conn) if conn.dumb_term);
+            let config = self.config.get();
+            let policy = osc::FilterPolicy {
+                strip_hyperlinks: config.strip_hyperlinks.unwrap_or(false),
+                title_suffix: config
+                    .title_suffix
+                    .as_ref()
+                    .filter(|_| !dumb_term)
+                    .map(|s| s.replace("$SHPOOL_SESSION_NAME", &self.name)),
+                clipboard: Some(config.clipboard.unwrap_or_default()),
+            };
+            Some(self.osc_filter.filter(&policy, buf, &mut self.filter_scratch))
+        } else {
+            None
+        };
+        // Recordings get what an attached client would have seen.
+        if let (Some(cast), Some(buf)) = (self.cast.lock().unwrap().as_mut(), filtered) {
+            let buf = self.cast_redactor.redact(buf, false, &mut self.cast_redact_scratch);
+            if !buf.is_empty() {
+                if let Err(e) = cast.output(buf) {
+                    warn!("recording output: {:?}", e);
+                }
+            }
+        }
+        if let (ClientConnectionMsg::New(conn), Some(buf)) = (&self.client_conn, filtered) {
+            if let Some(cm) = self.copy_mode.as_mut() {
+                cm.hold(buf);
+                return Ok(());
+            }
+            if self.lock.is_locked() {
+                // the spool has it, for the redraw on unlock
+                return Ok(());
+            }
+
+            // If we still need to do an initial motd dump, it means we have just finished
+            // dropping all the prompt setup stuff, we should dump the motd now before we
+            // write the first chunk.
+            if self.needs_initial_motd_dump {
+                self.needs_initial_motd_dump = false;
+                match self.daily_messenger.dump(&self.term_db) {
+                    Ok(motd) => {
+                        conn.write_data(&motd);
+                        if let Some(sl) = self.status_line.as_mut() {
+                            sl.track(&motd);
+                        }
+                    }
+                    Err(e) => warn!("Error handling clear: {:?}", e),
+                }
+            }
+
+            let buf = match self.status_line.as_mut() {
+                Some(sl) => sl.filter(buf, &mut self.status_scratch),
+                None => buf,
+            };
+            if !buf.is_empty() && !conn.output.push_output(buf) {
+                info!("client gone, assuming hangup");
+                reset_client_conn = true;
+            }
+        }
+        if reset_client_conn {
+            self.client_conn = ClientConnectionMsg::Disconnect;
+            self.copy_mode = None;
+            self.status_line = None;
+        }
+
+        Ok(())
+    }
+}
+
+impl SessionInner {
+    /// Spawn the reader thread which continually reads from the pty
+    /// and sends data both to the output spool and to the client,
+    /// if one is attached.
+    #[instrument(skip_all, fields(s = self.name))]
+    pub fn spawn_reader(
+        &self,
+        args: ReaderArgs,
+    ) -> anyhow::Result<thread::JoinHandle<anyhow::Result<()>>> {
+        let reader = Reader::new(self, args);
+        Ok(thread::Builder::new()
+            .name(format!("reader({})", self.name))
+            .spawn(move || log_if_error("error in reader", reader.run()))?)
+    }
+
+    /// bidi_stream shuffles bytes between the subprocess and
+    /// the client connection. It returns true if the subprocess
+    /// has exited, and false if it is still running.
+    #[instrument(skip_all, fields(s = self.name))]
+    pub fn bidi_stream(
+        &mut self,
+        attaching: AttachingClient,
+        child_exit_notifier: Arc<ExitNotifier>,
+    ) -> anyhow::Result<bool> {
+        let AttachingClient {
+            conn_id,
+            uid: client_uid,
+            tty_size: init_tty_size,
+            dumb_term,
+            replay,
+            send_timings,
+            prompt_hints,
+            on_attach_input,
+        } = attaching;
+        test_hooks::emit("daemon-bidi-stream-enter");
+        #[allow(clippy::let_unit_value)]
+        let _bidi_stream_test_guard = test_hooks::scoped("daemon-bidi-stream-done");
+
+        // we take the client stream so that it gets closed when this routine
+        // returns
+        let client_stream = match self.client_stream.take() {
+            Some(s) => s,
+            None => return Err(anyhow!("no client stream to take for bidi streaming")),
+        };
+
+        // All of the client's I/O happens in the attach loop on this
+        // thread, so nothing it touches can be allowed to block.
+        client_stream.set_nonblocking(true).context("making client stream non-blocking")?;
+        let reader_client_stream =
+            client_stream.try_clone().context("creating reader client stream handle")?;
+        let output = Arc::new(
+            OutputQueue::from_config(&self.config.get()).context("creating output queue")?,
+        );
+        let pty_master = self.pty.master();
+        pty_master.set_nonblocking().context("making pty master non-blocking")?;
+        let mut client = ClientIo::new(&client_stream, Arc::clone(&output));
+        let mut shell_input = ShellInput { master: pty_master, buf: vec![] };
+
+        // A session that sat idle for too long gets locked before the
+        // reader can restore anything to the new client.
+        let idle_timeout = lock::idle_timeout(&self.config.get());
+        if let Some(timeout) = idle_timeout {
+            if self.lock.idle(timeout) && self.lock_verifier().is_some() && self.lock.lock() {
+                info!("locking session that sat idle for over {:?}", timeout);
+            }
+        }
+
+        let status = self
+            .ask_reader(
+                &mut client,
+                ClientConnectionMsg::New(ClientConnection {
+                    output: Arc::clone(&output),
+                    size: init_tty_size,
+                    stream: reader_client_stream,
+                    dumb_term,
+                    replay,
+                    send_timings,
+                    prompt_hints,
+                }),
+            )
+            .context("attaching new client stream to reader thread")?;
+        info!("client connection status={:?}", status);
+
+        // Only type the on-attach command once the reader has the new
+        // client, so that its output doesn't go to nobody.
+        if let Some(input) = on_attach_input {
+            info!("typing on-attach command into '{}'", self.name);
+            self.record_input(&input);
+            if let Err(e) = shell_input.write(&input) {
+                warn!("typing on-attach command: {:?}", e);
+            }
+        }
+
+        let loop_res = self.attach_loop(
+            conn_id,
+            client_uid,
+            &mut client,
+            &mut shell_input,
+            &child_exit_notifier,
+        );
+        let c_done = matches!(loop_res, Ok(true));
+        debug!("attach loop done: child_done={}", c_done);
+
+        // Disconnect the reader thread, which shuts down the client
+        // stream. The client's output keeps flowing until the reader
+        // lets go of it, since the shell's exit status goes out last.
+        let msg = if c_done {
+            let exit_status = child_exit_notifier.wait(Some(Duration::from_secs(0))).unwrap_or(1);
+            info!("telling reader to disconnect with exit status {}", exit_status);
+            ClientConnectionMsg::DisconnectExit(exit_status)
+        } else {
+            info!("telling reader to disconnect without reaping");
+            ClientConnectionMsg::Disconnect
+        };
+        let send_timeout = Duration::from_millis((READER_POLL_MS + (READER_POLL_MS >> 1)) as u64);
+        match self
+            .ask_reader_within(&mut client, msg, Some(send_timeout))
+            .context("waiting for client connection ack")?
+        {
+            Some(status) => info!("detached from reader, status = {:?}", status),
+            None => {
+                info!("failed to tell reader to disconnect");
+
+                // the reader didn't close the client stream for us, so we'll need
+                // to handle that ourselves
+                client_stream.shutdown(net::Shutdown::Both)?;
+            }
+        }
+        loop_res.context("running attach loop")?;
+
+        // the reader has let go of the client connection by now, but if
+        // we never got through to it the queue is still open
+        output.close();
+
+        if c_done {
+            client_stream
+                .shutdown(std::net::Shutdown::Both)
+                .context("shutting down client stream")?;
+        }
+
+        info!("bidi_stream: done child_done={}", c_done);
+        Ok(c_done)
+    }
+
+    /// Pass the client's input along to the shell, and the shell's output
+    /// along to the client, until the client goes away or the shell
+    /// exits. Returns true if the shell exited.
+    #[instrument(skip_all, fields(cid = conn_id))]
+    fn attach_loop(
+        &self,
+        conn_id: usize,
+        client_uid: u32,
+        client: &mut ClientIo,
+        shell_input: &mut ShellInput,
+        child_exit_notifier: &ExitNotifier,
+    ) -> anyhow::Result<bool> {
+        let (bindings, mut bindings_generation) = {
+            let overrides = self.keybindings.lock().unwrap();
+            (self.compile_keybindings(&overrides), overrides.generation)
+        };
+        let mut scanner = InputScanner::new(bindings.context("compiling keybindings engine")?);
+        let output = Arc::clone(&client.output);
+
+        let mut buf: Vec<u8> = vec![0; consts::BUF_SIZE];
+        // In copy mode, input goes to the reader thread rather
+        // than the shell.
+        let mut in_copy_mode = false;
+        // While the session is locked, input is a passphrase.
+        let mut passphrase_prompt = lock::Prompt::default();
+        // We send a steady stream of heartbeats to the client so that
+        // if the connection unexpectedly goes down, we detect it
+        // immediately.
+        let mut last_heartbeat_at = time::Instant::now();
+
+        loop {
+            if let Some(exit_status) = child_exit_notifier.wait(Some(Duration::ZERO)) {
+                info!("child shell exited with status {}", exit_status);
+                // We don't need to worry about the ExitStatus frame
+                // because the reader thread cleanup should handle that.
+                return Ok(true);
+            }
+            if client.done() {
+                info!("client is gone");
+                return Ok(false);
+            }
+            let since_heartbeat = last_heartbeat_at.elapsed();
+            if since_heartbeat >= consts::HEARTBEAT_DURATION {
+                last_heartbeat_at = time::Instant::now();
+                if self.acl.lock().unwrap().access(client_uid).is_none() {
+                    info!("access revoked for uid {}, detaching", client_uid);
+                    return Ok(false);
+                }
+                self.heartbeat(client)?;
+                continue;
+            }
+
+            // Waiting on the client doubles as the sleep between checks
+            // on the shell and heartbeats.
+            let timeout =
+                consts::JOIN_POLL_DURATION.min(consts::HEARTBEAT_DURATION - since_heartbeat);
+            if !client.poll(Some(shell_input), timeout)? {
+                continue;
+            }
+
+            // N.B. we don't need to muck about with chunking or anything
+            // in this direction, because there is only one input stream
+            // to the shell subprocess.
+            let len = client.read(&mut buf)?;
+            if len == 0 {
+                continue;
+            }
+            metrics::inc(&metrics::METRICS.bytes_from_clients, len as u64);
+            test_hooks::emit("daemon-read-c2s-chunk");
+            // Only the length, the input can be a password or a secret
+            // the redact patterns would strip from the output.
+            trace!("read client len={}", len);
+
+            if self.lock.is_locked() {
+                // the lock screen took the client out of copy mode
+                in_copy_mode = false;
+                scanner.reset();
+                if let Some(passphrase) = passphrase_prompt.handle_input(&buf[..len]) {
+                    self.unlock(client, &passphrase)?;
+                }
+                continue;
+            }
+            self.lock.touch();
+
+            if in_copy_mode {
+                in_copy_mode = self.action_copy_mode_input(client, &buf[..len])?;
+                continue;
+            }
+
+            // pick up any changes made with `shpool keybind`, but don't
+            // clobber the engine state in the middle of a sequence
+            if scanner.is_idle() {
+                let overrides = self.keybindings.lock().unwrap();
+                if overrides.generation != bindings_generation {
+                    bindings_generation = overrides.generation;
+                    match self.compile_keybindings(&overrides) {
+                        Ok(b) => {
+                            info!("recompiled keybindings");
+                            scanner.set_bindings(b);
+                            send_notice(&output, "keybindings updated with shpool keybind");
+                        }
+                        Err(e) => warn!("recompiling keybindings: {:?}", e),
+                    }
+                }
+            }
+
+            // a client with read-only access can still detach and
+            // look around, but anything that would change the
+            // session gets dropped
+            let read_only = match self.acl.lock().unwrap().access(client_uid) {
+                Some(protocol::Access::ReadWrite) => false,
+                Some(protocol::Access::ReadOnly) => true,
+                None => {
+                    info!("access revoked for uid {}, detaching", client_uid);
+                    self.action_detach(client)?;
+                    continue;
+                }
+            };
+
+            scanner.scan(
+                &mut buf,
+                len,
+                |input| {
+                    if read_only {
+                        trace!("dropping {} bytes of read-only input", input.len());
+                        return Ok(());
+                    }
+                    if input.contains(&XOFF) && self.flow_notice() {
+                        send_control(
+                            &output,
+                            protocol::StreamControl::Message(String::from(PAUSED_MESSAGE)),
+                        );
+                    }
+                    self.record_input(input);
+                    shell_input.write(input)
+                },
+                |action| {
+                    use keybindings::Action::*;
+                    if read_only
+                        && !matches!(
+                            action,
+                            Detach | CopyMode | FlushOutput | Suspend | ToggleLocalEdit | NoOp
+                        )
+                    {
+                        send_notice(&output, "read-only access");
+                        return Ok(());
+                    }
+                    match action {
+                        Detach => self.action_detach(client)?,
+                        Kill => self.action_kill()?,
+                        Reset => self.action_reset(client)?,
+                        CopyMode => in_copy_mode = self.action_copy_mode(client)?,
+                        FlushOutput => self.action_flush_output(&output),
+                        ClearScrollback => self.action_clear_scrollback(client)?,
+                        ToggleStatusLine => self.action_toggle_status_line(client)?,
+                        Suspend => send_control(&output, protocol::StreamControl::Suspend),
+                        ToggleLocalEdit => {
+                            send_control(&output, protocol::StreamControl::ToggleLocalEdit)
+                        }
+                        NoOp => {}
+                        Custom(name) => self.action_custom(&name),
+                        NextJob => self.action_switch_job(client, protocol::JobTarget::Next)?,
+                        Lock => {
+                            if !self.action_lock(client)? {
+                                send_notice(
+                                    &output,
+                                    "no lock passphrase_hash or pam_service configured",
+                                );
+                            }
+                        }
+                    }
+                    Ok(())
+                },
+            )?;
+
+            debug!("passed along chunk of len {}", len);
+        }
+    }
+
+    /// Hand the reader thread a message and wait for its ack.
+    fn ask_reader(
+        &self,
+        client: &mut ClientIo,
+        msg: ClientConnectionMsg,
+    ) -> anyhow::Result<ClientConnectionStatus> {
+        self.ask_reader_within(client, msg, None)?.ok_or(anyhow!("reader never took the message"))
+    }
+
+    /// Hand the reader thread a message and wait for its ack, giving up
+    /// if the reader hasn't taken the message after timeout. The
+    /// client's output keeps flowing in the meantime, since the reader
+    /// might be waiting on room in the queue before it can get to the
+    /// message.
+    fn ask_reader_within(
+        &self,
+        client: &mut ClientIo,
+        mut msg: ClientConnectionMsg,
+        timeout: Option<time::Duration>,
+    ) -> anyhow::Result<Option<ClientConnectionStatus>> {
+        let deadline = timeout.map(|t| time::Instant::now() + t);
         let reader_ctl = self.reader_ctl.lock().unwrap();
-        reader_ctl
-            .client_connection
-            .send(ClientConnectionMsg::Disconnect)
+        loop {
+            match reader_ctl.client_connection.send_timeout(msg, READER_ACK_POLL_DUR) {
+                Ok(()) => break,
+                Err(crossbeam_channel::SendTimeoutError::Timeout(m)) => msg = m,
+                Err(crossbeam_channel::SendTimeoutError::Disconnected(_)) => {
+                    return Err(anyhow!("reader thread is gone"));
+                }
+            }
+            if deadline.map(|d| time::Instant::now() >= d).unwrap_or(false) {
+                return Ok(None);
+            }
+            client.flush();
+        }
+        loop {
+            match reader_ctl.client_connection_ack.try_recv() {
+                Ok(status) => return Ok(Some(status)),
+                Err(crossbeam_channel::TryRecvError::Empty) => {}
+                Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                    return Err(anyhow!("reader thread is gone"));
+                }
+            }
+            client.poll(None, READER_ACK_POLL_DUR)?;
+        }
+    }
+
+    /// Lock the session if it has sat without input for too long, then
+    /// queue up a heartbeat for the client.
+    fn heartbeat(&self, client: &mut ClientIo) -> anyhow::Result<()> {
+        let idle_timeout = lock::idle_timeout(&self.config.get());
+        if let Some(timeout) = idle_timeout {
+            if !self.lock.is_locked() && self.lock.idle(timeout) {
+                info!("locking session after {:?} without input", timeout);
+                self.action_lock(client)?;
+            }
+        }
+
+        if client.output.push_heartbeat() {
+            trace!("queued heartbeat");
+        }
+        Ok(())
+    }
+
+    /// Whether the client should hear about Ctrl-S pausing the
+    /// session's output right now.
+    fn flow_notice(&self) -> bool {
+        self.config.get().flow_control == Some(config::FlowControl::Notice)
+            && self.pty.flow_control().unwrap_or(false)
+    }
+
+    //
+    // actions which can be bound to keybindings
+    //
+
+    #[instrument(skip_all)]
+    fn record_input(&self, buf: &[u8]) {
+        record_input(self.recorder.as_deref(), &*self.pty, buf);
+    }
+
+    fn action_detach(&self, client: &mut ClientIo) -> anyhow::Result<()> {
+        let status = self
+            .ask_reader(client, ClientConnectionMsg::Disconnect)
             .context("signaling client detach to reader thread")?;
-        let status =
-            reader_ctl.client_connection_ack.recv().context("waiting for client connection ack")?;
 
         info!("action detach, status={:?}", status);
         Ok(())
     }
+
+    fn action_switch_job(
+        &self,
+        client: &mut ClientIo,
+        target: protocol::JobTarget,
+    ) -> anyhow::Result<()> {
+        let status = self
+            .ask_reader(
+                client,
+                ClientConnectionMsg::Hangup(protocol::StreamControl::SwitchJob(target)),
+            )
+            .context("signaling job switch to reader thread")?;
+
+        info!("action switch job, status={:?}", status);
+        Ok(())
+    }
+
+    fn action_clear_scrollback(&self, client: &mut ClientIo) -> anyhow::Result<()> {
+        let status = self
+            .ask_reader(client, ClientConnectionMsg::ClearScrollback)
+            .context("signaling clear to reader thread")?;
+
+        info!("action clear scrollback, status={:?}", status);
+        Ok(())
+    }
+
+    fn action_toggle_status_line(&self, client: &mut ClientIo) -> anyhow::Result<()> {
+        let status = self
+            .ask_reader(client, ClientConnectionMsg::ToggleStatusLine)
+            .context("signaling status line toggle to reader thread")?;
+
+        info!(
+            "action toggle status line, up={}",
+            matches!(status, ClientConnectionStatus::StatusLine(true))
+        );
+        Ok(())
+    }
+
+    fn action_reset(&self, client: &mut ClientIo) -> anyhow::Result<()> {
+        let status = self
+            .ask_reader(client, ClientConnectionMsg::Reset)
+            .context("signaling reset to reader thread")?;
+
+        info!("action reset, status={:?}", status);
+        Ok(())
+    }
+
+    /// Throw away the output queued up for the client. The reader thread
+    /// redraws the screen once the client catches up.
+    fn action_flush_output(&self, output: &OutputQueue) {
+        let flushed = output.flush();
+        info!("action flush-output, flushed {} bytes", flushed);
+    }
+
+    /// Returns true if the client actually went into copy mode.
+    fn action_copy_mode(&self, client: &mut ClientIo) -> anyhow::Result<bool> {
+        let status = self
+            .ask_reader(client, ClientConnectionMsg::CopyMode)
+            .context("signaling copy mode to reader thread")?;
+
+        info!("action copy-mode, status={:?}", status);
+        Ok(matches!(status, ClientConnectionStatus::CopyMode(true)))
+    }
+
+    /// Pass input along to copy mode. Returns true if the client is
+    /// still in copy mode afterwards.
+    fn action_copy_mode_input(&self, client: &mut ClientIo, buf: &[u8]) -> anyhow::Result<bool> {
+        let status = self
+            .ask_reader(client, ClientConnectionMsg::CopyModeInput(buf.to_vec()))
+            .context("sending copy mode input to reader thread")?;
+
+        debug!("copy mode input, status={:?}", status);
+        Ok(matches!(status, ClientConnectionStatus::CopyMode(true)))
+    }
+
+    /// How to check the passphrase that unlocks the session, if the
+    /// config sets one up.
+    fn lock_verifier(&self) -> Option<lock::Verifier> {
+        let verifier = lock::Verifier::from_config(&self.config.get());
+        match verifier {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("setting up lock passphrase check: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Returns false if the session can't be locked, since there would
+    /// be no way to unlock it.
+    fn action_lock(&self, client: &mut ClientIo) -> anyhow::Result<bool> {
+        if self.lock_verifier().is_none() {
+            warn!("no way to check lock passphrases, not locking");
+            return Ok(false);
+        }
+        if !self.lock.lock() {
+            return Ok(true);
+        }
+
+        let status = self
+            .ask_reader(client, ClientConnectionMsg::LockScreen(None))
+            .context("signaling lock to reader thread")?;
+
+        info!("action lock, locked={}", matches!(status, ClientConnectionStatus::Locked(true)));
+        Ok(true)
+    }
+
+    /// Check a passphrase typed at the lock screen, unlocking the
+    /// session if it is the right one.
+    fn unlock(&self, client: &mut ClientIo, passphrase: &[u8]) -> anyhow::Result<()> {
+        let verified = match self.lock_verifier() {
+            Some(verifier) => verifier.verify(passphrase),
+            None => Err(anyhow!("no lock passphrase_hash or pam_service configured")),
+        };
+        let msg = match verified {
+            Ok(true) => {
+                info!("unlocking session");
+                self.lock.unlock();
+                ClientConnectionMsg::Unlock
+            }
+            Ok(false) => {
+                info!("wrong passphrase");
+                ClientConnectionMsg::LockScreen(Some(String::from("wrong passphrase")))
+            }
+            Err(e) => {
+                warn!("checking passphrase: {:?}", e);
+                ClientConnectionMsg::LockScreen(Some(format!(
+                    "could not check passphrase: {:#}",
+                    e
+                )))
+            }
+        };
+
+        let status =
+            self.ask_reader(client, msg).context("signaling unlock attempt to reader thread")?;
+
+        debug!("unlock attempt, locked={}", matches!(status, ClientConnectionStatus::Locked(true)));
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn action_custom(&self, name: &str) {
+        match self.custom_actions.get(name) {
+            Some(action) => {
+                info!("running custom action '{}'", name);
+                if let Err(e) = action(&self.name) {
+                    warn!("custom action '{}': {:?}", name, e);
+                }
+            }
+            None => warn!("no custom action named '{}'", name),
+        }
+    }
+
+    #[instrument(skip_all)]
+    fn action_kill(&self) -> anyhow::Result<()> {
+        // Just hang up on the shell. The attach loop will notice
+        // when it exits and the usual cleanup will take care of the rest.
+        let child_pid = self.pty.child_pid();
+        signal::kill(Pid::from_raw(child_pid), Some(signal::Signal::SIGHUP))
+            .context("sending SIGHUP to child proc")?;
+
+        info!("action kill, sent SIGHUP to {}", child_pid);
+        Ok(())
+    }
+
+    fn compile_keybindings(
+        &self,
+        overrides: &KeybindingOverrides,
+    ) -> anyhow::Result<keybindings::Bindings> {
+        let config = self.config.get();
+        let bindings = overrides.effective(&config);
+        Ok(keybindings::Bindings::new(
+            bindings.iter().map(|b| (b.binding.as_str(), b.action.clone())),
+        )?
+        .with_csi_u(config.csi_u_keybindings.unwrap_or(false)))
+    }
 }
 
 /// A handle for poking at the always-running reader thread.
@@ -948,6 +2241,375 @@ pub struct ReaderCtl {
     pub tty_size_change_ack: crossbeam_channel::Receiver<()>,
 }
 
+impl ReaderCtl {
+    /// Tell the reader thread to drop its client, if it has one.
+    pub fn detach(&self) -> anyhow::Result<ClientConnectionStatus> {
+        self.client_connection
+            .send(ClientConnectionMsg::Disconnect)
+            .context("sending client detach to reader")?;
+        self.client_connection_ack.recv().context("getting client conn ack")
+    }
+}
+
+/// The attached client's end of the attach loop. Rather than blocking on
+/// any one thing, the loop polls the client socket, the output queue and
+/// the pty for whichever of them it is waiting on, so a client only
+/// needs the one thread no matter which way the data is going.
+struct ClientIo<'a> {
+    stream: &'a UnixStream,
+    output: Arc<OutputQueue>,
+    writer: output_queue::Writer,
+    /// How the last attempt to write out the queue went.
+    flushed: output_queue::Flushed,
+    /// Set once the client has stopped sending input.
+    input_closed: bool,
+}
+
+impl<'a> ClientIo<'a> {
+    /// The stream must be in non-blocking mode.
+    fn new(stream: &'a UnixStream, output: Arc<OutputQueue>) -> Self {
+        ClientIo {
+            stream,
+            writer: output_queue::Writer::new(Arc::clone(&output)),
+            output,
+            flushed: output_queue::Flushed::Idle,
+            input_closed: false,
+        }
+    }
+
+    /// True once the client has hung up or the reader is done with it.
+    fn done(&self) -> bool {
+        matches!(self.flushed, output_queue::Flushed::HungUp | output_queue::Flushed::Closed)
+    }
+
+    /// Write out as much of the queued output as the client will take.
+    fn flush(&mut self) {
+        if !self.done() {
+            let mut stream = self.stream;
+            self.flushed = self.writer.flush(&mut stream);
+        }
+    }
+
+    /// Write out what output we can, then wait up to timeout for more
+    /// output, room for it, or anything else to do. Given shell input to
+    /// feed, this also feeds the shell and waits on input from the
+    /// client, returning true if there is some. We hold off on reading
+    /// more until the shell has taken the last of it though, so a shell
+    /// that isn't reading its input slows down the client rather than
+    /// piling input up here.
+    fn poll(
+        &mut self,
+        mut shell_input: Option<&mut ShellInput>,
+        mut timeout: time::Duration,
+    ) -> anyhow::Result<bool> {
+        self.flush();
+        if let output_queue::Flushed::Waiting(wait) = self.flushed {
+            timeout = timeout.min(wait);
+        }
+
+        let reading = !self.input_closed
+            && shell_input.as_ref().map(|input| input.buf.is_empty()).unwrap_or(false);
+        let mut client_events = poll::PollFlags::empty();
+        if reading {
+            client_events |= poll::PollFlags::POLLIN;
+        }
+        if self.flushed == output_queue::Flushed::Blocked {
+            client_events |= poll::PollFlags::POLLOUT;
+        }
+
+        let mut poll_fds = Vec::with_capacity(3);
+        if !self.done() {
+            // hangups get reported whether we ask for them or not
+            poll_fds.push(poll::PollFd::new(self.stream.as_fd(), client_events));
+            if self.flushed != output_queue::Flushed::Blocked {
+                poll_fds.push(poll::PollFd::new(self.output.waker(), poll::PollFlags::POLLIN));
+            }
+        }
+        if let Some(input) = shell_input.as_ref().filter(|input| !input.buf.is_empty()) {
+            poll_fds.push(poll::PollFd::new(input.master.borrow_fd(), poll::PollFlags::POLLOUT));
+        }
+        let timeout_ms = timeout.as_micros().div_ceil(1000).min(u16::MAX as u128) as u16;
+        match poll::poll(&mut poll_fds, timeout_ms) {
+            Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+            Err(e) => return Err(e).context("polling client"),
+        }
+        let client_revents = match self.done() {
+            true => poll::PollFlags::empty(),
+            false => poll_fds[0].revents().unwrap_or(poll::PollFlags::empty()),
+        };
+        drop(poll_fds);
+
+        if let Some(input) = shell_input.as_mut() {
+            input.flush()?;
+        }
+        if client_revents.intersects(poll::PollFlags::POLLHUP | poll::PollFlags::POLLERR) {
+            info!("client hung up");
+            self.output.hangup();
+            self.flushed = output_queue::Flushed::HungUp;
+            return Ok(false);
+        }
+        Ok(reading && client_revents.contains(poll::PollFlags::POLLIN))
+    }
+
+    /// Read whatever input the client has for us.
+    fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        match self.stream.read(buf) {
+            Ok(0) => {
+                info!("client stopped sending input");
+                self.input_closed = true;
+                Ok(0)
+            }
+            Ok(len) => Ok(len),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                Ok(0)
+            }
+            Err(e) => Err(e).context("reading client chunk"),
+        }
+    }
+}
+
+/// Client input on its way to the shell. Whatever the pty won't take
+/// yet gets held on to rather than blocking on the pty, so that a shell
+/// that isn't reading its input can't hold up its own output.
+struct ShellInput {
+    /// In non-blocking mode.
+    master: pty::Master,
+    buf: Vec<u8>,
+}
+
+impl ShellInput {
+    fn write(&mut self, input: &[u8]) -> anyhow::Result<()> {
+        self.buf.extend_from_slice(input);
+        self.flush()
+    }
+
+    /// Feed the shell as much of the held input as it will take.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        let mut written = 0;
+        let res = loop {
+            if written == self.buf.len() {
+                break Ok(());
+            }
+            match self.master.try_write(&self.buf[written..]) {
+                Ok(0) => break Ok(()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => break Err(e).context("writing client input"),
+            }
+        };
+        self.buf.drain(..written);
+        res
+    }
+}
+
+/// Show the attached client a notice, outside of the shell's output.
+fn send_notice(output: &OutputQueue, msg: &str) {
+    send_control(output, protocol::StreamControl::Notice(String::from(msg)));
+}
+
+/// Send the attached client a control message.
+fn send_control(output: &OutputQueue, ctl: protocol::StreamControl) {
+    if let Err(e) = output.push_control(&ctl) {
+        warn!("sending control message {:?}: {:?}", ctl, e);
+    }
+}
+
+/// Add input headed for the shell to the audit recording, if there
+/// is one. Passwords only get noted by length, and if we can't tell
+/// whether the input is a password we assume it is.
+fn record_input(recorder: Option<&Mutex<audit::Recorder>>, pty: &dyn pty::Pty, buf: &[u8]) {
+    let recorder = match recorder {
+        Some(r) => r,
+        None => return,
+    };
+    if buf.is_empty() {
+        return;
+    }
+
+    let redact = match pty.reading_password() {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("checking for password prompt: {:?}", e);
+            true
+        }
+    };
+    let event = if redact {
+        audit::Event::RedactedInput(buf.len())
+    } else {
+        audit::Event::Input(buf.to_vec())
+    };
+    if let Err(e) = recorder.lock().unwrap().record(event) {
+        warn!("recording input: {:?}", e);
+    }
+}
+
+/// Type input into the session from somewhere other than an attached
+/// client. Input from an attached client can get interleaved with it,
+/// but only at the granularity of whole writes.
+pub fn send_input(
+    pty: &dyn pty::Pty,
+    recorder: Option<&Mutex<audit::Recorder>>,
+    buf: &[u8],
+) -> anyhow::Result<()> {
+    record_input(recorder, pty, buf);
+    pty.master().write_all(buf).context("writing input to pty")?;
+    Ok(())
+}
+
+/// Picks keybindings out of the input from a client on its way to the
+/// shell. Bytes that might be the start of a keybinding get held back
+/// until it is clear whether they are, but everything that does not
+/// turn out to be one goes through exactly as it came in.
+struct InputScanner {
+    bindings: keybindings::Bindings,
+    /// The bytes of a keybinding that might be in progress, which have
+    /// been held back from the shell.
+    partial_keybinding: Vec<u8>,
+    snip_sections: Vec<(usize, usize)>, // (<len>, <end offset>)
+    keep_sections: Vec<(usize, usize)>, // (<start offset>, <end offset>)
+}
+
+impl InputScanner {
+    fn new(bindings: keybindings::Bindings) -> Self {
+        InputScanner {
+            bindings,
+            partial_keybinding: vec![],
+            snip_sections: vec![],
+            keep_sections: vec![],
+        }
+    }
+
+    /// True when no keybinding is in progress, so the bindings can be
+    /// swapped out without clobbering the engine state in the middle of
+    /// a sequence.
+    fn is_idle(&self) -> bool {
+        self.partial_keybinding.is_empty()
+    }
+
+    fn set_bindings(&mut self, bindings: keybindings::Bindings) {
+        self.bindings = bindings;
+    }
+
+    /// Drop any keybinding in progress without sending it on.
+    fn reset(&mut self) {
+        self.partial_keybinding.clear();
+    }
+
+    /// Scan the first len bytes of buf, which get rearranged in the
+    /// process. Input meant for the shell gets handed to write, and the
+    /// actions of any keybindings that fire to act, in the order they
+    /// came in.
+    fn scan<W, A>(
+        &mut self,
+        buf: &mut [u8],
+        mut len: usize,
+        mut write: W,
+        mut act: A,
+    ) -> anyhow::Result<()>
+    where
+        W: FnMut(&[u8]) -> anyhow::Result<()>,
+        A: FnMut(keybindings::Action) -> anyhow::Result<()>,
+    {
+        // We might be able to gain some perf by doing this scanning in
+        // a background thread (though maybe not given the need to copy
+        // the data), but just doing it inline doesn't seem have have
+        // a major perf impact, and this way is simpler.
+        self.snip_sections.clear();
+        // Most input can't possibly be part of a keybinding, so
+        // skip ahead to the bytes that could start one rather
+        // than feeding everything to the engine byte by byte.
+        let mut i = 0;
+        while i < len {
+            if self.partial_keybinding.is_empty() {
+                i += self.bindings.skippable(&buf[i..len]);
+                if i == len {
+                    break;
+                }
+            }
+            let byte = &buf[i];
+
+            use keybindings::BindingResult::*;
+            match self.bindings.transition(*byte) {
+                NoMatch
+                    if !self.partial_keybinding.is_empty() && i < self.partial_keybinding.len() =>
+                {
+                    // it turned out the partial keybinding match was not
+                    // a real match, so flush it to the output stream
+                    debug!(
+                        "flushing partial keybinding_len={} i={}",
+                        self.partial_keybinding.len(),
+                        i
+                    );
+                    write(&self.partial_keybinding)?;
+                    if i > 0 {
+                        // snip the leading part of the input chunk that
+                        // was part of this keybinding
+                        self.snip_sections.push((i, i - 1));
+                    }
+                    self.partial_keybinding.clear()
+                }
+                NoMatch => {
+                    self.partial_keybinding.clear();
+                }
+                Partial => {
+                    self.partial_keybinding.push(*byte);
+                }
+                Match(action) => {
+                    info!("{:?} keybinding action fired", action);
+                    metrics::inc(&metrics::METRICS.keybinding_matches, 1);
+                    let keybinding_len = self.partial_keybinding.len() + 1;
+                    if keybinding_len <= i + 1 {
+                        // this keybinding is wholly contained in buf
+                        debug!("snipping keybinding_len={} i={}", keybinding_len, i);
+                        self.snip_sections.push((keybinding_len, i));
+                    } else {
+                        // this keybinding was split across multiple
+                        // input buffers, just snip the last bit
+                        debug!("snipping split keybinding i={}", i);
+                        self.snip_sections.push((i + 1, i));
+                    }
+                    self.partial_keybinding.clear();
+
+                    act(action)?;
+                }
+            }
+            i += 1;
+        }
+        if !self.partial_keybinding.is_empty() {
+            // we have a partial keybinding pending, so don't write
+            // it to the output stream immediately
+            let snip_chunk_len = if self.partial_keybinding.len() > len {
+                len
+            } else {
+                self.partial_keybinding.len()
+            };
+            debug!(
+                "end of buf w/ partial keybinding_len={} snip_chunk_len={} buf_len={}",
+                self.partial_keybinding.len(),
+                snip_chunk_len,
+                len
+            );
+            self.snip_sections.push((snip_chunk_len, len - 1));
+        }
+        len = snip_buf(buf, len, &self.snip_sections[..], &mut self.keep_sections);
+
+        write(&buf[0..len])?;
+        if !self.partial_keybinding.is_empty() && self.bindings.abandon_escape() {
+            // The partial keybinding bytes were snipped off the end
+            // of the chunk, so they still go out in the right order.
+            debug!("flushing lone escape len={}", self.partial_keybinding.len());
+            write(&self.partial_keybinding)?;
+            self.partial_keybinding.clear();
+        }
+
+        Ok(())
+    }
+}
+
 /// Given a buffer, a length after which the data is not valid, a list of
 /// sections to remove, and some scratch space, compact the given buffer and
 /// return a new len.
@@ -1024,4 +2686,233 @@ mod test {
             assert_eq!(&buf[..got_len], &want_buf[..]);
         }
     }
+
+    // Keybinding configurations for the input scanner tests, along with
+    // the raw bytes each binding comes in as.
+    const BINDING_SETS: &[&[(&str, &[u8])]] = &[
+        &[],
+        &[("Ctrl-Space Ctrl-q", b"\x00\x11")],
+        &[("Ctrl-a", b"\x01")],
+        &[("Ctrl-a d", b"\x01d"), ("Ctrl-a Ctrl-a", b"\x01\x01"), ("Ctrl-b", b"\x02")],
+        &[("Ctrl-x Ctrl-y Ctrl-z", b"\x18\x19\x1a"), ("Ctrl-x q", b"\x18q"), ("a b", b"ab")],
+    ];
+
+    // Bits of input that are likely to trip up the scanner: the bytes the
+    // bindings above are made of, escape sequences, including CSI u ones
+    // and the bracketed paste markers, and plain text.
+    const PIECES: &[&[u8]] = &[
+        b"\x00",
+        b"\x01",
+        b"\x02",
+        b"\x11",
+        b"\x18",
+        b"\x19",
+        b"\x1a",
+        b"a",
+        b"b",
+        b"d",
+        b"q",
+        b"\x1b",
+        b"\x1b[",
+        b"\x1b[97;5u",
+        b"\x1b[100u",
+        b"\x1b[A",
+        b"\x1b[200~",
+        b"\x1b[201~",
+        b"1",
+        b";",
+        b"u",
+        b"~",
+        b"hello",
+        b"\xc3\xa9",
+        b"\xff",
+        b"\r",
+    ];
+
+    /// A tiny xorshift generator, so the property tests below are
+    /// repeatable without pulling in a dependency.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn stream(&mut self, pieces: &[&[u8]]) -> Vec<u8> {
+            let mut stream = vec![];
+            for _ in 0..self.below(40) {
+                stream.extend_from_slice(pieces[self.below(pieces.len())]);
+            }
+            stream
+        }
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    /// True if the stream has a complete CSI u sequence in it, which the
+    /// engine decodes into a key that might be bound.
+    fn contains_csi_u(stream: &[u8]) -> bool {
+        stream.windows(2).enumerate().any(|(start, w)| {
+            w == b"\x1b["
+                && stream[start + 2..]
+                    .iter()
+                    .find(|b| !(b.is_ascii_digit() || **b == b';' || **b == b':'))
+                    .map(|b| *b == b'u')
+                    .unwrap_or(false)
+        })
+    }
+
+    /// Feed the stream through a scanner in random sized chunks, returning
+    /// the bytes that made it to the shell, the bytes still held back at
+    /// the end, and the names of the actions that fired.
+    fn scan_chunked(
+        rng: &mut Rng,
+        set: &[(&str, &[u8])],
+        csi_u: bool,
+        stream: &[u8],
+    ) -> (Vec<u8>, Vec<u8>, Vec<String>) {
+        let bindings = keybindings::Bindings::new(
+            set.iter().map(|(b, _)| (*b, keybindings::Action::Custom(String::from(*b)))),
+        )
+        .unwrap()
+        .with_csi_u(csi_u);
+        let mut scanner = InputScanner::new(bindings);
+
+        let mut delivered = vec![];
+        let mut fired = vec![];
+        let mut rest = stream;
+        while !rest.is_empty() {
+            let (chunk, tail) = rest.split_at(1 + rng.below(rest.len()));
+            rest = tail;
+            // an exact fit, so that any reads or writes past the chunk
+            // trip the bounds checks
+            let mut buf = chunk.to_vec();
+            scanner
+                .scan(
+                    &mut buf,
+                    chunk.len(),
+                    |input| {
+                        delivered.extend_from_slice(input);
+                        Ok(())
+                    },
+                    |action| {
+                        match action {
+                            keybindings::Action::Custom(name) => fired.push(name),
+                            action => panic!("unexpected action {:?}", action),
+                        }
+                        Ok(())
+                    },
+                )
+                .unwrap();
+        }
+
+        (delivered, scanner.partial_keybinding.clone(), fired)
+    }
+
+    #[test]
+    fn input_without_bindings_passes_through() {
+        let mut rng = Rng(0x5eed_0f5b_9001);
+        for set in BINDING_SETS.iter() {
+            for csi_u in [false, true] {
+                let mut cases = 0;
+                while cases < 2000 {
+                    let stream = rng.stream(PIECES);
+                    if set.iter().any(|(_, raw)| contains(&stream, raw))
+                        || (csi_u && contains_csi_u(&stream))
+                    {
+                        continue;
+                    }
+                    cases += 1;
+
+                    let (mut delivered, held, fired) = scan_chunked(&mut rng, set, csi_u, &stream);
+                    assert!(fired.is_empty(), "{:?} fired on {:?}", fired, stream);
+                    delivered.extend(held);
+                    assert_eq!(
+                        delivered, stream,
+                        "bindings={:?} csi_u={} stream={:?}",
+                        set, csi_u, stream
+                    );
+                }
+            }
+        }
+    }
+
+    /// What the scanner should do with a stream that has no escape
+    /// sequences in it. Keybinding matching starts over from the byte
+    /// after the end of any sequence that turned out not to be one, and
+    /// no binding is a prefix of another, so the first one to match wins.
+    fn expected_scan(set: &[(&str, &[u8])], stream: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<String>) {
+        let mut delivered = vec![];
+        let mut fired = vec![];
+        let mut start = 0;
+        'outer: while start < stream.len() {
+            for end in start + 1..=stream.len() {
+                let candidate = &stream[start..end];
+                if let Some((name, _)) = set.iter().find(|(_, raw)| *raw == candidate) {
+                    fired.push(String::from(*name));
+                    start = end;
+                    continue 'outer;
+                }
+                if !set.iter().any(|(_, raw)| raw.starts_with(candidate)) {
+                    delivered.extend_from_slice(candidate);
+                    start = end;
+                    continue 'outer;
+                }
+            }
+            return (delivered, stream[start..].to_vec(), fired);
+        }
+        (delivered, vec![], fired)
+    }
+
+    #[test]
+    fn input_with_bindings_snips_them() {
+        let pieces: Vec<&[u8]> = PIECES.iter().filter(|p| !p.contains(&0x1b)).copied().collect();
+        let mut rng = Rng(0xb1_4d_1a_65);
+        for set in BINDING_SETS.iter() {
+            for _ in 0..2000 {
+                let stream = rng.stream(&pieces);
+                let got = scan_chunked(&mut rng, set, false, &stream);
+                assert_eq!(
+                    got,
+                    expected_scan(set, &stream),
+                    "bindings={:?} stream={:?}",
+                    set,
+                    stream
+                );
+            }
+        }
+
+        // a keybinding right after some other input in the same chunk
+        let bindings =
+            keybindings::Bindings::new(vec![("Ctrl-a", keybindings::Action::NoOp)]).unwrap();
+        let mut scanner = InputScanner::new(bindings);
+        let mut buf = b"x\x01".to_vec();
+        let mut delivered = vec![];
+        let mut fired = vec![];
+        scanner
+            .scan(
+                &mut buf,
+                2,
+                |input| {
+                    delivered.extend_from_slice(input);
+                    Ok(())
+                },
+                |action| {
+                    fired.push(action);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(delivered, b"x");
+        assert_eq!(fired, vec![keybindings::Action::NoOp]);
+    }
 }
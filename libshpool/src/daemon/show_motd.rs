@@ -13,7 +13,6 @@
 // limitations under the License.
 
 use std::{
-    io,
     os::unix::net::UnixStream,
     sync::{Arc, Mutex},
 };
@@ -24,7 +23,7 @@ use tracing::info;
 use crate::{
     config,
     daemon::pager::{Pager, PagerCtl},
-    protocol, tty,
+    tty,
 };
 
 /// Showers know how to show the message of the day.
@@ -45,19 +44,11 @@ impl DailyMessenger {
         })
     }
 
-    pub fn dump<W: io::Write>(
-        &self,
-        mut stream: W,
-        term_db: &termini::TermInfo,
-    ) -> anyhow::Result<()> {
+    /// The motd, ready to be sent to the client as output.
+    pub fn dump(&self, term_db: &termini::TermInfo) -> anyhow::Result<Vec<u8>> {
         assert!(matches!(self.mode, config::MotdDisplayMode::Dump));
 
-        let raw_motd_value = self.raw_motd_value(term_db)?;
-
-        let chunk =
-            protocol::Chunk { kind: protocol::ChunkKind::Data, buf: raw_motd_value.as_slice() };
-
-        chunk.write_to(&mut stream).context("dumping motd")
+        self.raw_motd_value(term_db).context("dumping motd")
     }
 
     /// Display the motd in a pager. Callers should do a downcast error
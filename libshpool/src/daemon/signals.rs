@@ -13,7 +13,6 @@
 // limitations under the License.
 
 use std::{
-    path::PathBuf,
     sync::{atomic::AtomicBool, Arc},
     thread,
 };
@@ -22,12 +21,14 @@ use anyhow::Context;
 use signal_hook::{consts::TERM_SIGNALS, flag, iterator::Signals};
 use tracing::{error, info};
 
+use super::server::Server;
+
 pub struct Handler {
-    sock: Option<PathBuf>,
+    server: Arc<Server>,
 }
 impl Handler {
-    pub fn new(sock: Option<PathBuf>) -> Self {
-        Handler { sock }
+    pub fn new(server: Arc<Server>) -> Self {
+        Handler { server }
     }
 
     pub fn spawn(self) -> anyhow::Result<()> {
@@ -57,11 +58,10 @@ impl Handler {
             for signal in &mut signals {
                 assert!(TERM_SIGNALS.contains(&signal));
 
-                info!("term sig handler: cleaning up socket");
-                if let Some(sock) = self.sock {
-                    if let Err(e) = std::fs::remove_file(sock).context("cleaning up socket") {
-                        error!("error cleaning up socket file: {}", e);
-                    }
+                info!("term sig handler: shutting down");
+                if let Err(e) = self.server.shutdown(false, "the daemon got a signal to shut down")
+                {
+                    error!("error shutting down: {:?}", e);
                 }
 
                 info!("term sig handler: exiting");
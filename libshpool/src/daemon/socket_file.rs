@@ -0,0 +1,282 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs, io,
+    os::unix::{
+        fs::MetadataExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    thread, time,
+};
+
+use anyhow::{anyhow, Context};
+use nix::{
+    sys::{
+        signal::{self, Signal},
+        socket,
+    },
+    unistd::Pid,
+};
+use tracing::{info, warn};
+
+use crate::protocol;
+
+/// How long to wait for a daemon that is already listening on the
+/// socket to say hello.
+const PROBE_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+/// How long to give a daemon that is being taken over to get out of
+/// the way.
+const TAKEOVER_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+const TAKEOVER_POLL_DUR: time::Duration = time::Duration::from_millis(50);
+
+/// Bind a listener at the given path. A socket file that nobody is
+/// listening on any more, left behind by a daemon that didn't get to
+/// clean up after itself, gets replaced. A daemon that is still
+/// listening gets asked to step aside if takeover is set, and is
+/// otherwise left alone.
+pub fn bind(path: &Path, takeover: bool) -> anyhow::Result<(SocketFile, UnixListener)> {
+    let listener = match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            match probe(path)? {
+                Probe::Stale => warn!("replacing stale socket {:?}", path),
+                Probe::Live(daemon) if takeover => {
+                    take_over(path, &daemon).context("taking over from running daemon")?
+                }
+                Probe::Live(daemon) => {
+                    let hint = if daemon.older {
+                        ", it is older than this one, so restart with --takeover to replace it"
+                    } else {
+                        ""
+                    };
+                    return Err(anyhow!(
+                        "a daemon{} is already listening on {}{}",
+                        daemon.pid.map(|p| format!(" (pid={})", p)).unwrap_or_default(),
+                        path.display(),
+                        hint
+                    ));
+                }
+            }
+            match fs::remove_file(path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).context("removing old socket"),
+            }
+            UnixListener::bind(path)?
+        }
+        res => res?,
+    };
+    Ok((SocketFile::new(path)?, listener))
+}
+
+/// What is on the other end of a socket file that is already there.
+enum Probe {
+    /// Nobody is listening.
+    Stale,
+    Live(LiveDaemon),
+}
+
+struct LiveDaemon {
+    pid: Option<Pid>,
+    /// The daemon speaks an older protocol or lacks some capabilities.
+    older: bool,
+    /// The daemon understands `shpool daemon stop`.
+    can_stop: bool,
+}
+
+fn probe(path: &Path) -> anyhow::Result<Probe> {
+    let client = match protocol::Client::with_timeout(path, Some(PROBE_TIMEOUT)) {
+        Ok(client) => client,
+        Err(err) => {
+            let refused = err
+                .downcast_ref::<io::Error>()
+                .map(|e| e.kind() == io::ErrorKind::ConnectionRefused)
+                .unwrap_or(false);
+            if refused {
+                return Ok(Probe::Stale);
+            }
+
+            // Something is listening, but it is not a daemon we can
+            // talk to.
+            warn!("handshake with running daemon failed: {:?}", err);
+            let older = matches!(
+                err.downcast_ref::<protocol::HandshakeError>(),
+                Some(protocol::HandshakeError::VersionMismatch { daemon_version, .. })
+                    if *daemon_version < protocol::VERSION
+            );
+            let pid = UnixStream::connect(path).ok().and_then(|s| peer_pid(&s));
+            return Ok(Probe::Live(LiveDaemon { pid, older, can_stop: false }));
+        }
+    };
+
+    let has = |capability: &str| client.capabilities().iter().any(|c| c == capability);
+    Ok(Probe::Live(LiveDaemon {
+        pid: peer_pid(&client.stream),
+        older: !protocol::CAPABILITIES.iter().all(|c| has(c)),
+        can_stop: has("stop"),
+    }))
+}
+
+fn peer_pid(stream: &UnixStream) -> Option<Pid> {
+    socket::getsockopt(stream, socket::sockopt::PeerCredentials)
+        .ok()
+        .map(|creds| Pid::from_raw(creds.pid()))
+}
+
+/// Get the running daemon to shut down, handing its sessions over to us
+/// if it knows how, and wait for it to let go of the socket.
+fn take_over(path: &Path, daemon: &LiveDaemon) -> anyhow::Result<()> {
+    if daemon.can_stop {
+        info!("asking running daemon to stop and hand over its sessions");
+        let mut client = protocol::Client::with_timeout(path, Some(TAKEOVER_TIMEOUT))
+            .context("connecting to running daemon")?;
+        client
+            .write_connect_header(protocol::ConnectHeader::Stop(protocol::StopRequest {
+                keep_sessions: true,
+            }))
+            .context("writing stop request header")?;
+        match client.read_reply().context("reading stop reply")? {
+            protocol::StopReply::Stopped { held } => {
+                info!("running daemon stopped, handing over {} sessions", held.len())
+            }
+            protocol::StopReply::Failed(reason) => {
+                return Err(anyhow!("running daemon could not stop: {}", reason));
+            }
+        }
+    } else if let Some(pid) = daemon.pid {
+        // Daemons from before `shpool daemon stop` still clean up on
+        // SIGTERM, though their sessions go down with them.
+        warn!("running daemon can't hand over its sessions, sending it SIGTERM");
+        signal::kill(pid, Signal::SIGTERM).context("signaling running daemon")?;
+    } else {
+        return Err(anyhow!("could not work out how to stop the running daemon"));
+    }
+
+    let deadline = time::Instant::now() + TAKEOVER_TIMEOUT;
+    loop {
+        match UnixStream::connect(path) {
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    || e.kind() == io::ErrorKind::ConnectionRefused =>
+            {
+                return Ok(());
+            }
+            _ if time::Instant::now() > deadline => {
+                return Err(anyhow!("timed out waiting for running daemon to exit"));
+            }
+            _ => thread::sleep(TAKEOVER_POLL_DUR),
+        }
+    }
+}
+
+/// The socket file the daemon is listening on, which it needs to clean
+/// up on the way out. By the time the daemon gets around to that, some
+/// other daemon might have replaced the file with its own socket, so
+/// this remembers which file it bound in order to leave any other one
+/// alone.
+#[derive(Debug)]
+pub struct SocketFile {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+}
+
+impl SocketFile {
+    /// Remember the socket file that was just bound at the given path.
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let meta = fs::symlink_metadata(path).context("statting socket")?;
+        Ok(SocketFile { path: PathBuf::from(path), dev: meta.dev(), ino: meta.ino() })
+    }
+
+    /// Remove the socket file if it is still the one we bound.
+    pub fn remove(&self) -> anyhow::Result<()> {
+        // Rename the file out of the way first so that it can be checked
+        // without racing against someone binding a new socket at the path.
+        let mut doomed = self.path.as_os_str().to_owned();
+        doomed.push(format!(".{}.doomed", std::process::id()));
+        match fs::rename(&self.path, &doomed) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("socket already gone");
+                return Ok(());
+            }
+            Err(e) => return Err(e).context("moving socket out of the way"),
+        }
+
+        let meta = fs::symlink_metadata(&doomed).context("statting socket")?;
+        if meta.dev() == self.dev && meta.ino() == self.ino {
+            fs::remove_file(&doomed).context("removing socket")?;
+            info!("removed socket {:?}", self.path);
+        } else {
+            warn!("socket {:?} belongs to someone else now, leaving it be", self.path);
+            // put it back, unless yet another socket showed up meanwhile
+            if let Err(e) = fs::hard_link(&doomed, &self.path) {
+                warn!("could not put socket back: {:?}", e);
+            }
+            fs::remove_file(&doomed).context("cleaning up moved socket")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn bind_replaces_stale() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-socket-file")?;
+        let path = tmp_dir.path().join("shpool.socket");
+
+        // dropping the listener leaves the file behind
+        drop(UnixListener::bind(&path)?);
+        let (socket_file, _listener) = bind(&path, false)?;
+        assert!(UnixStream::connect(&path).is_ok());
+
+        // but a live socket is left alone
+        assert!(bind(&path, false).is_err());
+        socket_file.remove()?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn leaves_other_sockets() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-socket-file")?;
+        let path = tmp_dir.path().join("shpool.socket");
+
+        let _ours = UnixListener::bind(&path)?;
+        let socket_file = SocketFile::new(&path)?;
+        fs::remove_file(&path)?;
+        let _theirs = UnixListener::bind(&path)?;
+
+        socket_file.remove()?;
+        assert!(path.exists());
+        assert_eq!(fs::read_dir(tmp_dir.path())?.count(), 1);
+
+        let socket_file = SocketFile::new(&path)?;
+        socket_file.remove()?;
+        assert!(!path.exists());
+        // a second remove is fine
+        socket_file.remove()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Spilling old scrollback out of the output spool and onto disk.
+
+  Every line of scrollback in the output spool is a full width vt100
+  row, so a lot of chatty sessions with deep scrollback add up to a lot
+  of daemon memory. With `scrollback_memory_lines` set, once the spool
+  has that many lines of scrollback, they get moved out to a ring file
+  under $XDG_STATE_HOME/shpool/scrollback as plain text, and the spool
+  starts over with just what is on the screen. The ring file holds the
+  rest of the `output_spool_lines` budget, throwing away the oldest
+  lines to make room for new ones, and copy mode reads it back so that
+  the spilled lines are still there to page through.
+
+  vt100 has no way to drop just the scrollback, so starting the spool
+  over means feeding a fresh one the state of the screen. That keeps
+  what is on the screen along with the cursor and the current
+  attributes, but things like a scroll region the shell set up are
+  lost, which is why spilling waits until the alternate screen is not
+  in use.
+*/
+
+use std::{
+    collections::VecDeque,
+    fs,
+    os::unix::fs::{DirBuilderExt, FileExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    time,
+};
+
+use anyhow::Context;
+use tracing::{info, warn};
+
+use super::copy_mode;
+use crate::consts;
+
+/// The room the ring file gets per line it is meant to hold. Most lines
+/// are much shorter than this, and a few long ones just mean a few less
+/// lines get kept.
+const LINE_BYTES: u64 = 256;
+
+/// The smallest the ring file gets, which leaves room for the longest
+/// lines a shell is likely to print.
+const MIN_RING_BYTES: u64 = 1024 * 1024;
+
+/// The most lines the spool gets to keep in memory. Reading the
+/// scrollback back out of vt100 only goes so far back, and this is a
+/// lot of memory already.
+const MAX_MEMORY_LINES: usize = 32 * 1024;
+
+pub struct Spill {
+    ring: Ring,
+    /// How many lines of scrollback the spool gets to keep in memory.
+    memory_lines: usize,
+}
+
+impl Spill {
+    /// Set up a ring file for the given session in the state dir that
+    /// holds up to disk_lines lines.
+    pub fn create(
+        state_dir: &Path,
+        session_name: &str,
+        memory_lines: usize,
+        disk_lines: usize,
+    ) -> anyhow::Result<Self> {
+        let dir = state_dir.join("scrollback");
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("creating scrollback dir {:?}", dir))?;
+        let started_at =
+            time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default();
+        let path = dir.join(format!("{}-{}.ring", session_name, started_at.as_millis()));
+        let capacity = (disk_lines as u64).saturating_mul(LINE_BYTES).max(MIN_RING_BYTES);
+        info!("spilling scrollback past {} lines to {:?}", memory_lines, path);
+        let memory_lines = memory_lines.min(MAX_MEMORY_LINES);
+        Ok(Spill { ring: Ring::create(path, capacity, disk_lines)?, memory_lines })
+    }
+
+    /// How many lines of scrollback the output spool should be able to
+    /// hold. Spilling only happens between chunks of output, so the
+    /// spool needs room for a chunk's worth of lines past the budget,
+    /// or vt100 would throw some of them away before they could be
+    /// spilled.
+    pub fn spool_lines(&self) -> usize {
+        self.memory_lines + consts::BUF_SIZE
+    }
+
+    /// Move the spool's scrollback out to disk, if it has grown to the
+    /// in-memory budget. This is meant to be called after every chunk
+    /// of output the spool takes in.
+    pub fn after_output(&mut self, spool: &mut shpool_vt100::Parser) -> anyhow::Result<()> {
+        let screen = spool.screen_mut();
+        if screen.alternate_screen() {
+            return Ok(());
+        }
+        screen.set_scrollback(usize::MAX);
+        let len = screen.scrollback();
+        screen.set_scrollback(0);
+        if len == 0 || len < self.memory_lines {
+            return Ok(());
+        }
+
+        // every row of the scrollback comes before the rows of the screen
+        let mut lines = copy_mode::rows(spool.screen());
+        lines.resize(len, String::new());
+        for line in lines.iter() {
+            self.ring.push(line).context("spilling scrollback")?;
+        }
+
+        let (rows, cols) = spool.screen().size();
+        let state = spool.screen().state_formatted();
+        *spool = shpool_vt100::Parser::new(rows, cols, self.spool_lines());
+        spool.process(&state);
+        Ok(())
+    }
+
+    /// The lines that have been spilled, oldest first.
+    pub fn lines(&self) -> anyhow::Result<Vec<String>> {
+        self.ring.lines()
+    }
+
+    /// Throw away everything that has been spilled.
+    pub fn clear(&mut self) {
+        self.ring.clear();
+    }
+}
+
+/// A file of lines that wraps around once it fills up, overwriting the
+/// oldest lines. Where each line lives is kept in memory, which is a
+/// lot smaller than the lines themselves.
+struct Ring {
+    file: fs::File,
+    path: PathBuf,
+    capacity: u64,
+    max_lines: usize,
+    /// Where the next line goes.
+    head: u64,
+    /// How many bytes the lines in the file take up, right up to head.
+    used: u64,
+    /// The offset and length of each line in the file, oldest first.
+    index: VecDeque<(u64, u64)>,
+}
+
+impl Ring {
+    fn create(path: PathBuf, capacity: u64, max_lines: usize) -> anyhow::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("creating scrollback file {:?}", path))?;
+        Ok(Ring { file, path, capacity, max_lines, head: 0, used: 0, index: VecDeque::new() })
+    }
+
+    fn push(&mut self, line: &str) -> anyhow::Result<()> {
+        if self.max_lines == 0 {
+            return Ok(());
+        }
+        let mut end = line.len().min(self.capacity as usize);
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        let buf = &line.as_bytes()[..end];
+        let len = buf.len() as u64;
+
+        while self.index.len() >= self.max_lines || self.used + len > self.capacity {
+            match self.index.pop_front() {
+                Some((_, old_len)) => self.used -= old_len,
+                None => break,
+            }
+        }
+
+        let start = self.head;
+        let first = (self.capacity - start).min(len) as usize;
+        self.file.write_all_at(&buf[..first], start).context("writing line")?;
+        self.file.write_all_at(&buf[first..], 0).context("writing wrapped line")?;
+        self.head = (start + len) % self.capacity;
+        self.used += len;
+        self.index.push_back((start, len));
+        Ok(())
+    }
+
+    fn lines(&self) -> anyhow::Result<Vec<String>> {
+        let mut lines = Vec::with_capacity(self.index.len());
+        for (start, len) in self.index.iter() {
+            let mut buf = vec![0; *len as usize];
+            let first = (self.capacity - start).min(*len) as usize;
+            self.file.read_exact_at(&mut buf[..first], *start).context("reading line")?;
+            self.file.read_exact_at(&mut buf[first..], 0).context("reading wrapped line")?;
+            lines.push(String::from_utf8_lossy(&buf).into_owned());
+        }
+        Ok(lines)
+    }
+
+    fn clear(&mut self) {
+        self.index.clear();
+        self.head = 0;
+        self.used = 0;
+        if let Err(e) = self.file.set_len(0) {
+            warn!("truncating scrollback file {:?}: {:?}", self.path, e);
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("removing scrollback file {:?}: {:?}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn ring_wraps() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut ring = Ring::create(dir.path().join("ring"), 16, 3)?;
+        ring.push("one")?;
+        ring.push("two")?;
+        assert_eq!(ring.lines()?, vec!["one", "two"]);
+
+        // too many lines
+        ring.push("three")?;
+        ring.push("four")?;
+        assert_eq!(ring.lines()?, vec!["two", "three", "four"]);
+
+        // too many bytes, with the newest line wrapping around the end
+        ring.push("fivefive")?;
+        assert_eq!(ring.lines()?, vec!["four", "fivefive"]);
+
+        ring.clear();
+        assert!(ring.lines()?.is_empty());
+        ring.push("six")?;
+        assert_eq!(ring.lines()?, vec!["six"]);
+
+        let path = ring.path.clone();
+        drop(ring);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn spills() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut spill = Spill::create(dir.path(), "sess", 10, 100)?;
+        let mut spool = shpool_vt100::Parser::new(5, 80, spill.spool_lines());
+        spool.process(b"\x1b[1m");
+        for i in 0..40 {
+            spool.process(format!("line {}\r\n", i).as_bytes());
+            spill.after_output(&mut spool)?;
+        }
+        spool.process(b"$ ");
+
+        let spilled = spill.lines()?;
+        assert_eq!(spilled.len(), 30);
+        assert_eq!(spilled[0], "line 0");
+        assert_eq!(spilled[29], "line 29");
+
+        // the rest are still in memory, with the screen as it was
+        let screen = spool.screen();
+        let kept = copy_mode::rows(screen);
+        assert_eq!(kept.first().map(String::as_str), Some("line 30"));
+        assert_eq!(kept.last().map(String::as_str), Some("$"));
+        assert_eq!(screen.cursor_position(), (4, 2));
+        assert!(screen.bold());
+
+        spill.clear();
+        assert!(spill.lines()?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Per-session status files, which let scripts running inside a session
+  (via $SHPOOL_SESSION_STATUS_FILE) and other tools see the state of the
+  session without speaking the control protocol. The file is JSON and
+  gets replaced atomically, so readers never see a partial update.
+*/
+
+use std::{fs, io, path::PathBuf, time};
+
+use anyhow::Context;
+use serde_derive::Serialize;
+use tracing::warn;
+
+use crate::tty;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub name: String,
+    /// Bumped on every update, so readers can cheaply tell
+    /// whether anything changed.
+    pub generation: u64,
+    pub started_at_unix_ms: i64,
+    /// The TERM the session was started with.
+    pub term: Option<String>,
+    pub attached: bool,
+    /// The size of the most recently attached terminal.
+    pub tty_size: Option<tty::Size>,
+    /// Set while a client is attached.
+    pub client: Option<Client>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Client {
+    /// The pid of the `shpool attach` process, if known.
+    pub pid: Option<i32>,
+    pub term: Option<String>,
+}
+
+/// StatusFile owns the status file of a single session. It rewrites the
+/// file on every update and removes it when the session goes away.
+#[derive(Debug)]
+pub struct StatusFile {
+    path: PathBuf,
+    status: Status,
+}
+
+impl StatusFile {
+    pub fn new(
+        path: PathBuf,
+        name: &str,
+        term: Option<&str>,
+        started_at: time::SystemTime,
+    ) -> Self {
+        let started_at_unix_ms = started_at
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default();
+        StatusFile {
+            path,
+            status: Status {
+                name: String::from(name),
+                generation: 0,
+                started_at_unix_ms,
+                term: term.map(String::from),
+                attached: false,
+                tty_size: None,
+                client: None,
+            },
+        }
+    }
+
+    /// The current status, as last written out.
+    pub fn status(&self) -> Status {
+        self.status.clone()
+    }
+
+    /// Apply a change to the status and write it out. Failing to write
+    /// the file is not worth disrupting the session over, so errors
+    /// just get logged.
+    pub fn update<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Status),
+    {
+        f(&mut self.status);
+        self.status.generation += 1;
+        if let Err(e) = self.write() {
+            warn!("writing status file {:?}: {:?}", self.path, e);
+        }
+    }
+
+    fn write(&self) -> anyhow::Result<()> {
+        let mut buf = serde_json::to_vec_pretty(&self.status).context("encoding status")?;
+        buf.push(b'\n');
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, buf).context("writing tmp status file")?;
+        fs::rename(&tmp_path, &self.path).context("moving status file into place")?;
+        Ok(())
+    }
+}
+
+impl std::ops::Drop for StatusFile {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("removing status file {:?}: {:?}", self.path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn lifecycle() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("sh1.json");
+
+        let mut status_file =
+            StatusFile::new(path.clone(), "sh1", Some("xterm"), time::SystemTime::now());
+        status_file.update(|s| {
+            s.attached = true;
+            s.tty_size = Some(tty::Size { rows: 24, cols: 80, xpixel: 0, ypixel: 0 });
+        });
+        let status: serde_json::Value = serde_json::from_slice(&fs::read(&path)?)?;
+        assert_eq!(status["name"], "sh1");
+        assert_eq!(status["term"], "xterm");
+        assert_eq!(status["generation"], 1);
+        assert_eq!(status["attached"], true);
+        assert_eq!(status["tty_size"]["cols"], 80);
+
+        status_file.update(|s| s.attached = false);
+        let status: serde_json::Value = serde_json::from_slice(&fs::read(&path)?)?;
+        assert_eq!(status["generation"], 2);
+        assert_eq!(status["attached"], false);
+
+        drop(status_file);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,427 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! A status line along the bottom row of the client's terminal.
+
+  While the status line is up, the shell gets a pty one row shorter
+  than the terminal, and the terminal's scroll region (set with DECSTBM)
+  is kept to the rows above the status line so that output scrolling
+  by never runs over it. Programs set the scroll region themselves,
+  often to "the whole screen", which on the client's terminal would
+  take in the status line, so `StatusLine` rewrites the sequences that
+  set it on their way to the client. It also notices the sequences that
+  wipe out the bottom row, like clearing the screen or switching to
+  the alternate screen, so that the status line can be drawn again.
+
+  Drawing moves the cursor and changes the drawing attributes, so the
+  output also goes through a terminal emulator of its own, which knows
+  what to put back afterwards.
+*/
+
+use std::{io::Write, time};
+
+use crate::tty;
+
+/// Escape sequences longer than this are not anything we care about,
+/// so they get passed along rather than held back any longer.
+const MAX_SEQ_LEN: usize = 64;
+
+const ESC: u8 = 0x1b;
+/// CAN and SUB, which abort an escape sequence.
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+
+/// The size of the pty the shell gets while the status line is up.
+pub fn shell_size(size: &tty::Size) -> tty::Size {
+    tty::Size {
+        rows: size.rows.saturating_sub(1).max(1),
+        cols: size.cols,
+        xpixel: size.xpixel,
+        ypixel: size.ypixel,
+    }
+}
+
+/// What the status line says: the session name, which attach this is,
+/// the given flags and the time, squeezed into the given width.
+pub fn text(name: &str, attaches: u64, flags: &[String], clock: &str, cols: u16) -> String {
+    let mut left = format!(" {} | attach #{}", name, attaches);
+    for flag in flags.iter() {
+        left.push_str(" | ");
+        left.push_str(flag);
+    }
+    let right = format!("{} ", clock);
+
+    let cols = cols as usize;
+    let left_len = left.chars().count();
+    let right_len = right.chars().count();
+    if left_len + 1 + right_len > cols {
+        // the clock is the first thing to go
+        return left.chars().take(cols).collect();
+    }
+    format!("{}{}{}", left, " ".repeat(cols - left_len - right_len), right)
+}
+
+/// The flag for a session that has been quiet for a while, if it has.
+pub fn idle_flag(idle: time::Duration) -> Option<String> {
+    let mins = idle.as_secs() / 60;
+    match mins {
+        0 => None,
+        1..=59 => Some(format!("idle {}m", mins)),
+        _ => Some(format!("idle {}h", mins / 60)),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum ScanState {
+    Ground,
+    /// Just saw an ESC.
+    Escape,
+    /// In a CSI sequence.
+    Csi,
+}
+
+pub struct StatusLine {
+    /// The size of the client's terminal.
+    size: tty::Size,
+    /// Follows along with the part of the terminal the shell has.
+    emulator: shpool_vt100::Parser,
+    state: ScanState,
+    /// The escape sequence being scanned, held back from the client
+    /// until it is complete so that it can be rewritten.
+    seq: Vec<u8>,
+    /// The scroll region the shell asked for, kept clear of the status
+    /// line, as one based (top, bottom) rows.
+    margins: (u16, u16),
+    /// What the status line says on the client's terminal, if it is
+    /// there at all.
+    drawn: Option<String>,
+}
+
+impl StatusLine {
+    /// A status line for a terminal of the given size, which should
+    /// be blank with the cursor in the top left corner (or be about to
+    /// be redrawn from scratch, see `reset`).
+    pub fn new(size: &tty::Size) -> Self {
+        let shell = shell_size(size);
+        StatusLine {
+            size: size.clone(),
+            emulator: shpool_vt100::Parser::new(shell.rows, shell.cols, 0),
+            state: ScanState::Ground,
+            seq: vec![],
+            margins: (1, shell.rows),
+            drawn: None,
+        }
+    }
+
+    pub fn resize(&mut self, size: &tty::Size) {
+        let shell = shell_size(size);
+        self.size = size.clone();
+        self.emulator.screen_mut().set_size(shell.rows, shell.cols);
+        // terminals forget the scroll region when they get resized
+        self.margins = (1, shell.rows);
+        self.drawn = None;
+    }
+
+    /// Rewrite a chunk of shell output on its way to the client, using
+    /// `scratch` as the backing storage for the result if needed. An
+    /// unfinished escape sequence at the end gets held back until the
+    /// rest of it shows up.
+    pub fn filter<'a>(&mut self, input: &'a [u8], scratch: &'a mut Vec<u8>) -> &'a [u8] {
+        scratch.clear();
+        if self.state == ScanState::Ground && memchr::memchr(ESC, input).is_none() {
+            self.emulator.process(input);
+            return input;
+        }
+
+        for &byte in input.iter() {
+            match self.state {
+                ScanState::Ground if byte == ESC => {
+                    self.seq.push(byte);
+                    self.state = ScanState::Escape;
+                }
+                ScanState::Ground => scratch.push(byte),
+                ScanState::Escape => {
+                    self.seq.push(byte);
+                    match byte {
+                        b'[' => self.state = ScanState::Csi,
+                        // An ESC right after an ESC starts over.
+                        ESC => {
+                            scratch.push(ESC);
+                            self.seq = vec![ESC];
+                        }
+                        b'c' => {
+                            // RIS resets the scroll region along with
+                            // everything else, and homes the cursor, so
+                            // setting it right after changes nothing else.
+                            scratch.append(&mut self.seq);
+                            self.margins = (1, self.shell_rows());
+                            write!(scratch, "\x1b[1;{}r", self.shell_rows()).unwrap();
+                            self.drawn = None;
+                            self.state = ScanState::Ground;
+                        }
+                        _ => {
+                            scratch.append(&mut self.seq);
+                            self.state = ScanState::Ground;
+                        }
+                    }
+                }
+                ScanState::Csi => match byte {
+                    ESC => {
+                        scratch.append(&mut self.seq);
+                        self.seq.push(byte);
+                        self.state = ScanState::Escape;
+                    }
+                    CAN | SUB => {
+                        scratch.append(&mut self.seq);
+                        scratch.push(byte);
+                        self.state = ScanState::Ground;
+                    }
+                    0x40..=0x7e => {
+                        self.seq.push(byte);
+                        self.end_csi(scratch);
+                        self.state = ScanState::Ground;
+                    }
+                    _ => {
+                        self.seq.push(byte);
+                        if self.seq.len() > MAX_SEQ_LEN {
+                            scratch.append(&mut self.seq);
+                            self.state = ScanState::Ground;
+                        }
+                    }
+                },
+            }
+        }
+
+        self.emulator.process(scratch);
+        scratch
+    }
+
+    /// Handle the complete CSI sequence in `self.seq`.
+    fn end_csi(&mut self, out: &mut Vec<u8>) {
+        let seq = std::mem::take(&mut self.seq);
+        let body = &seq[2..seq.len() - 1];
+        let final_byte = seq[seq.len() - 1];
+        let private = body.first().copied().filter(|b| (b'<'..=b'?').contains(b));
+        let intermediates: Vec<u8> =
+            body.iter().copied().filter(|b| (0x20..=0x2f).contains(b)).collect();
+        let params = if private.is_some() { &body[1..] } else { body };
+        let params: Vec<u16> = params
+            .split(|b| *b == b';')
+            .map(|p| std::str::from_utf8(p).ok().and_then(|p| p.parse().ok()).unwrap_or(0))
+            .collect();
+
+        match (private, intermediates.as_slice(), final_byte) {
+            // DECSTBM, which gets pulled up off of the status line
+            (None, [], b'r') => {
+                let shell_rows = self.shell_rows();
+                let top = params.first().copied().filter(|t| *t > 0).unwrap_or(1);
+                let bottom =
+                    params.get(1).copied().filter(|b| *b > 0).unwrap_or(shell_rows).min(shell_rows);
+                if top < bottom {
+                    self.margins = (top, bottom);
+                    write!(out, "\x1b[{};{}r", top, bottom).unwrap();
+                    return;
+                }
+            }
+            // DECSTR, which resets the scroll region but leaves the
+            // cursor where it is
+            (None, [b'!'], b'p') => {
+                out.extend(&seq);
+                self.margins = (1, self.shell_rows());
+                write!(out, "\x1b7\x1b[1;{}r\x1b8", self.shell_rows()).unwrap();
+                return;
+            }
+            // erasing the display, which may take the status line with it
+            (_, [], b'J') => self.drawn = None,
+            // switching between the main and alternate screens
+            (Some(b'?'), [], b'h' | b'l')
+                if params.iter().any(|p| matches!(p, 47 | 1047 | 1049)) =>
+            {
+                self.drawn = None
+            }
+            _ => {}
+        }
+        out.extend(&seq);
+    }
+
+    /// Follow along with output of our own making sent to the client.
+    pub fn track(&mut self, buf: &[u8]) {
+        self.emulator.process(buf);
+    }
+
+    /// Follow along with output of our own making that put the client's
+    /// terminal back to its default modes, scroll region included.
+    pub fn reset(&mut self, buf: &[u8]) {
+        self.track(buf);
+        self.margins = (1, self.shell_rows());
+        self.drawn = None;
+    }
+
+    /// Note that something of our own making covered up the status line.
+    pub fn invalidate(&mut self) {
+        self.drawn = None;
+    }
+
+    /// Whether the status line has to be drawn to say the given text.
+    /// Drawing in the middle of an escape sequence would garble it, so
+    /// this waits for the end of any sequence held back.
+    pub fn needs_draw(&self, text: &str) -> bool {
+        self.state == ScanState::Ground && self.drawn.as_deref() != Some(text)
+    }
+
+    /// Whether something covered up the status line.
+    pub fn is_stale(&self) -> bool {
+        self.drawn.is_none()
+    }
+
+    /// The bytes that draw the status line with the given text, then
+    /// put the cursor and drawing attributes back how the shell left
+    /// them.
+    pub fn draw(&mut self, text: &str) -> Vec<u8> {
+        let (top, bottom) = self.margins;
+        let mut buf = vec![];
+        write!(buf, "\x1b[?25l\x1b[{};{}r\x1b[{};1H\x1b[0;7m", top, bottom, self.size.rows)
+            .unwrap();
+        buf.extend(text.chars().take(self.size.cols as usize).collect::<String>().as_bytes());
+        buf.extend(b"\x1b[0m");
+        self.restore_cursor(&mut buf);
+        self.drawn = Some(String::from(text));
+        buf
+    }
+
+    /// The bytes that take the status line down, giving the whole
+    /// terminal back to the shell.
+    pub fn take_down(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        write!(buf, "\x1b[?25l\x1b[r\x1b[{};1H\x1b[0m\x1b[2K", self.size.rows).unwrap();
+        self.restore_cursor(&mut buf);
+        buf
+    }
+
+    fn restore_cursor(&self, buf: &mut Vec<u8>) {
+        let screen = self.emulator.screen();
+        // the cursor state goes first, since it can mess with the
+        // drawing attributes
+        buf.extend(screen.cursor_state_formatted());
+        buf.extend(screen.attributes_formatted());
+    }
+
+    fn shell_rows(&self) -> u16 {
+        shell_size(&self.size).rows
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    fn size(rows: u16, cols: u16) -> tty::Size {
+        tty::Size { rows, cols, xpixel: 0, ypixel: 0 }
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn rewrites_scroll_region() {
+        let cases: Vec<(&[u8], &[u8])> = vec![
+            (b"plain text", b"plain text"),
+            (b"\x1b[r", b"\x1b[1;23r"),
+            (b"a\x1b[5;rb", b"a\x1b[5;23rb"),
+            (b"\x1b[2;24r", b"\x1b[2;23r"),
+            (b"\x1b[2;10r", b"\x1b[2;10r"),
+            (b"\x1bc", b"\x1bc\x1b[1;23r"),
+            (b"\x1b[!p", b"\x1b[!p\x1b7\x1b[1;23r\x1b8"),
+            // other sequences ending in r are left alone
+            (b"\x1b[?1r\x1b[1 r", b"\x1b[?1r\x1b[1 r"),
+            (b"\x1b[31mred\x1b[0m", b"\x1b[31mred\x1b[0m"),
+            (b"\x1b]0;title\x07", b"\x1b]0;title\x07"),
+        ];
+        for (input, want) in cases {
+            let mut status_line = StatusLine::new(&size(24, 80));
+            let mut scratch = vec![];
+            let got = status_line.filter(input, &mut scratch);
+            assert_eq!(
+                got,
+                want,
+                "input={:?} got={:?}",
+                String::from_utf8_lossy(input),
+                String::from_utf8_lossy(got)
+            );
+        }
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn split_sequence() {
+        let input = b"x\x1b[r\x1b[2J$ ";
+        for split in 0..input.len() {
+            let mut status_line = StatusLine::new(&size(24, 80));
+            status_line.drawn = Some(String::from("status"));
+            let mut scratch = vec![];
+            let mut out = status_line.filter(&input[..split], &mut scratch).to_vec();
+            out.extend(status_line.filter(&input[split..], &mut scratch));
+            assert_eq!(out, b"x\x1b[1;23r\x1b[2J$ ", "split={}", split);
+            assert!(status_line.is_stale(), "split={}", split);
+        }
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn draw() -> anyhow::Result<()> {
+        let mut status_line = StatusLine::new(&size(24, 20));
+        let mut scratch = vec![];
+        status_line.filter(b"\x1b[1mhi\x1b[3;5H", &mut scratch);
+        assert!(status_line.needs_draw("status"));
+
+        let drawn = String::from_utf8(status_line.draw("status"))?;
+        assert!(drawn.starts_with("\x1b[?25l\x1b[1;23r\x1b[24;1H\x1b[0;7mstatus\x1b[0m"));
+        // back to where the shell left things
+        assert!(drawn.contains("\x1b[3;5H"), "drawn={:?}", drawn);
+        assert!(drawn.ends_with("\x1b[1m"), "drawn={:?}", drawn);
+        assert!(!status_line.needs_draw("status"));
+        assert!(status_line.needs_draw("status 2"));
+
+        // nothing gets drawn in the middle of a sequence
+        status_line.filter(b"\x1b[2", &mut scratch);
+        assert!(!status_line.needs_draw("status 2"));
+        status_line.filter(b"J", &mut scratch);
+        assert!(status_line.needs_draw("status"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn idle() {
+        assert_eq!(idle_flag(time::Duration::from_secs(59)), None);
+        assert_eq!(idle_flag(time::Duration::from_secs(61)), Some(String::from("idle 1m")));
+        assert_eq!(
+            idle_flag(time::Duration::from_secs(2 * 60 * 60 + 5)),
+            Some(String::from("idle 2h"))
+        );
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn layout() {
+        let flags = vec![String::from("rec")];
+        assert_eq!(
+            text("main", 3, &flags, "14:05", 40),
+            " main | attach #3 | rec           14:05 "
+        );
+        assert_eq!(text("main", 3, &flags, "14:05", 22), " main | attach #3 | re");
+        assert_eq!(shell_size(&size(24, 80)).rows, 23);
+        assert_eq!(shell_size(&size(1, 80)).rows, 1);
+    }
+}
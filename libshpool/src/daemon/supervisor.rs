@@ -0,0 +1,320 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Starting a session's command over when it exits, as configured by
+  the `restart_policy` config table.
+
+  Rather than the daemon swapping a fresh pty in under the attached
+  client, the command of a session with a restart policy runs under a
+  supervisor (`shpool daemon supervise`, which isn't meant to be run by
+  hand) that sits in the session's pty in its place. The supervisor
+  starts the command over in the same pty whenever the policy calls for
+  it, so clients stay attached right through a restart, and exits with
+  the command's status once the policy says to stop.
+
+  A SIGHUP or SIGTERM to the supervisor, like the one `shpool kill`
+  sends, gets passed along to the command and stops any more restarts.
+*/
+
+use std::{
+    io,
+    os::unix::process::{CommandExt, ExitStatusExt},
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use anyhow::{anyhow, Context};
+use nix::{
+    sys::signal::{self, SigSet, Signal},
+    unistd::{self, Pid},
+};
+use signal_hook::{consts::*, flag};
+use tracing::{info, warn};
+
+use crate::{config, duration};
+
+const DEFAULT_BACKOFF: time::Duration = time::Duration::from_secs(1);
+const DEFAULT_MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+
+/// How often to check on the command and for signals.
+const POLL_DUR: time::Duration = time::Duration::from_millis(50);
+
+/// The resolved restart policy for a single session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    /// Restart after the command exits successfully too, rather than
+    /// just when it fails.
+    pub always: bool,
+    pub backoff: time::Duration,
+    pub max_backoff: time::Duration,
+    pub max_restarts: Option<u32>,
+}
+
+/// Find the first restart_policy entry matching the given session
+/// name and resolve it. Returns None if no entry matches or the entry
+/// says never to restart. Bad durations fall back to the defaults, and
+/// `shpool doctor` points them out.
+pub fn resolve(entries: &[config::RestartPolicy], session_name: &str) -> Option<Policy> {
+    let entry = entries.iter().find(|e| matches(&e.session, session_name))?;
+    let parse = |src: &Option<String>, default| {
+        src.as_deref().and_then(|s| duration::parse(s).ok()).unwrap_or(default)
+    };
+    let always = match entry.restart {
+        config::Restart::Never => return None,
+        config::Restart::OnFailure => false,
+        config::Restart::Always => true,
+    };
+    let backoff = parse(&entry.backoff, DEFAULT_BACKOFF);
+    Some(Policy {
+        always,
+        backoff,
+        max_backoff: parse(&entry.max_backoff, DEFAULT_MAX_BACKOFF).max(backoff),
+        max_restarts: entry.max_restarts,
+    })
+}
+
+impl Policy {
+    /// Wrap the given command, which must not have anything but its
+    /// program and args set up yet, so that it runs under a supervisor.
+    pub fn wrap(
+        &self,
+        cmd: &process::Command,
+        arg0: Option<&str>,
+    ) -> anyhow::Result<process::Command> {
+        let exe = std::env::current_exe().context("finding the shpool binary")?;
+        let mut wrapped = process::Command::new(exe);
+        wrapped
+            .arg("daemon")
+            .arg("supervise")
+            .arg("--backoff-ms")
+            .arg(self.backoff.as_millis().to_string())
+            .arg("--max-backoff-ms")
+            .arg(self.max_backoff.as_millis().to_string());
+        if self.always {
+            wrapped.arg("--always");
+        }
+        if let Some(max_restarts) = self.max_restarts {
+            wrapped.arg("--max-restarts").arg(max_restarts.to_string());
+        }
+        if let Some(arg0) = arg0 {
+            wrapped.arg("--arg0").arg(arg0);
+        }
+        wrapped.arg("--").arg(cmd.get_program()).args(cmd.get_args());
+        Ok(wrapped)
+    }
+
+    /// Whether a command that exited with the given status should
+    /// get started over.
+    fn wants_restart(&self, status: process::ExitStatus) -> bool {
+        if self.always {
+            return true;
+        }
+        match status.signal() {
+            Some(sig) => ![SIGHUP, SIGINT, SIGTERM, SIGPIPE].contains(&sig),
+            None => !status.success(),
+        }
+    }
+
+    /// How long to wait before the next restart, given the last wait,
+    /// if the last exit was also restarted, and how long the command
+    /// stayed up this time.
+    fn next_delay(&self, last: Option<time::Duration>, uptime: time::Duration) -> time::Duration {
+        match last {
+            Some(last) if uptime < self.max_backoff => (last * 2).min(self.max_backoff),
+            _ => self.backoff,
+        }
+    }
+}
+
+/// Run the command with the given argv under the policy, exiting with
+/// the status of its last run.
+pub fn run(policy: Policy, arg0: Option<String>, argv: Vec<String>) -> anyhow::Result<()> {
+    if argv.is_empty() {
+        return Err(anyhow!("no command to supervise"));
+    }
+
+    // Handling a signal rather than ignoring it means that it goes
+    // back to the default for the command once it gets exec'd. A ^C
+    // is meant for the command, not us.
+    let stop = Arc::new(AtomicBool::new(false));
+    for sig in [SIGHUP, SIGTERM] {
+        flag::register(sig, Arc::clone(&stop)).context("registering stop signal handler")?;
+    }
+    let interrupted = Arc::new(AtomicBool::new(false));
+    for sig in [SIGINT, SIGQUIT] {
+        flag::register(sig, Arc::clone(&interrupted)).context("registering signal handler")?;
+    }
+
+    let mut restarts = 0;
+    let mut delay = None;
+    loop {
+        let mut cmd = process::Command::new(&argv[0]);
+        cmd.args(&argv[1..]);
+        if let Some(arg0) = &arg0 {
+            cmd.arg0(arg0);
+        }
+        let started_at = time::Instant::now();
+        let mut child = cmd.spawn().with_context(|| format!("spawning {:?}", argv[0]))?;
+        let pid = Pid::from_raw(child.id() as i32);
+        info!("started {:?} as {}", argv[0], pid);
+
+        let mut stopping = false;
+        let status = loop {
+            if let Some(status) = child.try_wait().context("waiting on command")? {
+                break status;
+            }
+            if !stopping && stop.load(Ordering::Relaxed) {
+                info!("passing stop signal along to {}", pid);
+                stopping = true;
+                if let Err(e) = signal::kill(pid, Signal::SIGHUP) {
+                    warn!("signaling command: {:?}", e);
+                }
+            }
+            thread::sleep(POLL_DUR);
+        };
+        let code = status.code().or(status.signal().map(|sig| 128 + sig)).unwrap_or(1);
+        info!("{:?} exited with {:?}", argv[0], status);
+
+        // a run that stayed up for a while starts the count over
+        let uptime = started_at.elapsed();
+        if uptime >= policy.max_backoff {
+            restarts = 0;
+        }
+        let out_of_restarts = policy.max_restarts.map(|max| restarts >= max).unwrap_or(false);
+        if stopping
+            || stop.load(Ordering::Relaxed)
+            || out_of_restarts
+            || !policy.wants_restart(status)
+        {
+            process::exit(code);
+        }
+
+        let next = policy.next_delay(delay, uptime);
+        restarts += 1;
+        delay = Some(next);
+        eprintln!(
+            "\r\nshpool: {} exited with status {}, restarting in {}",
+            argv[0],
+            code,
+            duration::format_coarse(next)
+        );
+
+        let wake_at = time::Instant::now() + next;
+        while time::Instant::now() < wake_at {
+            if stop.load(Ordering::Relaxed) {
+                process::exit(code);
+            }
+            thread::sleep(POLL_DUR);
+        }
+        reclaim_terminal();
+    }
+}
+
+/// Make our process group the foreground one again, since the command
+/// may have been a shell that put its own process group in charge of
+/// the terminal, which would leave the next run of the command unable
+/// to read from it.
+fn reclaim_terminal() {
+    // Changing the foreground process group from the background gets
+    // us a SIGTTOU unless it is blocked.
+    let mut ttou = SigSet::empty();
+    ttou.add(Signal::SIGTTOU);
+    if let Err(e) = ttou.thread_block() {
+        warn!("blocking SIGTTOU: {:?}", e);
+        return;
+    }
+    if let Err(e) = unistd::tcsetpgrp(io::stdin(), unistd::getpgrp()) {
+        warn!("taking back the terminal: {:?}", e);
+    }
+    if let Err(e) = ttou.thread_unblock() {
+        warn!("unblocking SIGTTOU: {:?}", e);
+    }
+}
+
+fn matches(pattern: &str, session_name: &str) -> bool {
+    if let Some(prefix) = pattern.strip_suffix('*') {
+        session_name.starts_with(prefix)
+    } else {
+        pattern == session_name
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    fn entry(session: &str, restart: config::Restart) -> config::RestartPolicy {
+        config::RestartPolicy {
+            session: String::from(session),
+            restart,
+            backoff: Some(String::from("2s")),
+            max_backoff: Some(String::from("5s")),
+            max_restarts: None,
+        }
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn resolve_entries() {
+        let entries = vec![
+            entry("dev-quiet", config::Restart::Never),
+            entry("dev*", config::Restart::OnFailure),
+            entry("job", config::Restart::Always),
+        ];
+        assert_eq!(resolve(&entries, "dev-quiet"), None);
+        assert_eq!(resolve(&entries, "main"), None);
+        assert_eq!(
+            resolve(&entries, "dev-server"),
+            Some(Policy {
+                always: false,
+                backoff: time::Duration::from_secs(2),
+                max_backoff: time::Duration::from_secs(5),
+                max_restarts: None,
+            })
+        );
+        assert!(resolve(&entries, "job").map(|p| p.always).unwrap_or(false));
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn restart_on_failure() {
+        let mut policy = resolve(&[entry("dev", config::Restart::OnFailure)], "dev").unwrap();
+        let exit = |code: i32| process::ExitStatus::from_raw(code << 8);
+        let killed = |sig: i32| process::ExitStatus::from_raw(sig);
+        assert!(!policy.wants_restart(exit(0)));
+        assert!(policy.wants_restart(exit(1)));
+        assert!(policy.wants_restart(killed(SIGSEGV)));
+        assert!(!policy.wants_restart(killed(SIGTERM)));
+
+        policy.always = true;
+        assert!(policy.wants_restart(exit(0)));
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn backoff() {
+        let policy = resolve(&[entry("dev", config::Restart::Always)], "dev").unwrap();
+        let secs = time::Duration::from_secs;
+        assert_eq!(policy.next_delay(None, secs(0)), secs(2));
+        assert_eq!(policy.next_delay(Some(secs(2)), secs(1)), secs(4));
+        assert_eq!(policy.next_delay(Some(secs(4)), secs(1)), secs(5));
+        // staying up for a while resets it
+        assert_eq!(policy.next_delay(Some(secs(5)), secs(10)), secs(2));
+    }
+}
@@ -14,7 +14,10 @@
 
 use std::{
     env,
-    os::unix::{io::FromRawFd, net::UnixListener},
+    os::unix::{
+        io::{FromRawFd, RawFd},
+        net::UnixListener,
+    },
 };
 
 use anyhow::{anyhow, Context};
@@ -44,3 +47,10 @@ pub fn activation_socket() -> anyhow::Result<UnixListener> {
     // Safety: we have just verified that this is a unix socket.
     unsafe { Ok(UnixListener::from_raw_fd(fd)) }
 }
+
+/// The fd systemd passed the activation socket in, if it passed one.
+/// It stays open across an in place restart, so the new image finds it
+/// just where the old one did.
+pub fn activation_socket_fd() -> Option<RawFd> {
+    env::var_os("LISTEN_FDS").map(|_| FIRST_ACTIVATION_SOCKET_FD)
+}
@@ -0,0 +1,114 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! The optional TCP listener configured with the `tcp_listener` config
+  table. Each connection must complete a mutual TLS handshake, after
+  which it gets relayed to the daemon's unix socket, where it is handled
+  like any other client.
+*/
+
+use std::{
+    net::{TcpListener, TcpStream},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+use anyhow::{anyhow, Context};
+use rustls::{server::WebPkiClientVerifier, ServerConfig, ServerConnection};
+use tracing::{error, info, instrument};
+
+use crate::{config, tls};
+
+/// Bind the listener and start accepting connections in the background.
+/// Errors in the config are reported right away so that the daemon
+/// fails to start rather than silently not listening.
+pub fn spawn(listener_config: &config::TcpListener, socket: PathBuf) -> anyhow::Result<()> {
+    let tls_config = server_config(listener_config).context("building tls config")?;
+    let pins = listener_config.pinned_client_certs.clone().unwrap_or_default();
+    let listener = TcpListener::bind(&listener_config.listen)
+        .with_context(|| format!("binding to {}", listener_config.listen))?;
+    info!("listening for tls connections on {}", listener_config.listen);
+
+    thread::Builder::new()
+        .name(String::from("tcp-listener"))
+        .spawn(move || {
+            for (conn_id, stream) in listener.incoming().enumerate() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("accepting tcp connection: {:?}", e);
+                        continue;
+                    }
+                };
+                let tls_config = Arc::clone(&tls_config);
+                let pins = pins.clone();
+                let socket = socket.clone();
+                if let Err(e) = thread::Builder::new()
+                    .name(format!("tcp-relay({})", conn_id))
+                    .spawn(move || {
+                        if let Err(e) = handle_conn(tls_config, &pins, stream, &socket, conn_id) {
+                            error!("handling tcp connection: {:?}", e);
+                        }
+                    })
+                {
+                    error!("spawning tcp relay thread: {:?}", e);
+                }
+            }
+        })
+        .map_err(|e| anyhow!("{:?}", e))?;
+
+    Ok(())
+}
+
+fn server_config(listener_config: &config::TcpListener) -> anyhow::Result<Arc<ServerConfig>> {
+    let roots = tls::load_roots(&listener_config.client_ca)?;
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .build()
+        .context("building client certificate verifier")?;
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(
+            tls::load_certs(&listener_config.cert)?,
+            tls::load_key(&listener_config.key)?,
+        )
+        .context("loading server certificate")?;
+
+    Ok(Arc::new(config))
+}
+
+#[instrument(skip_all, fields(tcid = conn_id))]
+fn handle_conn(
+    tls_config: Arc<ServerConfig>,
+    pins: &[String],
+    mut tcp: TcpStream,
+    socket: &Path,
+    conn_id: usize,
+) -> anyhow::Result<()> {
+    let peer = tcp.peer_addr().context("getting peer address")?;
+    info!("new tcp connection from {}", peer);
+
+    // Don't let a peer that never finishes the handshake hold a thread forever.
+    tcp.set_read_timeout(Some(tls::HANDSHAKE_TIMEOUT))
+        .context("setting read timeout on tcp stream")?;
+    let mut conn = ServerConnection::new(tls_config).context("creating tls connection")?.into();
+    tls::handshake(&mut conn, &mut tcp)?;
+    tls::check_pins(pins, conn.peer_certificates())?;
+    tcp.set_read_timeout(None).context("unsetting read timeout on tcp stream")?;
+    info!("authenticated tls connection from {}", peer);
+
+    let unix = UnixStream::connect(socket).context("connecting to daemon socket")?;
+    tls::relay(conn, tcp, unix)
+}
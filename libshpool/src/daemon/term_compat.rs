@@ -0,0 +1,139 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Checking a reattaching terminal against the one a session started on.
+
+  The programs running in a session picked their escape sequences based
+  on the TERM the session was created with, and they keep using them no
+  matter what terminal gets attached later. Reattaching from a terminal
+  that doesn't speak the same dialect leaves full screen programs
+  garbled, so the daemon compares the terminfo entries for the two
+  TERMs and warns the client about the capabilities that differ. Plenty
+  of entries with different names are close enough that nobody would
+  notice, so they only get flagged if a capability that full screen
+  programs lean on differs.
+*/
+
+use termini::{NumberCapability, StringCapability, TermInfo};
+use tracing::info;
+
+/// The string capabilities that full screen programs can't do without,
+/// along with how to describe them to the user. termini's capabilities
+/// can't be copied, so this hands out a fresh list every time.
+fn string_caps() -> [(StringCapability, &'static str); 11] {
+    [
+        (StringCapability::CursorAddress, "cursor movement"),
+        (StringCapability::ChangeScrollRegion, "scroll regions"),
+        (StringCapability::EnterAlternativeMode, "the alternate screen"),
+        (StringCapability::KeypadXmit, "keypad mode"),
+        (StringCapability::KeyUp, "arrow keys"),
+        (StringCapability::KeyDown, "arrow keys"),
+        (StringCapability::KeyLeft, "arrow keys"),
+        (StringCapability::KeyRight, "arrow keys"),
+        (StringCapability::KeyBackspace, "backspace"),
+        (StringCapability::SetAnsiForeground, "colors"),
+        (StringCapability::SetAnsiBackground, "colors"),
+    ]
+}
+
+/// Compare the TERM a session was started with against the TERM of a
+/// client attaching to it, returning a warning for the client if they
+/// don't look compatible.
+pub fn check(session_term: Option<&str>, client_term: Option<&str>) -> Option<String> {
+    let (session_term, client_term) = match (session_term, client_term) {
+        (Some(s), Some(c)) if s != c => (s, c),
+        _ => return None,
+    };
+
+    let differences = match (TermInfo::from_name(session_term), TermInfo::from_name(client_term)) {
+        (Ok(session_db), Ok(client_db)) => differences(&session_db, &client_db),
+        (session_res, client_res) => {
+            info!(
+                "could not compare terminfo (session={:?}, client={:?})",
+                session_res.err(),
+                client_res.err()
+            );
+            vec![String::from("unknown terminfo")]
+        }
+    };
+    if differences.is_empty() {
+        return None;
+    }
+
+    Some(format!(
+        "this session was started with TERM={} but this terminal is TERM={} ({} differ), so full screen programs may draw incorrectly until they are restarted with the new TERM",
+        session_term,
+        client_term,
+        differences.join(", ")
+    ))
+}
+
+/// Describe the capabilities that full screen programs use which the
+/// two entries disagree on.
+fn differences(session_db: &TermInfo, client_db: &TermInfo) -> Vec<String> {
+    let mut differences = vec![];
+    let session_colors = session_db.number_cap(NumberCapability::MaxColors).unwrap_or(0);
+    let client_colors = client_db.number_cap(NumberCapability::MaxColors).unwrap_or(0);
+    if session_colors != client_colors {
+        differences.push(format!("colors ({} vs {})", session_colors, client_colors));
+    }
+
+    for ((session_cap, desc), (client_cap, _)) in string_caps().into_iter().zip(string_caps()) {
+        let desc = String::from(desc);
+        if session_db.raw_string_cap(session_cap) != client_db.raw_string_cap(client_cap)
+            && !differences.iter().any(|d| d.starts_with(&desc))
+        {
+            differences.push(desc);
+        }
+    }
+
+    differences
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn same_or_missing_term() {
+        assert_eq!(check(Some("xterm"), Some("xterm")), None);
+        assert_eq!(check(None, Some("xterm")), None);
+        assert_eq!(check(Some("xterm"), None), None);
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn unknown_term() {
+        let warning = check(Some("xterm"), Some("no-such-terminal")).unwrap();
+        assert!(warning.contains("TERM=no-such-terminal"), "{}", warning);
+        assert!(warning.contains("unknown terminfo"), "{}", warning);
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn different_colors() -> anyhow::Result<()> {
+        let (xterm, xterm_256) =
+            match (TermInfo::from_name("xterm"), TermInfo::from_name("xterm-256color")) {
+                (Ok(a), Ok(b)) => (a, b),
+                // no terminfo database to test against
+                _ => return Ok(()),
+            };
+        assert!(differences(&xterm, &xterm).is_empty());
+        assert_eq!(differences(&xterm, &xterm_256)[0], "colors (8 vs 256)");
+
+        Ok(())
+    }
+}
@@ -66,7 +66,11 @@ where
             return self.nodes[0].value.is_some();
         }
 
-        if let TrieCursor::Match { is_partial, .. } = match_state { !is_partial } else { false }
+        if let TrieCursor::Match { is_partial, .. } = match_state {
+            !is_partial
+        } else {
+            false
+        }
     }
 
     /// Process a single token of input, returning the current state.
@@ -133,7 +137,7 @@ where
 
 impl TrieTab<u8> for Vec<Option<usize>> {
     fn new() -> Self {
-        vec![None; u8::MAX as usize]
+        vec![None; u8::MAX as usize + 1]
     }
 
     fn get(&self, index: u8) -> Option<&usize> {
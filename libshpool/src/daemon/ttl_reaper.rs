@@ -23,19 +23,38 @@
 use std::{
     cmp,
     collections::{BinaryHeap, HashMap},
-    sync::{Arc, Mutex},
-    time::Instant,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
+use anyhow::Context;
 use tracing::{info, span, warn, Level};
 
-use super::shell;
+use super::{metrics, registry, shell};
+use crate::{config, duration, protocol};
+
+// How long to give the reader thread to pick up a hangup, and then
+// to get the notice written out to the client.
+const HANGUP_MSG_TIMEOUT: Duration = Duration::from_millis(500);
+const HANGUP_ACK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a new session gets to live, given the ttl the client asked
+/// for, if any. Bad values in the config are quietly ignored, and
+/// `shpool doctor` points them out.
+pub fn session_ttl(config: &config::Config, requested: Option<Duration>) -> Option<Duration> {
+    let parse = |src: &Option<String>| src.as_deref().and_then(|s| duration::parse(s).ok());
+    let ttl = requested.or_else(|| parse(&config.session_ttl));
+    match (ttl, parse(&config.max_session_ttl)) {
+        (Some(ttl), Some(max)) => Some(ttl.min(max)),
+        (ttl, max) => ttl.or(max),
+    }
+}
 
 /// Run the reaper thread loop. Should be invoked in a dedicated
 /// thread.
 pub fn run(
     new_sess: crossbeam_channel::Receiver<(String, Instant)>,
-    shells: Arc<Mutex<HashMap<String, Box<shell::Session>>>>,
+    shells: Arc<registry::Sessions>,
 ) -> anyhow::Result<()> {
     let _s = span!(Level::INFO, "ttl_reaper").entered();
 
@@ -102,8 +121,9 @@ pub fn run(
                         continue;
                     }
 
-                    let mut shells = shells.lock().unwrap();
+                    let mut shells = shells.shard(&reapable.session_name);
                     if let Some(sess) = shells.get(&reapable.session_name) {
+                        hang_up(&reapable.session_name, sess);
                         if let Err(e) = sess.kill() {
                             warn!("error trying to kill '{}': {:?}",
                                   reapable.session_name, e);
@@ -114,12 +134,37 @@ pub fn run(
                         continue;
                     }
                     shells.remove(&reapable.session_name);
+                    metrics::inc(&metrics::METRICS.reaps, 1);
                 }
             }
         }
     }
 }
 
+/// Tell the attached client, if there is one, why its session is about
+/// to go away.
+fn hang_up(name: &str, sess: &shell::Session) {
+    let reason = format!("session '{}' reached the end of its ttl", name);
+    let reader_ctl = sess.reader_ctl.lock().unwrap();
+    let res = reader_ctl
+        .client_connection
+        .send_timeout(
+            shell::ClientConnectionMsg::Hangup(protocol::StreamControl::Ended(reason)),
+            HANGUP_MSG_TIMEOUT,
+        )
+        .context("sending hangup msg")
+        .and_then(|_| {
+            reader_ctl.client_connection_ack.recv_timeout(HANGUP_ACK_TIMEOUT).context("getting ack")
+        });
+    match res {
+        Ok(shell::ClientConnectionStatus::Detached) => {
+            info!("told client of '{}' about the ttl", name)
+        }
+        Ok(_) => {}
+        Err(err) => warn!("notifying '{}' of the ttl: {:?}", name, err),
+    }
+}
+
 /// A record in the min heap that we use to track the
 /// sessions that need to be cleaned up.
 #[derive(Debug)]
@@ -148,3 +193,34 @@ impl cmp::Ord for Reapable {
         other.reap_at.cmp(&self.reap_at)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn ttl_from_config() -> anyhow::Result<()> {
+        let hour = Duration::from_secs(60 * 60);
+        let cases = vec![
+            ("", None, None),
+            ("", Some(hour), Some(hour)),
+            ("session_ttl = \"2h\"", None, Some(2 * hour)),
+            // the client's ttl wins over the default
+            ("session_ttl = \"2h\"", Some(hour), Some(hour)),
+            // but not over the limit
+            ("max_session_ttl = \"30m\"", Some(hour), Some(hour / 2)),
+            ("max_session_ttl = \"30m\"", None, Some(hour / 2)),
+            ("session_ttl = \"2h\"\nmax_session_ttl = \"3h\"", None, Some(2 * hour)),
+            ("session_ttl = \"2h\"\nmax_session_ttl = \"1h\"", None, Some(hour)),
+            // a bad value is as good as none
+            ("session_ttl = \"soon\"", None, None),
+        ];
+        for (src, requested, want) in cases.into_iter() {
+            let config: config::Config = toml::from_str(src)?;
+            assert_eq!(session_ttl(&config, requested), want, "config: {}", src);
+        }
+        Ok(())
+    }
+}
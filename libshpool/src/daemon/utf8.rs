@@ -0,0 +1,168 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Keeping multibyte UTF-8 characters in one piece.
+
+  The reader gets the shell's output in whatever chunks the pty hands
+  out, which can end in the middle of a character. That is harmless
+  as long as the next chunk goes to the same place, but if a client
+  detaches or gets replaced in between, the two halves land on
+  different terminals and both of them draw garbage. `Joiner` holds
+  the start of such a character back until the rest of it shows up so
+  that every chunk the reader deals with ends on a character boundary.
+*/
+
+/// The longest a UTF-8 encoded character can be.
+const MAX_CHAR_LEN: usize = 4;
+
+/// Holds back a trailing incomplete character from each chunk, gluing
+/// it onto the front of the next one.
+#[derive(Debug, Default)]
+pub struct Joiner {
+    held: Vec<u8>,
+}
+
+impl Joiner {
+    pub fn new() -> Self {
+        Joiner::default()
+    }
+
+    /// Join a chunk of output with whatever was held back from the last
+    /// one, using `scratch` as the backing storage for the result if
+    /// needed. The result might be empty if the whole chunk is part of
+    /// a character that isn't finished yet.
+    pub fn join<'a>(&mut self, input: &'a [u8], scratch: &'a mut Vec<u8>) -> &'a [u8] {
+        scratch.clear();
+        if self.held.is_empty() {
+            let keep = input.len() - incomplete_tail(input);
+            self.held.extend_from_slice(&input[keep..]);
+            return &input[..keep];
+        }
+
+        scratch.append(&mut self.held);
+        scratch.extend_from_slice(input);
+        let keep = scratch.len() - incomplete_tail(scratch);
+        self.held.extend_from_slice(&scratch[keep..]);
+        scratch.truncate(keep);
+        scratch
+    }
+
+    pub fn is_holding(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    /// Give up on the rest of the held character ever showing up, and
+    /// hand back the bytes as they are.
+    pub fn flush<'a>(&mut self, scratch: &'a mut Vec<u8>) -> &'a [u8] {
+        scratch.clear();
+        scratch.append(&mut self.held);
+        scratch
+    }
+}
+
+/// Skip over the tail end of a character that got cut off at the start
+/// of the buffer.
+pub fn trim_partial_start(buf: &[u8]) -> &[u8] {
+    let skip = buf.iter().take(MAX_CHAR_LEN - 1).take_while(|b| is_continuation(**b)).count();
+    &buf[skip..]
+}
+
+/// How many bytes at the end of the buffer belong to a character that
+/// is still missing some of its bytes.
+fn incomplete_tail(buf: &[u8]) -> usize {
+    for back in 1..=buf.len().min(MAX_CHAR_LEN - 1) {
+        let byte = buf[buf.len() - back];
+        if is_continuation(byte) {
+            continue;
+        }
+        let char_len = match byte {
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            // ascii, or not valid UTF-8 to begin with
+            _ => return 0,
+        };
+        return if back < char_len { back } else { 0 };
+    }
+    0
+}
+
+fn is_continuation(byte: u8) -> bool {
+    byte & 0xc0 == 0x80
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn split_char() {
+        let text = "a€b🦀".as_bytes();
+        for split in 0..text.len() {
+            let mut joiner = Joiner::new();
+            let mut scratch = vec![];
+            let mut out = joiner.join(&text[..split], &mut scratch).to_vec();
+            assert!(std::str::from_utf8(&out).is_ok(), "split={}", split);
+            out.extend(joiner.join(&text[split..], &mut scratch));
+            assert!(!joiner.is_holding());
+            assert_eq!(out, text, "split={}", split);
+        }
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn byte_at_a_time() {
+        let text = "日本語 text".as_bytes();
+        let mut joiner = Joiner::new();
+        let mut scratch = vec![];
+        let mut out: Vec<u8> = vec![];
+        for byte in text.chunks(1) {
+            let chunk = joiner.join(byte, &mut scratch);
+            assert!(std::str::from_utf8(chunk).is_ok());
+            out.extend(chunk);
+        }
+        assert_eq!(out, text);
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn flush() {
+        let mut joiner = Joiner::new();
+        let mut scratch = vec![];
+        assert_eq!(joiner.join(b"x\xe2\x82", &mut scratch), b"x");
+        assert!(joiner.is_holding());
+        assert_eq!(joiner.flush(&mut scratch), b"\xe2\x82");
+        assert!(!joiner.is_holding());
+        assert_eq!(joiner.join(b"y", &mut scratch), b"y");
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn invalid_passes_through() {
+        let mut joiner = Joiner::new();
+        let mut scratch = vec![];
+        assert_eq!(joiner.join(b"\xff\x80", &mut scratch), b"\xff\x80");
+        assert!(!joiner.is_holding());
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn trim() {
+        assert_eq!(trim_partial_start(b"\x82\xacabc"), b"abc");
+        assert_eq!(trim_partial_start("€".as_bytes()), "€".as_bytes());
+        assert_eq!(trim_partial_start(b""), b"");
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! utmp and wtmp records for sessions.
+
+  Tools like `who`, `w` and `last` find out who is logged in from the
+  utmp and wtmp files, which login, sshd and terminal emulators keep up
+  to date for the ttys they hand out. shpool hands out ttys too, so when
+  the config asks for it, the daemon adds a record for each new shell's
+  pty, with the session name standing in for the remote host, and marks
+  it dead once the shell exits. Both files are normally only writable
+  by root and the utmp group.
+*/
+
+use std::{ffi::CStr, mem, os::fd::RawFd, time};
+
+use anyhow::{anyhow, Context};
+use tracing::{info, warn};
+
+/// Where login records get appended for `last`.
+const WTMP_PATH: &[u8] = b"/var/log/wtmp\0";
+
+extern "C" {
+    // not in the libc crate, but in every libc with utmpx support
+    fn updwtmpx(wtmpx_file: *const libc::c_char, utmpx: *const libc::utmpx);
+}
+
+/// A login record for a session's pty, which gets marked dead on drop.
+pub struct Record {
+    entry: libc::utmpx,
+}
+
+impl std::fmt::Debug for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Record").field("pid", &self.entry.ut_pid).finish()
+    }
+}
+
+impl Record {
+    /// Record that the given user logged in on the pty with the given
+    /// master, running the shell with the given pid.
+    pub fn add(user: &str, pid: libc::pid_t, master: RawFd, session: &str) -> anyhow::Result<Self> {
+        let line = pty_line(master)?;
+
+        // Safety: utmpx is plain old data, for which all zeros is
+        //         a blank record.
+        let mut entry: libc::utmpx = unsafe { mem::zeroed() };
+        entry.ut_type = libc::USER_PROCESS;
+        entry.ut_pid = pid;
+        copy_field(&mut entry.ut_line, line.as_bytes());
+        // Like login, the id is the tail end of the line, so "pts/12"
+        // becomes "s/12".
+        copy_field(&mut entry.ut_id, &line.as_bytes()[line.len().saturating_sub(4)..]);
+        copy_field(&mut entry.ut_user, user.as_bytes());
+        copy_field(&mut entry.ut_host, format!("shpool:{}", session).as_bytes());
+
+        let mut record = Record { entry };
+        record.write().context("writing login record")?;
+        info!("added utmp record for {} on {}", user, line);
+        Ok(record)
+    }
+
+    fn write(&mut self) -> anyhow::Result<()> {
+        let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?;
+        self.entry.ut_tv.tv_sec = now.as_secs() as _;
+        self.entry.ut_tv.tv_usec = now.subsec_micros() as _;
+
+        // Safety: the entry is fully initialized, and these functions
+        //         copy it rather than holding on to it.
+        let written = unsafe {
+            libc::setutxent();
+            let written = libc::pututxline(&self.entry);
+            libc::endutxent();
+            written
+        };
+        if written.is_null() {
+            return Err(anyhow!("updating utmp: {}", std::io::Error::last_os_error()));
+        }
+        // Safety: as above, and the path is NUL terminated.
+        unsafe { updwtmpx(WTMP_PATH.as_ptr() as *const libc::c_char, &self.entry) };
+
+        Ok(())
+    }
+}
+
+impl Drop for Record {
+    fn drop(&mut self) {
+        self.entry.ut_type = libc::DEAD_PROCESS;
+        self.entry.ut_user.fill(0);
+        self.entry.ut_host.fill(0);
+        if let Err(err) = self.write() {
+            warn!("marking login record dead: {:?}", err);
+        }
+    }
+}
+
+/// The name of the pty with the given master, relative to /dev, which
+/// is the form utmp wants.
+fn pty_line(master: RawFd) -> anyhow::Result<String> {
+    let mut buf = [0 as libc::c_char; 128];
+    // Safety: the buffer is as long as we say it is.
+    let ret = unsafe { libc::ptsname_r(master, buf.as_mut_ptr(), buf.len()) };
+    if ret != 0 {
+        return Err(anyhow!("getting pty name: {}", std::io::Error::from_raw_os_error(ret)));
+    }
+    // Safety: ptsname_r NUL terminates the name on success.
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) }.to_str().context("parsing pty name")?;
+    Ok(String::from(name.strip_prefix("/dev/").unwrap_or(name)))
+}
+
+/// Copy a string into a fixed size utmp field, truncating it if need
+/// be. The fields are not required to be NUL terminated.
+fn copy_field(field: &mut [libc::c_char], src: &[u8]) {
+    for (dst, byte) in field.iter_mut().zip(src.iter()) {
+        *dst = *byte as libc::c_char;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn copy_field_truncates() {
+        let mut field = [0 as libc::c_char; 4];
+        copy_field(&mut field, b"pts/12");
+        assert_eq!(field, [b'p' as libc::c_char, b't' as _, b's' as _, b'/' as _]);
+
+        let mut field = [0 as libc::c_char; 4];
+        copy_field(&mut field, b"ab");
+        assert_eq!(field, [b'a' as libc::c_char, b'b' as _, 0, 0]);
+    }
+}
@@ -0,0 +1,447 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! The `shpool doctor` command, which checks for the usual suspects
+  when shpool is not working and says what to do about them.
+
+  Each check is independent, so one problem doesn't hide the others.
+*/
+
+use std::{
+    env, fmt, fs, io,
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::{Path, PathBuf},
+    time,
+};
+
+use anyhow::anyhow;
+use nix::sys::socket;
+
+use super::{config, daemon::keybindings, duration, paths, protocol};
+
+const HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+/// Warn once this fraction of the system's ptys are in use.
+const PTY_WARN_FRACTION: f64 = 0.9;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Ok => write!(f, "ok"),
+            Status::Warn => write!(f, "warn"),
+            Status::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+    /// What the user should do about it, if anything.
+    fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, detail: String) -> Self {
+        Check { name, status: Status::Ok, detail, fix: None }
+    }
+
+    fn warn(name: &'static str, detail: String, fix: String) -> Self {
+        Check { name, status: Status::Warn, detail, fix: Some(fix) }
+    }
+
+    fn fail(name: &'static str, detail: String, fix: String) -> Self {
+        Check { name, status: Status::Fail, detail, fix: Some(fix) }
+    }
+}
+
+pub fn run(config_file: Option<String>, socket: PathBuf) -> anyhow::Result<()> {
+    let mut checks = check_config(config_file.as_deref());
+    checks.extend(check_daemon(&socket));
+    checks.push(check_term(env::var("TERM").ok().as_deref()));
+    checks.push(check_systemd_units());
+    checks.push(check_ptys());
+
+    let mut failed = 0;
+    for check in checks.iter() {
+        println!("{:<6}{:<10}{}", check.status.to_string(), check.name, check.detail);
+        if let Some(fix) = &check.fix {
+            println!("{:<16}fix: {}", "", fix);
+        }
+        if check.status == Status::Fail {
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        eprintln!("shpool: doctor: {} checks failed", failed);
+        return Err(anyhow!("{} checks failed", failed));
+    }
+
+    Ok(())
+}
+
+/// Make sure the config parses and that the things in it which only
+/// get looked at when a session starts are sane.
+fn check_config(config_file: Option<&str>) -> Vec<Check> {
+    let path = match config_file {
+        Some(f) => Some(PathBuf::from(f)),
+        None => config::default_path().ok(),
+    };
+    let path_desc = path.as_ref().map(|p| p.display().to_string()).unwrap_or_default();
+
+    let (config, loaded_from) = match config::load(config_file) {
+        Ok(c) => c,
+        Err(err) => {
+            return vec![Check::fail(
+                "config",
+                format!("{:#}", err),
+                format!("fix the error in {}", path_desc),
+            )];
+        }
+    };
+
+    let mut checks = vec![match &loaded_from {
+        Some(f) => Check::ok("config", format!("parsed {}", f)),
+        None => Check::ok("config", format!("no config at {}, using defaults", path_desc)),
+    }];
+
+    // the default config falls back to where older versions read it from
+    if let (None, Some(f), Ok(dir)) = (config_file, &loaded_from, paths::config_dir()) {
+        let want = dir.join("config.toml");
+        if Path::new(f) != want {
+            checks.push(Check::warn(
+                "config",
+                format!("read from the legacy location {}", f),
+                format!("move it to {}", want.display()),
+            ));
+        }
+    }
+
+    // the daemon only logs these, unless strict_config is set
+    let unknown_keys = loaded_from
+        .as_ref()
+        .and_then(|f| config::read(Path::new(f)).ok())
+        .and_then(|table| config::from_table(table).ok())
+        .map(|(_, keys)| keys)
+        .unwrap_or_default();
+    if !unknown_keys.is_empty() {
+        checks.push(Check::warn(
+            "config",
+            format!("unknown keys: {}", unknown_keys.join(", ")),
+            String::from("fix the typos, the keys are described in the README"),
+        ));
+    }
+
+    let bindings = config.keybinding.clone().unwrap_or_default();
+    if let Err(err) =
+        keybindings::Bindings::new(bindings.iter().map(|b| (b.binding.as_str(), b.action.clone())))
+    {
+        checks.push(Check::fail(
+            "config",
+            format!("bad keybinding: {:#}", err),
+            String::from("fix the binding, the syntax is described in the README"),
+        ));
+    }
+
+    // the daemon quietly falls back to the defaults for these
+    let durations = [
+        ("hooks.timeout", config.hooks.as_ref().and_then(|h| h.timeout.as_ref())),
+        ("activity.active_window", config.activity.as_ref().and_then(|a| a.active_window.as_ref())),
+        (
+            "activity.silence_threshold",
+            config.activity.as_ref().and_then(|a| a.silence_threshold.as_ref()),
+        ),
+        ("lock.idle_timeout", config.lock.as_ref().and_then(|l| l.idle_timeout.as_ref())),
+        ("session_ttl", config.session_ttl.as_ref()),
+        ("max_session_ttl", config.max_session_ttl.as_ref()),
+        ("exited_session_linger", config.exited_session_linger.as_ref()),
+    ];
+    let restart_durations = config.restart_policy.iter().flatten().flat_map(|p| {
+        [
+            ("restart_policy.backoff", p.backoff.as_ref()),
+            ("restart_policy.max_backoff", p.max_backoff.as_ref()),
+        ]
+    });
+    for (key, value) in durations.into_iter().chain(restart_durations) {
+        if let Some(Err(err)) = value.map(|v| duration::parse(v)) {
+            checks.push(Check::fail(
+                "config",
+                format!("bad {}: {:#}", key, err),
+                String::from("use a duration like 30s, 10m or 01:30:00"),
+            ));
+        }
+    }
+
+    if let Some(hash) = config.lock.as_ref().and_then(|l| l.passphrase_hash.as_ref()) {
+        if let Err(err) = argon2::PasswordHash::new(hash) {
+            checks.push(Check::fail(
+                "config",
+                format!("bad lock.passphrase_hash: {}", err),
+                String::from("use the encoded hash that `argon2 <salt> -id -e` prints"),
+            ));
+        }
+    }
+
+    if let Some(shell) = &config.shell {
+        if !is_executable(Path::new(shell)) {
+            checks.push(Check::fail(
+                "config",
+                format!("shell {} is not an executable file", shell),
+                String::from(
+                    "point shell at an installed shell, or remove it to use your login shell",
+                ),
+            ));
+        }
+    }
+
+    checks
+}
+
+/// Check that the daemon is listening and speaks our protocol.
+fn check_daemon(socket: &Path) -> Vec<Check> {
+    let fix_start = String::from(
+        "start the daemon with `systemctl --user start shpool.socket` or `shpool daemon &`",
+    );
+    match UnixStream::connect(socket) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return vec![Check::fail(
+                "daemon",
+                format!("no daemon socket at {}", socket.display()),
+                fix_start,
+            )];
+        }
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            return vec![Check::fail(
+                "daemon",
+                format!(
+                    "{} is a stale socket left behind by a daemon that is no longer running",
+                    socket.display()
+                ),
+                format!("{}, which replaces it", fix_start),
+            )];
+        }
+        Err(e) => {
+            return vec![Check::fail(
+                "daemon",
+                format!("connecting to {}: {}", socket.display(), e),
+                String::from("check the permissions on the socket and the directory it is in"),
+            )];
+        }
+    }
+
+    let client = match protocol::Client::with_timeout(socket, Some(HANDSHAKE_TIMEOUT)) {
+        Ok(c) => c,
+        Err(err) => {
+            let fix = match err.downcast_ref::<protocol::HandshakeError>() {
+                Some(protocol::HandshakeError::Forbidden(_)) => {
+                    "add your uid or gid to allowed_peers in the daemon's config"
+                }
+                _ => {
+                    "restart the daemon so that it runs the same version as this client \
+                     with `shpool daemon --takeover`"
+                }
+            };
+            return vec![Check::fail(
+                "daemon",
+                format!("handshake with the daemon failed: {:#}", err),
+                String::from(fix),
+            )];
+        }
+    };
+
+    let mut checks = vec![Check::ok("daemon", format!("listening on {}", socket.display()))];
+    // daemons that know how to stop can hand their sessions over
+    let restart = if client.capabilities().iter().any(|c| c == "stop") {
+        "restart the daemon with `shpool daemon --takeover`, which keeps all sessions"
+    } else {
+        "restart the daemon with `shpool daemon --takeover` (this ends all sessions)"
+    };
+    let missing: Vec<&str> = protocol::CAPABILITIES
+        .iter()
+        .filter(|c| !client.capabilities().iter().any(|have| have == *c))
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        checks.push(Check::warn(
+            "daemon",
+            format!("the daemon is older than this client and lacks {}", missing.join(", ")),
+            String::from(restart),
+        ));
+    }
+
+    let daemon_exe = socket::getsockopt(&client.stream, socket::sockopt::PeerCredentials)
+        .map_err(io::Error::from)
+        .and_then(|creds| fs::read_link(format!("/proc/{}/exe", creds.pid())));
+    if let Ok(daemon_exe) = daemon_exe {
+        let daemon_exe = daemon_exe.to_string_lossy();
+        if daemon_exe.ends_with(" (deleted)") {
+            checks.push(Check::warn(
+                "daemon",
+                format!("the daemon binary {} has been replaced since it started", daemon_exe),
+                String::from(restart),
+            ));
+        } else if let Ok(self_exe) = env::current_exe() {
+            if self_exe.to_string_lossy() != daemon_exe {
+                checks.push(Check::warn(
+                    "daemon",
+                    format!("the daemon runs {} but this is {}", daemon_exe, self_exe.display()),
+                    String::from("make sure only one shpool is installed and on your PATH"),
+                ));
+            }
+        }
+    }
+
+    checks
+}
+
+/// The daemon needs a terminfo entry for the TERM clients attach with.
+fn check_term(term: Option<&str>) -> Check {
+    match term {
+        None => Check::warn(
+            "term",
+            String::from("TERM is not set"),
+            String::from("run shpool from a terminal, or set TERM"),
+        ),
+        Some(term) => match termini::TermInfo::from_name(term) {
+            Ok(_) => Check::ok("term", format!("found a terminfo entry for {}", term)),
+            Err(err) => Check::fail(
+                "term",
+                format!("no terminfo entry for TERM={}: {}", term, err),
+                format!(
+                    "install the terminfo for {} (often in an ncurses-term package) or set \
+                     TERM to something common like xterm-256color",
+                    term
+                ),
+            ),
+        },
+    }
+}
+
+/// Look for the systemd user units, which is how most people get the
+/// daemon started.
+fn check_systemd_units() -> Check {
+    let mut dirs = vec![];
+    if let Ok(config_home) = env::var("XDG_CONFIG_HOME") {
+        dirs.push(PathBuf::from(config_home).join("systemd/user"));
+    }
+    if let Ok(home) = env::var("HOME") {
+        dirs.push(PathBuf::from(&home).join(".config/systemd/user"));
+        dirs.push(PathBuf::from(&home).join(".local/share/systemd/user"));
+    }
+    for dir in ["/etc/systemd/user", "/usr/local/lib/systemd/user", "/usr/lib/systemd/user"] {
+        dirs.push(PathBuf::from(dir));
+    }
+
+    let unit = match dirs.iter().map(|d| d.join("shpool.service")).find(|u| u.exists()) {
+        Some(u) => u,
+        None => {
+            return Check::warn(
+                "systemd",
+                String::from("no shpool.service user unit installed"),
+                String::from(
+                    "install the units from the README so the daemon starts with your session, \
+                     or start it some other way",
+                ),
+            );
+        }
+    };
+
+    let exec_start = fs::read_to_string(&unit).ok().and_then(|contents| {
+        contents.lines().find_map(|l| {
+            l.trim()
+                .strip_prefix("ExecStart=")
+                .and_then(|cmd| cmd.split_whitespace().next())
+                .map(String::from)
+        })
+    });
+    match exec_start {
+        Some(bin) if !is_executable(Path::new(&bin)) => Check::fail(
+            "systemd",
+            format!("{} starts {}, which is not an executable file", unit.display(), bin),
+            format!(
+                "point ExecStart in {} at your shpool binary, then run `systemctl --user daemon-reload`",
+                unit.display()
+            ),
+        ),
+        _ => Check::ok("systemd", format!("found {}", unit.display())),
+    }
+}
+
+/// Every session needs a pty, so running out of them makes attaches
+/// fail in confusing ways.
+fn check_ptys() -> Check {
+    let read = |f: &str| -> Option<u64> { fs::read_to_string(f).ok()?.trim().parse().ok() };
+    match (read("/proc/sys/kernel/pty/nr"), read("/proc/sys/kernel/pty/max")) {
+        (Some(nr), Some(max)) => pty_usage(nr, max),
+        _ => Check::ok("ptys", String::from("could not read pty limits, skipping")),
+    }
+}
+
+fn pty_usage(nr: u64, max: u64) -> Check {
+    if (nr as f64) < (max as f64) * PTY_WARN_FRACTION {
+        Check::ok("ptys", format!("{} of {} in use", nr, max))
+    } else {
+        Check::warn(
+            "ptys",
+            format!("{} of {} ptys in use", nr, max),
+            String::from("kill sessions you no longer need, or raise kernel.pty.max with sysctl"),
+        )
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn stale_socket() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-doctor")?;
+        let socket = tmp_dir.path().join("shpool.socket");
+
+        let checks = check_daemon(&socket);
+        assert_eq!(checks[0].status, Status::Fail);
+        assert!(checks[0].detail.contains("no daemon socket"));
+
+        // binding leaves the socket file behind once the listener is gone
+        drop(std::os::unix::net::UnixListener::bind(&socket)?);
+        let checks = check_daemon(&socket);
+        assert_eq!(checks[0].status, Status::Fail);
+        assert!(checks[0].detail.contains("stale socket"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn ptys() {
+        assert_eq!(pty_usage(10, 4096).status, Status::Ok);
+        assert_eq!(pty_usage(4000, 4096).status, Status::Warn);
+    }
+}
@@ -29,6 +29,18 @@ pub fn parse(src: &str) -> anyhow::Result<time::Duration> {
     }
 }
 
+/// Formats a duration in the largest whole unit that fits, in the
+/// same suffix format that parse accepts, so 2h59m comes out as 2h.
+pub fn format_coarse(dur: time::Duration) -> String {
+    let secs = dur.as_secs();
+    for (unit, unit_secs) in [('d', 60 * 60 * 24), ('h', 60 * 60), ('m', 60)] {
+        if secs >= unit_secs {
+            return format!("{}{}", secs / unit_secs, unit);
+        }
+    }
+    format!("{}s", secs)
+}
+
 /// Parses dd:hh:mm:ss or any suffix
 fn parse_colon_duration(src: &str) -> anyhow::Result<time::Duration> {
     let mut parts = src.split(':').collect::<Vec<_>>();
@@ -37,22 +49,18 @@ fn parse_colon_duration(src: &str) -> anyhow::Result<time::Duration> {
         bail!("'{}' must have at least one part", src);
     }
     let mut secs = parts[0].parse::<u64>().context("parsing seconds part")?;
-    dbg!(secs);
     if parts.len() == 1 {
         return Ok(time::Duration::from_secs(secs));
     }
     secs += parts[1].parse::<u64>().context("parsing minutes part")? * 60;
-    dbg!(secs);
     if parts.len() == 2 {
         return Ok(time::Duration::from_secs(secs));
     }
     secs += parts[2].parse::<u64>().context("parsing hours part")? * 60 * 60;
-    dbg!(secs);
     if parts.len() == 3 {
         return Ok(time::Duration::from_secs(secs));
     }
     secs += parts[3].parse::<u64>().context("parsing days part")? * 60 * 60 * 24;
-    dbg!(secs);
     if parts.len() != 4 {
         bail!("colon duration cannot have more than 4 parts");
     }
@@ -125,4 +133,18 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn coarse() {
+        let cases = vec![
+            (time::Duration::from_millis(1500), "1s"),
+            (time::Duration::from_secs(59), "59s"),
+            (time::Duration::from_secs(2 * 60 * 60 + 59 * 60), "2h"),
+            (time::Duration::from_secs(3 * 60 * 60 * 24 + 5), "3d"),
+        ];
+
+        for (dur, want) in cases.into_iter() {
+            assert_eq!(format_coarse(dur), want);
+        }
+    }
 }
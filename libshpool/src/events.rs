@@ -0,0 +1,112 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    time,
+};
+
+use anyhow::Context;
+use serde_json::json;
+
+use super::{
+    protocol,
+    protocol::{ConnectHeader, SessionEvent, SessionEventKind},
+};
+
+pub fn run(socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require_capability("events")?;
+    client.write_connect_header(ConnectHeader::Events).context("writing events header")?;
+
+    let mut stdout = io::stdout();
+    loop {
+        let event: SessionEvent = client.read_reply().context("reading event")?;
+        let line = format!("{}\n", to_json(event));
+        match stdout.write_all(line.as_bytes()).and_then(|_| stdout.flush()) {
+            Ok(()) => {}
+            // whatever we were piped into is done listening
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(e).context("writing event"),
+        }
+    }
+}
+
+/// Turn an event into the json object printed for it, one per line.
+fn to_json(event: SessionEvent) -> serde_json::Value {
+    let at = time::UNIX_EPOCH + time::Duration::from_millis(event.at_ms);
+    let at = chrono::DateTime::<chrono::Utc>::from(at);
+    let mut line = json!({
+        "time": at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "session": event.session,
+    });
+    let name = match event.kind {
+        SessionEventKind::Created => "created",
+        SessionEventKind::Attached => "attached",
+        SessionEventKind::Detached => "detached",
+        SessionEventKind::Exited { exit_status } => {
+            line["exit_status"] = json!(exit_status);
+            "exited"
+        }
+        SessionEventKind::Bell => "bell",
+        SessionEventKind::Notification { title, body } => {
+            line["title"] = json!(title);
+            line["body"] = json!(body);
+            "notification"
+        }
+        SessionEventKind::Silence => "silence",
+        SessionEventKind::Activity => "activity",
+    };
+    line["event"] = json!(name);
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn json_lines() {
+        let event = |kind| SessionEvent { at_ms: 1500, session: String::from("main"), kind };
+        assert_eq!(
+            to_json(event(SessionEventKind::Exited { exit_status: 2 })),
+            json!({
+                "time": "1970-01-01T00:00:01.500Z",
+                "session": "main",
+                "event": "exited",
+                "exit_status": 2,
+            })
+        );
+        assert_eq!(
+            to_json(event(SessionEventKind::Notification {
+                title: Some(String::from("build")),
+                body: String::from("done"),
+            }))["event"],
+            "notification"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    protocol,
+    protocol::{ConnectHeader, GcReply, GcRequest},
+};
+
+pub fn run(dry_run: bool, socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require_capability("gc")?;
+    client
+        .write_connect_header(ConnectHeader::Gc(GcRequest { dry_run }))
+        .context("writing gc request header")?;
+    let reply: GcReply = client.read_reply().context("reading reply")?;
+    if let Some(err) = reply.error {
+        eprintln!("forbidden: {}", err);
+        return Err(anyhow!("forbidden: {}", err));
+    }
+
+    println!("SESSION\tBYTES\tPATH");
+    let mut total = 0;
+    let mut failed = 0;
+    for item in reply.items.iter() {
+        println!("{}\t{}\t{}", item.session, item.bytes, item.path);
+        if let Some(err) = &item.error {
+            eprintln!("could not remove {}: {}", item.path, err);
+            failed += 1;
+        } else {
+            total += item.bytes;
+        }
+    }
+    if dry_run {
+        println!("{} bytes reclaimable", total);
+    } else {
+        println!("{} bytes freed", total);
+    }
+
+    if failed > 0 {
+        return Err(anyhow!("failed to remove {} items", failed));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,68 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    protocol,
+    protocol::{ConnectHeader, GetEnvReply, GetEnvRequest},
+};
+
+pub fn run(session: String, var: Option<String>, socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require_capability("env")?;
+    client
+        .write_connect_header(ConnectHeader::GetEnv(GetEnvRequest { session: session.clone() }))
+        .context("writing getenv request header")?;
+    let reply: GetEnvReply = client.read_reply().context("reading reply")?;
+
+    let env = match reply {
+        GetEnvReply::Env(env) => env,
+        GetEnvReply::NotFound => {
+            eprintln!("not found: {}", session);
+            return Err(anyhow!("not found: {}", session));
+        }
+    };
+
+    match var {
+        // like printenv, just the value, failing if it isn't set
+        Some(var) => match env.into_iter().find(|(k, _)| *k == var) {
+            Some((_, Some(val))) => println!("{}", val),
+            _ => return Err(anyhow!("{} is not set", var)),
+        },
+        // unset variables get a leading '-', like tmux's show-environment
+        None => {
+            for (k, v) in env.into_iter() {
+                match v {
+                    Some(v) => println!("{}={}", k, v),
+                    None => println!("-{}", k),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
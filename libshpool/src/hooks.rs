@@ -59,4 +59,31 @@ pub trait Hooks {
     fn on_shell_disconnect(&self, _session_name: &str) -> anyhow::Result<()> {
         Ok(())
     }
+
+    /// Triggered when creating the named session would put the daemon over
+    /// the `max_sessions` limit from the config. By default the session is
+    /// rejected, but returning `Ok(true)` admits it anyway, which allows
+    /// wrapping binaries to hook in their own policy (for example granting
+    /// temporary exceptions). Returning an error rejects the session.
+    fn on_quota_exceeded(
+        &self,
+        _session_name: &str,
+        _num_sessions: usize,
+        _max_sessions: usize,
+    ) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    /// Triggered when a program running in a session with no attached
+    /// client emits a desktop notification via an OSC 9 or OSC 777
+    /// escape sequence. The notification will also be replayed to the
+    /// next client to attach.
+    fn on_notification(
+        &self,
+        _session_name: &str,
+        _title: Option<&str>,
+        _body: &str,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
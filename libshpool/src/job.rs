@@ -0,0 +1,165 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Jobs are extra shells that live alongside a session's own shell.
+//! Each one is a session in its own right, named after the session it
+//! belongs to, so "main:build" is the job "build" of the session "main".
+//! The session's own shell is addressed by the session's name.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    common, create, protocol,
+    protocol::{ConnectHeader, JobsReply, SwitchJobReply, SwitchJobRequest},
+    JobCommands,
+};
+
+/// Separates a session's name from a job's name in the session name
+/// of the job.
+pub const SEPARATOR: char = ':';
+
+/// The name of the session the given session or job belongs to.
+pub fn base_session(name: &str) -> &str {
+    name.split_once(SEPARATOR).map(|(base, _)| base).unwrap_or(name)
+}
+
+/// The session name of the given job of `base`. The job can also be
+/// given by its full session name, or as `base` itself for the
+/// session's own shell.
+pub fn job_session(base: &str, job: &str) -> String {
+    if job == base || base_session(job) == base {
+        String::from(job)
+    } else {
+        format!("{}{}{}", base, SEPARATOR, job)
+    }
+}
+
+/// The name of a job within its session, which is the session's name
+/// for the session's own shell.
+pub fn job_name(name: &str) -> &str {
+    name.split_once(SEPARATOR).map(|(_, job)| job).unwrap_or(name)
+}
+
+pub fn run(
+    config_file: Option<String>,
+    session: Option<String>,
+    command: JobCommands,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let mut sessions: Vec<String> = session.into_iter().collect();
+    common::resolve_sessions(&mut sessions, "manage jobs for")?;
+    let base = String::from(base_session(&sessions[0]));
+
+    match command {
+        JobCommands::New { background, cmd, job } => {
+            if list(&base, &socket)?.is_empty() {
+                eprintln!("no session named '{}'", base);
+                return Err(anyhow!("no session named '{}'", base));
+            }
+            let target = job_session(&base, &job);
+            create::run(config_file, target.clone(), None, cmd, vec![], vec![], socket.clone())?;
+            if !background {
+                // there might not be a terminal attached to switch, in
+                // which case the job just waits for one
+                switch(&base, &target, &socket)?;
+            }
+        }
+        JobCommands::Switch { job } => {
+            let target = job_session(&base, &job);
+            match switch(&base, &target, &socket)? {
+                SwitchJobReply::Switched => {}
+                SwitchJobReply::NotFound => {
+                    eprintln!("session '{}' has no job '{}'", base, job);
+                    return Err(anyhow!("no job '{}' in '{}'", job, base));
+                }
+                SwitchJobReply::NotAttached => {
+                    eprintln!("session '{}' has no terminal attached", base);
+                    return Err(anyhow!("no terminal attached to '{}'", base));
+                }
+                SwitchJobReply::Forbidden(reason) => {
+                    eprintln!("forbidden: {}", reason);
+                    return Err(anyhow!("forbidden: {}", reason));
+                }
+            }
+        }
+        JobCommands::List => {
+            let jobs = list(&base, &socket)?;
+            if jobs.is_empty() {
+                eprintln!("no session named '{}'", base);
+                return Err(anyhow!("no session named '{}'", base));
+            }
+
+            println!("NAME\tSTATUS");
+            for job in jobs.iter() {
+                let status = if job.attached { "attached" } else { "disconnected" };
+                println!("{}\t{}", job_name(&job.name), status);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn list(base: &str, socket: &PathBuf) -> anyhow::Result<Vec<protocol::Job>> {
+    let mut client = dial(socket)?;
+    client.require_capability("jobs")?;
+    client
+        .write_connect_header(ConnectHeader::Jobs(String::from(base)))
+        .context("writing jobs request header")?;
+    let reply: JobsReply = client.read_reply().context("reading reply")?;
+    Ok(reply.jobs)
+}
+
+fn switch(base: &str, target: &str, socket: &PathBuf) -> anyhow::Result<SwitchJobReply> {
+    let mut client = dial(socket)?;
+    client.require_capability("jobs")?;
+    client
+        .write_connect_header(ConnectHeader::SwitchJob(SwitchJobRequest {
+            session: String::from(base),
+            target: String::from(target),
+        }))
+        .context("writing switch job request header")?;
+    client.read_reply().context("reading reply")
+}
+
+fn dial(socket: &PathBuf) -> anyhow::Result<protocol::Client> {
+    match protocol::Client::new(socket) {
+        Ok(c) => Ok(c),
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            Err(io_err).context("connecting to daemon")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(base_session("main"), "main");
+        assert_eq!(base_session("main:build"), "main");
+        assert_eq!(job_session("main", "build"), "main:build");
+        assert_eq!(job_session("main", "main"), "main");
+        assert_eq!(job_session("main", "main:build"), "main:build");
+        assert_eq!(job_name("main:build"), "build");
+        assert_eq!(job_name("main"), "main");
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! The `log_format = "json"` logging mode.
+
+  Every event becomes a single line JSON object. The fields of the
+  enclosing spans get hoisted up so that each line says which session
+  (`session`) and client connection (`conn_id`) it is about, and which
+  part of the daemon (`component`) it came from, which makes it possible
+  to pull the story of a single session out of a busy daemon's logs
+  with something like `jq 'select(.session == "main")'`.
+*/
+
+use std::{fmt, io, sync::Mutex};
+
+use serde_json::{Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// A layer which writes events as JSON lines to the given writer.
+pub struct JsonLayer<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: io::Write> JsonLayer<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLayer { writer: Mutex::new(writer) }
+    }
+}
+
+/// The recorded fields of a span, stashed in the span's extensions.
+#[derive(Default)]
+struct Fields(Map<String, Value>);
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(String::from(field.name()), Value::from(format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(String::from(field.name()), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(String::from(field.name()), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(String::from(field.name()), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(String::from(field.name()), Value::from(value));
+    }
+}
+
+/// The short span field names used throughout the daemon, and the
+/// names they get in the JSON output.
+fn context_key(field: &str) -> Option<&'static str> {
+    match field {
+        "s" => Some("session"),
+        "cid" => Some("conn_id"),
+        _ => None,
+    }
+}
+
+impl<S, W> Layer<S> for JsonLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: io::Write + 'static,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        let mut line = Map::new();
+        line.insert(
+            String::from("timestamp"),
+            Value::from(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true)),
+        );
+        line.insert(String::from("level"), Value::from(meta.level().as_str()));
+        line.insert(
+            String::from("thread"),
+            Value::from(format!("{:?}", std::thread::current().id())),
+        );
+        line.insert(String::from("target"), Value::from(meta.target()));
+
+        // Hoist the fields of the enclosing spans, outermost first so
+        // that inner spans win if they set the same field.
+        let mut fields = Map::new();
+        let mut component = None;
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                component = Some(span.name());
+                if let Some(span_fields) = span.extensions().get::<Fields>() {
+                    for (k, v) in span_fields.0.iter() {
+                        match context_key(k) {
+                            Some(key) => line.insert(String::from(key), v.clone()),
+                            None => fields.insert(k.clone(), v.clone()),
+                        };
+                    }
+                }
+            }
+        }
+        let component =
+            component.unwrap_or_else(|| meta.target().rsplit("::").next().unwrap_or(meta.target()));
+        line.insert(String::from("component"), Value::from(component));
+
+        let mut event_fields = Fields::default();
+        event.record(&mut event_fields);
+        let message = event_fields.0.remove("message").unwrap_or(Value::from(""));
+        line.insert(String::from("message"), message);
+        fields.extend(event_fields.0);
+        if !fields.is_empty() {
+            line.insert(String::from("fields"), Value::Object(fields));
+        }
+
+        // we unwrap to propagate the poison as an unwind
+        let mut writer = self.writer.lock().unwrap();
+        if serde_json::to_writer(&mut *writer, &line).is_ok() {
+            let _ = writer.write_all(b"\n");
+            let _ = writer.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+    use std::sync::Arc;
+    use tracing::{info, span, Level};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn session_context() -> anyhow::Result<()> {
+        let buf = SharedBuf::default();
+        let subscriber = tracing_subscriber::registry().with(JsonLayer::new(buf.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let _conn = span!(Level::INFO, "handle_conn", cid = 7).entered();
+            let _reader = span!(Level::INFO, "reader", s = "main").entered();
+            info!(len = 3, "read chunk");
+        });
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone())?;
+        let line: Value = serde_json::from_str(out.trim())?;
+        assert_eq!(line["session"], "main");
+        assert_eq!(line["conn_id"], 7);
+        assert_eq!(line["component"], "reader");
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "read chunk");
+        assert_eq!(line["fields"]["len"], 3);
+
+        Ok(())
+    }
+}
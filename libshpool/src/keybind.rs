@@ -0,0 +1,141 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fs, io, path::PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+
+use super::{
+    config,
+    daemon::keybindings,
+    protocol,
+    protocol::{ConnectHeader, KeybindOp, KeybindReply, KeybindRequest},
+    KeybindCommands,
+};
+
+pub fn run(
+    config_file: Option<String>,
+    command: KeybindCommands,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let (sessions, persist, op) = match command {
+        KeybindCommands::Add { sessions, persist, binding, action } => {
+            (sessions, persist, KeybindOp::Add { binding, action })
+        }
+        KeybindCommands::Remove { sessions, persist, binding } => {
+            (sessions, persist, KeybindOp::Remove { binding })
+        }
+        KeybindCommands::List { sessions } => (sessions, false, KeybindOp::List),
+    };
+    if persist && !sessions.is_empty() {
+        eprintln!("shpool: --persist applies to all sessions, it can't be combined with --session");
+        bail!("--persist used with --session");
+    }
+
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require_capability("keybind")?;
+    client
+        .write_connect_header(ConnectHeader::Keybind(KeybindRequest { sessions, op: op.clone() }))
+        .context("writing keybind request header")?;
+    let reply: KeybindReply = client.read_reply().context("reading reply")?;
+
+    if let Some(err) = reply.error {
+        eprintln!("shpool: {}", err);
+        return Err(anyhow!("{}", err));
+    }
+
+    if let KeybindOp::List = op {
+        println!("SESSION\tBINDING\tACTION");
+        for session in reply.sessions.iter() {
+            for (binding, action) in session.bindings.iter() {
+                println!("{}\t{}\t{}", session.name, binding, action);
+            }
+        }
+    }
+
+    if !reply.not_found_sessions.is_empty() {
+        eprintln!("not found: {}", reply.not_found_sessions.join(" "));
+        return Err(anyhow!("not found: {}", reply.not_found_sessions.join(" ")));
+    }
+
+    if persist {
+        let res = match op {
+            KeybindOp::Add { binding, action } => {
+                persist_change(config_file, &binding, Some(&action))
+            }
+            KeybindOp::Remove { binding } => persist_change(config_file, &binding, None),
+            KeybindOp::List => Ok(()),
+        };
+        if let Err(e) = res {
+            eprintln!("shpool: could not update config file: {:#}", e);
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Write a keybinding change back to the config file, preserving the
+/// rest of the file as is. An action of None removes the binding.
+fn persist_change(
+    config_file: Option<String>,
+    binding: &str,
+    action: Option<&str>,
+) -> anyhow::Result<()> {
+    let config_path = match config_file {
+        Some(p) => PathBuf::from(p),
+        None => config::default_path()?,
+    };
+    let config_str = match fs::read_to_string(&config_path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context("reading config file"),
+    };
+    let mut doc: toml_edit::Document = config_str.parse().context("parsing config file")?;
+
+    let tables = doc
+        .entry("keybinding")
+        .or_insert(toml_edit::Item::ArrayOfTables(toml_edit::ArrayOfTables::new()))
+        .as_array_of_tables_mut()
+        .ok_or(anyhow!("'keybinding' in the config file is not an array of tables"))?;
+    tables.retain(|t| {
+        !t.get("binding")
+            .and_then(|b| b.as_str())
+            .map(|b| keybindings::same_binding(b, binding))
+            .unwrap_or(false)
+    });
+    if let Some(action) = action {
+        let mut table = toml_edit::Table::new();
+        table.insert("binding", toml_edit::value(binding));
+        table.insert("action", toml_edit::value(action));
+        tables.push(table);
+    }
+
+    if let Some(dir) = config_path.parent() {
+        fs::create_dir_all(dir).context("creating config dir")?;
+    }
+    fs::write(&config_path, doc.to_string()).context("writing config file")?;
+
+    Ok(())
+}
@@ -48,6 +48,10 @@ where
         eprintln!("not found: {}", reply.not_found_sessions.join(" "));
         return Err(anyhow!("not found: {}", reply.not_found_sessions.join(" ")));
     }
+    if !reply.forbidden_sessions.is_empty() {
+        eprintln!("forbidden: {}", reply.forbidden_sessions.join(" "));
+        return Err(anyhow!("forbidden: {}", reply.forbidden_sessions.join(" ")));
+    }
 
     Ok(())
 }
@@ -17,38 +17,89 @@ use std::{
     env, fs,
     hash::{Hash, Hasher},
     io,
-    path::PathBuf,
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
     sync::Mutex,
+    time,
 };
 
-use anyhow::{anyhow, Context};
-use clap::{Parser, Subcommand};
+use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
+pub use config::Config;
+pub use daemon::{CustomAction, Daemon, DaemonBuilder};
 pub use hooks::Hooks;
+
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub use daemon::fuzz as fuzz_protocol;
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub use daemon::keybindings::fuzz as fuzz_keybindings;
 use tracing::error;
-use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::{
+    filter::LevelFilter, fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt,
+};
 
+mod acl;
 mod attach;
+mod audit;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+mod capture;
+mod clear;
 mod common;
+mod completion;
 mod config;
 mod consts;
+mod create;
 mod daemon;
 mod detach;
+mod doctor;
 mod duration;
+mod events;
+mod gc;
+mod getenv;
 mod hooks;
+mod job;
+mod json_log;
+mod keybind;
 mod kill;
 mod list;
+mod local_edit;
+mod metrics;
+mod paste;
+mod paths;
 mod protocol;
+pub mod pty;
+mod record;
+mod reset;
+mod restart;
+mod resurrect;
+mod roam;
+mod save_output;
+mod search;
+mod self_update;
+mod send;
+mod setenv;
+mod stop;
 mod test_hooks;
+mod timing;
+mod tls;
 mod tty;
+mod tunnel;
 mod user;
+mod version;
+mod wait;
 
 /// The command line arguments that shpool expects.
 /// These can be directly parsed with clap or manually
 /// constructed in order to present some other user
 /// interface.
 ///
-/// NOTE: You must check `version()` and handle it yourself
-/// if it is set. Clap won't do a good job with its
+/// NOTE: You must check `version()` and print your own version
+/// if it is set, then pass the args along to `run` so that it can
+/// report on the daemon. Clap won't do a good job with its
 /// automatic version support for a library.
 #[derive(Parser, Debug)]
 #[clap(author, about)]
@@ -78,14 +129,37 @@ running in daemon mode, the logs will go to stderr by default."
         action,
         long_help = "The path for the unix socket to listen on
 
-This defaults to $XDG_RUNTIME_DIR/shpool/shpool.socket or ~/.shpool/shpool.socket
-if XDG_RUNTIME_DIR is unset.
+This defaults to shpool.socket in the runtime dir, which is
+$XDG_RUNTIME_DIR/shpool, or $XDG_STATE_HOME/shpool/run if XDG_RUNTIME_DIR
+is unset, unless paths.runtime_dir in the config says otherwise. If the
+default socket is missing, clients fall back to the socket of a daemon
+started by an older shpool in ~/.shpool/shpool, then to
+/run/shpool/shpool.socket, where a multi-user daemon listens.
+
+If unset, $SHPOOL_SOCKET is used instead, which the daemon sets in the
+sessions it spawns so that shpool commands run inside a session reach
+the daemon it belongs to.
 
 This flag gets overridden by systemd socket activation when
-the daemon is launched by systemd."
+the daemon is launched by systemd.",
+        global = true
     )]
     pub socket: Option<String>,
 
+    #[clap(
+        long,
+        action,
+        conflicts_with = "socket",
+        long_help = "The name of the daemon instance to use
+
+Each named instance is a separate daemon, with its own sessions, that
+keeps its socket and runtime data in instances/<name> in the runtime
+dir. This makes it easy to run several daemons side by side, such as
+one per project. If unset, $SHPOOL_INSTANCE is used instead.",
+        global = true
+    )]
+    pub instance: Option<String>,
+
     #[clap(short, long, action, help = "a toml file containing configuration")]
     pub config_file: Option<String>,
 
@@ -96,22 +170,52 @@ the daemon is launched by systemd."
 /// The subcommds that shpool supports.
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    #[clap(about = "Print version")]
+    #[clap(about = "Print the version of the client and the running daemon")]
     Version,
 
-    #[clap(about = "Starts running a daemon that holds a pool of shells")]
-    Daemon,
+    #[clap(about = "Starts running a daemon that holds a pool of shells
+
+If another daemon is already listening on the socket, the new one
+gives up, unless --takeover is given, in which case the running daemon
+gets asked to shut down and hand its sessions over. A socket left
+behind by a daemon that is no longer running just gets replaced.
+
+With --multi-user, runs as root on behalf of every user on the machine,
+listening on /run/shpool/shpool.socket unless --socket says otherwise.
+Each user's connections get handed to a daemon of their own, which runs
+as them.
+
+With a subcommand, manages a running daemon instead.")]
+    Daemon {
+        #[clap(long, help = "Replace a daemon that is already running, keeping its sessions")]
+        takeover: bool,
+        #[clap(
+            long,
+            conflicts_with = "takeover",
+            help = "Serve every user on the machine from a daemon running as root"
+        )]
+        multi_user: bool,
+        #[clap(subcommand)]
+        command: Option<DaemonCommands>,
+    },
 
     #[clap(about = "Creates or attaches to an existing shell session")]
     Attach {
         #[clap(short, long, help = "If a tty is already attached to the session, detach it first")]
         force: bool,
+        #[clap(
+            long,
+            conflicts_with = "force",
+            help = "Create the session without attaching to it, like `shpool new`"
+        )]
+        create_only: bool,
         #[clap(
             long,
             long_help = "Automatically kill the session after the given time
 
 This option only applies when first creating a session, it is ignored on
-reattach.
+reattach. It overrides session_ttl from the config, but can't go past
+max_session_ttl.
 
 The duration can be specified either in a colon seperated format
 of the form dd:hh:mm:ss where any prefix may be left off (i.e. '01:00:30:00'
@@ -129,8 +233,235 @@ The command is broken up into a binary to invoke and a list of arguments to
 pass to the binary using the shell-words crate."
         )]
         cmd: Option<String>,
+        #[clap(
+            long,
+            value_delimiter = ',',
+            long_help = "Additional environment variables to forward to the session
+
+These are added to the `forward_env` list from the config file. Each
+entry is either the exact name of a variable or a prefix followed by
+a '*' (i.e. 'LC_*') to forward every variable with that prefix.
+Forwarded variables are set in the environment of freshly created
+sessions, and on reattach they are written out to the
+$SHPOOL_SESSION_DIR/forward_env file so that a running shell can
+pick them up by sourcing it."
+        )]
+        forward_env: Vec<String>,
+        #[clap(
+            long,
+            value_name = "PATH",
+            long_help = "The directory to start the session in, rather than $HOME
+
+Like --ttl, this only applies when first creating a session."
+        )]
+        cwd: Option<String>,
+        #[clap(
+            long,
+            value_name = "TERM",
+            long_help = "The TERM to start the session with, rather than this terminal's
+
+This overrides both the TERM of the terminal running `shpool attach`
+and any TERM set in the env table of the config file. Like --ttl,
+this only applies when first creating a session."
+        )]
+        term: Option<String>,
+        #[clap(
+            long,
+            value_name = "RUNTIME:ID",
+            conflicts_with = "nsenter_pid",
+            long_help = "A running container to start the session in, rather than the host
+
+The container is given as docker:<id> or podman:<id>, and the session's
+shell is started inside the container's namespaces, so that it lives in
+the container but can be reattached like any other session. The shell
+has to exist inside the container, and --cwd is a path inside it. Like
+--ttl, this only applies when first creating a session."
+        )]
+        container: Option<String>,
+        #[clap(
+            long,
+            value_name = "PID",
+            long_help = "A process whose namespaces to start the session in
+
+This works like --container, for a container runtime shpool does not
+know about, or any other process living in its own namespaces."
+        )]
+        nsenter_pid: Option<i32>,
+        #[clap(
+            long,
+            conflicts_with_all = ["create_only", "replay_lines", "replay_all"],
+            help = "Don't redraw any of the session's output on reattach"
+        )]
+        no_replay: bool,
+        #[clap(
+            long,
+            value_name = "N",
+            conflicts_with_all = ["create_only", "replay_all"],
+            long_help = "Redraw up to the last N lines of the session's output on reattach
+
+This overrides session_restore_mode from the config file for this
+attach only, and can reach back into the scrollback kept by the
+screen and lines modes."
+        )]
+        replay_lines: Option<u16>,
+        #[clap(
+            long,
+            conflicts_with = "create_only",
+            help = "Redraw all of the session's scrollback on reattach"
+        )]
+        replay_all: bool,
+        #[clap(
+            long,
+            value_name = "CMD",
+            conflicts_with = "create_only",
+            long_help = "A command to type into the session's shell on reattach
+
+This overrides on_attach_cmd from the config file for this attach only.
+Like on_attach_cmd, it only gets typed if the shell is sitting at its
+prompt, unless on_attach_cmd_always is set."
+        )]
+        on_attach_cmd: Option<String>,
+        #[clap(
+            long,
+            conflicts_with = "create_only",
+            long_help = "Start the session's command over if it has exited
+
+Normally, attaching to a session whose shell or command exited while
+nothing was attached prints what it left on the screen and exits with
+its exit status. With --restart, the session gets started over with
+the same command instead, or with the one given by --cmd."
+        )]
+        restart: bool,
+        #[clap(
+            long,
+            conflicts_with = "create_only",
+            long_help = "Print how long each phase of the attach took once it is over
+
+This times connecting to the daemon, the protocol handshake, finding
+or spawning the session and replaying its output, which helps track
+down why an attach is slow. The same timings go to the debug logs of
+the client and the daemon either way."
+        )]
+        time: bool,
+        #[clap(
+            short,
+            long,
+            conflicts_with = "create_only",
+            help = "Don't print the attach_banner from the config file"
+        )]
+        quiet: bool,
         #[clap(help = "The name of the shell session to create or attach to")]
         name: String,
+        #[clap(
+            last = true,
+            conflicts_with = "cmd",
+            long_help = "A command and arguments to run instead of the user's default shell
+
+Everything after a '--' is passed through as is, without any shell-words
+splitting (i.e. `shpool attach build -- cargo build --release`). Like
+--cmd, this only applies when first creating a session. When the command
+exits, the attached client exits with the same status."
+        )]
+        argv: Vec<String>,
+    },
+
+    #[clap(about = "Creates a new session without attaching to it
+
+The session starts out just like one that has been detached from, and
+shpool exits as soon as it is running, which makes this handy for
+kicking off background jobs from scripts (i.e.
+`shpool new --name build -- make -j`). Fails if a session with the
+given name is already running.")]
+    New {
+        #[clap(short, long, help = "The name of the session to create")]
+        name: String,
+        #[clap(
+            long,
+            help = "Automatically kill the session after the given time, see attach --ttl"
+        )]
+        ttl: Option<String>,
+        #[clap(
+            short,
+            long,
+            help = "A command to run instead of the user's default shell, see attach --cmd"
+        )]
+        cmd: Option<String>,
+        #[clap(
+            long,
+            value_delimiter = ',',
+            help = "Additional environment variables to forward to the session"
+        )]
+        forward_env: Vec<String>,
+        #[clap(
+            long,
+            value_name = "PATH",
+            help = "The directory to start the session in, rather than $HOME"
+        )]
+        cwd: Option<String>,
+        #[clap(
+            long,
+            value_name = "TERM",
+            help = "The TERM to start the session with, see attach --term"
+        )]
+        term: Option<String>,
+        #[clap(
+            long,
+            value_name = "RUNTIME:ID",
+            conflicts_with = "nsenter_pid",
+            help = "A running container to start the session in, see attach --container"
+        )]
+        container: Option<String>,
+        #[clap(
+            long,
+            value_name = "PID",
+            help = "A process whose namespaces to start the session in, see attach --nsenter-pid"
+        )]
+        nsenter_pid: Option<i32>,
+        #[clap(
+            last = true,
+            conflicts_with = "cmd",
+            help = "A command and arguments to run instead of the user's default shell"
+        )]
+        argv: Vec<String>,
+    },
+
+    #[clap(about = "Wait for a session to exit
+
+Blocks until the shell or command running in the session exits, then
+exits with the same status. Any number of waiters can wait on the
+same session.")]
+    Wait {
+        #[clap(help = "The session to wait for")]
+        session: String,
+    },
+
+    #[clap(about = "Stream session lifecycle events
+
+Prints a line of json for each session that gets created, attached,
+detached or exits, and for each bell, notification, silence or
+activity alert, as it happens. Handy for feeding a status bar like
+waybar or polybar without polling `shpool list`.")]
+    Events,
+
+    #[clap(about = "Type input into a session without attaching to it
+
+The input goes to the session's shell exactly as if it had been typed
+into an attached terminal, so it is interpreted by whatever is running
+in the foreground. Pass --enter to hit enter after the input.")]
+    Send {
+        #[clap(help = "The session to send input to")]
+        session: String,
+        #[clap(
+            long,
+            required_unless_present = "stdin",
+            conflicts_with = "stdin",
+            help = "The text to send"
+        )]
+        text: Option<String>,
+        #[clap(long, help = "Send everything read from stdin")]
+        stdin: bool,
+        #[clap(long, help = "Send a carriage return after the input")]
+        enter: bool,
     },
 
     #[clap(about = "Make the given session detach from shpool
@@ -143,6 +474,30 @@ environment.")]
         sessions: Vec<String>,
     },
 
+    #[clap(about = "Put the terminal state of the given sessions back to defaults
+
+This recovers a session garbled by something like cat'ing a binary
+without killing it. Scrollback is left alone. If no session name is
+provided $SHPOOL_SESSION_NAME will be used if it is present in the
+environment.")]
+    Reset {
+        #[clap(help = "sessions to reset")]
+        sessions: Vec<String>,
+    },
+
+    #[clap(about = "Wipe the scrollback of the given sessions
+
+This throws away the scrollback and screen contents the daemon keeps for
+the sessions, along with the raw output `shpool capture --bytes` shows,
+so that something like an accidentally cat'ed secret does not get
+replayed on the next attach. The terminal of an attached client gets
+cleared too. Recordings are left alone. If no session name is provided
+$SHPOOL_SESSION_NAME will be used if it is present in the environment.")]
+    Clear {
+        #[clap(help = "sessions to clear")]
+        sessions: Vec<String>,
+    },
+
     #[clap(about = "Kill the given sessions
 
 This detaches the session if it is attached and kills the underlying
@@ -155,12 +510,502 @@ will be used if it is present in the environment.")]
     },
 
     #[clap(about = "lists all the running shell sessions")]
+    List {
+        #[clap(long, hide = true, help = "Only print session names, for the completion scripts")]
+        names_only: bool,
+    },
+
+    #[clap(about = "Clean up runtime data left behind by sessions that no longer exist
+
+Each session gets a directory under the runtime dir for things like the
+SSH_AUTH_SOCK symlink and the forwarded environment, which sticks around
+after the session exits or gets killed.")]
+    Gc {
+        #[clap(long, help = "Report what would be removed without removing anything")]
+        dry_run: bool,
+    },
+
+    #[clap(about = "Check for common problems with the shpool setup
+
+Looks at the config, the daemon, TERM, the systemd units and the pty
+limits, and suggests a fix for anything that looks wrong.")]
+    Doctor,
+
+    #[clap(about = "Dump the daemon metrics in the Prometheus text format
+
+To have Prometheus scrape them directly, set metrics_listener in the
+config instead.")]
+    Metrics,
+
+    #[clap(about = "Print the last thing copied in copy mode
+
+Copy mode gets bound to a key with the copy-mode keybinding action.
+The paste buffers are shared by all sessions and last as long as the
+daemon does.")]
+    Paste {
+        #[clap(long, short, help = "The paste buffer to print instead of the newest one")]
+        buffer: Option<String>,
+    },
+
+    #[clap(about = "Fill a paste buffer from a file or stdin
+
+Without --buffer, the text goes in a fresh automatically named
+buffer, just like text copied in copy mode does.")]
+    LoadBuffer {
+        #[clap(long, short, help = "The paste buffer to fill")]
+        buffer: Option<String>,
+        #[clap(help = "The file to load, or - for stdin (the default)")]
+        file: Option<PathBuf>,
+    },
+
+    #[clap(about = "Type a paste buffer into a session
+
+The text goes to the session's shell just as if it had been pasted
+into an attached terminal. If no session name is provided
+$SHPOOL_SESSION_NAME will be used if it is present in the
+environment.")]
+    PasteBuffer {
+        #[clap(long, help = "The session to paste into")]
+        session: Option<String>,
+        #[clap(long, short, help = "The paste buffer to paste instead of the newest one")]
+        buffer: Option<String>,
+    },
+
+    #[clap(about = "Save the scrollback of a session to a file
+
+Saves everything the daemon has kept of the session's output (see
+output_spool_lines), which lets you capture the output of a long job
+after the fact. Nothing is kept with session_restore_mode = \"simple\".")]
+    SaveOutput {
+        #[clap(long, help = "Save just the text, without colors or other escape sequences")]
+        strip_ansi: bool,
+        #[clap(help = "The session to save the output of")]
+        session: String,
+        #[clap(help = "The file to write the output to")]
+        file: String,
+    },
+
+    #[clap(about = "Print what a session is displaying right now
+
+By default this prints the rows currently on the session's screen as
+plain text, which needs the screen the daemon keeps for each session, so
+it does not work with session_restore_mode = \"simple\". --bytes prints
+the most recent raw output instead, which works in any mode.")]
+    Capture {
+        #[clap(long, conflicts_with = "bytes", help = "Keep colors and other escape sequences")]
+        ansi: bool,
+        #[clap(
+            long,
+            value_name = "N",
+            help = "Print the last N raw bytes of output (at most 64KiB) instead of the screen"
+        )]
+        bytes: Option<usize>,
+        #[clap(help = "The session to capture")]
+        session: String,
+    },
+
+    #[clap(about = "Search a session's scrollback
+
+Prints the lines of the session's scrollback, as kept by the daemon,
+that match the given regex, much like grep would, and exits with status
+1 if none do. This needs the screen the daemon keeps for each session,
+so it does not work with session_restore_mode = \"simple\".")]
+    Search {
+        #[clap(short, long, help = "Match without regard to case")]
+        ignore_case: bool,
+        #[clap(
+            short = 'C',
+            long,
+            value_name = "N",
+            default_value = "0",
+            help = "Print N lines of context around each match"
+        )]
+        context: usize,
+        #[clap(short = 'n', long, help = "Prefix each line with its line number")]
+        line_number: bool,
+        #[clap(help = "The session to search")]
+        session: String,
+        #[clap(help = "The regex to search for")]
+        pattern: String,
+    },
+
+    #[clap(about = "Start or stop recording what a session displays
+
+Recordings are asciicast v2 files, which `asciinema play` can replay.
+They only contain output, not what gets typed. By default they go in
+the recordings dir in the state dir, or the dir set with recording.dir
+in the config.")]
+    Record {
+        #[clap(help = "The session to record")]
+        session: String,
+        #[clap(subcommand)]
+        command: RecordCommands,
+    },
+
+    #[clap(about = "Start up the saved sessions which are not running
+
+When resurrect is set in the config, the daemon saves the name, command,
+starting directory and some of the environment of each new session, and
+forgets them once the session is killed or exits. This starts a fresh
+session for each one left over, for example after a reboot. What was
+running in the old sessions does not come back.")]
+    Resurrect,
+
+    #[clap(about = "Show or change who may use a session
+
+By default every peer the daemon lets in has full access to every
+session. Once a session has an acl, the users on it get the access it
+grants them, and other users can't attach, send input or capture
+output. The user running the daemon always has full access, and is the
+only one who can change acls. Acls are a way to share a session with a
+coworker, not a security boundary between untrusted users.")]
+    Acl {
+        #[clap(help = "The session to show or change the acl of")]
+        session: String,
+        #[clap(subcommand)]
+        command: AclCommands,
+    },
+
+    #[clap(about = "Set environment variables for a running session
+
+There is no way to change the environment of a shell that is already
+running, so this updates the daemon's record of the session's
+environment, which gets written to $SHPOOL_SESSION_DIR/forward_env.
+Source that file in the session (for example from PROMPT_COMMAND) to
+pick up the new values. Variables forwarded by a later attach replace
+the ones set here.")]
+    Setenv {
+        #[clap(long, value_delimiter = ',', help = "Variables to unset")]
+        unset: Vec<String>,
+        #[clap(help = "The session to set the variables for")]
+        session: String,
+        #[clap(help = "The variables to set, as KEY=VALUE")]
+        vars: Vec<String>,
+    },
+
+    #[clap(about = "Print the daemon's record of a session's environment
+
+Prints every variable as KEY=VALUE, with variables that have been
+unset printed as -KEY. Given a variable name, prints just its value.")]
+    Getenv {
+        #[clap(help = "The session to print the environment of")]
+        session: String,
+        #[clap(help = "A single variable to print")]
+        var: Option<String>,
+    },
+
+    #[clap(about = "List or edit the keybindings of running sessions
+
+Changes apply immediately, even to sessions with a terminal attached.
+By default changes apply to all running sessions, and they only last
+as long as the session does unless --persist is given.")]
+    Keybind {
+        #[clap(subcommand)]
+        command: KeybindCommands,
+    },
+
+    #[clap(about = "Run more than one shell in a session
+
+Jobs are extra shells that live alongside a session's own shell, each
+with a pty of its own. The terminal attached to a session can be
+switched between its jobs with `shpool job switch` or a key bound to
+the next-job action. A job is a session named <session>:<job>, so it
+also shows up in `shpool list` and can be attached to directly. Killing
+a session kills its jobs along with it.")]
+    Job {
+        #[clap(long, help = "The session to manage the jobs of, defaults to $SHPOOL_SESSION_NAME")]
+        session: Option<String>,
+        #[clap(subcommand)]
+        command: JobCommands,
+    },
+
+    #[clap(about = "Reach a daemon on another machine over mutual TLS
+
+Listens on the local socket (see --socket) and forwards each connection
+to the tcp_listener of a remote daemon, so the other commands can be
+pointed at the remote daemon with --socket.")]
+    Tunnel {
+        #[clap(help = "The host:port of the remote daemon's tcp_listener")]
+        addr: String,
+        #[clap(long, help = "A PEM file with the client certificate chain to present")]
+        cert: String,
+        #[clap(long, help = "A PEM file with the private key for --cert")]
+        key: String,
+        #[clap(
+            long,
+            help = "A PEM file with the CA certificates the daemon's certificate must chain up to"
+        )]
+        ca: String,
+        #[clap(
+            long = "pin",
+            help = "A sha256 fingerprint the daemon's certificate must have, may be repeated"
+        )]
+        pins: Vec<String>,
+        #[clap(
+            long,
+            help = "The name to check the daemon's certificate against, defaults to the host in ADDR"
+        )]
+        server_name: Option<String>,
+    },
+
+    #[clap(about = "Attach to a session on another machine over udp (experimental)
+
+Starts `shpool roam-server` on the host over ssh, which attaches to the
+session there and then talks to this client directly over authenticated
+udp datagrams rather than through ssh. The connection survives the
+client changing networks or going to sleep, and rather than replaying
+everything it missed, a client that was out of touch just catches up to
+the current screen. The host has to let udp through on one of the
+--ports. Press Ctrl-^ . to detach.")]
+    Roam {
+        #[clap(long, default_value = "ssh", help = "The ssh command to reach the host with")]
+        ssh: String,
+        #[clap(long, default_value = "shpool", help = "The shpool command to run on the host")]
+        remote_shpool: String,
+        #[clap(
+            long,
+            help = "The udp ports the server may listen on, like 60001:60999 (the default)"
+        )]
+        ports: Option<String>,
+        #[clap(
+            long,
+            help = "How long the server waits to hear from this client before detaching, 1d by default"
+        )]
+        idle_timeout: Option<String>,
+        #[clap(help = "The host to reach over ssh, like user@host")]
+        host: String,
+        #[clap(help = "The session on the host to attach to")]
+        remote_session: String,
+    },
+
+    #[clap(hide = true, about = "The far end of `shpool roam`, run over ssh")]
+    RoamServer {
+        #[clap(long, help = "The rows of the client's terminal")]
+        rows: u16,
+        #[clap(long, help = "The columns of the client's terminal")]
+        cols: u16,
+        #[clap(long, help = "The TERM of the client's terminal")]
+        term: Option<String>,
+        #[clap(long, default_value = roam::DEFAULT_PORTS, help = "The udp ports to try")]
+        ports: String,
+        #[clap(
+            long,
+            default_value = roam::DEFAULT_IDLE_TIMEOUT,
+            help = "How long to wait to hear from the client before detaching"
+        )]
+        idle_timeout: String,
+        #[clap(help = "The session to attach to")]
+        name: String,
+    },
+
+    #[clap(about = "Decrypt and print a session recording
+
+Recordings are written when the daemon is configured with session_audit.
+The key is fetched by running the key_cmd from the config.")]
+    AuditDump {
+        #[clap(help = "The recording to print")]
+        file: String,
+    },
+
+    #[clap(about = "Print a completion script for the given shell
+
+The script completes subcommands and flags, as well as the names of
+running sessions for commands like attach and kill. For bash, add
+`source <(shpool completion bash)` to your .bashrc. For zsh, save the
+output as _shpool somewhere on your $fpath. For fish, save it to
+~/.config/fish/completions/shpool.fish.")]
+    Completion {
+        #[clap(help = "The shell to print the script for")]
+        shell: CompletionShell,
+    },
+
+    #[clap(about = "Update shpool to the latest release
+
+Checks the release manifest at the self_update url from the config, and
+if there is a newer version, downloads it, verifies its signature with
+the configured public key and replaces the running binary with it.")]
+    SelfUpdate {
+        #[clap(long, help = "Only report whether there is a newer version")]
+        check: bool,
+        #[clap(long, help = "Install the latest release even if it is not newer")]
+        force: bool,
+        #[clap(long, help = "Restart a running daemon onto the new version, keeping its sessions")]
+        restart_daemon: bool,
+    },
+}
+
+/// The subcommands of `shpool keybind`.
+#[derive(Subcommand, Debug)]
+pub enum KeybindCommands {
+    #[clap(about = "Bind a key sequence to an action")]
+    Add {
+        #[clap(long = "session", help = "A session to bind the keys in, may be repeated")]
+        sessions: Vec<String>,
+        #[clap(long, help = "Also save the binding to the config file")]
+        persist: bool,
+        #[clap(help = "The key sequence to bind (i.e. 'Ctrl-Space Ctrl-k')")]
+        binding: String,
+        #[clap(help = "The action to perform, one of detach, kill or noop")]
+        action: String,
+    },
+
+    #[clap(about = "Remove the binding for a key sequence")]
+    Remove {
+        #[clap(long = "session", help = "A session to unbind the keys in, may be repeated")]
+        sessions: Vec<String>,
+        #[clap(long, help = "Also remove the binding from the config file")]
+        persist: bool,
+        #[clap(help = "The key sequence to unbind")]
+        binding: String,
+    },
+
+    #[clap(about = "List the keybindings in effect for each session")]
+    List {
+        #[clap(long = "session", help = "A session to list bindings for, may be repeated")]
+        sessions: Vec<String>,
+    },
+}
+
+/// The subcommands of `shpool job`.
+#[derive(Subcommand, Debug)]
+pub enum JobCommands {
+    #[clap(about = "Start a new job and switch the attached terminal to it")]
+    New {
+        #[clap(short, long, help = "Leave the attached terminal where it is")]
+        background: bool,
+        #[clap(short, long, help = "A command to run instead of the user's default shell")]
+        cmd: Option<String>,
+        #[clap(help = "The name of the job")]
+        job: String,
+    },
+
+    #[clap(about = "Switch the attached terminal to another job
+
+The session's own shell is switched to by giving the session's name.")]
+    Switch {
+        #[clap(help = "The name of the job to switch to")]
+        job: String,
+    },
+
+    #[clap(about = "List the jobs of the session")]
     List,
 }
 
+/// The subcommands of `shpool record`.
+#[derive(Subcommand, Debug)]
+pub enum RecordCommands {
+    #[clap(about = "Start recording the session")]
+    Start {
+        #[clap(short, long, help = "The file to record to, which must not exist yet")]
+        file: Option<String>,
+    },
+
+    #[clap(about = "Stop recording the session")]
+    Stop,
+}
+
+/// The subcommands of `shpool acl`.
+#[derive(Subcommand, Debug)]
+pub enum AclCommands {
+    #[clap(about = "Show the users on the acl and their access")]
+    Show,
+
+    #[clap(about = "Give a user access to the session, read-only by default
+
+Read-only users can attach and watch, but what they type is dropped,
+only the detach, copy-mode and flush-output keybindings work, and they
+can't kill, reset, clear, record or change the env of the session.")]
+    Grant {
+        #[clap(long, help = "Let the user type into the session too")]
+        read_write: bool,
+        #[clap(help = "The user to give access to, by name or uid")]
+        user: String,
+    },
+
+    #[clap(about = "Take away a user's access to the session
+
+Any client the user has attached gets detached.")]
+    Revoke {
+        #[clap(help = "The user to take access away from, by name or uid")]
+        user: String,
+    },
+
+    #[clap(about = "Remove the acl, giving every peer full access again")]
+    Clear,
+}
+
+/// The subcommands of `shpool daemon`.
+#[derive(Subcommand, Debug)]
+pub enum DaemonCommands {
+    #[clap(about = "Stop the running daemon
+
+Any attached clients get told why they are being disconnected. Normally
+the sessions exit along with the daemon, but with --keep-sessions they
+get handed off to a holder process, and the next daemon to start up on
+the same socket picks them back up.")]
+    Stop {
+        #[clap(long, help = "Keep the sessions running for the next daemon to pick up")]
+        keep_sessions: bool,
+    },
+
+    #[clap(about = "Restart the daemon into the shpool binary installed now
+
+The daemon execs whatever binary is installed where it was started
+from, keeping its pid and command line, which makes this the way to
+pick up an upgrade. Normally the sessions exit, but with
+--preserve-sessions they get handed off to a holder process and the
+new daemon picks them back up as it starts.")]
+    Restart {
+        #[clap(long, help = "Keep the sessions running across the restart")]
+        preserve_sessions: bool,
+    },
+
+    #[clap(
+        hide = true,
+        about = "Holds sessions for the next daemon, spawned by stop --keep-sessions"
+    )]
+    Hold {
+        // a different id from the global --socket, which clap would
+        // otherwise mix up with this arg
+        #[clap(
+            id = "hold_socket",
+            value_name = "SOCKET",
+            help = "The socket to wait for the next daemon on"
+        )]
+        socket: PathBuf,
+    },
+
+    #[clap(
+        hide = true,
+        about = "Runs a session's command, restarting it according to its restart_policy"
+    )]
+    Supervise {
+        #[clap(long, help = "Restart after clean exits too")]
+        always: bool,
+        #[clap(long, help = "The wait before the first restart")]
+        backoff_ms: u64,
+        #[clap(long, help = "The longest wait between restarts")]
+        max_backoff_ms: u64,
+        #[clap(long, help = "The most restarts in a row before giving up")]
+        max_restarts: Option<u32>,
+        #[clap(long, help = "The argv[0] to run the command with")]
+        arg0: Option<String>,
+        #[clap(last = true, required = true, help = "The command to run")]
+        argv: Vec<String>,
+    },
+}
+
+/// The shells that `shpool completion` can print scripts for.
+#[derive(ValueEnum, Copy, Clone, Debug)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
 impl Args {
-    /// Version indicates if the wrapping binary must display the
-    /// version then exit.
+    /// Version indicates if the wrapping binary must display its
+    /// version before handing off to `run`.
     pub fn version(&self) -> bool {
         matches!(self.command, Commands::Version)
     }
@@ -169,12 +1014,22 @@ impl Args {
 /// Run the shpool tool with the given arguments. If hooks is provided,
 /// inject the callbacks into the daemon.
 pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> anyhow::Result<()> {
+    run_with_pty_backend(args, hooks, None)
+}
+
+/// Like `run`, but if pty_backend is provided the daemon spawns session
+/// shells with it rather than in real ptys.
+pub fn run_with_pty_backend(
+    args: Args,
+    hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>,
+    pty_backend: Option<Box<dyn pty::PtyBackend + Send + Sync>>,
+) -> anyhow::Result<()> {
     match (&args.command, env::var(consts::SENTINEL_FLAG_VAR).as_deref()) {
-        (Commands::Daemon, Ok("prompt")) => {
+        (Commands::Daemon { command: None, .. }, Ok("prompt")) => {
             println!("{}", consts::PROMPT_SENTINEL);
             std::process::exit(0);
         }
-        (Commands::Daemon, Ok("startup")) => {
+        (Commands::Daemon { command: None, .. }, Ok("startup")) => {
             println!("{}", consts::STARTUP_SENTINEL);
             std::process::exit(0);
         }
@@ -188,23 +1043,35 @@ pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> an
     } else {
         tracing::Level::TRACE
     };
-    if let Some(log_file) = args.log_file.clone() {
-        let file = fs::File::create(log_file)?;
-        tracing_subscriber::fmt()
-            .with_max_level(trace_level)
-            .with_thread_ids(true)
-            .with_target(false)
-            .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-            .with_writer(Mutex::new(file))
-            .init();
-    } else if let Commands::Daemon { .. } = args.command {
-        tracing_subscriber::fmt()
-            .with_max_level(trace_level)
-            .with_thread_ids(true)
-            .with_target(false)
-            .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-            .with_writer(io::stderr)
-            .init();
+    // Some of the config has to be known before it gets loaded for real,
+    // like the log format and where the runtime dir is, so peek at it
+    // now. Any problems with it get reported once it is loaded for real.
+    let early_config =
+        config::load(args.config_file.as_deref()).map(|(config, _)| config).unwrap_or_default();
+
+    let log_writer: Option<Box<dyn io::Write + Send>> = if let Some(log_file) = &args.log_file {
+        Some(Box::new(fs::File::create(log_file)?))
+    } else if let Commands::Daemon { command: None, .. } = args.command {
+        Some(Box::new(io::stderr()))
+    } else {
+        None
+    };
+    if let Some(log_writer) = log_writer {
+        let log_format = early_config.log_format.clone().unwrap_or_default();
+        if log_format == config::LogFormat::Json {
+            tracing_subscriber::registry()
+                .with(json_log::JsonLayer::new(log_writer))
+                .with(LevelFilter::from_level(trace_level))
+                .init();
+        } else {
+            tracing_subscriber::fmt()
+                .with_max_level(trace_level)
+                .with_thread_ids(true)
+                .with_target(false)
+                .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+                .with_writer(Mutex::new(log_writer))
+                .init();
+        }
     }
 
     #[cfg(feature = "test_hooks")]
@@ -218,16 +1085,23 @@ pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> an
         test_hooks::TEST_HOOK_SERVER.wait_for_connect()?;
     }
 
-    let mut runtime_dir = match env::var("XDG_RUNTIME_DIR") {
-        Ok(runtime_dir) => PathBuf::from(runtime_dir),
-        Err(_) => {
-            PathBuf::from(env::var("HOME").context("no XDG_RUNTIME_DIR or HOME")?).join(".shpool")
-        }
-    }
-    .join("shpool");
+    let mut runtime_dir = paths::runtime_dir(&early_config)?;
 
-    let socket = match args.socket {
-        Some(s) => {
+    // Flags win over the environment, so that a command run inside a
+    // session can still reach some other daemon.
+    let from_env = |var| env::var(var).ok().filter(|v: &String| !v.is_empty());
+    let (socket_arg, instance) = match (args.socket, args.instance) {
+        (Some(s), _) => (Some(s), None),
+        (None, Some(i)) => (None, Some(i)),
+        (None, None) => match from_env(consts::SOCKET_VAR) {
+            Some(s) => (Some(s), None),
+            None => (None, from_env(consts::INSTANCE_VAR)),
+        },
+    };
+
+    let socket_given = socket_arg.is_some() || instance.is_some();
+    let socket = match (socket_arg, &instance) {
+        (Some(s), _) => {
             // The user can reasonably expect that if they provide seperate
             // sockets for differnt shpool instances to run on, they won't
             // stomp on one another. To respect this expectation we need to
@@ -240,23 +1114,206 @@ pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> an
 
             PathBuf::from(s)
         }
-        None => runtime_dir.join("shpool.socket"),
+        (None, Some(instance)) => {
+            runtime_dir = paths::instance_runtime_dir(&runtime_dir, instance)?;
+            runtime_dir.join(paths::SOCKET_NAME)
+        }
+        (None, None) => {
+            let socket = runtime_dir.join(paths::SOCKET_NAME);
+            // Clients can still reach a daemon an older shpool started
+            // in the legacy runtime dir, and when a multi-user daemon
+            // serves the machine, they reach their own daemon through
+            // its socket.
+            let serving = matches!(args.command, Commands::Daemon { command: None, .. });
+            let system_socket = Path::new(daemon::multi_user::SYSTEM_SOCKET);
+            if serving || socket.exists() {
+                socket
+            } else if let Some(legacy_socket) = paths::legacy_socket() {
+                legacy_socket
+            } else if system_socket.exists() {
+                system_socket.to_path_buf()
+            } else {
+                socket
+            }
+        }
     };
 
     let res: anyhow::Result<()> = match args.command {
-        Commands::Version => return Err(anyhow!("wrapper binary must handle version")),
-        Commands::Daemon => daemon::run(
-            args.config_file,
-            runtime_dir,
-            hooks.unwrap_or(Box::new(NoopHooks {})),
-            socket,
-        ),
-        Commands::Attach { force, ttl, cmd, name } => {
-            attach::run(args.config_file, name, force, ttl, cmd, socket)
+        Commands::Version => version::run(socket),
+        Commands::Daemon { command: Some(DaemonCommands::Stop { keep_sessions }), .. } => {
+            stop::run(keep_sessions, socket)
+        }
+        Commands::Daemon {
+            command: Some(DaemonCommands::Restart { preserve_sessions }), ..
+        } => restart::run(preserve_sessions, socket),
+        Commands::Daemon { command: Some(DaemonCommands::Hold { socket }), .. } => {
+            daemon::holder::run(socket)
+        }
+        Commands::Daemon {
+            command:
+                Some(DaemonCommands::Supervise {
+                    always,
+                    backoff_ms,
+                    max_backoff_ms,
+                    max_restarts,
+                    arg0,
+                    argv,
+                }),
+            ..
+        } => {
+            let policy = daemon::supervisor::Policy {
+                always,
+                backoff: time::Duration::from_millis(backoff_ms),
+                max_backoff: time::Duration::from_millis(max_backoff_ms),
+                max_restarts,
+            };
+            daemon::supervisor::run(policy, arg0, argv)
+        }
+        Commands::Daemon { multi_user: true, command: None, .. } => {
+            let socket = if socket_given {
+                socket
+            } else {
+                PathBuf::from(daemon::multi_user::SYSTEM_SOCKET)
+            };
+            daemon::multi_user::run(&socket, args.verbose)
+        }
+        Commands::Daemon { takeover, command: None, .. } => {
+            if !socket_given {
+                paths::migrate_legacy(&runtime_dir);
+            }
+            if instance.is_some() {
+                fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(&runtime_dir)
+                    .context("creating instance runtime dir")?;
+            }
+            let mut builder = Daemon::builder()
+                .runtime_dir(runtime_dir)
+                .socket(socket)
+                .takeover(takeover)
+                .pty_backend(pty_backend.unwrap_or_else(default_pty_backend));
+            if let Some(instance) = instance {
+                builder = builder.instance(instance);
+            }
+            if let Some(config_file) = args.config_file {
+                builder = builder.config_file(config_file);
+            }
+            if let Some(hooks) = hooks {
+                builder = builder.hooks(hooks);
+            }
+            builder.build().and_then(Daemon::run)
+        }
+        Commands::Attach {
+            force,
+            create_only,
+            ttl,
+            cmd,
+            forward_env,
+            cwd,
+            term,
+            container,
+            nsenter_pid,
+            no_replay,
+            replay_lines,
+            replay_all,
+            on_attach_cmd,
+            restart,
+            time,
+            quiet,
+            name,
+            argv,
+        } => {
+            let cmd = if argv.is_empty() { cmd } else { Some(shell_words::join(argv)) };
+            let replay = match (no_replay, replay_lines, replay_all) {
+                (true, _, _) => protocol::Replay::Off,
+                (_, Some(n), _) => protocol::Replay::Lines(n),
+                (_, _, true) => protocol::Replay::All,
+                _ => protocol::Replay::Default,
+            };
+            let nsenter_pid = attach::nsenter_pid(container, nsenter_pid)?;
+            let options = attach::options(replay, cwd, term, on_attach_cmd, nsenter_pid, restart)?;
+            if create_only {
+                create::run(args.config_file, name, ttl, cmd, forward_env, options, socket)
+            } else {
+                let request = attach::Request {
+                    force,
+                    ttl,
+                    cmd,
+                    forward_env,
+                    options,
+                    print_timing: time,
+                    quiet,
+                };
+                attach::run(args.config_file, name, request, socket)
+            }
+        }
+        Commands::New { name, ttl, cmd, forward_env, cwd, term, container, nsenter_pid, argv } => {
+            let cmd = if argv.is_empty() { cmd } else { Some(shell_words::join(argv)) };
+            let nsenter_pid = attach::nsenter_pid(container, nsenter_pid)?;
+            let options =
+                attach::options(protocol::Replay::Default, cwd, term, None, nsenter_pid, false)?;
+            create::run(args.config_file, name, ttl, cmd, forward_env, options, socket)
+        }
+        Commands::Wait { session } => wait::run(session, socket),
+        Commands::Events => events::run(socket),
+        Commands::Send { session, text, stdin, enter } => {
+            send::run(session, text, stdin, enter, socket)
         }
         Commands::Detach { sessions } => detach::run(sessions, socket),
         Commands::Kill { sessions } => kill::run(sessions, socket),
-        Commands::List => list::run(socket),
+        Commands::Reset { sessions } => reset::run(sessions, socket),
+        Commands::Clear { sessions } => clear::run(sessions, socket),
+        Commands::List { names_only } => list::run(names_only, socket),
+        Commands::Gc { dry_run } => gc::run(dry_run, socket),
+        Commands::Metrics => metrics::run(socket),
+        Commands::Paste { buffer } => paste::run(buffer, socket),
+        Commands::LoadBuffer { buffer, file } => paste::load_buffer(buffer, file, socket),
+        Commands::PasteBuffer { session, buffer } => paste::paste_buffer(session, buffer, socket),
+        Commands::SaveOutput { strip_ansi, session, file } => {
+            save_output::run(session, file, strip_ansi, socket)
+        }
+        Commands::Capture { ansi, bytes, session } => capture::run(session, ansi, bytes, socket),
+        Commands::Search { ignore_case, context, line_number, session, pattern } => {
+            search::run(session, pattern, ignore_case, context, line_number, socket)
+        }
+        Commands::Record { session, command } => record::run(session, command, socket),
+        Commands::Resurrect => resurrect::run(args.config_file, socket),
+        Commands::Acl { session, command } => acl::run(session, command, socket),
+        Commands::Setenv { unset, session, vars } => setenv::run(session, vars, unset, socket),
+        Commands::Getenv { session, var } => getenv::run(session, var, socket),
+        Commands::Doctor => doctor::run(args.config_file, socket),
+        Commands::Keybind { command } => keybind::run(args.config_file, command, socket),
+        Commands::Job { session, command } => job::run(args.config_file, session, command, socket),
+        Commands::Tunnel { addr, cert, key, ca, pins, server_name } => {
+            tunnel::run(tunnel::Remote { addr, cert, key, ca, pins, server_name }, socket)
+        }
+        Commands::Roam { ssh, remote_shpool, ports, idle_timeout, host, remote_session } => {
+            roam::run(roam::Args {
+                ssh,
+                remote_shpool,
+                ports,
+                idle_timeout,
+                host,
+                session: remote_session,
+            })
+        }
+        Commands::RoamServer { rows, cols, term, ports, idle_timeout, name } => {
+            let args = roam::server::Args {
+                session: name,
+                rows,
+                cols,
+                term,
+                ports: roam::parse_ports(&ports)?,
+                idle_timeout: duration::parse(&idle_timeout).context("parsing --idle-timeout")?,
+            };
+            roam::server::run(args, socket)
+        }
+        Commands::AuditDump { file } => audit::run(args.config_file, file),
+        Commands::Completion { shell } => completion::run(shell),
+        Commands::SelfUpdate { check, force, restart_daemon } => {
+            self_update::run(args.config_file, check, force, restart_daemon, socket)
+        }
     };
 
     if let Err(err) = res {
@@ -267,5 +1324,15 @@ pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> an
     Ok(())
 }
 
+fn default_pty_backend() -> Box<dyn pty::PtyBackend + Send + Sync> {
+    #[cfg(feature = "test_hooks")]
+    if std::env::var("SHPOOL_TEST_PTY_BACKEND").as_deref() == Ok("fake") {
+        log::info!("using the fake pty backend");
+        return Box::<pty::Fake>::default();
+    }
+
+    Box::<pty::Forking>::default()
+}
+
 struct NoopHooks {}
 impl hooks::Hooks for NoopHooks {}
@@ -12,17 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{io, path::PathBuf, time};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    time,
+};
 
 use anyhow::Context;
 
 use super::{
-    protocol,
-    protocol::{ConnectHeader, ListReply},
+    duration, protocol,
+    protocol::{
+        ActivityReply, AlertsReply, ConnectHeader, CwdReply, ListReply, SessionActivity,
+        SessionAlerts,
+    },
 };
 
-pub fn run(socket: PathBuf) -> anyhow::Result<()> {
-    let mut client = match protocol::Client::new(socket) {
+pub fn run(names_only: bool, socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(&socket) {
         Ok(c) => c,
         Err(err) => {
             let io_err = err.downcast::<io::Error>()?;
@@ -36,13 +44,88 @@ pub fn run(socket: PathBuf) -> anyhow::Result<()> {
     client.write_connect_header(ConnectHeader::List).context("sending list connect header")?;
     let reply: ListReply = client.read_reply().context("reading reply")?;
 
-    println!("NAME\tSTARTED_AT\tSTATUS");
+    // This gets run by the completion scripts on every tab press, so
+    // skip the follow up requests and just give the names.
+    if names_only {
+        for session in reply.sessions.iter() {
+            println!("{}", session.name);
+        }
+        return Ok(());
+    }
+
+    let supports = |capability: &str| client.capabilities().iter().any(|c| c == capability);
+    let (supports_alerts, supports_activity, supports_cwd) =
+        (supports("alerts"), supports("activity"), supports("cwd"));
+
+    // Older daemons don't track alerts, activity or cwds, in which case
+    // those columns just stay empty.
+    let mut alerts = HashMap::new();
+    if supports_alerts {
+        let reply: AlertsReply = fetch(&socket, ConnectHeader::Alerts)?;
+        alerts.extend(reply.sessions.into_iter().map(|a| (a.name.clone(), a)));
+    }
+    let mut activity = HashMap::new();
+    if supports_activity {
+        let reply: ActivityReply = fetch(&socket, ConnectHeader::Activity)?;
+        activity.extend(reply.sessions.into_iter().map(|a| (a.name.clone(), a)));
+    }
+    let mut cwds = HashMap::new();
+    if supports_cwd {
+        let reply: CwdReply = fetch(&socket, ConnectHeader::Cwd)?;
+        cwds.extend(reply.sessions.into_iter().filter_map(|c| c.cwd.map(|cwd| (c.name, cwd))));
+    }
+
+    println!("NAME\tSTARTED_AT\tSTATUS\tACTIVITY\tALERTS\tCWD");
     for session in reply.sessions.iter() {
         let started_at =
             time::UNIX_EPOCH + time::Duration::from_millis(session.started_at_unix_ms as u64);
         let started_at = chrono::DateTime::<chrono::Utc>::from(started_at);
-        println!("{}\t{}\t{}", session.name, started_at.to_rfc3339(), session.status);
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            session.name,
+            started_at.to_rfc3339(),
+            session.status,
+            activity.get(&session.name).map(describe_activity).unwrap_or_default(),
+            alerts.get(&session.name).map(describe_alerts).unwrap_or_default(),
+            cwds.get(&session.name).map(String::as_str).unwrap_or_default()
+        );
     }
 
     Ok(())
 }
+
+/// Make a follow up request for the given header on a fresh connection.
+fn fetch<R>(socket: &Path, header: ConnectHeader) -> anyhow::Result<R>
+where
+    R: serde::de::DeserializeOwned,
+{
+    let mut client = protocol::Client::new(socket).context("connecting to daemon")?;
+    client.write_connect_header(header).context("sending connect header")?;
+    client.read_reply().context("reading reply")
+}
+
+fn describe_activity(activity: &SessionActivity) -> String {
+    if activity.active {
+        String::from("active")
+    } else {
+        format!(
+            "silent for {}",
+            duration::format_coarse(time::Duration::from_millis(activity.idle_ms))
+        )
+    }
+}
+
+fn describe_alerts(alerts: &SessionAlerts) -> String {
+    let mut parts = vec![];
+    if alerts.bells > 0 {
+        parts.push(format!("{} bell{}", alerts.bells, if alerts.bells == 1 { "" } else { "s" }));
+    }
+    if alerts.notifications > 0 {
+        parts.push(format!(
+            "{} notification{}",
+            alerts.notifications,
+            if alerts.notifications == 1 { "" } else { "s" }
+        ));
+    }
+    parts.join(", ")
+}
@@ -0,0 +1,187 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Line editing in the client, for links slow enough that waiting on
+  every key to make the round trip to the shell and back makes typing
+  miserable.
+
+  While the daemon says the shell is sitting at its prompt, typing gets
+  echoed locally and held back until enter gets hit, at which point the
+  local echo gets erased and the whole line goes to the shell, which
+  echoes it for real. Only the simplest editing happens locally:
+  backspace and ^U. Anything else, like tab completion, arrow keys or a
+  keybinding, gives up on the line and sends it along as typed so far,
+  with the rest of the line going straight to the shell as usual.
+
+  The local echo has to stay on the row the prompt is on, since there
+  is no erasing it once it wraps, so a line that gets too long gets
+  given up on the same way.
+*/
+
+/// A line being typed at the prompt.
+#[derive(Debug)]
+pub struct LineEditor {
+    /// What has been typed, and echoed, but not sent yet.
+    line: Vec<u8>,
+    /// How many columns the local echo takes up.
+    width: usize,
+    /// How many columns the local echo may take up before it would
+    /// wrap onto the next row.
+    room: usize,
+}
+
+/// What to do with some input run through a LineEditor.
+#[derive(Debug, Default, PartialEq)]
+pub struct Output {
+    /// Bytes to write to the local terminal.
+    pub echo: Vec<u8>,
+    /// Bytes to send on to the shell.
+    pub send: Vec<u8>,
+}
+
+impl LineEditor {
+    /// Start a line with the cursor in the given column of a terminal
+    /// with the given number of columns.
+    pub fn new(col: u16, cols: u16) -> Self {
+        let cols = usize::from(cols.max(1));
+        let room = (cols - usize::from(col) % cols).saturating_sub(1);
+        LineEditor { line: vec![], width: 0, room }
+    }
+
+    /// Run the given input through the editor. Returns true once the
+    /// line is done with, either because it got sent or because it got
+    /// given up on. Any input after that goes straight to the shell.
+    pub fn feed(&mut self, input: &[u8], out: &mut Output) -> bool {
+        for (i, &b) in input.iter().enumerate() {
+            match b {
+                b'\r' | b'\n' => {
+                    self.give_up(out);
+                    out.send.extend(&input[i..]);
+                    return true;
+                }
+                // backspace or delete
+                0x08 | 0x7f => {
+                    if let Some(len) = self.last_char_len() {
+                        self.line.truncate(self.line.len() - len);
+                        self.width -= 1;
+                        out.echo.extend(b"\x08 \x08");
+                    }
+                }
+                // ^U
+                0x15 => {
+                    self.erase(out);
+                    self.line.clear();
+                }
+                // a utf-8 continuation byte doesn't take up a column
+                0x80..=0xbf => {
+                    self.line.push(b);
+                    out.echo.push(b);
+                }
+                0x20..=0x7e | 0xc0..=0xff if self.width < self.room => {
+                    self.line.push(b);
+                    self.width += 1;
+                    out.echo.push(b);
+                }
+                _ => {
+                    self.give_up(out);
+                    out.send.extend(&input[i..]);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Erase the local echo and send along what has been typed so far,
+    /// for when the shell is no longer at its prompt or local editing
+    /// got turned off.
+    pub fn give_up(&mut self, out: &mut Output) {
+        self.erase(out);
+        out.send.append(&mut self.line);
+    }
+
+    fn erase(&mut self, out: &mut Output) {
+        if self.width > 0 {
+            out.echo.extend(format!("\x1b[{}D\x1b[K", self.width).as_bytes());
+            self.width = 0;
+        }
+    }
+
+    /// How many bytes the last character of the line takes up.
+    fn last_char_len(&self) -> Option<usize> {
+        let lead = self.line.iter().rposition(|b| !(0x80..=0xbf).contains(b))?;
+        Some(self.line.len() - lead)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    fn feed(editor: &mut LineEditor, input: &[u8]) -> (bool, Output) {
+        let mut out = Output::default();
+        let done = editor.feed(input, &mut out);
+        (done, out)
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn line() {
+        let mut editor = LineEditor::new(2, 80);
+        let (done, out) = feed(&mut editor, b"lx");
+        assert!(!done);
+        assert_eq!(out, Output { echo: b"lx".to_vec(), send: vec![] });
+
+        let (done, out) = feed(&mut editor, b"\x7fs -l\x15ls\rnext");
+        assert!(done);
+        assert_eq!(
+            out,
+            Output {
+                echo: b"\x08 \x08s -l\x1b[5D\x1b[Kls\x1b[2D\x1b[K".to_vec(),
+                send: b"ls\rnext".to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn utf8() {
+        let mut editor = LineEditor::new(0, 80);
+        let (_, out) = feed(&mut editor, "aé".as_bytes());
+        assert_eq!(out.echo, "aé".as_bytes());
+        let (_, out) = feed(&mut editor, b"\x7f");
+        assert_eq!(out.echo, b"\x08 \x08");
+        let (done, out) = feed(&mut editor, b"\r");
+        assert!(done);
+        assert_eq!(out, Output { echo: b"\x1b[1D\x1b[K".to_vec(), send: b"a\r".to_vec() });
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn gives_up() {
+        // tab completion is up to the shell
+        let mut editor = LineEditor::new(0, 80);
+        let (done, out) = feed(&mut editor, b"ca\tfoo");
+        assert!(done);
+        assert_eq!(out, Output { echo: b"ca\x1b[2D\x1b[K".to_vec(), send: b"ca\tfoo".to_vec() });
+
+        // the echo can't wrap, counting from a prompt that already did
+        let mut editor = LineEditor::new(85, 80);
+        let (done, out) = feed(&mut editor, &[b'x'; 80]);
+        assert!(done);
+        assert_eq!(out.send, vec![b'x'; 80]);
+        assert!(out.echo.ends_with(b"\x1b[74D\x1b[K"));
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    common, protocol,
+    protocol::{
+        ConnectHeader, LoadBufferReply, LoadBufferRequest, PasteBufferReply, PasteBufferRequest,
+        PasteReply, ShowBufferRequest,
+    },
+};
+
+/// Print the newest paste buffer, or the named one.
+pub fn run(buffer: Option<String>, socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = connect(&socket)?;
+
+    let reply: PasteReply = match &buffer {
+        // stick to the plain request when we can, so that older
+        // daemons still work
+        None => {
+            client.require_capability("paste")?;
+            client.write_connect_header(ConnectHeader::Paste).context("sending paste header")?;
+            client.read_reply().context("reading reply")?
+        }
+        Some(_) => {
+            client.require_capability("paste-buffers")?;
+            client
+                .write_connect_header(ConnectHeader::ShowBuffer(ShowBufferRequest {
+                    buffer: buffer.clone(),
+                }))
+                .context("sending show buffer header")?;
+            client.read_reply().context("reading reply")?
+        }
+    };
+    if let Some(err) = reply.error {
+        eprintln!("forbidden: {}", err);
+        return Err(anyhow!("forbidden: {}", err));
+    }
+    match (reply.contents, buffer) {
+        (Some(contents), _) => print!("{}", contents),
+        (None, Some(buffer)) => {
+            eprintln!("no such paste buffer: {}", buffer);
+            return Err(anyhow!("no such paste buffer: {}", buffer));
+        }
+        (None, None) => {
+            eprintln!("the paste buffer is empty, copy something in copy mode first");
+            return Err(anyhow!("empty paste buffer"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Fill a paste buffer from a file, or from stdin.
+pub fn load_buffer(
+    buffer: Option<String>,
+    file: Option<PathBuf>,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let mut contents = vec![];
+    match file.as_deref() {
+        Some(file) if file != Path::new("-") => {
+            contents = fs::read(file).with_context(|| format!("reading {:?}", file))?;
+        }
+        _ => {
+            io::stdin().read_to_end(&mut contents).context("reading stdin")?;
+        }
+    }
+    let contents = String::from_utf8(contents)
+        .map_err(|_| anyhow!("paste buffers can only hold utf-8 text"))?;
+
+    let mut client = connect(&socket)?;
+    client.require_capability("paste-buffers")?;
+    client
+        .write_connect_header(ConnectHeader::LoadBuffer(LoadBufferRequest { buffer, contents }))
+        .context("sending load buffer header")?;
+    let reply: LoadBufferReply = client.read_reply().context("reading reply")?;
+    if let Some(err) = reply.error {
+        eprintln!("forbidden: {}", err);
+        return Err(anyhow!("forbidden: {}", err));
+    }
+    eprintln!("loaded paste buffer '{}'", reply.buffer);
+
+    Ok(())
+}
+
+/// Type a paste buffer into a session.
+pub fn paste_buffer(
+    session: Option<String>,
+    buffer: Option<String>,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let mut sessions = session.into_iter().collect();
+    common::resolve_sessions(&mut sessions, "paste into")?;
+    let session = sessions.remove(0);
+
+    let mut client = connect(&socket)?;
+    client.require_capability("paste-buffers")?;
+    client
+        .write_connect_header(ConnectHeader::PasteBuffer(PasteBufferRequest {
+            session: session.clone(),
+            buffer: buffer.clone(),
+        }))
+        .context("sending paste buffer header")?;
+    let reply: PasteBufferReply = client.read_reply().context("reading reply")?;
+
+    match reply {
+        PasteBufferReply::Ok => Ok(()),
+        PasteBufferReply::NotFound => {
+            eprintln!("not found: {}", session);
+            Err(anyhow!("not found: {}", session))
+        }
+        PasteBufferReply::NoBuffer => {
+            let msg = match buffer {
+                Some(buffer) => format!("no such paste buffer: {}", buffer),
+                None => String::from("there are no paste buffers yet"),
+            };
+            eprintln!("{}", msg);
+            Err(anyhow!(msg))
+        }
+        PasteBufferReply::Forbidden(reason) => {
+            eprintln!("forbidden: {}", reason);
+            Err(anyhow!("forbidden: {}", reason))
+        }
+    }
+}
+
+fn connect(socket: &Path) -> anyhow::Result<protocol::Client> {
+    match protocol::Client::new(socket) {
+        Ok(c) => Ok(c),
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            Err(io_err).context("connecting to daemon")
+        }
+    }
+}
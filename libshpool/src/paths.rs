@@ -0,0 +1,212 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Where shpool keeps things on disk.
+
+  shpool follows the XDG base directory spec. The config lives in
+  $XDG_CONFIG_HOME/shpool, the socket and the rest of the data that
+  only matters while the daemon is up live in $XDG_RUNTIME_DIR/shpool,
+  and longer lived state, like audit recordings, lives in
+  $XDG_STATE_HOME/shpool. The runtime and state dirs can be moved with
+  the `[paths]` section of the config.
+
+  Older versions of shpool always read the config from
+  ~/.config/shpool, even with XDG_CONFIG_HOME pointing elsewhere, and
+  put the runtime dir in ~/.shpool/shpool when XDG_RUNTIME_DIR was
+  unset. A config in the old spot still gets read if there is none in
+  the new one, clients still find a daemon listening in the old runtime
+  dir, and a new daemon clears out the old runtime dir once nothing is
+  listening there anymore.
+*/
+
+use std::{
+    env, fs,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use tracing::{info, warn};
+
+use crate::{config, user};
+
+/// The file name of the daemon's socket within the runtime dir.
+pub const SOCKET_NAME: &str = "shpool.socket";
+
+/// The directory the config gets read from by default,
+/// $XDG_CONFIG_HOME/shpool.
+pub fn config_dir() -> anyhow::Result<PathBuf> {
+    Ok(xdg_dir("XDG_CONFIG_HOME", ".config")?.join("shpool"))
+}
+
+/// The config file to read when none is passed explicitly. This is
+/// config.toml in the config dir, unless only the one in the legacy
+/// spot exists.
+pub fn default_config_file() -> anyhow::Result<PathBuf> {
+    let path = config_dir()?.join("config.toml");
+    if !path.exists() {
+        let legacy = home_dir()?.join(".config").join("shpool").join("config.toml");
+        if legacy.exists() {
+            info!("no config at {:?}, reading the legacy one at {:?}", path, legacy);
+            return Ok(legacy);
+        }
+    }
+    Ok(path)
+}
+
+/// The directory for the daemon's socket and per-session runtime data,
+/// paths.runtime_dir from the config or $XDG_RUNTIME_DIR/shpool. When
+/// there is no XDG_RUNTIME_DIR, which is the case outside of a login
+/// session on many systems, this falls back to a dir under the state
+/// dir, as the spec suggests.
+pub fn runtime_dir(config: &config::Config) -> anyhow::Result<PathBuf> {
+    if let Some(dir) = config.paths.as_ref().and_then(|p| p.runtime_dir.as_ref()) {
+        return Ok(PathBuf::from(dir));
+    }
+    match env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if Path::new(&dir).is_absolute() => Ok(PathBuf::from(dir).join("shpool")),
+        _ => Ok(state_dir(config)?.join("run")),
+    }
+}
+
+/// The runtime dir of the named daemon instance, which lives inside the
+/// default runtime dir so that instances don't step on each other or on
+/// the default daemon.
+pub fn instance_runtime_dir(runtime_dir: &Path, instance: &str) -> anyhow::Result<PathBuf> {
+    if instance.is_empty() || instance == "." || instance == ".." || instance.contains('/') {
+        return Err(anyhow!("'{}' is not a valid instance name", instance));
+    }
+    Ok(runtime_dir.join("instances").join(instance))
+}
+
+/// The directory for state that should outlive the daemon, like audit
+/// recordings, paths.state_dir from the config or
+/// $XDG_STATE_HOME/shpool.
+pub fn state_dir(config: &config::Config) -> anyhow::Result<PathBuf> {
+    if let Some(dir) = config.paths.as_ref().and_then(|p| p.state_dir.as_ref()) {
+        return Ok(PathBuf::from(dir));
+    }
+    Ok(xdg_dir("XDG_STATE_HOME", ".local/state")?.join("shpool"))
+}
+
+/// Where older versions of shpool put the runtime dir when
+/// XDG_RUNTIME_DIR was unset.
+fn legacy_runtime_dir() -> anyhow::Result<PathBuf> {
+    Ok(home_dir()?.join(".shpool").join("shpool"))
+}
+
+/// The socket of a daemon started by an older version of shpool, if
+/// there is one where it would have been.
+pub fn legacy_socket() -> Option<PathBuf> {
+    let socket = legacy_runtime_dir().ok()?.join(SOCKET_NAME);
+    if socket.exists() {
+        Some(socket)
+    } else {
+        None
+    }
+}
+
+/// Clear out the legacy runtime dir, if it is not the one in use and
+/// no daemon is listening in it anymore. Failing to do so is not worth
+/// keeping the daemon from starting over, so this just logs.
+pub fn migrate_legacy(runtime_dir: &Path) {
+    let legacy = match legacy_runtime_dir() {
+        Ok(dir) => dir,
+        Err(_) => return,
+    };
+    if !legacy.is_dir() || legacy == runtime_dir {
+        return;
+    }
+
+    let socket = legacy.join(SOCKET_NAME);
+    if UnixStream::connect(&socket).is_ok() {
+        warn!(
+            "a daemon from an older version of shpool is still listening on {:?}, \
+             stop it with `shpool --socket {} daemon stop` to finish moving to {:?}",
+            socket,
+            socket.display(),
+            runtime_dir
+        );
+        return;
+    }
+
+    match fs::remove_dir_all(&legacy) {
+        Ok(()) => {
+            info!("removed legacy runtime dir {:?}, now using {:?}", legacy, runtime_dir);
+            // ~/.shpool held nothing else, so drop it too if it is empty
+            if let Some(parent) = legacy.parent() {
+                let _ = fs::remove_dir(parent);
+            }
+        }
+        Err(err) => warn!("removing legacy runtime dir {:?}: {:?}", legacy, err),
+    }
+}
+
+/// The given XDG base directory variable, or the given fallback under
+/// the home dir if it is unset. The spec says to ignore relative paths.
+fn xdg_dir(var: &str, fallback: &str) -> anyhow::Result<PathBuf> {
+    match env::var(var) {
+        Ok(dir) if Path::new(&dir).is_absolute() => Ok(PathBuf::from(dir)),
+        _ => Ok(home_dir().with_context(|| format!("no {} or home dir", var))?.join(fallback)),
+    }
+}
+
+fn home_dir() -> anyhow::Result<PathBuf> {
+    match env::var("HOME") {
+        Ok(home) if !home.is_empty() => Ok(PathBuf::from(home)),
+        _ => {
+            let home = user::info()?.home_dir;
+            if home.is_empty() {
+                return Err(anyhow!("no home dir"));
+            }
+            Ok(PathBuf::from(home))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn config_overrides() -> anyhow::Result<()> {
+        let config: config::Config = toml::from_str(
+            r#"
+            [paths]
+            runtime_dir = "/tmp/shpool-run"
+            state_dir = "/tmp/shpool-state"
+            "#,
+        )?;
+        assert_eq!(runtime_dir(&config)?, PathBuf::from("/tmp/shpool-run"));
+        assert_eq!(state_dir(&config)?, PathBuf::from("/tmp/shpool-state"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn instance_dirs() -> anyhow::Result<()> {
+        assert_eq!(
+            instance_runtime_dir(Path::new("/run/user/1000/shpool"), "work")?,
+            PathBuf::from("/run/user/1000/shpool/instances/work")
+        );
+        for bad in ["", ".", "..", "a/b", "/abs"] {
+            assert!(instance_runtime_dir(Path::new("/run"), bad).is_err(), "{:?} passed", bad);
+        }
+
+        Ok(())
+    }
+}
@@ -15,25 +15,271 @@
 use std::{
     fmt,
     io::{self, Read, Write},
-    os::unix::net::UnixStream,
+    os::{
+        fd::{AsFd, AsRawFd},
+        unix::net::UnixStream,
+    },
     path::Path,
-    sync::atomic::{AtomicI32, Ordering},
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering},
+        Mutex,
+    },
     thread, time,
 };
 
 use anyhow::{anyhow, Context};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use nix::{
+    poll,
+    sys::signal::{self, Signal},
+    unistd::{self, isatty},
+};
 use serde_derive::{Deserialize, Serialize};
-use tracing::{debug, error, instrument, span, trace, warn, Level};
+use tracing::{debug, error, info, instrument, span, trace, warn, Level};
 
-use super::{consts, tty};
+use super::{
+    consts,
+    local_edit::{self, LineEditor},
+    timing, tty,
+};
 
 const JOIN_POLL_DUR: time::Duration = time::Duration::from_millis(100);
 const JOIN_HANGUP_DUR: time::Duration = time::Duration::from_millis(300);
 
+// Moves to the start of the line and erases it.
+const CLEAR_LINE: &[u8] = b"\r\x1b[2K";
+
+// Saves the cursor, moves to the top left corner and turns on reverse
+// video, the way into drawing a message.
+const MESSAGE_START: &[u8] = b"\x1b7\x1b[1;1H\x1b[7m";
+// Turns off reverse video, erases the rest of the row and puts the
+// cursor back.
+const MESSAGE_END: &[u8] = b"\x1b[0m\x1b[K\x1b8";
+
+/// The version of the wire protocol spoken by this build. This only needs
+/// to be bumped for changes that break existing messages. Purely additive
+/// changes, like a new ConnectHeader variant, should add a capability
+/// instead so that older clients can keep talking to newer daemons.
+///
+/// Version history:
+///
+/// 1. The initial handshake.
+/// 2. Control messages get length prefixed frames and attach streams gain a
+///    control channel.
+pub const VERSION: u32 = 2;
+
+/// The optional protocol features this build knows how to use.
+pub const CAPABILITIES: &[&str] = &[
+    "keybind",
+    "gc",
+    "metrics",
+    "reset",
+    "alerts",
+    "activity",
+    "paste",
+    "save-output",
+    "env",
+    "create",
+    "wait",
+    "send-input",
+    "capture",
+    "stop",
+    "attach-replay",
+    "cwd",
+    "jobs",
+    "on-attach-cmd",
+    "attach-timings",
+    "version",
+    "restart",
+    "nsenter",
+    "record",
+    "resurrect",
+    "acl",
+    "clear",
+    "exited-screen",
+    "attach-restart",
+    "redraw",
+    "local-edit",
+    "events",
+    "paste-buffers",
+    "search",
+    "term-override",
+    "attach-info",
+];
+
+/// The largest control frame either side is willing to read. This
+/// mostly guards against allocating a huge buffer when the length
+/// prefix is garbage.
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// The largest hello either side is willing to read. The hellos are not
+/// framed, so this is what keeps a garbage length for one of the
+/// strings in a hello from turning into a huge allocation.
+const MAX_HELLO_SIZE: u64 = 64 * 1024;
+
+/// Leads off every ClientHello so that the daemon can tell a client that
+/// predates the handshake (which would send a ConnectHeader right away)
+/// apart from one speaking a different protocol version.
+const HELLO_MAGIC: u32 = 0x7368_706c; // "shpl"
+
+/// ClientHello is the first thing a client sends after connecting. It is
+/// answered with a DaemonHello before the ConnectHeader gets sent. The
+/// layout of the hello messages must never change, which is also why
+/// they are the only messages not wrapped in a frame.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientHello {
+    pub magic: u32,
+    pub version: u32,
+    /// The capabilities the client would like to use.
+    pub capabilities: Vec<String>,
+}
+
+impl Default for ClientHello {
+    fn default() -> Self {
+        ClientHello {
+            magic: HELLO_MAGIC,
+            version: VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| String::from(*c)).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DaemonHello {
+    pub version: u32,
+    /// The subset of the requested capabilities that the daemon supports.
+    pub capabilities: Vec<String>,
+    /// If set, the daemon is about to hang up rather than
+    /// accept a ConnectHeader.
+    pub error: Option<HandshakeError>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// The client and daemon speak incompatible protocol versions.
+    VersionMismatch { client_version: u32, daemon_version: u32 },
+    /// The client did not lead off with a well formed hello.
+    BadHello,
+    /// The daemon does not accept connections from the client's user.
+    Forbidden(String),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::VersionMismatch { client_version, daemon_version } => write!(
+                f,
+                "the daemon speaks protocol version {}, but this client speaks version {}, \
+                 you probably need to restart the daemon after upgrading shpool",
+                daemon_version, client_version
+            ),
+            HandshakeError::BadHello => write!(f, "malformed protocol hello"),
+            HandshakeError::Forbidden(reason) => {
+                write!(f, "the daemon refused the connection: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Work out the daemon's reply to the given hello.
+pub fn negotiate(hello: &ClientHello) -> DaemonHello {
+    let error = if hello.magic != HELLO_MAGIC {
+        Some(HandshakeError::BadHello)
+    } else if hello.version != VERSION {
+        Some(HandshakeError::VersionMismatch {
+            client_version: hello.version,
+            daemon_version: VERSION,
+        })
+    } else {
+        None
+    };
+    let capabilities = if error.is_some() {
+        vec![]
+    } else {
+        hello.capabilities.iter().filter(|c| CAPABILITIES.contains(&c.as_str())).cloned().collect()
+    };
+
+    DaemonHello { version: VERSION, capabilities, error }
+}
+
+/// Read a ClientHello or DaemonHello, which go over the wire bare rather
+/// than in a frame.
+pub fn read_hello<R, M>(r: &mut R) -> anyhow::Result<M>
+where
+    R: Read,
+    M: serde::de::DeserializeOwned,
+{
+    use bincode::Options;
+
+    // the same encoding bincode::serialize_into writes the hellos
+    // with, just with a cap on how much gets read
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_HELLO_SIZE)
+        .deserialize_from(r)
+        .context("decoding hello")
+}
+
+/// Write a control message as a single frame.
+///
+/// format:
+///
+/// ```text
+/// little endian 4 byte word: length prefix
+/// N bytes: bincode encoded message
+/// ```
+///
+/// Since the whole message gets encoded up front, a failure to serialize
+/// can never leave half a message on the wire, and the reader always
+/// knows exactly how many bytes belong to the message, even if it cannot
+/// make sense of them.
+pub fn write_frame<W, M>(w: &mut W, msg: &M) -> anyhow::Result<()>
+where
+    W: Write,
+    M: serde::Serialize,
+{
+    let buf = bincode::serialize(msg).context("encoding frame")?;
+    if buf.len() > MAX_FRAME_SIZE {
+        return Err(anyhow!(
+            "frame of size {} exceeds size limit of {} bytes",
+            buf.len(),
+            MAX_FRAME_SIZE
+        ));
+    }
+    w.write_u32::<LittleEndian>(buf.len() as u32).context("writing frame length")?;
+    w.write_all(&buf).context("writing frame")?;
+    w.flush().context("flushing frame")?;
+
+    Ok(())
+}
+
+/// Read a control message written by write_frame.
+pub fn read_frame<R, M>(r: &mut R) -> anyhow::Result<M>
+where
+    R: Read,
+    M: serde::de::DeserializeOwned,
+{
+    let len = r.read_u32::<LittleEndian>().context("reading frame length")? as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(anyhow!(
+            "frame of size {} exceeds size limit of {} bytes",
+            len,
+            MAX_FRAME_SIZE
+        ));
+    }
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf).context("reading frame")?;
+
+    bincode::deserialize(&buf).context("decoding frame")
+}
+
 /// ConnectHeader is the blob of metadata that a client transmits when it
 /// first connections. It uses an enum to allow different connection types
-/// to be initiated on the same socket. The ConnectHeader is always prefixed
+/// to be initiated on the same socket. The ConnectHeader and all of the
+/// replies are sent with write_frame, so they are always prefixed
 /// with a 4 byte little endian unsigned word to indicate length.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ConnectHeader {
@@ -58,6 +304,704 @@ pub enum ConnectHeader {
     /// A message to request that a list of running
     /// sessions get killed.
     Kill(KillRequest),
+    /// A message to list or edit the keybindings of
+    /// running sessions.
+    Keybind(KeybindRequest),
+    /// A message to request that on-disk state left behind
+    /// by sessions that no longer exist gets cleaned up.
+    Gc(GcRequest),
+    /// A request for the daemon's metrics.
+    ///
+    /// Responds with a MetricsReply.
+    Metrics,
+    /// A message to request that the terminal state of a list of
+    /// running sessions get reset.
+    Reset(ResetRequest),
+    /// A request for the alerts that sessions have raised while
+    /// detached.
+    ///
+    /// Responds with an AlertsReply.
+    Alerts,
+    /// A request for how recently each session produced output.
+    ///
+    /// Responds with an ActivityReply.
+    Activity,
+    /// A request for the contents of the newest paste buffer.
+    ///
+    /// Responds with a PasteReply.
+    Paste,
+    /// A request for the output a session has kept in its
+    /// output spool.
+    ///
+    /// Responds with a SaveOutputReply.
+    SaveOutput(SaveOutputRequest),
+    /// A message to set or unset variables in the daemon's record
+    /// of a session's environment.
+    ///
+    /// Responds with a SetEnvReply.
+    SetEnv(SetEnvRequest),
+    /// A request for the daemon's record of a session's environment.
+    ///
+    /// Responds with a GetEnvReply.
+    GetEnv(GetEnvRequest),
+    /// Create the session described by the given header without
+    /// attaching to it.
+    ///
+    /// Responds with a CreateReply.
+    Create(AttachHeader),
+    /// A request to be told when a session's child exits.
+    ///
+    /// Responds with a WaitReply once it has.
+    Wait(WaitRequest),
+    /// A message to type input into a running session.
+    ///
+    /// Responds with a SendInputReply.
+    SendInput(SendInputRequest),
+    /// A request for what a session is displaying right now.
+    ///
+    /// Responds with a CaptureReply.
+    Capture(CaptureRequest),
+    /// A request for the daemon to shut down.
+    ///
+    /// Responds with a StopReply once everything is wrapped up, just
+    /// before the daemon exits.
+    Stop(StopRequest),
+    /// Attach like `Attach`, with extra options that don't fit in
+    /// the header.
+    ///
+    /// Responds with an AttachReplyHeader.
+    AttachWithOptions(AttachHeader, Vec<AttachOption>),
+    /// Create like `Create`, with extra options that don't fit in
+    /// the header.
+    ///
+    /// Responds with a CreateReply.
+    CreateWithOptions(AttachHeader, Vec<AttachOption>),
+    /// A request for the working directory of each session.
+    ///
+    /// Responds with a CwdReply.
+    Cwd,
+    /// A request for the jobs of the session with the given name,
+    /// which can be the name of any of its jobs.
+    ///
+    /// Responds with a JobsReply.
+    Jobs(String),
+    /// A request to point the terminal attached to one of a session's
+    /// jobs at another one of them.
+    ///
+    /// Responds with a SwitchJobReply.
+    SwitchJob(SwitchJobRequest),
+    /// A request for the version of shpool the daemon is running.
+    ///
+    /// Responds with a VersionReply.
+    Version,
+    /// A request for the daemon to restart into the binary now
+    /// installed where it was started from.
+    ///
+    /// Responds with a RestartReply just before the daemon execs the
+    /// new binary.
+    Restart(RestartRequest),
+    /// A request to start or stop recording a session.
+    ///
+    /// Responds with a RecordReply.
+    Record(RecordRequest),
+    /// A request to start up the saved sessions which are not running.
+    ///
+    /// Responds with a ResurrectReply.
+    Resurrect(ResurrectRequest),
+    /// A request to show or change a session's access control list.
+    ///
+    /// Responds with an AclReply.
+    Acl(AclRequest),
+    /// A request to wipe the scrollback of a list of running sessions.
+    ///
+    /// Responds with a ClearReply.
+    Clear(ClearRequest),
+    /// A request to hear about session lifecycle events as they
+    /// happen.
+    ///
+    /// Responds with a SessionEvent for each event, for as long as the
+    /// client stays connected.
+    Events,
+    /// A request to fill a paste buffer.
+    ///
+    /// Responds with a LoadBufferReply.
+    LoadBuffer(LoadBufferRequest),
+    /// A request for the contents of a paste buffer.
+    ///
+    /// Responds with a PasteReply.
+    ShowBuffer(ShowBufferRequest),
+    /// A request to type the contents of a paste buffer into a
+    /// session.
+    ///
+    /// Responds with a PasteBufferReply.
+    PasteBuffer(PasteBufferRequest),
+    /// A request for the lines of a session's scrollback that match a
+    /// pattern.
+    ///
+    /// Responds with a SearchReply.
+    Search(SearchRequest),
+}
+
+/// MetricsReply carries the daemon metrics, already rendered in the
+/// Prometheus text format.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MetricsReply {
+    pub text: String,
+}
+
+/// VersionReply carries the version of libshpool the daemon was
+/// built from.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VersionReply {
+    pub version: String,
+}
+
+/// GcRequest represents a request to clean up the runtime
+/// data of sessions that are no longer running.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GcRequest {
+    /// Only report what would be removed, don't remove anything.
+    pub dry_run: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GcReply {
+    pub items: Vec<GcItem>,
+    /// Set if the daemon refused to collect anything, with the reason.
+    pub error: Option<String>,
+}
+
+/// GcItem describes a single piece of garbage that was found.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GcItem {
+    /// The session the data belonged to.
+    pub session: String,
+    pub path: String,
+    /// The disk space used by the item.
+    pub bytes: u64,
+    /// Set if the item could not be removed.
+    pub error: Option<String>,
+}
+
+/// ResetRequest represents a request to put the terminal
+/// state of the given named sessions back to defaults.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResetRequest {
+    /// The sessions to reset
+    pub sessions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResetReply {
+    pub not_found_sessions: Vec<String>,
+    /// sessions the peer only has read-only access to
+    pub forbidden_sessions: Vec<String>,
+}
+
+/// ClearRequest asks for the scrollback and screen the daemon keeps
+/// for the given named sessions to be thrown away.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClearRequest {
+    /// The sessions to clear
+    pub sessions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClearReply {
+    pub not_found_sessions: Vec<String>,
+    /// sessions the peer only has read-only access to
+    pub forbidden_sessions: Vec<String>,
+}
+
+/// AlertsReply lists the sessions that have rung the bell or emitted
+/// a desktop notification since a client was last attached.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AlertsReply {
+    pub sessions: Vec<SessionAlerts>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionAlerts {
+    pub name: String,
+    pub bells: u64,
+    pub notifications: u64,
+}
+
+/// ActivityReply says how long each session has gone without
+/// producing output.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ActivityReply {
+    pub sessions: Vec<SessionActivity>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionActivity {
+    pub name: String,
+    /// Milliseconds since the session last produced output.
+    pub idle_ms: u64,
+    /// Set if the session produced output within the daemon's
+    /// configured activity window.
+    pub active: bool,
+}
+
+/// SessionEvent is something that happened to a session, as streamed
+/// to clients that asked with ConnectHeader::Events.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionEvent {
+    /// Milliseconds since the unix epoch.
+    pub at_ms: u64,
+    pub session: String,
+    pub kind: SessionEventKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SessionEventKind {
+    Created,
+    Attached,
+    Detached,
+    Exited {
+        exit_status: i32,
+    },
+    /// A BEL from a session with no client attached.
+    Bell,
+    /// A desktop notification from a session with no client attached.
+    Notification {
+        title: Option<String>,
+        body: String,
+    },
+    /// A busy session with no client attached went quiet.
+    Silence,
+    /// A quiet session with no client attached produced output.
+    Activity,
+}
+
+/// CwdReply has the working directory of each session's child.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CwdReply {
+    pub sessions: Vec<SessionCwd>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionCwd {
+    pub name: String,
+    /// None if the daemon could not find out, for example because
+    /// the child just exited.
+    pub cwd: Option<String>,
+}
+
+/// JobsReply lists the jobs of a session, starting with the session's
+/// own shell and then the rest in order of name.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobsReply {
+    pub jobs: Vec<Job>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Job {
+    /// The full session name of the job, i.e. "main:build".
+    pub name: String,
+    pub attached: bool,
+}
+
+/// SwitchJobRequest asks for the terminal attached to any of the jobs
+/// of `session` to be switched over to the job `target`, which is the
+/// full session name of the job to switch to.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SwitchJobRequest {
+    pub session: String,
+    pub target: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum SwitchJobReply {
+    Switched,
+    /// There is no such job.
+    NotFound,
+    /// None of the session's jobs has a terminal attached, so there
+    /// is nothing to switch.
+    NotAttached,
+    /// The session's ACL doesn't let the peer switch its jobs.
+    Forbidden(String),
+}
+
+/// SaveOutputRequest asks for the scrollback of a session.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SaveOutputRequest {
+    pub session: String,
+    /// Just the text, without any escape sequences.
+    pub strip_ansi: bool,
+}
+
+/// SearchRequest asks for the lines of a session's scrollback that
+/// match a regex.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchRequest {
+    pub session: String,
+    pub pattern: String,
+    pub ignore_case: bool,
+    /// How many lines to include before and after each match.
+    pub context: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum SearchReply {
+    /// The matching lines and the lines around them, in order. Empty
+    /// if nothing matched.
+    Lines(Vec<SearchLine>),
+    /// The session was not found in the session table
+    NotFound,
+    /// There is no scrollback to search, because the daemon is using
+    /// the simple session restore mode.
+    NoSpool,
+    /// The pattern is not a valid regex.
+    BadPattern(String),
+    /// The attach_auth command turned the sender away.
+    Forbidden(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SearchLine {
+    /// Where the line is in the scrollback, counting from 1 for the
+    /// oldest line kept.
+    pub number: usize,
+    pub text: String,
+    /// Whether this line matched, rather than being context.
+    pub matched: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum SaveOutputReply {
+    /// The session's scrollback and screen contents.
+    Output(Vec<u8>),
+    /// The session was not found in the session table
+    NotFound,
+    /// The session keeps no output around, because the daemon
+    /// is using the simple session restore mode.
+    NoSpool,
+    /// The attach_auth command turned the sender away.
+    Forbidden(String),
+}
+
+/// RecordRequest asks for a session's recording to be started or
+/// stopped.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecordRequest {
+    pub session: String,
+    pub action: RecordAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum RecordAction {
+    /// Start recording to the given file, or to a new file in the
+    /// configured recordings dir.
+    Start {
+        file: Option<String>,
+    },
+    Stop,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum RecordReply {
+    /// The session is now being recorded to the given file.
+    Started(String),
+    /// The recording to the given file is finished.
+    Stopped(String),
+    /// The session is already being recorded to the given file.
+    AlreadyRecording(String),
+    /// There is no recording to stop.
+    NotRecording,
+    /// The recording could not be started, for the given reason.
+    Failed(String),
+    /// The session was not found in the session table
+    NotFound,
+    /// The session's ACL doesn't let the sender record it.
+    Forbidden(String),
+}
+
+/// ResurrectRequest asks for the saved sessions to be started up
+/// again. The resurrected sessions get the client's terminal size and
+/// environment, the same as with a create, and then the environment
+/// saved with them on top.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResurrectRequest {
+    pub local_tty_size: tty::Size,
+    pub local_env: Vec<(String, String)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum ResurrectReply {
+    /// What happened to each saved session, in the order they were
+    /// saved in.
+    Resurrected(Vec<ResurrectedSession>),
+    /// The daemon is not saving sessions, because there is no
+    /// resurrect section in its config.
+    NotEnabled,
+    /// The saved sessions could not be read, for the given reason.
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ResurrectedSession {
+    pub name: String,
+    pub outcome: ResurrectOutcome,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum ResurrectOutcome {
+    Created,
+    /// The session was already running, so it was left alone.
+    Running,
+    /// The session could not be created, for the given reason.
+    Failed(String),
+}
+
+/// AclRequest asks for a session's access control list to be shown or
+/// changed. Only the user running the daemon may change it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AclRequest {
+    pub session: String,
+    pub action: AclAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum AclAction {
+    Show,
+    Grant {
+        uid: u32,
+        access: Access,
+    },
+    Revoke {
+        uid: u32,
+    },
+    /// Drop the ACL, so that every peer gets full access again.
+    Clear,
+}
+
+/// What a user may do with a session.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Attach and watch, but not type into the session.
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AclEntry {
+    pub uid: u32,
+    pub access: Access,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum AclReply {
+    /// The session's ACL after the change, or None if it has none and
+    /// every peer gets full access.
+    Acl(Option<Vec<AclEntry>>),
+    /// The session was not found in the session table
+    NotFound,
+    /// The request was refused, for the given reason.
+    Forbidden(String),
+    /// The ACL got changed, but could not be saved, for the given
+    /// reason.
+    Failed(String),
+}
+
+/// CaptureRequest asks for a snapshot of a session's output.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CaptureRequest {
+    pub session: String,
+    pub mode: CaptureMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CaptureMode {
+    /// The rows currently on the screen, either as plain text or with
+    /// the escape sequences needed to redraw them.
+    Screen { ansi: bool },
+    /// Up to the given number of the most recent raw bytes of output.
+    Raw { bytes: usize },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum CaptureReply {
+    Output(Vec<u8>),
+    /// The session was not found in the session table
+    NotFound,
+    /// There is no screen to capture, because the daemon is using
+    /// the simple session restore mode.
+    NoSpool,
+    /// The attach_auth command turned the sender away.
+    Forbidden(String),
+}
+
+/// StopRequest asks the daemon to shut down.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StopRequest {
+    /// Hand the sessions off to a holder process so that the next
+    /// daemon to start up can pick them back up, rather than letting
+    /// them exit along with the daemon.
+    pub keep_sessions: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum StopReply {
+    /// The daemon is on its way out, and the named sessions are
+    /// waiting for the next daemon in a holder process.
+    Stopped { held: Vec<String> },
+    /// The daemon could not hand off the sessions, so it is still
+    /// running.
+    Failed(String),
+}
+
+/// RestartRequest asks the daemon to restart in place.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RestartRequest {
+    /// Hand the sessions off to a holder process for the new daemon
+    /// to pick back up, rather than letting them exit.
+    pub preserve_sessions: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum RestartReply {
+    /// The daemon is about to exec the new binary, and the named
+    /// sessions are waiting for it in a holder process.
+    Restarting { held: Vec<String> },
+    /// The daemon could not get ready to restart, so it is still
+    /// running the old binary.
+    Failed(String),
+}
+
+/// SetEnvRequest updates the environment record of a session.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetEnvRequest {
+    pub session: String,
+    /// The variables to set, along with their new values.
+    pub set: Vec<(String, String)>,
+    /// The variables to unset.
+    pub unset: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum SetEnvReply {
+    Ok,
+    /// The session was not found in the session table
+    NotFound,
+    /// A variable name that can't be used in a shell. Nothing
+    /// gets changed if any of the names are bad.
+    BadName(String),
+    /// The session's ACL doesn't let the sender change its env.
+    Forbidden(String),
+}
+
+/// GetEnvRequest asks for the environment record of a session.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetEnvRequest {
+    pub session: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum GetEnvReply {
+    /// The variables in the record, sorted by name. A value of
+    /// None means that the variable has been unset.
+    Env(Vec<(String, Option<String>)>),
+    /// The session was not found in the session table
+    NotFound,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum CreateReply {
+    /// The session was created and is running with no client attached.
+    Created { warnings: Vec<String> },
+    /// There is already a running session with the given name.
+    Exists,
+    /// The daemon refused to create the session.
+    Forbidden(String),
+}
+
+/// WaitRequest asks to be told when the child of a session exits.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WaitRequest {
+    pub session: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum WaitReply {
+    /// The session's child exited with the given status.
+    Exited { exit_status: i32 },
+    /// The session was not found in the session table
+    NotFound,
+}
+
+/// SendInputRequest carries input to write to a session's shell,
+/// just as if it had been typed into an attached terminal.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SendInputRequest {
+    pub session: String,
+    pub input: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum SendInputReply {
+    Ok,
+    /// The session was not found in the session table
+    NotFound,
+    /// The session's ACL or the attach_auth command doesn't let the
+    /// sender type into it.
+    Forbidden(String),
+}
+
+/// PasteReply carries the contents of a paste buffer.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PasteReply {
+    /// None if there is no such buffer, or nothing has been copied
+    /// since the daemon started.
+    pub contents: Option<String>,
+    /// Set if the daemon refused to show the buffer, with the reason.
+    pub error: Option<String>,
+}
+
+/// LoadBufferRequest carries text to put in a paste buffer.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoadBufferRequest {
+    /// The buffer to fill, or None for a fresh automatically named one.
+    pub buffer: Option<String>,
+    pub contents: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoadBufferReply {
+    /// The name of the buffer that got filled.
+    pub buffer: String,
+    /// Set if the daemon refused to fill the buffer, with the reason,
+    /// in which case no buffer got filled.
+    pub error: Option<String>,
+}
+
+/// ShowBufferRequest asks for the contents of a paste buffer.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShowBufferRequest {
+    /// The buffer to show, or None for the newest one.
+    pub buffer: Option<String>,
+}
+
+/// PasteBufferRequest asks for a paste buffer to be typed into a
+/// session, just as if it had been pasted into an attached terminal.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PasteBufferRequest {
+    pub session: String,
+    /// The buffer to paste, or None for the newest one.
+    pub buffer: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum PasteBufferReply {
+    Ok,
+    /// The session was not found in the session table
+    NotFound,
+    /// There is no such buffer, or no buffers at all.
+    NoBuffer,
+    /// The session's ACL or the attach_auth command doesn't let the
+    /// sender type into it.
+    Forbidden(String),
 }
 
 /// KillRequest represents a request to kill
@@ -71,6 +1015,47 @@ pub struct KillRequest {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KillReply {
     pub not_found_sessions: Vec<String>,
+    /// sessions the peer only has read-only access to
+    pub forbidden_sessions: Vec<String>,
+}
+
+/// KeybindRequest represents a request to list or edit
+/// the keybindings of the given named sessions.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeybindRequest {
+    /// The sessions to operate on. If empty, the request
+    /// applies to all sessions.
+    pub sessions: Vec<String>,
+    pub op: KeybindOp,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum KeybindOp {
+    /// Bind the given key sequence to the given action, replacing
+    /// any existing binding for the same sequence.
+    Add { binding: String, action: String },
+    /// Remove the binding for the given key sequence.
+    Remove { binding: String },
+    /// Just report the current bindings.
+    List,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct KeybindReply {
+    /// Set if the request was invalid, in which case
+    /// no sessions were touched.
+    pub error: Option<String>,
+    pub not_found_sessions: Vec<String>,
+    /// The bindings of each session after applying the request.
+    pub sessions: Vec<SessionKeybindings>,
+}
+
+/// SessionKeybindings describes the keybindings active in a session.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionKeybindings {
+    pub name: String,
+    /// (binding, action) pairs
+    pub bindings: Vec<(String, String)>,
 }
 
 /// DetachRequest represents a request to detach
@@ -88,6 +1073,8 @@ pub struct DetachReply {
     /// sessions that are in the session table, but have no
     /// tty attached
     pub not_attached_sessions: Vec<String>,
+    /// sessions the peer only has read-only access to
+    pub forbidden_sessions: Vec<String>,
 }
 
 /// SessionMessageRequest represents a request that
@@ -111,6 +1098,10 @@ pub enum SessionMessageRequestPayload {
     /// Detach the given session. Generated internally
     /// by the server from a batch detach request.
     Detach,
+    /// Redraw the screen of the client attached to the given
+    /// session. Generated when a `shpool attach` process gets
+    /// continued after being suspended. Needs "redraw".
+    Redraw,
 }
 
 /// ResizeRequest resizes the pty for a given named session.
@@ -134,6 +1125,10 @@ pub enum SessionMessageReply {
     Resize(ResizeReply),
     /// The response to a detach message
     Detach(SessionMessageDetachReply),
+    /// The response to a redraw message
+    Redraw,
+    /// The session's ACL doesn't let the sender do this.
+    Forbidden(String),
 }
 
 /// A reply to a detach message
@@ -179,6 +1174,81 @@ impl AttachHeader {
     }
 }
 
+/// AttachOption is an extra setting for an attach or create. New
+/// settings get new variants, and a client should only send the ones
+/// the daemon has the capability for.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AttachOption {
+    /// How much output to replay on reattach. Needs "attach-replay".
+    Replay(Replay),
+    /// The absolute path of the directory to start a new session in,
+    /// rather than the user's home directory. Needs "cwd".
+    Cwd(String),
+    /// A command to type into the session's shell on reattach, in place
+    /// of the on_attach_cmd from the config. Needs "on-attach-cmd".
+    OnAttachCmd(String),
+    /// Send back how long each phase of the attach took on the daemon
+    /// side, as StreamControl::Timings messages. Needs "attach-timings".
+    Timings,
+    /// Start a new session inside the namespaces of the given process,
+    /// usually a container's init process, rather than on the host. Any
+    /// Cwd is a path in the target's mount namespace. Needs "nsenter".
+    NsenterPid(i32),
+    /// When the session's shell or command has already exited, send
+    /// back what it left on the screen along with the exit status, as
+    /// AttachStatus::ExitedWithScreen. Needs "exited-screen".
+    ExitedScreen,
+    /// When the session's shell or command has already exited, start
+    /// it over in place of the session rather than reporting the exit.
+    /// Needs "attach-restart".
+    Restart,
+    /// Send StreamControl::Prompt messages as the session's shell
+    /// starts and stops sitting at its prompt, for a client that does
+    /// its own line editing there. Needs "local-edit".
+    PromptHints,
+    /// The TERM to start a new session with, in place of the client's
+    /// TERM and any TERM pinned in the config. Needs "term-override".
+    Term(String),
+    /// Follow the AttachReplyHeader with an AttachInfo about the session
+    /// if the client gets attached. Needs "attach-info".
+    Info,
+}
+
+impl AttachOption {
+    /// The capability the daemon needs to have to understand this
+    /// option.
+    pub fn capability(&self) -> &'static str {
+        match self {
+            AttachOption::Replay(_) => "attach-replay",
+            AttachOption::Cwd(_) => "cwd",
+            AttachOption::OnAttachCmd(_) => "on-attach-cmd",
+            AttachOption::Timings => "attach-timings",
+            AttachOption::NsenterPid(_) => "nsenter",
+            AttachOption::ExitedScreen => "exited-screen",
+            AttachOption::Restart => "attach-restart",
+            AttachOption::PromptHints => "local-edit",
+            AttachOption::Term(_) => "term-override",
+            AttachOption::Info => "attach-info",
+        }
+    }
+}
+
+/// Replay controls how much of a session's output the daemon redraws
+/// for a client that reattaches to it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Replay {
+    /// Whatever the session_restore_mode config option calls for.
+    #[default]
+    Default,
+    /// Nothing at all.
+    Off,
+    /// Up to the given number of the most recent lines, including
+    /// scrollback.
+    Lines(u16),
+    /// As much scrollback as the daemon has kept.
+    All,
+}
+
 /// AttachReplyHeader is the blob of metadata that the shpool service prefixes
 /// the data stream with after an attach. In can be used to indicate a
 /// connection error.
@@ -187,6 +1257,17 @@ pub struct AttachReplyHeader {
     pub status: AttachStatus,
 }
 
+/// AttachInfo tells a client that asked with AttachOption::Info about
+/// the session it just got attached to, so that it can print a banner.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AttachInfo {
+    /// The hostname of the machine the daemon is running on.
+    pub host: String,
+    pub started_at_unix_ms: i64,
+    /// How many times a client attached to the session before this one.
+    pub attaches: u64,
+}
+
 /// ListReply is contains a list of active sessions to be displayed to the user.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ListReply {
@@ -206,6 +1287,9 @@ pub struct Session {
 pub enum SessionStatus {
     Attached,
     Disconnected,
+    /// The session's shell or command has exited, but nobody has
+    /// attached to collect the exit status yet.
+    Exited(i32),
 }
 
 impl fmt::Display for SessionStatus {
@@ -213,6 +1297,7 @@ impl fmt::Display for SessionStatus {
         match self {
             SessionStatus::Attached => write!(f, "attached"),
             SessionStatus::Disconnected => write!(f, "disconnected"),
+            SessionStatus::Exited(exit_status) => write!(f, "exited (status {})", exit_status),
         }
     }
 }
@@ -241,6 +1326,14 @@ pub enum AttachStatus {
     Forbidden(String),
     /// Some unexpected error
     UnexpectedError(String),
+    /// Exited indicates that the session's shell or command exited while
+    /// no client was attached. The session gets reaped, and the client
+    /// should exit with the given status.
+    Exited { exit_status: i32 },
+    /// ExitedWithScreen is Exited for a client that asked for the
+    /// ExitedScreen option, along with the rows that were left on the
+    /// session's screen as plain text.
+    ExitedWithScreen { exit_status: i32, screen: String },
 }
 
 /// ChunkKind is a tag that indicates what type of frame is being transmitted
@@ -257,6 +1350,92 @@ pub enum ChunkKind {
     /// have exactly 4 bytes of data, which will contain a little endian
     /// code indicating the child's exit status.
     ExitStatus = 2,
+    /// A bincode encoded StreamControl message, framed just like
+    /// a Data chunk. This lets control traffic be interleaved with
+    /// pty output without the two getting tangled up.
+    Control = 3,
+}
+
+/// StreamControl messages travel on the control channel of an attach
+/// stream. Clients skip messages they cannot decode, so new variants
+/// can be added without a protocol version bump.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum StreamControl {
+    /// Something the daemon wants noted in the client's log
+    /// without scribbling over the user's terminal.
+    Notice(String),
+    /// The daemon is shutting down, for the given reason, and the
+    /// connection is about to close.
+    Shutdown(String),
+    /// The daemon ended the session, for the given reason, and the
+    /// connection is about to close.
+    Ended(String),
+    /// The client should attach to another job of the same session
+    /// once the connection closes.
+    SwitchJob(JobTarget),
+    /// How long some phases of the attach took on the daemon side, for
+    /// a client that asked with AttachOption::Timings.
+    Timings(Vec<TimedPhase>),
+    /// The daemon is about to replay this many bytes of output, enough
+    /// that the client should let the user know what is taking so long.
+    Replay { bytes: u64 },
+    /// The user asked to suspend the client, like a ^C^Z would suspend
+    /// any other program if raw mode did not keep it from the terminal.
+    Suspend,
+    /// The session's shell is sitting at its prompt with the cursor in
+    /// the given column, or None once it stops. Only sent to a client
+    /// that asked with AttachOption::PromptHints, and sent again
+    /// whenever either changes.
+    Prompt(Option<u16>),
+    /// The user asked to turn the client's local line editing on or off.
+    ToggleLocalEdit,
+    /// Something the user should see right away, unlike a Notice. The
+    /// client draws it over the top row of the terminal, where it stays
+    /// until the session draws over it.
+    Message(String),
+}
+
+/// How long one phase of an attach took.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TimedPhase {
+    pub name: String,
+    pub micros: u64,
+}
+
+/// Which job an attached client should switch over to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum JobTarget {
+    /// The one after the current job, as listed in a JobsReply.
+    Next,
+    /// The job with the given full session name.
+    Session(String),
+}
+
+/// How an attach stream came to an end.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PipeEnd {
+    /// `shpool attach` should exit with the given status.
+    Exit(i32),
+    /// The daemon asked for the client to attach to another job.
+    SwitchJob(JobTarget),
+}
+
+impl StreamControl {
+    /// Encode the message and write it as a Control chunk.
+    pub fn write_to<W>(&self, w: &mut W) -> anyhow::Result<()>
+    where
+        W: std::io::Write,
+    {
+        let buf = bincode::serialize(self).context("encoding control message")?;
+        if buf.len() > consts::BUF_SIZE {
+            return Err(anyhow!(
+                "control message of size {} exceeds size limit of {} bytes",
+                buf.len(),
+                consts::BUF_SIZE
+            ));
+        }
+        Chunk { kind: ChunkKind::Control, buf: &buf }.write_to(w).context("writing control chunk")
+    }
 }
 
 impl TryFrom<u8> for ChunkKind {
@@ -267,6 +1446,7 @@ impl TryFrom<u8> for ChunkKind {
             0 => Ok(ChunkKind::Data),
             1 => Ok(ChunkKind::Heartbeat),
             2 => Ok(ChunkKind::ExitStatus),
+            3 => Ok(ChunkKind::Control),
             _ => Err(anyhow!("unknown ChunkKind {}", v)),
         }
     }
@@ -305,6 +1485,18 @@ impl<'data> Chunk<'data> {
         Ok(())
     }
 
+    /// Append the kind tag and length prefix that go ahead of the buf
+    /// on the wire to out, so that callers can write a bunch of chunks
+    /// out with a single vectored write.
+    pub fn encode_header(&self, out: &mut Vec<u8>) {
+        out.push(self.kind as u8);
+        if let ChunkKind::ExitStatus = self.kind {
+            assert!(self.buf.len() == 4);
+        } else {
+            out.extend_from_slice(&(self.buf.len() as u32).to_le_bytes());
+        }
+    }
+
     pub fn read_into<R>(r: &mut R, buf: &'data mut [u8]) -> anyhow::Result<Self>
     where
         R: std::io::Read,
@@ -335,62 +1527,202 @@ impl<'data> Chunk<'data> {
 
 pub struct Client {
     pub stream: UnixStream,
+    /// The capabilities both this client and the daemon support.
+    capabilities: Vec<String>,
+    /// How long the hello exchange took, as opposed to connecting.
+    pub handshake_time: time::Duration,
 }
 
 impl Client {
+    /// Connect to the daemon and perform the protocol handshake.
     pub fn new<P: AsRef<Path>>(sock: P) -> anyhow::Result<Self> {
-        let stream = UnixStream::connect(sock).context("connecting to shpool")?;
-        Ok(Client { stream })
+        Self::with_timeout(sock, None)
     }
 
-    pub fn write_connect_header(&mut self, header: ConnectHeader) -> anyhow::Result<()> {
-        let serialize_stream = self.stream.try_clone().context("cloning stream for reply")?;
-        bincode::serialize_into(serialize_stream, &header).context("writing reply")?;
+    /// Like new, but give up on any read or write, starting with the
+    /// handshake, that takes longer than the given timeout. The timeout
+    /// stays in effect until it is reset on the stream.
+    pub fn with_timeout<P: AsRef<Path>>(
+        sock: P,
+        timeout: Option<time::Duration>,
+    ) -> anyhow::Result<Self> {
+        let mut stream = UnixStream::connect(sock).context("connecting to shpool")?;
+        stream.set_read_timeout(timeout).context("setting read timeout")?;
+        stream.set_write_timeout(timeout).context("setting write timeout")?;
 
-        Ok(())
+        let handshake_start = time::Instant::now();
+        let serialize_stream = stream.try_clone().context("cloning stream for hello")?;
+        bincode::serialize_into(serialize_stream, &ClientHello::default())
+            .context("writing client hello")?;
+        let hello: DaemonHello = read_hello(&mut stream).context("reading daemon hello")?;
+        if let Some(err) = hello.error {
+            eprintln!("shpool: {}", err);
+            return Err(err).context("protocol handshake");
+        }
+        let handshake_time = handshake_start.elapsed();
+        debug!(
+            "daemon speaks protocol v{} with capabilities {:?} (handshake took {:?})",
+            hello.version, hello.capabilities, handshake_time
+        );
+
+        Ok(Client { stream, capabilities: hello.capabilities, handshake_time })
+    }
+
+    /// The capabilities both this client and the daemon support.
+    pub fn capabilities(&self) -> &[String] {
+        &self.capabilities
+    }
+
+    /// Check that the daemon supports the given capability, printing
+    /// an error for the user if it does not.
+    pub fn require_capability(&self, capability: &str) -> anyhow::Result<()> {
+        if self.capabilities.iter().any(|c| c == capability) {
+            return Ok(());
+        }
+
+        eprintln!(
+            "shpool: the daemon does not support '{}', you probably need to restart it after upgrading shpool",
+            capability
+        );
+        Err(anyhow!("daemon lacks capability '{}'", capability))
+    }
+
+    pub fn write_connect_header(&mut self, header: ConnectHeader) -> anyhow::Result<()> {
+        write_frame(&mut self.stream, &header).context("writing header")
     }
 
     pub fn read_reply<R>(&mut self) -> anyhow::Result<R>
     where
         R: serde::de::DeserializeOwned,
     {
-        let reply: R = bincode::deserialize_from(&mut self.stream).context("parsing header")?;
-        Ok(reply)
+        read_frame(&mut self.stream).context("parsing reply")
     }
 
     /// pipe_bytes suffles bytes from std{in,out} to the unix
     /// socket and back again. It is the main loop of
     /// `shpool attach`.
     ///
+    /// If raw_mode is false, the local terminal is left in whatever
+    /// mode it was already in, which is what we want for dumb terminals
+    /// that do their own line editing.
+    ///
+    /// If there is a timer, the wait for the first output gets timed,
+    /// phases the daemon timed get added to it, and it gets printed
+    /// once the stream is done.
+    ///
+    /// If local_edit is true, lines typed at the shell's prompt get
+    /// edited locally and only sent once they are done, as long as the
+    /// daemon sends prompt hints. The toggle-local-edit keybinding
+    /// flips it.
+    ///
     /// Return value: the exit status that `shpool attach` should
-    /// exit with.
+    /// exit with, or the job it should attach to next.
     #[instrument(skip_all)]
-    pub fn pipe_bytes(self) -> anyhow::Result<i32> {
-        let tty_guard = tty::set_attach_flags()?;
+    pub fn pipe_bytes(
+        self,
+        raw_mode: bool,
+        local_edit: bool,
+        timer: Option<timing::Timer>,
+    ) -> anyhow::Result<PipeEnd> {
+        let tty_guard = if raw_mode { Some(tty::set_attach_flags()?) } else { None };
+        // Status lines would just be junk in a pipe.
+        let show_notices = raw_mode && isatty(io::stdout().as_raw_fd()).unwrap_or(false);
 
         let mut read_client_stream = self.stream.try_clone().context("cloning read stream")?;
         let mut write_client_stream = self.stream.try_clone().context("cloning read stream")?;
 
         let exit_status = AtomicI32::new(1);
+        // Why the daemon hung up on us, if it said.
+        let shutdown_reason: Mutex<Option<String>> = Mutex::new(None);
+        let timer = timer.map(Mutex::new);
+        let report_shutdown = || {
+            if let Some(reason) = shutdown_reason.lock().unwrap().take() {
+                eprintln!("shpool: {}", reason);
+            }
+            if let Some(timer) = &timer {
+                eprint!("{}", timer.lock().unwrap().summary());
+            }
+        };
+        // Where to go next if the daemon asked us to switch jobs.
+        let switch_job: Mutex<Option<JobTarget>> = Mutex::new(None);
+        // Tells the stdin thread to stop reading so that a switch can
+        // hand the terminal over to the next connection.
+        let stop = AtomicBool::new(false);
+        let local_edit = AtomicBool::new(local_edit);
+        // The column the cursor sits in at the shell's prompt, as of the
+        // last prompt hint, and how many hints there have been, so that
+        // a line only gets edited locally when starting from a fresh one.
+        let prompt: Mutex<Option<u16>> = Mutex::new(None);
+        let prompt_seq = AtomicUsize::new(0);
         thread::scope(|s| {
             // stdin -> sock
             let stdin_to_sock_h = s.spawn(|| -> anyhow::Result<()> {
                 let _s = span!(Level::INFO, "stdin->sock").entered();
                 let mut stdin = std::io::stdin().lock();
                 let mut buf = vec![0; consts::BUF_SIZE];
+                let mut editor: Option<LineEditor> = None;
+                let mut editing_from_seq = 0;
 
                 loop {
+                    let mut poll_fds = [poll::PollFd::new(stdin.as_fd(), poll::PollFlags::POLLIN)];
+                    let nready = poll::poll(&mut poll_fds, JOIN_POLL_DUR.as_millis() as u16)
+                        .context("polling stdin")?;
+                    if stop.load(Ordering::Acquire) {
+                        return Ok(());
+                    }
+
+                    // The shell left its prompt, or local editing got
+                    // turned off, so the line so far is up to the shell.
+                    let prompt_col = *prompt.lock().unwrap();
+                    let editing = local_edit.load(Ordering::Acquire);
+                    if editor.is_some() && (!editing || prompt_col.is_none()) {
+                        let mut out = local_edit::Output::default();
+                        if let Some(mut e) = editor.take() {
+                            e.give_up(&mut out);
+                        }
+                        write_stdout_unlocked(&out.echo)?;
+                        write_client_stream.write_all(&out.send)?;
+                        write_client_stream.flush().context("flushing client")?;
+                    }
+
+                    if nready == 0 {
+                        continue;
+                    }
+
                     let nread = stdin.read(&mut buf).context("reading stdin from user")?;
                     if nread == 0 {
                         continue;
                     }
                     debug!("read {} bytes", nread);
 
-                    let to_write = &buf[..nread];
+                    let mut to_write = &buf[..nread];
                     trace!("created to_write='{}'", String::from_utf8_lossy(to_write));
 
-                    write_client_stream.write_all(to_write)?;
-                    write_client_stream.flush().context("flushing client")?;
+                    let seq = prompt_seq.load(Ordering::Acquire);
+                    if let (true, None, Some(col)) = (editing, &editor, prompt_col) {
+                        // A hint from before the last line got sent
+                        // is stale, the shell has not caught up yet.
+                        if seq != editing_from_seq {
+                            let cols = tty::Size::from_fd(consts::STDIN_FD)
+                                .map(|size| size.cols)
+                                .unwrap_or(80);
+                            editor = Some(LineEditor::new(col, cols));
+                            editing_from_seq = seq;
+                        }
+                    }
+                    let mut edited = local_edit::Output::default();
+                    if let Some(e) = editor.as_mut() {
+                        if e.feed(to_write, &mut edited) {
+                            editor = None;
+                        }
+                        write_stdout_unlocked(&edited.echo)?;
+                        to_write = edited.send.as_slice();
+                    }
+
+                    if !to_write.is_empty() {
+                        write_client_stream.write_all(to_write)?;
+                        write_client_stream.flush().context("flushing client")?;
+                    }
                 }
             });
 
@@ -400,6 +1732,10 @@ impl Client {
 
                 let mut stdout = std::io::stdout().lock();
                 let mut buf = vec![0; consts::BUF_SIZE];
+                let mut saw_output = false;
+                // A replay notice is up, and should be cleared before
+                // the replay gets written over it.
+                let mut showing_notice = false;
 
                 loop {
                     let chunk = match Chunk::read_into(&mut read_client_stream, &mut buf) {
@@ -424,7 +1760,15 @@ impl Client {
                             trace!("got heartbeat chunk");
                         }
                         ChunkKind::Data => {
+                            if showing_notice {
+                                stdout.write_all(CLEAR_LINE).context("clearing replay notice")?;
+                                showing_notice = false;
+                            }
                             stdout.write_all(chunk.buf).context("writing chunk to stdout")?;
+                            if let (false, Some(timer)) = (saw_output, &timer) {
+                                timer.lock().unwrap().phase("first output");
+                            }
+                            saw_output = true;
 
                             if let Err(e) = stdout.flush() {
                                 if e.kind() == std::io::ErrorKind::WouldBlock {
@@ -447,6 +1791,81 @@ impl Client {
                                 Ordering::Release,
                             );
                         }
+                        ChunkKind::Control => {
+                            match bincode::deserialize::<StreamControl>(chunk.buf) {
+                                Ok(StreamControl::Notice(msg)) => info!("daemon notice: {}", msg),
+                                Ok(StreamControl::Shutdown(reason)) => {
+                                    info!("daemon shutting down: {}", reason);
+                                    *shutdown_reason.lock().unwrap() = Some(reason);
+                                    return Ok(());
+                                }
+                                Ok(StreamControl::Ended(reason)) => {
+                                    info!("daemon ended the session: {}", reason);
+                                    *shutdown_reason.lock().unwrap() = Some(reason);
+                                    return Ok(());
+                                }
+                                Ok(StreamControl::SwitchJob(target)) => {
+                                    info!("switching jobs: {:?}", target);
+                                    *switch_job.lock().unwrap() = Some(target);
+                                    stop.store(true, Ordering::Release);
+                                    return Ok(());
+                                }
+                                Ok(StreamControl::Timings(phases)) => {
+                                    debug!("daemon timings: {:?}", phases);
+                                    if let Some(timer) = &timer {
+                                        timer.lock().unwrap().extend("daemon ", phases);
+                                    }
+                                }
+                                Ok(StreamControl::Replay { bytes }) => {
+                                    info!("daemon replaying {} bytes", bytes);
+                                    if show_notices {
+                                        stdout.write_all(CLEAR_LINE)?;
+                                        write!(
+                                            stdout,
+                                            "shpool: restoring {} of output...",
+                                            human_size(bytes)
+                                        )
+                                        .context("writing replay notice")?;
+                                        stdout.flush().context("flushing replay notice")?;
+                                        showing_notice = true;
+                                    }
+                                }
+                                Ok(StreamControl::Suspend) => {
+                                    info!("daemon asked us to suspend");
+                                    // There is no job control to hand
+                                    // the terminal back to in a pipe.
+                                    if raw_mode {
+                                        stdout.flush().context("flushing before suspend")?;
+                                        signal::raise(Signal::SIGTSTP).context("suspending")?;
+                                    }
+                                }
+                                Ok(StreamControl::Prompt(hint)) => {
+                                    debug!("prompt hint: {:?}", hint);
+                                    *prompt.lock().unwrap() = hint;
+                                    if hint.is_some() {
+                                        prompt_seq.fetch_add(1, Ordering::AcqRel);
+                                    }
+                                }
+                                Ok(StreamControl::ToggleLocalEdit) => {
+                                    let was = local_edit.fetch_xor(true, Ordering::AcqRel);
+                                    info!("local editing {}", if was { "off" } else { "on" });
+                                }
+                                Ok(StreamControl::Message(msg)) => {
+                                    info!("daemon message: {}", msg);
+                                    if show_notices {
+                                        stdout.write_all(MESSAGE_START)?;
+                                        write!(stdout, " shpool: {} ", msg)?;
+                                        stdout.write_all(MESSAGE_END)?;
+                                        stdout.flush().context("flushing message")?;
+                                    } else {
+                                        eprintln!("shpool: {}", msg);
+                                    }
+                                }
+                                // The chunk framing means we can skip over
+                                // messages from a newer daemon and stay in sync.
+                                Err(e) => warn!("skipping unknown control message: {:?}", e),
+                            }
+                        }
                     }
                 }
             });
@@ -485,6 +1904,7 @@ impl Client {
                             // make sure that we restore the tty flags on the input
                             // tty before exiting the process.
                             drop(tty_guard);
+                            report_shutdown();
 
                             std::process::exit(exit_status.load(Ordering::Acquire));
                         }
@@ -495,6 +1915,11 @@ impl Client {
             }
 
             match stdin_to_sock_h.join() {
+                // the daemon hangs up on a switch, so there is a window
+                // where input can't go anywhere
+                Ok(Err(e)) if switch_job.lock().unwrap().is_some() => {
+                    info!("dropping input sent while switching jobs: {:?}", e)
+                }
                 Ok(v) => v?,
                 Err(panic_err) => std::panic::resume_unwind(panic_err),
             }
@@ -503,15 +1928,93 @@ impl Client {
                 Err(panic_err) => std::panic::resume_unwind(panic_err),
             }
 
-            Ok(exit_status.load(Ordering::Acquire))
+            drop(tty_guard);
+            report_shutdown();
+            Ok(match switch_job.lock().unwrap().take() {
+                Some(target) => PipeEnd::SwitchJob(target),
+                None => PipeEnd::Exit(exit_status.load(Ordering::Acquire)),
+            })
         })
     }
 }
 
+/// Format a byte count for people, like "3.2 MiB".
+/// Write to stdout without taking the lock on it, which the
+/// sock->stdout thread holds for as long as it runs.
+fn write_stdout_unlocked(mut buf: &[u8]) -> anyhow::Result<()> {
+    while !buf.is_empty() {
+        match unistd::write(io::stdout().as_fd(), buf) {
+            Ok(n) => buf = &buf[n..],
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => return Err(e).context("writing local echo"),
+        }
+    }
+    Ok(())
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn negotiate_hello() {
+        let reply = negotiate(&ClientHello::default());
+        assert_eq!(reply.error, None);
+        assert_eq!(reply.capabilities, CAPABILITIES);
+
+        let reply = negotiate(&ClientHello {
+            capabilities: vec![String::from("gc"), String::from("teleport")],
+            ..ClientHello::default()
+        });
+        assert_eq!(reply.error, None);
+        assert_eq!(reply.capabilities, vec![String::from("gc")]);
+
+        let reply = negotiate(&ClientHello { version: VERSION + 1, ..ClientHello::default() });
+        assert_eq!(
+            reply.error,
+            Some(HandshakeError::VersionMismatch {
+                client_version: VERSION + 1,
+                daemon_version: VERSION
+            })
+        );
+        assert!(reply.capabilities.is_empty());
+
+        let reply = negotiate(&ClientHello { magic: 0, ..ClientHello::default() });
+        assert_eq!(reply.error, Some(HandshakeError::BadHello));
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let mut buf = vec![];
+        bincode::serialize_into(&mut buf, &ClientHello::default()).expect("write to succeed");
+        let hello: ClientHello = read_hello(&mut &buf[..]).expect("read to succeed");
+        assert_eq!(hello.magic, HELLO_MAGIC);
+        assert_eq!(hello.capabilities, CAPABILITIES);
+
+        // a capability claiming to be enormous gets turned away before
+        // anything tries to make room for it
+        let mut buf = vec![];
+        buf.extend_from_slice(&HELLO_MAGIC.to_le_bytes());
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&1u64.to_le_bytes());
+        buf.extend_from_slice(&(1u64 << 62).to_le_bytes());
+        assert!(read_hello::<_, ClientHello>(&mut &buf[..]).is_err());
+    }
+
     #[test]
     fn chunk_round_trip() {
         let data: Vec<u8> = vec![0, 0, 0, 1, 5, 6];
@@ -519,6 +2022,7 @@ mod test {
             Chunk { kind: ChunkKind::Data, buf: data.as_slice() },
             Chunk { kind: ChunkKind::Heartbeat, buf: &data[..0] },
             Chunk { kind: ChunkKind::ExitStatus, buf: &data[..4] },
+            Chunk { kind: ChunkKind::Control, buf: data.as_slice() },
         ];
 
         let mut buf = vec![0; 256];
@@ -529,6 +2033,61 @@ mod test {
             let round_tripped =
                 Chunk::read_into(&mut file_obj, &mut buf).expect("parse to succeed");
             assert_eq!(c, round_tripped);
+
+            // the header plus the buf is the same thing write_to writes
+            let mut encoded = vec![];
+            c.encode_header(&mut encoded);
+            encoded.extend_from_slice(c.buf);
+            assert_eq!(encoded, file_obj.get_ref()[..encoded.len()]);
         }
     }
+
+    #[test]
+    fn frame_round_trip() {
+        let mut stream = io::Cursor::new(vec![]);
+        write_frame(&mut stream, &ConnectHeader::List).expect("write to succeed");
+        write_frame(&mut stream, &KillRequest { sessions: vec![String::from("s")] })
+            .expect("write to succeed");
+        stream.set_position(0);
+
+        let header: ConnectHeader = read_frame(&mut stream).expect("read to succeed");
+        assert!(matches!(header, ConnectHeader::List));
+        let req: KillRequest = read_frame(&mut stream).expect("read to succeed");
+        assert_eq!(req.sessions, vec![String::from("s")]);
+
+        // a truncated frame is an error rather than a short message
+        let mut stream = io::Cursor::new(vec![8, 0, 0, 0, 1, 2]);
+        assert!(read_frame::<_, ConnectHeader>(&mut stream).is_err());
+
+        let mut stream = io::Cursor::new(vec![0xff, 0xff, 0xff, 0xff]);
+        assert!(read_frame::<_, ConnectHeader>(&mut stream).is_err());
+    }
+
+    #[test]
+    fn control_chunk_interleaving() {
+        let mut stream = io::Cursor::new(vec![]);
+        Chunk { kind: ChunkKind::Data, buf: b"before" }.write_to(&mut stream).unwrap();
+        StreamControl::Notice(String::from("hi")).write_to(&mut stream).unwrap();
+        Chunk { kind: ChunkKind::Data, buf: b"after" }.write_to(&mut stream).unwrap();
+        stream.set_position(0);
+
+        let mut buf = vec![0; 256];
+        let chunk = Chunk::read_into(&mut stream, &mut buf).unwrap();
+        assert_eq!(chunk, Chunk { kind: ChunkKind::Data, buf: b"before" });
+        let chunk = Chunk::read_into(&mut stream, &mut buf).unwrap();
+        assert_eq!(chunk.kind, ChunkKind::Control);
+        let msg: StreamControl = bincode::deserialize(chunk.buf).unwrap();
+        assert_eq!(msg, StreamControl::Notice(String::from("hi")));
+        let chunk = Chunk::read_into(&mut stream, &mut buf).unwrap();
+        assert_eq!(chunk, Chunk { kind: ChunkKind::Data, buf: b"after" });
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_size(0), "0 bytes");
+        assert_eq!(human_size(1023), "1023 bytes");
+        assert_eq!(human_size(1024), "1.0 KiB");
+        assert_eq!(human_size(3 * 1024 * 1024 + 200 * 1024), "3.2 MiB");
+        assert_eq!(human_size(u64::MAX), "16777216.0 TiB");
+    }
 }
@@ -0,0 +1,448 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Backends for running the child process of a session.
+
+  The daemon doesn't care much how a session's child gets run, just that
+  it gets a file descriptor it can poll, read the child's output from
+  and write input to, and that it can resize the child's terminal and
+  wait for it to exit. `PtyBackend` captures that, which lets wrapping
+  binaries swap in something other than a real pty.
+
+  `Forking` is the backend the daemon normally uses. `Fake` runs the
+  child with a socket in place of a pty, which is enough to drive a
+  shell from tests in sandboxes that don't hand out ptys. `adopt` wraps
+  a pty that some earlier daemon spawned.
+*/
+
+use std::{
+    fmt, io,
+    os::{
+        fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd},
+        unix::{net::UnixStream, process::CommandExt},
+    },
+    process,
+    sync::Mutex,
+    thread, time,
+};
+
+use anyhow::{anyhow, Context};
+use nix::{
+    errno::Errno,
+    fcntl, poll,
+    sys::{
+        signal,
+        wait::{self, WaitStatus},
+    },
+    unistd::Pid,
+};
+use tracing::info;
+
+pub use crate::tty::Size;
+use crate::{consts, tty};
+
+/// A way of spawning the child process of a session.
+pub trait PtyBackend: fmt::Debug {
+    /// Start the given command on the far side of a new pty. The command
+    /// should get the pty as its stdin, stdout and stderr.
+    fn spawn(&self, req: SpawnRequest) -> anyhow::Result<Box<dyn Pty + Send + Sync>>;
+}
+
+/// What to run in a new pty.
+#[derive(Debug)]
+pub struct SpawnRequest {
+    pub cmd: process::Command,
+    /// Turn off echo on the pty before starting the command.
+    pub noecho: bool,
+    /// Turn off XON/XOFF flow control on the pty before starting the
+    /// command.
+    pub no_flow_control: bool,
+}
+
+/// A running child process along with the pty it is attached to.
+pub trait Pty: fmt::Debug {
+    /// The daemon's end of the pty.
+    fn master(&self) -> Master;
+
+    fn child_pid(&self) -> libc::pid_t;
+
+    /// Block until the child exits, returning its exit status if it
+    /// exited normally.
+    fn wait_for_exit(&self) -> anyhow::Result<Option<i32>>;
+
+    fn size(&self) -> anyhow::Result<Size>;
+
+    fn set_size(&self, size: &Size) -> anyhow::Result<()>;
+
+    /// Check if the child looks like it is reading a password.
+    fn reading_password(&self) -> anyhow::Result<bool>;
+    /// Check if the child looks like it is sitting at a prompt, with
+    /// nothing else running in the foreground.
+    fn at_prompt(&self) -> anyhow::Result<bool>;
+    /// Check if the pty pauses the child's output on Ctrl-S.
+    fn flow_control(&self) -> anyhow::Result<bool>;
+}
+
+/// The daemon's end of a pty. This is just a borrowed fd so that it can
+/// be copied out to the threads that shuffle data in and out of the
+/// child, which means that it must not be used once the `Pty` it came
+/// from has been dropped.
+#[derive(Debug, Copy, Clone)]
+pub struct Master {
+    fd: RawFd,
+}
+
+impl Master {
+    pub fn new(fd: RawFd) -> Self {
+        Master { fd }
+    }
+
+    pub fn raw_fd(&self) -> RawFd {
+        self.fd
+    }
+
+    pub fn borrow_fd(&self) -> BorrowedFd<'_> {
+        // Safety: the fd stays open as long as the pty it came from
+        //         is live, which it must be for the master to be used.
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+
+    /// Put the master in non-blocking mode, so that input can be fed
+    /// to the child with `try_write` as it takes it. Reads and writes
+    /// through the io traits still block.
+    pub fn set_nonblocking(&self) -> anyhow::Result<()> {
+        let flags = fcntl::fcntl(self.fd, fcntl::FcntlArg::F_GETFL).context("getting flags")?;
+        let flags = fcntl::OFlag::from_bits_truncate(flags) | fcntl::OFlag::O_NONBLOCK;
+        fcntl::fcntl(self.fd, fcntl::FcntlArg::F_SETFL(flags)).context("setting flags")?;
+        Ok(())
+    }
+
+    /// Write as much of buf as the child will take right now, which is
+    /// only different from a plain write once the master is in
+    /// non-blocking mode.
+    pub fn try_write(&self, buf: &[u8]) -> io::Result<usize> {
+        nix::unistd::write(self.borrow_fd(), buf).map_err(io::Error::from)
+    }
+
+    /// Wait for the master to be ready, in case it is non-blocking.
+    fn wait(&self, flags: poll::PollFlags) -> io::Result<()> {
+        let mut poll_fds = [poll::PollFd::new(self.borrow_fd(), flags)];
+        match poll::poll(&mut poll_fds, poll::PollTimeout::NONE) {
+            Ok(_) | Err(Errno::EINTR) => Ok(()),
+            Err(e) => Err(io::Error::from(e)),
+        }
+    }
+}
+
+impl io::Read for Master {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match nix::unistd::read(self.fd, buf) {
+                Ok(len) => return Ok(len),
+                Err(Errno::EAGAIN) => self.wait(poll::PollFlags::POLLIN)?,
+                Err(Errno::EINTR) => continue,
+                // Reading a pty whose child has exited gives EIO rather
+                // than EOF, so treat any error as the end of the output.
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+impl io::Write for Master {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.try_write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.wait(poll::PollFlags::POLLOUT)?
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn wait_pid(pid: libc::pid_t) -> anyhow::Result<Option<i32>> {
+    loop {
+        match wait::waitpid(Pid::from_raw(pid), None).context("waiting on child")? {
+            WaitStatus::Exited(_, status) => return Ok(Some(status)),
+            WaitStatus::Signaled(..) => return Ok(None),
+            // waitpid without WUNTRACED shouldn't report anything else
+            _ => continue,
+        }
+    }
+}
+
+/// Forks the daemon and execs the command in a fresh pty from /dev/ptmx.
+#[derive(Debug, Default)]
+pub struct Forking {}
+
+impl PtyBackend for Forking {
+    fn spawn(&self, req: SpawnRequest) -> anyhow::Result<Box<dyn Pty + Send + Sync>> {
+        let SpawnRequest { mut cmd, noecho, no_flow_control } = req;
+        let fork = shpool_pty::fork::Fork::from_ptmx().context("forking pty")?;
+        if let Ok(slave) = fork.is_child() {
+            if noecho {
+                if let Some(fd) = slave.borrow_fd() {
+                    tty::disable_echo(fd).context("disabling echo on pty")?;
+                }
+            }
+            if no_flow_control {
+                if let Some(fd) = slave.borrow_fd() {
+                    tty::disable_flow_control(fd).context("disabling flow control on pty")?;
+                }
+            }
+            for fd in consts::STDERR_FD + 1..(nix::unistd::SysconfVar::OPEN_MAX as i32) {
+                let _ = nix::unistd::close(fd);
+            }
+            let err = cmd.exec();
+            eprintln!("shell exec err: {:?}", err);
+            std::process::exit(1);
+        }
+
+        let child_pid = fork.child_pid().ok_or(anyhow!("no child pid"))?;
+        let fd = *fork.is_parent().context("getting pty master")?.raw_fd();
+        let fd = fd.ok_or(anyhow!("no master fd"))?;
+        Ok(Box::new(ForkedPty { fork, child_pid, master: Master::new(fd) }))
+    }
+}
+
+#[derive(Debug)]
+struct ForkedPty {
+    /// Owns the master fd, closing it on drop.
+    #[allow(dead_code)]
+    fork: shpool_pty::fork::Fork,
+    child_pid: libc::pid_t,
+    master: Master,
+}
+
+impl Pty for ForkedPty {
+    fn master(&self) -> Master {
+        self.master
+    }
+
+    fn child_pid(&self) -> libc::pid_t {
+        self.child_pid
+    }
+
+    fn wait_for_exit(&self) -> anyhow::Result<Option<i32>> {
+        wait_pid(self.child_pid)
+    }
+
+    fn size(&self) -> anyhow::Result<Size> {
+        Size::from_fd(self.master.fd)
+    }
+
+    fn set_size(&self, size: &Size) -> anyhow::Result<()> {
+        size.set_fd(self.master.fd)
+    }
+
+    fn reading_password(&self) -> anyhow::Result<bool> {
+        tty::reading_password(self.master.borrow_fd())
+    }
+
+    fn at_prompt(&self) -> anyhow::Result<bool> {
+        tty::in_foreground(self.master.borrow_fd(), self.child_pid)
+    }
+
+    fn flow_control(&self) -> anyhow::Result<bool> {
+        tty::flow_control(self.master.borrow_fd())
+    }
+}
+
+/// How often to check if the child of an adopted pty is still around.
+const ADOPTED_POLL_DUR: time::Duration = time::Duration::from_millis(500);
+
+/// Take over a pty whose child was spawned by another process, such as
+/// an earlier daemon. The child usually isn't ours to wait on, so the
+/// exit gets noticed by polling and the exit status is never known.
+/// After an in place restart the child is still ours though, and gets
+/// reaped like any other.
+pub fn adopt(master: OwnedFd, child_pid: libc::pid_t) -> Box<dyn Pty + Send + Sync> {
+    Box::new(AdoptedPty { master: Master::new(master.as_raw_fd()), fd: master, child_pid })
+}
+
+#[derive(Debug)]
+struct AdoptedPty {
+    master: Master,
+    /// Owns the fd the master refers to.
+    #[allow(dead_code)]
+    fd: OwnedFd,
+    child_pid: libc::pid_t,
+}
+
+impl Pty for AdoptedPty {
+    fn master(&self) -> Master {
+        self.master
+    }
+
+    fn child_pid(&self) -> libc::pid_t {
+        self.child_pid
+    }
+
+    fn wait_for_exit(&self) -> anyhow::Result<Option<i32>> {
+        let pid = Pid::from_raw(self.child_pid);
+        loop {
+            // a child of ours sticks around as a zombie until it gets
+            // reaped, so kill(2) alone would never see it go
+            match wait::waitpid(pid, Some(wait::WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(_, status)) => return Ok(Some(status)),
+                Ok(WaitStatus::Signaled(..)) => return Ok(None),
+                _ => {}
+            }
+            match signal::kill(pid, None) {
+                Err(Errno::ESRCH) => return Ok(None),
+                _ => thread::sleep(ADOPTED_POLL_DUR),
+            }
+        }
+    }
+
+    fn size(&self) -> anyhow::Result<Size> {
+        Size::from_fd(self.master.fd)
+    }
+
+    fn set_size(&self, size: &Size) -> anyhow::Result<()> {
+        size.set_fd(self.master.fd)
+    }
+
+    fn reading_password(&self) -> anyhow::Result<bool> {
+        tty::reading_password(self.master.borrow_fd())
+    }
+
+    fn at_prompt(&self) -> anyhow::Result<bool> {
+        tty::in_foreground(self.master.borrow_fd(), self.child_pid)
+    }
+
+    fn flow_control(&self) -> anyhow::Result<bool> {
+        tty::flow_control(self.master.borrow_fd())
+    }
+}
+
+/// A stand in for a real pty, meant for testing. The child gets one end
+/// of a socket pair as its stdin, stdout and stderr, and the size of the
+/// terminal only exists in memory. Since the child isn't attached to a
+/// terminal, shells will run non-interactively, so there is no prompt
+/// and no line editing.
+#[derive(Debug, Default)]
+pub struct Fake {}
+
+impl PtyBackend for Fake {
+    fn spawn(&self, req: SpawnRequest) -> anyhow::Result<Box<dyn Pty + Send + Sync>> {
+        let SpawnRequest { mut cmd, .. } = req;
+        let (daemon_end, child_end) = UnixStream::pair().context("creating socket pair")?;
+        let child_end = OwnedFd::from(child_end);
+        cmd.stdin(child_end.try_clone().context("duping child socket")?)
+            .stdout(child_end.try_clone().context("duping child socket")?)
+            .stderr(child_end);
+        let child = cmd.spawn().context("spawning child")?;
+        // drop our copies of the child's end so that we see EOF once it exits
+        drop(cmd);
+        info!("spawned child (pid={}) with a fake pty", child.id());
+
+        Ok(Box::new(FakePty {
+            master: Master::new(daemon_end.as_raw_fd()),
+            stream: daemon_end,
+            child_pid: child.id() as libc::pid_t,
+            size: Mutex::new(Size::default()),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct FakePty {
+    master: Master,
+    /// Owns the fd the master refers to.
+    #[allow(dead_code)]
+    stream: UnixStream,
+    child_pid: libc::pid_t,
+    size: Mutex<Size>,
+}
+
+impl Pty for FakePty {
+    fn master(&self) -> Master {
+        self.master
+    }
+
+    fn child_pid(&self) -> libc::pid_t {
+        self.child_pid
+    }
+
+    fn wait_for_exit(&self) -> anyhow::Result<Option<i32>> {
+        wait_pid(self.child_pid)
+    }
+
+    fn size(&self) -> anyhow::Result<Size> {
+        Ok(self.size.lock().unwrap().clone())
+    }
+
+    fn set_size(&self, size: &Size) -> anyhow::Result<()> {
+        *self.size.lock().unwrap() = size.clone();
+        Ok(())
+    }
+
+    fn reading_password(&self) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    fn at_prompt(&self) -> anyhow::Result<bool> {
+        // there is no job control without a terminal, so the child
+        // is the only thing that can be reading input
+        Ok(true)
+    }
+
+    fn flow_control(&self) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn fake() -> anyhow::Result<()> {
+        let pty = Fake::default().spawn(SpawnRequest {
+            cmd: process::Command::new("cat"),
+            noecho: false,
+            no_flow_control: false,
+        })?;
+
+        let size = Size { rows: 10, cols: 20, xpixel: 0, ypixel: 0 };
+        pty.set_size(&size)?;
+        assert_eq!(pty.size()?, size);
+
+        let mut master = pty.master();
+        master.write_all(b"hello\n")?;
+        let mut buf = [0; 6];
+        master.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"hello\n");
+
+        nix::sys::signal::kill(
+            Pid::from_raw(pty.child_pid()),
+            Some(nix::sys::signal::Signal::SIGHUP),
+        )?;
+        assert_eq!(pty.wait_for_exit()?, None);
+        assert_eq!(master.read(&mut buf)?, 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{env, io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    protocol,
+    protocol::{ConnectHeader, RecordAction, RecordReply, RecordRequest},
+    RecordCommands,
+};
+
+pub fn run(session: String, command: RecordCommands, socket: PathBuf) -> anyhow::Result<()> {
+    let action = match command {
+        // the daemon has a different working directory than we do
+        RecordCommands::Start { file } => RecordAction::Start {
+            file: match file {
+                Some(f) => Some(
+                    env::current_dir()
+                        .context("getting cwd")?
+                        .join(f)
+                        .to_string_lossy()
+                        .into_owned(),
+                ),
+                None => None,
+            },
+        },
+        RecordCommands::Stop => RecordAction::Stop,
+    };
+
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require_capability("record")?;
+    client
+        .write_connect_header(ConnectHeader::Record(RecordRequest {
+            session: session.clone(),
+            action,
+        }))
+        .context("writing record request header")?;
+    let reply: RecordReply = client.read_reply().context("reading reply")?;
+
+    match reply {
+        RecordReply::Started(path) => println!("recording {} to {}", session, path),
+        RecordReply::Stopped(path) => println!("saved recording of {} to {}", session, path),
+        RecordReply::AlreadyRecording(path) => {
+            eprintln!("{} is already being recorded to {}", session, path);
+            return Err(anyhow!("already recording"));
+        }
+        RecordReply::NotRecording => {
+            eprintln!("{} is not being recorded", session);
+            return Err(anyhow!("not recording"));
+        }
+        RecordReply::Failed(reason) => {
+            eprintln!("could not start recording: {}", reason);
+            return Err(anyhow!("starting recording: {}", reason));
+        }
+        RecordReply::NotFound => {
+            eprintln!("not found: {}", session);
+            return Err(anyhow!("not found: {}", session));
+        }
+        RecordReply::Forbidden(reason) => {
+            eprintln!("forbidden: {}", reason);
+            return Err(anyhow!("forbidden: {}", reason));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,71 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf, thread, time};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    protocol,
+    protocol::{ConnectHeader, RestartReply, RestartRequest},
+};
+
+/// How long to wait for the new daemon to start taking connections.
+const RESTART_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+const RESTART_POLL_DUR: time::Duration = time::Duration::from_millis(50);
+
+pub fn run(preserve_sessions: bool, socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(&socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require_capability("restart")?;
+    client
+        .write_connect_header(ConnectHeader::Restart(RestartRequest { preserve_sessions }))
+        .context("writing restart request header")?;
+    let reply: RestartReply = client.read_reply().context("reading reply")?;
+
+    match reply {
+        RestartReply::Restarting { held } => {
+            for session in held.iter() {
+                println!("held: {}", session);
+            }
+        }
+        RestartReply::Failed(reason) => {
+            eprintln!("could not restart daemon: {}", reason);
+            return Err(anyhow!("could not restart daemon: {}", reason));
+        }
+    }
+
+    // don't hand control back until the new daemon is up, so that
+    // whatever runs next can count on it
+    let start = time::Instant::now();
+    loop {
+        match protocol::Client::with_timeout(&socket, Some(RESTART_TIMEOUT)) {
+            Ok(_) => return Ok(()),
+            Err(err) if start.elapsed() > RESTART_TIMEOUT => {
+                eprintln!("the daemon did not come back up, check its logs");
+                return Err(err).context("waiting for the daemon to restart");
+            }
+            Err(_) => thread::sleep(RESTART_POLL_DUR),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+use tracing::info;
+
+use super::{
+    attach, config, protocol,
+    protocol::{ConnectHeader, ResurrectOutcome, ResurrectReply, ResurrectRequest},
+    tty,
+};
+
+pub fn run(config_file: Option<String>, socket: PathBuf) -> anyhow::Result<()> {
+    let config_manager = config::Manager::new(config_file.as_deref())?;
+
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    // the sessions get started the same way `shpool new` would
+    let tty_size = match tty::Size::from_fd(0) {
+        Ok(s) => s,
+        Err(e) => {
+            info!("stdin is not a tty, using default size (err: {:?})", e);
+            tty::Size { rows: 24, cols: 80, xpixel: 0, ypixel: 0 }
+        }
+    };
+    let forward_patterns = config_manager.get().forward_env.clone().unwrap_or_default();
+
+    client.require_capability("resurrect")?;
+    client
+        .write_connect_header(ConnectHeader::Resurrect(ResurrectRequest {
+            local_tty_size: tty_size,
+            local_env: attach::local_env(&forward_patterns),
+        }))
+        .context("writing resurrect request header")?;
+    let reply: ResurrectReply = client.read_reply().context("reading reply")?;
+
+    let sessions = match reply {
+        ResurrectReply::Resurrected(sessions) => sessions,
+        ResurrectReply::NotEnabled => {
+            eprintln!("the daemon is not saving sessions, see resurrect in the config");
+            return Err(anyhow!("resurrect not enabled"));
+        }
+        ResurrectReply::Failed(reason) => {
+            eprintln!("could not read saved sessions: {}", reason);
+            return Err(anyhow!("reading saved sessions: {}", reason));
+        }
+    };
+
+    let mut failed = vec![];
+    for session in sessions.into_iter() {
+        match session.outcome {
+            ResurrectOutcome::Created => println!("{}\tcreated", session.name),
+            ResurrectOutcome::Running => println!("{}\talready running", session.name),
+            ResurrectOutcome::Failed(reason) => {
+                eprintln!("{}\tfailed: {}", session.name, reason);
+                failed.push(session.name);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(anyhow!("could not resurrect: {}", failed.join(" ")));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,417 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! `shpool roam`, an experimental way of attaching to a session on
+  another machine that survives the client changing networks or going
+  to sleep for a while.
+
+  `shpool roam` runs `shpool roam-server` on the remote host over ssh.
+  The server attaches to the session, binds a udp port, prints the port
+  and a fresh key and then gets out from under ssh so that it can exit.
+  From there on the client and the server talk over sealed datagrams
+  (see the wire module). Rather than shipping every byte of output, the
+  server keeps an emulated copy of the session's screen and syncs it to
+  the client (see the sync module), so a client that has been out of
+  touch catches right up to the current screen instead of replaying
+  everything it missed. Input gets numbered and sent again until the
+  server acknowledges it.
+
+  Anything the emulator does not keep track of, like the clipboard or
+  the bell, does not make it to the client.
+*/
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    os::fd::AsFd,
+    process, time,
+};
+
+use anyhow::{anyhow, bail, Context};
+use nix::poll;
+use tracing::{debug, info};
+
+use super::{common, duration, tty};
+
+pub mod server;
+mod sync;
+mod wire;
+
+/// Starts the line the server prints its connection details on.
+const BANNER: &str = "SHPOOL ROAM";
+
+/// How long to wait for datagrams before checking on everything else.
+const TICK: time::Duration = time::Duration::from_millis(10);
+/// How long to wait for an ack before sending something again.
+const RESEND: time::Duration = time::Duration::from_millis(250);
+/// How often to send something even with nothing to say, so that the
+/// other end knows we are still around.
+const HEARTBEAT: time::Duration = time::Duration::from_secs(3);
+/// How long to go without hearing from the server before saying so.
+const CONTACT_NOTICE: time::Duration = time::Duration::from_secs(5);
+
+/// The most input to send in one go.
+const MAX_INPUT: usize = 1024;
+
+pub const DEFAULT_PORTS: &str = "60001:60999";
+pub const DEFAULT_IDLE_TIMEOUT: &str = "1d";
+
+/// Ctrl-^, which starts the escape sequences only the client sees.
+const ESCAPE: u8 = 0x1e;
+
+pub struct Args {
+    pub ssh: String,
+    pub remote_shpool: String,
+    pub ports: Option<String>,
+    pub idle_timeout: Option<String>,
+    pub host: String,
+    pub session: String,
+}
+
+pub fn run(args: Args) -> anyhow::Result<()> {
+    let size =
+        tty::Size::from_fd(0).unwrap_or(tty::Size { rows: 24, cols: 80, xpixel: 0, ypixel: 0 });
+
+    let mut remote = shell_words::split(&args.remote_shpool).context("parsing --remote-shpool")?;
+    remote.extend(
+        ["roam-server", "--rows", &size.rows.to_string(), "--cols", &size.cols.to_string()]
+            .map(String::from),
+    );
+    if let Ok(term) = std::env::var("TERM") {
+        remote.extend([String::from("--term"), term]);
+    }
+    if let Some(ports) = &args.ports {
+        remote.extend([String::from("--ports"), ports.clone()]);
+    }
+    if let Some(idle_timeout) = &args.idle_timeout {
+        remote.extend([String::from("--idle-timeout"), idle_timeout.clone()]);
+    }
+    remote.extend([String::from("--"), args.session.clone()]);
+
+    let ssh = shell_words::split(&args.ssh).context("parsing --ssh")?;
+    let (ssh_bin, ssh_args) = ssh.split_first().ok_or(anyhow!("empty --ssh command"))?;
+    info!("running {:?} {:?} on {}", ssh, remote, args.host);
+    let out = process::Command::new(ssh_bin)
+        .args(ssh_args)
+        .arg(&args.host)
+        .arg("--")
+        .arg(shell_words::join(&remote))
+        .stdin(process::Stdio::inherit())
+        .stderr(process::Stdio::inherit())
+        .output()
+        .with_context(|| format!("running {}", ssh_bin))?;
+    if !out.status.success() {
+        bail!("starting the roam server on {} failed ({})", args.host, out.status);
+    }
+
+    let out = String::from_utf8_lossy(&out.stdout);
+    let details = out
+        .lines()
+        .find_map(|line| line.strip_prefix(BANNER))
+        .ok_or(anyhow!("the roam server on {} did not say where to find it", args.host))?;
+    let (addr, key) = parse_details(details, &args.host)?;
+    info!("roam server at {}", addr);
+
+    let status = {
+        let _tty_guard = tty::set_attach_flags()?;
+        Client::new(addr, &key, &args.host)?.run()?
+    };
+    process::exit(status);
+}
+
+/// Parse a port range like "60001:60999", or a single port.
+pub fn parse_ports(src: &str) -> anyhow::Result<(u16, u16)> {
+    let (low, high) = src.split_once(':').unwrap_or((src, src));
+    let low: u16 = low.parse().with_context(|| format!("parsing port range '{}'", src))?;
+    let high: u16 = high.parse().with_context(|| format!("parsing port range '{}'", src))?;
+    if low > high {
+        bail!("port range '{}' is backwards", src);
+    }
+    Ok((low, high))
+}
+
+/// Parse the ip, port and key the server printed after the banner,
+/// falling back to the ssh host when the server could not tell which ip
+/// it got reached at.
+fn parse_details(details: &str, host: &str) -> anyhow::Result<(SocketAddr, [u8; wire::KEY_LEN])> {
+    let fields: Vec<&str> = details.split_whitespace().collect();
+    let &[ip, port, key] = fields.as_slice() else {
+        bail!("could not parse roam server details '{}'", details);
+    };
+    let port: u16 = port.parse().context("parsing roam server port")?;
+    let addr = match ip.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port),
+        Err(_) => {
+            let host = host.rsplit('@').next().unwrap_or(host);
+            (host, port)
+                .to_socket_addrs()
+                .with_context(|| format!("resolving {}", host))?
+                .next()
+                .ok_or(anyhow!("{} has no addresses", host))?
+        }
+    };
+    let key = common::decode_hex(key).context("parsing roam key")?;
+    let key: [u8; wire::KEY_LEN] =
+        key.try_into().map_err(|_| anyhow!("roam key is the wrong length"))?;
+    Ok((addr, key))
+}
+
+struct Client<'host> {
+    udp: UdpSocket,
+    server: SocketAddr,
+    host: &'host str,
+    channel: wire::Channel,
+    receiver: sync::Receiver,
+    /// What the terminal is showing.
+    displayed: Option<shpool_vt100::Screen>,
+    /// Input the server has not acknowledged yet, and the sequence
+    /// number of the first chunk of it.
+    input: VecDeque<Vec<u8>>,
+    input_seq: u64,
+    size: tty::Size,
+    last_heard: time::Instant,
+    last_sent: time::Instant,
+    /// When the notice about losing touch with the server last got
+    /// drawn, if it is up.
+    notice_at: Option<time::Instant>,
+}
+
+impl<'host> Client<'host> {
+    fn new(
+        server: SocketAddr,
+        key: &[u8; wire::KEY_LEN],
+        host: &'host str,
+    ) -> anyhow::Result<Self> {
+        let bind_addr = if server.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+        let udp = UdpSocket::bind(bind_addr).context("binding udp socket")?;
+        udp.set_nonblocking(true).context("making udp socket nonblocking")?;
+        let now = time::Instant::now();
+        Ok(Client {
+            udp,
+            server,
+            host,
+            channel: wire::Channel::new(key, false)?,
+            receiver: sync::Receiver::default(),
+            displayed: None,
+            input: VecDeque::new(),
+            input_seq: 0,
+            size: tty::Size::from_fd(0)?,
+            last_heard: now,
+            last_sent: now - HEARTBEAT,
+            notice_at: None,
+        })
+    }
+
+    /// Pump input to the server and screens from it until the server
+    /// says goodbye or the user quits, returning the status to exit with.
+    fn run(mut self) -> anyhow::Result<i32> {
+        let mut stdin = io::stdin().lock();
+        let mut stdout = io::stdout().lock();
+        let mut buf = vec![0; 65536];
+        let mut escaped = false;
+        loop {
+            let mut send_now = false;
+            let mut poll_fds = [
+                poll::PollFd::new(stdin.as_fd(), poll::PollFlags::POLLIN),
+                poll::PollFd::new(self.udp.as_fd(), poll::PollFlags::POLLIN),
+            ];
+            poll::poll(&mut poll_fds, TICK.as_millis() as u16).context("polling")?;
+            let stdin_ready = poll_fds[0].any().unwrap_or(false);
+
+            if stdin_ready {
+                let len = stdin.read(&mut buf[..MAX_INPUT]).context("reading stdin")?;
+                if len == 0 {
+                    bail!("stdin closed");
+                }
+                let mut chunk = Vec::with_capacity(len);
+                for &b in buf[..len].iter() {
+                    match (escaped, b) {
+                        (true, b'.') => {
+                            self.quit()?;
+                            stdout.write_all(b"\r\nshpool: detached\r\n")?;
+                            return Ok(0);
+                        }
+                        (true, _) => {
+                            chunk.extend([ESCAPE, b]);
+                            escaped = false;
+                        }
+                        (false, ESCAPE) => escaped = true,
+                        (false, _) => chunk.push(b),
+                    }
+                }
+                if !chunk.is_empty() {
+                    self.input.push_back(chunk);
+                    send_now = true;
+                }
+            }
+
+            loop {
+                let (len, addr) = match self.udp.recv_from(&mut buf) {
+                    Ok(got) => got,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        // likely an icmp error from a network that went away
+                        debug!("receiving datagram: {:?}", e);
+                        break;
+                    }
+                };
+                let msg = match self.channel.open::<wire::ServerMsg>(&buf[..len]) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        debug!("dropping datagram from {}: {:?}", addr, e);
+                        continue;
+                    }
+                };
+                self.last_heard = time::Instant::now();
+                if self.notice_at.take().is_some() {
+                    // redraw over the notice
+                    if let Some(screen) = self.displayed.take() {
+                        render(&mut stdout, &screen, &mut self.displayed)?;
+                    }
+                }
+                let Some(msg) = msg else { continue };
+
+                while self.input_seq < msg.input_ack && !self.input.is_empty() {
+                    self.input.pop_front();
+                    self.input_seq += 1;
+                }
+                if let Some(state) = msg.state {
+                    if let Some(screen) = self.receiver.apply(state) {
+                        render(&mut stdout, screen, &mut self.displayed)?;
+                    }
+                    send_now = true;
+                }
+                if let Some(bye) = msg.bye {
+                    stdout.write_all(format!("\r\nshpool: {}\r\n", bye.reason).as_bytes())?;
+                    stdout.flush()?;
+                    return Ok(bye.exit_status.unwrap_or(0));
+                }
+            }
+
+            if let Ok(size) = tty::Size::from_fd(0) {
+                if size != self.size {
+                    self.size = size;
+                    send_now = true;
+                }
+            }
+
+            let since_heard = self.last_heard.elapsed();
+            if since_heard >= CONTACT_NOTICE
+                && self.notice_at.map(|at| at.elapsed().as_secs() >= 1).unwrap_or(true)
+            {
+                let notice = format!(
+                    "shpool: last heard from {} {} ago, Ctrl-^ . detaches",
+                    self.host,
+                    duration::format_coarse(since_heard)
+                );
+                write!(stdout, "\x1b7\x1b[1;1H\x1b[7m {} \x1b[0m\x1b[K\x1b8", notice)?;
+                stdout.flush()?;
+                self.notice_at = Some(time::Instant::now());
+            }
+
+            let resend = !self.input.is_empty() && self.last_sent.elapsed() >= RESEND;
+            if send_now || resend || self.last_sent.elapsed() >= HEARTBEAT {
+                self.send(false)?;
+            }
+        }
+    }
+
+    fn send(&mut self, quit: bool) -> anyhow::Result<()> {
+        let mut input = vec![];
+        let mut input_len = 0;
+        for chunk in self.input.iter() {
+            if !input.is_empty() && input_len + chunk.len() > MAX_INPUT {
+                break;
+            }
+            input_len += chunk.len();
+            input.push(chunk.clone());
+        }
+        let msg = wire::ClientMsg {
+            state_ack: self.receiver.newest(),
+            input_seq: self.input_seq,
+            input,
+            rows: self.size.rows,
+            cols: self.size.cols,
+            quit,
+        };
+        for datagram in self.channel.seal(&msg)? {
+            if let Err(e) = self.udp.send_to(&datagram, self.server) {
+                // Not having a network right now is the whole point.
+                debug!("sending datagram: {:?}", e);
+            }
+        }
+        self.last_sent = time::Instant::now();
+        Ok(())
+    }
+
+    /// Tell the server to let go of the session. It might not get the
+    /// message, in which case it gives up once the idle timeout runs out.
+    fn quit(&mut self) -> anyhow::Result<()> {
+        for _ in 0..3 {
+            self.send(true)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bring the terminal up to date with the given screen.
+fn render<W: Write>(
+    out: &mut W,
+    screen: &shpool_vt100::Screen,
+    displayed: &mut Option<shpool_vt100::Screen>,
+) -> anyhow::Result<()> {
+    match displayed {
+        Some(prev) if prev.size() == screen.size() => out.write_all(&screen.state_diff(prev))?,
+        _ => {
+            out.write_all(b"\x1b[H\x1b[2J")?;
+            out.write_all(&screen.state_formatted())?;
+        }
+    }
+    out.flush().context("flushing screen")?;
+    *displayed = Some(screen.clone());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn details() -> anyhow::Result<()> {
+        let key = "00".repeat(wire::KEY_LEN);
+        let (addr, got_key) = parse_details(&format!(" 10.0.0.2 60001 {}", key), "me@box")?;
+        assert_eq!(addr, "10.0.0.2:60001".parse()?);
+        assert_eq!(got_key, [0; wire::KEY_LEN]);
+
+        let (addr, _) = parse_details(&format!(" - 60002 {}", key), "me@127.0.0.1")?;
+        assert_eq!(addr, "127.0.0.1:60002".parse()?);
+
+        assert!(parse_details(" 10.0.0.2 60001 abcd", "box").is_err());
+        assert!(parse_details(" 10.0.0.2", "box").is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn ports() -> anyhow::Result<()> {
+        assert_eq!(parse_ports(DEFAULT_PORTS)?, (60001, 60999));
+        assert_eq!(parse_ports("61000")?, (61000, 61000));
+        assert!(parse_ports("61000:60000").is_err());
+        assert!(parse_ports("http").is_err());
+        Ok(())
+    }
+}
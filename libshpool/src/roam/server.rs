@@ -0,0 +1,318 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `shpool roam-server`, which `shpool roam` runs on the remote host
+//! over ssh. It attaches to the session like `shpool attach` would,
+//! keeps an emulated copy of the session's screen and syncs it to the
+//! client over UDP.
+
+use std::{
+    env, fs,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    os::fd::AsRawFd,
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex},
+    thread, time,
+};
+
+use anyhow::{anyhow, bail, Context};
+use nix::unistd::{self, ForkResult};
+use tracing::{debug, info, warn};
+
+use super::{
+    sync::Sender,
+    wire::{self, Bye, Channel, ClientMsg, ServerMsg},
+    BANNER, HEARTBEAT, RESEND, TICK,
+};
+use crate::{attach, consts, protocol, tty};
+
+/// How often the screen gets synced while output is streaming in.
+const FRAME: time::Duration = time::Duration::from_millis(20);
+/// How long to keep saying goodbye to a client that may not be
+/// getting the datagrams.
+const BYE_LINGER: time::Duration = time::Duration::from_secs(2);
+
+pub struct Args {
+    pub session: String,
+    pub rows: u16,
+    pub cols: u16,
+    pub term: Option<String>,
+    pub ports: (u16, u16),
+    pub idle_timeout: time::Duration,
+}
+
+pub fn run(args: Args, socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = protocol::Client::new(&socket).context("connecting to daemon")?;
+    let header = protocol::AttachHeader {
+        name: args.session.clone(),
+        local_tty_size: tty::Size { rows: args.rows, cols: args.cols, xpixel: 0, ypixel: 0 },
+        local_env: args.term.iter().map(|term| (String::from("TERM"), term.clone())).collect(),
+        ttl_secs: None,
+        cmd: None,
+    };
+    client
+        .write_connect_header(protocol::ConnectHeader::Attach(header))
+        .context("writing attach header")?;
+    let reply: protocol::AttachReplyHeader = client.read_reply().context("reading attach reply")?;
+    {
+        use protocol::AttachStatus::*;
+        match reply.status {
+            Attached { warnings } | Created { warnings } => {
+                for warning in warnings.into_iter() {
+                    eprintln!("shpool: warn: {}", warning);
+                }
+            }
+            Busy => bail!("session '{}' already has a terminal attached", args.session),
+            Forbidden(reason) => bail!("forbidden: {}", reason),
+            Exited { exit_status } | ExitedWithScreen { exit_status, .. } => {
+                bail!("session '{}' exited with status {}", args.session, exit_status)
+            }
+            UnexpectedError(err) => {
+                bail!("BUG: unexpected error attaching to '{}': {}", args.session, err)
+            }
+        }
+    }
+
+    // The address the client reached us at over ssh is the one most
+    // likely to work for the datagrams too.
+    let ip = env::var("SSH_CONNECTION")
+        .ok()
+        .and_then(|conn| conn.split_whitespace().nth(2).and_then(|ip| ip.parse::<IpAddr>().ok()));
+    let udp = bind(ip, args.ports)?;
+    let port = udp.local_addr().context("getting udp port")?.port();
+    let key = wire::new_key()?;
+
+    let key_hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+    let ip = ip.map(|ip| ip.to_string()).unwrap_or(String::from("-"));
+    println!("{} {} {} {}", BANNER, ip, port, key_hex);
+    io::stdout().flush().context("flushing connection details")?;
+    detach()?;
+
+    info!("serving session '{}' on udp port {}", args.session, port);
+    serve(args, socket, client.stream, udp, &key)
+}
+
+/// Bind a udp socket on the first free port in the range.
+fn bind(ip: Option<IpAddr>, (low, high): (u16, u16)) -> anyhow::Result<UdpSocket> {
+    let any = match ip {
+        Some(IpAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+    for port in low..=high {
+        match UdpSocket::bind(SocketAddr::new(any, port)) {
+            Ok(udp) => return Ok(udp),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e).context("binding udp socket"),
+        }
+    }
+    Err(anyhow!("no free udp port between {} and {}", low, high))
+}
+
+/// Get out from under ssh, so that it can exit once we stop writing to
+/// the stdout it handed us.
+fn detach() -> anyhow::Result<()> {
+    // Safety: no threads have been spawned yet.
+    if let ForkResult::Parent { .. } = unsafe { unistd::fork() }.context("forking")? {
+        process::exit(0);
+    }
+    unistd::setsid().context("starting a new session")?;
+    let devnull = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("opening /dev/null")?;
+    for fd in 0..=2 {
+        unistd::dup2(devnull.as_raw_fd(), fd).context("redirecting stdio")?;
+    }
+    Ok(())
+}
+
+/// The session's screen as of the last output from the daemon.
+struct Emulator {
+    parser: shpool_vt100::Parser,
+    dirty: bool,
+}
+
+fn serve(
+    args: Args,
+    socket: PathBuf,
+    mut stream: std::os::unix::net::UnixStream,
+    udp: UdpSocket,
+    key: &[u8; wire::KEY_LEN],
+) -> anyhow::Result<()> {
+    let emulator = Arc::new(Mutex::new(Emulator {
+        parser: shpool_vt100::Parser::new(args.rows, args.cols, 0),
+        dirty: true,
+    }));
+    let ended: Arc<Mutex<Option<Bye>>> = Arc::new(Mutex::new(None));
+
+    let mut read_stream = stream.try_clone().context("cloning attach stream")?;
+    {
+        let emulator = Arc::clone(&emulator);
+        let ended = Arc::clone(&ended);
+        thread::Builder::new()
+            .name(String::from("roam-reader"))
+            .spawn(move || {
+                let bye = read_output(&mut read_stream, &emulator);
+                *ended.lock().unwrap() = Some(bye);
+            })
+            .map_err(|e| anyhow!("{:?}", e))?;
+    }
+
+    udp.set_read_timeout(Some(TICK)).context("setting udp timeout")?;
+    let mut channel = Channel::new(key, true)?;
+    let mut sender = Sender::default();
+    let mut peer: Option<SocketAddr> = None;
+    let mut input_ack = 0;
+    let mut size = (args.rows, args.cols);
+    let mut last_heard = time::Instant::now();
+    let mut last_sent = time::Instant::now();
+    let mut last_frame = time::Instant::now();
+    let mut bye_at: Option<time::Instant> = None;
+    let mut buf = vec![0; 65536];
+    loop {
+        let mut send_now = false;
+        match udp.recv_from(&mut buf) {
+            Ok((len, addr)) => match channel.open::<ClientMsg>(&buf[..len]) {
+                Ok(msg) => {
+                    if peer != Some(addr) {
+                        info!("client is at {}", addr);
+                        peer = Some(addr);
+                    }
+                    last_heard = time::Instant::now();
+                    if let Some(msg) = msg {
+                        if msg.quit {
+                            info!("client quit");
+                            return Ok(());
+                        }
+                        sender.ack(msg.state_ack);
+                        for (seq, chunk) in (msg.input_seq..).zip(msg.input.iter()) {
+                            if seq == input_ack {
+                                stream.write_all(chunk).context("writing input")?;
+                                input_ack += 1;
+                                send_now = true;
+                            }
+                        }
+                        if (msg.rows, msg.cols) != size && msg.rows > 0 && msg.cols > 0 {
+                            size = (msg.rows, msg.cols);
+                            resize(&socket, &args.session, size, &emulator);
+                        }
+                    }
+                }
+                Err(e) => debug!("dropping datagram from {}: {:?}", addr, e),
+            },
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e).context("receiving datagram"),
+        }
+
+        if last_heard.elapsed() > args.idle_timeout {
+            info!("no word from the client in {:?}, giving up", args.idle_timeout);
+            return Ok(());
+        }
+        let bye = ended.lock().unwrap().clone();
+        let Some(peer) = peer else {
+            if bye.is_some() {
+                return Ok(());
+            }
+            continue;
+        };
+        if bye.is_some() && bye_at.is_none() {
+            bye_at = Some(time::Instant::now());
+        }
+
+        let mut state = None;
+        if last_frame.elapsed() >= FRAME {
+            let mut emulator = emulator.lock().unwrap();
+            if emulator.dirty {
+                emulator.dirty = false;
+                last_frame = time::Instant::now();
+                state = Some(sender.push(emulator.parser.screen()));
+            }
+        }
+        if state.is_none() && !sender.caught_up() && last_sent.elapsed() >= RESEND {
+            state = sender.resend();
+        }
+        let saying_bye = bye.is_some() && last_sent.elapsed() >= RESEND;
+        if state.is_some() || send_now || saying_bye || last_sent.elapsed() >= HEARTBEAT {
+            let msg = ServerMsg { input_ack, state, bye };
+            for datagram in channel.seal(&msg)? {
+                if let Err(e) = udp.send_to(&datagram, peer) {
+                    // The client may well be between networks.
+                    debug!("sending to {}: {:?}", peer, e);
+                }
+            }
+            last_sent = time::Instant::now();
+        }
+
+        if bye_at.map(|at| at.elapsed() >= BYE_LINGER).unwrap_or(false) {
+            return Ok(());
+        }
+    }
+}
+
+/// Feed the session's output into the emulator until the daemon hangs
+/// up, returning why it did.
+fn read_output(stream: &mut std::os::unix::net::UnixStream, emulator: &Mutex<Emulator>) -> Bye {
+    let mut buf = vec![0; consts::BUF_SIZE];
+    let mut exit_status = None;
+    let mut reason = None;
+    loop {
+        let chunk = match protocol::Chunk::read_into(stream, &mut buf) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                debug!("reading chunk: {:?}", e);
+                break;
+            }
+        };
+        match chunk.kind {
+            protocol::ChunkKind::Data => {
+                let mut emulator = emulator.lock().unwrap();
+                emulator.parser.process(chunk.buf);
+                emulator.dirty = true;
+            }
+            protocol::ChunkKind::ExitStatus => {
+                let mut status = [0; 4];
+                status.copy_from_slice(chunk.buf);
+                exit_status = Some(i32::from_le_bytes(status));
+            }
+            protocol::ChunkKind::Control => {
+                match bincode::deserialize::<protocol::StreamControl>(chunk.buf) {
+                    Ok(protocol::StreamControl::Shutdown(r))
+                    | Ok(protocol::StreamControl::Ended(r)) => reason = Some(r),
+                    Ok(ctl) => debug!("ignoring control message: {:?}", ctl),
+                    Err(e) => warn!("skipping unknown control message: {:?}", e),
+                }
+            }
+            protocol::ChunkKind::Heartbeat => {}
+        }
+    }
+
+    let reason = reason.unwrap_or_else(|| match exit_status {
+        Some(status) => format!("session exited with status {}", status),
+        None => String::from("detached"),
+    });
+    Bye { reason, exit_status }
+}
+
+fn resize(socket: &PathBuf, session: &str, (rows, cols): (u16, u16), emulator: &Mutex<Emulator>) {
+    info!("resizing to {}x{}", cols, rows);
+    emulator.lock().unwrap().parser.screen_mut().set_size(rows, cols);
+    if let Err(e) =
+        attach::send_resize(socket, session, tty::Size { rows, cols, xpixel: 0, ypixel: 0 })
+    {
+        warn!("resizing session: {:?}", e);
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Keeping the client's copy of the screen in sync with the server's.
+
+  The server numbers each screen state it sends and always diffs
+  against the newest state the client has acknowledged, so a lost
+  datagram never leaves the client with a diff it can't apply. The
+  client holds onto every state it has gotten since then, since the
+  server can't know which of them made it until the ack does.
+*/
+
+use std::collections::VecDeque;
+
+use super::wire::StateDiff;
+
+/// The most unacknowledged states to hold onto. A client that has been
+/// out of touch for a while just gets diffs against an older state.
+const MAX_SENT: usize = 32;
+
+/// The server's side of the screen sync.
+#[derive(Default)]
+pub struct Sender {
+    /// The newest state the client has acknowledged, if any.
+    acked: Option<(u64, shpool_vt100::Screen)>,
+    /// The states sent since then, oldest first.
+    sent: VecDeque<(u64, shpool_vt100::Screen)>,
+    next: u64,
+}
+
+impl Sender {
+    /// Record a new screen state, returning the diff to send for it.
+    pub fn push(&mut self, screen: &shpool_vt100::Screen) -> StateDiff {
+        self.next += 1;
+        let to = self.next;
+        if self.sent.len() >= MAX_SENT {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((to, screen.clone()));
+        self.diff_to(to, screen)
+    }
+
+    /// The diff for the newest state, to send again when it has not
+    /// been acknowledged for a while.
+    pub fn resend(&self) -> Option<StateDiff> {
+        let (to, screen) = self.sent.back()?;
+        Some(self.diff_to(*to, screen))
+    }
+
+    /// Whether the client has every state sent so far.
+    pub fn caught_up(&self) -> bool {
+        self.sent.is_empty()
+    }
+
+    /// Note that the client has the given state.
+    pub fn ack(&mut self, num: u64) {
+        while let Some((sent_num, _)) = self.sent.front() {
+            if *sent_num > num {
+                break;
+            }
+            let state = self.sent.pop_front();
+            if state.as_ref().map(|(n, _)| *n == num).unwrap_or(false) {
+                self.acked = state;
+            }
+        }
+    }
+
+    fn diff_to(&self, to: u64, screen: &shpool_vt100::Screen) -> StateDiff {
+        let (rows, cols) = screen.size();
+        let (from, diff) = match &self.acked {
+            Some((num, acked)) if acked.size() == screen.size() => {
+                (Some(*num), screen.state_diff(acked))
+            }
+            _ => (None, screen.state_formatted()),
+        };
+        StateDiff { from, to, rows, cols, diff }
+    }
+}
+
+/// The client's side of the screen sync.
+#[derive(Default)]
+pub struct Receiver {
+    /// The states gotten since the oldest one the server might still
+    /// diff against, oldest first.
+    states: VecDeque<(u64, shpool_vt100::Screen)>,
+}
+
+impl Receiver {
+    /// Apply a diff from the server. Returns the new state if it is newer
+    /// than any so far, and None if it is old news or its base state has
+    /// already been thrown away.
+    pub fn apply(&mut self, state: StateDiff) -> Option<&shpool_vt100::Screen> {
+        if state.to <= self.newest() {
+            return None;
+        }
+        let mut parser = shpool_vt100::Parser::new(state.rows, state.cols, 0);
+        if let Some(from) = state.from {
+            let (_, base) = self.states.iter().find(|(num, _)| *num == from)?;
+            *parser.screen_mut() = base.clone();
+            parser.screen_mut().set_size(state.rows, state.cols);
+            // the server never diffs against anything older again
+            self.states.retain(|(num, _)| *num >= from);
+        }
+        parser.process(&state.diff);
+        self.states.push_back((state.to, parser.screen().clone()));
+        self.states.back().map(|(_, screen)| screen)
+    }
+
+    /// The newest state the client has, to acknowledge.
+    pub fn newest(&self) -> u64 {
+        self.states.back().map(|(num, _)| *num).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    fn screen(output: &[u8]) -> shpool_vt100::Screen {
+        let mut parser = shpool_vt100::Parser::new(4, 20, 0);
+        parser.process(output);
+        parser.screen().clone()
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn lossy() {
+        let mut sender = Sender::default();
+        let mut receiver = Receiver::default();
+
+        let first = sender.push(&screen(b"$ "));
+        assert_eq!(first.from, None);
+        assert!(receiver.apply(first).is_some());
+        sender.ack(receiver.newest());
+        assert!(sender.caught_up());
+
+        // the next state gets lost, but the one after it still applies
+        let lost = sender.push(&screen(b"$ l"));
+        assert_eq!(lost.from, Some(1));
+        let latest = screen(b"$ ls");
+        let next = sender.push(&latest);
+        assert_eq!(next.from, Some(1));
+        let got = receiver.apply(next).unwrap();
+        assert_eq!(got.contents(), latest.contents());
+
+        // a late arrival is old news
+        assert!(receiver.apply(lost).is_none());
+        sender.ack(receiver.newest());
+        assert!(sender.caught_up());
+        assert_eq!(sender.push(&latest).from, Some(3));
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn resize() {
+        let mut sender = Sender::default();
+        let mut receiver = Receiver::default();
+        receiver.apply(sender.push(&screen(b"$ "))).unwrap();
+        sender.ack(receiver.newest());
+
+        let mut parser = shpool_vt100::Parser::new(10, 40, 0);
+        parser.process(b"$ wide");
+        let state = sender.push(parser.screen());
+        assert_eq!(state.from, None);
+        let got = receiver.apply(state).unwrap();
+        assert_eq!(got.size(), (10, 40));
+        assert_eq!(got.contents(), "$ wide");
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! The datagrams `shpool roam` and the roam server trade.
+
+  Each datagram is an 8 byte big endian counter followed by a fragment
+  of a message, sealed with ChaCha20-Poly1305 under the key the server
+  handed out over ssh. The counter doubles as the nonce, with the top
+  bit set for datagrams from the server so that the two directions
+  never share a nonce, and a datagram is only accepted if its counter
+  is higher than any seen before, which keeps old datagrams from being
+  replayed. Since every datagram is authenticated, the server can
+  safely follow a client to whatever address its datagrams come from
+  next.
+
+  Messages get split into fragments small enough to cross links with a
+  small MTU. A message only counts once every one of its fragments has
+  made it, and a fragment of a newer message throws away whatever was
+  left of an older one, since both ends keep sending their latest
+  state until it gets acknowledged anyway.
+*/
+
+use anyhow::{anyhow, bail, Context};
+use ring::{aead, rand, rand::SecureRandom};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+
+pub const KEY_LEN: usize = 32;
+
+/// The most message bytes that go in a single datagram, which keeps
+/// datagrams comfortably under the minimum IPv6 MTU once the framing
+/// gets added.
+const MAX_FRAGMENT: usize = 1100;
+
+const COUNTER_LEN: usize = 8;
+const SERVER_BIT: u64 = 1 << 63;
+
+/// What the client sends in every datagram.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct ClientMsg {
+    /// The newest screen state the client has.
+    pub state_ack: u64,
+    /// The sequence number of the first chunk of input.
+    pub input_seq: u64,
+    /// Input the server has not acknowledged yet, oldest first.
+    pub input: Vec<Vec<u8>>,
+    /// The size of the client's terminal.
+    pub rows: u16,
+    pub cols: u16,
+    /// The user is done, so the server should detach and exit.
+    pub quit: bool,
+}
+
+/// What the server sends in every datagram.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct ServerMsg {
+    /// How many chunks of input have been passed along to the session.
+    pub input_ack: u64,
+    pub state: Option<StateDiff>,
+    /// The server is done, because the session exited or got detached.
+    pub bye: Option<Bye>,
+}
+
+/// How to get from a screen state the client has to a newer one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StateDiff {
+    /// The state to apply the diff to, or None to start from a blank
+    /// screen.
+    pub from: Option<u64>,
+    pub to: u64,
+    pub rows: u16,
+    pub cols: u16,
+    pub diff: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Bye {
+    pub reason: String,
+    pub exit_status: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Fragment {
+    msg_id: u64,
+    index: u16,
+    count: u16,
+    data: Vec<u8>,
+}
+
+/// Generate a fresh key for a roam server.
+pub fn new_key() -> anyhow::Result<[u8; KEY_LEN]> {
+    let mut key = [0; KEY_LEN];
+    rand::SystemRandom::new().fill(&mut key).map_err(|_| anyhow!("generating key"))?;
+    Ok(key)
+}
+
+/// One end of the sealed datagram channel.
+pub struct Channel {
+    key: aead::LessSafeKey,
+    /// Set on the counters of the datagrams this end sends.
+    send_bit: u64,
+    send_counter: u64,
+    recv_high: Option<u64>,
+    next_msg_id: u64,
+    /// The message being put back together, and its fragments so far.
+    partial: Option<(u64, Vec<Option<Vec<u8>>>)>,
+}
+
+impl Channel {
+    pub fn new(key: &[u8; KEY_LEN], server: bool) -> anyhow::Result<Self> {
+        let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key)
+            .map_err(|_| anyhow!("bad roam key"))?;
+        Ok(Channel {
+            key: aead::LessSafeKey::new(key),
+            send_bit: if server { SERVER_BIT } else { 0 },
+            send_counter: 0,
+            recv_high: None,
+            next_msg_id: 0,
+            partial: None,
+        })
+    }
+
+    /// Seal a message into the datagrams to send for it.
+    pub fn seal<T: serde::Serialize>(&mut self, msg: &T) -> anyhow::Result<Vec<Vec<u8>>> {
+        let buf = bincode::serialize(msg).context("serializing message")?;
+        let msg_id = self.next_msg_id;
+        self.next_msg_id += 1;
+
+        let chunks: Vec<&[u8]> =
+            if buf.is_empty() { vec![&[]] } else { buf.chunks(MAX_FRAGMENT).collect() };
+        let count = u16::try_from(chunks.len()).context("message too big")?;
+        let mut datagrams = Vec::with_capacity(chunks.len());
+        for (index, data) in chunks.into_iter().enumerate() {
+            let fragment = Fragment { msg_id, index: index as u16, count, data: data.to_vec() };
+            let mut sealed = bincode::serialize(&fragment).context("serializing fragment")?;
+            let counter = self.send_counter | self.send_bit;
+            self.send_counter += 1;
+            self.key
+                .seal_in_place_append_tag(nonce(counter), aead::Aad::empty(), &mut sealed)
+                .map_err(|_| anyhow!("sealing datagram"))?;
+
+            let mut datagram = Vec::with_capacity(COUNTER_LEN + sealed.len());
+            datagram.extend_from_slice(&counter.to_be_bytes());
+            datagram.append(&mut sealed);
+            datagrams.push(datagram);
+        }
+        Ok(datagrams)
+    }
+
+    /// Open a datagram from the other end. Returns an error for a
+    /// datagram that did not come from the other end, or that is a
+    /// replay, and the message once the last of its fragments shows up.
+    pub fn open<T: DeserializeOwned>(&mut self, datagram: &[u8]) -> anyhow::Result<Option<T>> {
+        if datagram.len() < COUNTER_LEN {
+            bail!("short datagram");
+        }
+        let (counter, sealed) = datagram.split_at(COUNTER_LEN);
+        let counter = u64::from_be_bytes(counter.try_into().unwrap_or_default());
+        if counter & SERVER_BIT == self.send_bit {
+            bail!("datagram from the wrong direction");
+        }
+        if self.recv_high.map(|high| counter <= high).unwrap_or(false) {
+            bail!("stale datagram");
+        }
+
+        let mut sealed = sealed.to_vec();
+        let fragment = self
+            .key
+            .open_in_place(nonce(counter), aead::Aad::empty(), &mut sealed)
+            .map_err(|_| anyhow!("datagram failed authentication"))?;
+        self.recv_high = Some(counter);
+        let fragment: Fragment = bincode::deserialize(fragment).context("parsing fragment")?;
+        if fragment.count == 0 || fragment.index >= fragment.count {
+            bail!("bad fragment {}/{}", fragment.index, fragment.count);
+        }
+
+        match &self.partial {
+            Some((id, _)) if *id > fragment.msg_id => return Ok(None),
+            Some((id, frags))
+                if *id == fragment.msg_id && frags.len() == usize::from(fragment.count) => {}
+            _ => {
+                self.partial = Some((fragment.msg_id, vec![None; fragment.count.into()]));
+            }
+        }
+        let Some((_, frags)) = &mut self.partial else { return Ok(None) };
+        frags[usize::from(fragment.index)] = Some(fragment.data);
+        if frags.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+
+        let buf: Vec<u8> = frags.drain(..).flatten().flatten().collect();
+        self.partial = None;
+        Ok(Some(bincode::deserialize(&buf).context("parsing message")?))
+    }
+}
+
+fn nonce(counter: u64) -> aead::Nonce {
+    let mut nonce = [0; aead::NONCE_LEN];
+    nonce[aead::NONCE_LEN - COUNTER_LEN..].copy_from_slice(&counter.to_be_bytes());
+    aead::Nonce::assume_unique_for_key(nonce)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    fn pair() -> (Channel, Channel) {
+        let key = new_key().unwrap();
+        (Channel::new(&key, false).unwrap(), Channel::new(&key, true).unwrap())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn round_trip() -> anyhow::Result<()> {
+        let (mut client, mut server) = pair();
+        let msg = ClientMsg {
+            state_ack: 3,
+            input_seq: 7,
+            input: vec![b"ls\r".to_vec()],
+            rows: 24,
+            cols: 80,
+            quit: false,
+        };
+        let datagrams = client.seal(&msg)?;
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(server.open::<ClientMsg>(&datagrams[0])?, Some(msg));
+
+        // a replay gets turned away, and so does a reflection
+        assert!(server.open::<ClientMsg>(&datagrams[0]).is_err());
+        let reply = server.seal(&ServerMsg::default())?;
+        assert!(server.open::<ServerMsg>(&reply[0]).is_err());
+        assert_eq!(client.open::<ServerMsg>(&reply[0])?, Some(ServerMsg::default()));
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn tampered() -> anyhow::Result<()> {
+        let (mut client, mut server) = pair();
+        let mut datagram = client.seal(&ClientMsg::default())?.remove(0);
+        let last = datagram.len() - 1;
+        datagram[last] ^= 1;
+        assert!(server.open::<ClientMsg>(&datagram).is_err());
+
+        let (_, mut stranger) = pair();
+        let datagram = client.seal(&ClientMsg::default())?.remove(0);
+        assert!(stranger.open::<ClientMsg>(&datagram).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn fragments() -> anyhow::Result<()> {
+        let (mut client, mut server) = pair();
+        let state = |to| StateDiff { from: None, to, rows: 24, cols: 80, diff: vec![b'x'; 5000] };
+        let msg = ServerMsg { input_ack: 1, state: Some(state(1)), bye: None };
+        let datagrams = server.seal(&msg)?;
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|d| d.len() < 1200));
+
+        // a newer message takes over from one that lost a fragment
+        let newer = ServerMsg { input_ack: 1, state: Some(state(2)), bye: None };
+        let newer_datagrams = server.seal(&newer)?;
+        assert_eq!(client.open::<ServerMsg>(&datagrams[0])?, None);
+        let mut got = None;
+        for datagram in newer_datagrams.iter() {
+            got = client.open::<ServerMsg>(datagram)?;
+        }
+        assert_eq!(got, Some(newer));
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fs, io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    protocol,
+    protocol::{ConnectHeader, SaveOutputReply, SaveOutputRequest},
+};
+
+pub fn run(session: String, file: String, strip_ansi: bool, socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require_capability("save-output")?;
+    client
+        .write_connect_header(ConnectHeader::SaveOutput(SaveOutputRequest {
+            session: session.clone(),
+            strip_ansi,
+        }))
+        .context("writing save output request header")?;
+    let reply: SaveOutputReply = client.read_reply().context("reading reply")?;
+
+    match reply {
+        SaveOutputReply::Output(output) => {
+            fs::write(&file, output).with_context(|| format!("writing output to {}", file))?;
+        }
+        SaveOutputReply::NotFound => {
+            eprintln!("not found: {}", session);
+            return Err(anyhow!("not found: {}", session));
+        }
+        SaveOutputReply::NoSpool => {
+            eprintln!(
+                "no output kept for {}, the daemon is using session_restore_mode = \"simple\"",
+                session
+            );
+            return Err(anyhow!("no output spool"));
+        }
+        SaveOutputReply::Forbidden(reason) => {
+            eprintln!("forbidden: {}", reason);
+            return Err(anyhow!("forbidden: {}", reason));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,129 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    protocol,
+    protocol::{ConnectHeader, SearchLine, SearchReply, SearchRequest},
+};
+
+pub fn run(
+    session: String,
+    pattern: String,
+    ignore_case: bool,
+    context: usize,
+    line_numbers: bool,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require_capability("search")?;
+    client
+        .write_connect_header(ConnectHeader::Search(SearchRequest {
+            session: session.clone(),
+            pattern,
+            ignore_case,
+            context,
+        }))
+        .context("writing search request header")?;
+    let reply: SearchReply = client.read_reply().context("reading reply")?;
+
+    match reply {
+        // like grep, finding nothing is not an error but still fails
+        SearchReply::Lines(lines) if lines.is_empty() => std::process::exit(1),
+        SearchReply::Lines(lines) => {
+            io::stdout()
+                .write_all(format_lines(&lines, context > 0, line_numbers).as_bytes())
+                .context("writing output")?;
+        }
+        SearchReply::NotFound => {
+            eprintln!("not found: {}", session);
+            return Err(anyhow!("not found: {}", session));
+        }
+        SearchReply::NoSpool => {
+            eprintln!(
+                "no scrollback kept for {}, the daemon is using session_restore_mode = \"simple\"",
+                session
+            );
+            return Err(anyhow!("no output spool"));
+        }
+        SearchReply::BadPattern(err) => {
+            eprintln!("bad pattern: {}", err);
+            return Err(anyhow!("bad pattern: {}", err));
+        }
+        SearchReply::Forbidden(reason) => {
+            eprintln!("forbidden: {}", reason);
+            return Err(anyhow!("forbidden: {}", reason));
+        }
+    }
+
+    Ok(())
+}
+
+/// Lay the lines out the way grep would, with a `--` between groups
+/// of lines that aren't next to each other when there is context.
+fn format_lines(lines: &[SearchLine], separate: bool, line_numbers: bool) -> String {
+    let mut out = String::new();
+    let mut last = None;
+    for line in lines.iter() {
+        if separate && last.map(|n| n + 1 != line.number).unwrap_or(false) {
+            out.push_str("--\n");
+        }
+        if line_numbers {
+            let sep = if line.matched { ':' } else { '-' };
+            out.push_str(&format!("{}{}", line.number, sep));
+        }
+        out.push_str(&line.text);
+        out.push('\n');
+        last = Some(line.number);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn grep_style() {
+        let line =
+            |number, text: &str, matched| SearchLine { number, text: String::from(text), matched };
+        let lines = vec![
+            line(1, "a", false),
+            line(2, "ERROR one", true),
+            line(3, "b", false),
+            line(7, "ERROR two", true),
+        ];
+        assert_eq!(format_lines(&lines, true, false), "a\nERROR one\nb\n--\nERROR two\n");
+        assert_eq!(format_lines(&lines, true, true), "1-a\n2:ERROR one\n3-b\n--\n7:ERROR two\n");
+        assert_eq!(format_lines(&lines[1..2], false, true), "2:ERROR one\n");
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! The `shpool self-update` command, for people who install shpool
+  outside of a package manager.
+
+  The configured url points at a small JSON manifest describing the
+  latest release
+
+  ```json
+  {
+    "version": "0.7.0",
+    "binaries": {
+      "x86_64-linux": {
+        "url": "https://example.com/shpool-0.7.0-x86_64-linux",
+        "sha256": "<hex encoded sha256 of the binary>",
+        "signature": "<hex encoded ed25519 signature of the statement>"
+      }
+    }
+  }
+  ```
+
+  The signature is over a one line release statement tying the binary
+  to the version and target it is for
+
+  ```text
+  shpool-release 0.7.0 x86_64-linux <sha256>
+  ```
+
+  with a trailing newline. Nothing in the manifest gets trusted until
+  the signature checks out against the public key from the config, and
+  the binary only gets installed if it matches the signed checksum, so
+  the manifest and binaries can be served from anywhere. Since the
+  version is signed along with the binary, an old release can't be
+  passed off as a newer one to roll an install back. Fetching is done with curl, which is around
+  just about everywhere and saves us from carrying an http stack.
+*/
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::Write,
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{anyhow, bail, Context};
+use ring::signature;
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::{common, config, restart};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Refuse to download anything bigger than this, so a bad endpoint
+/// can't fill up the disk.
+const MAX_DOWNLOAD_BYTES: &str = "200M";
+
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    version: String,
+    binaries: HashMap<String, Binary>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Binary {
+    url: String,
+    sha256: String,
+    signature: String,
+}
+
+pub fn run(
+    config_file: Option<String>,
+    check: bool,
+    force: bool,
+    restart_daemon: bool,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let res = update(config_file, check, force, restart_daemon, socket);
+    if let Err(e) = &res {
+        eprintln!("shpool: self-update: {:#}", e);
+    }
+    res
+}
+
+fn update(
+    config_file: Option<String>,
+    check: bool,
+    force: bool,
+    restart_daemon: bool,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let config_manager = config::Manager::new(config_file.as_deref())?;
+    let settings = config_manager
+        .get()
+        .self_update
+        .clone()
+        .ok_or(anyhow!("no self_update url and public_key in the config"))?;
+    let public_key = common::decode_hex(&settings.public_key).context("parsing public_key")?;
+
+    let manifest: Manifest = serde_json::from_slice(&fetch(&settings.url)?)
+        .with_context(|| format!("parsing release manifest from {}", settings.url))?;
+    let target = format!("{}-{}", env::consts::ARCH, env::consts::OS);
+    let binary = manifest.binaries.get(&target).ok_or(anyhow!(
+        "release {} has no binary for {}",
+        manifest.version,
+        target
+    ))?;
+    let sha256 = common::decode_hex(&binary.sha256).context("parsing sha256")?;
+    let sig = common::decode_hex(&binary.signature).context("parsing signature")?;
+    signature::UnparsedPublicKey::new(&signature::ED25519, &public_key)
+        .verify(release_statement(&manifest.version, &target, &sha256).as_bytes(), &sig)
+        .map_err(|_| {
+            anyhow!("bad signature on release {} for {}, refusing it", manifest.version, target)
+        })?;
+
+    if !force && !is_newer(VERSION, &manifest.version)? {
+        println!("shpool {} is up to date", VERSION);
+        return Ok(());
+    }
+    if check {
+        println!("shpool {} is available (running {})", manifest.version, VERSION);
+        return Ok(());
+    }
+
+    let contents = fetch(&binary.url)?;
+    if Sha256::digest(&contents)[..] != sha256[..] {
+        bail!("{} doesn't match the signed release, refusing to install it", binary.url);
+    }
+
+    let exe =
+        env::current_exe().and_then(fs::canonicalize).context("finding the current executable")?;
+    install(&exe, &contents)?;
+    println!("updated shpool from {} to {}", VERSION, manifest.version);
+
+    // The daemon keeps running the old binary until it restarts, which
+    // it can do without dropping any sessions.
+    if UnixStream::connect(&socket).is_ok() {
+        if restart_daemon {
+            restart::run(true, socket).context("restarting the daemon")?;
+            println!("restarted the daemon on {}", manifest.version);
+        } else {
+            println!(
+                "the running daemon is still on {}, run `shpool daemon restart \
+                 --preserve-sessions` to move it and its sessions to the new version",
+                VERSION
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The statement a release's signature covers.
+fn release_statement(version: &str, target: &str, sha256: &[u8]) -> String {
+    let sha256: String = sha256.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("shpool-release {} {} {}\n", version, target, sha256)
+}
+
+/// Fetch the given url with curl.
+fn fetch(url: &str) -> anyhow::Result<Vec<u8>> {
+    let out = process::Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--max-filesize", MAX_DOWNLOAD_BYTES])
+        .arg(url)
+        .stdin(process::Stdio::null())
+        .output()
+        .context("running curl")?;
+    if !out.status.success() {
+        bail!("fetching {}: {}", url, String::from_utf8_lossy(&out.stderr).trim());
+    }
+
+    Ok(out.stdout)
+}
+
+/// Replace the executable at the given path. The new binary gets
+/// written next to the old one and renamed over it, so anything that
+/// runs shpool in the meantime sees either the old or the new binary
+/// but never half of one.
+fn install(exe: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let dir = exe.parent().ok_or(anyhow!("{:?} has no parent dir", exe))?;
+    let mut tmp = tempfile::Builder::new()
+        .prefix(".shpool-update")
+        .tempfile_in(dir)
+        .with_context(|| format!("creating tmp file in {:?}", dir))?;
+    tmp.write_all(contents).context("writing new binary")?;
+    tmp.as_file().sync_all().context("syncing new binary")?;
+
+    let mode = fs::metadata(exe).context("stating current binary")?.permissions().mode();
+    fs::set_permissions(tmp.path(), fs::Permissions::from_mode(mode))
+        .context("setting permissions on new binary")?;
+    tmp.persist(exe).with_context(|| format!("replacing {:?}", exe))?;
+
+    Ok(())
+}
+
+/// Compare dotted version numbers. Any pre-release or build suffix
+/// is ignored.
+fn is_newer(current: &str, candidate: &str) -> anyhow::Result<bool> {
+    let parse = |v: &str| -> anyhow::Result<Vec<u64>> {
+        let v = v.trim().trim_start_matches('v');
+        let v = v.split(['-', '+']).next().unwrap_or(v);
+        v.split('.')
+            .map(|part| part.parse::<u64>().with_context(|| format!("invalid version '{}'", v)))
+            .collect()
+    };
+    Ok(parse(candidate)? > parse(current)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn version_order() -> anyhow::Result<()> {
+        assert!(is_newer("0.6.2", "0.7.0")?);
+        assert!(is_newer("0.6.2", "v0.6.10")?);
+        assert!(is_newer("0.6.2", "1.0.0-rc1")?);
+        assert!(!is_newer("0.6.2", "0.6.2")?);
+        assert!(!is_newer("0.6.2", "0.6.1")?);
+        assert!(is_newer("0.6.2", "latest").is_err());
+
+        Ok(())
+    }
+}
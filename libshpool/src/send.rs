@@ -0,0 +1,79 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    io::{self, Read},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    protocol,
+    protocol::{ConnectHeader, SendInputReply, SendInputRequest},
+};
+
+pub fn run(
+    session: String,
+    text: Option<String>,
+    stdin: bool,
+    enter: bool,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let mut input = match text {
+        Some(text) => text.into_bytes(),
+        None if stdin => {
+            let mut buf = vec![];
+            io::stdin().read_to_end(&mut buf).context("reading stdin")?;
+            buf
+        }
+        None => vec![],
+    };
+    if enter {
+        // a terminal sends a carriage return for the enter key
+        input.push(b'\r');
+    }
+
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require_capability("send-input")?;
+    client
+        .write_connect_header(ConnectHeader::SendInput(SendInputRequest {
+            session: session.clone(),
+            input,
+        }))
+        .context("writing send input request header")?;
+    let reply: SendInputReply = client.read_reply().context("reading reply")?;
+
+    match reply {
+        SendInputReply::Ok => Ok(()),
+        SendInputReply::NotFound => {
+            eprintln!("not found: {}", session);
+            Err(anyhow!("not found: {}", session))
+        }
+        SendInputReply::Forbidden(reason) => {
+            eprintln!("forbidden: {}", reason);
+            Err(anyhow!("forbidden: {}", reason))
+        }
+    }
+}
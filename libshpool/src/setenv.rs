@@ -0,0 +1,83 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    protocol,
+    protocol::{ConnectHeader, SetEnvReply, SetEnvRequest},
+};
+
+pub fn run(
+    session: String,
+    vars: Vec<String>,
+    unset: Vec<String>,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let mut set = Vec::with_capacity(vars.len());
+    for var in vars.into_iter() {
+        match var.split_once('=') {
+            Some((k, v)) => set.push((String::from(k), String::from(v))),
+            None => {
+                eprintln!("expected KEY=VALUE, got '{}'", var);
+                return Err(anyhow!("bad assignment: {}", var));
+            }
+        }
+    }
+    if set.is_empty() && unset.is_empty() {
+        eprintln!("nothing to set or unset");
+        return Err(anyhow!("no variables given"));
+    }
+
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require_capability("env")?;
+    client
+        .write_connect_header(ConnectHeader::SetEnv(SetEnvRequest {
+            session: session.clone(),
+            set,
+            unset,
+        }))
+        .context("writing setenv request header")?;
+    let reply: SetEnvReply = client.read_reply().context("reading reply")?;
+
+    match reply {
+        SetEnvReply::Ok => {}
+        SetEnvReply::NotFound => {
+            eprintln!("not found: {}", session);
+            return Err(anyhow!("not found: {}", session));
+        }
+        SetEnvReply::BadName(var) => {
+            eprintln!("invalid variable name: '{}'", var);
+            return Err(anyhow!("invalid variable name: {}", var));
+        }
+        SetEnvReply::Forbidden(reason) => {
+            eprintln!("forbidden: {}", reason);
+            return Err(anyhow!("forbidden: {}", reason));
+        }
+    }
+
+    Ok(())
+}
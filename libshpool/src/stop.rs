@@ -0,0 +1,53 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    protocol,
+    protocol::{ConnectHeader, StopReply, StopRequest},
+};
+
+pub fn run(keep_sessions: bool, socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require_capability("stop")?;
+    client
+        .write_connect_header(ConnectHeader::Stop(StopRequest { keep_sessions }))
+        .context("writing stop request header")?;
+    let reply: StopReply = client.read_reply().context("reading reply")?;
+
+    match reply {
+        StopReply::Stopped { held } => {
+            for session in held.iter() {
+                println!("held: {}", session);
+            }
+            Ok(())
+        }
+        StopReply::Failed(reason) => {
+            eprintln!("could not stop daemon: {}", reason);
+            Err(anyhow!("could not stop daemon: {}", reason))
+        }
+    }
+}
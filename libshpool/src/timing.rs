@@ -0,0 +1,144 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Timing the phases of an attach, for tracking down slow attaches.
+
+  Both ends of an attach time the phases they are in charge of and log
+  each one at debug level as it wraps up. The client times connecting,
+  the protocol handshake, waiting on the reply and waiting on the first
+  output. The daemon times finding or spawning the session and
+  computing the replay. When `shpool attach --time` asks for them, the
+  daemon sends its phases back over the control channel of the attach
+  stream, and the client prints all of them once the attach is over.
+*/
+
+use std::time;
+
+use tracing::debug;
+
+use crate::protocol::TimedPhase;
+
+/// Times a sequence of back to back phases.
+#[derive(Debug)]
+pub struct Timer {
+    start: time::Instant,
+    /// When the last phase wrapped up.
+    last: time::Instant,
+    phases: Vec<TimedPhase>,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        let now = time::Instant::now();
+        Timer { start: now, last: now, phases: vec![] }
+    }
+
+    /// Note that the named phase just wrapped up, timing it from the
+    /// end of the one before it.
+    pub fn phase(&mut self, name: &str) {
+        self.split_phase(name, None);
+    }
+
+    /// Like phase, but the given amount of time at the end gets split
+    /// off into a phase of its own, for when something else timed the
+    /// tail end.
+    pub fn split_phase(&mut self, name: &str, tail: Option<(&str, time::Duration)>) {
+        let now = time::Instant::now();
+        let took = now.duration_since(self.last);
+        self.last = now;
+        let tail_took = tail.map(|(_, t)| t).unwrap_or_default();
+        self.push(name, took.saturating_sub(tail_took));
+        if let Some((tail_name, tail_took)) = tail {
+            self.push(tail_name, tail_took);
+        }
+    }
+
+    fn push(&mut self, name: &str, took: time::Duration) {
+        debug!("attach phase '{}' took {:?}", name, took);
+        self.phases.push(TimedPhase { name: String::from(name), micros: took.as_micros() as u64 });
+    }
+
+    /// Add phases that got timed somewhere else, like on the other end
+    /// of the connection, with the given prefix on their names.
+    pub fn extend(&mut self, prefix: &str, phases: Vec<TimedPhase>) {
+        self.phases.extend(
+            phases.into_iter().map(|p| TimedPhase { name: format!("{}{}", prefix, p.name), ..p }),
+        );
+    }
+
+    /// Hand over the phases timed so far, for sending elsewhere.
+    pub fn take(&mut self) -> Vec<TimedPhase> {
+        std::mem::take(&mut self.phases)
+    }
+
+    /// A table of the phases, along with the total time from the start
+    /// to the end of the last phase timed here.
+    pub fn summary(&self) -> String {
+        let width = self.phases.iter().map(|p| p.name.len()).max().unwrap_or(0).max(5);
+        let mut summary = String::from("shpool: attach timing:\n");
+        for phase in self.phases.iter() {
+            summary.push_str(&format!(
+                "  {:<width$}  {:>10}\n",
+                phase.name,
+                format_micros(phase.micros),
+                width = width
+            ));
+        }
+        let total = self.last.duration_since(self.start).as_micros() as u64;
+        summary.push_str(&format!(
+            "  {:<width$}  {:>10}\n",
+            "total",
+            format_micros(total),
+            width = width
+        ));
+        summary
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
+
+fn format_micros(micros: u64) -> String {
+    format!("{:.1}ms", micros as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn summary() {
+        let mut timer = Timer::new();
+        std::thread::sleep(time::Duration::from_millis(10));
+        timer.split_phase("connect", Some(("handshake", time::Duration::from_millis(4))));
+        timer.extend("daemon ", vec![TimedPhase { name: String::from("spawn"), micros: 35_120 }]);
+
+        let phases = timer.take();
+        assert_eq!(phases.len(), 3);
+        assert_eq!(phases[0].name, "connect");
+        assert!(phases[0].micros >= 6_000, "{:?}", phases);
+        assert_eq!(phases[1], TimedPhase { name: String::from("handshake"), micros: 4_000 });
+        assert_eq!(phases[2], TimedPhase { name: String::from("daemon spawn"), micros: 35_120 });
+
+        timer.extend("", phases);
+        let summary = timer.summary();
+        assert!(summary.contains("  daemon spawn      35.1ms\n"), "{}", summary);
+        assert!(summary.contains("  total"), "{}", summary);
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Mutual TLS support shared by the daemon's optional TCP listener
+  and `shpool tunnel`.
+
+  Rather than teaching the daemon and every client command to speak
+  over something other than a unix socket, both ends relay bytes
+  between a TLS connection and a unix socket. On the daemon side the
+  relay dials the daemon's own socket, and on the client side
+  `shpool tunnel` listens on a local socket that the rest of the
+  commands can use as usual.
+*/
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::TcpStream,
+    os::{fd::AsFd, unix::net::UnixStream},
+    time,
+};
+
+use anyhow::{anyhow, bail, Context};
+use nix::poll;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    Connection, RootCertStore,
+};
+use sha2::{Digest, Sha256};
+use tracing::{debug, instrument};
+
+use super::consts;
+
+/// How long a peer gets to finish the handshake. This is a good deal
+/// more generous than the timeouts on the unix socket since the peer
+/// is likely on the other end of a real network.
+pub const HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Load every certificate in the given PEM file.
+pub fn load_certs(path: &str) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let mut reader =
+        io::BufReader::new(fs::File::open(path).with_context(|| format!("opening {}", path))?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("parsing certificates in {}", path))?;
+    if certs.is_empty() {
+        bail!("no certificates in {}", path);
+    }
+
+    Ok(certs)
+}
+
+/// Load the first private key in the given PEM file.
+pub fn load_key(path: &str) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let mut reader =
+        io::BufReader::new(fs::File::open(path).with_context(|| format!("opening {}", path))?);
+    rustls_pemfile::private_key(&mut reader)
+        .with_context(|| format!("parsing private key in {}", path))?
+        .ok_or_else(|| anyhow!("no private key in {}", path))
+}
+
+/// Load the CA certificates in the given PEM file as trust anchors.
+pub fn load_roots(path: &str) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)?.into_iter() {
+        roots.add(cert).with_context(|| format!("adding CA certificate from {}", path))?;
+    }
+
+    Ok(roots)
+}
+
+/// The lowercase hex sha256 fingerprint of a DER encoded certificate.
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Make sure the peer's leaf certificate matches one of the given
+/// fingerprints. Fingerprints may be written with or without the
+/// colons openssl puts between bytes. An empty pin list allows any
+/// certificate that passed verification.
+pub fn check_pins(pins: &[String], peer_certs: Option<&[CertificateDer]>) -> anyhow::Result<()> {
+    if pins.is_empty() {
+        return Ok(());
+    }
+
+    let leaf = peer_certs
+        .and_then(|certs| certs.first())
+        .ok_or_else(|| anyhow!("peer did not present a certificate"))?;
+    let got = fingerprint(leaf);
+    let pinned = pins.iter().any(|pin| {
+        pin.chars().filter(|c| *c != ':').collect::<String>().to_ascii_lowercase() == got
+    });
+    if !pinned {
+        bail!("certificate with fingerprint {} is not pinned", got);
+    }
+
+    Ok(())
+}
+
+/// Drive the handshake to completion. The caller should set a read
+/// timeout on the socket first so a silent peer can't wedge us.
+pub fn handshake(conn: &mut Connection, tcp: &mut TcpStream) -> anyhow::Result<()> {
+    while conn.is_handshaking() {
+        conn.complete_io(tcp).context("tls handshake")?;
+    }
+
+    Ok(())
+}
+
+/// Shuffle bytes between an established TLS connection and a unix
+/// socket until either side hangs up.
+#[instrument(skip_all)]
+pub fn relay(mut conn: Connection, mut tcp: TcpStream, mut unix: UnixStream) -> anyhow::Result<()> {
+    let mut buf = vec![0; consts::BUF_SIZE];
+    let watchable_tcp = tcp.try_clone().context("cloning tcp stream")?;
+    let watchable_unix = unix.try_clone().context("cloning unix stream")?;
+    let ready = poll::PollFlags::POLLIN | poll::PollFlags::POLLHUP | poll::PollFlags::POLLERR;
+
+    loop {
+        flush_tls(&mut conn, &mut tcp)?;
+
+        let mut poll_fds = [
+            poll::PollFd::new(watchable_tcp.as_fd(), poll::PollFlags::POLLIN),
+            poll::PollFd::new(watchable_unix.as_fd(), poll::PollFlags::POLLIN),
+        ];
+        poll::poll(&mut poll_fds, poll::PollTimeout::NONE).context("polling both streams")?;
+        let tcp_ready = poll_fds[0].revents().map(|r| r.intersects(ready)).unwrap_or(false);
+        let unix_ready = poll_fds[1].revents().map(|r| r.intersects(ready)).unwrap_or(false);
+
+        if tcp_ready {
+            if conn.read_tls(&mut tcp).context("reading from tcp stream")? == 0 {
+                debug!("tcp stream closed");
+                return Ok(());
+            }
+            if let Err(e) = conn.process_new_packets() {
+                // make a best effort to tell the peer what went wrong
+                let _ = flush_tls(&mut conn, &mut tcp);
+                return Err(e).context("processing tls records");
+            }
+            loop {
+                match conn.reader().read(&mut buf) {
+                    Ok(0) => {
+                        debug!("peer sent close notify");
+                        return Ok(());
+                    }
+                    Ok(len) => unix.write_all(&buf[..len]).context("writing to unix stream")?,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e).context("reading tls plaintext"),
+                }
+            }
+        }
+
+        if unix_ready {
+            let len = unix.read(&mut buf).context("reading from unix stream")?;
+            if len == 0 {
+                debug!("unix stream closed");
+                conn.send_close_notify();
+                return flush_tls(&mut conn, &mut tcp);
+            }
+            let mut to_write = &buf[..len];
+            while !to_write.is_empty() {
+                let written = conn.writer().write(to_write).context("buffering tls plaintext")?;
+                to_write = &to_write[written..];
+                flush_tls(&mut conn, &mut tcp)?;
+            }
+        }
+    }
+}
+
+fn flush_tls(conn: &mut Connection, tcp: &mut TcpStream) -> anyhow::Result<()> {
+    while conn.wants_write() {
+        conn.write_tls(tcp).context("writing to tcp stream")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn pins() {
+        let cert = CertificateDer::from(vec![1, 2, 3]);
+        let want = fingerprint(&cert);
+        assert_eq!(want.len(), 64);
+
+        let certs = [cert];
+        let pins = vec![want.clone()];
+        assert!(check_pins(&[], None).is_ok());
+        assert!(check_pins(&pins, Some(&certs)).is_ok());
+        assert!(check_pins(&pins, None).is_err());
+        assert!(check_pins(&[String::from("00")], Some(&certs)).is_err());
+
+        // openssl style
+        let colons = want
+            .as_bytes()
+            .chunks(2)
+            .map(|b| String::from_utf8_lossy(b).to_ascii_uppercase())
+            .collect::<Vec<_>>()
+            .join(":");
+        assert!(check_pins(&[colons], Some(&certs)).is_ok());
+    }
+}
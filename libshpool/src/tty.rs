@@ -18,6 +18,7 @@ use std::{
         fd::BorrowedFd,
         unix::io::{AsRawFd, RawFd},
     },
+    sync::Mutex,
 };
 
 use anyhow::Context;
@@ -26,6 +27,7 @@ use nix::{
         termios,
         termios::{ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg},
     },
+    unistd,
     unistd::isatty,
 };
 use serde_derive::{Deserialize, Serialize};
@@ -33,11 +35,23 @@ use tracing::error;
 
 use crate::consts;
 
+/// The terminal settings from before set_attach_flags along with the
+/// ones it put in place, so that the signal handler can hand the
+/// terminal back while the client is suspended and take it again once
+/// the client continues.
+static ATTACH_FLAGS: Mutex<Option<(termios::Termios, termios::Termios)>> = Mutex::new(None);
+
 // see `man ioctl_tty` for info on these ioctl commands
 nix::ioctl_read_bad!(tiocgwinsz, libc::TIOCGWINSZ, libc::winsize);
 nix::ioctl_write_ptr_bad!(tiocswinsz, libc::TIOCSWINSZ, libc::winsize);
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+// The biggest terminal the daemon will size a session for. Real
+// terminals don't come close, and the output spool allocates every row
+// up front, so a garbage size from a client must not make it through.
+const MAX_ROWS: u16 = 1000;
+const MAX_COLS: u16 = 4000;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
 pub struct Size {
     pub rows: u16,
     pub cols: u16,
@@ -81,6 +95,25 @@ impl Size {
 
         Ok(())
     }
+
+    /// This size squeezed into what the daemon can cope with. A
+    /// terminal that has not been given a size yet reports 0x0, but
+    /// vt100 needs at least one row and column.
+    pub fn clamped(&self) -> Size {
+        Size {
+            rows: self.rows.clamp(1, MAX_ROWS),
+            cols: self.cols.clamp(1, MAX_COLS),
+            xpixel: self.xpixel,
+            ypixel: self.ypixel,
+        }
+    }
+}
+
+/// Returns true if the given TERM value names a terminal that can't
+/// make sense of cursor movement or other escape sequences, such as
+/// the terminals embedded in some editors or a CI log.
+pub fn is_dumb(term: Option<&str>) -> bool {
+    matches!(term, None | Some("") | Some("dumb"))
 }
 
 pub fn disable_echo(fd: BorrowedFd<'_>) -> anyhow::Result<()> {
@@ -92,6 +125,38 @@ pub fn disable_echo(fd: BorrowedFd<'_>) -> anyhow::Result<()> {
     Ok(())
 }
 
+pub fn disable_flow_control(fd: BorrowedFd<'_>) -> anyhow::Result<()> {
+    let mut term = termios::tcgetattr(fd).context("grabbing term flags")?;
+    term.input_flags &= !InputFlags::IXON;
+
+    termios::tcsetattr(fd, SetArg::TCSANOW, &term)?;
+
+    Ok(())
+}
+
+/// Check if the terminal pauses output when it gets a Ctrl-S.
+pub fn flow_control(fd: BorrowedFd<'_>) -> anyhow::Result<bool> {
+    let term = termios::tcgetattr(fd).context("grabbing term flags")?;
+    Ok(term.input_flags.contains(InputFlags::IXON))
+}
+
+/// Check if the terminal looks like it is reading a password, which
+/// is to say that echo is off but it is still in line mode. Full screen
+/// programs turn off echo too, but they also turn off line mode.
+pub fn reading_password(fd: BorrowedFd<'_>) -> anyhow::Result<bool> {
+    let term = termios::tcgetattr(fd).context("grabbing term flags")?;
+    Ok(!term.local_flags.contains(LocalFlags::ECHO)
+        && term.local_flags.contains(LocalFlags::ICANON))
+}
+
+/// Check if the process with the given pid leads the foreground process
+/// group of the terminal. For a shell, that means it is sitting at its
+/// prompt rather than waiting on a command it ran.
+pub fn in_foreground(fd: BorrowedFd<'_>, pid: libc::pid_t) -> anyhow::Result<bool> {
+    let pgrp = unistd::tcgetpgrp(fd).context("getting foreground process group")?;
+    Ok(pgrp.as_raw() == pid)
+}
+
 pub fn set_attach_flags() -> anyhow::Result<AttachFlagsGuard<'static>> {
     // Safety: stdin is live for the whole program duration
     let fd = unsafe { BorrowedFd::borrow_raw(consts::STDIN_FD) };
@@ -127,10 +192,41 @@ pub fn set_attach_flags() -> anyhow::Result<AttachFlagsGuard<'static>> {
     new.control_flags &= !(ControlFlags::CSIZE | ControlFlags::PARENB);
     new.control_flags |= ControlFlags::CS8;
     termios::tcsetattr(fd, SetArg::TCSANOW, &new)?;
+    *ATTACH_FLAGS.lock().unwrap() = Some((old.clone(), new));
 
     Ok(AttachFlagsGuard { fd, old: Some(old) })
 }
 
+/// Put the terminal back the way it was before set_attach_flags, for
+/// while the client is suspended. Does nothing if the flags are not
+/// set.
+pub fn suspend_attach_flags() -> anyhow::Result<()> {
+    if let Some((old, _)) = ATTACH_FLAGS.lock().unwrap().as_ref() {
+        // Safety: stdin is live for the whole program duration
+        let fd = unsafe { BorrowedFd::borrow_raw(consts::STDIN_FD) };
+        termios::tcsetattr(fd, SetArg::TCSADRAIN, old).context("restoring term flags")?;
+    }
+    Ok(())
+}
+
+/// Set the flags from set_attach_flags again after the client has been
+/// continued, unless it got continued in the background, where the
+/// terminal is not ours to change. Returns true if the flags got set.
+pub fn resume_attach_flags() -> anyhow::Result<bool> {
+    let flags = ATTACH_FLAGS.lock().unwrap();
+    let raw = match flags.as_ref() {
+        Some((_, raw)) => raw,
+        None => return Ok(false),
+    };
+    // Safety: stdin is live for the whole program duration
+    let fd = unsafe { BorrowedFd::borrow_raw(consts::STDIN_FD) };
+    if unistd::tcgetpgrp(fd).context("getting foreground process group")? != unistd::getpgrp() {
+        return Ok(false);
+    }
+    termios::tcsetattr(fd, SetArg::TCSANOW, raw).context("setting term flags")?;
+    Ok(true)
+}
+
 pub struct AttachFlagsGuard<'fd> {
     fd: BorrowedFd<'fd>,
     old: Option<termios::Termios>,
@@ -139,6 +235,7 @@ pub struct AttachFlagsGuard<'fd> {
 impl<'fd> std::ops::Drop for AttachFlagsGuard<'fd> {
     fn drop(&mut self) {
         if let Some(old) = &self.old {
+            *ATTACH_FLAGS.lock().unwrap() = None;
             if let Err(e) = termios::tcsetattr(self.fd, SetArg::TCSANOW, old) {
                 error!("error restoring terminal settings: {:?}", e);
             }
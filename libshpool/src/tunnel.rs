@@ -0,0 +1,136 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs,
+    net::TcpStream,
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::Arc,
+    thread,
+};
+
+use anyhow::{anyhow, bail, Context};
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection};
+use tracing::{error, info};
+
+use super::tls;
+
+/// The settings for dialing a daemon's tcp listener.
+pub struct Remote {
+    pub addr: String,
+    pub cert: String,
+    pub key: String,
+    pub ca: String,
+    pub pins: Vec<String>,
+    pub server_name: Option<String>,
+}
+
+pub fn run(remote: Remote, socket: PathBuf) -> anyhow::Result<()> {
+    let tls_config = client_config(&remote).context("building tls config")?;
+    let server_name = remote.server_name.clone().unwrap_or_else(|| host(&remote.addr));
+    let server_name = ServerName::try_from(server_name.clone())
+        .with_context(|| format!("invalid server name '{}'", server_name))?;
+
+    if socket.exists() {
+        if UnixStream::connect(&socket).is_ok() {
+            eprintln!("shpool: something is already listening on {:?}", socket);
+            bail!("socket {:?} in use", socket);
+        }
+        fs::remove_file(&socket).context("removing stale socket")?;
+    }
+    let listener = UnixListener::bind(&socket).context("binding to socket")?;
+    info!("tunneling {:?} to {}", socket, remote.addr);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                error!("accepting connection: {:?}", e);
+                continue;
+            }
+        };
+        let tls_config = Arc::clone(&tls_config);
+        let server_name = server_name.clone();
+        let addr = remote.addr.clone();
+        let pins = remote.pins.clone();
+        thread::Builder::new()
+            .name(String::from("tunnel"))
+            .spawn(move || {
+                let res = dial(tls_config, server_name, &addr, &pins)
+                    .and_then(|(conn, tcp)| tls::relay(conn, tcp, stream));
+                if let Err(e) = res {
+                    // The client on the other end of the unix socket will
+                    // just see a hangup, so make sure the user finds out why.
+                    eprintln!("shpool: tunnel to {}: {:#}", addr, e);
+                    error!("tunneling connection: {:?}", e);
+                }
+            })
+            .map_err(|e| anyhow!("{:?}", e))?;
+    }
+
+    Ok(())
+}
+
+fn client_config(remote: &Remote) -> anyhow::Result<Arc<ClientConfig>> {
+    let config = ClientConfig::builder()
+        .with_root_certificates(tls::load_roots(&remote.ca)?)
+        .with_client_auth_cert(tls::load_certs(&remote.cert)?, tls::load_key(&remote.key)?)
+        .context("loading client certificate")?;
+
+    Ok(Arc::new(config))
+}
+
+fn dial(
+    tls_config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    addr: &str,
+    pins: &[String],
+) -> anyhow::Result<(rustls::Connection, TcpStream)> {
+    let mut tcp = TcpStream::connect(addr).with_context(|| format!("connecting to {}", addr))?;
+    tcp.set_read_timeout(Some(tls::HANDSHAKE_TIMEOUT))
+        .context("setting read timeout on tcp stream")?;
+    let mut conn =
+        ClientConnection::new(tls_config, server_name).context("creating tls connection")?.into();
+    tls::handshake(&mut conn, &mut tcp)?;
+    tls::check_pins(pins, conn.peer_certificates())?;
+    tcp.set_read_timeout(None).context("unsetting read timeout on tcp stream")?;
+
+    Ok((conn, tcp))
+}
+
+/// The host part of a host:port address, with the brackets
+/// stripped off of ipv6 addresses.
+fn host(addr: &str) -> String {
+    let host = match addr.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => host,
+        _ => addr,
+    };
+    String::from(host.trim_start_matches('[').trim_end_matches(']'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(30000)]
+    fn host_part() {
+        assert_eq!(host("example.com:7431"), "example.com");
+        assert_eq!(host("127.0.0.1:7431"), "127.0.0.1");
+        assert_eq!(host("[::1]:7431"), "::1");
+        assert_eq!(host("example.com"), "example.com");
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf};
+
+use anyhow::Context;
+
+use super::{
+    protocol,
+    protocol::{ConnectHeader, VersionReply},
+};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Report the version of the daemon listening on the given socket,
+/// warning if it differs from this client. The wrapping binary prints
+/// its own version before calling into this.
+pub fn run(socket: PathBuf) -> anyhow::Result<()> {
+    let client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            if let Some(protocol::HandshakeError::VersionMismatch { daemon_version, .. }) =
+                err.downcast_ref::<protocol::HandshakeError>()
+            {
+                // the client has already explained the mismatch on stderr
+                println!("daemon (protocol v{})", daemon_version);
+                return Ok(());
+            }
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound
+                || io_err.kind() == io::ErrorKind::ConnectionRefused
+            {
+                println!("daemon not running");
+                return Ok(());
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    let daemon_version = daemon_version(client)?;
+    match &daemon_version {
+        Some(version) => println!("daemon {}", version),
+        None => println!("daemon unknown"),
+    }
+
+    if daemon_version.as_deref() != Some(VERSION) {
+        eprintln!(
+            "shpool: warn: the daemon is running {}, but this client is {}, \
+             restart the daemon with `shpool daemon --takeover` to pick up the new version",
+            daemon_version.as_deref().unwrap_or("an older version"),
+            VERSION
+        );
+    }
+
+    Ok(())
+}
+
+/// Ask the daemon what version it is. Daemons from before the version
+/// request existed don't know how to answer, so they get None.
+fn daemon_version(mut client: protocol::Client) -> anyhow::Result<Option<String>> {
+    if !client.capabilities().iter().any(|c| c == "version") {
+        return Ok(None);
+    }
+
+    client.write_connect_header(ConnectHeader::Version).context("sending version header")?;
+    let reply: VersionReply = client.read_reply().context("reading reply")?;
+    Ok(Some(reply.version))
+}
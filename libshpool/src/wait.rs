@@ -0,0 +1,49 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    protocol,
+    protocol::{ConnectHeader, WaitReply, WaitRequest},
+};
+
+pub fn run(session: String, socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require_capability("wait")?;
+    client
+        .write_connect_header(ConnectHeader::Wait(WaitRequest { session: session.clone() }))
+        .context("writing wait request header")?;
+    let reply: WaitReply = client.read_reply().context("reading reply")?;
+
+    match reply {
+        WaitReply::Exited { exit_status } => std::process::exit(exit_status),
+        WaitReply::NotFound => {
+            eprintln!("not found: {}", session);
+            Err(anyhow!("not found: {}", session))
+        }
+    }
+}
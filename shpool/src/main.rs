@@ -24,7 +24,6 @@ fn main() -> anyhow::Result<()> {
 
     if args.version() {
         println!("shpool {}", VERSION);
-        return Ok(());
     }
 
     libshpool::run(args, None)
@@ -0,0 +1,188 @@
+use std::{
+    fs,
+    os::unix::{fs::PermissionsExt, process::CommandExt},
+    process::Command,
+};
+
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn grant_show_revoke() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let _attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        daemon_proc.wait_until_list_matches(|out| out.contains("sh1"))?;
+
+        let out = daemon_proc.acl(vec!["sh1", "show"])?;
+        assert!(out.status.success(), "acl show did not exit successfully");
+        assert!(String::from_utf8_lossy(&out.stdout).contains("no acl"));
+
+        let out = daemon_proc.acl(vec!["sh1", "grant", "4242"])?;
+        assert!(out.status.success(), "acl grant did not exit successfully");
+        let out = daemon_proc.acl(vec!["sh1", "grant", "--read-write", "4243"])?;
+        assert!(out.status.success(), "acl grant did not exit successfully");
+
+        let out = daemon_proc.acl(vec!["sh1", "show"])?;
+        assert!(out.status.success(), "acl show did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(stdout.contains("\tread-only\n"), "stdout: {}", stdout);
+        assert!(stdout.contains("\tread-write\n"), "stdout: {}", stdout);
+
+        let out = daemon_proc.acl(vec!["sh1", "revoke", "4242"])?;
+        assert!(out.status.success(), "acl revoke did not exit successfully");
+        let out = daemon_proc.acl(vec!["sh1", "show"])?;
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(!stdout.contains("read-only"), "stdout: {}", stdout);
+        assert_eq!(stdout.lines().count(), 1, "stdout: {}", stdout);
+
+        let out = daemon_proc.acl(vec!["sh1", "clear"])?;
+        assert!(out.status.success(), "acl clear did not exit successfully");
+        let out = daemon_proc.acl(vec!["sh1", "show"])?;
+        assert!(String::from_utf8_lossy(&out.stdout).contains("no acl"));
+
+        // the daemon's own user is never shut out
+        let out = daemon_proc.acl(vec!["sh1", "revoke", "4243"])?;
+        assert!(out.status.success(), "acl revoke did not exit successfully");
+        let out = daemon_proc.send("sh1", vec!["--text", "echo hi", "--enter"])?;
+        assert!(out.status.success(), "send did not exit successfully");
+
+        let out = daemon_proc.acl(vec!["nosuchsession", "show"])?;
+        assert!(!out.status.success(), "acl of missing session exited successfully");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn read_only_cannot_mutate() -> anyhow::Result<()> {
+    // the daemon's own user always has full access, so this needs a
+    // second user, which only root can act as
+    if !nix::unistd::Uid::current().is_root() {
+        eprintln!("skipping, only root can connect as another user");
+        return Ok(());
+    }
+
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("acl_peers.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let _attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        daemon_proc.wait_until_list_matches(|out| out.contains("sh1"))?;
+        let out = daemon_proc.acl(vec!["sh1", "grant", "4242"])?;
+        assert!(out.status.success(), "acl grant did not exit successfully");
+
+        // let the other user reach the socket and a copy of the binary
+        let bin = daemon_proc.tmp_dir.join("shpool");
+        fs::copy(support::shpool_bin()?, &bin).context("copying shpool binary")?;
+        fs::set_permissions(&bin, fs::Permissions::from_mode(0o755))?;
+        fs::set_permissions(&daemon_proc.tmp_dir, fs::Permissions::from_mode(0o755))?;
+        fs::set_permissions(&daemon_proc.socket_path, fs::Permissions::from_mode(0o777))?;
+        let as_reader = |args: &[&str]| {
+            Command::new(&bin)
+                .uid(4242)
+                .gid(4242)
+                .env("HOME", "/")
+                .arg("--socket")
+                .arg(&daemon_proc.socket_path)
+                .args(args)
+                .output()
+                .context("spawning proc as read-only user")
+        };
+
+        let out = as_reader(&["kill", "sh1"])?;
+        assert!(!out.status.success(), "read-only kill exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("forbidden: sh1"), "stderr: {}", stderr);
+
+        let out = as_reader(&["setenv", "sh1", "A=b"])?;
+        assert!(!out.status.success(), "read-only setenv exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("read-only access to 'sh1'"), "stderr: {}", stderr);
+
+        // neither went through
+        daemon_proc.wait_until_list_matches(|out| out.contains("sh1"))?;
+        let out = daemon_proc.getenv(vec!["sh1"])?;
+        assert!(out.status.success(), "getenv proc did not exit successfully");
+        assert!(!String::from_utf8_lossy(&out.stdout).contains("A=b"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn non_owners_cannot_create_stop_or_paste() -> anyhow::Result<()> {
+    if !nix::unistd::Uid::current().is_root() {
+        eprintln!("skipping, only root can connect as another user");
+        return Ok(());
+    }
+
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("acl_peers.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let _attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        daemon_proc.wait_until_list_matches(|out| out.contains("sh1"))?;
+        // 4242 gets to read sh1, 4343 may connect but is not on its acl
+        let out = daemon_proc.acl(vec!["sh1", "grant", "4242"])?;
+        assert!(out.status.success(), "acl grant did not exit successfully");
+
+        let bin = daemon_proc.tmp_dir.join("shpool");
+        fs::copy(support::shpool_bin()?, &bin).context("copying shpool binary")?;
+        fs::set_permissions(&bin, fs::Permissions::from_mode(0o755))?;
+        fs::set_permissions(&daemon_proc.tmp_dir, fs::Permissions::from_mode(0o755))?;
+        fs::set_permissions(&daemon_proc.socket_path, fs::Permissions::from_mode(0o777))?;
+        let as_user = |uid: u32, args: &[&str]| {
+            Command::new(&bin)
+                .uid(uid)
+                .gid(uid)
+                .env("HOME", "/")
+                .arg("--socket")
+                .arg(&daemon_proc.socket_path)
+                .args(args)
+                .output()
+                .with_context(|| format!("spawning proc as uid {}", uid))
+        };
+
+        for uid in [4242, 4343] {
+            for args in [&["new", "--name", "sh2"][..], &["daemon", "stop"], &["paste"]] {
+                let out = as_user(uid, args)?;
+                assert!(!out.status.success(), "uid {} {:?} exited successfully", uid, args);
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                assert!(
+                    stderr.contains("only the user running the daemon may"),
+                    "uid {} {:?} stderr: {}",
+                    uid,
+                    args,
+                    stderr
+                );
+            }
+        }
+
+        // listings only show the sessions the peer is on the acl of
+        let out = as_user(4242, &["list"])?;
+        assert!(out.status.success(), "list did not exit successfully");
+        assert!(String::from_utf8_lossy(&out.stdout).contains("sh1"));
+        let out = as_user(4343, &["list"])?;
+        assert!(out.status.success(), "list did not exit successfully");
+        assert!(!String::from_utf8_lossy(&out.stdout).contains("sh1"));
+
+        // the daemon is still up, and nothing got created
+        let out = daemon_proc.list()?;
+        assert!(out.status.success(), "list did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(stdout.contains("sh1"), "stdout: {}", stdout);
+        assert!(!stdout.contains("sh2"), "stdout: {}", stdout);
+
+        Ok(())
+    })
+}
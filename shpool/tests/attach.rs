@@ -2,7 +2,8 @@ use std::{
     env, fs,
     io::BufRead,
     io::{Read, Write},
-    path::PathBuf,
+    os::unix::net::UnixListener,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
     thread, time,
 };
@@ -82,6 +83,65 @@ fn custom_cmd() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn custom_cmd_argv() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let script = support::testdata_file("echo_stop.sh");
+        let mut attach_proc = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs {
+                    argv: vec![
+                        script.into_os_string().into_string().unwrap(),
+                        String::from("foo  bar"),
+                    ],
+                    ..Default::default()
+                },
+            )
+            .context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        // the arg gets passed through as a single word
+        line_matcher.match_re("foo  bar$")?;
+        line_matcher.match_re(r#"\/echo_stop\.sh$"#)?;
+
+        attach_proc.run_cmd("stop")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn custom_cmd_exit_status() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs {
+                    argv: vec![
+                        String::from("/bin/sh"),
+                        String::from("-c"),
+                        String::from("read -r line; exit 3"),
+                    ],
+                    ..Default::default()
+                },
+            )
+            .context("starting attach proc")?;
+
+        attach_proc.run_cmd("done")?;
+        let status = attach_proc.proc.wait()?;
+        assert_eq!(status.code(), Some(3));
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn forward_env() -> anyhow::Result<()> {
@@ -112,6 +172,296 @@ fn forward_env() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn cpu_affinity() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("cpu_affinity.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("pinned-1", Default::default()).context("starting attach proc")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("grep Cpus_allowed_list /proc/self/status")?;
+        line_matcher.scan_until_re(r"Cpus_allowed_list:\s+0$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn session_rlimits() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("session_limits.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo soft=$(ulimit -Sn) hard=$(ulimit -Hn)")?;
+        line_matcher.scan_until_re("soft=123 hard=123$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn forward_env_reattach() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+        {
+            let mut attach_proc = daemon_proc
+                .attach(
+                    "sh1",
+                    AttachArgs {
+                        forward_env: vec![String::from("FOO"), String::from("MY_*")],
+                        extra_env: vec![
+                            (String::from("FOO"), String::from("foo1")),
+                            (String::from("MY_VAR"), String::from("my1")),
+                        ],
+                        ..Default::default()
+                    },
+                )
+                .context("starting attach proc")?;
+
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            attach_proc.run_cmd(r#"echo "$FOO:$MY_VAR" "#)?;
+            line_matcher.scan_until_re("foo1:my1$")?;
+        }
+
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        {
+            let mut attach_proc = daemon_proc
+                .attach(
+                    "sh1",
+                    AttachArgs {
+                        forward_env: vec![String::from("FOO"), String::from("MY_*")],
+                        extra_env: vec![
+                            (String::from("FOO"), String::from("foo 2")),
+                            (String::from("MY_VAR"), String::from("my2")),
+                        ],
+                        ..Default::default()
+                    },
+                )
+                .context("reattaching")?;
+
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            attach_proc.run_cmd(r#". "$SHPOOL_SESSION_DIR/forward_env""#)?;
+            attach_proc.run_cmd(r#"echo "$FOO:$MY_VAR" "#)?;
+            line_matcher.scan_until_re("foo 2:my2$")?;
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn osc_replay_on_reattach() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::tempdir().context("creating tmp dir")?;
+        let go_file = tmp_dir.path().join("go");
+        let done_file = tmp_dir.path().join("done");
+
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            // emit a notification and a progress report once we have detached
+            attach_proc.run_cmd(&format!(
+                r#"(while [ ! -e {go} ]; do sleep 0.1; done; printf '\033]9;bg done\007\033]9;4;1;42\007'; touch {done}) &"#,
+                go = go_file.display(),
+                done = done_file.display(),
+            ))?;
+            attach_proc.run_cmd("echo started")?;
+            line_matcher.scan_until_re("started$")?;
+        }
+
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        std::fs::write(&go_file, "").context("writing go file")?;
+        support::wait_until(|| Ok(done_file.exists()))?;
+        // give the reader thread a moment to slurp up the output
+        thread::sleep(time::Duration::from_millis(500));
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("reattaching")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo reattached")?;
+        line_matcher.scan_until_re(r"\x1b\]9;bg done\x1b\\\x1b\]9;4;1;42\x1b\\")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn relink_ssh_auth_sock_on_reattach() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let first_tgt = daemon_proc.tmp_dir.join("ssh-auth-sock-first.fake");
+        fs::File::create(&first_tgt)?;
+        let second_tgt = daemon_proc.tmp_dir.join("ssh-auth-sock-second.fake");
+        fs::File::create(&second_tgt)?;
+
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+        {
+            let mut attach_proc = daemon_proc
+                .attach(
+                    "sh1",
+                    AttachArgs {
+                        extra_env: vec![(
+                            String::from("SSH_AUTH_SOCK"),
+                            String::from(first_tgt.to_str().unwrap()),
+                        )],
+                        ..Default::default()
+                    },
+                )
+                .context("starting attach proc")?;
+
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            attach_proc.run_cmd("ls -l $SSH_AUTH_SOCK")?;
+            line_matcher
+                .scan_until_re(r#".*sh1/ssh-auth-sock.socket ->.*ssh-auth-sock-first.fake$"#)?;
+        }
+
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        let mut attach_proc = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs {
+                    extra_env: vec![(
+                        String::from("SSH_AUTH_SOCK"),
+                        String::from(second_tgt.to_str().unwrap()),
+                    )],
+                    ..Default::default()
+                },
+            )
+            .context("reattaching")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("ls -l $SSH_AUTH_SOCK")?;
+        line_matcher
+            .scan_until_re(r#".*sh1/ssh-auth-sock.socket ->.*ssh-auth-sock-second.fake$"#)?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn strip_hyperlinks() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("strip_hyperlinks.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc
+            .run_cmd(r#"printf 'see \033]8;;https://example.com\033\\here\033]8;;\033\\ ok\n'"#)?;
+        line_matcher.scan_until_re("see here ok$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn hyperlink_reopened_on_reattach() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            // leave a link open across the detach
+            attach_proc.run_cmd(r#"printf '\033]8;;https://example.com\033\\'; echo opened"#)?;
+            line_matcher.scan_until_re("opened$")?;
+        }
+
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("reattaching")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo reattached")?;
+        line_matcher.scan_until_re(r"\x1b\]8;;https://example\.com\x1b\\")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn clipboard_truncate() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("clipboard_truncate.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        // "hello world" gets cut down to "hello "
+        attach_proc.run_cmd(r#"printf '\033]52;c;aGVsbG8gd29ybGQ=\007 ok\n'"#)?;
+        line_matcher.scan_until_re(r"\x1b\]52;c;aGVsbG8g\x1b\\ ok$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn title_suffix() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("title_suffix.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd(r#"printf '\033]0;vim\007\n'"#)?;
+        line_matcher.scan_until_re(r"\x1b\]0;vim \(sh1\)\x07$")?;
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn symlink_ssh_auth_sock() -> anyhow::Result<()> {
@@ -447,6 +797,50 @@ fn force_attach() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn handshake_timeout() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::tempdir().context("creating tmp dir")?;
+        let socket_path = tmp_dir.path().join("stalled.socket");
+
+        // a daemon that accepts connections but never says anything
+        let listener = UnixListener::bind(&socket_path).context("binding fake daemon socket")?;
+        thread::spawn(move || {
+            let mut conns = vec![];
+            for conn in listener.incoming() {
+                conns.push(conn);
+            }
+        });
+
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--config-file")
+            .arg(support::testdata_file("attach_timeout.toml"))
+            .arg("--socket")
+            .arg(&socket_path)
+            .arg("attach")
+            .arg("sh1")
+            .output()
+            .context("spawning attach proc")?;
+        assert!(!out.status.success(), "attach proc exited successfully");
+
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(
+            stderr
+                .contains("timed out after 1s waiting for the daemon to answer the protocol hello"),
+            "stderr: {}",
+            stderr
+        );
+        assert!(
+            stderr.contains("could not ask the daemon about session 'sh1'"),
+            "stderr: {}",
+            stderr
+        );
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn busy() -> anyhow::Result<()> {
@@ -474,17 +868,82 @@ fn busy() -> anyhow::Result<()> {
 
 #[test]
 #[timeout(30000)]
-fn daemon_hangup() -> anyhow::Result<()> {
+fn max_sessions() -> anyhow::Result<()> {
     support::dump_err(|| {
-        let mut daemon_proc = support::daemon::Proc::new(
-            "norc.toml",
-            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
-        )
-        .context("starting daemon proc")?;
-        let mut attach_proc =
-            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut daemon_proc =
+            support::daemon::Proc::new("max_sessions.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut waiter =
+            daemon_proc.events.take().unwrap().waiter(["daemon-session-quota-exceeded"]);
 
-        // make sure the shell is up and running
+        let mut sh1 = daemon_proc.attach("sh1", Default::default()).context("attaching sh1")?;
+        let mut line_matcher1 = sh1.line_matcher()?;
+        sh1.run_cmd("echo foo")?;
+        line_matcher1.scan_until_re("foo$")?;
+
+        let mut sh2 = daemon_proc.attach("sh2", Default::default()).context("attaching sh2")?;
+        let mut line_matcher2 = sh2.stderr_line_matcher()?;
+        line_matcher2.scan_until_re("would exceed the limit of 1 sessions$")?;
+        waiter.wait_event("daemon-session-quota-exceeded")?;
+
+        let exit_status = sh2.proc.wait()?;
+        assert!(!exit_status.success());
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn attach_auth() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("attach_auth.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut sh1 = daemon_proc.attach("sh1", Default::default()).context("attaching sh1")?;
+        let mut line_matcher1 = sh1.line_matcher()?;
+        sh1.run_cmd("echo foo")?;
+        line_matcher1.scan_until_re("foo$")?;
+
+        let mut secret =
+            daemon_proc.attach("secret", Default::default()).context("attaching secret")?;
+        let mut stderr_matcher = secret.stderr_line_matcher()?;
+        stderr_matcher.scan_until_re("attach denied: no token present$")?;
+        let exit_status = secret.proc.wait()?;
+        assert!(!exit_status.success());
+
+        // the denied attach never made the session
+        let out = daemon_proc.list()?;
+        assert!(!String::from_utf8_lossy(&out.stdout[..]).contains("secret"));
+
+        // reading from or typing into a session without attaching gets
+        // checked too
+        let out = daemon_proc.new_session(vec!["--name", "secret"])?;
+        assert!(out.status.success(), "new did not exit successfully");
+        let out = daemon_proc.send("secret", vec!["--text", "echo hi", "--enter"])?;
+        assert!(!out.status.success(), "send to secret exited successfully");
+        assert!(String::from_utf8_lossy(&out.stderr[..]).contains("no token present"));
+        let out = daemon_proc.capture(vec!["secret"])?;
+        assert!(!out.status.success(), "capture of secret exited successfully");
+        assert!(String::from_utf8_lossy(&out.stderr[..]).contains("no token present"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn daemon_hangup() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        // make sure the shell is up and running
         let mut line_matcher = attach_proc.line_matcher()?;
         attach_proc.run_cmd("echo foo")?;
         line_matcher.scan_until_re("foo$")?;
@@ -530,6 +989,54 @@ fn default_keybinding_detach() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn csi_u_keybinding_detach() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("csi_u_keybindings.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        let mut a1 =
+            daemon_proc.attach("sess", Default::default()).context("starting attach proc")?;
+        let mut lm1 = a1.line_matcher()?;
+
+        a1.run_cmd("echo ready")?;
+        lm1.scan_until_re("ready$")?;
+
+        a1.run_raw_cmd(b"\x1b[32;5u\x1b[1081::113;5u".to_vec())?; // Ctrl-Space Ctrl-q
+        a1.proc.wait()?;
+
+        waiter.wait_event("daemon-bidi-stream-done")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn bracketed_paste_no_detach() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut a1 =
+            daemon_proc.attach("sess", Default::default()).context("starting attach proc")?;
+        let mut lm1 = a1.line_matcher()?;
+
+        a1.run_cmd("echo ready")?;
+        lm1.scan_until_re("ready$")?;
+
+        // Ctrl-Space Ctrl-q inside a paste, then Ctrl-u to clear the line
+        a1.run_raw(b"\x1b[200~\x00\x11\x1b[201~\x15".to_vec())?;
+        a1.run_cmd("echo still-here")?;
+        lm1.scan_until_re("still-here$")?;
+
+        Ok(())
+    })
+}
+
 // test to exercise the code path where a keybinding
 // shows up in two different input chunks
 #[test]
@@ -762,10 +1269,374 @@ fn has_right_default_path() -> anyhow::Result<()> {
         let mut attach_proc =
             daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
 
-        let mut line_matcher = attach_proc.line_matcher()?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo $PATH")?;
+        line_matcher.scan_until_re("/usr/bin:/bin:/usr/sbin:/sbin$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn screen_restore() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            attach_proc.run_cmd("echo foo")?;
+            line_matcher.scan_until_re("foo$")?;
+        }
+
+        // wait until the daemon has noticed that the connection
+        // has dropped before we attempt to open the connection again
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            // the re-attach should redraw the screen for us, so we should
+            // get a line with "foo" as part of the re-drawn screen.
+            line_matcher.scan_until_re("foo$")?;
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn dumb_term_skips_restore() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            attach_proc.run_cmd("echo foo")?;
+            line_matcher.scan_until_re("foo$")?;
+        }
+
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        {
+            let mut attach_proc = daemon_proc
+                .attach(
+                    "sh1",
+                    AttachArgs {
+                        extra_env: vec![(String::from("TERM"), String::from("dumb"))],
+                        ..Default::default()
+                    },
+                )
+                .context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            let mut stderr_line_matcher = attach_proc.stderr_line_matcher()?;
+
+            stderr_line_matcher
+                .match_re("TERM is dumb, disabled: session restore, notification replay$")?;
+
+            // no redrawn screen, so the first thing we see is the new output
+            attach_proc.run_cmd("echo bar")?;
+            line_matcher.match_re("bar$")?;
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn term_mismatch_warning() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("user_env.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        {
+            let mut attach_proc = daemon_proc
+                .attach(
+                    "sh1",
+                    AttachArgs {
+                        extra_env: vec![(String::from("TERM"), String::from("xterm"))],
+                        ..Default::default()
+                    },
+                )
+                .context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            attach_proc.run_cmd("echo foo")?;
+            line_matcher.scan_until_re("foo$")?;
+        }
+
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        {
+            let mut attach_proc = daemon_proc
+                .attach(
+                    "sh1",
+                    AttachArgs {
+                        extra_env: vec![(String::from("TERM"), String::from("xterm-256color"))],
+                        ..Default::default()
+                    },
+                )
+                .context("starting attach proc")?;
+            let mut stderr_line_matcher = attach_proc.stderr_line_matcher()?;
+
+            stderr_line_matcher.match_re(
+                "^shpool: warn: this session was started with TERM=xterm but this terminal is TERM=xterm-256color \\(colors \\(8 vs 256\\)",
+            )?;
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn status_file() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let status_path = {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            attach_proc.run_cmd("echo hi")?;
+            line_matcher.scan_until_re("hi$")?;
+
+            attach_proc.run_cmd("echo path=$SHPOOL_SESSION_STATUS_FILE")?;
+            let captures = line_matcher.capture_re("path=(.*)$")?;
+            let status_path = PathBuf::from(captures[1].clone().unwrap());
+
+            attach_proc.run_cmd(r#"grep -c '"attached": true' "$SHPOOL_SESSION_STATUS_FILE""#)?;
+            line_matcher.match_re("^1$")?;
+
+            status_path
+        };
+
+        let read_status = || -> anyhow::Result<serde_json::Value> {
+            Ok(serde_json::from_str(&fs::read_to_string(&status_path)?)?)
+        };
+        support::wait_until(|| Ok(read_status()?["attached"] == false))?;
+        let status = read_status()?;
+        assert_eq!(status["name"], "sh1");
+        assert!(status["client"].is_null());
+        assert!(status["generation"].as_u64().unwrap() >= 2);
+
+        daemon_proc.kill(vec![String::from("sh1")])?;
+        support::wait_until(|| Ok(!status_path.exists()))?;
+
+        Ok(())
+    })
+}
+
+/// Write out a session_audit config recording to a dir under the
+/// given tmp dir, returning the config file and the recording dir.
+fn session_audit_config(tmp_dir: &Path, key_cmd: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let audit_dir = tmp_dir.join("audit");
+    let config_contents = fs::read_to_string(support::testdata_file("session_audit.toml.tmpl"))?
+        .replace("TMP_AUDIT_DIR", audit_dir.to_str().unwrap())
+        .replace("TMP_KEY_CMD", key_cmd);
+    let config_file = tmp_dir.join("session_audit.toml");
+    fs::write(&config_file, config_contents)?;
+    Ok((config_file, audit_dir))
+}
+
+#[test]
+#[timeout(30000)]
+fn session_audit() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-config")?;
+        let (config_file, audit_dir) =
+            session_audit_config(tmp_dir.path(), &format!("echo {}", "5a".repeat(32)))?;
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut stderr_line_matcher = attach_proc.stderr_line_matcher()?;
+            stderr_line_matcher.scan_until_re("this session is being recorded for audit")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            attach_proc.run_cmd("echo hi")?;
+            line_matcher.scan_until_re("hi$")?;
+
+            // input typed before the shell is up gets taken for a
+            // password, since echo is still off, so check on input sent
+            // once it is
+            attach_proc.run_cmd("echo audited")?;
+            line_matcher.scan_until_re("audited$")?;
+
+            attach_proc.run_cmd("echo recording=$SHPOOL_SESSION_RECORDING")?;
+            line_matcher.scan_until_re("recording=.*audit/sh1-.*shpoolaudit$")?;
+
+            // read -s turns off echo but leaves the terminal in line
+            // mode, just like a password prompt
+            attach_proc.run_cmd("echo ready; read -rs pw; echo got ${#pw} bytes")?;
+            line_matcher.scan_until_re("ready$")?;
+            attach_proc.run_cmd("hunter2")?;
+            line_matcher.scan_until_re("got 7 bytes$")?;
+        }
+
+        let recordings = fs::read_dir(&audit_dir)?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(recordings.len(), 1);
+        let out = daemon_proc.audit_dump(&config_file, &recordings[0].path())?;
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(
+            out.status.success(),
+            "audit-dump failed: {}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert!(stdout.contains(r#"in  "echo audited"#), "stdout: {}", stdout);
+        assert!(stdout.contains("out \"audited"), "stdout: {}", stdout);
+        assert!(stdout.contains("<8 bytes redacted>"), "stdout: {}", stdout);
+        assert!(!stdout.contains("hunter2"), "stdout: {}", stdout);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn session_audit_bad_key() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-config")?;
+        let (config_file, _) = session_audit_config(tmp_dir.path(), "false")?;
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut stderr_line_matcher = attach_proc.stderr_line_matcher()?;
+        stderr_line_matcher.scan_until_re("could not start session recording")?;
+        assert!(!attach_proc.proc.wait()?.success());
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn screen_wide_restore() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            attach_proc.run_cmd("echo ooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooy")?;
+            line_matcher.scan_until_re("ooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooy$")?;
+        }
+
+        // wait until the daemon has noticed that the connection
+        // has dropped before we attempt to open the connection again
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            // the re-attach should redraw the screen for us, so we should
+            // get a line with the full echo result as part of the re-drawn screen.
+            line_matcher.scan_until_re("ooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooy$")?;
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn lines_restore() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_lines.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            attach_proc.run_cmd("echo foo")?;
+            attach_proc.run_cmd("echo")?;
+            line_matcher.scan_until_re("foo$")?;
+        }
+
+        // wait until the daemon has noticed that the connection
+        // has dropped before we attempt to open the connection again
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            // the re-attach should redraw the last 2 lines for us, so we should
+            // get a line with "foo" as part of the re-drawn screen.
+            line_matcher.scan_until_re("foo$")?;
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn no_replay() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_lines.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            attach_proc.run_cmd("echo foo")?;
+            attach_proc.run_cmd("echo")?;
+            line_matcher.scan_until_re("foo$")?;
+        }
 
-        attach_proc.run_cmd("echo $PATH")?;
-        line_matcher.scan_until_re("/usr/bin:/bin:/usr/sbin:/sbin$")?;
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        {
+            let mut attach_proc = daemon_proc
+                .attach("sh1", AttachArgs { no_replay: true, ..Default::default() })
+                .context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            // nothing got redrawn, so the first line is the new output
+            attach_proc.run_cmd("echo bar")?;
+            line_matcher.match_re("^bar$")?;
+        }
 
         Ok(())
     })
@@ -773,10 +1644,10 @@ fn has_right_default_path() -> anyhow::Result<()> {
 
 #[test]
 #[timeout(30000)]
-fn screen_restore() -> anyhow::Result<()> {
+fn replay_lines() -> anyhow::Result<()> {
     support::dump_err(|| {
         let mut daemon_proc =
-            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+            support::daemon::Proc::new("restore_lines.toml", DaemonArgs::default())
                 .context("starting daemon proc")?;
         let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
 
@@ -785,22 +1656,24 @@ fn screen_restore() -> anyhow::Result<()> {
                 daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
             let mut line_matcher = attach_proc.line_matcher()?;
 
-            attach_proc.run_cmd("echo foo")?;
-            line_matcher.scan_until_re("foo$")?;
+            for word in ["first", "second", "third", "fourth"] {
+                attach_proc.run_cmd(format!("echo {}", word).as_str())?;
+            }
+            line_matcher.scan_until_re("fourth$")?;
         }
 
-        // wait until the daemon has noticed that the connection
-        // has dropped before we attempt to open the connection again
         daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
 
         {
-            let mut attach_proc =
-                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut attach_proc = daemon_proc
+                .attach("sh1", AttachArgs { replay_lines: Some(10), ..Default::default() })
+                .context("starting attach proc")?;
             let mut line_matcher = attach_proc.line_matcher()?;
 
-            // the re-attach should redraw the screen for us, so we should
-            // get a line with "foo" as part of the re-drawn screen.
-            line_matcher.scan_until_re("foo$")?;
+            // the config only asks for 2 lines, which would not reach
+            // back this far
+            line_matcher.scan_until_re("first$")?;
+            line_matcher.scan_until_re("fourth$")?;
         }
 
         Ok(())
@@ -809,10 +1682,39 @@ fn screen_restore() -> anyhow::Result<()> {
 
 #[test]
 #[timeout(30000)]
-fn screen_wide_restore() -> anyhow::Result<()> {
+fn attach_time() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut attach_proc = daemon_proc
+            .attach("sh1", AttachArgs { time: true, ..Default::default() })
+            .context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let out = daemon_proc.detach(vec![String::from("sh1")])?;
+        assert!(out.status.success(), "detach proc did not exit successfully");
+        attach_proc.proc.wait()?;
+
+        let mut stderr_line_matcher = attach_proc.stderr_line_matcher()?;
+        stderr_line_matcher.scan_until_re("shpool: attach timing:")?;
+        stderr_line_matcher.scan_until_re("^  handshake +[0-9.]+ms$")?;
+        stderr_line_matcher.scan_until_re("^  daemon spawn +[0-9.]+ms$")?;
+        stderr_line_matcher.scan_until_re("^  first output +[0-9.]+ms$")?;
+        stderr_line_matcher.scan_until_re("^  total +[0-9.]+ms$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn on_attach_cmd() -> anyhow::Result<()> {
     support::dump_err(|| {
         let mut daemon_proc =
-            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+            support::daemon::Proc::new("on_attach_cmd.toml", DaemonArgs::default())
                 .context("starting daemon proc")?;
         let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
 
@@ -821,12 +1723,10 @@ fn screen_wide_restore() -> anyhow::Result<()> {
                 daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
             let mut line_matcher = attach_proc.line_matcher()?;
 
-            attach_proc.run_cmd("echo ooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooy")?;
-            line_matcher.scan_until_re("ooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooy$")?;
+            attach_proc.run_cmd("echo first")?;
+            line_matcher.scan_until_re("first$")?;
         }
 
-        // wait until the daemon has noticed that the connection
-        // has dropped before we attempt to open the connection again
         daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
 
         {
@@ -834,9 +1734,7 @@ fn screen_wide_restore() -> anyhow::Result<()> {
                 daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
             let mut line_matcher = attach_proc.line_matcher()?;
 
-            // the re-attach should redraw the screen for us, so we should
-            // get a line with the full echo result as part of the re-drawn screen.
-            line_matcher.scan_until_re("ooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooyooooxooooy$")?;
+            line_matcher.scan_until_re("on-attach$")?;
         }
 
         Ok(())
@@ -845,10 +1743,10 @@ fn screen_wide_restore() -> anyhow::Result<()> {
 
 #[test]
 #[timeout(30000)]
-fn lines_restore() -> anyhow::Result<()> {
+fn on_attach_cmd_flag() -> anyhow::Result<()> {
     support::dump_err(|| {
         let mut daemon_proc =
-            support::daemon::Proc::new("restore_lines.toml", DaemonArgs::default())
+            support::daemon::Proc::new("on_attach_cmd.toml", DaemonArgs::default())
                 .context("starting daemon proc")?;
         let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
 
@@ -857,23 +1755,26 @@ fn lines_restore() -> anyhow::Result<()> {
                 daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
             let mut line_matcher = attach_proc.line_matcher()?;
 
-            attach_proc.run_cmd("echo foo")?;
-            attach_proc.run_cmd("echo")?;
-            line_matcher.scan_until_re("foo$")?;
+            attach_proc.run_cmd("echo first")?;
+            line_matcher.scan_until_re("first$")?;
         }
 
-        // wait until the daemon has noticed that the connection
-        // has dropped before we attempt to open the connection again
         daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
 
         {
-            let mut attach_proc =
-                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut attach_proc = daemon_proc
+                .attach(
+                    "sh1",
+                    AttachArgs {
+                        on_attach_cmd: Some(String::from("echo from-flag")),
+                        ..Default::default()
+                    },
+                )
+                .context("starting attach proc")?;
             let mut line_matcher = attach_proc.line_matcher()?;
 
-            // the re-attach should redraw the last 2 lines for us, so we should
-            // get a line with "foo" as part of the re-drawn screen.
-            line_matcher.scan_until_re("foo$")?;
+            // the flag takes the place of the config
+            line_matcher.scan_until_re("from-flag$")?;
         }
 
         Ok(())
@@ -956,6 +1857,163 @@ fn exits_with_same_status_as_shell() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn exits_with_same_status_after_detached_exit() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut waiter = daemon_proc
+            .events
+            .take()
+            .unwrap()
+            .waiter(["daemon-bidi-stream-enter", "daemon-bidi-stream-done"]);
+
+        let stop_file = daemon_proc.tmp_dir.join("stop");
+        let script = format!(
+            "while [ ! -e {} ]; do sleep 0.1; done; exit 7",
+            stop_file.to_str().ok_or(anyhow!("non-utf8 tmp dir"))?
+        );
+        let mut attach_proc = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs {
+                    argv: vec![String::from("/bin/sh"), String::from("-c"), script],
+                    ..Default::default()
+                },
+            )
+            .context("starting attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+
+        let out = daemon_proc.detach(vec![String::from("sh1")])?;
+        assert!(out.status.success(), "detach proc did not exit successfully");
+        attach_proc.proc.wait()?;
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-done")?);
+
+        fs::write(&stop_file, "")?;
+        daemon_proc.wait_until_list_matches(|listout| listout.contains("exited (status 7)"))?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("reattaching")?;
+        let status = attach_proc.proc.wait()?;
+        assert_eq!(status.code(), Some(7));
+
+        // collecting the exit status reaps the session
+        daemon_proc.wait_until_list_matches(|listout| !listout.contains("sh1"))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn exited_screen() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec![
+            "--name",
+            "sh1",
+            "--",
+            "sh",
+            "-c",
+            "echo goodbye; exit 3",
+        ])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+        daemon_proc.wait_until_list_matches(|listout| listout.contains("exited (status 3)"))?;
+
+        // the last screen gets printed along with the exit status
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("reattaching")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        line_matcher.scan_until_re("goodbye$")?;
+        let status = attach_proc.proc.wait()?;
+        assert_eq!(status.code(), Some(3));
+
+        daemon_proc.wait_until_list_matches(|listout| !listout.contains("sh1"))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn exited_restart() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let runs_file = daemon_proc.tmp_dir.join("runs");
+        let script = format!(
+            "echo run >> {}; exit 3",
+            runs_file.to_str().ok_or(anyhow!("non-utf8 tmp dir"))?
+        );
+
+        let out = daemon_proc.new_session(vec!["--name", "sh1", "--", "sh", "-c", &script])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+        daemon_proc.wait_until_list_matches(|listout| listout.contains("exited (status 3)"))?;
+
+        // the same command runs again, this time with a client attached
+        let mut attach_proc = daemon_proc
+            .attach("sh1", AttachArgs { restart: true, ..Default::default() })
+            .context("reattaching")?;
+        let status = attach_proc.proc.wait()?;
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(fs::read_to_string(&runs_file)?, "run\nrun\n");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn exited_linger() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("exited_linger.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec!["--name", "sh1", "--", "sh", "-c", "exit 3"])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+        daemon_proc.wait_until_list_matches(|listout| listout.contains("exited (status 3)"))?;
+
+        // nobody came to collect the exit status in time
+        daemon_proc.wait_until_list_matches(|listout| !listout.contains("sh1"))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn restart_policy() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restart_policy.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let runs_file = daemon_proc.tmp_dir.join("runs");
+        let script = format!(
+            "sh -c 'echo run >> {}; exit 3'",
+            runs_file.to_str().ok_or(anyhow!("non-utf8 tmp dir"))?
+        );
+
+        let mut attach_proc = daemon_proc
+            .attach("flaky", AttachArgs { cmd: Some(script), ..Default::default() })
+            .context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        line_matcher.scan_until_re("exited with status 3, restarting in 1s")?;
+        line_matcher.scan_until_re("exited with status 3, restarting in 2s")?;
+
+        // the client stays attached until the restarts run out
+        let status = attach_proc.proc.wait()?;
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(fs::read_to_string(&runs_file)?, "run\nrun\nrun\n");
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn ttl_hangup() -> anyhow::Result<()> {
@@ -1009,6 +2067,54 @@ fn ttl_no_hangup_yet() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn ttl_hangup_notice() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs { ttl: Some(time::Duration::from_secs(1)), ..Default::default() },
+            )
+            .context("starting attach proc")?;
+
+        // the client gets told why it is being hung up on
+        let mut stderr_matcher = attach_proc.stderr_line_matcher()?;
+        stderr_matcher.scan_until_re("session 'sh1' reached the end of its ttl")?;
+        attach_proc.proc.wait().context("waiting for attach proc")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn max_session_ttl() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("max_session_ttl.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        // asking for more time than the config allows gets cut down
+        let mut attach_proc = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs { ttl: Some(time::Duration::from_secs(1000)), ..Default::default() },
+            )
+            .context("starting attach proc")?;
+
+        let mut stderr_matcher = attach_proc.stderr_line_matcher()?;
+        stderr_matcher.scan_until_re("session 'sh1' reached the end of its ttl")?;
+        attach_proc.proc.wait().context("waiting for attach proc")?;
+
+        let out = daemon_proc.list()?;
+        assert!(!String::from_utf8_lossy(&out.stdout).contains("sh1"));
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn prompt_prefix_bash() -> anyhow::Result<()> {
@@ -1340,3 +2446,132 @@ fn up_arrow_no_crash() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+#[timeout(30000)]
+fn lock_keybinding() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("lock.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo before")?;
+        line_matcher.scan_until_re("before$")?;
+
+        attach_proc.run_raw(vec![0x16, 0x0c])?; // Ctrl-v Ctrl-l
+        line_matcher.scan_until_re("session 'sh1' is locked$")?;
+
+        attach_proc.run_raw(b"hunter3\r".to_vec())?;
+        line_matcher.scan_until_re("wrong passphrase$")?;
+
+        attach_proc.run_raw(b"hunter2\r".to_vec())?;
+        // give the daemon a moment to check the passphrase, since input
+        // that shows up in the same chunk as the enter gets dropped
+        thread::sleep(time::Duration::from_millis(500));
+        attach_proc.run_cmd("echo after")?;
+        line_matcher.scan_until_re("after$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn attach_banner() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("attach_banner.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let args = || AttachArgs {
+            config: Some(String::from("attach_banner.toml")),
+            ..Default::default()
+        };
+
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", args()).context("starting attach proc")?;
+            let mut stderr_line_matcher = attach_proc.stderr_line_matcher()?;
+            stderr_line_matcher.scan_until_re(
+                "^attached to 'sh1' on .+, created [0-9]+s ago, 0 previous attaches$",
+            )?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("echo hi")?;
+            line_matcher.scan_until_re("hi$")?;
+        }
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+        {
+            let mut attach_proc = daemon_proc.attach("sh1", args()).context("reattaching")?;
+            let mut stderr_line_matcher = attach_proc.stderr_line_matcher()?;
+            stderr_line_matcher.scan_until_re("^attached to 'sh1' on .+, 1 previous attaches$")?;
+        }
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        let mut attach_proc = daemon_proc
+            .attach("sh1", AttachArgs { quiet: true, ..args() })
+            .context("reattaching quietly")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo quiet")?;
+        line_matcher.scan_until_re("quiet$")?;
+        attach_proc.run_cmd("exit")?;
+        attach_proc.proc.wait().context("waiting for attach proc")?;
+        let mut stderr = String::new();
+        attach_proc
+            .proc
+            .stderr
+            .take()
+            .ok_or(anyhow!("missing stderr"))?
+            .read_to_string(&mut stderr)?;
+        assert!(!stderr.contains("attached to"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn flow_control_off() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("flow_control_off.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd(r"stty -a | grep -o -- '-\?ixon'")?;
+        line_matcher.scan_until_re("-ixon$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn flow_control_notice() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("flow_control_notice.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        let mut stderr_line_matcher = attach_proc.stderr_line_matcher()?;
+
+        attach_proc.run_cmd("echo ready")?;
+        line_matcher.scan_until_re("ready$")?;
+
+        attach_proc.run_raw(vec![0x13])?;
+        stderr_line_matcher.scan_until_re("output paused by Ctrl-S, press Ctrl-Q to resume$")?;
+
+        attach_proc.run_raw(vec![0x11])?;
+        attach_proc.run_cmd("echo resumed")?;
+        line_matcher.scan_until_re("resumed$")?;
+
+        Ok(())
+    })
+}
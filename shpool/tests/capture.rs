@@ -0,0 +1,112 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+const JOB: &str = "printf 'one\\n\\033[31mtwo\\033[0m\\n'; sleep 1000";
+
+#[test]
+#[timeout(30000)]
+fn screen() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec!["--name", "job", "--", "sh", "-c", JOB])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let mut screen = String::new();
+        support::wait_until(|| {
+            let out = daemon_proc.capture(vec!["job"])?;
+            assert!(out.status.success(), "capture proc did not exit successfully");
+            screen = String::from_utf8(out.stdout)?;
+            Ok(screen.starts_with("one\ntwo\n"))
+        })?;
+        // the whole screen, blank rows included
+        assert_eq!(screen.lines().count(), 24, "screen: {:?}", screen);
+
+        let out = daemon_proc.capture(vec!["--ansi", "job"])?;
+        assert!(out.status.success(), "capture proc did not exit successfully");
+        let screen = String::from_utf8(out.stdout)?;
+        assert!(screen.contains("\x1b[31mtwo"), "screen: {:?}", screen);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn raw_bytes() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        // the raw output is kept even when there is no screen
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec!["--name", "job", "--", "sh", "-c", JOB])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        support::wait_until(|| {
+            let out = daemon_proc.capture(vec!["--bytes", "9", "job"])?;
+            assert!(out.status.success(), "capture proc did not exit successfully");
+            Ok(out.stdout == b"two\x1b[0m\r\n")
+        })?;
+
+        let out = daemon_proc.capture(vec!["job"])?;
+        assert!(!out.status.success(), "capture proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("no screen kept for job"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn redacted() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("redact.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        // printed in pieces, so the secret arrives in separate reads
+        let job = "printf 'AWS_SECRET_ACCESS_KEY=hun'; sleep 0.05; printf 'ter2 ok\\n'; \
+                   echo token: deadbeef; sleep 1000";
+        let out = daemon_proc.new_session(vec!["--name", "job", "--", "sh", "-c", job])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let mut screen = String::new();
+        support::wait_until(|| {
+            let out = daemon_proc.capture(vec!["job"])?;
+            assert!(out.status.success(), "capture proc did not exit successfully");
+            screen = String::from_utf8(out.stdout)?;
+            Ok(screen.contains("token: "))
+        })?;
+        assert!(screen.starts_with("[REDACTED] ok\ntoken: dead...\n"), "screen: {:?}", screen);
+
+        let out = daemon_proc.capture(vec!["--bytes", "4096", "job"])?;
+        assert!(out.status.success(), "capture proc did not exit successfully");
+        let raw = String::from_utf8_lossy(&out.stdout);
+        assert!(!raw.contains("hunter2") && !raw.contains("deadbeef"), "raw: {:?}", raw);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn not_found() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        let out = daemon_proc.capture(vec!["nope"])?;
+        assert!(!out.status.success(), "capture proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nope"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
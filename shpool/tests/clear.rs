@@ -0,0 +1,48 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn wipes_output() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec![
+            "--name",
+            "job",
+            "--",
+            "sh",
+            "-c",
+            "echo hunter2; sleep 1000",
+        ])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        support::wait_until(|| {
+            let out = daemon_proc.capture(vec!["job"])?;
+            Ok(String::from_utf8_lossy(&out.stdout).contains("hunter2"))
+        })?;
+
+        let out = daemon_proc.clear(vec![String::from("job")])?;
+        assert!(out.status.success(), "clear proc did not exit successfully");
+
+        let out = daemon_proc.capture(vec!["job"])?;
+        assert!(out.status.success(), "capture proc did not exit successfully");
+        let screen = String::from_utf8_lossy(&out.stdout);
+        assert!(!screen.contains("hunter2"), "screen: {:?}", screen);
+
+        let out = daemon_proc.capture(vec!["--bytes", "4096", "job"])?;
+        assert!(out.status.success(), "capture proc did not exit successfully");
+        assert!(out.stdout.is_empty(), "raw output: {:?}", out.stdout);
+
+        let out = daemon_proc.clear(vec![String::from("nosuchsession")])?;
+        assert!(!out.status.success(), "clear of missing session exited successfully");
+
+        Ok(())
+    })
+}
@@ -0,0 +1,164 @@
+use std::{io, io::Read, thread, time};
+
+use anyhow::{anyhow, Context};
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+/// Read raw output until the given text shows up. Copy mode draws with
+/// cursor movement rather than whole lines, so the line matcher is no
+/// help here.
+fn read_until<R: Read>(out: &mut R, needle: &str) -> anyhow::Result<String> {
+    let start = time::Instant::now();
+    let mut seen = vec![];
+    let mut buf = vec![0; 4096];
+    while !String::from_utf8_lossy(&seen).contains(needle) {
+        match out.read(&mut buf) {
+            Ok(0) => return Err(anyhow!("EOF")),
+            Ok(n) => seen.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if start.elapsed() > time::Duration::from_secs(3) {
+                    return Err(anyhow!("timed out waiting for '{}'", needle));
+                }
+                thread::sleep(time::Duration::from_millis(20));
+            }
+            Err(e) => return Err(e).context("reading output"),
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&seen).to_string())
+}
+
+#[test]
+#[timeout(30000)]
+fn search_and_copy() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("copy_mode.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo first-line")?;
+        attach_proc.run_cmd("echo second-line")?;
+        attach_proc.run_cmd("echo third-line")?;
+        line_matcher.scan_until_re("third-line$")?;
+
+        attach_proc.run_raw(vec![22, 23, 5])?; // Ctrl-v Ctrl-w Ctrl-e
+        let out = read_until(&mut line_matcher.out, "[copy mode]")?;
+        assert!(out.contains("\x1b[?1049h"), "out: {:?}", out);
+
+        attach_proc.run_raw(b"?second\r".to_vec())?;
+        read_until(&mut line_matcher.out, "[copy mode] line")?;
+        attach_proc.run_raw(b"y".to_vec())?;
+        read_until(&mut line_matcher.out, "\x1b[?1049l")?;
+
+        let out = daemon_proc.paste(vec![])?;
+        assert!(out.status.success(), "paste proc did not exit successfully");
+        // noecho means the output ends up on the prompt line
+        assert_eq!(String::from_utf8_lossy(&out.stdout[..]), "prompt> second-line");
+
+        // input goes back to the shell
+        attach_proc.run_cmd("echo after")?;
+        read_until(&mut line_matcher.out, "after")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn spilled_scrollback() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let state_dir = tempfile::tempdir().context("creating state dir")?;
+        let mut daemon_proc = support::daemon::Proc::new(
+            "copy_mode_spill.toml",
+            DaemonArgs {
+                extra_env: vec![(
+                    String::from("XDG_STATE_HOME"),
+                    state_dir.path().to_string_lossy().to_string(),
+                )],
+                ..DaemonArgs::default()
+            },
+        )
+        .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("for i in $(seq 1 40); do echo spill-$i-x; done")?;
+        line_matcher.scan_until_re("spill-40-x$")?;
+
+        let spilled: Vec<_> = std::fs::read_dir(state_dir.path().join("shpool/scrollback"))
+            .context("reading scrollback dir")?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(spilled.len(), 1, "spilled: {:?}", spilled);
+
+        // the first line only made it this far by way of the ring file
+        attach_proc.run_raw(vec![22, 23, 5])?; // Ctrl-v Ctrl-w Ctrl-e
+        read_until(&mut line_matcher.out, "[copy mode]")?;
+        attach_proc.run_raw(b"?spill-1-\r".to_vec())?;
+        read_until(&mut line_matcher.out, "[copy mode] line")?;
+        attach_proc.run_raw(b"y".to_vec())?;
+        read_until(&mut line_matcher.out, "\x1b[?1049l")?;
+
+        let out = daemon_proc.paste(vec![])?;
+        assert!(out.status.success(), "paste proc did not exit successfully");
+        let pasted = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(pasted.ends_with("spill-1-x"), "pasted: {:?}", pasted);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn empty_paste() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("copy_mode.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.paste(vec![])?;
+        assert!(!out.status.success(), "paste proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("paste buffer is empty"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn named_buffers() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("copy_mode.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        let file = daemon_proc.tmp_dir.join("buffer.txt");
+        std::fs::write(&file, "echo pasted-$((1 + 1))")?;
+        let file = file.to_string_lossy().to_string();
+        let out = daemon_proc.load_buffer(vec!["--buffer", "notes", &file])?;
+        assert!(out.status.success(), "load-buffer proc did not exit successfully");
+
+        let out = daemon_proc.paste(vec!["--buffer", "notes"])?;
+        assert!(out.status.success(), "paste proc did not exit successfully");
+        assert_eq!(String::from_utf8_lossy(&out.stdout[..]), "echo pasted-$((1 + 1))");
+
+        let out = daemon_proc.paste_buffer(vec!["--session", "sh1"])?;
+        assert!(out.status.success(), "paste-buffer proc did not exit successfully");
+        attach_proc.run_cmd("")?;
+        line_matcher.scan_until_re("pasted-2$")?;
+
+        let out = daemon_proc.paste_buffer(vec!["--session", "sh1", "--buffer", "nope"])?;
+        assert!(!out.status.success(), "paste-buffer proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("no such paste buffer: nope"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
@@ -0,0 +1,116 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+/// A connection to a session's control socket.
+struct Ctl {
+    stream: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl Ctl {
+    fn connect(path: &PathBuf) -> anyhow::Result<Ctl> {
+        let stream = UnixStream::connect(path).context("connecting to ctl socket")?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Ctl { stream, reader })
+    }
+
+    fn cmd(&mut self, line: &str) -> anyhow::Result<String> {
+        writeln!(self.stream, "{}", line)?;
+        let mut reply = String::new();
+        self.reader.read_line(&mut reply)?;
+        Ok(String::from(reply.trim_end()))
+    }
+}
+
+#[test]
+#[timeout(30000)]
+fn drive_session() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        attach_proc.run_cmd("echo path=$SHPOOL_SESSION_CTL")?;
+        let captures = line_matcher.capture_re("path=(.*)$")?;
+        let ctl_path = PathBuf::from(captures[1].clone().unwrap());
+        let mut ctl = Ctl::connect(&ctl_path)?;
+
+        assert_eq!(ctl.cmd(r"send-text echo ctl$((1 + 1))\r")?, "ok");
+        line_matcher.scan_until_re("ctl2$")?;
+
+        let status = ctl.cmd("query-status")?;
+        let status: serde_json::Value =
+            serde_json::from_str(status.strip_prefix("ok ").context("status reply")?)?;
+        assert_eq!(status["name"], "sh1");
+        assert_eq!(status["attached"], true);
+
+        // the new size gets applied by the reader thread, so keep
+        // asking until it shows up
+        assert_eq!(ctl.cmd("resize 30 100")?, "ok");
+        support::wait_until(|| {
+            attach_proc.run_cmd("stty size")?;
+            let captures = line_matcher.capture_re(r"^(\d+ \d+)$")?;
+            Ok(captures[1].as_deref() == Some("30 100"))
+        })?;
+
+        assert!(ctl.cmd("frobnicate")?.starts_with("err unknown command"));
+        assert!(ctl.cmd("resize big")?.starts_with("err"));
+
+        assert_eq!(ctl.cmd("detach-clients")?, "ok");
+        attach_proc.proc.wait().context("waiting for attach proc")?;
+
+        // the socket goes away along with the session
+        daemon_proc.kill(vec![String::from("sh1")])?;
+        support::wait_until(|| Ok(!ctl_path.exists()))?;
+        assert!(ctl.cmd("query-status")?.starts_with("err session 'sh1' is gone"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn send_text_while_locked() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("lock.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        attach_proc.run_cmd("echo path=$SHPOOL_SESSION_CTL")?;
+        let captures = line_matcher.capture_re("path=(.*)$")?;
+        let ctl_path = PathBuf::from(captures[1].clone().unwrap());
+        let mut ctl = Ctl::connect(&ctl_path)?;
+
+        attach_proc.run_raw(vec![0x16, 0x0c])?; // Ctrl-v Ctrl-l
+        line_matcher.scan_until_re("session 'sh1' is locked$")?;
+        assert_eq!(ctl.cmd(r"send-text echo sneaky\r")?, "err session 'sh1' is locked");
+
+        attach_proc.run_raw(b"hunter2\r".to_vec())?;
+        support::wait_until(|| Ok(ctl.cmd(r"send-text echo ctl$((1 + 1))\r")? == "ok"))?;
+        line_matcher.scan_until_re("ctl2$")?;
+
+        Ok(())
+    })
+}
@@ -3,7 +3,7 @@ use std::{
     io::Read,
     os::unix::{
         io::{AsRawFd, FromRawFd},
-        net::UnixListener,
+        net::{UnixListener, UnixStream},
         process::CommandExt,
     },
     path,
@@ -302,3 +302,310 @@ fn echo_sentinel() -> anyhow::Result<()> {
         Ok(())
     })
 }
+
+#[test]
+#[timeout(30000)]
+fn json_logs() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("json_logs.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let log = std::fs::read_to_string(&daemon_proc.log_file)?;
+        let mut saw_session = false;
+        for line in log.lines() {
+            let line: serde_json::Value = serde_json::from_str(line)
+                .with_context(|| format!("parsing log line '{}'", line))?;
+            for field in ["timestamp", "level", "component", "message"] {
+                assert!(line.get(field).is_some(), "missing {} in {}", field, line);
+            }
+            if line["session"] == "sh1" {
+                assert!(line["conn_id"].is_u64(), "missing conn_id in {}", line);
+                saw_session = true;
+            }
+        }
+        assert!(saw_session, "no lines tagged with the session");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn hook_commands() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-hook-commands")?;
+        let hook_log = tmp_dir.path().join("hooks.log");
+        let config = std::fs::read_to_string(support::testdata_file("hook_commands.toml.tmpl"))?
+            .replace("TMP_HOOK_LOG", hook_log.to_str().unwrap());
+        let config_file = tmp_dir.path().join("hook_commands.toml");
+        std::fs::write(&config_file, config)?;
+
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let hook_lines = || -> anyhow::Result<Vec<String>> {
+            let mut lines: Vec<String> = std::fs::read_to_string(&hook_log)
+                .unwrap_or_default()
+                .lines()
+                .map(String::from)
+                .collect();
+            lines.sort();
+            Ok(lines)
+        };
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("echo hi")?;
+            line_matcher.scan_until_re("hi$")?;
+        }
+        support::wait_until(|| {
+            Ok(hook_lines()? == ["attach sh1", "detach sh1", "session_create sh1"])
+        })?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        attach_proc.run_cmd("exit 3")?;
+        attach_proc.proc.wait()?;
+        support::wait_until(|| Ok(hook_lines()?.contains(&String::from("session_exit sh1 3"))))?;
+        assert_eq!(
+            hook_lines()?,
+            ["attach sh1", "attach sh1", "detach sh1", "session_create sh1", "session_exit sh1 3"]
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn stop() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let out = daemon_proc.daemon_stop(vec![])?;
+        assert!(out.status.success(), "stop proc did not exit successfully");
+        assert!(daemon_proc.proc_wait()?.success());
+        assert!(!path::Path::new(&daemon_proc.socket_path).exists());
+
+        // the attached client gets told why it got kicked off
+        attach_proc.proc.wait()?;
+        let mut stderr = String::new();
+        attach_proc.proc.stderr.take().context("missing stderr")?.read_to_string(&mut stderr)?;
+        assert!(stderr.contains("shpool: the daemon was stopped"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn stop_keep_sessions() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec![
+            "--name",
+            "job",
+            "--",
+            "sh",
+            "-c",
+            "while read l; do echo \"got $l\"; done",
+        ])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let out = daemon_proc.daemon_stop(vec!["--keep-sessions"])?;
+        assert!(out.status.success(), "stop proc did not exit successfully");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "held: job\n");
+        assert!(daemon_proc.proc_wait()?.success());
+
+        // the next daemon picks the session back up, still running
+        daemon_proc.restart("norc.toml")?;
+        daemon_proc.wait_until_list_matches(|list| list.contains("job"))?;
+        let out = daemon_proc.send("job", vec!["--text", "hello", "--enter"])?;
+        assert!(out.status.success(), "send proc did not exit successfully");
+        support::wait_until(|| {
+            let out = daemon_proc.capture(vec!["--bytes", "1024", "job"])?;
+            Ok(String::from_utf8_lossy(&out.stdout).contains("got hello"))
+        })?;
+
+        // and the holder cleaned up after itself
+        let mut held_socket = daemon_proc.socket_path.clone().into_os_string();
+        held_socket.push(".held");
+        assert!(!path::Path::new(&held_socket).exists());
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn restart_preserve_sessions() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec![
+            "--name",
+            "job",
+            "--",
+            "sh",
+            "-c",
+            "while read l; do echo \"got $l\"; done",
+        ])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let out = daemon_proc.daemon_restart(vec!["--preserve-sessions"])?;
+        assert!(out.status.success(), "restart proc did not exit successfully");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "held: job\n");
+
+        // the daemon execed itself rather than exiting
+        let proc = daemon_proc.proc.as_mut().context("missing daemon proc")?;
+        assert!(proc.try_wait()?.is_none(), "daemon exited");
+
+        // and picked the session back up, still running
+        daemon_proc.wait_until_list_matches(|list| list.contains("job"))?;
+        let out = daemon_proc.send("job", vec!["--text", "hello", "--enter"])?;
+        assert!(out.status.success(), "send proc did not exit successfully");
+        support::wait_until(|| {
+            let out = daemon_proc.capture(vec!["--bytes", "1024", "job"])?;
+            Ok(String::from_utf8_lossy(&out.stdout).contains("got hello"))
+        })?;
+
+        // the session still ends like normal once the new daemon owns
+        // it, and Ctrl-D at the start of a line ends the read loop. The
+        // shell is still a child of the daemon, so its status is known.
+        let out = daemon_proc.send("job", vec!["--text", "\u{4}"])?;
+        assert!(out.status.success(), "send proc did not exit successfully");
+        daemon_proc.wait_until_list_matches(|list| list.contains("exited (status 0)"))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn restart_drops_sessions() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec!["--name", "job", "--", "sleep", "1000"])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let out = daemon_proc.daemon_restart(vec![])?;
+        assert!(out.status.success(), "restart proc did not exit successfully");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "");
+
+        let proc = daemon_proc.proc.as_mut().context("missing daemon proc")?;
+        assert!(proc.try_wait()?.is_none(), "daemon exited");
+        let out = daemon_proc.list()?;
+        assert!(out.status.success(), "list proc did not exit successfully");
+        assert!(!String::from_utf8_lossy(&out.stdout).contains("job"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn takeover() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec!["--name", "job", "--", "sleep", "1000"])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let daemon_cmd = |takeover: bool| -> anyhow::Result<Command> {
+            let mut cmd = Command::new(support::shpool_bin()?);
+            cmd.arg("--socket")
+                .arg(&daemon_proc.socket_path)
+                .arg("--config-file")
+                .arg(support::testdata_file("norc.toml"))
+                .arg("daemon");
+            if takeover {
+                cmd.arg("--takeover");
+            }
+            Ok(cmd)
+        };
+
+        // a daemon that is still running is left alone
+        let out = daemon_cmd(false)?.output()?;
+        assert!(!out.status.success(), "second daemon started");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("is already listening"), "stderr: {}", stderr);
+
+        // unless it gets taken over, sessions and all
+        let mut new_daemon =
+            daemon_cmd(true)?.stdout(Stdio::null()).stderr(Stdio::null()).spawn()?;
+        assert!(daemon_proc.proc_wait()?.success());
+        let res = daemon_proc.wait_until_list_matches(|list| list.contains("job"));
+        new_daemon.kill()?;
+        res
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn malformed_hello() -> anyhow::Result<()> {
+    use std::io::Write;
+
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        // the magic, a version, and then one capability that claims to
+        // be a few exabytes long
+        let mut hello = vec![];
+        hello.extend_from_slice(&0x7368_706c_u32.to_le_bytes());
+        hello.extend_from_slice(&0_u32.to_le_bytes());
+        hello.extend_from_slice(&1_u64.to_le_bytes());
+        hello.extend_from_slice(&(1_u64 << 62).to_le_bytes());
+        let mut stream =
+            UnixStream::connect(&daemon_proc.socket_path).context("connecting to daemon")?;
+        stream.write_all(&hello).context("writing hello")?;
+        let mut reply = vec![];
+        stream.read_to_end(&mut reply).context("reading daemon hello")?;
+
+        // no capabilities, and Some(HandshakeError::BadHello)
+        assert_eq!(reply.get(4..), Some(&[0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0][..]));
+
+        // the daemon is still around for everyone else
+        let out = daemon_proc.list()?;
+        assert!(out.status.success(), "list proc did not exit successfully");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn bad_session_name() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        for name in ["..", "a/b"] {
+            let out = daemon_proc.new_session(vec!["--name", name])?;
+            assert!(!out.status.success(), "new proc exited successfully");
+            let stderr = String::from_utf8_lossy(&out.stderr[..]);
+            assert!(stderr.contains("is not a valid session name"), "stderr: {}", stderr);
+        }
+
+        Ok(())
+    })
+}
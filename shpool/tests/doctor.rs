@@ -0,0 +1,66 @@
+use std::{fs, process::Command};
+
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn healthy() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.doctor(&support::testdata_file("norc.toml"))?;
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(out.status.success(), "stdout: {}", stdout);
+        assert!(stdout.contains("ok    config    parsed"), "stdout: {}", stdout);
+        assert!(stdout.contains("ok    daemon    listening on"), "stdout: {}", stdout);
+        assert!(!stdout.contains("FAIL"), "stdout: {}", stdout);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn problems() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test-doctor")
+            .rand_bytes(20)
+            .tempdir()
+            .context("creating tmp dir")?;
+        let config_file = tmp_dir.path().join("config.toml");
+        fs::write(
+            &config_file,
+            "shell = \"/does/not/exist\"\n[activity]\nsilence_threshold = \"10 min\"\n",
+        )?;
+
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg(tmp_dir.path().join("shpool.socket"))
+            .arg("--config-file")
+            .arg(&config_file)
+            .arg("doctor")
+            .env("TERM", "not-a-real-terminal")
+            .output()
+            .context("spawning doctor proc")?;
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(!out.status.success(), "stdout: {}", stdout);
+        assert!(stdout.contains("FAIL  config    shell /does/not/exist"), "stdout: {}", stdout);
+        assert!(
+            stdout.contains("FAIL  config    bad activity.silence_threshold"),
+            "stdout: {}",
+            stdout
+        );
+        assert!(stdout.contains("FAIL  daemon    no daemon socket"), "stdout: {}", stdout);
+        assert!(stdout.contains("FAIL  term      no terminfo entry"), "stdout: {}", stdout);
+        assert!(stdout.contains("fix: "), "stdout: {}", stdout);
+
+        Ok(())
+    })
+}
@@ -0,0 +1,88 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::{AttachArgs, DaemonArgs};
+
+#[test]
+#[timeout(30000)]
+fn setenv_getenv() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs {
+                    forward_env: vec![String::from("DISPLAY")],
+                    extra_env: vec![(String::from("DISPLAY"), String::from(":1"))],
+                    ..Default::default()
+                },
+            )
+            .context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd(r#"echo "display=$DISPLAY" "#)?;
+        line_matcher.scan_until_re("display=:1$")?;
+
+        let out = daemon_proc.setenv(vec!["sh1", "KRB5CCNAME=FILE:/tmp/krb5cc new"])?;
+        assert!(out.status.success(), "setenv proc did not exit successfully");
+        let out = daemon_proc.setenv(vec!["--unset", "DISPLAY", "sh1"])?;
+        assert!(out.status.success(), "setenv proc did not exit successfully");
+
+        let out = daemon_proc.getenv(vec!["sh1"])?;
+        assert!(out.status.success(), "getenv proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert_eq!(stdout, "-DISPLAY\nKRB5CCNAME=FILE:/tmp/krb5cc new\n");
+
+        let out = daemon_proc.getenv(vec!["sh1", "KRB5CCNAME"])?;
+        assert!(out.status.success(), "getenv proc did not exit successfully");
+        assert_eq!(String::from_utf8_lossy(&out.stdout[..]), "FILE:/tmp/krb5cc new\n");
+        let out = daemon_proc.getenv(vec!["sh1", "DISPLAY"])?;
+        assert!(!out.status.success(), "getenv proc exited successfully");
+
+        attach_proc.run_cmd(r#". "$SHPOOL_SESSION_DIR/forward_env""#)?;
+        attach_proc.run_cmd(r#"echo "$KRB5CCNAME:${DISPLAY-unset}" "#)?;
+        line_matcher.scan_until_re("FILE:/tmp/krb5cc new:unset$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn bad_requests() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.setenv(vec!["nope", "A=b"])?;
+        assert!(!out.status.success(), "setenv proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nope"), "stderr: {}", stderr);
+
+        let out = daemon_proc.getenv(vec!["nope"])?;
+        assert!(!out.status.success(), "getenv proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nope"), "stderr: {}", stderr);
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let out = daemon_proc.setenv(vec!["sh1", "$(touch x)=b"])?;
+        assert!(!out.status.success(), "setenv proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("invalid variable name"), "stderr: {}", stderr);
+
+        let out = daemon_proc.setenv(vec!["sh1", "NOEQUALS"])?;
+        assert!(!out.status.success(), "setenv proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("expected KEY=VALUE"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
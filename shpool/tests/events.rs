@@ -0,0 +1,60 @@
+use std::{
+    io::{BufRead, BufReader},
+    process::Stdio,
+};
+
+use anyhow::{anyhow, Context};
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn lifecycle() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut waiter = daemon_proc.events.take().unwrap().waiter(["daemon-events-subscribed"]);
+
+        let mut events_proc = daemon_proc
+            .events_cmd()?
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("spawning events proc")?;
+        let mut lines = BufReader::new(events_proc.stdout.take().unwrap()).lines();
+        waiter.wait_event("daemon-events-subscribed")?;
+
+        let out = daemon_proc.new_session(vec![
+            "--name",
+            "job",
+            "--",
+            "sh",
+            "-c",
+            "sleep 0.2; exit 3",
+        ])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let mut seen = vec![];
+        for line in &mut lines {
+            let event: serde_json::Value = serde_json::from_str(&line?)?;
+            assert_eq!(event["session"], "job");
+            assert!(event["time"].is_string(), "event: {}", event);
+            seen.push(event["event"].as_str().unwrap_or_default().to_string());
+            if event["event"] == "exited" {
+                assert_eq!(event["exit_status"], 3);
+                break;
+            }
+        }
+        if seen.last().map(String::as_str) != Some("exited") {
+            return Err(anyhow!("events ended early: {:?}", seen));
+        }
+        assert!(seen.iter().any(|e| e == "created"), "events: {:?}", seen);
+
+        events_proc.kill()?;
+        events_proc.wait()?;
+
+        Ok(())
+    })
+}
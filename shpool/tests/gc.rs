@@ -0,0 +1,75 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context};
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn empty() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        let out = daemon_proc.gc(false)?;
+        assert!(out.status.success(), "gc proc did not exit successfully");
+
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("0 bytes freed"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn killed_session() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut waiter = daemon_proc
+            .events
+            .take()
+            .unwrap()
+            .waiter(["daemon-bidi-stream-enter", "daemon-bidi-stream-enter"]);
+
+        let _sess1 = daemon_proc.attach("sh1", Default::default())?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+        let _sess2 = daemon_proc.attach("sh2", Default::default())?;
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-enter")?);
+
+        let out = daemon_proc.kill(vec![String::from("sh1")])?;
+        assert!(out.status.success(), "kill proc did not exit successfully");
+
+        let out = daemon_proc.gc(true)?;
+        assert!(out.status.success(), "gc proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("sh1\t"));
+        assert!(!stdout.contains("sh2\t"));
+        assert!(stdout.contains("bytes reclaimable"));
+
+        let sh1_dir = stdout
+            .lines()
+            .find(|l| l.starts_with("sh1\t"))
+            .and_then(|l| l.split('\t').nth(2))
+            .map(PathBuf::from)
+            .ok_or(anyhow!("no path for sh1 in gc output"))?;
+        assert!(sh1_dir.exists(), "dry run removed {:?}", sh1_dir);
+
+        let out = daemon_proc.gc(false)?;
+        assert!(out.status.success(), "gc proc did not exit successfully");
+        assert!(!sh1_dir.exists(), "gc did not remove {:?}", sh1_dir);
+
+        let out = daemon_proc.gc(true)?;
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(!stdout.contains("sh1\t"));
+
+        Ok(())
+    })
+}
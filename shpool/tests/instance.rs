@@ -0,0 +1,144 @@
+use std::{
+    path::Path,
+    process::{self, Command, Stdio},
+};
+
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+/// A daemon for a named instance, killed when it goes out of scope.
+struct Instance {
+    proc: process::Child,
+}
+
+impl Instance {
+    fn spawn(runtime_dir: &Path, name: &str) -> anyhow::Result<Instance> {
+        let proc = shpool(runtime_dir)?
+            .arg("--log-file")
+            .arg(runtime_dir.join(format!("daemon_{}.log", name)))
+            .arg("--config-file")
+            .arg(support::testdata_file("norc.toml"))
+            .arg("--instance")
+            .arg(name)
+            .arg("daemon")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .context("spawning daemon proc")?;
+        let socket = runtime_dir.join("shpool/instances").join(name).join("shpool.socket");
+        support::wait_until(|| Ok(socket.exists()))?;
+        Ok(Instance { proc })
+    }
+}
+
+impl std::ops::Drop for Instance {
+    fn drop(&mut self) {
+        if let Err(e) = self.proc.kill() {
+            eprintln!("err killing daemon proc: {:?}", e);
+        }
+        let _ = self.proc.wait();
+    }
+}
+
+/// A shpool command that looks for daemons in the given runtime dir, and
+/// doesn't care whether the tests are running inside a shpool session.
+fn shpool(runtime_dir: &Path) -> anyhow::Result<Command> {
+    let mut cmd = Command::new(support::shpool_bin()?);
+    cmd.env("XDG_RUNTIME_DIR", runtime_dir)
+        .env_remove("SHPOOL_SOCKET")
+        .env_remove("SHPOOL_INSTANCE");
+    Ok(cmd)
+}
+
+#[test]
+#[timeout(30000)]
+fn instances_are_isolated() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test-instance")
+            .tempdir()
+            .context("creating tmp dir")?;
+        let _a = Instance::spawn(tmp_dir.path(), "a")?;
+        let _b = Instance::spawn(tmp_dir.path(), "b")?;
+
+        let out = shpool(tmp_dir.path())?
+            .args(["new", "--instance", "a", "--name", "job", "--", "sleep", "1000"])
+            .output()
+            .context("spawning new proc")?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let list = |instance: &str| -> anyhow::Result<String> {
+            let out = shpool(tmp_dir.path())?
+                .env("SHPOOL_INSTANCE", instance)
+                .arg("list")
+                .output()
+                .context("spawning list proc")?;
+            assert!(out.status.success(), "list proc did not exit successfully");
+            Ok(String::from_utf8_lossy(&out.stdout).to_string())
+        };
+        assert!(list("a")?.contains("job"));
+        assert!(!list("b")?.contains("job"));
+
+        // bad names don't escape the instances dir
+        let out = shpool(tmp_dir.path())?
+            .args(["list", "--instance", "../a"])
+            .output()
+            .context("spawning list proc")?;
+        assert!(!out.status.success(), "list proc exited successfully");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn socket_from_anywhere() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        // after the subcommand
+        let out = Command::new(support::shpool_bin()?)
+            .arg("list")
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .output()
+            .context("spawning list proc")?;
+        assert!(out.status.success(), "list proc did not exit successfully");
+
+        // and from the environment
+        let out = Command::new(support::shpool_bin()?)
+            .env("SHPOOL_SOCKET", &daemon_proc.socket_path)
+            .arg("list")
+            .output()
+            .context("spawning list proc")?;
+        assert!(out.status.success(), "list proc did not exit successfully");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn session_knows_its_daemon() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo \"sock=$SHPOOL_SOCKET\"")?;
+        let socket = regex::escape(&daemon_proc.socket_path.to_string_lossy());
+        line_matcher.scan_until_re(&format!("sock={}$", socket))?;
+
+        Ok(())
+    })
+}
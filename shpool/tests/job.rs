@@ -0,0 +1,121 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn new_and_switch() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut waiter = daemon_proc.events.take().unwrap().waiter([
+            "daemon-bidi-stream-enter",
+            "daemon-bidi-stream-enter",
+            "daemon-bidi-stream-enter",
+        ]);
+
+        let mut attach_proc =
+            daemon_proc.attach("main", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let out = daemon_proc.job(vec!["--session", "main", "new", "build"])?;
+        assert!(out.status.success(), "job new proc did not exit successfully");
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+
+        attach_proc.run_cmd("echo in $SHPOOL_SESSION_NAME")?;
+        line_matcher.scan_until_re("in main:build$")?;
+
+        // the switch lets go of the session's own shell in the
+        // background, so it can still show as attached for a moment
+        support::wait_until(|| {
+            let out = daemon_proc.job(vec!["--session", "main:build", "list"])?;
+            assert!(out.status.success(), "job list proc did not exit successfully");
+            let stdout = String::from_utf8_lossy(&out.stdout[..]);
+            Ok(stdout.contains("main\tdisconnected\nbuild\tattached"))
+        })?;
+
+        let out = daemon_proc.job(vec!["--session", "main", "switch", "main"])?;
+        assert!(out.status.success(), "job switch proc did not exit successfully");
+        waiter.wait_final_event("daemon-bidi-stream-enter")?;
+
+        attach_proc.run_cmd("echo in $SHPOOL_SESSION_NAME")?;
+        line_matcher.scan_until_re("in main$")?;
+
+        let out = daemon_proc.job(vec!["--session", "main", "switch", "nope"])?;
+        assert!(!out.status.success(), "job switch proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("session 'main' has no job 'nope'"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn next_job_keybinding() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("next_job_keybinding.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut waiter = daemon_proc.events.take().unwrap().waiter([
+            "daemon-bidi-stream-enter",
+            "daemon-bidi-stream-enter",
+            "daemon-bidi-stream-enter",
+        ]);
+
+        let mut attach_proc =
+            daemon_proc.attach("main", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let out = daemon_proc.job(vec!["--session", "main", "new", "--background", "build"])?;
+        assert!(out.status.success(), "job new proc did not exit successfully");
+
+        attach_proc.run_raw(vec![0x16, 0x0e])?; // Ctrl-v Ctrl-n
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+        attach_proc.run_cmd("echo in $SHPOOL_SESSION_NAME")?;
+        line_matcher.scan_until_re("in main:build$")?;
+
+        // wraps back around to the session's own shell
+        attach_proc.run_raw(vec![0x16, 0x0e])?;
+        waiter.wait_final_event("daemon-bidi-stream-enter")?;
+        attach_proc.run_cmd("echo in $SHPOOL_SESSION_NAME")?;
+        line_matcher.scan_until_re("in main$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn kill_takes_jobs() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec!["--name", "main"])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+        let out = daemon_proc.job(vec!["--session", "main", "new", "build"])?;
+        assert!(out.status.success(), "job new proc did not exit successfully");
+        daemon_proc.wait_until_list_matches(|out| out.contains("main:build"))?;
+
+        let out = daemon_proc.kill(vec![String::from("main")])?;
+        assert!(out.status.success(), "kill proc did not exit successfully");
+        daemon_proc.wait_until_list_matches(|out| !out.contains("main"))?;
+
+        let out = daemon_proc.job(vec!["--session", "other", "new", "build"])?;
+        assert!(!out.status.success(), "job new proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("no session named 'other'"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
@@ -0,0 +1,101 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn list_default() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let bidi_enter_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-enter"]);
+
+        let _sess1 = daemon_proc.attach("sh1", Default::default())?;
+
+        daemon_proc.events = Some(bidi_enter_w.wait_final_event("daemon-bidi-stream-enter")?);
+
+        let out = daemon_proc.keybind(vec!["list"])?;
+        assert!(out.status.success(), "keybind list proc did not exit successfully");
+
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("SESSION"));
+        assert!(stdout.contains("sh1\tCtrl-Space Ctrl-q\tdetach"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn add_while_attached() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut waiter = daemon_proc
+            .events
+            .take()
+            .unwrap()
+            .waiter(["daemon-bidi-stream-enter", "daemon-bidi-stream-done"]);
+
+        let mut a1 =
+            daemon_proc.attach("sess", Default::default()).context("starting attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+
+        let out = daemon_proc.keybind(vec!["add", "Ctrl-v Ctrl-w Ctrl-g", "detach"])?;
+        assert!(out.status.success(), "keybind add proc did not exit successfully");
+
+        let out = daemon_proc.keybind(vec!["list", "--session", "sess"])?;
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("sess\tCtrl-v Ctrl-w Ctrl-g\tdetach"));
+
+        a1.run_raw_cmd(vec![22, 23, 7])?; // Ctrl-v Ctrl-w Ctrl-g
+        a1.proc.wait()?;
+
+        waiter.wait_final_event("daemon-bidi-stream-done")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn remove_missing_session() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        let out = daemon_proc.keybind(vec!["remove", "--session", "nosuchsession", "Ctrl-a"])?;
+        assert!(!out.status.success(), "keybind remove proc exited successfully");
+
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nosuchsession"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn bad_action() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        let out = daemon_proc.keybind(vec!["add", "Ctrl-a", "explode"])?;
+        assert!(!out.status.success(), "keybind add proc exited successfully");
+
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("unknown action 'explode'"));
+
+        Ok(())
+    })
+}
@@ -26,6 +26,7 @@ fn empty() -> anyhow::Result<()> {
         let stdout = String::from_utf8_lossy(&out.stdout[..]);
         assert!(stdout.contains("NAME"));
         assert!(stdout.contains("STARTED_AT"));
+        assert!(stdout.contains("ALERTS"));
 
         Ok(())
     })
@@ -124,3 +125,153 @@ fn two_sessions() -> anyhow::Result<()> {
         Ok(())
     })
 }
+
+#[test]
+#[timeout(30000)]
+fn alerts() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-alerts")?;
+        let hook_log = tmp_dir.path().join("hooks.log");
+        let config = std::fs::read_to_string(support::testdata_file("alerts.toml.tmpl"))?
+            .replace("TMP_HOOK_LOG", hook_log.to_str().unwrap());
+        let config_file = tmp_dir.path().join("alerts.toml");
+        std::fs::write(&config_file, config)?;
+
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let list_stdout = |daemon_proc: &mut support::daemon::Proc| -> anyhow::Result<String> {
+            let out = daemon_proc.list()?;
+            assert!(out.status.success(), "list proc did not exit successfully");
+            Ok(String::from(String::from_utf8_lossy(&out.stdout[..])))
+        };
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            // give the client time to go away before making noise
+            attach_proc
+                .run_cmd(r"(sleep 1; printf '\a\a'; printf '\e]9;build done\a'; echo noisy) &")?;
+            attach_proc.run_cmd("echo started")?;
+            line_matcher.scan_until_re("started$")?;
+        }
+
+        let alerts_re = Regex::new("sh1.*disconnected\t[^\t]*\t2 bells, 1 notification")?;
+        support::wait_until(|| Ok(alerts_re.is_match(&list_stdout(&mut daemon_proc)?)))?;
+        support::wait_until(|| {
+            let mut lines: Vec<String> = std::fs::read_to_string(&hook_log)
+                .unwrap_or_default()
+                .lines()
+                .map(String::from)
+                .collect();
+            lines.sort();
+            Ok(lines == ["bell sh1 ", "notification sh1 build done"])
+        })?;
+
+        // reattaching clears the alerts
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo back")?;
+        line_matcher.scan_until_re("back$")?;
+        let stdout = list_stdout(&mut daemon_proc)?;
+        assert!(Regex::new("sh1.*attached\tactive\t\t")?.is_match(&stdout), "stdout: {}", stdout);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn activity() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-activity")?;
+        let hook_log = tmp_dir.path().join("hooks.log");
+        let config = std::fs::read_to_string(support::testdata_file("activity.toml.tmpl"))?
+            .replace("TMP_HOOK_LOG", hook_log.to_str().unwrap());
+        let config_file = tmp_dir.path().join("activity.toml");
+        std::fs::write(&config_file, config)?;
+
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+        // wait_until backs off too far for the timings here to work out
+        let wait_for_hooks = |n: usize| -> anyhow::Result<Vec<String>> {
+            loop {
+                let lines: Vec<String> = std::fs::read_to_string(&hook_log)
+                    .unwrap_or_default()
+                    .lines()
+                    .map(String::from)
+                    .collect();
+                if lines.len() >= n {
+                    return Ok(lines);
+                }
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+        };
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            // a build that finishes shortly after we detach, and a cron
+            // job that complains a while later
+            attach_proc.run_cmd("(sleep 1; echo building; sleep 5; echo cron) &")?;
+            attach_proc.run_cmd("echo started")?;
+            line_matcher.scan_until_re("started$")?;
+
+            let out = daemon_proc.list()?;
+            let stdout = String::from_utf8_lossy(&out.stdout[..]);
+            assert!(Regex::new("sh1.*attached\tactive")?.is_match(&stdout), "stdout: {}", stdout);
+        }
+
+        assert_eq!(wait_for_hooks(1)?, ["silence sh1"]);
+        let out = daemon_proc.list()?;
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(
+            Regex::new("sh1.*disconnected\tsilent for [0-9]s")?.is_match(&stdout),
+            "stdout: {}",
+            stdout
+        );
+
+        // the cron job's output wakes the session back up, after which
+        // it goes quiet again
+        assert_eq!(wait_for_hooks(2)?[..2], ["silence sh1", "activity sh1"]);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn names_only() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut bidi_enter_w = daemon_proc
+            .events
+            .take()
+            .unwrap()
+            .waiter(["daemon-bidi-stream-enter", "daemon-bidi-stream-enter"]);
+
+        let _sess1 = daemon_proc.attach("sh1", Default::default())?;
+        bidi_enter_w.wait_event("daemon-bidi-stream-enter")?;
+        let _sess2 = daemon_proc.attach("sh2", Default::default())?;
+        bidi_enter_w.wait_event("daemon-bidi-stream-enter")?;
+
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("list")
+            .arg("--names-only")
+            .output()
+            .context("spawning list proc")?;
+        assert!(out.status.success(), "list proc did not exit successfully");
+
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        let mut names: Vec<&str> = stdout.lines().collect();
+        names.sort();
+        assert_eq!(names, ["sh1", "sh2"]);
+
+        Ok(())
+    })
+}
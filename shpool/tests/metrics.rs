@@ -0,0 +1,89 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use anyhow::Context;
+use ntest::timeout;
+use regex::Regex;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+/// Pull the value of an unlabeled metric out of the text format.
+fn metric(text: &str, name: &str) -> anyhow::Result<u64> {
+    let re = Regex::new(&format!("(?m)^{} ([0-9]+)$", name))?;
+    let caps = re.captures(text).with_context(|| format!("no {} in:\n{}", name, text))?;
+    Ok(caps[1].parse()?)
+}
+
+#[test]
+#[timeout(30000)]
+fn dump() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.metrics()?;
+        assert!(out.status.success(), "metrics proc did not exit successfully");
+        let text = String::from_utf8_lossy(&out.stdout).to_string();
+        assert_eq!(metric(&text, "shpool_sessions")?, 0);
+        assert!(text.contains("# TYPE shpool_attach_latency_seconds histogram"));
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let out = daemon_proc.metrics()?;
+        assert!(out.status.success(), "metrics proc did not exit successfully");
+        let text = String::from_utf8_lossy(&out.stdout).to_string();
+        assert_eq!(metric(&text, "shpool_sessions")?, 1);
+        assert_eq!(metric(&text, "shpool_attached_clients")?, 1);
+        assert_eq!(metric(&text, "shpool_sessions_created_total")?, 1);
+        assert_eq!(metric(&text, "shpool_attach_latency_seconds_count")?, 1);
+        assert!(metric(&text, "shpool_output_bytes_total")? > 0);
+        assert!(metric(&text, "shpool_input_bytes_total")? > 0);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn http_listener() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test-metrics")
+            .rand_bytes(20)
+            .tempdir()
+            .context("creating tmp dir")?;
+        // grab a free port for the daemon
+        let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string();
+        let config = fs::read_to_string(support::testdata_file("metrics_listener.toml.tmpl"))?
+            .replace("TMP_METRICS_ADDR", &addr);
+        let config_file = tmp_dir.path().join("metrics_listener.toml");
+        fs::write(&config_file, config)?;
+
+        let _daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut stream = TcpStream::connect(&addr).context("connecting to metrics listener")?;
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp)?;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "resp: {}", resp);
+        assert_eq!(metric(&resp, "shpool_sessions")?, 0);
+
+        let mut stream = TcpStream::connect(&addr).context("connecting to metrics listener")?;
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n")?;
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp)?;
+        assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"), "resp: {}", resp);
+
+        Ok(())
+    })
+}
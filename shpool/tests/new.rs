@@ -0,0 +1,190 @@
+use std::process::Command;
+
+use anyhow::Context;
+use ntest::timeout;
+use regex::Regex;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn new_then_attach() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec!["--name", "sh1"])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let out = daemon_proc.list()?;
+        assert!(out.status.success(), "list proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(Regex::new("sh1.*disconnected")?.is_match(&stdout), "stdout: {}", stdout);
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn runs_cmd() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-new")?;
+        let marker = tmp_dir.path().join("marker");
+
+        let script = format!("touch {}; sleep 1000", marker.display());
+        let out = daemon_proc.new_session(vec!["--name", "job", "--", "sh", "-c", &script])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        support::wait_until(|| Ok(marker.exists()))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn already_exists() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec!["--name", "sh1"])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let out = daemon_proc.new_session(vec!["--name", "sh1"])?;
+        assert!(!out.status.success(), "new proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("session 'sh1' already exists"), "stderr: {}", stderr);
+
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("attach")
+            .arg("--create-only")
+            .arg("sh1")
+            .output()
+            .context("spawning attach proc")?;
+        assert!(!out.status.success(), "attach proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("session 'sh1' already exists"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn cwd() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-new")?;
+        let start_dir = tmp_dir.path().canonicalize()?.join("start");
+        let other_dir = tmp_dir.path().canonicalize()?.join("other");
+        std::fs::create_dir(&start_dir)?;
+        std::fs::create_dir(&other_dir)?;
+        let start_dir = start_dir.to_string_lossy().into_owned();
+        let other_dir = other_dir.to_string_lossy().into_owned();
+
+        let out = daemon_proc.new_session(vec!["--name", "sh1", "--cwd", &start_dir])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+        daemon_proc.wait_until_list_matches(|out| out.contains(&start_dir))?;
+
+        // list follows the shell around
+        let out =
+            daemon_proc.send("sh1", vec!["--text", &format!("cd {}", other_dir), "--enter"])?;
+        assert!(out.status.success(), "send proc did not exit successfully");
+        daemon_proc.wait_until_list_matches(|out| out.contains(&other_dir))?;
+
+        let out = daemon_proc.new_session(vec!["--name", "sh2", "--cwd", "/does/not/exist"])?;
+        assert!(!out.status.success(), "new proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("resolving --cwd /does/not/exist"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn term() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        // norc.toml blanks out TERM, which --term still beats
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let script = "echo \"term=$TERM.\"; sleep 1000";
+        let out = daemon_proc.new_session(vec![
+            "--name",
+            "job",
+            "--term",
+            "xterm-256color",
+            "--",
+            "sh",
+            "-c",
+            script,
+        ])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+        support::wait_until(|| {
+            let out = daemon_proc.capture(vec!["--bytes", "1024", "job"])?;
+            Ok(String::from_utf8_lossy(&out.stdout).contains("term=xterm-256color."))
+        })?;
+
+        let out = daemon_proc.new_session(vec!["--name", "plain", "--", "sh", "-c", script])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+        support::wait_until(|| {
+            let out = daemon_proc.capture(vec!["--bytes", "1024", "plain"])?;
+            Ok(String::from_utf8_lossy(&out.stdout).contains("term=."))
+        })?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn nsenter() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-new")?;
+        let start_dir = tmp_dir.path().canonicalize()?.to_string_lossy().into_owned();
+
+        // the daemon is already in all of its own namespaces, so entering
+        // them is a no-op, but the cwd still gets changed into after
+        let daemon_pid = daemon_proc.proc.as_ref().map(|p| p.id()).unwrap_or(0).to_string();
+        let out = daemon_proc.new_session(vec![
+            "--name",
+            "sh1",
+            "--nsenter-pid",
+            &daemon_pid,
+            "--cwd",
+            &start_dir,
+        ])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+        daemon_proc.wait_until_list_matches(|out| out.contains(&start_dir))?;
+
+        let out = daemon_proc.new_session(vec!["--name", "sh2", "--nsenter-pid", "0"])?;
+        assert!(!out.status.success(), "new proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("could not enter namespaces"), "stderr: {}", stderr);
+
+        let out = daemon_proc.new_session(vec!["--name", "sh3", "--container", "lxc:foo"])?;
+        assert!(!out.status.success(), "new proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("should look like docker:<id>"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
@@ -0,0 +1,54 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+fn fake_pty_args() -> DaemonArgs {
+    DaemonArgs {
+        extra_env: vec![(String::from("SHPOOL_TEST_PTY_BACKEND"), String::from("fake"))],
+        ..Default::default()
+    }
+}
+
+#[test]
+#[timeout(30000)]
+fn fake_pty_session() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", fake_pty_args())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo hi$((1 + 1))")?;
+        line_matcher.scan_until_re("hi2$")?;
+        // the shell doesn't have a terminal at all
+        attach_proc.run_cmd("[ -t 0 ] || echo no-tty")?;
+        line_matcher.scan_until_re("no-tty$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn fake_pty_exit_status() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", fake_pty_args())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo ready")?;
+        line_matcher.scan_until_re("ready$")?;
+        attach_proc.run_cmd("exit 3")?;
+
+        let exit_status = attach_proc.proc.wait().context("waiting for attach proc")?;
+        assert_eq!(exit_status.code(), Some(3));
+
+        Ok(())
+    })
+}
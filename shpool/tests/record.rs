@@ -0,0 +1,114 @@
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+/// Parse an asciicast file into its header and events.
+fn read_cast(path: &Path) -> anyhow::Result<(serde_json::Value, Vec<serde_json::Value>)> {
+    let contents = fs::read_to_string(path).context("reading recording")?;
+    let mut lines = contents.lines().map(serde_json::from_str::<serde_json::Value>);
+    let header = lines.next().context("empty recording")??;
+    let events = lines.collect::<Result<Vec<_>, _>>()?;
+    Ok((header, events))
+}
+
+/// All the output in a recording, glued back together.
+fn cast_output(events: &[serde_json::Value]) -> String {
+    events.iter().filter(|e| e[1] == "o").filter_map(|e| e[2].as_str()).collect()
+}
+
+#[test]
+#[timeout(30000)]
+fn start_stop() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo before$((1 + 1))")?;
+        line_matcher.scan_until_re("before2$")?;
+
+        let cast_path = daemon_proc.tmp_dir.join("sh1.cast");
+        let cast_arg = cast_path.to_string_lossy().into_owned();
+        let out = daemon_proc.record(vec!["sh1", "start", "--file", &cast_arg])?;
+        assert!(out.status.success(), "record start did not exit successfully");
+
+        // only one recording at a time
+        let out = daemon_proc.record(vec!["sh1", "start"])?;
+        assert!(!out.status.success(), "second record start exited successfully");
+
+        attach_proc.run_cmd("echo during$((1 + 1))")?;
+        line_matcher.scan_until_re("during2$")?;
+        support::wait_until(|| Ok(cast_output(&read_cast(&cast_path)?.1).contains("during2")))?;
+
+        let out = daemon_proc.record(vec!["sh1", "stop"])?;
+        assert!(out.status.success(), "record stop did not exit successfully");
+        let out = daemon_proc.record(vec!["sh1", "stop"])?;
+        assert!(!out.status.success(), "second record stop exited successfully");
+
+        attach_proc.run_cmd("echo after$((1 + 1))")?;
+        line_matcher.scan_until_re("after2$")?;
+
+        let (header, events) = read_cast(&cast_path)?;
+        assert_eq!(header["version"], 2);
+        assert_eq!(header["title"], "sh1");
+        let output = cast_output(&events);
+        assert!(!output.contains("before2"), "output: {:?}", output);
+        assert!(!output.contains("after2"), "output: {:?}", output);
+
+        let out = daemon_proc.record(vec!["nosuchsession", "start"])?;
+        assert!(!out.status.success(), "record of missing session exited successfully");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn auto() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-config")?;
+        let recording_dir = tmp_dir.path().join("recordings");
+        let config_contents = fs::read_to_string(support::testdata_file("recording.toml.tmpl"))?
+            .replace("TMP_RECORDING_DIR", recording_dir.to_str().unwrap());
+        let config_file = tmp_dir.path().join("recording.toml");
+        fs::write(&config_file, config_contents)?;
+
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        // the recording gets made without anyone attaching
+        let out = daemon_proc.new_session(vec![
+            "--name",
+            "job",
+            "--",
+            "sh",
+            "-c",
+            "echo hi; sleep 1000",
+        ])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        support::wait_until(|| {
+            let cast_path = match fs::read_dir(&recording_dir) {
+                Ok(mut entries) => match entries.next() {
+                    Some(entry) => entry?.path(),
+                    None => return Ok(false),
+                },
+                Err(_) => return Ok(false),
+            };
+            let name = cast_path.file_name().unwrap().to_string_lossy().into_owned();
+            assert!(name.starts_with("job-") && name.ends_with(".cast"), "name: {}", name);
+            Ok(cast_output(&read_cast(&cast_path)?.1).contains("hi"))
+        })?;
+
+        let out = daemon_proc.record(vec!["job", "stop"])?;
+        assert!(out.status.success(), "record stop did not exit successfully");
+
+        Ok(())
+    })
+}
@@ -0,0 +1,98 @@
+use std::{io, io::Read, thread, time};
+
+use anyhow::{anyhow, Context};
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+/// Read raw output until the given text shows up. The soft reset
+/// lands in the middle of a line, so the line matcher would lose it.
+fn read_until<R: Read>(out: &mut R, needle: &str) -> anyhow::Result<String> {
+    let start = time::Instant::now();
+    let mut seen = vec![];
+    let mut buf = vec![0; 4096];
+    while !String::from_utf8_lossy(&seen).contains(needle) {
+        match out.read(&mut buf) {
+            Ok(0) => return Err(anyhow!("EOF")),
+            Ok(n) => seen.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if start.elapsed() > time::Duration::from_secs(3) {
+                    return Err(anyhow!("timed out waiting for '{}'", needle));
+                }
+                thread::sleep(time::Duration::from_millis(20));
+            }
+            Err(e) => return Err(e).context("reading output"),
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&seen).to_string())
+}
+
+#[test]
+#[timeout(30000)]
+fn garbled_session() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        // switch to the alternate screen and leave an escape sequence
+        // hanging, like cat'ing a binary might
+        attach_proc.run_cmd("printf '\\033[?1049h\\033[?25l\\033]0;garb'; echo")?;
+        attach_proc.run_cmd("echo before")?;
+        line_matcher.scan_until_re("before$")?;
+
+        let out = daemon_proc.reset(vec![String::from("sh1")])?;
+        assert!(out.status.success(), "reset proc did not exit successfully");
+
+        // the client gets the soft reset, and the shell keeps going
+        attach_proc.run_cmd("echo after")?;
+        let out = read_until(&mut line_matcher.out, "after")?;
+        assert!(out.contains("\x1b[!p\x1b[?1049l"), "out: {:?}", out);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn keybinding() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("reset_keybinding.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo before")?;
+        line_matcher.scan_until_re("before$")?;
+
+        attach_proc.run_raw(vec![22, 23, 18])?; // Ctrl-v Ctrl-w Ctrl-r
+        attach_proc.run_cmd("echo after")?;
+        let out = read_until(&mut line_matcher.out, "after")?;
+        assert!(out.contains("\x1b[!p\x1b[?1049l"), "out: {:?}", out);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn not_found() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.reset(vec![String::from("nope")])?;
+        assert!(!out.status.success(), "reset proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nope"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
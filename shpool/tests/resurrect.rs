@@ -0,0 +1,110 @@
+use std::{fs, path::Path, process::Command};
+
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+/// The names of the sessions saved in the given definitions file.
+fn saved_names(path: &Path) -> anyhow::Result<Vec<String>> {
+    let defs: serde_json::Value = serde_json::from_slice(&fs::read(path)?)?;
+    Ok(defs
+        .as_array()
+        .context("definitions are not a list")?
+        .iter()
+        .filter_map(|d| d["name"].as_str().map(String::from))
+        .collect())
+}
+
+#[test]
+#[timeout(30000)]
+fn save_and_resurrect() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-config")?;
+        let defs_file = tmp_dir.path().join("state").join("resurrect.json");
+        let config_contents = fs::read_to_string(support::testdata_file("resurrect.toml.tmpl"))?
+            .replace("TMP_RESURRECT_FILE", defs_file.to_str().unwrap());
+        let config_file = tmp_dir.path().join("resurrect.toml");
+        fs::write(&config_file, config_contents)?;
+        let proj_dir = tmp_dir.path().join("proj");
+        fs::create_dir(&proj_dir)?;
+        let marker = proj_dir.join("marker");
+
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        // writes the forwarded variable to a file in its cwd, so we can
+        // tell that both came back
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("new")
+            .arg("--name")
+            .arg("proj")
+            .arg("--cwd")
+            .arg(&proj_dir)
+            .arg("--forward-env")
+            .arg("RESURRECT_TEST_VAR")
+            .arg("--")
+            .args(["sh", "-c", "echo \"$RESURRECT_TEST_VAR\" > marker; exec sleep 1000"])
+            .env("RESURRECT_TEST_VAR", "hello")
+            .output()
+            .context("spawning new proc")?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+        support::wait_until(|| Ok(fs::read_to_string(&marker).unwrap_or_default() == "hello\n"))?;
+
+        let out = daemon_proc.new_session(vec!["--name", "killed", "--", "sleep", "1000"])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+        let out = daemon_proc.new_session(vec!["--name", "exited", "--", "true"])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+        let out = daemon_proc.new_session(vec!["--name", "temp", "--ttl", "1h"])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+        daemon_proc.kill(vec![String::from("killed")])?;
+
+        // only the session that is meant to stick around is left
+        support::wait_until(|| Ok(saved_names(&defs_file)? == vec![String::from("proj")]))?;
+        let defs: serde_json::Value = serde_json::from_slice(&fs::read(&defs_file)?)?;
+        assert_eq!(defs[0]["cwd"], proj_dir.to_str().unwrap());
+        assert_eq!(defs[0]["env"], serde_json::json!([["RESURRECT_TEST_VAR", "hello"]]));
+
+        // going down with the daemon keeps the definitions around
+        daemon_proc.proc_kill()?;
+        daemon_proc.proc_wait()?;
+        fs::remove_file(&marker)?;
+        assert_eq!(saved_names(&defs_file)?, vec![String::from("proj")]);
+
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting second daemon proc")?;
+        let out = daemon_proc.resurrect()?;
+        assert!(out.status.success(), "resurrect proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert_eq!(stdout, "proj\tcreated\n");
+        support::wait_until(|| Ok(fs::read_to_string(&marker).unwrap_or_default() == "hello\n"))?;
+
+        let out = daemon_proc.list()?;
+        assert!(String::from_utf8_lossy(&out.stdout[..]).contains("proj"));
+
+        let out = daemon_proc.resurrect()?;
+        assert!(out.status.success(), "resurrect proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert_eq!(stdout, "proj\talready running\n");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn not_enabled() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let out = daemon_proc.resurrect()?;
+        assert!(!out.status.success(), "resurrect proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not saving sessions"), "stderr: {}", stderr);
+        Ok(())
+    })
+}
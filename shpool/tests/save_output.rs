@@ -0,0 +1,85 @@
+use std::fs;
+
+use anyhow::{anyhow, Context};
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn raw_and_stripped() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("printf '\\033[31mred\\033[0m line\\n'")?;
+        // more than a screenful, so that some of it is in the scrollback
+        attach_proc.run_cmd("for i in $(seq 1 60); do echo out-$i; done; echo done")?;
+        line_matcher.scan_until_re("done$")?;
+
+        let plain_file = daemon_proc.tmp_dir.join("plain.txt");
+        let plain_path = plain_file.to_str().ok_or(anyhow!("non-utf8 tmp dir"))?;
+        let out = daemon_proc.save_output(vec!["--strip-ansi", "sh1", plain_path])?;
+        assert!(out.status.success(), "save-output proc did not exit successfully");
+        let plain = fs::read_to_string(&plain_file)?;
+        assert!(plain.contains("red line\n"), "plain: {:?}", plain);
+        assert!(plain.contains("out-1\nout-2\n"), "plain: {:?}", plain);
+        assert!(plain.contains("\nout-60\n"), "plain: {:?}", plain);
+        assert!(!plain.contains('\x1b'), "plain: {:?}", plain);
+
+        let raw_file = daemon_proc.tmp_dir.join("raw.txt");
+        let raw_path = raw_file.to_str().ok_or(anyhow!("non-utf8 tmp dir"))?;
+        let out = daemon_proc.save_output(vec!["sh1", raw_path])?;
+        assert!(out.status.success(), "save-output proc did not exit successfully");
+        let raw = String::from_utf8(fs::read(&raw_file)?)?;
+        assert!(raw.contains("\x1b[31mred"), "raw: {:?}", raw);
+        assert!(raw.contains("out-1\r\n"), "raw: {:?}", raw);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn not_found() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        let out = daemon_proc.save_output(vec!["nope", "/dev/null"])?;
+        assert!(!out.status.success(), "save-output proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nope"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn simple_restore_mode() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let out = daemon_proc.save_output(vec!["sh1", "/dev/null"])?;
+        assert!(!out.status.success(), "save-output proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("no output kept for sh1"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
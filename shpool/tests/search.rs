@@ -0,0 +1,68 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+const JOB: &str =
+    "printf 'start\\nok\\nERROR: disk full\\nok\\nok\\nok\\nerror: retrying\\n'; sleep 1000";
+
+#[test]
+#[timeout(30000)]
+fn matches() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec!["--name", "job", "--", "sh", "-c", JOB])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let mut found = String::new();
+        support::wait_until(|| {
+            let out = daemon_proc.search(vec!["job", "ERROR"])?;
+            found = String::from_utf8(out.stdout)?;
+            Ok(out.status.success())
+        })?;
+        assert_eq!(found, "ERROR: disk full\n");
+
+        let out = daemon_proc.search(vec!["-i", "-n", "-C", "1", "job", "^error"])?;
+        assert!(out.status.success(), "search proc did not exit successfully");
+        assert_eq!(
+            String::from_utf8(out.stdout)?,
+            "2-ok\n3:ERROR: disk full\n4-ok\n--\n6-ok\n7:error: retrying\n"
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn no_match() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec!["--name", "job", "--", "sh", "-c", JOB])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let out = daemon_proc.search(vec!["job", "panic"])?;
+        assert_eq!(out.status.code(), Some(1));
+        assert!(out.stdout.is_empty());
+
+        let out = daemon_proc.search(vec!["job", "("])?;
+        assert!(!out.status.success(), "search proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("bad pattern"), "stderr: {}", stderr);
+
+        let out = daemon_proc.search(vec!["nope", "ERROR"])?;
+        assert!(!out.status.success(), "search proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nope"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
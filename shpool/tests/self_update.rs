@@ -0,0 +1,158 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use anyhow::{anyhow, Context};
+use ntest::timeout;
+use ring::{digest, signature};
+use tempfile::TempDir;
+
+mod support;
+
+/// The seed of the key test releases get signed with, the other half of
+/// the public_key in self_update.toml.tmpl.
+const SIGNING_KEY_SEED: &str = "38fc65a882b62942ea27c38d077f01491c12fa7ad7803dc2da9e708a353daaa0";
+
+/// Set up a copy of the shpool binary for self-update to clobber, along
+/// with a config and release manifest offering the given version of the
+/// given binary. Returns the copied binary and the config file.
+fn release(tmp_dir: &Path, version: &str, binary: &Path) -> anyhow::Result<(PathBuf, PathBuf)> {
+    release_signed_as(tmp_dir, version, binary, version, binary)
+}
+
+/// Like release, but with the signature and checksum in the manifest
+/// for the given signed version and binary, which need not be the ones
+/// the manifest offers.
+fn release_signed_as(
+    tmp_dir: &Path,
+    version: &str,
+    binary: &Path,
+    signed_version: &str,
+    signed_binary: &Path,
+) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let exe = tmp_dir.join("shpool");
+    fs::copy(support::shpool_bin()?, &exe).context("copying shpool binary")?;
+
+    let target = format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS);
+    let sha256 = hex(digest::digest(&digest::SHA256, &fs::read(signed_binary)?).as_ref());
+    let statement = format!("shpool-release {} {} {}\n", signed_version, target, sha256);
+    let seed = (0..SIGNING_KEY_SEED.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&SIGNING_KEY_SEED[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = signature::Ed25519KeyPair::from_seed_unchecked(&seed)
+        .map_err(|e| anyhow!("loading signing key: {}", e))?;
+
+    let manifest = fs::read_to_string(support::testdata_file("self_update/manifest.json.tmpl"))?
+        .replace("TMP_VERSION", version)
+        .replace("TMP_TARGET", &target)
+        .replace("TMP_BINARY_URL", &format!("file://{}", binary.display()))
+        .replace("TMP_SHA256", &sha256)
+        .replace("TMP_SIGNATURE", &hex(key.sign(statement.as_bytes()).as_ref()));
+    let manifest_file = tmp_dir.join("manifest.json");
+    fs::write(&manifest_file, manifest)?;
+
+    let config = fs::read_to_string(support::testdata_file("self_update.toml.tmpl"))?
+        .replace("TMP_MANIFEST_URL", &format!("file://{}", manifest_file.display()));
+    let config_file = tmp_dir.join("self_update.toml");
+    fs::write(&config_file, config)?;
+
+    Ok((exe, config_file))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn self_update(exe: &Path, config_file: &Path, args: &[&str]) -> anyhow::Result<Output> {
+    Command::new(exe)
+        .arg("--config-file")
+        .arg(config_file)
+        .arg("--socket")
+        .arg(exe.with_file_name("no-daemon.socket"))
+        .arg("self-update")
+        .args(args)
+        .output()
+        .context("running self-update")
+}
+
+#[test]
+#[timeout(30000)]
+fn installs_signed_release() -> anyhow::Result<()> {
+    let tmp_dir = TempDir::with_prefix("shpool-test-self-update")?;
+    let (exe, config_file) =
+        release(tmp_dir.path(), "99.0.0", &support::testdata_file("self_update/shpool-new"))?;
+
+    let out = self_update(&exe, &config_file, &["--check"])?;
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("shpool 99.0.0 is available"));
+
+    let out = self_update(&exe, &config_file, &[])?;
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(out.status.success(), "stderr: {}", String::from_utf8_lossy(&out.stderr));
+    assert!(stdout.contains("to 99.0.0"), "stdout: {}", stdout);
+
+    let out = Command::new(&exe).arg("hi").output()?;
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "fake shpool hi\n");
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn rejects_bad_signature() -> anyhow::Result<()> {
+    let tmp_dir = TempDir::with_prefix("shpool-test-self-update")?;
+    // the signed release is for shpool-new, not this
+    let imposter = tmp_dir.path().join("imposter");
+    fs::write(&imposter, "#!/bin/sh\necho pwned\n")?;
+    let new = support::testdata_file("self_update/shpool-new");
+    let (exe, config_file) =
+        release_signed_as(tmp_dir.path(), "99.0.0", &imposter, "99.0.0", &new)?;
+    let before = fs::read(&exe)?;
+
+    let out = self_update(&exe, &config_file, &[])?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("doesn't match the signed release"), "stderr: {}", stderr);
+    assert!(fs::read(&exe)? == before, "binary was replaced");
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn rejects_tampered_version() -> anyhow::Result<()> {
+    let tmp_dir = TempDir::with_prefix("shpool-test-self-update")?;
+    // an old release, signed as such, passed off as a new one
+    let old = support::testdata_file("self_update/shpool-new");
+    let (exe, config_file) = release_signed_as(tmp_dir.path(), "99.0.0", &old, "0.0.1", &old)?;
+    let before = fs::read(&exe)?;
+
+    for args in [&["--check"][..], &[]] {
+        let out = self_update(&exe, &config_file, args)?;
+        assert!(!out.status.success());
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(!stdout.contains("99.0.0"), "stdout: {}", stdout);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("bad signature"), "stderr: {}", stderr);
+    }
+    assert!(fs::read(&exe)? == before, "binary was replaced");
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn up_to_date() -> anyhow::Result<()> {
+    let tmp_dir = TempDir::with_prefix("shpool-test-self-update")?;
+    let (exe, config_file) =
+        release(tmp_dir.path(), "0.0.1", &support::testdata_file("self_update/shpool-new"))?;
+
+    let out = self_update(&exe, &config_file, &[])?;
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("is up to date"));
+
+    Ok(())
+}
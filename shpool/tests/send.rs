@@ -0,0 +1,65 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn detached() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-send")?;
+        let marker = tmp_dir.path().join("marker");
+
+        let out = daemon_proc.new_session(vec!["--name", "sh1"])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let cmd = format!("touch {}", marker.display());
+        let out = daemon_proc.send("sh1", vec!["--text", &cmd, "--enter"])?;
+        assert!(out.status.success(), "send proc did not exit successfully");
+
+        support::wait_until(|| Ok(marker.exists()))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn attached() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo ready")?;
+        line_matcher.scan_until_re("ready$")?;
+
+        let out = daemon_proc.send("sh1", vec!["--text", "echo sent", "--enter"])?;
+        assert!(out.status.success(), "send proc did not exit successfully");
+        line_matcher.scan_until_re("sent$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn not_found() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.send("nope", vec!["--text", "x"])?;
+        assert!(!out.status.success(), "send proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nope"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
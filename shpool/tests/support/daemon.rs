@@ -12,14 +12,14 @@ use std::{
 use anyhow::{anyhow, Context};
 use tempfile::TempDir;
 
-use super::{attach, events::Events, shpool_bin, testdata_file, wait_until};
+use super::{attach, events::Events, shpool_bin, terminal, testdata_file, wait_until};
 
 /// Proc is a helper handle for a `shpool daemon` subprocess.
 /// It kills the subprocess when it goes out of scope.
 pub struct Proc {
     pub proc: Option<process::Child>,
     subproc_counter: usize,
-    log_file: PathBuf,
+    pub log_file: PathBuf,
     local_tmp_dir: Option<TempDir>,
     pub tmp_dir: PathBuf,
     pub events: Option<Events>,
@@ -39,6 +39,22 @@ impl std::default::Default for DaemonArgs {
     }
 }
 
+/// Tunnel is a handle for a `shpool tunnel` subprocess.
+/// It kills the subprocess when it goes out of scope.
+pub struct Tunnel {
+    pub proc: process::Child,
+    pub socket_path: PathBuf,
+}
+
+impl std::ops::Drop for Tunnel {
+    fn drop(&mut self) {
+        if let Err(e) = self.proc.kill() {
+            eprintln!("err killing tunnel proc: {:?}", e);
+        }
+        let _ = self.proc.wait();
+    }
+}
+
 #[derive(Default)]
 pub struct AttachArgs {
     pub config: Option<String>,
@@ -46,6 +62,14 @@ pub struct AttachArgs {
     pub extra_env: Vec<(String, String)>,
     pub ttl: Option<time::Duration>,
     pub cmd: Option<String>,
+    pub argv: Vec<String>,
+    pub forward_env: Vec<String>,
+    pub no_replay: bool,
+    pub replay_lines: Option<u16>,
+    pub on_attach_cmd: Option<String>,
+    pub restart: bool,
+    pub time: bool,
+    pub quiet: bool,
 }
 
 pub struct HooksRecorder {
@@ -199,13 +223,18 @@ impl Proc {
                     .into_string()
                     .map_err(|e| anyhow!("conversion error: {:?}", e))?,
             ),
+            instance: None,
             config_file: Some(
                 testdata_file(config)
                     .into_os_string()
                     .into_string()
                     .map_err(|e| anyhow!("conversion error: {:?}", e))?,
             ),
-            command: libshpool::Commands::Daemon,
+            command: libshpool::Commands::Daemon {
+                takeover: false,
+                multi_user: false,
+                command: None,
+            },
         };
         let hooks_recorder = Box::new(HooksRecorder {
             records: Arc::new(Mutex::new(HookRecords {
@@ -248,8 +277,38 @@ impl Proc {
         })
     }
 
+    /// Start a fresh daemon on the same socket, for use once the first
+    /// one has been stopped.
+    pub fn restart<P: AsRef<Path>>(&mut self, config: P) -> anyhow::Result<()> {
+        let log_file = self.tmp_dir.join(format!("daemon_{}.log", self.subproc_counter));
+        eprintln!("respawning daemon proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        let proc = Command::new(shpool_bin()?)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("--config-file")
+            .arg(testdata_file(config))
+            .arg("daemon")
+            .spawn()
+            .context("spawning daemon process")?;
+        self.proc = Some(proc);
+        self.log_file = log_file;
+
+        wait_until(|| Ok(UnixStream::connect(&self.socket_path).is_ok()))
+    }
+
     pub fn proc_kill(&mut self) -> std::io::Result<()> {
-        if let Some(proc) = &mut self.proc { proc.kill() } else { Ok(()) }
+        if let Some(proc) = &mut self.proc {
+            proc.kill()
+        } else {
+            Ok(())
+        }
     }
 
     pub fn proc_wait(&mut self) -> std::io::Result<std::process::ExitStatus> {
@@ -261,6 +320,43 @@ impl Proc {
     }
 
     pub fn attach(&mut self, name: &str, args: AttachArgs) -> anyhow::Result<attach::Proc> {
+        let (mut cmd, log_file, test_hook_socket_path) = self.attach_cmd(name, args)?;
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).stdin(Stdio::piped());
+        let proc = cmd.spawn().context(format!("spawning attach proc for {}", name))?;
+
+        let events = Events::new(&test_hook_socket_path)?;
+
+        Ok(attach::Proc { proc, log_file, events: Some(events) })
+    }
+
+    /// Like attach, but runs `shpool attach` in a terminal of the given
+    /// size, the way a user would, rather than with pipes for stdio.
+    pub fn attach_term(
+        &mut self,
+        name: &str,
+        rows: u16,
+        cols: u16,
+        args: AttachArgs,
+    ) -> anyhow::Result<terminal::Term> {
+        let set_term = !args.extra_env.iter().any(|(var, _)| var == "TERM");
+        let (mut cmd, log_file, test_hook_socket_path) = self.attach_cmd(name, args)?;
+        if set_term {
+            cmd.env("TERM", "xterm");
+        }
+        let mut term = terminal::Term::spawn(cmd, rows, cols, log_file)
+            .context(format!("spawning attach proc for {} in a terminal", name))?;
+        term.events = Some(Events::new(&test_hook_socket_path)?);
+
+        Ok(term)
+    }
+
+    /// Build up a `shpool attach` command, returning it along with the
+    /// paths of its log file and test hook socket.
+    fn attach_cmd(
+        &mut self,
+        name: &str,
+        args: AttachArgs,
+    ) -> anyhow::Result<(Command, PathBuf, PathBuf)> {
         let log_file = self.tmp_dir.join(format!("attach_{}_{}.log", name, self.subproc_counter));
         let test_hook_socket_path =
             self.tmp_dir.join(format!("attach_test_hook_{}_{}.socket", name, self.subproc_counter));
@@ -268,7 +364,6 @@ impl Proc {
         self.subproc_counter += 1;
 
         let mut cmd = Command::new(shpool_bin()?);
-        cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).stdin(Stdio::piped());
         if let Some(config_file) = args.config {
             cmd.arg("--config-file").arg(testdata_file(config_file));
         }
@@ -293,11 +388,33 @@ impl Proc {
             cmd.arg("-c");
             cmd.arg(cmd_str);
         }
-        let proc = cmd.arg(name).spawn().context(format!("spawning attach proc for {}", name))?;
-
-        let events = Events::new(&test_hook_socket_path)?;
+        for var in args.forward_env.iter() {
+            cmd.arg("--forward-env").arg(var);
+        }
+        if args.no_replay {
+            cmd.arg("--no-replay");
+        }
+        if let Some(n) = args.replay_lines {
+            cmd.arg("--replay-lines").arg(n.to_string());
+        }
+        if let Some(on_attach_cmd) = &args.on_attach_cmd {
+            cmd.arg("--on-attach-cmd").arg(on_attach_cmd);
+        }
+        if args.restart {
+            cmd.arg("--restart");
+        }
+        if args.time {
+            cmd.arg("--time");
+        }
+        if args.quiet {
+            cmd.arg("--quiet");
+        }
+        cmd.arg(name);
+        if !args.argv.is_empty() {
+            cmd.arg("--").args(&args.argv);
+        }
 
-        Ok(attach::Proc { proc, log_file, events: Some(events) })
+        Ok((cmd, log_file, test_hook_socket_path))
     }
 
     pub fn detach(&mut self, sessions: Vec<String>) -> anyhow::Result<process::Output> {
@@ -319,6 +436,40 @@ impl Proc {
         cmd.output().context("spawning detach proc")
     }
 
+    pub fn reset(&mut self, sessions: Vec<String>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("reset_{}.log", self.subproc_counter));
+        eprintln!("spawning reset proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("reset")
+            .args(sessions)
+            .output()
+            .context("spawning reset proc")
+    }
+
+    pub fn clear(&mut self, sessions: Vec<String>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("clear_{}.log", self.subproc_counter));
+        eprintln!("spawning clear proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("clear")
+            .args(sessions)
+            .output()
+            .context("spawning clear proc")
+    }
+
     pub fn kill(&mut self, sessions: Vec<String>) -> anyhow::Result<process::Output> {
         let log_file = self.tmp_dir.join(format!("kill_{}.log", self.subproc_counter));
         eprintln!("spawning kill proc with log {:?}", &log_file);
@@ -338,6 +489,446 @@ impl Proc {
         cmd.output().context("spawning kill proc")
     }
 
+    pub fn gc(&mut self, dry_run: bool) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("gc_{}.log", self.subproc_counter));
+        eprintln!("spawning gc proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        let mut cmd = Command::new(shpool_bin()?);
+        cmd.arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("gc");
+        if dry_run {
+            cmd.arg("--dry-run");
+        }
+
+        cmd.output().context("spawning gc proc")
+    }
+
+    pub fn doctor(&mut self, config: &Path) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("doctor_{}.log", self.subproc_counter));
+        eprintln!("spawning doctor proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("--config-file")
+            .arg(config)
+            .arg("doctor")
+            .env("TERM", "xterm")
+            .output()
+            .context("spawning doctor proc")
+    }
+
+    pub fn metrics(&mut self) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("metrics_{}.log", self.subproc_counter));
+        eprintln!("spawning metrics proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("metrics")
+            .output()
+            .context("spawning metrics proc")
+    }
+
+    pub fn version(&mut self) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("version_{}.log", self.subproc_counter));
+        eprintln!("spawning version proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("version")
+            .output()
+            .context("spawning version proc")
+    }
+
+    pub fn paste(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("paste_{}.log", self.subproc_counter));
+        eprintln!("spawning paste proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("paste")
+            .args(args)
+            .output()
+            .context("spawning paste proc")
+    }
+
+    pub fn load_buffer(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("load_buffer_{}.log", self.subproc_counter));
+        eprintln!("spawning load-buffer proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("load-buffer")
+            .args(args)
+            .output()
+            .context("spawning load-buffer proc")
+    }
+
+    pub fn paste_buffer(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("paste_buffer_{}.log", self.subproc_counter));
+        eprintln!("spawning paste-buffer proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("paste-buffer")
+            .args(args)
+            .output()
+            .context("spawning paste-buffer proc")
+    }
+
+    pub fn save_output(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("save_output_{}.log", self.subproc_counter));
+        eprintln!("spawning save-output proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("save-output")
+            .args(args)
+            .output()
+            .context("spawning save-output proc")
+    }
+
+    pub fn capture(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("capture_{}.log", self.subproc_counter));
+        eprintln!("spawning capture proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("capture")
+            .args(args)
+            .output()
+            .context("spawning capture proc")
+    }
+
+    pub fn search(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("search_{}.log", self.subproc_counter));
+        eprintln!("spawning search proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("search")
+            .args(args)
+            .output()
+            .context("spawning search proc")
+    }
+
+    pub fn record(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("record_{}.log", self.subproc_counter));
+        eprintln!("spawning record proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("record")
+            .args(args)
+            .output()
+            .context("spawning record proc")
+    }
+
+    pub fn resurrect(&mut self) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("resurrect_{}.log", self.subproc_counter));
+        eprintln!("spawning resurrect proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("resurrect")
+            .output()
+            .context("spawning resurrect proc")
+    }
+
+    pub fn acl(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("acl_{}.log", self.subproc_counter));
+        eprintln!("spawning acl proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("acl")
+            .args(args)
+            .output()
+            .context("spawning acl proc")
+    }
+
+    pub fn setenv(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("setenv_{}.log", self.subproc_counter));
+        eprintln!("spawning setenv proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("setenv")
+            .args(args)
+            .output()
+            .context("spawning setenv proc")
+    }
+
+    pub fn getenv(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("getenv_{}.log", self.subproc_counter));
+        eprintln!("spawning getenv proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("getenv")
+            .args(args)
+            .output()
+            .context("spawning getenv proc")
+    }
+
+    pub fn new_session(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("new_{}.log", self.subproc_counter));
+        eprintln!("spawning new proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("new")
+            .args(args)
+            .output()
+            .context("spawning new proc")
+    }
+
+    pub fn daemon_stop(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("stop_{}.log", self.subproc_counter));
+        eprintln!("spawning stop proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("daemon")
+            .arg("stop")
+            .args(args)
+            .output()
+            .context("spawning stop proc")
+    }
+
+    pub fn daemon_restart(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("restart_{}.log", self.subproc_counter));
+        eprintln!("spawning restart proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("daemon")
+            .arg("restart")
+            .args(args)
+            .output()
+            .context("spawning restart proc")
+    }
+
+    pub fn send(&mut self, session: &str, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("send_{}.log", self.subproc_counter));
+        eprintln!("spawning send proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("send")
+            .arg(session)
+            .args(args)
+            .output()
+            .context("spawning send proc")
+    }
+
+    /// wait_cmd builds a `shpool wait` command without running it,
+    /// so that tests can have several waiters going at once.
+    pub fn wait_cmd(&mut self, session: &str) -> anyhow::Result<Command> {
+        let log_file = self.tmp_dir.join(format!("wait_{}.log", self.subproc_counter));
+        eprintln!("spawning wait proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        let mut cmd = Command::new(shpool_bin()?);
+        cmd.arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("wait")
+            .arg(session);
+        Ok(cmd)
+    }
+
+    /// events_cmd builds a `shpool events` command without running it.
+    pub fn events_cmd(&mut self) -> anyhow::Result<Command> {
+        let log_file = self.tmp_dir.join(format!("events_{}.log", self.subproc_counter));
+        eprintln!("spawning events proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        let mut cmd = Command::new(shpool_bin()?);
+        cmd.arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("events");
+        Ok(cmd)
+    }
+
+    pub fn keybind(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("keybind_{}.log", self.subproc_counter));
+        eprintln!("spawning keybind proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("keybind")
+            .args(args)
+            .output()
+            .context("spawning keybind proc")
+    }
+
+    pub fn job(&mut self, args: Vec<&str>) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("job_{}.log", self.subproc_counter));
+        eprintln!("spawning job proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("job")
+            .args(args)
+            .output()
+            .context("spawning job proc")
+    }
+
+    pub fn audit_dump(&mut self, config: &Path, file: &Path) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("audit_dump_{}.log", self.subproc_counter));
+        eprintln!("spawning audit-dump proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--config-file")
+            .arg(config)
+            .arg("audit-dump")
+            .arg(file)
+            .output()
+            .context("spawning audit-dump proc")
+    }
+
+    /// Spawn a `shpool tunnel` to the given address, serving a fresh
+    /// socket in the tmp dir. The args get passed through to the
+    /// tunnel command (i.e. --cert).
+    pub fn tunnel(&mut self, addr: &str, args: Vec<String>) -> anyhow::Result<Tunnel> {
+        let log_file = self.tmp_dir.join(format!("tunnel_{}.log", self.subproc_counter));
+        let socket_path = self.tmp_dir.join(format!("tunnel_{}.socket", self.subproc_counter));
+        eprintln!("spawning tunnel proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        let proc = Command::new(shpool_bin()?)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&socket_path)
+            .arg("tunnel")
+            .args(args)
+            .arg(addr)
+            .spawn()
+            .context("spawning tunnel proc")?;
+        let tunnel = Tunnel { proc, socket_path };
+
+        wait_until(|| Ok(tunnel.socket_path.exists()))?;
+        Ok(tunnel)
+    }
+
     pub fn wait_until_list_matches<F>(&mut self, pred: F) -> anyhow::Result<()>
     where
         F: Fn(&str) -> bool,
@@ -0,0 +1,159 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    os::unix::{
+        io::{AsRawFd, FromRawFd},
+        process::CommandExt,
+    },
+    path::PathBuf,
+    process,
+    process::Command,
+    sync::{Arc, Mutex},
+    thread, time,
+};
+
+use anyhow::{anyhow, Context};
+use nix::{libc, pty};
+
+use super::events::Events;
+
+// How long to wait on the screen before giving up.
+const AWAIT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+const AWAIT_POLL: time::Duration = time::Duration::from_millis(10);
+
+nix::ioctl_write_int_bad!(tiocsctty, libc::TIOCSCTTY);
+nix::ioctl_write_ptr_bad!(tiocswinsz, libc::TIOCSWINSZ, libc::winsize);
+
+/// Term is a scripted stand in for the terminal a user would run
+/// `shpool attach` in. The subprocess gets a pty as its controlling
+/// terminal, and everything it writes gets fed through a terminal
+/// emulator so that tests can assert about what ends up on the screen,
+/// rather than about raw output lines.
+///
+/// It kills the subprocess when it goes out of scope.
+pub struct Term {
+    pub proc: process::Child,
+    pub log_file: PathBuf,
+    pub events: Option<Events>,
+    master: fs::File,
+    parser: Arc<Mutex<shpool_vt100::Parser>>,
+}
+
+impl Term {
+    /// Spawn the given command with a fresh pty of the given size as its
+    /// controlling terminal.
+    pub fn spawn(
+        mut cmd: Command,
+        rows: u16,
+        cols: u16,
+        log_file: PathBuf,
+    ) -> anyhow::Result<Term> {
+        let size = libc::winsize { ws_row: rows, ws_col: cols, ws_xpixel: 0, ws_ypixel: 0 };
+        let pty = pty::openpty(&size, None).context("opening pty")?;
+        // Safety: openpty just handed us these fds, and nothing else owns them
+        let (master, slave) =
+            unsafe { (fs::File::from_raw_fd(pty.master), fs::File::from_raw_fd(pty.slave)) };
+
+        cmd.stdin(slave.try_clone().context("cloning pty slave")?)
+            .stdout(slave.try_clone().context("cloning pty slave")?)
+            .stderr(slave);
+        // Safety: setsid and ioctl are both async signal safe
+        unsafe {
+            cmd.pre_exec(|| {
+                // start a new session so that the pty can become its
+                // controlling terminal, which is what routes SIGWINCH
+                // to the subprocess when we resize it
+                nix::unistd::setsid()?;
+                tiocsctty(0, 0)?;
+                Ok(())
+            });
+        }
+        let proc = cmd.spawn().context("spawning proc under pty")?;
+        // close our copies of the slave, so that reads on the master
+        // fail once the subprocess is gone
+        drop(cmd);
+
+        let parser = Arc::new(Mutex::new(shpool_vt100::Parser::new(rows, cols, 0)));
+        let mut reader = master.try_clone().context("cloning pty master")?;
+        let reader_parser = Arc::clone(&parser);
+        thread::spawn(move || {
+            let mut buf = vec![0; 1024 * 16];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(len) => reader_parser.lock().unwrap().process(&buf[..len]),
+                }
+            }
+        });
+
+        Ok(Term { proc, log_file, events: None, master, parser })
+    }
+
+    /// Type the given bytes in, as if the user had.
+    pub fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.master.write_all(bytes).context("writing to pty master")?;
+        self.master.flush().context("flushing pty master")?;
+        Ok(())
+    }
+
+    /// Type the given command and hit enter.
+    pub fn run_cmd(&mut self, cmd: &str) -> anyhow::Result<()> {
+        eprintln!("running cmd '{}'", cmd);
+        self.write(format!("{}\r", cmd).as_bytes())
+    }
+
+    /// Resize the terminal, which sends the subprocess a SIGWINCH.
+    pub fn resize(&mut self, rows: u16, cols: u16) -> anyhow::Result<()> {
+        self.parser.lock().unwrap().screen_mut().set_size(rows, cols);
+        let size = libc::winsize { ws_row: rows, ws_col: cols, ws_xpixel: 0, ws_ypixel: 0 };
+        // Safety: size is a valid winsize for the duration of the call
+        unsafe { tiocswinsz(self.master.as_raw_fd(), &size) }.context("resizing pty")?;
+        Ok(())
+    }
+
+    /// The text currently on the screen, one line per row.
+    pub fn contents(&self) -> String {
+        self.parser.lock().unwrap().screen().contents()
+    }
+
+    /// Wait for the given text to show up somewhere on the screen.
+    pub fn await_text(&self, text: &str) -> anyhow::Result<()> {
+        self.await_screen(AWAIT_TIMEOUT, |screen| screen.contents().contains(text))
+            .with_context(|| format!("waiting for '{}' to show up", text))
+    }
+
+    /// Wait for the given predicate on the screen to become true,
+    /// failing with a dump of the screen if it does not in time.
+    pub fn await_screen<P>(&self, timeout: time::Duration, mut pred: P) -> anyhow::Result<()>
+    where
+        P: FnMut(&shpool_vt100::Screen) -> bool,
+    {
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            if pred(self.parser.lock().unwrap().screen()) {
+                return Ok(());
+            }
+            if time::Instant::now() > deadline {
+                return Err(anyhow!("timed out, screen:\n{}", self.contents()));
+            }
+            thread::sleep(AWAIT_POLL);
+        }
+    }
+
+    pub fn await_event(&mut self, event: &str) -> anyhow::Result<()> {
+        if let Some(events) = &mut self.events {
+            events.await_event(event)
+        } else {
+            Err(anyhow!("no events stream"))
+        }
+    }
+}
+
+impl std::ops::Drop for Term {
+    fn drop(&mut self) {
+        if let Err(e) = self.proc.kill() {
+            eprintln!("err killing term proc: {:?}", e);
+        }
+        let _ = self.proc.wait();
+    }
+}
@@ -0,0 +1,235 @@
+use std::{fs, time};
+
+use anyhow::Context;
+use nix::{
+    sys::signal::{self, Signal},
+    unistd::Pid,
+};
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+// The default detach keybinding, Ctrl-Space Ctrl-q.
+const DETACH: &[u8] = b"\x00\x11";
+
+#[test]
+#[timeout(30000)]
+fn detach_reattach() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+        let mut term = daemon_proc.attach_term("sh1", 24, 80, Default::default())?;
+        term.await_text("prompt> ")?;
+        term.run_cmd("echo hi$((1 + 1))")?;
+        term.await_text("hi2")?;
+
+        term.write(DETACH)?;
+        term.proc.wait().context("waiting for attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-done")?;
+
+        let mut term = daemon_proc.attach_term("sh1", 24, 80, Default::default())?;
+        // the screen from before comes back without any new output
+        term.await_text("hi2")?;
+        term.run_cmd("echo back$((1 + 1))")?;
+        term.await_text("back2")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn replay_full_screen() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+        let mut term = daemon_proc.attach_term("sh1", 10, 80, Default::default())?;
+        term.await_text("prompt> ")?;
+        term.run_cmd("seq 100 130")?;
+        term.await_screen(time::Duration::from_secs(10), |screen| {
+            screen.contents().trim_end().ends_with("130\nprompt>")
+        })?;
+        let before = term.contents();
+
+        term.write(DETACH)?;
+        term.proc.wait().context("waiting for attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-done")?;
+
+        // the new terminal starts out blank, so everything on it got
+        // replayed by the daemon
+        let term = daemon_proc.attach_term("sh1", 10, 80, Default::default())?;
+        term.await_screen(time::Duration::from_secs(10), |screen| screen.contents() == before)
+            .context("waiting for the screen to come back")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn replay_notice() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("replay_notice.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+        let mut term = daemon_proc.attach_term("sh1", 10, 80, Default::default())?;
+        term.await_text("prompt> ")?;
+        term.run_cmd("seq 100 130")?;
+        term.await_screen(time::Duration::from_secs(10), |screen| {
+            screen.contents().trim_end().ends_with("130\nprompt>")
+        })?;
+        let before = term.contents();
+
+        term.write(DETACH)?;
+        term.proc.wait().context("waiting for attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-done")?;
+
+        // every replay is big enough for a notice with this config, and
+        // the notice must be gone once the screen is back
+        let term = daemon_proc.attach_term("sh1", 10, 80, Default::default())?;
+        term.await_screen(time::Duration::from_secs(10), |screen| screen.contents() == before)
+            .context("waiting for the screen to come back")?;
+        let log = fs::read_to_string(&term.log_file).context("reading attach log")?;
+        assert!(log.contains("daemon replaying"), "no replay notice in log");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn resize_propagates() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut term = daemon_proc.attach_term("sh1", 24, 80, Default::default())?;
+        term.await_text("prompt> ")?;
+        term.run_cmd("stty size")?;
+        term.await_text("24 80")?;
+
+        // The new size takes a trip through the daemon before it gets to
+        // the shell's pty, so keep asking until it shows up.
+        term.resize(30, 100)?;
+        support::wait_until(|| {
+            term.run_cmd("stty size")?;
+            Ok(term
+                .await_screen(time::Duration::from_millis(100), |screen| {
+                    screen.contents().contains("30 100")
+                })
+                .is_ok())
+        })?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn reattach_new_size() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_screen.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+        let mut term = daemon_proc.attach_term("sh1", 24, 80, Default::default())?;
+        term.await_text("prompt> ")?;
+        term.write(DETACH)?;
+        term.proc.wait().context("waiting for attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-done")?;
+
+        // the daemon nudges the size on reattach to force a redraw, so
+        // keep asking until the real one has settled in
+        let mut term = daemon_proc.attach_term("sh1", 40, 120, Default::default())?;
+        term.await_text("prompt> ")?;
+        support::wait_until(|| {
+            term.run_cmd("stty size")?;
+            Ok(term
+                .await_screen(time::Duration::from_millis(100), |screen| {
+                    screen.contents().contains("40 120")
+                })
+                .is_ok())
+        })?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn status_line() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("status_line.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut term = daemon_proc.attach_term("sh1", 24, 80, Default::default())?;
+        term.await_screen(time::Duration::from_secs(10), |screen| {
+            screen.rows(0, 80).last().is_some_and(|row| row.starts_with(" sh1 | attach #1"))
+        })
+        .context("waiting for the status line")?;
+
+        // the shell gets the rows above the status line
+        term.await_text("prompt> ")?;
+        term.run_cmd("stty size")?;
+        term.await_text("23 80")?;
+
+        // Ctrl-Space s takes it down again
+        term.write(b"\x00s")?;
+        support::wait_until(|| {
+            term.run_cmd("stty size")?;
+            Ok(term
+                .await_screen(time::Duration::from_millis(100), |screen| {
+                    screen.contents().contains("24 80")
+                })
+                .is_ok())
+        })?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn suspend_resume() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut term = daemon_proc.attach_term("sh1", 24, 80, Default::default())?;
+        term.await_text("prompt> ")?;
+        term.run_cmd("echo hi$((1 + 1))")?;
+        term.await_text("hi2")?;
+
+        let pid = Pid::from_raw(term.proc.id() as i32);
+        signal::kill(pid, Signal::SIGTSTP)?;
+        support::wait_until(|| {
+            let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+            Ok(stat.rsplit(')').next().is_some_and(|s| s.trim_start().starts_with('T')))
+        })?;
+
+        // the terminal is back in cooked mode, so it echoes typing on
+        // its own, and ^U throws the typing away again
+        term.write(b"zzz")?;
+        term.await_text("zzz")?;
+        term.write(b"\x15")?;
+
+        signal::kill(pid, Signal::SIGCONT)?;
+        term.await_screen(time::Duration::from_secs(10), |screen| {
+            let contents = screen.contents();
+            contents.contains("hi2") && !contents.contains("zzz")
+        })?;
+        term.run_cmd("echo back$((1 + 1))")?;
+        term.await_text("back2")?;
+
+        // the terminal is raw again, or the ^Q would have gone to flow
+        // control rather than the detach keybinding
+        term.write(DETACH)?;
+        term.proc.wait().context("waiting for attach proc")?;
+
+        Ok(())
+    })
+}
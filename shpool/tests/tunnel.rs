@@ -0,0 +1,107 @@
+use std::{fs, net::TcpListener, path::PathBuf};
+
+use anyhow::Context;
+use ntest::timeout;
+use tempfile::TempDir;
+
+mod support;
+
+use crate::support::daemon::{DaemonArgs, Proc};
+
+/// Start a daemon with a tcp listener on a free local port. Returns
+/// the daemon, the address it listens on, and the dir holding its
+/// config, which needs to stay alive as long as the daemon.
+fn listening_daemon() -> anyhow::Result<(Proc, String, TempDir)> {
+    // There is a small window for something else to grab the port
+    // before the daemon binds it, but it is unlikely to matter.
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string();
+
+    let tmp_dir = TempDir::with_prefix("shpool-test-config")?;
+    let tls_dir = support::testdata_file("tls");
+    let config_contents = fs::read_to_string(support::testdata_file("tcp_listener.toml.tmpl"))?
+        .replace("TMP_LISTEN_ADDR", &addr)
+        .replace("TMP_TLS_DIR", tls_dir.to_str().unwrap());
+    let config_file = tmp_dir.path().join("tcp_listener.toml");
+    fs::write(&config_file, config_contents)?;
+
+    let daemon_proc =
+        Proc::new(&config_file, DaemonArgs::default()).context("starting daemon proc")?;
+    Ok((daemon_proc, addr, tmp_dir))
+}
+
+fn tunnel_args(client: &str, extra: &[&str]) -> Vec<String> {
+    let tls_dir = support::testdata_file("tls");
+    let file = |name: String| String::from(tls_dir.join(name).to_str().unwrap());
+    let mut args = vec![
+        String::from("--cert"),
+        file(format!("{}.pem", client)),
+        String::from("--key"),
+        file(format!("{}.key", client)),
+        String::from("--ca"),
+        file(String::from("ca.pem")),
+    ];
+    args.extend(extra.iter().map(|a| String::from(*a)));
+    args
+}
+
+#[test]
+#[timeout(30000)]
+fn attach_through_tunnel() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let (mut daemon_proc, addr, _config_dir) = listening_daemon()?;
+        let tunnel = daemon_proc.tunnel(&addr, tunnel_args("client", &[]))?;
+
+        // point all the client commands at the tunnel rather than the daemon
+        daemon_proc.socket_path = PathBuf::from(&tunnel.socket_path);
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let out = daemon_proc.list()?;
+        assert!(out.status.success(), "list proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("sh1"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn untrusted_client() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let (mut daemon_proc, addr, _config_dir) = listening_daemon()?;
+        let tunnel = daemon_proc.tunnel(&addr, tunnel_args("rogue_client", &[]))?;
+        daemon_proc.socket_path = PathBuf::from(&tunnel.socket_path);
+
+        let out = daemon_proc.list()?;
+        assert!(!out.status.success(), "list through an untrusted tunnel succeeded");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn pinned_server_cert() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let (mut daemon_proc, addr, _config_dir) = listening_daemon()?;
+
+        let tunnel = daemon_proc.tunnel(&addr, tunnel_args("client", &["--pin", "00:11:22"]))?;
+        daemon_proc.socket_path = PathBuf::from(&tunnel.socket_path);
+        let out = daemon_proc.list()?;
+        assert!(!out.status.success(), "list through a tunnel with the wrong pin succeeded");
+
+        let pin = fs::read_to_string(support::testdata_file("tls/server.sha256"))?;
+        let tunnel = daemon_proc.tunnel(&addr, tunnel_args("client", &["--pin", pin.trim()]))?;
+        daemon_proc.socket_path = PathBuf::from(&tunnel.socket_path);
+        let out = daemon_proc.list()?;
+        assert!(out.status.success(), "list through a tunnel with the right pin failed");
+
+        Ok(())
+    })
+}
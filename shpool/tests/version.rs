@@ -0,0 +1,51 @@
+use std::process::Command;
+
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[test]
+#[timeout(30000)]
+fn matching_daemon() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+        let out = daemon_proc.version()?;
+        assert!(out.status.success(), "version proc did not exit successfully");
+
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert_eq!(stdout, format!("shpool {}\ndaemon {}\n", VERSION, VERSION));
+
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert_eq!(stderr.len(), 0, "expected no stderr, got: {}", stderr);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn no_daemon() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg("/fake/does/not/exist/shpool.socket")
+            .arg("version")
+            .output()
+            .context("spawning version proc")?;
+        assert!(out.status.success(), "version proc did not exit successfully");
+
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert_eq!(stdout, format!("shpool {}\ndaemon not running\n", VERSION));
+
+        Ok(())
+    })
+}
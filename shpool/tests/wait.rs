@@ -0,0 +1,68 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn exit_status() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.new_session(vec!["--name", "job", "--", "sh", "-c", "exit 3"])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let out = daemon_proc.wait_cmd("job")?.output().context("running wait proc")?;
+        assert_eq!(out.status.code(), Some(3));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn many_waiters() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-wait")?;
+        let go = tmp_dir.path().join("go");
+
+        // the job sticks around until the test says otherwise so that
+        // the waiters are sure to be waiting when it exits
+        let script = format!("while [ ! -e {} ]; do sleep 0.1; done; exit 5", go.display());
+        let out = daemon_proc.new_session(vec!["--name", "job", "--", "sh", "-c", &script])?;
+        assert!(out.status.success(), "new proc did not exit successfully");
+
+        let waiters = (0..3)
+            .map(|_| daemon_proc.wait_cmd("job")?.spawn().context("spawning wait proc"))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        std::fs::write(&go, "")?;
+
+        for waiter in waiters.into_iter() {
+            let out = waiter.wait_with_output().context("waiting on wait proc")?;
+            assert_eq!(out.status.code(), Some(5));
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn not_found() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.wait_cmd("nope")?.output().context("running wait proc")?;
+        assert!(!out.status.success(), "wait proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nope"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}